@@ -0,0 +1,13 @@
+CREATE TABLE slot_preferences (
+    id SERIAL PRIMARY KEY,
+    room_id VARCHAR NOT NULL,
+    slot INTEGER NOT NULL,
+    player_name VARCHAR NOT NULL,
+    deathlink_opt_out BOOLEAN NOT NULL DEFAULT FALSE,
+    muted BOOLEAN NOT NULL DEFAULT FALSE,
+    alias VARCHAR,
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    UNIQUE(room_id, player_name)
+);
+
+CREATE INDEX idx_slot_preferences_room_id ON slot_preferences(room_id);
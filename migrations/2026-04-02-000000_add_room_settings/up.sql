@@ -0,0 +1,7 @@
+CREATE TABLE room_settings (
+    room_id VARCHAR NOT NULL,
+    key VARCHAR NOT NULL,
+    value JSONB NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (room_id, key)
+);
@@ -0,0 +1,11 @@
+CREATE TABLE connection_attempts (
+    id SERIAL PRIMARY KEY,
+    room_id VARCHAR NOT NULL,
+    slot INTEGER,
+    name VARCHAR NOT NULL,
+    outcome VARCHAR NOT NULL,
+    errors TEXT[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_connection_attempts_room_slot ON connection_attempts(room_id, slot, created_at DESC);
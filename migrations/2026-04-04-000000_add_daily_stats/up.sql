@@ -0,0 +1,23 @@
+CREATE TABLE traffic_snapshots (
+    id SERIAL PRIMARY KEY,
+    room_id VARCHAR NOT NULL,
+    messages_proxied BIGINT NOT NULL,
+    chat_messages BIGINT NOT NULL,
+    sessions BIGINT NOT NULL,
+    slots INTEGER[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_traffic_snapshots_room_created ON traffic_snapshots(room_id, created_at);
+
+CREATE TABLE daily_stats (
+    room_id VARCHAR NOT NULL,
+    day DATE NOT NULL,
+    deathlinks BIGINT NOT NULL,
+    chat_messages BIGINT NOT NULL,
+    sessions BIGINT NOT NULL,
+    unique_slots BIGINT NOT NULL,
+    messages_proxied BIGINT NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (room_id, day)
+);
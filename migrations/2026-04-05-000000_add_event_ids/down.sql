@@ -0,0 +1,3 @@
+ALTER TABLE connection_attempts DROP COLUMN event_id;
+ALTER TABLE countdowns DROP COLUMN event_id;
+ALTER TABLE deathlinks DROP COLUMN event_id;
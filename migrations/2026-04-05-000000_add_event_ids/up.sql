@@ -0,0 +1,3 @@
+ALTER TABLE deathlinks ADD COLUMN event_id UUID UNIQUE;
+ALTER TABLE countdowns ADD COLUMN event_id UUID UNIQUE;
+ALTER TABLE connection_attempts ADD COLUMN event_id UUID UNIQUE;
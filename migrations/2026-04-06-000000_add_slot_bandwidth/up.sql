@@ -0,0 +1,9 @@
+CREATE TABLE slot_bandwidth (
+    room_id VARCHAR NOT NULL,
+    slot INTEGER NOT NULL,
+    day DATE NOT NULL,
+    client_to_upstream BIGINT NOT NULL,
+    upstream_to_client BIGINT NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (room_id, slot, day)
+);
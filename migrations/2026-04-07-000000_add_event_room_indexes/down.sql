@@ -0,0 +1,9 @@
+-- Partitioned tables stay partitioned, only the indexes are reverted
+DROP INDEX IF EXISTS idx_connection_attempts_room_created;
+DROP INDEX IF EXISTS idx_countdowns_room_slot;
+DROP INDEX IF EXISTS idx_countdowns_room_created;
+DROP INDEX IF EXISTS idx_deathlinks_room_slot;
+DROP INDEX IF EXISTS idx_deathlinks_room_created;
+
+CREATE INDEX idx_countdowns_room_id ON countdowns(room_id);
+CREATE INDEX idx_deathlinks_room_id ON deathlinks(room_id);
@@ -0,0 +1,81 @@
+-- Hash partitioning by room is only picked at bootstrap, when the proxy connects with
+-- DB_PARTITIONED=true and the event tables are still empty. Installs with rows are never moved.
+DO $$
+DECLARE
+    partitions CONSTANT INTEGER := 8;
+    event_table TEXT;
+    i INTEGER;
+BEGIN
+    IF coalesce(current_setting('apx.partitioned', true), '') <> 'on' THEN
+        RETURN;
+    END IF;
+    IF EXISTS (SELECT 1 FROM deathlinks)
+        OR EXISTS (SELECT 1 FROM countdowns)
+        OR EXISTS (SELECT 1 FROM connection_attempts)
+    THEN
+        RAISE WARNING 'DB_PARTITIONED is set but the event tables already hold rows, leaving them unpartitioned';
+        RETURN;
+    END IF;
+
+    -- Unique constraints of a partitioned table have to include room_id
+    DROP TABLE deathlinks;
+    CREATE TABLE deathlinks (
+        id SERIAL,
+        room_id VARCHAR NOT NULL,
+        slot INTEGER NOT NULL,
+        source VARCHAR NOT NULL,
+        cause VARCHAR,
+        created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+        event_id UUID,
+        PRIMARY KEY (id, room_id),
+        UNIQUE (room_id, event_id)
+    ) PARTITION BY HASH (room_id);
+
+    DROP TABLE countdowns;
+    CREATE TABLE countdowns (
+        id SERIAL,
+        room_id VARCHAR NOT NULL,
+        slot INTEGER NOT NULL,
+        created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+        event_id UUID,
+        PRIMARY KEY (id, room_id),
+        UNIQUE (room_id, event_id)
+    ) PARTITION BY HASH (room_id);
+
+    DROP TABLE connection_attempts;
+    CREATE TABLE connection_attempts (
+        id SERIAL,
+        room_id VARCHAR NOT NULL,
+        slot INTEGER,
+        name VARCHAR NOT NULL,
+        outcome VARCHAR NOT NULL,
+        errors TEXT[] NOT NULL DEFAULT '{}',
+        created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+        event_id UUID,
+        PRIMARY KEY (id, room_id),
+        UNIQUE (room_id, event_id)
+    ) PARTITION BY HASH (room_id);
+
+    FOREACH event_table IN ARRAY ARRAY['deathlinks', 'countdowns', 'connection_attempts'] LOOP
+        FOR i IN 0..partitions - 1 LOOP
+            EXECUTE format(
+                'CREATE TABLE %I PARTITION OF %I FOR VALUES WITH (MODULUS %s, REMAINDER %s)',
+                event_table || '_p' || i, event_table, partitions, i
+            );
+        END LOOP;
+    END LOOP;
+END
+$$;
+
+-- Covered by the indexes below
+DROP INDEX IF EXISTS idx_deathlinks_room_id;
+DROP INDEX IF EXISTS idx_countdowns_room_id;
+
+-- Room history, newest first, optionally for a single slot
+CREATE INDEX idx_deathlinks_room_created ON deathlinks(room_id, created_at DESC);
+CREATE INDEX idx_deathlinks_room_slot ON deathlinks(room_id, slot, created_at DESC);
+CREATE INDEX idx_countdowns_room_created ON countdowns(room_id, created_at DESC);
+CREATE INDEX idx_countdowns_room_slot ON countdowns(room_id, slot, created_at DESC);
+CREATE INDEX idx_connection_attempts_room_created ON connection_attempts(room_id, created_at DESC);
+-- Already there unless the table was just partitioned
+CREATE INDEX IF NOT EXISTS idx_connection_attempts_room_slot ON connection_attempts(room_id, slot, created_at DESC);
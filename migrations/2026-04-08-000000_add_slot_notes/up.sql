@@ -0,0 +1,10 @@
+CREATE TABLE slot_notes (
+    id SERIAL PRIMARY KEY,
+    room_id VARCHAR NOT NULL,
+    slot INTEGER NOT NULL,
+    author VARCHAR NOT NULL,
+    note TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_slot_notes_room_slot ON slot_notes(room_id, slot, id DESC);
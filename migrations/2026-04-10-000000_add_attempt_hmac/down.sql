@@ -0,0 +1 @@
+ALTER TABLE connection_attempts DROP COLUMN attempt_hmac;
@@ -0,0 +1 @@
+ALTER TABLE connection_attempts ADD COLUMN attempt_hmac VARCHAR;
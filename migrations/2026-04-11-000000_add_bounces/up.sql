@@ -0,0 +1,12 @@
+CREATE TABLE bounces (
+    id SERIAL PRIMARY KEY,
+    room_id VARCHAR NOT NULL,
+    slot INTEGER NOT NULL,
+    tags TEXT[] NOT NULL DEFAULT '{}',
+    data JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    event_id UUID UNIQUE
+);
+
+CREATE INDEX idx_bounces_room_created ON bounces(room_id, created_at DESC);
+CREATE INDEX idx_bounces_room_slot ON bounces(room_id, slot, created_at DESC);
@@ -0,0 +1,8 @@
+-- Only the latest preferences of each name fit the old key
+DELETE FROM slot_preferences older USING slot_preferences newer
+WHERE older.room_id = newer.room_id
+    AND older.player_name = newer.player_name
+    AND (older.updated_at, older.id) < (newer.updated_at, newer.id);
+ALTER TABLE slot_preferences DROP CONSTRAINT slot_preferences_room_id_slot_player_name_key;
+ALTER TABLE slot_preferences ADD CONSTRAINT slot_preferences_room_id_player_name_key
+    UNIQUE (room_id, player_name);
@@ -0,0 +1,3 @@
+ALTER TABLE slot_preferences DROP CONSTRAINT slot_preferences_room_id_player_name_key;
+ALTER TABLE slot_preferences ADD CONSTRAINT slot_preferences_room_id_slot_player_name_key
+    UNIQUE (room_id, slot, player_name);
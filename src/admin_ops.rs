@@ -0,0 +1,224 @@
+use aprs_proto::primitives::SlotId;
+use serde::Serialize;
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+use crate::config::{Config, DeathlinkProbability};
+use crate::coordination::{Coordinator, SoftStateKey};
+use crate::db::{self, DieselPool};
+use crate::events::EventBus;
+use crate::progress::ProgressSummary;
+use crate::proto::PrintJSON;
+use crate::ready::{self, ReadyStates, SlotReadiness};
+use crate::registry::{ClientControl, ClientId, ClientRegistry, ClientSummary};
+
+#[derive(Serialize)]
+pub struct ClientInfo {
+    #[serde(flatten)]
+    client: ClientSummary,
+    progress: Option<ProgressSummary>,
+}
+
+impl ClientInfo {
+    pub fn slot(&self) -> SlotId {
+        self.client.slot
+    }
+}
+
+/// Clients connected to the room, with the progress of their slot
+pub async fn list_clients(client_registry: &ClientRegistry) -> Vec<ClientInfo> {
+    let progress = client_registry.progress().await;
+    client_registry
+        .clients()
+        .await
+        .into_iter()
+        .map(|client| ClientInfo {
+            progress: progress.get(&client.slot).copied(),
+            client,
+        })
+        .collect()
+}
+
+/// Closes the connection of `client_id`. Returns whether it was connected.
+pub async fn kick(client_registry: &ClientRegistry, client_id: ClientId) -> bool {
+    let Some(control) = client_registry.control(client_id).await else {
+        return false;
+    };
+    let kicked = control.send(ClientControl::Kick).await.is_ok();
+    if kicked {
+        log::info!("Kicking client {}", client_id);
+    }
+    kicked
+}
+
+/// Shows `text` to every logged in client, returns how many there were
+pub async fn broadcast(client_registry: &ClientRegistry, text: &str) -> usize {
+    let message = serde_json::to_value(PrintJSON::new(text)).unwrap();
+    let sent = client_registry.broadcast(&[message]).await;
+    log::info!("Broadcast a message to {} clients", sent);
+    sent
+}
+
+/// Stores and applies the probability of DeathLinks going through, `percent` is clamped to
+/// 0-100. Returns the probability applied, between 0 and 1.
+pub async fn set_deathlink_probability(
+    db_pool: &DieselPool,
+    deathlink_probability: &DeathlinkProbability,
+    coordinator: Option<&Coordinator>,
+    room_id: &str,
+    percent: f64,
+) -> anyhow::Result<f64> {
+    let actual = db::models::set_deathlink_probability(db_pool, room_id, percent / 100.0).await?;
+    deathlink_probability.set(actual);
+    if let Some(coordinator) = coordinator {
+        coordinator
+            .announce(SoftStateKey::DeathlinkProbability)
+            .await;
+    }
+    log::info!("DeathLink probability set to {:.2}%", actual * 100.0);
+    Ok(actual)
+}
+
+/// Readiness of every slot the room has, for synchronized starts
+pub async fn readiness(
+    client_registry: &ClientRegistry,
+    slot_names: &RwLock<HashMap<SlotId, String>>,
+    ready: &ReadyStates,
+    config: &Config,
+) -> Vec<SlotReadiness> {
+    let connected = client_registry.slot_client_counts().await;
+    ready::matrix(
+        &*slot_names.read().await,
+        &ready.slots(),
+        &connected,
+        &config.ready_exempt_slots,
+    )
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StartRefused {
+    /// Nobody logged in yet, so there are no slots to wait for
+    NoSlots,
+    /// Slots that aren't exempt and either aren't ready or aren't connected
+    WaitingOn(Vec<SlotId>),
+}
+
+#[derive(Serialize, Debug)]
+pub struct Started {
+    /// Clients the start announcement was shown to
+    pub announced_to: usize,
+    /// Client the countdown was sent upstream by, with START_COUNTDOWN
+    pub countdown_by: Option<ClientId>,
+}
+
+/// Starts the race once every slot that isn't exempt is ready and connected: the start
+/// announcement goes to every client, the countdown upstream with START_COUNTDOWN, and the
+/// ready flags are reset for the next one.
+pub async fn start(
+    client_registry: &ClientRegistry,
+    slot_names: &RwLock<HashMap<SlotId, String>>,
+    ready: &ReadyStates,
+    events: &EventBus,
+    config: &Config,
+) -> Result<Started, StartRefused> {
+    let readiness = readiness(client_registry, slot_names, ready, config).await;
+    if readiness.is_empty() {
+        return Err(StartRefused::NoSlots);
+    }
+    let waiting_on: Vec<SlotId> = readiness
+        .iter()
+        .filter(|slot| slot.holds_back())
+        .map(|slot| slot.slot)
+        .collect();
+    if !waiting_on.is_empty() {
+        return Err(StartRefused::WaitingOn(waiting_on));
+    }
+
+    let announced_to = broadcast(client_registry, &config.start_announcement).await;
+    let countdown_by = if config.start_countdown {
+        send_countdown(client_registry, &readiness).await
+    } else {
+        None
+    };
+    ready.reset(events);
+    log::info!("Started the race for {} slots", readiness.len());
+    Ok(Started {
+        announced_to,
+        countdown_by,
+    })
+}
+
+/// Has the first client of a ready slot run the countdown, the one players can't
+async fn send_countdown(
+    client_registry: &ClientRegistry,
+    readiness: &[SlotReadiness],
+) -> Option<ClientId> {
+    let text = format!("!countdown {}", ready::START_COUNTDOWN_SECONDS);
+    let candidates = client_registry
+        .clients()
+        .await
+        .into_iter()
+        .filter(|client| {
+            readiness
+                .iter()
+                .any(|slot| slot.slot == client.slot && slot.ready)
+        });
+    for client in candidates {
+        let Some(control) = client_registry.control(client.client_id).await else {
+            continue;
+        };
+        if control.send(ClientControl::Say(text.clone())).await.is_ok() {
+            log::info!("Client {} runs the start countdown", client.client_id);
+            return Some(client.client_id);
+        }
+    }
+    log::warn!("No ready client could run the start countdown");
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flapping::FlapLimits;
+    use crate::registry::tests::entry;
+    use std::time::Instant;
+    use tokio::sync::mpsc;
+    use tungstenite::Message;
+
+    #[tokio::test]
+    async fn test_kick_reaches_the_connection() {
+        let registry = ClientRegistry::new(FlapLimits::default());
+        assert!(!kick(&registry, 1).await);
+
+        let (mut client, _responses) = entry(1, Instant::now());
+        let (control, mut controls) = mpsc::channel(1);
+        client.control = control;
+        registry.register(1, client).await;
+        assert!(kick(&registry, 1).await);
+        assert!(matches!(controls.recv().await, Some(ClientControl::Kick)));
+
+        // The connection already went away
+        drop(controls);
+        assert!(!kick(&registry, 1).await);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_reaches_every_client() {
+        let registry = ClientRegistry::new(FlapLimits::default());
+        assert_eq!(broadcast(&registry, "hello").await, 0);
+
+        let (client, mut responses) = entry(1, Instant::now());
+        registry.register(1, client).await;
+        assert_eq!(broadcast(&registry, "hello").await, 1);
+        let Message::Text(text) = responses.recv().await else {
+            panic!("expected a text message");
+        };
+        let messages: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(messages[0]["cmd"], "PrintJSON");
+        assert_eq!(messages[0]["data"][0]["text"], "hello");
+
+        let clients = list_clients(&registry).await;
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].client.client_id, 1);
+    }
+}
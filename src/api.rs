@@ -1,13 +1,57 @@
 use aprs_proto::primitives::SlotId;
+use futures_util::{Stream, TryStreamExt};
 use rocket::{
-    Request, State,
+    Request, Shutdown, State,
+    figment::Figment,
+    http::{Method, RawStr},
     request::{FromRequest, Outcome},
+    response::Redirect,
+    response::stream::{Event, EventStream},
     serde::json::Json,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::sync::RwLock;
 
-use crate::config::AppState;
-use crate::lobby::refresh_login_info;
+use crate::admin_ops::{self, ClientInfo, StartRefused, Started};
+use crate::audit::AuditKey;
+use crate::bandwidth::{self, SlotBandwidth};
+use crate::bounce_log::{self, RecordedSlots};
+use crate::bridge::{self, BridgeKey, Refused};
+use crate::claims::ClaimedSlot;
+use crate::config::{AppState, Config};
+use crate::console::{Console, WebSocketUpgrade};
+use crate::coordination::SoftStateKey;
+use crate::csv::{CsvDownload, CsvRow};
+use crate::db::models::{
+    Bounce, ConnectionAttempt, Countdown, DeathLink, HistoryFilter, SlotCountdowns,
+    SlotDeathSummary, SlotNote,
+};
+use crate::diagnostics::{self, Diagnostics};
+use crate::events::next_event;
+use crate::lobby::{RejectedRefresh, RoomLogins};
+use crate::login_queue::{LoginQueue, QueuedLogin};
+use crate::motd;
+use crate::net;
+use crate::pace::PaceReport;
+use crate::password_audit::PasswordFailure;
+use crate::preferences::{self, SlotPreferences};
+use crate::progress::ProgressSummary;
+use crate::ready::SlotReadiness;
+use crate::registry::{ClientControl, ClientId, ClientRegistry, ReconnectError};
+use crate::reload::ReloadReport;
+use crate::retention::{self, TableRetention};
+use crate::scheduled_messages::{self, Announcement, ScheduledMessage};
+use crate::selftest::{self, SelfTestReport};
+use crate::shedding::{DegradedStatus, Mode};
+use crate::standby::{SnapshotError, StateSnapshot};
+use crate::tls::{self, CertExpiry, ExpiryStatus};
+use crate::token::{self, TokenClaims, TokenKey};
+use crate::transforms::Transforms;
+
+/// Where the API is mounted
+pub const BASE: &str = "/api";
 
 struct ApiKey;
 
@@ -25,20 +69,149 @@ impl<'r> FromRequest<'r> for ApiKey {
     }
 }
 
-#[rocket::post("/refresh_passwords")]
+/// The room a request is for, as a request-local once one of the room guards resolved it
+struct RequestRoom(Option<String>);
+
+/// Why the last room guard failed, reported by the 404 catcher
+struct RoomNotFound(String);
+
+/// Room of a route under `/rooms/<room_id>`, the primary room or one routed to another AP server,
+/// with the state kept for it
+pub struct RoomRef<'r> {
+    pub room_id: &'r str,
+    pub passwords: &'r RwLock<HashMap<SlotId, String>>,
+    pub client_registry: &'r ClientRegistry,
+    pub login_queue: &'r LoginQueue,
+    is_primary: bool,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RoomRef<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let state = req.guard::<&State<AppState>>().await.unwrap();
+
+        // Segments are relative to where the API is mounted
+        let room_id = req.routed_segment(1).unwrap_or_default();
+        let room = if room_id == state.config.room_id {
+            Some(RoomRef {
+                room_id: &state.config.room_id,
+                passwords: &state.passwords,
+                client_registry: &state.client_registry,
+                login_queue: &state.login_queue,
+                is_primary: true,
+            })
+        } else {
+            state
+                .rooms
+                .get_key_value(room_id)
+                .map(|(room_id, route)| RoomRef {
+                    room_id,
+                    passwords: &route.passwords,
+                    client_registry: &route.client_registry,
+                    login_queue: &route.login_queue,
+                    is_primary: false,
+                })
+        };
+
+        match room {
+            Some(room) => {
+                req.local_cache(|| RequestRoom(Some(room.room_id.to_string())));
+                Outcome::Success(room)
+            }
+            None => {
+                log::debug!("Request for unknown room {}", room_id);
+                req.local_cache(|| RoomNotFound(format!("Unknown room {}", room_id)));
+                Outcome::Error((rocket::http::Status::NotFound, ()))
+            }
+        }
+    }
+}
+
+/// A [`RoomRef`] to the primary room. Exclusions, preferences and the other room settings are
+/// only kept for it, routed rooms don't have any.
+pub struct PrimaryRoom<'r>(RoomRef<'r>);
+
+impl<'r> std::ops::Deref for PrimaryRoom<'r> {
+    type Target = RoomRef<'r>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PrimaryRoom<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let room = rocket::outcome::try_outcome!(req.guard::<RoomRef<'r>>().await);
+        if !room.is_primary {
+            log::debug!("Request for a setting of routed room {}", room.room_id);
+            req.local_cache(|| {
+                RoomNotFound(format!(
+                    "Room {} is routed, only the primary room has this",
+                    room.room_id
+                ))
+            });
+            return Outcome::Error((rocket::http::Status::NotFound, ()));
+        }
+        Outcome::Success(PrimaryRoom(room))
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiError {
+    error: String,
+    /// Room the request was for, if it exists
+    room_id: Option<String>,
+}
+
+#[rocket::catch(404)]
+fn not_found(req: &Request<'_>) -> Json<ApiError> {
+    let RoomNotFound(error) = req.local_cache(|| RoomNotFound("Not found".to_string()));
+    let RequestRoom(room_id) = req.local_cache(|| RequestRoom(None));
+    Json(ApiError {
+        error: error.clone(),
+        room_id: room_id.clone(),
+    })
+}
+
+pub fn catchers() -> Vec<rocket::Catcher> {
+    rocket::catchers![not_found]
+}
+
+/// Slots that look like a lobby bug are refused with 409 unless `force` is set
+#[rocket::post("/rooms/<_>/refresh_passwords?<force>")]
 async fn refresh_passwords(
     _key: ApiKey,
+    room: PrimaryRoom<'_>,
     state: &State<AppState>,
+    force: Option<bool>,
 ) -> Result<(), rocket::http::Status> {
     log::info!("Refreshing passwords from lobby API");
 
-    match refresh_login_info(&state.config).await {
-        Ok(new_passwords) => {
-            let mut passwords = state.passwords.write().await;
-            *passwords = new_passwords;
+    let previous = room.passwords.read().await.len();
+    let refresh = state
+        .lobby
+        .refresh(room.room_id, Some(previous), force.unwrap_or(false))
+        .await;
+    match refresh {
+        Ok(Ok(login_info)) => {
+            let logins = RoomLogins {
+                room_id: room.room_id,
+                passwords: room.passwords,
+                slot_names: &state.slot_names,
+                preferences: &state.preferences,
+                db_pool: &state.db_pool,
+                events: &state.events,
+            };
+            logins.replace(login_info).await;
             log::info!("Successfully refreshed passwords");
             Ok(())
         }
+        Ok(Err(_)) => Err(rocket::http::Status::Conflict),
         Err(e) => {
             log::error!("Failed to refresh passwords: {:?}", e);
             Err(rocket::http::Status::InternalServerError)
@@ -46,35 +219,64 @@ async fn refresh_passwords(
     }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct ExclusionListResponse {
-    excluded_slots: Vec<SlotId>,
+/// Tells the instances sharing the database about a change of soft state already applied here
+async fn announce(state: &AppState, key: SoftStateKey) {
+    if let Some(coordinator) = &state.coordinator {
+        coordinator.announce(key).await;
+    }
 }
 
-#[rocket::get("/deathlink_exclusions")]
+#[rocket::get("/rooms/<_>/deathlink_exclusions")]
 async fn get_deathlink_exclusions(
     _key: ApiKey,
+    _room: PrimaryRoom<'_>,
     state: &State<AppState>,
-) -> Json<ExclusionListResponse> {
+) -> Json<Vec<SlotId>> {
+    Json(exclusion_list(state).await)
+}
+
+/// The excluded slots, in order
+async fn exclusion_list(state: &AppState) -> Vec<SlotId> {
     let exclusions = state.deathlink_exclusions.read().await;
     let mut excluded_slots: Vec<SlotId> = exclusions.iter().copied().collect();
     excluded_slots.sort_unstable();
-    Json(ExclusionListResponse { excluded_slots })
+    excluded_slots
+}
+
+/// Excludes `slot`, answering with the updated list whether or not it was excluded already
+#[rocket::put("/rooms/<_>/deathlink_exclusions/<slot>")]
+async fn put_deathlink_exclusion(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+    slot: i64,
+) -> Result<Json<Vec<SlotId>>, rocket::http::Status> {
+    let slot = SlotId(slot);
+    if let Err(e) =
+        crate::db::models::add_deathlink_exclusion(&state.db_pool, room.room_id, slot).await
+    {
+        log::error!("Failed to persist deathlink exclusion: {:?}", e);
+        return Err(rocket::http::Status::InternalServerError);
+    }
+    if state.deathlink_exclusions.write().await.insert(slot) {
+        log::info!("Added slot {} to deathlink exclusion list", slot.0);
+    }
+    announce(state, SoftStateKey::DeathlinkExclusions).await;
+    Ok(Json(exclusion_list(state).await))
 }
 
-#[rocket::post("/deathlink_exclusions/<slot>")]
+#[rocket::post("/rooms/<_>/deathlink_exclusions/<slot>")]
 async fn add_deathlink_exclusion(
     _key: ApiKey,
+    room: PrimaryRoom<'_>,
     state: &State<AppState>,
     slot: i64,
 ) -> rocket::http::Status {
     let slot = SlotId(slot);
-    match crate::db::models::add_deathlink_exclusion(&state.db_pool, &state.config.room_id, slot)
-        .await
-    {
+    match crate::db::models::add_deathlink_exclusion(&state.db_pool, room.room_id, slot).await {
         Ok(newly_added) => {
-            let mut exclusions = state.deathlink_exclusions.write().await;
-            exclusions.insert(slot);
+            state.deathlink_exclusions.write().await.insert(slot);
+            announce(state, SoftStateKey::DeathlinkExclusions).await;
 
             if newly_added {
                 log::info!("Added slot {} to deathlink exclusion list", slot.0);
@@ -91,61 +293,329 @@ async fn add_deathlink_exclusion(
     }
 }
 
-#[rocket::delete("/deathlink_exclusions/<slot>")]
+/// Stops excluding `slot`, answering with the updated list whether or not it was excluded
+#[rocket::delete("/rooms/<_>/deathlink_exclusions/<slot>")]
 async fn remove_deathlink_exclusion(
     _key: ApiKey,
+    room: PrimaryRoom<'_>,
     state: &State<AppState>,
     slot: i64,
-) -> rocket::http::Status {
+) -> Result<Json<Vec<SlotId>>, rocket::http::Status> {
     let slot = SlotId(slot);
-    match crate::db::models::remove_deathlink_exclusion(&state.db_pool, &state.config.room_id, slot)
-        .await
-    {
+    match crate::db::models::remove_deathlink_exclusion(&state.db_pool, room.room_id, slot).await {
         Ok(was_present) => {
-            let mut exclusions = state.deathlink_exclusions.write().await;
-            exclusions.remove(&slot);
+            state.deathlink_exclusions.write().await.remove(&slot);
+            announce(state, SoftStateKey::DeathlinkExclusions).await;
 
             if was_present {
                 log::info!("Removed slot {} from deathlink exclusion list", slot.0);
-                rocket::http::Status::Ok
             } else {
                 log::debug!("Slot {} was not in deathlink exclusion list", slot.0);
-                rocket::http::Status::NotFound
             }
+            Ok(Json(exclusion_list(state).await))
         }
         Err(e) => {
             log::error!(
                 "Failed to remove deathlink exclusion from database: {:?}",
                 e
             );
-            rocket::http::Status::InternalServerError
+            Err(rocket::http::Status::InternalServerError)
         }
     }
 }
 
-#[rocket::get("/deathlinks/<room_id>")]
+/// `from` and `to` are inclusive `YYYY-MM-DD` days
+fn parse_day(day: Option<&str>) -> Result<Option<chrono::NaiveDate>, rocket::http::Status> {
+    day.map(str::parse::<chrono::NaiveDate>)
+        .transpose()
+        .map_err(|_| rocket::http::Status::BadRequest)
+}
+
+fn history_filter(
+    slot: Option<i32>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<HistoryFilter, rocket::http::Status> {
+    Ok(HistoryFilter {
+        slot,
+        from: parse_day(from)?,
+        to: parse_day(to)?,
+        since: None,
+    })
+}
+
+/// `since` is an RFC 3339 timestamp, like `2024-01-01T12:00:00Z`
+fn parse_instant(
+    instant: Option<&str>,
+) -> Result<Option<chrono::DateTime<chrono::Utc>>, rocket::http::Status> {
+    instant
+        .map(chrono::DateTime::parse_from_rfc3339)
+        .transpose()
+        .map(|instant| instant.map(|instant| instant.to_utc()))
+        .map_err(|_| rocket::http::Status::BadRequest)
+}
+
+/// Like `<room_id>-deathlinks-2024-01-01-to-2024-01-31.csv`
+fn history_filename(room_id: &str, table: &str, filter: &HistoryFilter) -> String {
+    let mut filename = format!("{}-{}", room_id, table);
+    match (filter.from, filter.to) {
+        (Some(from), Some(to)) => filename.push_str(&format!("-{}-to-{}", from, to)),
+        (Some(from), None) => filename.push_str(&format!("-from-{}", from)),
+        (None, Some(to)) => filename.push_str(&format!("-to-{}", to)),
+        (None, None) => {}
+    }
+    filename.push_str(".csv");
+    filename
+}
+
+fn csv_time(time: &chrono::DateTime<chrono::Utc>) -> String {
+    time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+impl CsvRow for DeathLink {
+    const HEADER: &'static [&'static str] = &["created_at", "slot", "source", "cause"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            csv_time(&self.created_at),
+            self.slot.to_string(),
+            self.source.clone(),
+            self.cause.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+impl CsvRow for ConnectionAttempt {
+    const HEADER: &'static [&'static str] = &["created_at", "slot", "name", "outcome", "errors"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            csv_time(&self.created_at),
+            self.slot.map(|slot| slot.to_string()).unwrap_or_default(),
+            self.name.clone(),
+            self.outcome.clone(),
+            self.errors.join("; "),
+        ]
+    }
+}
+
+const DEFAULT_DEATHLINKS_PAGE: i64 = 100;
+const MAX_DEATHLINKS_PAGE: i64 = 1000;
+
+#[derive(Serialize)]
+pub struct DeathlinksPage {
+    /// Newest first
+    deathlinks: Vec<DeathLink>,
+    /// Deathlinks the filters match, over every page
+    total: i64,
+    limit: i64,
+    offset: i64,
+}
+
+#[rocket::get("/rooms/<_>/deathlinks?<slot>&<from>&<to>&<since>&<limit>&<offset>")]
 async fn get_room_deathlinks(
     _key: ApiKey,
+    room: RoomRef<'_>,
     state: &State<AppState>,
-    room_id: &str,
-) -> Result<Json<Vec<crate::db::models::DeathLink>>, rocket::http::Status> {
-    match crate::db::models::get_room_deathlinks(&state.db_pool, room_id).await {
-        Ok(deathlinks) => Ok(Json(deathlinks)),
+    slot: Option<i32>,
+    from: Option<&str>,
+    to: Option<&str>,
+    since: Option<&str>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+) -> Result<Json<DeathlinksPage>, rocket::http::Status> {
+    let filter = HistoryFilter {
+        since: parse_instant(since)?,
+        ..history_filter(slot, from, to)?
+    };
+    let limit = page_size(limit, DEFAULT_DEATHLINKS_PAGE, MAX_DEATHLINKS_PAGE)?;
+    let offset = offset.unwrap_or(0);
+    if offset < 0 {
+        return Err(rocket::http::Status::BadRequest);
+    }
+    match crate::db::models::get_room_deathlinks(
+        &state.db_pool,
+        room.room_id,
+        filter,
+        limit,
+        offset,
+    )
+    .await
+    {
+        Ok((deathlinks, total)) => Ok(Json(DeathlinksPage {
+            deathlinks,
+            total,
+            limit,
+            offset,
+        })),
+        Err(e) => {
+            log::error!(
+                "Failed to get deathlinks for room {}: {:?}",
+                room.room_id,
+                e
+            );
+            Err(rocket::http::Status::InternalServerError)
+        }
+    }
+}
+
+#[rocket::get("/rooms/<_>/deathlinks.csv?<slot>&<from>&<to>")]
+async fn get_room_deathlinks_csv(
+    _key: ApiKey,
+    room: RoomRef<'_>,
+    state: &State<AppState>,
+    slot: Option<i32>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<CsvDownload<impl Stream<Item = anyhow::Result<DeathLink>> + Send>, rocket::http::Status>
+{
+    let filter = history_filter(slot, from, to)?;
+    let rows = crate::db::models::stream_room_deathlinks(
+        state.db_pool.clone(),
+        room.room_id.to_string(),
+        filter,
+    );
+    Ok(CsvDownload::new(
+        &history_filename(room.room_id, "deathlinks", &filter),
+        rows,
+    ))
+}
+
+const DEFAULT_COUNTDOWNS_PAGE: i64 = 100;
+const MAX_COUNTDOWNS_PAGE: i64 = 1000;
+
+/// Countdowns players started, newest first
+#[rocket::get("/rooms/<_>/countdowns?<slot>&<limit>")]
+async fn get_room_countdowns(
+    _key: ApiKey,
+    room: RoomRef<'_>,
+    state: &State<AppState>,
+    slot: Option<i32>,
+    limit: Option<i64>,
+) -> Result<Json<Vec<Countdown>>, rocket::http::Status> {
+    let limit = page_size(limit, DEFAULT_COUNTDOWNS_PAGE, MAX_COUNTDOWNS_PAGE)?;
+    match crate::db::models::get_room_countdowns(&state.db_pool, room.room_id, slot, limit).await {
+        Ok(countdowns) => Ok(Json(countdowns)),
+        Err(e) => {
+            log::error!(
+                "Failed to get countdowns for room {}: {:?}",
+                room.room_id,
+                e
+            );
+            Err(rocket::http::Status::InternalServerError)
+        }
+    }
+}
+
+/// Per slot, how many countdowns it started
+#[rocket::get("/rooms/<_>/countdowns/summary")]
+async fn get_countdown_summary(
+    _key: ApiKey,
+    room: RoomRef<'_>,
+    state: &State<AppState>,
+) -> Result<Json<Vec<SlotCountdowns>>, rocket::http::Status> {
+    match crate::db::models::get_room_countdown_summary(&state.db_pool, room.room_id).await {
+        Ok(summary) => Ok(Json(summary)),
+        Err(e) => {
+            log::error!(
+                "Failed to summarize countdowns of room {}: {:?}",
+                room.room_id,
+                e
+            );
+            Err(rocket::http::Status::InternalServerError)
+        }
+    }
+}
+
+/// Per slot, when it last died, how long it's been surviving and its streak of deaths
+#[rocket::get("/rooms/<_>/deathlink_summary")]
+async fn get_deathlink_summary(
+    _key: ApiKey,
+    room: RoomRef<'_>,
+    state: &State<AppState>,
+) -> Result<Json<Vec<SlotDeathSummary>>, rocket::http::Status> {
+    let slots = room
+        .passwords
+        .read()
+        .await
+        .keys()
+        .map(|slot| slot.0 as i32)
+        .collect();
+    match crate::db::models::get_room_deathlink_summary(
+        &state.db_pool,
+        room.room_id,
+        slots,
+        chrono::Utc::now(),
+    )
+    .await
+    {
+        Ok(summary) => Ok(Json(summary)),
+        Err(e) => {
+            log::error!(
+                "Failed to summarize deathlinks of room {}: {:?}",
+                room.room_id,
+                e
+            );
+            Err(rocket::http::Status::InternalServerError)
+        }
+    }
+}
+
+/// Recorded login attempts, newest first
+#[rocket::get("/rooms/<_>/sessions?<slot>&<from>&<to>")]
+async fn get_room_sessions(
+    _key: ApiKey,
+    room: RoomRef<'_>,
+    state: &State<AppState>,
+    slot: Option<i32>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Json<Vec<ConnectionAttempt>>, rocket::http::Status> {
+    let filter = history_filter(slot, from, to)?;
+    match crate::db::models::get_room_connection_attempts(&state.db_pool, room.room_id, filter)
+        .await
+    {
+        Ok(attempts) => Ok(Json(attempts)),
         Err(e) => {
-            log::error!("Failed to get deathlinks for room {}: {:?}", room_id, e);
+            log::error!("Failed to get sessions for room {}: {:?}", room.room_id, e);
             Err(rocket::http::Status::InternalServerError)
         }
     }
 }
 
+#[rocket::get("/rooms/<_>/sessions.csv?<slot>&<from>&<to>")]
+async fn get_room_sessions_csv(
+    _key: ApiKey,
+    room: RoomRef<'_>,
+    state: &State<AppState>,
+    slot: Option<i32>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<
+    CsvDownload<impl Stream<Item = anyhow::Result<ConnectionAttempt>> + Send>,
+    rocket::http::Status,
+> {
+    let filter = history_filter(slot, from, to)?;
+    let rows = crate::db::models::stream_room_connection_attempts(
+        state.db_pool.clone(),
+        room.room_id.to_string(),
+        filter,
+    );
+    Ok(CsvDownload::new(
+        &history_filename(room.room_id, "sessions", &filter),
+        rows,
+    ))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ProbabilityResponse {
     probability: f64,
 }
 
-#[rocket::get("/deathlink_probability")]
+#[rocket::get("/rooms/<_>/deathlink_probability")]
 async fn get_deathlink_probability(
     _key: ApiKey,
+    _room: PrimaryRoom<'_>,
     state: &State<AppState>,
 ) -> Json<ProbabilityResponse> {
     let probability = state.deathlink_probability.get();
@@ -157,28 +627,25 @@ pub struct SetProbabilityRequest {
     probability: f64,
 }
 
-#[rocket::put("/deathlink_probability", data = "<request>")]
+#[rocket::put("/rooms/<_>/deathlink_probability", data = "<request>")]
 async fn set_deathlink_probability(
     _key: ApiKey,
+    room: PrimaryRoom<'_>,
     state: &State<AppState>,
     request: Json<SetProbabilityRequest>,
 ) -> Result<Json<ProbabilityResponse>, rocket::http::Status> {
-    let normalized = request.probability / 100.0;
-
-    match crate::db::models::set_deathlink_probability(
+    match admin_ops::set_deathlink_probability(
         &state.db_pool,
-        &state.config.room_id,
-        normalized,
+        &state.deathlink_probability,
+        state.coordinator.as_deref(),
+        room.room_id,
+        request.probability,
     )
     .await
     {
-        Ok(actual) => {
-            state.deathlink_probability.set(actual);
-            log::info!("DeathLink probability set to {:.2}%", actual * 100.0);
-            Ok(Json(ProbabilityResponse {
-                probability: actual,
-            }))
-        }
+        Ok(actual) => Ok(Json(ProbabilityResponse {
+            probability: actual,
+        })),
         Err(e) => {
             log::error!("Failed to persist deathlink probability: {:?}", e);
             Err(rocket::http::Status::InternalServerError)
@@ -211,8 +678,12 @@ async fn add_deferred_datapackage_game(
     let game_name = &request.game_name;
     match crate::db::models::add_deferred_datapackage_game(&state.db_pool, game_name).await {
         Ok(newly_added) => {
-            let mut games = state.deferred_datapackage_games.write().await;
-            games.insert(game_name.clone());
+            state
+                .deferred_datapackage_games
+                .write()
+                .await
+                .insert(game_name.clone());
+            announce(state, SoftStateKey::DeferredDatapackageGames).await;
 
             if newly_added {
                 log::info!("Added '{}' to deferred datapackage games", game_name);
@@ -242,8 +713,12 @@ async fn remove_deferred_datapackage_game(
 ) -> rocket::http::Status {
     match crate::db::models::remove_deferred_datapackage_game(&state.db_pool, game_name).await {
         Ok(was_present) => {
-            let mut games = state.deferred_datapackage_games.write().await;
-            games.remove(game_name);
+            state
+                .deferred_datapackage_games
+                .write()
+                .await
+                .remove(game_name);
+            announce(state, SoftStateKey::DeferredDatapackageGames).await;
 
             if was_present {
                 log::info!("Removed '{}' from deferred datapackage games", game_name);
@@ -263,41 +738,2474 @@ async fn remove_deferred_datapackage_game(
     }
 }
 
-pub fn routes() -> Vec<rocket::Route> {
-    rocket::routes![
-        refresh_passwords,
-        get_deathlink_exclusions,
-        add_deathlink_exclusion,
-        remove_deathlink_exclusion,
-        get_room_deathlinks,
-        get_deathlink_probability,
-        set_deathlink_probability,
-        get_deferred_datapackage_games,
-        add_deferred_datapackage_game,
-        remove_deferred_datapackage_game,
-    ]
+#[derive(Serialize)]
+pub struct EffectivePreferences {
+    slot: SlotId,
+    player_name: Option<String>,
+    #[serde(flatten)]
+    preferences: SlotPreferences,
 }
 
-#[derive(Clone)]
-pub struct MetricsRoute(pub rocket_prometheus::PrometheusMetrics);
+#[rocket::get("/rooms/<_>/preferences")]
+async fn get_preferences(
+    _key: ApiKey,
+    _room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+) -> Json<Vec<EffectivePreferences>> {
+    let preferences = state.preferences.read().await;
+    let slot_names = state.slot_names.read().await;
+    let mut effective: Vec<EffectivePreferences> = preferences
+        .iter()
+        .map(|(slot, preferences)| EffectivePreferences {
+            slot: *slot,
+            player_name: slot_names.get(slot).cloned(),
+            preferences: preferences.clone(),
+        })
+        .collect();
+    effective.sort_unstable_by_key(|p| p.slot);
+    Json(effective)
+}
 
-#[rocket::async_trait]
-impl rocket::route::Handler for MetricsRoute {
-    async fn handle<'r>(
-        &self,
-        req: &'r rocket::Request<'_>,
-        data: rocket::Data<'r>,
-    ) -> rocket::route::Outcome<'r> {
-        let rocket::outcome::Outcome::Success(_api_key) = req.guard::<ApiKey>().await else {
-            return rocket::route::Outcome::Error(rocket::http::Status::Unauthorized);
-        };
+#[rocket::put("/rooms/<_>/preferences/<slot>", data = "<request>")]
+async fn set_preferences(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+    slot: i64,
+    request: Json<SlotPreferences>,
+) -> Result<Json<SlotPreferences>, rocket::http::Status> {
+    let slot = SlotId(slot);
+    let Some(player_name) = state.slot_names.read().await.get(&slot).cloned() else {
+        log::debug!("Cannot set preferences for unknown slot {}", slot.0);
+        return Err(rocket::http::Status::NotFound);
+    };
+
+    let new_preferences = request.into_inner();
+    let new_preference = crate::db::models::NewSlotPreference {
+        room_id: room.room_id.to_string(),
+        slot: slot.0 as i32,
+        player_name: preferences::canonical_name(&player_name),
+        deathlink_opt_out: new_preferences.deathlink_opt_out,
+        muted: new_preferences.muted,
+        alias: new_preferences.alias.clone(),
+    };
 
-        self.0.handle(req, data).await
+    match crate::db::models::upsert_slot_preference(&state.db_pool, new_preference).await {
+        Ok(()) => {
+            state
+                .preferences
+                .write()
+                .await
+                .insert(slot, new_preferences.clone());
+            announce(state, SoftStateKey::Preferences).await;
+            log::info!(
+                "Updated preferences for slot {} ({}): {:?}",
+                slot.0,
+                player_name,
+                new_preferences
+            );
+            Ok(Json(new_preferences))
+        }
+        Err(e) => {
+            log::error!("Failed to persist slot preferences: {:?}", e);
+            Err(rocket::http::Status::InternalServerError)
+        }
     }
 }
 
-impl From<MetricsRoute> for Vec<rocket::Route> {
-    fn from(val: MetricsRoute) -> Self {
-        vec![rocket::Route::new(rocket::http::Method::Get, "/", val)]
+#[rocket::get("/rooms/<_>/events")]
+fn get_events(
+    _key: ApiKey,
+    _room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let mut receiver = state.events.subscribe();
+    EventStream! {
+        loop {
+            let event = rocket::tokio::select! {
+                event = next_event(&mut receiver, "sse") => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield Event::json(&event).event(event.kind());
+        }
+    }
+}
+
+/// The events of `get_events` and a few commands over a WebSocket, the API key being its first
+/// message rather than a header
+#[rocket::get("/rooms/<_>/admin")]
+fn admin_console(
+    _room: PrimaryRoom<'_>,
+    upgrade: WebSocketUpgrade,
+    peer: SocketAddr,
+    state: &State<AppState>,
+) -> Console {
+    Console::new(upgrade, peer, state)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MotdPayload {
+    motd: Option<String>,
+}
+
+#[rocket::get("/rooms/<_>/motd")]
+async fn get_motd(
+    _key: ApiKey,
+    _room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+) -> Json<MotdPayload> {
+    Json(MotdPayload {
+        motd: state.motd.read().await.clone(),
+    })
+}
+
+#[rocket::put("/rooms/<_>/motd", data = "<request>")]
+async fn set_motd(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+    request: Json<MotdPayload>,
+) -> Result<Json<MotdPayload>, rocket::http::Status> {
+    let motd = request.into_inner().motd.filter(|m| !m.trim().is_empty());
+    if motd
+        .as_deref()
+        .is_some_and(|m| m.chars().count() > motd::MAX_MOTD_LENGTH)
+    {
+        log::debug!(
+            "Rejecting motd longer than {} characters",
+            motd::MAX_MOTD_LENGTH
+        );
+        return Err(rocket::http::Status::BadRequest);
+    }
+
+    let value = serde_json::to_value(&motd).unwrap();
+    match crate::db::models::set_room_setting(
+        &state.db_pool,
+        room.room_id,
+        motd::MOTD_SETTING_KEY,
+        value,
+    )
+    .await
+    {
+        Ok(()) => {
+            *state.motd.write().await = motd.clone();
+            announce(state, SoftStateKey::Motd).await;
+            match &motd {
+                Some(motd) => log::info!("Motd set ({} characters)", motd.chars().count()),
+                None => log::info!("Motd cleared"),
+            }
+            Ok(Json(MotdPayload { motd }))
+        }
+        Err(e) => {
+            log::error!("Failed to persist motd: {:?}", e);
+            Err(rocket::http::Status::InternalServerError)
+        }
+    }
+}
+
+/// Like RECORD_BOUNCES, `off`, `all` or slots like `1,4,7`
+#[derive(Serialize, Deserialize)]
+pub struct RecordBouncesPayload {
+    record_bounces: String,
+}
+
+#[rocket::get("/rooms/<_>/record_bounces")]
+async fn get_record_bounces(
+    _key: ApiKey,
+    _room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+) -> Json<RecordBouncesPayload> {
+    Json(RecordBouncesPayload {
+        record_bounces: state.bounce_recorder.slots().to_string(),
+    })
+}
+
+#[rocket::put("/rooms/<_>/record_bounces", data = "<request>")]
+async fn set_record_bounces(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+    request: Json<RecordBouncesPayload>,
+) -> Result<Json<RecordBouncesPayload>, rocket::http::Status> {
+    let slots: RecordedSlots = request.record_bounces.parse().map_err(|e| {
+        log::debug!("Rejecting recorded Bounce slots: {}", e);
+        rocket::http::Status::BadRequest
+    })?;
+
+    let record_bounces = slots.to_string();
+    match crate::db::models::set_room_setting(
+        &state.db_pool,
+        room.room_id,
+        bounce_log::RECORD_BOUNCES_SETTING_KEY,
+        serde_json::Value::String(record_bounces.clone()),
+    )
+    .await
+    {
+        Ok(()) => {
+            state.bounce_recorder.set_slots(slots);
+            announce(state, SoftStateKey::RecordBounces).await;
+            log::info!("Recording Bounces of slots: {}", record_bounces);
+            Ok(Json(RecordBouncesPayload { record_bounces }))
+        }
+        Err(e) => {
+            log::error!("Failed to persist recorded Bounce slots: {:?}", e);
+            Err(rocket::http::Status::InternalServerError)
+        }
+    }
+}
+
+/// Recorded Bounces returned at most, the newest
+const MAX_BOUNCES: i64 = 1000;
+
+/// `since` is an RFC 3339 timestamp
+#[rocket::get("/rooms/<_>/bounces?<slot>&<since>")]
+async fn get_bounces(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+    slot: Option<i64>,
+    since: Option<&str>,
+) -> Result<Json<Vec<Bounce>>, rocket::http::Status> {
+    let since = since
+        .map(str::parse::<chrono::DateTime<chrono::Utc>>)
+        .transpose()
+        .map_err(|_| rocket::http::Status::BadRequest)?;
+    match crate::db::models::get_room_bounces(
+        &state.db_pool,
+        room.room_id,
+        slot.map(SlotId),
+        since,
+        MAX_BOUNCES,
+    )
+    .await
+    {
+        Ok(bounces) => Ok(Json(bounces)),
+        Err(e) => {
+            log::error!("Failed to get recorded Bounces: {:?}", e);
+            Err(rocket::http::Status::InternalServerError)
+        }
+    }
+}
+
+/// Changes are sent out as soon as they're made, persisting them only keeps them past a restart
+async fn persist_scheduled_messages(
+    state: &AppState,
+    room_id: &str,
+) -> Result<(), rocket::http::Status> {
+    scheduled_messages::persist(&state.db_pool, room_id, &state.scheduled_messages)
+        .await
+        .map_err(|e| {
+            log::error!("Failed to persist scheduled messages: {:?}", e);
+            rocket::http::Status::InternalServerError
+        })
+}
+
+fn validate_announcement(
+    request: Json<Announcement>,
+) -> Result<Announcement, rocket::http::Status> {
+    let announcement = request.into_inner();
+    announcement.validate().map_err(|e| {
+        log::debug!("Rejecting scheduled message: {}", e);
+        rocket::http::Status::BadRequest
+    })?;
+    Ok(announcement)
+}
+
+#[rocket::get("/rooms/<_>/scheduled_messages")]
+async fn get_scheduled_messages(
+    _key: ApiKey,
+    _room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+) -> Json<Vec<ScheduledMessage>> {
+    Json(state.scheduled_messages.list())
+}
+
+#[rocket::post("/rooms/<_>/scheduled_messages", data = "<request>")]
+async fn add_scheduled_message(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+    request: Json<Announcement>,
+) -> Result<Json<ScheduledMessage>, rocket::http::Status> {
+    let announcement = validate_announcement(request)?;
+    let message = state
+        .scheduled_messages
+        .add(announcement, chrono::Utc::now());
+    log::info!("Scheduled message {} added", message.id);
+    persist_scheduled_messages(state, room.room_id).await?;
+    Ok(Json(message))
+}
+
+#[rocket::put("/rooms/<_>/scheduled_messages/<id>", data = "<request>")]
+async fn update_scheduled_message(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+    id: u32,
+    request: Json<Announcement>,
+) -> Result<Json<ScheduledMessage>, rocket::http::Status> {
+    let announcement = validate_announcement(request)?;
+    let message = state
+        .scheduled_messages
+        .update(id, announcement, chrono::Utc::now())
+        .ok_or(rocket::http::Status::NotFound)?;
+    log::info!("Scheduled message {} updated", id);
+    persist_scheduled_messages(state, room.room_id).await?;
+    Ok(Json(message))
+}
+
+#[rocket::delete("/rooms/<_>/scheduled_messages/<id>")]
+async fn remove_scheduled_message(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+    id: u32,
+) -> rocket::http::Status {
+    if !state.scheduled_messages.remove(id) {
+        return rocket::http::Status::NotFound;
+    }
+    log::info!("Scheduled message {} removed", id);
+    match persist_scheduled_messages(state, room.room_id).await {
+        Ok(()) => rocket::http::Status::Ok,
+        Err(status) => status,
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReconnectResult {
+    client_id: u64,
+    reconnected: bool,
+    error: Option<String>,
+    elapsed_ms: u64,
+}
+
+/// Re-establishes the upstream connection of every client logged in to the slot while keeping
+/// the client sockets open. Each client answers for itself, a client that isn't ready doesn't
+/// stop the others. Conflict when none of them was.
+#[rocket::post("/rooms/<_>/clients/<slot>/reconnect_upstream")]
+async fn reconnect_upstream(
+    _key: ApiKey,
+    room: RoomRef<'_>,
+    slot: i64,
+) -> Result<Json<Vec<ReconnectResult>>, rocket::http::Status> {
+    let controls = room.client_registry.controls_for_slot(SlotId(slot)).await;
+    if controls.is_empty() {
+        log::debug!("No client connected to slot {}, nothing to reconnect", slot);
+        return Err(rocket::http::Status::NotFound);
+    }
+
+    let mut results = Vec::with_capacity(controls.len());
+    let mut ready = false;
+    for (client_id, control) in controls {
+        let started = std::time::Instant::now();
+        let (reply_tx, reply_rx) = rocket::tokio::sync::oneshot::channel();
+        if control
+            .send(ClientControl::ReconnectUpstream(reply_tx))
+            .await
+            .is_err()
+        {
+            // The connection went away in the meantime
+            continue;
+        }
+        let Ok(outcome) = reply_rx.await else {
+            continue;
+        };
+
+        let error = match outcome {
+            Ok(()) => None,
+            Err(ReconnectError::NotReady) => {
+                log::debug!(
+                    "Client {} on slot {} isn't ready to reconnect upstream",
+                    client_id,
+                    slot
+                );
+                results.push(ReconnectResult {
+                    client_id,
+                    reconnected: false,
+                    error: Some("Not logged in, or already reconnecting".to_string()),
+                    elapsed_ms: started.elapsed().as_millis() as u64,
+                });
+                continue;
+            }
+            Err(ReconnectError::Failed(e)) => Some(e),
+        };
+        ready = true;
+
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        log::info!(
+            "Upstream reconnect for client {} on slot {} took {}ms (error: {:?})",
+            client_id,
+            slot,
+            elapsed_ms,
+            error
+        );
+        results.push(ReconnectResult {
+            client_id,
+            reconnected: error.is_none(),
+            error,
+            elapsed_ms,
+        });
+    }
+
+    if results.is_empty() {
+        return Err(rocket::http::Status::NotFound);
+    }
+    if !ready {
+        return Err(rocket::http::Status::Conflict);
+    }
+    Ok(Json(results))
+}
+
+#[derive(Serialize)]
+pub struct LastRefusal {
+    errors: Vec<String>,
+    at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize)]
+pub struct SlotStatus {
+    slot: SlotId,
+    name: String,
+    password_required: bool,
+    connected_clients: usize,
+    last_refusal: Option<LastRefusal>,
+    bandwidth: SlotBandwidth,
+    over_quota: bool,
+    /// Reconnected more than `FLAP_THRESHOLD` times within `FLAP_WINDOW`
+    flapping: bool,
+    latest_note: Option<SlotNote>,
+}
+
+#[rocket::get("/rooms/<_>/slots")]
+async fn get_slots(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+) -> Result<Json<Vec<SlotStatus>>, rocket::http::Status> {
+    let refusals = match crate::db::models::get_latest_refusals(&state.db_pool, room.room_id).await
+    {
+        Ok(refusals) => refusals,
+        Err(e) => {
+            log::error!("Failed to fetch connection refusals: {:?}", e);
+            return Err(rocket::http::Status::InternalServerError);
+        }
+    };
+    let mut refusals: std::collections::HashMap<i64, LastRefusal> = refusals
+        .into_iter()
+        .filter_map(|attempt| {
+            let slot = attempt.slot?;
+            Some((
+                slot as i64,
+                LastRefusal {
+                    errors: attempt.errors,
+                    at: attempt.created_at,
+                },
+            ))
+        })
+        .collect();
+
+    let mut latest_notes: HashMap<i32, SlotNote> =
+        match crate::db::models::get_latest_slot_notes(&state.db_pool, room.room_id).await {
+            Ok(notes) => notes.into_iter().map(|note| (note.slot, note)).collect(),
+            Err(e) => {
+                log::error!("Failed to fetch slot notes: {:?}", e);
+                return Err(rocket::http::Status::InternalServerError);
+            }
+        };
+
+    let client_counts = room.client_registry.slot_client_counts().await;
+    let flapping = room.client_registry.flapping_slots().await;
+    let passwords = room.passwords.read().await;
+    let slot_names = state.slot_names.read().await;
+    let quota = &state.live.snapshot().bandwidth_quota;
+    let mut slots: Vec<SlotStatus> = slot_names
+        .iter()
+        .map(|(slot, name)| {
+            let bandwidth = bandwidth::usage(room.room_id, *slot);
+            SlotStatus {
+                slot: *slot,
+                name: name.clone(),
+                password_required: passwords.get(slot).is_some_and(|p| !p.is_empty()),
+                connected_clients: client_counts.get(slot).copied().unwrap_or(0),
+                last_refusal: refusals.remove(&slot.0),
+                bandwidth,
+                over_quota: quota.is_exceeded(&bandwidth),
+                flapping: flapping.contains(slot),
+                latest_note: latest_notes.remove(&(slot.0 as i32)),
+            }
+        })
+        .collect();
+    slots.sort_unstable_by_key(|s| s.slot);
+    Ok(Json(slots))
+}
+
+const DEFAULT_NOTES_PAGE: i64 = 20;
+const MAX_NOTES_PAGE: i64 = 100;
+
+fn page_size(limit: Option<i64>, default: i64, max: i64) -> Result<i64, rocket::http::Status> {
+    match limit {
+        None => Ok(default),
+        Some(limit) if (1..=max).contains(&limit) => Ok(limit),
+        Some(limit) => {
+            log::debug!(
+                "Rejecting a page of {} rows, up to {} are served",
+                limit,
+                max
+            );
+            Err(rocket::http::Status::BadRequest)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewNote {
+    author: String,
+    note: String,
+}
+
+#[derive(Serialize)]
+pub struct NotesPage {
+    /// Newest first
+    notes: Vec<SlotNote>,
+    /// `before` of the next page, None once there are no older notes
+    next_before: Option<i32>,
+}
+
+#[rocket::post("/rooms/<_>/slots/<slot>/notes", data = "<request>")]
+async fn add_slot_note(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+    slot: i64,
+    request: Json<NewNote>,
+) -> Result<Json<SlotNote>, rocket::http::Status> {
+    let slot = SlotId(slot);
+    if !state.slot_names.read().await.contains_key(&slot) {
+        log::debug!("Cannot add a note to unknown slot {}", slot.0);
+        return Err(rocket::http::Status::NotFound);
+    }
+    let NewNote { author, note } = request.into_inner();
+    if author.trim().is_empty() || note.trim().is_empty() {
+        return Err(rocket::http::Status::BadRequest);
+    }
+
+    let new_note = crate::db::models::NewSlotNote {
+        room_id: room.room_id.to_string(),
+        slot: slot.0 as i32,
+        author,
+        note,
+    };
+    match crate::db::models::insert_slot_note(&state.db_pool, new_note).await {
+        Ok(note) => {
+            log::info!("{} added a note to slot {}", note.author, slot.0);
+            Ok(Json(note))
+        }
+        Err(e) => {
+            log::error!("Failed to persist slot note: {:?}", e);
+            Err(rocket::http::Status::InternalServerError)
+        }
+    }
+}
+
+#[rocket::get("/rooms/<_>/slots/<slot>/notes?<before>&<limit>")]
+async fn get_slot_notes(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+    slot: i64,
+    before: Option<i32>,
+    limit: Option<i64>,
+) -> Result<Json<NotesPage>, rocket::http::Status> {
+    let slot = SlotId(slot);
+    if !state.slot_names.read().await.contains_key(&slot) {
+        return Err(rocket::http::Status::NotFound);
+    }
+    let limit = page_size(limit, DEFAULT_NOTES_PAGE, MAX_NOTES_PAGE)?;
+
+    // One more than asked tells whether there's another page
+    match crate::db::models::get_slot_notes(&state.db_pool, room.room_id, slot, before, limit + 1)
+        .await
+    {
+        Ok(mut notes) => {
+            let more = notes.len() as i64 > limit;
+            notes.truncate(limit as usize);
+            let next_before = if more {
+                notes.last().map(|note| note.id)
+            } else {
+                None
+            };
+            Ok(Json(NotesPage { notes, next_before }))
+        }
+        Err(e) => {
+            log::error!("Failed to get notes of slot {}: {:?}", slot.0, e);
+            Err(rocket::http::Status::InternalServerError)
+        }
+    }
+}
+
+#[rocket::delete("/rooms/<_>/notes/<id>")]
+async fn delete_slot_note(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+    id: i32,
+) -> rocket::http::Status {
+    match crate::db::models::delete_slot_note(&state.db_pool, room.room_id, id).await {
+        Ok(true) => {
+            log::info!("Deleted slot note {}", id);
+            rocket::http::Status::Ok
+        }
+        Ok(false) => rocket::http::Status::NotFound,
+        Err(e) => {
+            log::error!("Failed to delete slot note {}: {:?}", id, e);
+            rocket::http::Status::InternalServerError
+        }
+    }
+}
+
+#[rocket::get("/rooms/<_>/clients")]
+async fn get_clients(_key: ApiKey, room: RoomRef<'_>) -> Json<Vec<ClientInfo>> {
+    Json(admin_ops::list_clients(room.client_registry).await)
+}
+
+/// Clients logged in to the slot, with the memory each connection holds
+#[rocket::get("/rooms/<_>/clients/<slot>")]
+async fn get_slot_clients(
+    _key: ApiKey,
+    room: RoomRef<'_>,
+    slot: i64,
+) -> Result<Json<Vec<ClientInfo>>, rocket::http::Status> {
+    let clients: Vec<ClientInfo> = admin_ops::list_clients(room.client_registry)
+        .await
+        .into_iter()
+        .filter(|client| client.slot() == SlotId(slot))
+        .collect();
+    if clients.is_empty() {
+        return Err(rocket::http::Status::NotFound);
+    }
+    Ok(Json(clients))
+}
+
+/// Logins waiting for a seat, in the order they get one
+#[rocket::get("/rooms/<_>/queue")]
+async fn get_queue(_key: ApiKey, room: RoomRef<'_>) -> Json<Vec<QueuedLogin>> {
+    Json(room.login_queue.waiting())
+}
+
+/// Moves a queued login to the front, it gets the next free seat
+#[rocket::post("/rooms/<_>/queue/<client_id>/promote")]
+async fn promote_queued_login(
+    _key: ApiKey,
+    room: RoomRef<'_>,
+    client_id: ClientId,
+) -> rocket::http::Status {
+    if !room.login_queue.promote(client_id) {
+        log::debug!("Client {} isn't in the login queue", client_id);
+        return rocket::http::Status::NotFound;
+    }
+    log::info!(
+        "Moved client {} to the front of the login queue of room {}",
+        client_id,
+        room.room_id
+    );
+    rocket::http::Status::Ok
+}
+
+#[derive(Serialize)]
+pub struct SlotProgress {
+    slot: SlotId,
+    name: Option<String>,
+    #[serde(flatten)]
+    progress: ProgressSummary,
+    items_received: usize,
+}
+
+#[rocket::get("/rooms/<_>/progress")]
+async fn get_progress(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+) -> Json<Vec<SlotProgress>> {
+    let slot_names = state.slot_names.read().await;
+    let items_received = room.client_registry.items_received().await;
+    let mut progress: Vec<SlotProgress> = room
+        .client_registry
+        .progress()
+        .await
+        .into_iter()
+        .map(|(slot, progress)| SlotProgress {
+            slot,
+            name: slot_names.get(&slot).cloned(),
+            progress,
+            items_received: items_received.get(&slot).copied().unwrap_or(0),
+        })
+        .collect();
+    progress.sort_unstable_by_key(|p| p.slot);
+    Json(progress)
+}
+
+/// Checks per minute over the last 10 minutes by slot and for the whole room, with when the
+/// room would be done at that pace
+#[rocket::get("/rooms/<_>/pace")]
+async fn get_pace(_key: ApiKey, room: RoomRef<'_>) -> Json<PaceReport> {
+    Json(room.client_registry.pace(chrono::Utc::now()).await)
+}
+
+/// Which slots are ready for a synchronized start, and whether they're connected
+#[rocket::get("/rooms/<_>/ready")]
+async fn get_ready(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+) -> Json<Vec<SlotReadiness>> {
+    Json(
+        admin_ops::readiness(
+            room.client_registry,
+            &state.slot_names,
+            &state.ready,
+            &state.config,
+        )
+        .await,
+    )
+}
+
+#[rocket::post("/rooms/<_>/ready/reset")]
+async fn reset_ready(
+    _key: ApiKey,
+    _room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+) -> rocket::http::Status {
+    state.ready.reset(&state.events);
+    log::info!("Ready flags reset");
+    rocket::http::Status::Ok
+}
+
+/// Refused with a 409 until every slot that isn't in READY_EXEMPT_SLOTS is ready and connected
+#[rocket::post("/rooms/<_>/start")]
+async fn start(
+    _key: ApiKey,
+    room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+) -> Result<Json<Started>, rocket::http::Status> {
+    let started = admin_ops::start(
+        room.client_registry,
+        &state.slot_names,
+        &state.ready,
+        &state.events,
+        &state.config,
+    )
+    .await;
+    match started {
+        Ok(started) => Ok(Json(started)),
+        Err(StartRefused::NoSlots) => {
+            log::info!("Refusing to start, nobody logged in yet");
+            Err(rocket::http::Status::Conflict)
+        }
+        Err(StartRefused::WaitingOn(slots)) => {
+            log::info!("Refusing to start, waiting on slots {:?}", slots);
+            Err(rocket::http::Status::Conflict)
+        }
+    }
+}
+
+#[rocket::get("/state_snapshot")]
+async fn get_state_snapshot(_key: ApiKey, state: &State<AppState>) -> Json<StateSnapshot> {
+    Json(state.standby.snapshot().await)
+}
+
+#[rocket::post("/state_snapshot", data = "<snapshot>")]
+async fn load_state_snapshot(
+    _key: ApiKey,
+    state: &State<AppState>,
+    snapshot: Json<StateSnapshot>,
+) -> rocket::http::Status {
+    let version = snapshot.version;
+    match state.standby.apply(snapshot.into_inner()).await {
+        Ok(true) => {
+            log::info!("Loaded state snapshot {}", version);
+            rocket::http::Status::Ok
+        }
+        Ok(false) => rocket::http::Status::Ok,
+        Err(e @ SnapshotError::Stale { .. }) => {
+            log::warn!("Refusing state snapshot: {}", e);
+            rocket::http::Status::Conflict
+        }
+        Err(e) => {
+            log::warn!("Refusing state snapshot: {}", e);
+            rocket::http::Status::UnprocessableEntity
+        }
+    }
+}
+
+#[rocket::post("/promote")]
+async fn promote(_key: ApiKey, state: &State<AppState>) -> rocket::http::Status {
+    if state.standby.promote() {
+        log::info!("Promoted to primary, accepting player connections");
+    } else {
+        log::debug!("Promotion requested but this instance is already the primary");
+    }
+    rocket::http::Status::Ok
+}
+
+const DEFAULT_TOKEN_TTL_SECONDS: u64 = 600;
+
+#[derive(Deserialize)]
+pub struct MintTokenRequest {
+    slot: i64,
+    /// Defaults to the proxied room
+    room_id: Option<String>,
+    ttl_seconds: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct MintTokenResponse {
+    token: String,
+    expires_at: u64,
+}
+
+#[rocket::post("/tokens", data = "<request>")]
+async fn mint_token(
+    _key: ApiKey,
+    state: &State<AppState>,
+    request: Json<MintTokenRequest>,
+) -> Result<Json<MintTokenResponse>, rocket::http::Status> {
+    let Some(key) = state.config.token_secret.as_deref().map(TokenKey::new) else {
+        log::warn!("Token requested but TOKEN_SECRET isn't set");
+        return Err(rocket::http::Status::NotFound);
+    };
+
+    let request = request.into_inner();
+    let claims = TokenClaims {
+        room_id: request
+            .room_id
+            .unwrap_or_else(|| state.config.room_id.clone()),
+        slot: SlotId(request.slot),
+        exp: token::now().saturating_add(request.ttl_seconds.unwrap_or(DEFAULT_TOKEN_TTL_SECONDS)),
+    };
+    log::info!(
+        "Minted a token for slot {} of room {}",
+        claims.slot.0,
+        claims.room_id
+    );
+    Ok(Json(MintTokenResponse {
+        token: key.mint(&claims),
+        expires_at: claims.exp,
+    }))
+}
+
+#[rocket::get("/rooms/<_>/password_failures")]
+async fn get_password_failures(
+    _key: ApiKey,
+    _room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+) -> Json<Vec<PasswordFailure>> {
+    Json(state.password_failures.recent())
+}
+
+/// 404 unless SLOT_CLAIMING is set
+#[rocket::get("/rooms/<_>/claims")]
+async fn get_claims(
+    _key: ApiKey,
+    _room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+) -> Result<Json<Vec<ClaimedSlot>>, rocket::http::Status> {
+    let claims = state
+        .slot_claims
+        .as_ref()
+        .ok_or(rocket::http::Status::NotFound)?;
+    Ok(Json(claims.claimed(chrono::Utc::now())))
+}
+
+/// Lets another client log in to the slot, the next one to do so claims it
+#[rocket::delete("/rooms/<_>/claims/<slot>")]
+async fn clear_claim(
+    _key: ApiKey,
+    _room: PrimaryRoom<'_>,
+    state: &State<AppState>,
+    slot: i64,
+) -> rocket::http::Status {
+    let Some(claims) = &state.slot_claims else {
+        return rocket::http::Status::NotFound;
+    };
+    if claims.clear(SlotId(slot)) {
+        log::info!("Cleared the claim on slot {}", slot);
+        rocket::http::Status::Ok
+    } else {
+        log::debug!("Slot {} isn't claimed", slot);
+        rocket::http::Status::NotFound
+    }
+}
+
+#[rocket::get("/selftest")]
+async fn get_selftest(
+    _key: ApiKey,
+    state: &State<AppState>,
+) -> Result<Json<SelfTestReport>, rocket::http::Status> {
+    match &*state.selftest.read().await {
+        Some(report) => Ok(Json(report.clone())),
+        None => Err(rocket::http::Status::NotFound),
+    }
+}
+
+#[rocket::post("/selftest")]
+async fn run_selftest(_key: ApiKey, state: &State<AppState>) -> Json<SelfTestReport> {
+    let report = selftest::run(&state.config.ap_server.url(), &state.config.selftest).await;
+    if !report.ok {
+        log::warn!(
+            "Upstream self-test failed at {:?}: {}",
+            report.failed_step,
+            report.error.as_deref().unwrap_or_default()
+        );
+    }
+    *state.selftest.write().await = Some(report.clone());
+    Json(report)
+}
+
+/// Same as SIGHUP. Invalid settings leave the running configuration as it is.
+#[rocket::post("/reload_config")]
+async fn reload_config(
+    _key: ApiKey,
+    state: &State<AppState>,
+) -> Result<Json<ReloadReport>, rocket::http::Status> {
+    match state.reloader.reload().await {
+        Ok(report) => Ok(Json(report)),
+        Err(e) => {
+            log::error!("Failed to reload the configuration: {:?}", e);
+            Err(rocket::http::Status::UnprocessableEntity)
+        }
+    }
+}
+
+/// Rules of TRANSFORMS_PATH new connections apply
+#[rocket::get("/transforms")]
+fn get_transforms(_key: ApiKey, state: &State<AppState>) -> Json<Transforms> {
+    Json(state.live.snapshot().transforms.clone())
+}
+
+#[rocket::get("/diagnostics")]
+async fn get_diagnostics(_key: ApiKey, state: &State<AppState>) -> Json<Diagnostics> {
+    Json(diagnostics::collect(&state.events, &state.load_shedding))
+}
+
+#[derive(Deserialize)]
+pub struct DegradedModeRequest {
+    mode: Mode,
+}
+
+/// Forces degraded mode on or off, `auto` hands it back to the message rate
+#[rocket::put("/degraded_mode", data = "<request>")]
+async fn set_degraded_mode(
+    _key: ApiKey,
+    state: &State<AppState>,
+    request: Json<DegradedModeRequest>,
+) -> Json<DegradedStatus> {
+    log::info!("Degraded mode set to {:?}", request.mode);
+    state.load_shedding.set_mode(request.mode);
+    Json(state.load_shedding.status())
+}
+
+#[rocket::get("/retention")]
+async fn get_retention(
+    _key: ApiKey,
+    state: &State<AppState>,
+) -> Result<Json<Vec<TableRetention>>, rocket::http::Status> {
+    match retention::tables(&state.db_pool, &state.config.retention).await {
+        Ok(tables) => Ok(Json(tables)),
+        Err(e) => {
+            log::error!("Failed to get estimated row counts: {:?}", e);
+            Err(rocket::http::Status::InternalServerError)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PruneRequest {
+    table: String,
+}
+
+#[derive(Serialize)]
+pub struct PruneResponse {
+    deleted: usize,
+}
+
+/// Prunes the table right away instead of waiting for the next hourly run
+#[rocket::post("/retention/prune", data = "<request>")]
+async fn prune_table(
+    _key: ApiKey,
+    state: &State<AppState>,
+    request: Json<PruneRequest>,
+) -> Result<Json<PruneResponse>, rocket::http::Status> {
+    let table = request.table.as_str();
+    if !crate::db::models::PRUNABLE_TABLES.contains(&table) {
+        return Err(rocket::http::Status::NotFound);
+    }
+    match retention::prune(
+        &state.db_pool,
+        &state.config.retention,
+        table,
+        chrono::Utc::now(),
+    )
+    .await
+    {
+        Ok(Some(deleted)) => Ok(Json(PruneResponse { deleted })),
+        // Kept forever, there's nothing to prune past
+        Ok(None) => Err(rocket::http::Status::UnprocessableEntity),
+        Err(e) => {
+            log::error!("Failed to prune {}: {:?}", table, e);
+            Err(rocket::http::Status::InternalServerError)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Health {
+    /// The worst of the checks below
+    status: ExpiryStatus,
+    /// Only when the certificate was loaded from disk
+    tls: Option<CertExpiry>,
+    /// Rooms still on their previous passwords after a refresh was refused, a warning
+    rejected_password_refreshes: BTreeMap<String, RejectedRefresh>,
+    /// Rooms whose AP server accepts connections without ever sending RoomInfo, critical
+    unresponsive_upstreams: Vec<String>,
+}
+
+/// Doesn't need the API key so monitors can poll it
+#[rocket::get("/health")]
+async fn get_health(state: &State<AppState>) -> Json<Health> {
+    let tls = tls::cert_expiry();
+    let rejected_password_refreshes = state.lobby.rejected_refreshes();
+    let mut status = tls
+        .as_ref()
+        .map_or(ExpiryStatus::Ok, |expiry| expiry.status);
+    if !rejected_password_refreshes.is_empty() {
+        status = status.max(ExpiryStatus::Warning);
+    }
+    let mut unresponsive_upstreams: Vec<String> = state
+        .rooms
+        .iter()
+        .filter(|(_, route)| route.upstream.is_unresponsive())
+        .map(|(room_id, _)| room_id.clone())
+        .collect();
+    if state.upstream.is_unresponsive() {
+        unresponsive_upstreams.push(state.config.room_id.clone());
+    }
+    unresponsive_upstreams.sort_unstable();
+    if !unresponsive_upstreams.is_empty() {
+        status = ExpiryStatus::Critical;
+    }
+    Json(Health {
+        status,
+        tls,
+        rejected_password_refreshes,
+        unresponsive_upstreams,
+    })
+}
+
+/// `from` and `to` are inclusive `YYYY-MM-DD` days
+#[rocket::get("/rooms/<_>/daily_stats?<from>&<to>")]
+async fn get_daily_stats(
+    _key: ApiKey,
+    room: RoomRef<'_>,
+    state: &State<AppState>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Json<Vec<crate::db::models::DailyStats>>, rocket::http::Status> {
+    let (from, to) = (parse_day(from)?, parse_day(to)?);
+    let room_id = room.room_id;
+
+    match crate::db::models::get_room_daily_stats(&state.db_pool, room_id, from, to).await {
+        Ok(stats) => Ok(Json(stats)),
+        Err(e) => {
+            log::error!("Failed to get daily stats for room {}: {:?}", room_id, e);
+            Err(rocket::http::Status::InternalServerError)
+        }
+    }
+}
+
+// No Debug on purpose, the password must not end up in logs
+#[derive(Deserialize)]
+pub struct ValidatePasswordRequest {
+    slot: i64,
+    password: String,
+}
+
+#[derive(Serialize)]
+pub struct ValidatePasswordResponse {
+    slot: SlotId,
+    password_required: bool,
+    matches: bool,
+}
+
+/// Checks a password against the currently loaded map the same way logins are, so lobby and
+/// proxy disagreeing can be told apart from a player mistyping
+#[rocket::post("/rooms/<_>/validate_password", data = "<request>")]
+async fn validate_password(
+    _key: ApiKey,
+    room: RoomRef<'_>,
+    request: Json<ValidatePasswordRequest>,
+) -> Json<ValidatePasswordResponse> {
+    let slot = SlotId(request.slot);
+    let passwords = room.passwords.read().await;
+    let (password_required, matches) = match passwords.get(&slot) {
+        Some(expected) if !expected.is_empty() => (true, *expected == request.password),
+        Some(_) | None => (false, true),
+    };
+    log::info!(
+        "Validated a password for slot {}: required={}, matches={}",
+        slot.0,
+        password_required,
+        matches
+    );
+    Json(ValidatePasswordResponse {
+        slot,
+        password_required,
+        matches,
+    })
+}
+
+// No Debug on purpose, the password must not end up in logs
+#[derive(Deserialize)]
+pub struct VerifyAttemptRequest {
+    attempt_id: i32,
+    password: String,
+}
+
+#[derive(Serialize)]
+pub struct VerifyAttemptResponse {
+    attempt_id: i32,
+    slot: Option<i32>,
+    name: String,
+    outcome: String,
+    matches: bool,
+}
+
+/// Whether a recorded login attempt was made with `password`, to settle a player saying they
+/// typed the right one. Only attempts recorded with the current AUDIT_HMAC_KEY can be told.
+#[rocket::post("/rooms/<_>/verify_attempt", data = "<request>")]
+async fn verify_attempt(
+    _key: ApiKey,
+    room: RoomRef<'_>,
+    state: &State<AppState>,
+    request: Json<VerifyAttemptRequest>,
+) -> Result<Json<VerifyAttemptResponse>, rocket::http::Status> {
+    let Some(key) = state.config.audit_hmac_key.as_deref().map(AuditKey::new) else {
+        log::warn!("Attempt verification requested but AUDIT_HMAC_KEY isn't set");
+        return Err(rocket::http::Status::NotFound);
+    };
+    let attempt =
+        match crate::db::models::get_connection_attempt(&state.db_pool, request.attempt_id).await {
+            Ok(Some(attempt)) if attempt.room_id == room.room_id => attempt,
+            Ok(_) => return Err(rocket::http::Status::NotFound),
+            Err(e) => {
+                log::error!(
+                    "Failed to get connection attempt {}: {:?}",
+                    request.attempt_id,
+                    e
+                );
+                return Err(rocket::http::Status::InternalServerError);
+            }
+        };
+    // Recorded while AUDIT_HMAC_KEY wasn't set
+    let Some(recorded) = &attempt.attempt_hmac else {
+        return Err(rocket::http::Status::Conflict);
+    };
+    let matches = key.matches(recorded, &attempt.name, &request.password);
+    log::info!(
+        "Verified connection attempt {} of {}: matches={}",
+        attempt.id,
+        attempt.name,
+        matches
+    );
+    Ok(Json(VerifyAttemptResponse {
+        attempt_id: attempt.id,
+        slot: attempt.slot,
+        name: attempt.name,
+        outcome: attempt.outcome,
+        matches,
+    }))
+}
+
+/// The `X-Bridge-Signature` of a request, if it has one
+struct BridgeSignature(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BridgeSignature {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let signature = req.headers().get_one(bridge::SIGNATURE_HEADER);
+        Outcome::Success(BridgeSignature(signature.map(str::to_string)))
+    }
+}
+
+/// Chat the peer room of a bridge relays, shown to everyone logged in to the primary room. The
+/// peer signs it with BRIDGE_SHARED_SECRET rather than holding an API key.
+#[rocket::post("/bridge", data = "<body>")]
+async fn receive_bridged_chat(
+    signature: BridgeSignature,
+    state: &State<AppState>,
+    body: Vec<u8>,
+) -> Result<(), rocket::http::Status> {
+    let Some(key) = state
+        .config
+        .bridge_shared_secret
+        .as_deref()
+        .map(BridgeKey::new)
+    else {
+        log::warn!("Received bridged chat but BRIDGE_SHARED_SECRET isn't set");
+        return Err(rocket::http::Status::NotFound);
+    };
+    let BridgeSignature(Some(signature)) = signature else {
+        return Err(rocket::http::Status::Unauthorized);
+    };
+    let delivered = bridge::deliver(
+        &key,
+        &state.config.room_id,
+        &state.client_registry,
+        &body,
+        &signature,
+    )
+    .await;
+    match delivered {
+        Ok(clients) => {
+            log::debug!("Showed bridged chat to {} clients", clients);
+            Ok(())
+        }
+        Err(refused) => {
+            log::warn!("Refusing bridged chat: {}", refused);
+            Err(match refused {
+                Refused::BadSignature => rocket::http::Status::Unauthorized,
+                Refused::Malformed | Refused::TooLong => rocket::http::Status::BadRequest,
+                Refused::OwnRoom => rocket::http::Status::Conflict,
+            })
+        }
+    }
+}
+
+/// Paths from before routes were scoped to a room, relative to [`BASE`]. They lead to the same
+/// path under the primary room.
+const LEGACY_ROUTES: &[(Method, &str)] = &[
+    (Method::Post, "/refresh_passwords"),
+    (Method::Get, "/deathlink_exclusions"),
+    (Method::Post, "/deathlink_exclusions/<slot>"),
+    (Method::Put, "/deathlink_exclusions/<slot>"),
+    (Method::Delete, "/deathlink_exclusions/<slot>"),
+    (Method::Get, "/deathlink_probability"),
+    (Method::Put, "/deathlink_probability"),
+    (Method::Get, "/deathlink_summary"),
+    (Method::Get, "/preferences"),
+    (Method::Put, "/preferences/<slot>"),
+    (Method::Get, "/events"),
+    (Method::Get, "/motd"),
+    (Method::Put, "/motd"),
+    (Method::Get, "/scheduled_messages"),
+    (Method::Post, "/scheduled_messages"),
+    (Method::Put, "/scheduled_messages/<id>"),
+    (Method::Delete, "/scheduled_messages/<id>"),
+    (Method::Post, "/clients/<slot>/reconnect_upstream"),
+    (Method::Get, "/slots"),
+    (Method::Get, "/clients"),
+    (Method::Get, "/clients/<slot>"),
+    (Method::Get, "/progress"),
+    (Method::Get, "/pace"),
+    (Method::Get, "/password_failures"),
+    (Method::Post, "/validate_password"),
+    (Method::Post, "/verify_attempt"),
+    (Method::Get, "/claims"),
+    (Method::Delete, "/claims/<slot>"),
+];
+
+/// `path` under `/rooms/<room_id>`, absolute
+fn room_path(room_id: &str, path: &str) -> String {
+    format!(
+        "{}/rooms/{}{}",
+        BASE,
+        RawStr::new(room_id).percent_encode(),
+        path
+    )
+}
+
+/// Temporary redirects keep the method and body, and the primary room can change between
+/// restarts anyway
+#[derive(Clone)]
+struct LegacyRedirect;
+
+#[rocket::async_trait]
+impl rocket::route::Handler for LegacyRedirect {
+    async fn handle<'r>(
+        &self,
+        req: &'r rocket::Request<'_>,
+        _data: rocket::Data<'r>,
+    ) -> rocket::route::Outcome<'r> {
+        let state = req.guard::<&State<AppState>>().await.unwrap();
+        let uri = req.uri();
+        let path = uri.path().as_str();
+        let mut target = room_path(
+            &state.config.room_id,
+            path.strip_prefix(BASE).unwrap_or(path),
+        );
+        if let Some(query) = uri.query() {
+            target.push('?');
+            target.push_str(query.as_str());
+        }
+        rocket::route::Outcome::from(req, Redirect::temporary(target))
+    }
+}
+
+/// Every deathlink of the room in a single array, as this answered before the room path was
+/// paged. Old clients read it that way, so it isn't a redirect.
+#[rocket::get("/deathlinks/<_>")]
+async fn legacy_room_deathlinks(
+    _key: ApiKey,
+    room: RoomRef<'_>,
+    state: &State<AppState>,
+) -> Result<Json<Vec<DeathLink>>, rocket::http::Status> {
+    let rows = crate::db::models::stream_room_deathlinks(
+        state.db_pool.clone(),
+        room.room_id.to_string(),
+        HistoryFilter::default(),
+    );
+    match rows.try_collect().await {
+        Ok(deathlinks) => Ok(Json(deathlinks)),
+        Err(e) => {
+            log::error!(
+                "Failed to get deathlinks for room {}: {:?}",
+                room.room_id,
+                e
+            );
+            Err(rocket::http::Status::InternalServerError)
+        }
+    }
+}
+
+/// The room used to be a query parameter, defaulting to the primary room
+#[rocket::get("/daily_stats?<room_id>")]
+fn legacy_daily_stats(
+    state: &State<AppState>,
+    uri: &rocket::http::uri::Origin<'_>,
+    room_id: Option<&str>,
+) -> Redirect {
+    let mut target = room_path(room_id.unwrap_or(&state.config.room_id), "/daily_stats");
+    let query: Vec<&str> = uri
+        .query()
+        .map(|query| query.as_str())
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty() && !pair.starts_with("room_id="))
+        .collect();
+    if !query.is_empty() {
+        target.push('?');
+        target.push_str(&query.join("&"));
+    }
+    Redirect::temporary(target)
+}
+
+/// Rocket configuration for where the admin API listens, over `figment`. Rocket binds to
+/// localhost when nothing says otherwise.
+pub fn listener_figment(figment: Figment, config: &Config) -> anyhow::Result<Figment> {
+    let mut figment = figment;
+    if let Some(addr) = config.api_bind_addr {
+        figment = figment.merge(("address", addr));
+    }
+    if let Some(port) = config.api_port {
+        figment = figment.merge(("port", port));
+    }
+    match (&config.api_tls_cert_path, &config.api_tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            figment = figment
+                .merge(("tls.certs", cert_path))
+                .merge(("tls.key", key_path));
+        }
+        (None, None) => {}
+        _ => anyhow::bail!("API_TLS_CERT_PATH and API_TLS_KEY_PATH must be set together"),
+    }
+
+    let addr = figment
+        .extract_inner::<IpAddr>("address")
+        .unwrap_or(Ipv4Addr::LOCALHOST.into());
+    let port = figment.extract_inner::<u16>("port").unwrap_or(8000);
+    if config.api_require_private && !net::is_private(addr) {
+        anyhow::bail!(
+            "The admin API would listen on public address {} but API_REQUIRE_PRIVATE is set",
+            addr
+        );
+    }
+    let tls = if figment.find_value("tls").is_ok() {
+        "with"
+    } else {
+        "without"
+    };
+    log::info!(
+        "Admin API listening on {} {} TLS",
+        SocketAddr::new(addr, port),
+        tls
+    );
+    Ok(figment)
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    let legacy = LEGACY_ROUTES
+        .iter()
+        .map(|(method, path)| rocket::Route::new(*method, path, LegacyRedirect));
+    let mut routes = rocket::routes![
+        legacy_room_deathlinks,
+        legacy_daily_stats,
+        refresh_passwords,
+        get_deathlink_exclusions,
+        add_deathlink_exclusion,
+        put_deathlink_exclusion,
+        remove_deathlink_exclusion,
+        get_room_deathlinks,
+        get_room_deathlinks_csv,
+        get_deathlink_summary,
+        get_room_countdowns,
+        get_countdown_summary,
+        get_room_sessions,
+        get_room_sessions_csv,
+        get_deathlink_probability,
+        set_deathlink_probability,
+        get_deferred_datapackage_games,
+        add_deferred_datapackage_game,
+        remove_deferred_datapackage_game,
+        get_events,
+        admin_console,
+        get_preferences,
+        set_preferences,
+        get_motd,
+        set_motd,
+        get_record_bounces,
+        set_record_bounces,
+        get_bounces,
+        get_scheduled_messages,
+        add_scheduled_message,
+        update_scheduled_message,
+        remove_scheduled_message,
+        reconnect_upstream,
+        get_slots,
+        add_slot_note,
+        get_slot_notes,
+        delete_slot_note,
+        get_clients,
+        get_slot_clients,
+        get_queue,
+        promote_queued_login,
+        get_progress,
+        get_pace,
+        get_ready,
+        reset_ready,
+        start,
+        get_state_snapshot,
+        load_state_snapshot,
+        promote,
+        mint_token,
+        get_password_failures,
+        get_claims,
+        clear_claim,
+        validate_password,
+        verify_attempt,
+        receive_bridged_chat,
+        get_daily_stats,
+        get_selftest,
+        run_selftest,
+        reload_config,
+        get_transforms,
+        get_diagnostics,
+        set_degraded_mode,
+        get_retention,
+        prune_table,
+        get_health,
+    ];
+    routes.extend(legacy);
+    routes
+}
+
+#[derive(Clone)]
+pub struct MetricsRoute(pub std::sync::Arc<crate::metrics::Registry>);
+
+#[rocket::async_trait]
+impl rocket::route::Handler for MetricsRoute {
+    async fn handle<'r>(
+        &self,
+        req: &'r rocket::Request<'_>,
+        _data: rocket::Data<'r>,
+    ) -> rocket::route::Outcome<'r> {
+        let rocket::outcome::Outcome::Success(_api_key) = req.guard::<ApiKey>().await else {
+            return rocket::route::Outcome::Error(rocket::http::Status::Unauthorized);
+        };
+
+        match self.0.encode() {
+            Ok(body) => rocket::route::Outcome::from(req, (rocket::http::ContentType::Plain, body)),
+            Err(e) => {
+                log::error!("Failed to encode metrics: {:?}", e);
+                rocket::route::Outcome::Error(rocket::http::Status::InternalServerError)
+            }
+        }
+    }
+}
+
+impl From<MetricsRoute> for Vec<rocket::Route> {
+    fn from(val: MetricsRoute) -> Self {
+        vec![rocket::Route::new(rocket::http::Method::Get, "/", val)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DataPackageCache;
+    use crate::bounce_log::BounceRecorder;
+    use crate::claims::SlotClaims;
+    use crate::config::tests::{test_config, test_vars};
+    use crate::coordination::Coordinator;
+    use crate::flapping::FlapLimits;
+    use crate::lobby::LobbyClient;
+    use crate::proxy::RoomRoute;
+    use crate::reload::{Live, LiveSettings, Reloader};
+    use crate::scheduled_messages::ScheduledMessages;
+    use crate::shedding::LoadShedding;
+    use crate::standby::{SoftState, Standby};
+    use crate::upstream::UpstreamServers;
+    use diesel_async::AsyncPgConnection;
+    use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+    use rocket::http::{Header, Status};
+    use rocket::local::asynchronous::{Client, LocalRequest};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Primary room `main` with a `hello` motd, and `race` routed to another AP server. The
+    /// database is never reached, only routes keeping their state in memory are usable.
+    async fn client() -> Client {
+        client_with(test_config("main")).await
+    }
+
+    /// `TEST_DATABASE_URL`, once the migrations ran on it. Tests calling this are `#[ignore]`d,
+    /// run them with `TEST_DATABASE_URL=... cargo test -- --ignored`.
+    async fn test_database_url() -> String {
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is set");
+        // The migrations run on a connection of their own, away from the test's runtime
+        rocket::tokio::task::spawn_blocking(|| drop(crate::db::tests::test_database()))
+            .await
+            .unwrap();
+        url
+    }
+
+    async fn client_with(config: Config) -> Client {
+        let db_pool = crate::db::DieselPool::builder(AsyncDieselConnectionManager::<
+            AsyncPgConnection,
+        >::new(&config.db_url))
+        .build()
+        .unwrap();
+        let motd = Arc::new(RwLock::new(Some("hello".to_string())));
+        let soft_state = SoftState {
+            deathlink_exclusions: Default::default(),
+            deathlink_probability: Default::default(),
+            deferred_datapackage_games: Default::default(),
+            preferences: Default::default(),
+            motd: motd.clone(),
+        };
+        let race = RoomRoute {
+            upstream: Arc::new(UpstreamServers::new(
+                "127.0.0.1:1".parse().unwrap(),
+                Vec::new(),
+                Duration::ZERO,
+            )),
+            passwords: Arc::new(RwLock::new(HashMap::from([(SlotId(1), "pw".into())]))),
+            datapackage_cache: Arc::new(
+                DataPackageCache::from_response(serde_json::json!({})).unwrap(),
+            ),
+            client_registry: Arc::new(ClientRegistry::new(FlapLimits::default())),
+            login_queue: Arc::new(LoginQueue::new("race", None, Duration::ZERO)),
+            slot_groups: Default::default(),
+        };
+        let live = Live::new(LiveSettings::from(&config));
+        let reloader = Arc::new(Reloader::new(test_vars(&[]), live.clone(), motd.clone()));
+        let slot_names: Arc<RwLock<HashMap<SlotId, String>>> = Default::default();
+        let bounce_recorder = Arc::new(BounceRecorder::new(
+            config.record_bounces.clone(),
+            config.bounce_daily_cap,
+        ));
+        let coordinator = config.coordinate_instances.then(|| {
+            Arc::new(Coordinator::new(
+                config.room_id.clone(),
+                db_pool.clone(),
+                soft_state.clone(),
+                slot_names.clone(),
+                bounce_recorder.clone(),
+            ))
+        });
+        let state = AppState {
+            passwords: Default::default(),
+            deathlink_exclusions: soft_state.deathlink_exclusions.clone(),
+            deathlink_probability: soft_state.deathlink_probability.clone(),
+            deferred_datapackage_games: soft_state.deferred_datapackage_games.clone(),
+            slot_names,
+            preferences: soft_state.preferences.clone(),
+            slot_groups: Default::default(),
+            motd,
+            bounce_recorder,
+            scheduled_messages: Arc::new(ScheduledMessages::new(
+                config.scheduled_messages.0.clone(),
+                chrono::Utc::now(),
+            )),
+            db_pool,
+            events: crate::events::EventBus::new(),
+            client_registry: Arc::new(ClientRegistry::new(FlapLimits::default())),
+            login_queue: Arc::new(LoginQueue::new(
+                &config.room_id,
+                config.max_logged_in_clients,
+                config.login_queue_max_wait,
+            )),
+            load_shedding: Arc::new(LoadShedding::new(None, Duration::ZERO)),
+            standby: Arc::new(Standby::new("main".into(), soft_state, false)),
+            password_failures: Arc::new(crate::password_audit::PasswordFailures::new(0)),
+            slot_claims: config
+                .slot_claiming
+                .then(|| Arc::new(SlotClaims::new(config.claim_ttl))),
+            coordinator,
+            ready: Default::default(),
+            selftest: Default::default(),
+            upstream: Arc::new(UpstreamServers::new(
+                "127.0.0.1:1".parse().unwrap(),
+                Vec::new(),
+                Duration::ZERO,
+            )),
+            rooms: Arc::new(HashMap::from([("race".to_string(), race)])),
+            lobby: Arc::new(LobbyClient::new(&config)),
+            live,
+            reloader,
+            config,
+        };
+        let rocket = rocket::build()
+            .manage(state)
+            .mount(BASE, routes())
+            .register(BASE, catchers());
+        Client::untracked(rocket).await.unwrap()
+    }
+
+    fn api_key() -> Header<'static> {
+        Header::new("X-Api-Key", "key")
+    }
+
+    async fn get_json(client: &Client, path: &str) -> (Status, serde_json::Value) {
+        dispatch_json(client.get(path.to_string())).await
+    }
+
+    async fn dispatch_json(request: LocalRequest<'_>) -> (Status, serde_json::Value) {
+        let response = request.header(api_key()).dispatch().await;
+        let status = response.status();
+        (status, response.into_json().await.unwrap_or_default())
+    }
+
+    #[rocket::async_test]
+    async fn test_room_paths() {
+        let client = client().await;
+        let (status, motd) = get_json(&client, "/api/rooms/main/motd").await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(motd["motd"], "hello");
+
+        // Routed rooms have their own passwords and clients
+        let (status, clients) = get_json(&client, "/api/rooms/race/clients").await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(clients, serde_json::json!([]));
+        let response = client
+            .post("/api/rooms/race/validate_password")
+            .header(api_key())
+            .body(r#"{"slot": 1, "password": "pw"}"#)
+            .dispatch()
+            .await;
+        let validation: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(validation["password_required"], true);
+        assert_eq!(validation["matches"], true);
+
+        // But no settings
+        let (status, error) = get_json(&client, "/api/rooms/race/motd").await;
+        assert_eq!(status, Status::NotFound);
+        assert_eq!(error["room_id"], "race");
+
+        let (status, error) = get_json(&client, "/api/rooms/nope/motd").await;
+        assert_eq!(status, Status::NotFound);
+        assert_eq!(error["error"], "Unknown room nope");
+        assert_eq!(error["room_id"], serde_json::Value::Null);
+
+        let response = client.get("/api/rooms/main/motd").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn test_each_refresh_publishes_the_changed_slots() {
+        use crate::events::RoomEvent;
+        use crate::lobby::RefreshedSlot;
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let lobby = MockServer::start().await;
+        let slots = |bob: Option<&str>| {
+            ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {"slot_number": 1, "player_name": "Alice", "password": "a"},
+                {"slot_number": 2, "player_name": "Bob", "password": bob},
+            ]))
+        };
+        for (bob, times) in [(None, 2), (Some("b"), 1)] {
+            Mock::given(method("GET"))
+                .and(path("/api/room/main/slots_passwords"))
+                .respond_with(slots(bob))
+                .up_to_n_times(times)
+                .mount(&lobby)
+                .await;
+        }
+        let client = client_with(Config {
+            lobby_root_url: lobby.uri().parse().unwrap(),
+            ..test_config("main")
+        })
+        .await;
+        let mut events = client
+            .rocket()
+            .state::<AppState>()
+            .unwrap()
+            .events
+            .subscribe();
+
+        let mut refresh = async || {
+            let response = client
+                .post("/api/rooms/main/refresh_passwords")
+                .header(api_key())
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            let changed = match events.try_recv() {
+                Ok(RoomEvent::PasswordsRefreshed {
+                    changed_slots,
+                    total,
+                }) => {
+                    assert_eq!(total, 2);
+                    changed_slots
+                }
+                other => panic!("Expected a refresh, got {:?}", other),
+            };
+            assert!(events.try_recv().is_err());
+            changed
+                .into_iter()
+                .map(|RefreshedSlot { slot, name }| (slot.0, name))
+                .collect::<Vec<_>>()
+        };
+        let names = |slots: &[(i64, &str)]| {
+            slots
+                .iter()
+                .map(|(slot, name)| (*slot, name.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(refresh().await, names(&[(1, "Alice"), (2, "Bob")]));
+        assert_eq!(refresh().await, names(&[]));
+        assert_eq!(refresh().await, names(&[(2, "Bob")]));
+    }
+
+    #[rocket::async_test]
+    async fn test_suspicious_refreshes_need_forcing() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let lobby = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/room/main/slots_passwords"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([])))
+            .mount(&lobby)
+            .await;
+        let client = client_with(Config {
+            lobby_root_url: lobby.uri().parse().unwrap(),
+            ..test_config("main")
+        })
+        .await;
+        let passwords = client
+            .rocket()
+            .state::<AppState>()
+            .unwrap()
+            .passwords
+            .clone();
+        let refresh = async |uri: &str| {
+            client
+                .post(uri.to_string())
+                .header(api_key())
+                .dispatch()
+                .await
+                .status()
+        };
+
+        assert_eq!(
+            refresh("/api/rooms/main/refresh_passwords").await,
+            Status::Conflict
+        );
+        assert_eq!(passwords.read().await.len(), 1);
+        let (_, health) = get_json(&client, "/api/health").await;
+        assert_eq!(health["status"], "warning");
+        assert_eq!(
+            health["rejected_password_refreshes"]["main"]["reason"],
+            "no_slots"
+        );
+
+        assert_eq!(
+            refresh("/api/rooms/main/refresh_passwords?force=true").await,
+            Status::Ok
+        );
+        assert!(passwords.read().await.is_empty());
+        let (_, health) = get_json(&client, "/api/health").await;
+        assert_eq!(health["rejected_password_refreshes"], serde_json::json!({}));
+    }
+
+    #[test]
+    fn test_admin_listener() {
+        let listener = |config: Config| listener_figment(Figment::new(), &config);
+        let figment = listener(Config {
+            api_bind_addr: Some("10.0.0.2".parse().unwrap()),
+            api_port: Some(9000),
+            api_tls_cert_path: Some("cert.pem".into()),
+            api_tls_key_path: Some("key.pem".into()),
+            api_require_private: true,
+            ..test_config("main")
+        })
+        .unwrap();
+        assert_eq!(
+            figment.extract_inner::<String>("address").unwrap(),
+            "10.0.0.2"
+        );
+        assert_eq!(figment.extract_inner::<u16>("port").unwrap(), 9000);
+        assert_eq!(
+            figment.extract_inner::<String>("tls.key").unwrap(),
+            "key.pem"
+        );
+
+        // Rocket's default address is private
+        assert!(
+            listener(Config {
+                api_require_private: true,
+                ..test_config("main")
+            })
+            .is_ok()
+        );
+        assert!(
+            listener(Config {
+                api_bind_addr: Some("0.0.0.0".parse().unwrap()),
+                api_require_private: true,
+                ..test_config("main")
+            })
+            .is_err()
+        );
+        assert!(
+            listener(Config {
+                api_tls_cert_path: Some("cert.pem".into()),
+                ..test_config("main")
+            })
+            .is_err()
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_legacy_paths_redirect() {
+        let client = client().await;
+        let redirects = [
+            (client.get("/api/motd"), "/api/rooms/main/motd"),
+            (
+                client.delete("/api/scheduled_messages/2"),
+                "/api/rooms/main/scheduled_messages/2",
+            ),
+            (
+                client.put("/api/preferences/3"),
+                "/api/rooms/main/preferences/3",
+            ),
+            (
+                client.post("/api/clients/3/reconnect_upstream"),
+                "/api/rooms/main/clients/3/reconnect_upstream",
+            ),
+            (client.get("/api/clients/3"), "/api/rooms/main/clients/3"),
+            (
+                client.put("/api/deathlink_exclusions/3"),
+                "/api/rooms/main/deathlink_exclusions/3",
+            ),
+            (client.delete("/api/claims/3"), "/api/rooms/main/claims/3"),
+            (client.get("/api/pace"), "/api/rooms/main/pace"),
+            (
+                client.get("/api/deathlink_summary"),
+                "/api/rooms/main/deathlink_summary",
+            ),
+            (
+                client.post("/api/verify_attempt"),
+                "/api/rooms/main/verify_attempt",
+            ),
+            (
+                client.get("/api/daily_stats"),
+                "/api/rooms/main/daily_stats",
+            ),
+            (
+                client.get("/api/daily_stats?from=2026-04-01&room_id=race&to=2026-04-02"),
+                "/api/rooms/race/daily_stats?from=2026-04-01&to=2026-04-02",
+            ),
+        ];
+        for (request, location) in redirects {
+            let response = request.dispatch().await;
+            assert_eq!(response.status(), Status::TemporaryRedirect);
+            assert_eq!(response.headers().get_one("Location"), Some(location));
+        }
+
+        // The redirect leads to the same response as before
+        let response = client.get("/api/motd").header(api_key()).dispatch().await;
+        let location = response.headers().get_one("Location").unwrap().to_string();
+        let (status, motd) = get_json(&client, &location).await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(motd["motd"], "hello");
+    }
+
+    #[test]
+    fn test_history_filenames() {
+        let day = |day: &str| Some(day.parse().unwrap());
+        let filter = HistoryFilter {
+            from: day("2026-04-01"),
+            to: day("2026-04-30"),
+            ..Default::default()
+        };
+        assert_eq!(
+            history_filename("main", "deathlinks", &filter),
+            "main-deathlinks-2026-04-01-to-2026-04-30.csv"
+        );
+        let filter = HistoryFilter {
+            to: day("2026-04-30"),
+            ..Default::default()
+        };
+        assert_eq!(
+            history_filename("main", "sessions", &filter),
+            "main-sessions-to-2026-04-30.csv"
+        );
+        assert_eq!(
+            history_filename("main", "sessions", &HistoryFilter::default()),
+            "main-sessions.csv"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_csv_downloads_reject_bad_days() {
+        let client = client().await;
+        for path in [
+            "/api/rooms/main/deathlinks.csv?from=yesterday",
+            "/api/rooms/main/sessions.csv?to=2026-13-01",
+        ] {
+            let response = client.get(path).header(api_key()).dispatch().await;
+            assert_eq!(response.status(), Status::BadRequest, "{}", path);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_deathlink_exclusions_are_a_list_of_slots() {
+        let client = client().await;
+        let state = client.rocket().state::<AppState>().unwrap();
+        state
+            .deathlink_exclusions
+            .write()
+            .await
+            .extend([SlotId(4), SlotId(2)]);
+
+        let (status, exclusions) = get_json(&client, "/api/rooms/main/deathlink_exclusions").await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(exclusions, serde_json::json!([2, 4]));
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn test_deathlink_exclusion_toggles_are_idempotent() {
+        let db_url = test_database_url().await;
+        let room = format!("exclusions-{}", uuid::Uuid::new_v4());
+        let client = client_with(Config {
+            db_url,
+            ..test_config(&room)
+        })
+        .await;
+        let exclusion = |slot: i64| format!("/api/rooms/{}/deathlink_exclusions/{}", room, slot);
+
+        for _ in 0..2 {
+            let (status, exclusions) = dispatch_json(client.put(exclusion(3))).await;
+            assert_eq!(status, Status::Ok);
+            assert_eq!(exclusions, serde_json::json!([3]));
+        }
+        // Not excluded to begin with
+        let (status, exclusions) = dispatch_json(client.delete(exclusion(5))).await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(exclusions, serde_json::json!([3]));
+        let (status, exclusions) = dispatch_json(client.delete(exclusion(3))).await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(exclusions, serde_json::json!([]));
+    }
+
+    #[rocket::async_test]
+    async fn test_deathlinks_reject_bad_pages() {
+        let client = client().await;
+        for query in [
+            "limit=0".to_string(),
+            format!("limit={}", MAX_DEATHLINKS_PAGE + 1),
+            "offset=-1".to_string(),
+            "since=2026-04-01".to_string(),
+        ] {
+            let (status, _) =
+                get_json(&client, &format!("/api/rooms/main/deathlinks?{}", query)).await;
+            assert_eq!(status, Status::BadRequest, "{}", query);
+        }
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn test_deathlinks_pages() {
+        let db_url = test_database_url().await;
+        let room = format!("deathlinks-{}", uuid::Uuid::new_v4());
+        let client = client_with(Config {
+            db_url,
+            ..test_config(&room)
+        })
+        .await;
+        let state = client.rocket().state::<AppState>().unwrap();
+        let deathlinks: Vec<_> = [1, 2, 1]
+            .into_iter()
+            .map(|slot| {
+                crate::db::models::NewDeathLink::new(
+                    room.clone(),
+                    SlotId(slot),
+                    "Alice".into(),
+                    None,
+                )
+            })
+            .collect();
+        crate::db::models::insert_deathlinks(&state.db_pool, &deathlinks)
+            .await
+            .unwrap();
+        let deathlinks = format!("/api/rooms/{}/deathlinks", room);
+
+        let (status, page) = get_json(&client, &format!("{}?limit=2&offset=1", deathlinks)).await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(page["total"], 3);
+        assert_eq!(page["deathlinks"].as_array().unwrap().len(), 2);
+        let (_, page) = get_json(&client, &format!("{}?slot=1&offset=1", deathlinks)).await;
+        assert_eq!(page["total"], 2);
+        assert_eq!(page["deathlinks"].as_array().unwrap().len(), 1);
+        assert_eq!(page["limit"], DEFAULT_DEATHLINKS_PAGE);
+        let (_, page) = get_json(
+            &client,
+            &format!("{}?since=2999-01-01T00:00:00Z", deathlinks),
+        )
+        .await;
+        assert_eq!(page["total"], 0);
+
+        // Unpaged on the legacy route
+        let (status, all) = get_json(&client, &format!("/api/deathlinks/{}", room)).await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(all.as_array().unwrap().len(), 3);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn test_countdowns_and_their_summary() {
+        let db_url = test_database_url().await;
+        let room = format!("countdowns-{}", uuid::Uuid::new_v4());
+        let client = client_with(Config {
+            db_url,
+            ..test_config(&room)
+        })
+        .await;
+        let state = client.rocket().state::<AppState>().unwrap();
+        let countdowns: Vec<_> = [2, 1, 2]
+            .into_iter()
+            .map(|slot| crate::db::models::NewCountdown::new(room.clone(), SlotId(slot)))
+            .collect();
+        crate::db::models::insert_countdowns(&state.db_pool, &countdowns)
+            .await
+            .unwrap();
+        let countdowns = format!("/api/rooms/{}/countdowns", room);
+
+        let (status, all) = get_json(&client, &countdowns).await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(all.as_array().unwrap().len(), 3);
+        assert!(
+            all[0]["created_at"]
+                .as_str()
+                .unwrap()
+                .parse::<chrono::DateTime<chrono::Utc>>()
+                .is_ok()
+        );
+        let (_, limited) = get_json(&client, &format!("{}?slot=2&limit=1", countdowns)).await;
+        assert_eq!(limited.as_array().unwrap().len(), 1);
+        assert_eq!(limited[0]["slot"], 2);
+        let (status, _) = get_json(&client, &format!("{}?limit=0", countdowns)).await;
+        assert_eq!(status, Status::BadRequest);
+
+        let (_, summary) = get_json(&client, &format!("{}/summary", countdowns)).await;
+        assert_eq!(
+            summary,
+            serde_json::json!([{"slot": 1, "attempts": 1}, {"slot": 2, "attempts": 2}])
+        );
+    }
+
+    async fn add_slot(client: &Client, slot: i64, name: &str) {
+        let state = client.rocket().state::<AppState>().unwrap();
+        state
+            .slot_names
+            .write()
+            .await
+            .insert(SlotId(slot), name.to_string());
+    }
+
+    async fn post_note(client: &Client, path: &str, author: &str, note: &str) -> Status {
+        client
+            .post(path.to_string())
+            .header(api_key())
+            .json(&serde_json::json!({"author": author, "note": note}))
+            .dispatch()
+            .await
+            .status()
+    }
+
+    #[rocket::async_test]
+    async fn test_slot_notes_are_checked_before_the_database() {
+        let client = client().await;
+        let notes = "/api/rooms/main/slots/1/notes";
+        assert_eq!(
+            post_note(&client, notes, "mod", "hi").await,
+            Status::NotFound
+        );
+        assert_eq!(get_json(&client, notes).await.0, Status::NotFound);
+        // Notes are settings of the primary room
+        let (status, _) = get_json(&client, "/api/rooms/race/slots/1/notes").await;
+        assert_eq!(status, Status::NotFound);
+
+        add_slot(&client, 1, "Alice").await;
+        assert_eq!(
+            post_note(&client, notes, "mod", " ").await,
+            Status::BadRequest
+        );
+        assert_eq!(
+            post_note(&client, notes, "", "hi").await,
+            Status::BadRequest
+        );
+        for limit in [0, MAX_NOTES_PAGE + 1] {
+            let (status, _) = get_json(&client, &format!("{}?limit={}", notes, limit)).await;
+            assert_eq!(status, Status::BadRequest, "limit {}", limit);
+        }
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn test_slot_notes_pages() {
+        let db_url = test_database_url().await;
+        let room = format!("notes-{}", uuid::Uuid::new_v4());
+        let client = client_with(Config {
+            db_url,
+            ..test_config(&room)
+        })
+        .await;
+        add_slot(&client, 1, "Alice").await;
+        let notes = format!("/api/rooms/{}/slots/1/notes", room);
+        for note in ["first", "second", "third"] {
+            assert_eq!(post_note(&client, &notes, "mod", note).await, Status::Ok);
+        }
+
+        let (status, page) = get_json(&client, &format!("{}?limit=2", notes)).await;
+        assert_eq!(status, Status::Ok);
+        let texts = |page: &serde_json::Value| -> Vec<String> {
+            page["notes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|note| note["note"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(texts(&page), ["third", "second"]);
+        let before = page["next_before"].as_i64().unwrap();
+        let (_, last) = get_json(&client, &format!("{}?limit=2&before={}", notes, before)).await;
+        assert_eq!(texts(&last), ["first"]);
+        assert_eq!(last["next_before"], serde_json::Value::Null);
+
+        let (_, slots) = get_json(&client, &format!("/api/rooms/{}/slots", room)).await;
+        assert_eq!(slots[0]["latest_note"]["note"], "third");
+
+        let id = last["notes"][0]["id"].as_i64().unwrap();
+        let delete = format!("/api/rooms/{}/notes/{}", room, id);
+        for expected in [Status::Ok, Status::NotFound] {
+            let response = client
+                .delete(delete.clone())
+                .header(api_key())
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), expected);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_health_needs_no_key() {
+        let client = client().await;
+        let response = client.get("/api/health").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let health: serde_json::Value = response.into_json().await.unwrap();
+        assert!(health["status"].is_string());
+    }
+
+    #[rocket::async_test]
+    async fn test_health_reports_silent_upstreams() {
+        let client = client().await;
+        let state = client.rocket().state::<AppState>().unwrap();
+        while !state.rooms["race"].upstream.room_info_timed_out() {}
+
+        let (_, health) = get_json(&client, "/api/health").await;
+        assert_eq!(health["status"], "critical");
+        assert_eq!(
+            health["unresponsive_upstreams"],
+            serde_json::json!(["race"])
+        );
+
+        state.rooms["race"].upstream.room_info_received();
+        let (_, health) = get_json(&client, "/api/health").await;
+        assert_eq!(health["unresponsive_upstreams"], serde_json::json!([]));
+    }
+
+    #[rocket::async_test]
+    async fn test_login_queue_routes() {
+        let client = client().await;
+        let (status, queue) = get_json(&client, "/api/rooms/race/queue").await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(queue, serde_json::json!([]));
+
+        let response = client
+            .post("/api/rooms/main/queue/1/promote")
+            .header(api_key())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn test_pace_of_the_room_and_its_slots() {
+        use crate::progress::LocationProgress;
+
+        let client = client().await;
+        let registry = &client.rocket().state::<AppState>().unwrap().client_registry;
+        registry
+            .init_progress(SlotId(1), LocationProgress::new(&[], &[1, 2, 3, 4]), "main")
+            .await;
+        registry.record_checks(SlotId(1), &[1, 2], "main").await;
+        // Resent checks don't count again
+        registry.record_checks(SlotId(1), &[1, 2], "main").await;
+
+        let (status, pace) = get_json(&client, "/api/rooms/main/pace").await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(pace["room"]["checks_last_10m"], 2);
+        assert_eq!(pace["room"]["remaining_locations"], 2);
+        assert_eq!(pace["room"]["eta_seconds"], 600);
+        assert_eq!(pace["slots"][0]["slot"], 1);
+        assert_eq!(pace["slots"][0]["checks_per_minute"], 0.2);
+
+        let (_, pace) = get_json(&client, "/api/rooms/race/pace").await;
+        assert_eq!(pace["slots"], serde_json::json!([]));
+        assert_eq!(pace["room"]["eta_seconds"], 0);
+    }
+
+    #[rocket::async_test]
+    async fn test_claims_are_cleared_by_admins() {
+        let client = client().await;
+        let (status, _) = get_json(&client, "/api/rooms/main/claims").await;
+        assert_eq!(status, Status::NotFound);
+
+        let client = client_with(Config {
+            slot_claiming: true,
+            ..test_config("main")
+        })
+        .await;
+        let claims = client
+            .rocket()
+            .state::<AppState>()
+            .unwrap()
+            .slot_claims
+            .clone();
+        let _hold = claims
+            .unwrap()
+            .admit(SlotId(1), "alice-uuid", chrono::Utc::now())
+            .unwrap();
+        let (_, claimed) = get_json(&client, "/api/rooms/main/claims").await;
+        assert_eq!(claimed[0]["uuid"], "alice-uuid");
+
+        for expected in [Status::Ok, Status::NotFound] {
+            let response = client
+                .delete("/api/rooms/main/claims/1")
+                .header(api_key())
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), expected);
+        }
+        let (_, claimed) = get_json(&client, "/api/rooms/main/claims").await;
+        assert_eq!(claimed, serde_json::json!([]));
+    }
+
+    #[rocket::async_test]
+    async fn test_scheduled_messages_are_validated() {
+        let client = client_with(Config {
+            scheduled_messages: r#"[{"repeat": {"cron": "0 * * * *"}, "text": "Hourly"}]"#
+                .parse()
+                .unwrap(),
+            ..test_config("main")
+        })
+        .await;
+        let (status, listed) = get_json(&client, "/api/rooms/main/scheduled_messages").await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(listed[0]["id"], 1);
+        assert_eq!(listed[0]["repeat"]["cron"], "0 * * * *");
+
+        let invalid = r#"{"repeat": {"every_seconds": 60}, "text": "Hi", "color": "pink"}"#;
+        let response = client
+            .post("/api/rooms/main/scheduled_messages")
+            .header(api_key())
+            .body(invalid)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let valid = r#"{"repeat": {"every_seconds": 60}, "text": "Hi"}"#;
+        let response = client
+            .put("/api/rooms/main/scheduled_messages/2")
+            .header(api_key())
+            .body(valid)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .delete("/api/rooms/main/scheduled_messages/2")
+            .header(api_key())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+        let (_, listed) = get_json(&client, "/api/rooms/main/scheduled_messages").await;
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn test_degraded_mode_shows_in_diagnostics() {
+        let client = client().await;
+        let response = client
+            .put("/api/degraded_mode")
+            .header(api_key())
+            .json(&serde_json::json!({"mode": "on"}))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let status: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(status["degraded"], true);
+
+        let (_, diagnostics) = get_json(&client, "/api/diagnostics").await;
+        assert_eq!(diagnostics["degraded_mode"]["mode"], "on");
+        assert!(diagnostics["degraded_mode"]["since"].is_string());
+    }
+
+    #[rocket::async_test]
+    async fn test_only_tables_with_a_retention_can_be_pruned() {
+        let client = client_with(Config {
+            retention: r#"{"deathlinks": 30}"#.parse().unwrap(),
+            ..test_config("main")
+        })
+        .await;
+        for (table, expected) in [
+            ("slot_notes", Status::NotFound),
+            ("countdowns", Status::UnprocessableEntity),
+        ] {
+            let response = client
+                .post("/api/retention/prune")
+                .header(api_key())
+                .json(&serde_json::json!({ "table": table }))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), expected, "{}", table);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_attempts_are_only_verified_with_an_audit_key() {
+        let client = client().await;
+        let response = client
+            .post("/api/rooms/main/verify_attempt")
+            .header(api_key())
+            .json(&serde_json::json!({"attempt_id": 1, "password": "pw"}))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn test_bridged_chat_needs_a_signature() {
+        let body = br#"{"origin_room": "peer", "name": "Alice", "text": "hi"}"#;
+        let key = BridgeKey::new("secret");
+        let post = async |client: &Client, signature: Option<String>| {
+            let mut request = client.post("/api/bridge").body(body);
+            if let Some(signature) = signature {
+                request = request.header(Header::new(bridge::SIGNATURE_HEADER, signature));
+            }
+            request.dispatch().await.status()
+        };
+
+        let client = client().await;
+        assert_eq!(post(&client, Some(key.sign(body))).await, Status::NotFound);
+
+        let client = client_with(Config {
+            bridge_shared_secret: Some("secret".to_string()),
+            ..test_config("main")
+        })
+        .await;
+        assert_eq!(post(&client, None).await, Status::Unauthorized);
+        let forged = BridgeKey::new("forged").sign(body);
+        assert_eq!(post(&client, Some(forged)).await, Status::Unauthorized);
+        assert_eq!(post(&client, Some(key.sign(body))).await, Status::Ok);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn test_mutes_reach_the_instances_sharing_the_database() {
+        let url = test_database_url().await;
+        // A room of its own, so earlier runs left nothing to converge on
+        let room_id = uuid::Uuid::new_v4().to_string();
+        let config = || Config {
+            db_url: url.clone(),
+            coordinate_instances: true,
+            ..test_config(&room_id)
+        };
+        let instances = [client_with(config()).await, client_with(config()).await];
+        for instance in &instances {
+            let state = instance.rocket().state::<AppState>().unwrap();
+            state
+                .slot_names
+                .write()
+                .await
+                .insert(SlotId(1), "Alice".into());
+            rocket::tokio::spawn(crate::coordination::listen(
+                state.coordinator.clone().unwrap(),
+                url.clone(),
+            ));
+        }
+
+        let response = instances[0]
+            .put(format!("/api/rooms/{}/preferences/1", room_id))
+            .header(api_key())
+            .json(&serde_json::json!({"muted": true}))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let other = instances[1].rocket().state::<AppState>().unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(1);
+        while !preferences::is_muted(&*other.preferences.read().await, &SlotId(1)) {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "The mute never reached the other instance"
+            );
+            rocket::tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_reload_config_needs_key() {
+        let client = client().await;
+        let response = client.post("/api/reload_config").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
     }
 }
@@ -0,0 +1,51 @@
+use crate::signing::HmacKey;
+
+/// AUDIT_HMAC_KEY. Login attempts are kept as an HMAC of the name and password the player
+/// typed, so an admin knowing the expected password can tell whether they mistyped it, without
+/// the password ever being stored.
+#[derive(Clone)]
+pub struct AuditKey(HmacKey);
+
+impl AuditKey {
+    pub fn new(secret: &str) -> Self {
+        Self(HmacKey::new(secret))
+    }
+
+    /// What's recorded of an attempt to log in as `name` with `password`
+    pub fn attempt(&self, name: &str, password: &str) -> String {
+        // Length prefixed, so no other name and password run together the same way
+        let length = (name.len() as u64).to_be_bytes();
+        self.0
+            .sign(&[length.as_slice(), name.as_bytes(), password.as_bytes()])
+    }
+
+    /// Whether a recorded attempt of `name` was made with `password`, compared in constant time
+    pub fn matches(&self, attempt: &str, name: &str, password: &str) -> bool {
+        let length = (name.len() as u64).to_be_bytes();
+        self.0
+            .verify(
+                &[length.as_slice(), name.as_bytes(), password.as_bytes()],
+                attempt,
+            )
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attempts_match_only_their_password() {
+        let key = AuditKey::new("secret");
+        let attempt = key.attempt("Alice", "hunter2");
+        assert!(!attempt.contains("hunter2"));
+        assert!(key.matches(&attempt, "Alice", "hunter2"));
+
+        assert!(!key.matches(&attempt, "Alice", "hunter2 "));
+        assert!(!key.matches(&attempt, "Alice", ""));
+        assert!(!key.matches(&attempt, "Alic", "ehunter2"));
+        assert!(!AuditKey::new("other").matches(&attempt, "Alice", "hunter2"));
+        assert!(!key.matches("not base64!", "Alice", "hunter2"));
+    }
+}
@@ -0,0 +1,392 @@
+use anyhow::Result;
+use aprs_proto::primitives::SlotId;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+use crate::db::DieselPool;
+use crate::db::models::{self, DailySlotBandwidth};
+use crate::diagnostics;
+use crate::metrics;
+
+/// Commands a slot past its quota can't send anymore, unless configured otherwise. Everything
+/// else still goes through, LocationChecks and StatusUpdate in particular.
+const DEFAULT_LIMITED_COMMANDS: &[&str] = &["Bounce", "Set", "Say"];
+
+/// Bytes exchanged by each slot, counted here and persisted periodically so a restart doesn't
+/// reset them
+static USAGE: LazyLock<Mutex<Usage>> = LazyLock::new(Default::default);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    ClientToUpstream,
+    UpstreamToClient,
+}
+
+/// Bytes a slot exchanged during a UTC day
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct SlotBandwidth {
+    pub day: NaiveDate,
+    pub client_to_upstream: u64,
+    pub upstream_to_client: u64,
+}
+
+impl SlotBandwidth {
+    fn new(day: NaiveDate) -> Self {
+        Self {
+            day,
+            client_to_upstream: 0,
+            upstream_to_client: 0,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.client_to_upstream + self.upstream_to_client
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Counted {
+    bandwidth: SlotBandwidth,
+    /// Changed since it was last persisted
+    dirty: bool,
+}
+
+/// Counts are kept per day, the window starts over at midnight UTC. Previous days stick around
+/// until they've been persisted.
+#[derive(Default)]
+struct Usage(HashMap<(String, SlotId, NaiveDate), Counted>);
+
+impl Usage {
+    fn add(
+        &mut self,
+        room_id: &str,
+        slot: SlotId,
+        direction: Direction,
+        bytes: usize,
+        now: DateTime<Utc>,
+    ) {
+        let day = now.date_naive();
+        let counted = self
+            .0
+            .entry((room_id.to_string(), slot, day))
+            .or_insert_with(|| Counted {
+                bandwidth: SlotBandwidth::new(day),
+                dirty: false,
+            });
+        match direction {
+            Direction::ClientToUpstream => counted.bandwidth.client_to_upstream += bytes as u64,
+            Direction::UpstreamToClient => counted.bandwidth.upstream_to_client += bytes as u64,
+        }
+        counted.dirty = true;
+    }
+
+    /// Bytes counted so far on `now`'s day
+    fn get(&self, room_id: &str, slot: SlotId, now: DateTime<Utc>) -> SlotBandwidth {
+        let day = now.date_naive();
+        self.0
+            .get(&(room_id.to_string(), slot, day))
+            .map_or_else(|| SlotBandwidth::new(day), |counted| counted.bandwidth)
+    }
+
+    /// Today's bytes of every slot seen, slots only seen on previous days are at zero
+    fn current(&self, now: DateTime<Utc>) -> Vec<(String, SlotId, SlotBandwidth)> {
+        let slots: HashSet<(&str, SlotId)> = self
+            .0
+            .keys()
+            .map(|(room_id, slot, _)| (room_id.as_str(), *slot))
+            .collect();
+        slots
+            .into_iter()
+            .map(|(room_id, slot)| (room_id.to_string(), slot, self.get(room_id, slot, now)))
+            .collect()
+    }
+
+    /// Counts loaded from the database, added to whatever was counted since startup
+    fn restore(&mut self, room_id: String, slot: SlotId, bandwidth: SlotBandwidth) {
+        let counted = self
+            .0
+            .entry((room_id, slot, bandwidth.day))
+            .or_insert_with(|| Counted {
+                bandwidth: SlotBandwidth::new(bandwidth.day),
+                dirty: false,
+            });
+        counted.bandwidth.client_to_upstream += bandwidth.client_to_upstream;
+        counted.bandwidth.upstream_to_client += bandwidth.upstream_to_client;
+    }
+
+    /// Counts changed since the last call, and forgets the previous days already persisted
+    fn take_changed(&mut self, now: DateTime<Utc>) -> Vec<(String, SlotId, SlotBandwidth)> {
+        let today = now.date_naive();
+        let mut changed = Vec::new();
+        self.0.retain(|(room_id, slot, day), counted| {
+            if counted.dirty {
+                counted.dirty = false;
+                changed.push((room_id.clone(), *slot, counted.bandwidth));
+                // Kept for one more round in case persisting it fails
+                return true;
+            }
+            *day >= today
+        });
+        changed
+    }
+
+    /// Flags counts that couldn't be persisted, they're persisted again next time
+    fn mark_changed(&mut self, room_id: &str, slot: SlotId, day: NaiveDate) {
+        if let Some(counted) = self.0.get_mut(&(room_id.to_string(), slot, day)) {
+            counted.dirty = true;
+        }
+    }
+}
+
+/// Commands subject to the quota, from a comma separated list
+#[derive(Clone, Debug, PartialEq)]
+pub struct LimitedCommands(HashSet<String>);
+
+impl Default for LimitedCommands {
+    fn default() -> Self {
+        Self(
+            DEFAULT_LIMITED_COMMANDS
+                .iter()
+                .map(|cmd| cmd.to_string())
+                .collect(),
+        )
+    }
+}
+
+impl std::str::FromStr for LimitedCommands {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(
+            s.split(',')
+                .map(str::trim)
+                .filter(|cmd| !cmd.is_empty())
+                .map(str::to_string)
+                .collect(),
+        ))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Quota {
+    /// Bytes per slot per day, both directions together. `None` for no quota.
+    pub daily_bytes: Option<u64>,
+    pub limited: LimitedCommands,
+}
+
+impl Quota {
+    pub fn is_exceeded(&self, bandwidth: &SlotBandwidth) -> bool {
+        self.daily_bytes
+            .is_some_and(|daily_bytes| bandwidth.total() >= daily_bytes)
+    }
+
+    /// Whether `cmd` is dropped once the quota is exceeded
+    pub fn limits(&self, cmd: &str) -> bool {
+        self.limited.0.contains(cmd)
+    }
+}
+
+/// Counts the bytes of a connection against its slot, from the moment it's known
+#[derive(Clone)]
+pub struct Meter {
+    room_id: String,
+    slot: Arc<Mutex<Option<SlotId>>>,
+}
+
+impl Meter {
+    pub fn new(room_id: &str) -> Self {
+        Self {
+            room_id: room_id.to_string(),
+            slot: Default::default(),
+        }
+    }
+
+    /// Bytes go to the slot the connection is logged in to, a later login moves them to its own
+    pub fn set_slot(&self, slot: SlotId) {
+        *self.slot.lock().unwrap() = Some(slot);
+    }
+
+    pub fn record(&self, direction: Direction, bytes: usize) {
+        let slot = *self.slot.lock().unwrap();
+        if let Some(slot) = slot {
+            USAGE
+                .lock()
+                .unwrap()
+                .add(&self.room_id, slot, direction, bytes, Utc::now());
+        }
+    }
+
+    pub fn is_over(&self, quota: &Quota) -> bool {
+        let slot = *self.slot.lock().unwrap();
+        quota.daily_bytes.is_some()
+            && slot.is_some_and(|slot| quota.is_exceeded(&usage(&self.room_id, slot)))
+    }
+}
+
+/// Bytes the slot exchanged today
+pub fn usage(room_id: &str, slot: SlotId) -> SlotBandwidth {
+    USAGE.lock().unwrap().get(room_id, slot, Utc::now())
+}
+
+/// Loads today's counts so a restart carries on from them
+pub async fn load(pool: &DieselPool) -> Result<usize> {
+    let rows = models::get_slot_bandwidth(pool, Utc::now().date_naive()).await?;
+    let count = rows.len();
+    let mut usage = USAGE.lock().unwrap();
+    for row in rows {
+        usage.restore(
+            row.room_id,
+            SlotId(row.slot as i64),
+            SlotBandwidth {
+                day: row.day,
+                client_to_upstream: row.client_to_upstream as u64,
+                upstream_to_client: row.upstream_to_client as u64,
+            },
+        );
+    }
+    Ok(count)
+}
+
+pub async fn persist(pool: DieselPool, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        diagnostics::heartbeat("bandwidth");
+        let changed = USAGE.lock().unwrap().take_changed(Utc::now());
+        for (room_id, slot, bandwidth) in changed {
+            let row = DailySlotBandwidth {
+                room_id: room_id.clone(),
+                slot: slot.0 as i32,
+                day: bandwidth.day,
+                client_to_upstream: bandwidth.client_to_upstream as i64,
+                upstream_to_client: bandwidth.upstream_to_client as i64,
+            };
+            if let Err(e) = models::upsert_slot_bandwidth(&pool, &row).await {
+                log::error!(
+                    "Failed to persist bandwidth of slot {} in {}: {:?}",
+                    slot.0,
+                    room_id,
+                    e
+                );
+                USAGE
+                    .lock()
+                    .unwrap()
+                    .mark_changed(&room_id, slot, bandwidth.day);
+            }
+        }
+
+        let current = USAGE.lock().unwrap().current(Utc::now());
+        for (room_id, slot, bandwidth) in current {
+            metrics::record_slot_bandwidth(&room_id, slot, &bandwidth);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32, minute: u32, second: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 4, day, hour, minute, second)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_usage_rolls_over_at_midnight() {
+        let mut usage = Usage::default();
+        let slot = SlotId(1);
+        usage.add(
+            "room",
+            slot,
+            Direction::ClientToUpstream,
+            100,
+            at(6, 23, 59, 59),
+        );
+        usage.add(
+            "room",
+            slot,
+            Direction::UpstreamToClient,
+            50,
+            at(6, 23, 59, 59),
+        );
+        assert_eq!(usage.get("room", slot, at(6, 23, 59, 59)).total(), 150);
+
+        let midnight = at(7, 0, 0, 0);
+        assert_eq!(usage.get("room", slot, midnight).total(), 0);
+        usage.add("room", slot, Direction::ClientToUpstream, 10, midnight);
+        let today = usage.get("room", slot, midnight);
+        assert_eq!(today.day, midnight.date_naive());
+        assert_eq!(today.client_to_upstream, 10);
+        assert_eq!(today.upstream_to_client, 0);
+        assert_eq!(
+            usage.current(midnight),
+            vec![("room".to_string(), slot, today)]
+        );
+
+        // Yesterday's count still gets persisted once, then it's forgotten
+        let mut changed = usage.take_changed(midnight);
+        changed.sort_unstable_by_key(|(_, _, bandwidth)| bandwidth.day);
+        assert_eq!(
+            changed
+                .iter()
+                .map(|(_, _, bandwidth)| bandwidth.total())
+                .collect::<Vec<_>>(),
+            vec![150, 10]
+        );
+        assert!(usage.take_changed(midnight).is_empty());
+        assert_eq!(usage.0.len(), 1);
+    }
+
+    #[test]
+    fn test_failed_persist_is_retried() {
+        let mut usage = Usage::default();
+        let now = at(6, 12, 0, 0);
+        usage.add("room", SlotId(1), Direction::ClientToUpstream, 100, now);
+        assert_eq!(usage.take_changed(now).len(), 1);
+        usage.mark_changed("room", SlotId(1), now.date_naive());
+        assert_eq!(usage.take_changed(now).len(), 1);
+
+        // Restored counts are already in the database
+        usage.restore(
+            "room".to_string(),
+            SlotId(2),
+            SlotBandwidth::new(now.date_naive()),
+        );
+        assert!(usage.take_changed(now).is_empty());
+    }
+
+    #[test]
+    fn test_quota_classification() {
+        let quota = Quota {
+            daily_bytes: Some(1000),
+            limited: LimitedCommands::default(),
+        };
+        for cmd in ["Bounce", "Set", "Say"] {
+            assert!(quota.limits(cmd), "{}", cmd);
+        }
+        for cmd in ["LocationChecks", "StatusUpdate", "Sync", "Connect"] {
+            assert!(!quota.limits(cmd), "{}", cmd);
+        }
+
+        let mut bandwidth = SlotBandwidth::new(at(6, 0, 0, 0).date_naive());
+        bandwidth.client_to_upstream = 600;
+        assert!(!quota.is_exceeded(&bandwidth));
+        bandwidth.upstream_to_client = 400;
+        assert!(quota.is_exceeded(&bandwidth));
+        assert!(!Quota::default().is_exceeded(&bandwidth));
+
+        let quota = Quota {
+            daily_bytes: Some(1000),
+            limited: " Bounce, LocationScouts ,".parse().unwrap(),
+        };
+        assert!(quota.limits("LocationScouts"));
+        assert!(quota.limits("Bounce"));
+        assert!(!quota.limits("Say"));
+        assert!(!quota.limits(""));
+    }
+}
@@ -0,0 +1,230 @@
+use aprs_proto::primitives::SlotId;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::events::RoomEvent;
+
+/// Room setting holding RECORD_BOUNCES as set through the API, which overrides the variable
+pub const RECORD_BOUNCES_SETTING_KEY: &str = "record_bounces";
+
+#[derive(Debug)]
+pub struct InvalidRecordedSlots(String);
+
+impl std::fmt::Display for InvalidRecordedSlots {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "expected off, all or a comma separated list of slots, got {}",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidRecordedSlots {}
+
+/// RECORD_BOUNCES, the slots whose Bounces are kept for tracker developers to look at
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum RecordedSlots {
+    #[default]
+    Off,
+    All,
+    Slots(BTreeSet<SlotId>),
+}
+
+impl RecordedSlots {
+    fn includes(&self, slot: SlotId) -> bool {
+        match self {
+            RecordedSlots::Off => false,
+            RecordedSlots::All => true,
+            RecordedSlots::Slots(slots) => slots.contains(&slot),
+        }
+    }
+}
+
+/// `off`, `all` or slots like `1,4,7`, an empty list is off
+impl std::str::FromStr for RecordedSlots {
+    type Err = InvalidRecordedSlots;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => return Ok(RecordedSlots::Off),
+            "all" => return Ok(RecordedSlots::All),
+            _ => {}
+        }
+        let slots = s
+            .split(',')
+            .map(str::trim)
+            .filter(|slot| !slot.is_empty())
+            .map(|slot| slot.parse().map(SlotId))
+            .collect::<Result<BTreeSet<_>, _>>()
+            .map_err(|_| InvalidRecordedSlots(s.to_string()))?;
+        if slots.is_empty() {
+            return Ok(RecordedSlots::Off);
+        }
+        Ok(RecordedSlots::Slots(slots))
+    }
+}
+
+impl std::fmt::Display for RecordedSlots {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordedSlots::Off => write!(f, "off"),
+            RecordedSlots::All => write!(f, "all"),
+            RecordedSlots::Slots(slots) => {
+                let slots: Vec<String> = slots.iter().map(|slot| slot.0.to_string()).collect();
+                write!(f, "{}", slots.join(","))
+            }
+        }
+    }
+}
+
+/// Picks the Bounces stored in the `bounces` table, no more than `daily_cap` a day for each
+/// slot. DeathLinks are left out, they're stored as such already.
+pub struct BounceRecorder {
+    /// Whether anything is recorded at all, all a Bounce checks while nothing is recorded
+    enabled: AtomicBool,
+    slots: Mutex<RecordedSlots>,
+    daily_cap: usize,
+    /// Bounces recorded by slot on that day
+    recorded: Mutex<(NaiveDate, HashMap<SlotId, usize>)>,
+}
+
+impl BounceRecorder {
+    pub fn new(slots: RecordedSlots, daily_cap: usize) -> Self {
+        Self {
+            enabled: AtomicBool::new(slots != RecordedSlots::Off),
+            slots: Mutex::new(slots),
+            daily_cap,
+            recorded: Mutex::new((Utc::now().date_naive(), HashMap::new())),
+        }
+    }
+
+    pub fn slots(&self) -> RecordedSlots {
+        self.slots.lock().unwrap().clone()
+    }
+
+    pub fn set_slots(&self, slots: RecordedSlots) {
+        let mut current = self.slots.lock().unwrap();
+        self.enabled
+            .store(slots != RecordedSlots::Off, Ordering::Relaxed);
+        *current = slots;
+    }
+
+    /// Counts what was already stored on `day` against the cap, so a restart doesn't reset it
+    pub fn restore(&self, day: NaiveDate, recorded: HashMap<SlotId, usize>) {
+        *self.recorded.lock().unwrap() = (day, recorded);
+    }
+
+    /// The event storing a Bounce `slot` sent, when it's recorded
+    pub fn record(&self, slot: SlotId, bounce: &Value, now: DateTime<Utc>) -> Option<RoomEvent> {
+        if !self.enabled.load(Ordering::Relaxed) || !self.slots.lock().unwrap().includes(slot) {
+            return None;
+        }
+        let tags: Vec<String> = bounce
+            .get("tags")
+            .and_then(|tags| serde_json::from_value(tags.clone()).ok())
+            .unwrap_or_default();
+        if tags.iter().any(|tag| tag == "DeathLink") {
+            return None;
+        }
+
+        let mut recorded = self.recorded.lock().unwrap();
+        let today = now.date_naive();
+        if recorded.0 != today {
+            *recorded = (today, HashMap::new());
+        }
+        let count = recorded.1.entry(slot).or_default();
+        if *count >= self.daily_cap {
+            return None;
+        }
+        *count += 1;
+        if *count == self.daily_cap {
+            log::info!(
+                "Slot {} reached its {} recorded Bounces for today",
+                slot.0,
+                self.daily_cap
+            );
+        }
+
+        Some(RoomEvent::BounceRecorded {
+            slot,
+            tags,
+            data: bounce.get("data").cloned().unwrap_or(Value::Null),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn bounce(tags: &[&str]) -> Value {
+        json!({"cmd": "Bounce", "tags": tags, "data": {"x": 1}})
+    }
+
+    #[test]
+    fn test_recorded_slots_parse() {
+        assert_eq!("".parse::<RecordedSlots>().unwrap(), RecordedSlots::Off);
+        assert_eq!("Off".parse::<RecordedSlots>().unwrap(), RecordedSlots::Off);
+        assert_eq!("all".parse::<RecordedSlots>().unwrap(), RecordedSlots::All);
+        let slots: RecordedSlots = "4, 1,".parse().unwrap();
+        assert_eq!(
+            slots,
+            RecordedSlots::Slots(BTreeSet::from([SlotId(1), SlotId(4)]))
+        );
+        assert_eq!(slots.to_string(), "1,4");
+        assert!("1,two".parse::<RecordedSlots>().is_err());
+    }
+
+    #[test]
+    fn test_only_listed_slots_are_recorded() {
+        let now = Utc::now();
+        let recorder = BounceRecorder::new(RecordedSlots::Off, 10);
+        assert!(
+            recorder
+                .record(SlotId(1), &bounce(&["Tracker"]), now)
+                .is_none()
+        );
+
+        recorder.set_slots("1".parse().unwrap());
+        let Some(RoomEvent::BounceRecorded { slot, tags, data }) =
+            recorder.record(SlotId(1), &bounce(&["Tracker"]), now)
+        else {
+            panic!("Bounce of slot 1 wasn't recorded");
+        };
+        assert_eq!(slot, SlotId(1));
+        assert_eq!(tags, ["Tracker"]);
+        assert_eq!(data, json!({"x": 1}));
+        assert!(
+            recorder
+                .record(SlotId(2), &bounce(&["Tracker"]), now)
+                .is_none()
+        );
+        assert!(
+            recorder
+                .record(SlotId(1), &bounce(&["DeathLink"]), now)
+                .is_none()
+        );
+
+        recorder.set_slots(RecordedSlots::All);
+        assert!(recorder.record(SlotId(2), &bounce(&[]), now).is_some());
+    }
+
+    #[test]
+    fn test_recording_is_capped_per_slot_and_day() {
+        let now = Utc::now();
+        let recorder = BounceRecorder::new(RecordedSlots::All, 2);
+        recorder.restore(now.date_naive(), HashMap::from([(SlotId(1), 1)]));
+        assert!(recorder.record(SlotId(1), &bounce(&[]), now).is_some());
+        assert!(recorder.record(SlotId(1), &bounce(&[]), now).is_none());
+        // Other slots have their own
+        assert!(recorder.record(SlotId(2), &bounce(&[]), now).is_some());
+
+        let tomorrow = now + chrono::Duration::days(1);
+        assert!(recorder.record(SlotId(1), &bounce(&[]), tomorrow).is_some());
+    }
+}
@@ -0,0 +1,267 @@
+use anyhow::{Context, Result};
+use reqwest::Url;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::proto::{JSONMessagePart, PrintJSON};
+use crate::proxy::MAX_SAY_LENGTH;
+use crate::registry::ClientRegistry;
+use crate::signing::HmacKey;
+
+/// Header carrying the signature of what's posted to `/api/bridge`
+pub const SIGNATURE_HEADER: &str = "X-Bridge-Signature";
+/// Chat waiting for the peer, past this the newest is dropped
+const LINK_QUEUE: usize = 100;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// BRIDGE_SHARED_SECRET, both instances of a bridge sign what they post to each other with it
+#[derive(Clone)]
+pub struct BridgeKey(HmacKey);
+
+impl BridgeKey {
+    pub fn new(secret: &str) -> Self {
+        Self(HmacKey::new(secret))
+    }
+
+    pub fn sign(&self, body: &[u8]) -> String {
+        self.0.sign(&[body])
+    }
+
+    /// Compared in constant time
+    pub fn verify(&self, body: &[u8], signature: &str) -> bool {
+        self.0.verify(&[body], signature).is_ok()
+    }
+}
+
+/// A message a player of `origin_room` said, as it crosses the bridge
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BridgedChat {
+    pub origin_room: String,
+    pub name: String,
+    pub text: String,
+}
+
+impl BridgedChat {
+    /// What the clients of the other room are shown, prefixed with the room it comes from
+    pub fn to_print_json(&self) -> Value {
+        let message = PrintJSON {
+            data: vec![
+                JSONMessagePart {
+                    text: format!("[{}] ", self.origin_room),
+                    type_: Some("color".to_string()),
+                    color: Some("cyan".to_string()),
+                },
+                JSONMessagePart {
+                    text: format!("{}: {}", self.name, self.text),
+                    type_: None,
+                    color: None,
+                },
+            ],
+            ..PrintJSON::new("")
+        };
+        serde_json::to_value(message).unwrap()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Refused {
+    BadSignature,
+    Malformed,
+    /// Chat of this very room coming back, it's never delivered twice
+    OwnRoom,
+    TooLong,
+}
+
+impl std::fmt::Display for Refused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Refused::BadSignature => write!(f, "bad signature"),
+            Refused::Malformed => write!(f, "not a bridged chat message"),
+            Refused::OwnRoom => write!(f, "chat from this room"),
+            Refused::TooLong => write!(f, "longer than a Say may be"),
+        }
+    }
+}
+
+/// Shows the chat the peer posted to `/api/bridge` to everyone logged in to the room. Returns
+/// how many clients got it. It only goes to clients, never to upstream or back over the bridge,
+/// so a message crosses once however the two instances are linked.
+pub async fn deliver(
+    key: &BridgeKey,
+    room_id: &str,
+    client_registry: &ClientRegistry,
+    body: &[u8],
+    signature: &str,
+) -> Result<usize, Refused> {
+    if !key.verify(body, signature) {
+        return Err(Refused::BadSignature);
+    }
+    let chat: BridgedChat = serde_json::from_slice(body).map_err(|_| Refused::Malformed)?;
+    if chat.origin_room == room_id {
+        return Err(Refused::OwnRoom);
+    }
+    // Held to what local players may say
+    if chat.text.len() > MAX_SAY_LENGTH {
+        return Err(Refused::TooLong);
+    }
+    Ok(client_registry.broadcast(&[chat.to_print_json()]).await)
+}
+
+/// Chat of the room's players on its way to the peer, see `run`
+#[derive(Clone)]
+pub struct Bridge {
+    room_id: String,
+    outgoing: mpsc::Sender<BridgedChat>,
+}
+
+impl Bridge {
+    pub fn new(room_id: String) -> (Self, mpsc::Receiver<BridgedChat>) {
+        let (outgoing, receiver) = mpsc::channel(LINK_QUEUE);
+        (Self { room_id, outgoing }, receiver)
+    }
+
+    pub fn forward(&self, name: &str, text: &str) {
+        let chat = BridgedChat {
+            origin_room: self.room_id.clone(),
+            name: name.to_string(),
+            text: text.to_string(),
+        };
+        if self.outgoing.try_send(chat).is_err() {
+            log::warn!(
+                "Dropping chat of {} for the bridge, the peer is too far behind",
+                name
+            );
+        }
+    }
+}
+
+/// Posts the chat to the peer's `/api/bridge` as it comes, in order. A post that fails is tried
+/// again with backoff until the peer is back, what's said meanwhile waits its turn.
+pub async fn run(
+    peer: Url,
+    key: BridgeKey,
+    mut outgoing: mpsc::Receiver<BridgedChat>,
+) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .context("Failed to build the bridge HTTP client")?;
+    while let Some(chat) = outgoing.recv().await {
+        let body = serde_json::to_vec(&chat)?;
+        let signature = key.sign(&body);
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match post(&client, &peer, &body, &signature).await {
+                Ok(()) => break,
+                Err(e) => {
+                    log::warn!("Failed to bridge chat, retrying in {:?}: {:?}", backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn post(client: &reqwest::Client, peer: &Url, body: &[u8], signature: &str) -> Result<()> {
+    let response = client
+        .post(peer.clone())
+        .header(CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, signature)
+        .body(body.to_vec())
+        .send()
+        .await
+        .context("Failed to reach the bridge peer")?;
+    if !response.status().is_success() {
+        anyhow::bail!("Bridge peer answered HTTP {}", response.status());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flapping::FlapLimits;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn chat(origin_room: &str) -> Vec<u8> {
+        serde_json::to_vec(&BridgedChat {
+            origin_room: origin_room.to_string(),
+            name: "Alice".to_string(),
+            text: "hi".to_string(),
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_only_signed_chat_of_the_peer_is_delivered() {
+        let key = BridgeKey::new("secret");
+        let registry = ClientRegistry::new(FlapLimits::default());
+        let body = chat("peer");
+        assert_eq!(
+            deliver(&key, "room", &registry, &body, &key.sign(&body)).await,
+            Ok(0)
+        );
+
+        let other = BridgeKey::new("other");
+        assert_eq!(
+            deliver(&key, "room", &registry, &body, &other.sign(&body)).await,
+            Err(Refused::BadSignature)
+        );
+        let own = chat("room");
+        assert_eq!(
+            deliver(&key, "room", &registry, &own, &key.sign(&own)).await,
+            Err(Refused::OwnRoom)
+        );
+        let garbage = b"{}";
+        assert_eq!(
+            deliver(&key, "room", &registry, garbage, &key.sign(garbage)).await,
+            Err(Refused::Malformed)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_link_retries_until_the_peer_is_back() {
+        let peer = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&peer)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/bridge"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&peer)
+            .await;
+
+        let key = BridgeKey::new("secret");
+        let (bridge, outgoing) = Bridge::new("room".to_string());
+        bridge.forward("Alice", "first");
+        bridge.forward("Alice", "second");
+        drop(bridge);
+        let url = format!("{}/api/bridge", peer.uri()).parse().unwrap();
+        run(url, key.clone(), outgoing).await.unwrap();
+
+        let texts: Vec<String> = peer
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| {
+                let signature = request.headers.get(SIGNATURE_HEADER).unwrap();
+                assert!(key.verify(&request.body, signature.to_str().unwrap()));
+                serde_json::from_slice::<BridgedChat>(&request.body)
+                    .unwrap()
+                    .text
+            })
+            .collect();
+        assert_eq!(texts, ["first", "first", "second"]);
+    }
+}
@@ -0,0 +1,128 @@
+/// What a client may send before it is logged in. Old clients fetch the datapackage one game at
+/// a time, so the defaults leave room for a few hundred requests.
+#[derive(Clone, Copy, Debug)]
+pub struct PreLoginLimits {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for PreLoginLimits {
+    fn default() -> Self {
+        Self {
+            max_messages: 500,
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BudgetExceeded {
+    Messages(usize),
+    Bytes(usize),
+}
+
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BudgetExceeded::Messages(max) => {
+                write!(f, "sent more than {} messages before logging in", max)
+            }
+            BudgetExceeded::Bytes(max) => {
+                write!(f, "sent more than {} bytes before logging in", max)
+            }
+        }
+    }
+}
+
+pub struct PreLoginBudget {
+    limits: PreLoginLimits,
+    messages: usize,
+    bytes: usize,
+}
+
+impl PreLoginBudget {
+    pub fn new(limits: PreLoginLimits) -> Self {
+        Self {
+            limits,
+            messages: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Accounts for one client message of `bytes` bytes
+    pub fn spend(&mut self, bytes: usize) -> Result<(), BudgetExceeded> {
+        self.messages += 1;
+        self.bytes += bytes;
+        if self.messages > self.limits.max_messages {
+            return Err(BudgetExceeded::Messages(self.limits.max_messages));
+        }
+        if self.bytes > self.limits.max_bytes {
+            return Err(BudgetExceeded::Bytes(self.limits.max_bytes));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn size(value: serde_json::Value) -> usize {
+        serde_json::to_string(&[value]).unwrap().len()
+    }
+
+    #[test]
+    fn test_login_with_datapackage_fetch_fits_comfortably() {
+        let games: Vec<String> = (0..200)
+            .map(|i| format!("Some Long Game Name {}", i))
+            .collect();
+
+        // Fresh client, whole datapackage at once, then one game at a time like older clients do
+        let mut flow = vec![size(json!({"cmd": "GetDataPackage", "games": &games}))];
+        flow.extend(
+            games
+                .iter()
+                .map(|game| size(json!({"cmd": "GetDataPackage", "games": [game]}))),
+        );
+        flow.push(size(json!({
+            "cmd": "Connect",
+            "password": "a reasonably long password",
+            "game": "Some Long Game Name 0",
+            "name": "Player",
+            "uuid": "0123456789abcdef",
+            "version": {"major": 0, "minor": 6, "build": 0, "class": "Version"},
+            "items_handling": 7,
+            "tags": ["AP", "DeathLink"],
+            "slot_data": true,
+        })));
+
+        // Still fits when the defaults are halved
+        let defaults = PreLoginLimits::default();
+        let mut budget = PreLoginBudget::new(PreLoginLimits {
+            max_messages: defaults.max_messages / 2,
+            max_bytes: defaults.max_bytes / 2,
+        });
+        for bytes in flow {
+            assert_eq!(budget.spend(bytes), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_budget_exceeded() {
+        let limits = PreLoginLimits {
+            max_messages: 3,
+            max_bytes: 100,
+        };
+
+        let mut budget = PreLoginBudget::new(limits);
+        for _ in 0..3 {
+            assert_eq!(budget.spend(1), Ok(()));
+        }
+        assert_eq!(budget.spend(1), Err(BudgetExceeded::Messages(3)));
+
+        let mut budget = PreLoginBudget::new(limits);
+        assert_eq!(budget.spend(100), Ok(()));
+        assert_eq!(budget.spend(1), Err(BudgetExceeded::Bytes(100)));
+    }
+}
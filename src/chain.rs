@@ -0,0 +1,143 @@
+use anyhow::{Result, bail};
+use std::sync::Arc;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::client::Request;
+use tungstenite::http::{HeaderMap, HeaderValue};
+
+use crate::net;
+
+/// Sent on upstream connections and handshake responses, `<version> <id>`
+pub const HEADER: &str = "X-APX-Proxy";
+
+/// What a proxy does when its upstream turns out to be another APX
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChainMode {
+    /// Refuse the connection, both proxies would intercept the login
+    Deny,
+    /// Hand the login over to the other APX: passwords go through untouched and are checked there
+    Passthrough,
+}
+
+#[derive(Debug)]
+pub struct InvalidChainMode(String);
+
+impl std::fmt::Display for InvalidChainMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected deny or passthrough, got {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidChainMode {}
+
+impl std::str::FromStr for ChainMode {
+    type Err = InvalidChainMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "deny" => Ok(ChainMode::Deny),
+            "passthrough" => Ok(ChainMode::Passthrough),
+            _ => Err(InvalidChainMode(s.to_string())),
+        }
+    }
+}
+
+/// Tells a proxy apart from other APX instances, even ones on the same host
+#[derive(Clone)]
+pub struct Identity(Arc<str>);
+
+/// A new random one for each proxy
+impl Default for Identity {
+    fn default() -> Self {
+        Self(format!("{:016x}", rand::random::<u64>()).into())
+    }
+}
+
+impl Identity {
+    /// Marks headers of a request or response as coming from this proxy
+    pub fn mark(&self, headers: &mut HeaderMap) {
+        let value = format!("{} {}", env!("CARGO_PKG_VERSION"), self.0);
+        let value = HeaderValue::from_str(&value).expect("version and id are valid in a header");
+        headers.insert(HEADER, value);
+    }
+
+    /// Upstream connection request carrying our header
+    pub fn upstream_request(&self, upstream_url: &str) -> tungstenite::Result<Request> {
+        let mut request = upstream_url.into_client_request()?;
+        self.mark(request.headers_mut());
+        Ok(request)
+    }
+
+    /// Who's on the other side of a connection, from its request or response headers
+    pub fn peer<'a>(&self, headers: &'a HeaderMap) -> Peer<'a> {
+        let Some(value) = headers.get(HEADER).and_then(|v| v.to_str().ok()) else {
+            return Peer::Plain;
+        };
+        match value.split_once(' ') {
+            Some((_, id)) if id == &*self.0 => Peer::Itself,
+            Some((version, _)) => Peer::Apx(version),
+            None => Peer::Apx(value),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Peer<'a> {
+    /// Not a proxy, or not one that tells
+    Plain,
+    /// Another APX, with the version it reported
+    Apx(&'a str),
+    /// This very proxy, the connection loops back onto itself
+    Itself,
+}
+
+/// Refuses an upstream that is one of our own listeners, every connection would loop back in.
+/// Only catches what resolves to this host, anything else is caught by the header on connect.
+pub async fn refuse_self_connection(ap_server: &str, ports: &[u16]) -> Result<()> {
+    let Ok(addrs) = tokio::net::lookup_host(ap_server).await else {
+        // Reported with a better error when connecting
+        return Ok(());
+    };
+    for addr in addrs {
+        if ports.contains(&addr.port()) && net::is_local(addr.ip()) {
+            bail!(
+                "AP_SERVER {} resolves to {}, which is this proxy's own listener",
+                ap_server,
+                addr
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_from_headers() {
+        let identity = Identity::default();
+        let mut headers = HeaderMap::new();
+        assert_eq!(identity.peer(&headers), Peer::Plain);
+
+        identity.mark(&mut headers);
+        assert_eq!(identity.peer(&headers), Peer::Itself);
+        assert_eq!(
+            Identity::default().peer(&headers),
+            Peer::Apx(env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refuses_own_listener() {
+        assert!(
+            refuse_self_connection("127.0.0.1:36000", &[36000, 36001])
+                .await
+                .is_err()
+        );
+        assert!(
+            refuse_self_connection("127.0.0.1:38281", &[36000, 36001])
+                .await
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use anyhow::Result;
+use aprs_proto::primitives::SlotId;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::db::DieselPool;
+use crate::db::models;
+use crate::diagnostics;
+use crate::resume;
+
+pub const CLAIMS_SETTING_KEY: &str = "slot_claims";
+/// Wait before persisting again after a failure
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+struct Claim {
+    uuid: String,
+    /// Last login to the slot or end of one of its sessions
+    last_seen: DateTime<Utc>,
+    /// Sessions currently logged in with the claim
+    sessions: usize,
+}
+
+/// A claim as persisted and listed through the API
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ClaimedSlot {
+    pub slot: SlotId,
+    pub uuid: String,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// The login uuid doesn't match the slot's claim
+#[derive(Debug, PartialEq)]
+pub struct ClaimRefused;
+
+/// Locks each slot to the uuid of the first client that logged in to it, so rooms without
+/// passwords still keep others out of a slot once it's being played. A claim expires once its
+/// slot went `ttl` without a session.
+pub struct SlotClaims {
+    ttl: chrono::Duration,
+    claims: Mutex<HashMap<SlotId, Claim>>,
+    /// Woken on every change, for `persist`
+    changed: Notify,
+}
+
+/// Held by a session logged in with a claim, the claim can't expire until it's dropped
+pub struct ClaimHold {
+    claims: Arc<SlotClaims>,
+    slot: SlotId,
+    uuid: String,
+}
+
+impl Drop for ClaimHold {
+    fn drop(&mut self) {
+        self.claims.release(self.slot, &self.uuid, Utc::now());
+    }
+}
+
+impl SlotClaims {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl: chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX),
+            claims: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Claims persisted by a previous run, as stored under `CLAIMS_SETTING_KEY`
+    pub fn restore(&self, setting: serde_json::Value) -> Result<usize> {
+        let restored: Vec<ClaimedSlot> = serde_json::from_value(setting)?;
+        let count = restored.len();
+        let mut claims = self.claims.lock().unwrap();
+        for claimed in restored {
+            claims.insert(
+                claimed.slot,
+                Claim {
+                    uuid: claimed.uuid,
+                    last_seen: claimed.last_seen,
+                    sessions: 0,
+                },
+            );
+        }
+        Ok(count)
+    }
+
+    /// Lets a login with `uuid` in to `slot`, claiming it if nobody did. Logins without a uuid
+    /// can't claim a slot.
+    pub fn admit(
+        self: &Arc<Self>,
+        slot: SlotId,
+        uuid: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<ClaimHold>, ClaimRefused> {
+        let mut claims = self.claims.lock().unwrap();
+        if claims
+            .get(&slot)
+            .is_some_and(|claim| self.is_expired(claim, now))
+        {
+            log::info!("Claim on slot {} expired", slot.0);
+            claims.remove(&slot);
+        }
+        match claims.get_mut(&slot) {
+            Some(claim) if claim.uuid != uuid => return Err(ClaimRefused),
+            Some(claim) => {
+                claim.sessions += 1;
+                claim.last_seen = now;
+            }
+            None if uuid.is_empty() => return Ok(None),
+            None => {
+                log::info!("Slot {} claimed by client {}", slot.0, uuid);
+                claims.insert(
+                    slot,
+                    Claim {
+                        uuid: uuid.to_string(),
+                        last_seen: now,
+                        sessions: 1,
+                    },
+                );
+            }
+        }
+        self.changed.notify_one();
+        Ok(Some(ClaimHold {
+            claims: self.clone(),
+            slot,
+            uuid: uuid.to_string(),
+        }))
+    }
+
+    /// The uuid `slot` is claimed by, if it's the one hashing to `uuid_hash`
+    pub fn claimant(&self, slot: SlotId, uuid_hash: &str) -> Option<String> {
+        let claims = self.claims.lock().unwrap();
+        let claim = claims.get(&slot)?;
+        (resume::hash(&claim.uuid) == uuid_hash).then(|| claim.uuid.clone())
+    }
+
+    /// Returns whether the slot was claimed
+    pub fn clear(&self, slot: SlotId) -> bool {
+        let cleared = self.claims.lock().unwrap().remove(&slot).is_some();
+        if cleared {
+            self.changed.notify_one();
+        }
+        cleared
+    }
+
+    /// Claims that haven't expired, by slot
+    pub fn claimed(&self, now: DateTime<Utc>) -> Vec<ClaimedSlot> {
+        let mut claimed: Vec<ClaimedSlot> = self
+            .claims
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, claim)| !self.is_expired(claim, now))
+            .map(|(slot, claim)| ClaimedSlot {
+                slot: *slot,
+                uuid: claim.uuid.clone(),
+                last_seen: claim.last_seen,
+            })
+            .collect();
+        claimed.sort_unstable_by_key(|claimed| claimed.slot);
+        claimed
+    }
+
+    fn release(&self, slot: SlotId, uuid: &str, now: DateTime<Utc>) {
+        let mut claims = self.claims.lock().unwrap();
+        // Cleared, or claimed again by someone else, while the session went on
+        let Some(claim) = claims.get_mut(&slot).filter(|claim| claim.uuid == uuid) else {
+            return;
+        };
+        claim.sessions = claim.sessions.saturating_sub(1);
+        claim.last_seen = now;
+        self.changed.notify_one();
+    }
+
+    fn is_expired(&self, claim: &Claim, now: DateTime<Utc>) -> bool {
+        claim.sessions == 0 && now.signed_duration_since(claim.last_seen) >= self.ttl
+    }
+}
+
+/// Writes the claims to the room settings whenever they change
+pub async fn persist(pool: DieselPool, room_id: String, claims: Arc<SlotClaims>) {
+    loop {
+        claims.changed.notified().await;
+        diagnostics::heartbeat("slot_claims");
+        let setting =
+            serde_json::to_value(claims.claimed(Utc::now())).expect("claims always serialize");
+        if let Err(e) = models::set_room_setting(&pool, &room_id, CLAIMS_SETTING_KEY, setting).await
+        {
+            log::error!("Failed to persist slot claims, retrying: {:?}", e);
+            tokio::time::sleep(RETRY_DELAY).await;
+            claims.changed.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 4, 10, hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_first_login_claims_the_slot() {
+        let claims = Arc::new(SlotClaims::new(HOUR));
+        let hold = claims.admit(SlotId(1), "alice-uuid", at(0)).unwrap();
+        assert!(hold.is_some());
+        assert_eq!(
+            claims.admit(SlotId(1), "mallory-uuid", at(0)).err(),
+            Some(ClaimRefused)
+        );
+        // Reconnecting from the same client is fine, even alongside the first session,
+        // and other slots are unaffected
+        assert!(claims.admit(SlotId(1), "alice-uuid", at(0)).is_ok());
+        assert!(claims.admit(SlotId(2), "mallory-uuid", at(0)).is_ok());
+
+        // Without a uuid there's nothing to claim with
+        assert!(claims.admit(SlotId(3), "", at(0)).unwrap().is_none());
+        assert!(claims.admit(SlotId(3), "bob-uuid", at(0)).is_ok());
+    }
+
+    #[test]
+    fn test_claims_expire_without_sessions() {
+        let claims = Arc::new(SlotClaims::new(2 * HOUR));
+        let now = Utc::now();
+        let hours = |hours| now + chrono::Duration::hours(hours);
+        let hold = claims.admit(SlotId(1), "alice-uuid", now).unwrap();
+        // A session in progress keeps the claim, however long it goes
+        assert!(claims.admit(SlotId(1), "mallory-uuid", hours(5)).is_err());
+        drop(hold);
+
+        assert!(claims.admit(SlotId(1), "mallory-uuid", hours(1)).is_err());
+        assert_eq!(claims.claimed(hours(3)), []);
+        assert!(claims.admit(SlotId(1), "mallory-uuid", hours(3)).is_ok());
+    }
+
+    #[test]
+    fn test_cleared_and_restored_claims() {
+        let claims = Arc::new(SlotClaims::new(HOUR));
+        let _hold = claims.admit(SlotId(1), "alice-uuid", at(0)).unwrap();
+        assert!(claims.clear(SlotId(1)));
+        assert!(!claims.clear(SlotId(1)));
+        let _hold = claims.admit(SlotId(1), "bob-uuid", at(0)).unwrap();
+
+        let restored = SlotClaims::new(HOUR);
+        let setting = serde_json::to_value(claims.claimed(at(0))).unwrap();
+        assert_eq!(restored.restore(setting).unwrap(), 1);
+        assert_eq!(restored.claimed(at(0))[0].uuid, "bob-uuid");
+    }
+}
@@ -2,57 +2,560 @@ use anyhow::{Context, Result};
 use aprs_proto::primitives::SlotId;
 use reqwest::Url;
 use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use tokio::sync::RwLock;
 
+use crate::bandwidth::Quota;
+use crate::bounce_log::RecordedSlots;
+use crate::budget::PreLoginLimits;
+use crate::chain::ChainMode;
+use crate::db_writer::EventSinks;
+use crate::deathlink_feedback::DeathlinkFeedback;
+use crate::flapping::FlapLimits;
+use crate::json_limits::{CommandSizeLimits, ParseLimits};
+use crate::net::TlsDetection;
+use crate::origin::AllowedOrigins;
+use crate::outbox::ResponseLimits;
+use crate::permissions::PermissionOverrides;
+use crate::preferences::PreferenceMap;
+use crate::proxy::RoomRoute;
+use crate::ready::{DEFAULT_START_ANNOUNCEMENT, ExemptSlots};
+use crate::release_pacing::ReleasePacing;
+use crate::reload::{Live, Reloader};
+use crate::retention::RetentionPolicy;
+use crate::scheduled_messages::Announcements;
+use crate::selftest::{FailureMode, SelfTestOptions, SelfTestReport};
+use crate::send::DroppableCommands;
+use crate::session::SessionLimits;
+use crate::stats::Schedule;
+use crate::transforms::Transforms;
+use crate::upstream::{ApServer, ApServers};
+
 pub struct Config {
     pub lobby_root_url: Url,
     pub lobby_api_key: String,
+    /// How long a failed lobby fetch is answered from memory instead of asking the lobby again
+    pub lobby_negative_cache: Duration,
+    /// How long startup keeps retrying a lobby that fails before giving up
+    pub lobby_fetch_timeout: Duration,
+    /// Password refreshes returning fewer slots than this are refused, unless forced
+    pub password_min_slots: usize,
+    /// How often the slot passwords are fetched again from the lobby, zero for never
+    pub password_refresh_interval: Duration,
     pub db_url: String,
+    /// Whether a fresh install hash partitions its event tables by room
+    pub db_partitioned: bool,
     pub apx_api_key: String,
+    /// Where the admin API listens, Rocket's own configuration decides when unset
+    pub api_bind_addr: Option<IpAddr>,
+    pub api_port: Option<u16>,
+    pub api_tls_cert_path: Option<String>,
+    pub api_tls_key_path: Option<String>,
+    /// Refuses to start if the admin API would listen on a public address
+    pub api_require_private: bool,
     pub room_id: String,
-    pub ap_server: String,
+    pub ap_server: ApServer,
+    /// Tried in order when AP_SERVER can't be reached
+    pub ap_server_fallbacks: Vec<ApServer>,
+    /// How long resolved addresses of the AP servers are kept
+    pub upstream_resolve_interval: Duration,
+    /// How long upstream has to send its RoomInfo once connected before the client is let go
+    pub room_info_timeout: Duration,
     pub tls_cert_path: Option<String>,
     pub tls_key_path: Option<String>,
+    pub acme_domain: Option<String>,
+    pub acme_contact_email: Option<String>,
+    pub acme_cache_dir: String,
+    pub tls_detection: TlsDetection,
+    /// Origins browsers may connect from, any when unset. Clients without an Origin header are
+    /// always let in.
+    pub allowed_origins: Option<AllowedOrigins>,
+    pub denial_cooldown: Duration,
+    /// How long after logging in a connection doesn't receive deathlinks, zero for never
+    pub deathlink_grace: Duration,
+    /// Who is told when a DeathLink is absorbed instead of reaching a slot
+    pub deathlink_feedback: DeathlinkFeedback,
+    pub listen_dual_stack: bool,
+    pub webhook_url: Option<Url>,
+    pub motd: Option<String>,
+    /// Announcements sent to everyone logged in on a schedule, until changed through the API
+    pub scheduled_messages: Announcements,
+    /// Rooms on other AP servers, by their path. They only get the passwords APX checks:
+    /// UPSTREAM_ROOM_PASSWORD, RESUME_WINDOW_SECONDS and SLOT_CLAIMING are for the default room.
+    pub room_routes: Vec<RoomRouteConfig>,
+    /// Connections past this many are closed as soon as they're accepted
+    pub max_concurrent_connections: Option<usize>,
+    /// Connections without a login after this long are logged as stale
+    pub stale_connection_age: Duration,
+    pub max_upstream_connections: Option<usize>,
+    pub upstream_queue_wait: Duration,
+    /// Logins past this many wait in the login queue, trackers aren't counted
+    pub max_logged_in_clients: Option<usize>,
+    /// How long a login waits in the queue before it's refused
+    pub login_queue_max_wait: Duration,
+    /// Upstream messages a second that switch to degraded mode, never on its own when unset
+    pub degrade_messages_per_second: Option<u64>,
+    /// How long the rate has to stay below half of that for degraded mode to end
+    pub degrade_recovery: Duration,
+    pub per_slot_gauges: bool,
+    /// Root URL of the primary instance when running as a warm standby
+    pub follow_url: Option<Url>,
+    pub follow_interval: Duration,
+    /// Keeps soft state changed through the API in sync with the instances sharing the database
+    pub coordinate_instances: bool,
+    /// Shared with the lobby to sign connection tokens
+    pub token_secret: Option<String>,
+    /// Keys the HMAC login attempts are recorded as, see `audit::AuditKey`
+    pub audit_hmac_key: Option<String>,
+    /// Server password of the AP room itself, sent upstream in place of the slot passwords APX
+    /// checks. Only for the default room.
+    pub upstream_room_password: Option<String>,
+    /// Upstream messages beyond these aren't parsed, only forwarded as is. Strings too long and
+    /// numbers that aren't finite are only logged.
+    pub upstream_parse_limits: ParseLimits,
+    /// Client messages beyond these close the connection before they're parsed
+    pub client_parse_limits: ParseLimits,
+    /// Client commands over their limit are dropped, upstream ones are only counted
+    pub command_size_limits: CommandSizeLimits,
+    /// WebSocket messages from clients past this many bytes close their connection, 64MiB when
+    /// unset
+    pub client_max_message_size: Option<usize>,
+    /// Answers commands AP doesn't define with an InvalidPacket rather than forwarding them
+    pub strict_commands: bool,
+    /// InvalidPackets a connection is sent before the next one closes it instead
+    pub invalid_packet_limit: usize,
+    pub prelogin_limits: PreLoginLimits,
+    /// How many failed password validations are kept for
+    /// `/api/rooms/<room_id>/password_failures`
+    pub password_failure_history: usize,
+    pub response_limits: ResponseLimits,
+    /// Approximate bytes the buffers of a single connection may hold before it's closed,
+    /// unlimited when unset
+    pub per_connection_memory_cap: Option<usize>,
+    /// When daily stats are aggregated
+    pub stats_schedule: Schedule,
+    /// How often the traffic counted for daily stats and slot bandwidth is persisted
+    pub stats_snapshot_interval: Duration,
+    pub bandwidth_quota: Quota,
+    /// Given up on rather than the connection when the client's socket stays congested
+    pub client_droppable_commands: DroppableCommands,
+    /// Presented to clients instead of upstream's permissions when stricter, and enforced on
+    /// their `!release`, `!collect` and `!remaining`
+    pub override_permissions: Option<PermissionOverrides>,
+    /// Rules of TRANSFORMS_PATH, read again on every reload
+    pub transforms: Transforms,
+    /// Lets players change hints of items other slots receive, which some AP versions allow
+    pub allow_cross_slot_hint_updates: bool,
+    /// Spreads out what a goal releases to everyone over time, rather than all at once
+    pub release_pacing: Option<ReleasePacing>,
+    /// Moves logged in trackers to one upstream connection per slot they share
+    pub mirror_trackers: bool,
+    /// Locks each slot of the default room to the client uuid that first logged in to it
+    pub slot_claiming: bool,
+    /// How long a claim outlives its slot's last session
+    pub claim_ttl: Duration,
+    /// How long after a session ended it can still be resumed with its token, without the slot's
+    /// password. No tokens are handed out when unset, nor in the rooms of `room_routes`.
+    pub resume_window: Option<Duration>,
+    /// Slots whose Bounces are stored for `/api/rooms/<room_id>/bounces`, unless set through
+    /// the API
+    pub record_bounces: RecordedSlots,
+    /// Bounces recorded per slot per day at most
+    pub bounce_daily_cap: usize,
+    /// Slots a synchronized start doesn't wait to be ready
+    pub ready_exempt_slots: ExemptSlots,
+    /// Shown to every client once everyone is ready and the start goes through
+    pub start_announcement: String,
+    /// Whether the start also runs a countdown upstream, sent by one of the ready players. The
+    /// proxy still refuses the ones players send themselves.
+    pub start_countdown: bool,
+    pub session_limits: SessionLimits,
+    /// What happens when AP_SERVER turns out to be another APX
+    pub chain_mode: ChainMode,
+    pub flap_limits: FlapLimits,
+    /// Whether a self-test against upstream runs before players are let in
+    pub startup_selftest: bool,
+    pub selftest_failure: FailureMode,
+    pub selftest: SelfTestOptions,
+    /// Events that failed to be stored, kept in memory by each sink until it's back
+    pub db_retry_queue: usize,
+    /// How long deathlinks and countdowns may wait to be stored together, zero stores them as
+    /// they come
+    pub db_batch_delay: Duration,
+    /// Where events past `db_retry_queue` go instead of being dropped, the `http` sink uses its
+    /// `http` subdirectory
+    pub spill_dir: Option<PathBuf>,
+    /// Days of history kept by table, pruned hourly
+    pub retention: RetentionPolicy,
+    pub event_sinks: EventSinks,
+    /// Collector the `http` event sink posts to
+    pub event_sink_url: Option<Url>,
+    pub event_sink_token: Option<String>,
+    /// `/api/bridge` of the APX instance of another room, where the players' chat is relayed
+    pub bridge_peer_url: Option<Url>,
+    /// Signs the chat relayed both ways, `/api/bridge` only accepts chat with it set
+    pub bridge_shared_secret: Option<String>,
+}
+
+/// Additional room reachable through `/room/<room_id>`, on its own AP server
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoomRouteConfig {
+    pub room_id: String,
+    pub ap_server: ApServer,
+}
+
+/// Where settings are read from: the environment, overlaid by the `KEY=VALUE` lines of
+/// CONFIG_FILE when it's set. Only the file can change while running, reloads re-read it.
+#[derive(Clone, Default)]
+pub struct Vars(HashMap<String, String>);
+
+impl Vars {
+    pub fn load() -> Result<Self> {
+        // Anything that isn't unicode can't be one of ours
+        let mut vars: HashMap<String, String> = std::env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        if let Some(path) = vars.get("CONFIG_FILE").cloned() {
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read CONFIG_FILE {}", path))?;
+            vars.extend(parse_config_file(&contents).context("CONFIG_FILE")?);
+        }
+        Ok(Self(vars))
+    }
+
+    fn var(&self, name: &str) -> Option<String> {
+        self.0.get(name).cloned()
+    }
+
+    fn parse<T>(&self, name: &str) -> Result<Option<T>>
+    where
+        T: std::str::FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        self.0
+            .get(name)
+            .map(|value| value.parse::<T>())
+            .transpose()
+            .with_context(|| name.to_string())
+    }
+
+    /// Takes the values `names` have in `other`
+    pub fn update(&mut self, other: &Vars, names: &[String]) {
+        for name in names {
+            match other.0.get(name) {
+                Some(value) => self.0.insert(name.clone(), value.clone()),
+                None => self.0.remove(name),
+            };
+        }
+    }
+
+    /// Names of the settings that differ from `other`, sorted
+    pub fn changed(&self, other: &Vars) -> Vec<String> {
+        let mut changed: Vec<String> = self
+            .0
+            .keys()
+            .chain(other.0.keys())
+            .filter(|name| self.0.get(*name) != other.0.get(*name))
+            .cloned()
+            .collect();
+        changed.sort_unstable();
+        changed.dedup();
+        changed
+    }
+}
+
+/// `KEY=VALUE` lines, blank ones and `#` comments are skipped
+fn parse_config_file(contents: &str) -> Result<HashMap<String, String>> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let Some((name, value)) = line.split_once('=') else {
+                anyhow::bail!("Expected KEY=VALUE, got {}", line);
+            };
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self> {
-        Ok(Config {
-            lobby_root_url: std::env::var("LOBBY_ROOT_URL")
+    pub fn from_vars(vars: &Vars) -> Result<Self> {
+        let config = Config {
+            lobby_root_url: vars
+                .var("LOBBY_ROOT_URL")
                 .context("LOBBY_ROOT_URL")?
                 .parse()
                 .context("LOBBY_ROOT_URL")?,
-            lobby_api_key: std::env::var("LOBBY_API_KEY").context("LOBBY_API_KEY")?,
-            db_url: std::env::var("DATABASE_URL").context("DATABASE_URL")?,
-            apx_api_key: std::env::var("APX_API_KEY").context("APX_API_KEY")?,
-            room_id: std::env::var("LOBBY_ROOM_ID").context("LOBBY_ROOM_ID")?,
-            ap_server: std::env::var("AP_SERVER").context("AP_SERVER")?,
-            tls_cert_path: std::env::var("TLS_CERT_PATH").ok(),
-            tls_key_path: std::env::var("TLS_KEY_PATH").ok(),
-        })
+            lobby_api_key: vars.var("LOBBY_API_KEY").context("LOBBY_API_KEY")?,
+            lobby_negative_cache: Duration::from_secs(
+                vars.parse("LOBBY_NEGATIVE_CACHE_SECONDS")?.unwrap_or(10),
+            ),
+            lobby_fetch_timeout: Duration::from_secs(
+                vars.parse("LOBBY_FETCH_TIMEOUT")?.unwrap_or(300),
+            ),
+            password_min_slots: vars.parse("PASSWORD_MIN_SLOTS")?.unwrap_or(0),
+            password_refresh_interval: Duration::from_secs(
+                vars.parse("PASSWORD_REFRESH_INTERVAL")?.unwrap_or(300),
+            ),
+            db_url: vars.var("DATABASE_URL").context("DATABASE_URL")?,
+            db_partitioned: vars.parse("DB_PARTITIONED")?.unwrap_or(false),
+            apx_api_key: vars.var("APX_API_KEY").context("APX_API_KEY")?,
+            api_bind_addr: vars.parse("API_BIND_ADDR")?,
+            api_port: vars.parse("API_PORT")?,
+            api_tls_cert_path: vars.var("API_TLS_CERT_PATH"),
+            api_tls_key_path: vars.var("API_TLS_KEY_PATH"),
+            api_require_private: vars.parse("API_REQUIRE_PRIVATE")?.unwrap_or(false),
+            room_id: vars.var("LOBBY_ROOM_ID").context("LOBBY_ROOM_ID")?,
+            ap_server: vars.parse("AP_SERVER")?.context("AP_SERVER")?,
+            ap_server_fallbacks: vars
+                .parse::<ApServers>("AP_SERVER_FALLBACKS")?
+                .unwrap_or_default()
+                .0,
+            upstream_resolve_interval: Duration::from_secs(
+                vars.parse("UPSTREAM_RESOLVE_INTERVAL")?.unwrap_or(300),
+            ),
+            room_info_timeout: Duration::from_secs(vars.parse("ROOMINFO_TIMEOUT")?.unwrap_or(15)),
+            tls_cert_path: vars.var("TLS_CERT_PATH"),
+            tls_key_path: vars.var("TLS_KEY_PATH"),
+            acme_domain: vars.var("ACME_DOMAIN"),
+            acme_contact_email: vars.var("ACME_CONTACT_EMAIL"),
+            acme_cache_dir: vars
+                .var("ACME_CACHE_DIR")
+                .unwrap_or_else(|| "acme_cache".to_string()),
+            tls_detection: vars.parse("TLS_DETECTION")?.unwrap_or(TlsDetection::Auto),
+            allowed_origins: vars.parse("ALLOWED_ORIGINS")?,
+            denial_cooldown: Duration::from_secs(
+                vars.parse("DENIAL_COOLDOWN_SECONDS")?.unwrap_or(5),
+            ),
+            deathlink_grace: Duration::from_secs(
+                vars.parse("DEATHLINK_GRACE_SECONDS")?.unwrap_or(0),
+            ),
+            deathlink_feedback: vars.parse("DEATHLINK_FEEDBACK")?.unwrap_or_default(),
+            listen_dual_stack: vars.parse("LISTEN_DUAL_STACK")?.unwrap_or(false),
+            webhook_url: vars.parse("WEBHOOK_URL")?,
+            motd: vars.var("MOTD"),
+            scheduled_messages: vars.parse("SCHEDULED_MESSAGES")?.unwrap_or_default(),
+            room_routes: vars
+                .var("ROOM_ROUTES")
+                .map(|routes| parse_room_routes(&routes))
+                .transpose()
+                .context("ROOM_ROUTES")?
+                .unwrap_or_default(),
+            max_concurrent_connections: vars.parse("MAX_CONCURRENT_CONNECTIONS")?,
+            stale_connection_age: Duration::from_secs(
+                vars.parse("STALE_CONNECTION_SECONDS")?.unwrap_or(300),
+            ),
+            max_upstream_connections: vars.parse("MAX_UPSTREAM_CONNECTIONS")?,
+            upstream_queue_wait: Duration::from_secs(
+                vars.parse("UPSTREAM_QUEUE_WAIT_SECONDS")?.unwrap_or(10),
+            ),
+            max_logged_in_clients: vars.parse("MAX_LOGGED_IN_CLIENTS")?,
+            login_queue_max_wait: Duration::from_secs(
+                vars.parse("LOGIN_QUEUE_MAX_WAIT_SECONDS")?.unwrap_or(1800),
+            ),
+            degrade_messages_per_second: vars.parse("DEGRADE_MESSAGES_PER_SECOND")?,
+            degrade_recovery: Duration::from_secs(
+                vars.parse("DEGRADE_RECOVERY_SECONDS")?.unwrap_or(30),
+            ),
+            per_slot_gauges: vars.parse("PER_SLOT_GAUGES")?.unwrap_or(true),
+            follow_url: vars.parse("FOLLOW_URL")?,
+            follow_interval: Duration::from_secs(
+                vars.parse("FOLLOW_INTERVAL_SECONDS")?.unwrap_or(5),
+            ),
+            coordinate_instances: vars.parse("COORDINATE_INSTANCES")?.unwrap_or(false),
+            token_secret: vars.var("TOKEN_SECRET").filter(|s| !s.is_empty()),
+            audit_hmac_key: vars.var("AUDIT_HMAC_KEY").filter(|s| !s.is_empty()),
+            upstream_room_password: vars.var("UPSTREAM_ROOM_PASSWORD").filter(|s| !s.is_empty()),
+            upstream_parse_limits: ParseLimits {
+                max_depth: vars.parse("UPSTREAM_MAX_JSON_DEPTH")?.unwrap_or(100),
+                max_size: vars
+                    .parse("UPSTREAM_MAX_PARSE_BYTES")?
+                    .unwrap_or(15 * 1024 * 1024),
+                max_string: vars
+                    .parse("UPSTREAM_MAX_JSON_STRING_BYTES")?
+                    .unwrap_or(1024 * 1024),
+            },
+            client_parse_limits: ParseLimits {
+                max_depth: vars.parse("CLIENT_MAX_JSON_DEPTH")?.unwrap_or(32),
+                max_size: 15 * 1024 * 1024,
+                max_string: vars
+                    .parse("CLIENT_MAX_JSON_STRING_BYTES")?
+                    .unwrap_or(64 * 1024),
+            },
+            command_size_limits: vars.parse("COMMAND_SIZE_LIMITS")?.unwrap_or_default(),
+            client_max_message_size: vars.parse("CLIENT_MAX_MESSAGE_BYTES")?,
+            strict_commands: vars.parse("STRICT_COMMANDS")?.unwrap_or(false),
+            invalid_packet_limit: vars.parse("INVALID_PACKET_LIMIT")?.unwrap_or(10),
+            prelogin_limits: PreLoginLimits {
+                max_messages: vars
+                    .parse("PRELOGIN_MAX_MESSAGES")?
+                    .unwrap_or(PreLoginLimits::default().max_messages),
+                max_bytes: vars
+                    .parse("PRELOGIN_MAX_BYTES")?
+                    .unwrap_or(PreLoginLimits::default().max_bytes),
+            },
+            password_failure_history: vars.parse("PASSWORD_FAILURE_HISTORY")?.unwrap_or(100),
+            response_limits: ResponseLimits {
+                queue_capacity: vars
+                    .parse("RESPONSE_QUEUE_CAPACITY")?
+                    .unwrap_or(ResponseLimits::default().queue_capacity),
+                max_batch: vars
+                    .parse("SYNTHESIZED_MAX_BATCH")?
+                    .unwrap_or(ResponseLimits::default().max_batch),
+                synthesized_share: vars
+                    .parse("SYNTHESIZED_MAX_SHARE")?
+                    .unwrap_or(ResponseLimits::default().synthesized_share),
+                synthesized_burst: vars
+                    .parse("SYNTHESIZED_BURST_BYTES")?
+                    .unwrap_or(ResponseLimits::default().synthesized_burst),
+            },
+            per_connection_memory_cap: vars.parse("PER_CONNECTION_MEMORY_CAP")?,
+            stats_schedule: vars.parse("STATS_SCHEDULE")?.unwrap_or_default(),
+            stats_snapshot_interval: Duration::from_secs(
+                vars.parse("STATS_SNAPSHOT_INTERVAL_SECONDS")?
+                    .unwrap_or(300),
+            ),
+            bandwidth_quota: Quota {
+                daily_bytes: vars.parse("DAILY_BYTE_QUOTA_PER_SLOT")?,
+                limited: vars.parse("QUOTA_LIMITED_COMMANDS")?.unwrap_or_default(),
+            },
+            client_droppable_commands: vars.parse("CLIENT_DROPPABLE_COMMANDS")?.unwrap_or_default(),
+            override_permissions: vars.parse("OVERRIDE_PERMISSIONS")?,
+            transforms: match vars.var("TRANSFORMS_PATH") {
+                Some(path) => Transforms::load(&path)?,
+                None => Transforms::default(),
+            },
+            allow_cross_slot_hint_updates: vars
+                .parse("ALLOW_CROSS_SLOT_HINT_UPDATES")?
+                .unwrap_or(false),
+            release_pacing: if vars.parse("SUPPRESS_AUTO_RELEASE")?.unwrap_or(false) {
+                Some(ReleasePacing {
+                    window: vars
+                        .parse("RELEASE_PACING_WINDOW_SECONDS")?
+                        .map(Duration::from_secs)
+                        .unwrap_or(ReleasePacing::default().window),
+                    items_per_second: vars
+                        .parse("RELEASE_PACING_ITEMS_PER_SECOND")?
+                        .unwrap_or(ReleasePacing::default().items_per_second),
+                })
+            } else {
+                None
+            },
+            mirror_trackers: vars.parse("MIRROR_TRACKERS")?.unwrap_or(false),
+            slot_claiming: vars.parse("SLOT_CLAIMING")?.unwrap_or(false),
+            claim_ttl: Duration::from_secs(3600 * vars.parse("CLAIM_TTL_HOURS")?.unwrap_or(72)),
+            resume_window: vars
+                .parse("RESUME_WINDOW_SECONDS")?
+                .map(Duration::from_secs),
+            record_bounces: vars.parse("RECORD_BOUNCES")?.unwrap_or_default(),
+            bounce_daily_cap: vars.parse("RECORD_BOUNCES_DAILY_CAP")?.unwrap_or(1000),
+            ready_exempt_slots: vars.parse("READY_EXEMPT_SLOTS")?.unwrap_or_default(),
+            start_announcement: vars
+                .var("START_ANNOUNCEMENT")
+                .filter(|text| !text.trim().is_empty())
+                .unwrap_or_else(|| DEFAULT_START_ANNOUNCEMENT.to_string()),
+            start_countdown: vars.parse("START_COUNTDOWN")?.unwrap_or(false),
+            session_limits: SessionLimits {
+                max_duration: vars.parse("MAX_SESSION_SECONDS")?.map(Duration::from_secs),
+                close_at: vars.parse("CLOSE_AT")?,
+                ..SessionLimits::default()
+            },
+            chain_mode: vars.parse("CHAIN_MODE")?.unwrap_or(ChainMode::Deny),
+            flap_limits: FlapLimits {
+                window: Duration::from_secs(60 * vars.parse("FLAP_WINDOW")?.unwrap_or(10)),
+                threshold: vars.parse("FLAP_THRESHOLD")?.unwrap_or(5),
+                notify: vars.parse("FLAP_NOTICE")?.unwrap_or(false),
+            },
+            startup_selftest: vars.parse("STARTUP_SELFTEST")?.unwrap_or(false),
+            selftest_failure: vars
+                .parse("SELFTEST_FAILURE")?
+                .unwrap_or(FailureMode::Abort),
+            selftest: SelfTestOptions {
+                step_timeout: Duration::from_secs(
+                    vars.parse("SELFTEST_TIMEOUT_SECONDS")?.unwrap_or(10),
+                ),
+                datapackage_game: vars
+                    .var("SELFTEST_DATAPACKAGE_GAME")
+                    .filter(|game| !game.is_empty()),
+            },
+            db_retry_queue: vars.parse("DB_RETRY_QUEUE")?.unwrap_or(1000),
+            db_batch_delay: Duration::from_millis(vars.parse("DB_BATCH_DELAY_MS")?.unwrap_or(0)),
+            spill_dir: vars.parse("SPILL_DIR")?,
+            retention: vars.parse("RETENTION_DAYS")?.unwrap_or_default(),
+            event_sinks: vars.parse("EVENT_SINKS")?.unwrap_or_default(),
+            event_sink_url: vars.parse("EVENT_SINK_URL")?,
+            event_sink_token: vars.var("EVENT_SINK_TOKEN"),
+            bridge_peer_url: vars.parse("BRIDGE_PEER_URL")?,
+            bridge_shared_secret: vars.var("BRIDGE_SHARED_SECRET"),
+        };
+        // The claims are only kept for the default room, a routed room would go unlocked
+        if config.slot_claiming && !config.room_routes.is_empty() {
+            anyhow::bail!("SLOT_CLAIMING can't be combined with ROOM_ROUTES");
+        }
+        Ok(config)
     }
 }
 
+/// Parses `room_id=host:port` pairs separated by commas
+fn parse_room_routes(routes: &str) -> Result<Vec<RoomRouteConfig>> {
+    routes
+        .split(',')
+        .map(str::trim)
+        .filter(|route| !route.is_empty())
+        .map(|route| {
+            let Some((room_id, ap_server)) = route.split_once('=') else {
+                anyhow::bail!("Expected room_id=host:port, got {}", route);
+            };
+            let (room_id, ap_server) = (room_id.trim(), ap_server.trim());
+            if room_id.is_empty() || ap_server.is_empty() {
+                anyhow::bail!("Expected room_id=host:port, got {}", route);
+            }
+            Ok(RoomRouteConfig {
+                room_id: room_id.to_string(),
+                ap_server: ap_server.parse()?,
+            })
+        })
+        .collect()
+}
+
 pub struct AppState {
     pub config: Config,
+    /// What of `config` can change on reload, as currently applied
+    pub live: Live,
+    pub reloader: Arc<Reloader>,
     pub passwords: Arc<RwLock<HashMap<SlotId, String>>>,
     pub deathlink_exclusions: Arc<RwLock<HashSet<SlotId>>>,
     pub deathlink_probability: Arc<DeathlinkProbability>,
     pub deferred_datapackage_games: Arc<RwLock<HashSet<String>>>,
+    pub slot_names: Arc<RwLock<HashMap<SlotId, String>>>,
+    pub preferences: Arc<RwLock<PreferenceMap>>,
+    /// Item-link groups of the room, refreshed on every login
+    pub slot_groups: Arc<RwLock<crate::groups::SlotGroups>>,
+    pub motd: Arc<RwLock<Option<String>>>,
+    pub bounce_recorder: Arc<crate::bounce_log::BounceRecorder>,
+    pub scheduled_messages: Arc<crate::scheduled_messages::ScheduledMessages>,
     pub db_pool: crate::db::DieselPool,
-}
-
-pub enum Signal {
-    DeathLink {
-        slot: SlotId,
-        source: String,
-        cause: Option<String>,
-    },
-    CountdownInit {
-        slot: SlotId,
-    },
+    pub events: crate::events::EventBus,
+    pub client_registry: Arc<crate::registry::ClientRegistry>,
+    pub login_queue: Arc<crate::login_queue::LoginQueue>,
+    pub load_shedding: Arc<crate::shedding::LoadShedding>,
+    pub standby: Arc<crate::standby::Standby>,
+    pub password_failures: Arc<crate::password_audit::PasswordFailures>,
+    pub slot_claims: Option<Arc<crate::claims::SlotClaims>>,
+    pub coordinator: Option<Arc<crate::coordination::Coordinator>>,
+    pub ready: Arc<crate::ready::ReadyStates>,
+    /// Last self-test against upstream, at startup or through the API
+    pub selftest: Arc<RwLock<Option<SelfTestReport>>>,
+    /// AP server of the default room
+    pub upstream: Arc<crate::upstream::UpstreamServers>,
+    /// Rooms routed to other AP servers, by room id
+    pub rooms: Arc<HashMap<String, RoomRoute>>,
+    pub lobby: Arc<crate::lobby::LobbyClient>,
 }
 
 pub struct DeathlinkProbability(AtomicU64);
@@ -79,3 +582,193 @@ impl DeathlinkProbability {
         clamped
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Just what's required to start, plus `settings`
+    pub(crate) fn test_vars(settings: &[(&str, &str)]) -> Vars {
+        let required = [
+            ("LOBBY_ROOT_URL", "http://127.0.0.1:1"),
+            ("LOBBY_API_KEY", "lobby"),
+            ("DATABASE_URL", "postgres://127.0.0.1:1/apx"),
+            ("APX_API_KEY", "key"),
+            ("LOBBY_ROOM_ID", "test"),
+            ("AP_SERVER", "127.0.0.1:1"),
+        ];
+        let vars = required.iter().chain(settings);
+        Vars(
+            vars.map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    /// Defaults for everything read from the environment, with services nobody listens on
+    pub(crate) fn test_config(room_id: &str) -> Config {
+        Config {
+            lobby_root_url: "http://127.0.0.1:1".parse().unwrap(),
+            lobby_api_key: "lobby".into(),
+            lobby_negative_cache: Duration::ZERO,
+            lobby_fetch_timeout: Duration::ZERO,
+            password_min_slots: 0,
+            password_refresh_interval: Duration::ZERO,
+            db_url: "postgres://127.0.0.1:1/apx".into(),
+            db_partitioned: false,
+            apx_api_key: "key".into(),
+            api_bind_addr: None,
+            api_port: None,
+            api_tls_cert_path: None,
+            api_tls_key_path: None,
+            api_require_private: false,
+            room_id: room_id.into(),
+            ap_server: "127.0.0.1:1".parse().unwrap(),
+            ap_server_fallbacks: Vec::new(),
+            upstream_resolve_interval: Duration::from_secs(300),
+            room_info_timeout: Duration::from_secs(15),
+            tls_cert_path: None,
+            tls_key_path: None,
+            acme_domain: None,
+            acme_contact_email: None,
+            acme_cache_dir: "acme_cache".into(),
+            tls_detection: TlsDetection::Auto,
+            allowed_origins: None,
+            denial_cooldown: Duration::ZERO,
+            deathlink_grace: Duration::ZERO,
+            deathlink_feedback: DeathlinkFeedback::Off,
+            listen_dual_stack: false,
+            webhook_url: None,
+            motd: None,
+            scheduled_messages: Announcements::default(),
+            room_routes: Vec::new(),
+            max_concurrent_connections: None,
+            stale_connection_age: Duration::from_secs(300),
+            max_upstream_connections: None,
+            upstream_queue_wait: Duration::ZERO,
+            max_logged_in_clients: None,
+            login_queue_max_wait: Duration::from_secs(1800),
+            degrade_messages_per_second: None,
+            degrade_recovery: Duration::from_secs(30),
+            per_slot_gauges: false,
+            follow_url: None,
+            follow_interval: Duration::ZERO,
+            coordinate_instances: false,
+            token_secret: None,
+            audit_hmac_key: None,
+            upstream_room_password: None,
+            upstream_parse_limits: ParseLimits {
+                max_depth: 100,
+                max_size: 1024 * 1024,
+                max_string: 1024 * 1024,
+            },
+            client_parse_limits: ParseLimits {
+                max_depth: 32,
+                max_size: 15 * 1024 * 1024,
+                max_string: 64 * 1024,
+            },
+            command_size_limits: CommandSizeLimits::default(),
+            client_max_message_size: None,
+            strict_commands: false,
+            invalid_packet_limit: 10,
+            prelogin_limits: PreLoginLimits::default(),
+            password_failure_history: 0,
+            response_limits: ResponseLimits::default(),
+            per_connection_memory_cap: None,
+            stats_schedule: Schedule::default(),
+            stats_snapshot_interval: Duration::from_secs(300),
+            bandwidth_quota: Quota::default(),
+            client_droppable_commands: DroppableCommands::default(),
+            override_permissions: None,
+            transforms: Transforms::default(),
+            allow_cross_slot_hint_updates: false,
+            release_pacing: None,
+            mirror_trackers: false,
+            slot_claiming: false,
+            claim_ttl: Duration::from_secs(3600),
+            resume_window: None,
+            record_bounces: RecordedSlots::default(),
+            bounce_daily_cap: 1000,
+            ready_exempt_slots: ExemptSlots::default(),
+            start_announcement: DEFAULT_START_ANNOUNCEMENT.to_string(),
+            start_countdown: false,
+            session_limits: SessionLimits::default(),
+            chain_mode: ChainMode::Deny,
+            flap_limits: FlapLimits::default(),
+            startup_selftest: false,
+            selftest_failure: FailureMode::Abort,
+            selftest: SelfTestOptions {
+                step_timeout: Duration::from_secs(1),
+                datapackage_game: None,
+            },
+            db_retry_queue: 0,
+            db_batch_delay: Duration::ZERO,
+            spill_dir: None,
+            retention: RetentionPolicy::default(),
+            event_sinks: EventSinks::default(),
+            event_sink_url: None,
+            event_sink_token: None,
+            bridge_peer_url: None,
+            bridge_shared_secret: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_config_file() {
+        let vars = parse_config_file("# Lobby\nMOTD = Hello = world\n\nCLOSE_AT=\n").unwrap();
+        assert_eq!(vars["MOTD"], "Hello = world");
+        assert_eq!(vars["CLOSE_AT"], "");
+        assert!(parse_config_file("MOTD").is_err());
+    }
+
+    #[test]
+    fn test_ap_servers_are_validated() {
+        let config = Config::from_vars(&test_vars(&[
+            ("AP_SERVER", "ws://ap1"),
+            ("AP_SERVER_FALLBACKS", "ap2:38282, ap3:38283"),
+        ]))
+        .unwrap();
+        assert_eq!(config.ap_server.to_string(), "ap1:38281");
+        assert_eq!(config.ap_server_fallbacks.len(), 2);
+
+        for (name, value) in [
+            ("AP_SERVER", "wss://ap1"),
+            ("AP_SERVER", "ap1:38281/room"),
+            ("AP_SERVER_FALLBACKS", "ap2:38282, ap3:"),
+        ] {
+            let error = Config::from_vars(&test_vars(&[(name, value)]))
+                .err()
+                .unwrap();
+            assert!(format!("{:#}", error).starts_with(name), "{:#}", error);
+        }
+    }
+
+    #[test]
+    fn test_parse_room_routes() {
+        let routes = parse_room_routes("race=ap1:38281, async = ap2:38282,").unwrap();
+        assert_eq!(
+            routes,
+            vec![
+                RoomRouteConfig {
+                    room_id: "race".into(),
+                    ap_server: "ap1:38281".parse().unwrap()
+                },
+                RoomRouteConfig {
+                    room_id: "async".into(),
+                    ap_server: "ap2:38282".parse().unwrap()
+                },
+            ]
+        );
+        assert!(parse_room_routes("").unwrap().is_empty());
+        assert!(parse_room_routes("race").is_err());
+        assert!(parse_room_routes("=ap1:38281").is_err());
+        assert!(parse_room_routes("race=ap1:38281/race").is_err());
+
+        let error = Config::from_vars(&test_vars(&[
+            ("ROOM_ROUTES", "race=ap1:38281"),
+            ("SLOT_CLAIMING", "true"),
+        ]))
+        .err()
+        .unwrap();
+        assert!(error.to_string().contains("SLOT_CLAIMING"), "{:#}", error);
+    }
+}
@@ -0,0 +1,310 @@
+use futures_util::{SinkExt, StreamExt};
+use rocket::data::{IoHandler, IoStream};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::Message;
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, Role};
+
+use crate::admin_ops;
+use crate::config::{AppState, DeathlinkProbability};
+use crate::coordination::Coordinator;
+use crate::db::DieselPool;
+use crate::events::{EventBus, RoomEvent, next_event};
+use crate::registry::{ClientId, ClientRegistry};
+
+/// How long the console waits for the API key before closing
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The upgrade request of a WebSocket
+pub struct WebSocketUpgrade {
+    accept_key: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WebSocketUpgrade {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = req.headers();
+        let upgrade = headers
+            .get("Upgrade")
+            .any(|protocol| protocol.eq_ignore_ascii_case("websocket"));
+        let version = headers.get_one("Sec-WebSocket-Version") == Some("13");
+        match headers.get_one("Sec-WebSocket-Key") {
+            Some(key) if upgrade && version => Outcome::Success(WebSocketUpgrade {
+                accept_key: derive_accept_key(key.as_bytes()),
+            }),
+            _ => Outcome::Error((Status::BadRequest, ())),
+        }
+    }
+}
+
+/// First message of a console, anything else closes it
+#[derive(Deserialize)]
+struct Authentication {
+    api_key: String,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    ListClients,
+    Kick {
+        client_id: ClientId,
+    },
+    Broadcast {
+        text: String,
+    },
+    /// In percent, like `PUT /deathlink_probability`
+    SetDeathlinkProbability {
+        probability: f64,
+    },
+}
+
+#[derive(Deserialize)]
+struct ConsoleRequest {
+    id: u64,
+    #[serde(flatten)]
+    command: Command,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Outgoing<'a> {
+    /// To the request of the same id, `None` when it couldn't be read
+    Response {
+        id: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        result: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Event {
+        event: &'a RoomEvent,
+    },
+}
+
+impl Outgoing<'_> {
+    fn to_message(&self) -> Message {
+        Message::Text(serde_json::to_string(self).unwrap().into())
+    }
+}
+
+/// WebSocket counterpart of the API for overlays and admins, at `/rooms/<room_id>/admin`.
+/// Once the first message brought the API key, the room's events are pushed like SSE does and
+/// commands are answered under the id they came with.
+pub struct Console {
+    accept_key: String,
+    peer: SocketAddr,
+    api_key: String,
+    room_id: String,
+    events: EventBus,
+    client_registry: Arc<ClientRegistry>,
+    db_pool: DieselPool,
+    deathlink_probability: Arc<DeathlinkProbability>,
+    coordinator: Option<Arc<Coordinator>>,
+}
+
+impl Console {
+    pub fn new(upgrade: WebSocketUpgrade, peer: SocketAddr, state: &AppState) -> Self {
+        Self {
+            accept_key: upgrade.accept_key,
+            peer,
+            api_key: state.config.apx_api_key.clone(),
+            room_id: state.config.room_id.clone(),
+            events: state.events.clone(),
+            client_registry: state.client_registry.clone(),
+            db_pool: state.db_pool.clone(),
+            deathlink_probability: state.deathlink_probability.clone(),
+            coordinator: state.coordinator.clone(),
+        }
+    }
+
+    async fn serve<S>(&self, mut ws: WebSocketStream<S>)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let authenticated = match tokio::time::timeout(AUTH_TIMEOUT, ws.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => self.authenticates(&text),
+            _ => false,
+        };
+        if !authenticated {
+            log::warn!(
+                "Closing the admin console of {}, it didn't authenticate",
+                self.peer
+            );
+            let frame = CloseFrame {
+                code: CloseCode::Policy,
+                reason: "Unauthorized".into(),
+            };
+            let _ = ws.send(Message::Close(Some(frame))).await;
+            return;
+        }
+        log::info!("Admin console opened by {}", self.peer);
+
+        let mut events = self.events.subscribe();
+        loop {
+            let outgoing = tokio::select! {
+                message = ws.next() => match message {
+                    Some(Ok(Message::Text(text))) => self.dispatch(&text).await,
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        log::debug!("Admin console of {} failed: {}", self.peer, e);
+                        break;
+                    }
+                },
+                event = next_event(&mut events, "admin_console") => match event {
+                    Some(event) => Outgoing::Event { event: &event }.to_message(),
+                    None => break,
+                },
+            };
+            if ws.send(outgoing).await.is_err() {
+                break;
+            }
+        }
+        log::info!("Admin console of {} closed", self.peer);
+    }
+
+    fn authenticates(&self, text: &str) -> bool {
+        serde_json::from_str::<Authentication>(text)
+            .is_ok_and(|authentication| authentication.api_key == self.api_key)
+    }
+
+    async fn dispatch(&self, text: &str) -> Message {
+        let request: ConsoleRequest = match serde_json::from_str(text) {
+            Ok(request) => request,
+            Err(e) => {
+                let id = serde_json::from_str::<Value>(text)
+                    .ok()
+                    .and_then(|request| request.get("id")?.as_u64());
+                log::info!(
+                    "Admin console of {} sent an unknown command: {}",
+                    self.peer,
+                    e
+                );
+                return Outgoing::Response {
+                    id,
+                    result: None,
+                    error: Some(e.to_string()),
+                }
+                .to_message();
+            }
+        };
+        log::info!(
+            "Admin console of {} sent {:?} ({})",
+            self.peer,
+            request.command,
+            request.id
+        );
+
+        let (result, error) = match self.run(request.command).await {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Outgoing::Response {
+            id: Some(request.id),
+            result,
+            error,
+        }
+        .to_message()
+    }
+
+    async fn run(&self, command: Command) -> Result<Value, String> {
+        match command {
+            Command::ListClients => {
+                let clients = admin_ops::list_clients(&self.client_registry).await;
+                Ok(serde_json::to_value(clients).unwrap())
+            }
+            Command::Kick { client_id } => {
+                if admin_ops::kick(&self.client_registry, client_id).await {
+                    Ok(Value::Null)
+                } else {
+                    Err(format!("No client {}", client_id))
+                }
+            }
+            Command::Broadcast { text } => {
+                let sent = admin_ops::broadcast(&self.client_registry, &text).await;
+                Ok(serde_json::json!({ "sent": sent }))
+            }
+            Command::SetDeathlinkProbability { probability } => {
+                admin_ops::set_deathlink_probability(
+                    &self.db_pool,
+                    &self.deathlink_probability,
+                    self.coordinator.as_deref(),
+                    &self.room_id,
+                    probability,
+                )
+                .await
+                .map(|actual| serde_json::json!({ "probability": actual }))
+                .map_err(|e| {
+                    log::error!("Failed to persist deathlink probability: {:?}", e);
+                    "Failed to persist the probability".to_string()
+                })
+            }
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Console {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .raw_header("Sec-WebSocket-Accept", self.accept_key.clone())
+            .upgrade("websocket", self)
+            .ok()
+    }
+}
+
+#[rocket::async_trait]
+impl IoHandler for Console {
+    async fn io(self: Box<Self>, io: IoStream) -> std::io::Result<()> {
+        let ws = WebSocketStream::from_raw_socket(io, Role::Server, None).await;
+        self.serve(ws).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests_carry_their_command() {
+        let request: ConsoleRequest =
+            serde_json::from_str(r#"{"id": 3, "command": "kick", "client_id": 7}"#).unwrap();
+        assert_eq!(request.id, 3);
+        assert_eq!(request.command, Command::Kick { client_id: 7 });
+        let request: ConsoleRequest =
+            serde_json::from_str(r#"{"id": 4, "command": "list_clients"}"#).unwrap();
+        assert_eq!(request.command, Command::ListClients);
+        assert!(
+            serde_json::from_str::<ConsoleRequest>(r#"{"id": 5, "command": "shutdown"}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn test_responses_are_tagged_apart_from_events() {
+        let response = Outgoing::Response {
+            id: Some(3),
+            result: None,
+            error: Some("No client 7".to_string()),
+        };
+        let Message::Text(text) = response.to_message() else {
+            panic!("expected a text message");
+        };
+        let response: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(
+            response,
+            serde_json::json!({"type": "response", "id": 3, "error": "No client 7"})
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use anyhow::{Context, Result, bail};
+use aprs_proto::primitives::SlotId;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{RwLock, mpsc};
+use tokio_postgres::AsyncMessage;
+use uuid::Uuid;
+
+use crate::bounce_log::{self, BounceRecorder, RecordedSlots};
+use crate::db::{self, DieselPool};
+use crate::motd;
+use crate::preferences;
+use crate::standby::SoftState;
+
+/// Postgres channel changes of the soft state are announced on
+const CHANNEL: &str = "apx_soft_state";
+/// Wait before listening again once the connection dropped
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Soft state changed through the API, as announced to the other instances
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SoftStateKey {
+    Motd,
+    RecordBounces,
+    DeathlinkExclusions,
+    DeathlinkProbability,
+    /// Shared by every room
+    DeferredDatapackageGames,
+    Preferences,
+}
+
+const ALL_KEYS: [SoftStateKey; 6] = [
+    SoftStateKey::Motd,
+    SoftStateKey::RecordBounces,
+    SoftStateKey::DeathlinkExclusions,
+    SoftStateKey::DeathlinkProbability,
+    SoftStateKey::DeferredDatapackageGames,
+    SoftStateKey::Preferences,
+];
+
+/// Payload of a notification on CHANNEL
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Change {
+    /// Instance the change was made on, which has it applied already
+    instance: Uuid,
+    room_id: String,
+    key: SoftStateKey,
+}
+
+/// Keeps the soft state of instances sharing a database in sync, with COORDINATE_INSTANCES.
+/// Changes made through the API are announced once stored, and every other instance reloads
+/// them from the database.
+pub struct Coordinator {
+    instance: Uuid,
+    room_id: String,
+    db_pool: DieselPool,
+    state: SoftState,
+    slot_names: Arc<RwLock<HashMap<SlotId, String>>>,
+    bounce_recorder: Arc<BounceRecorder>,
+}
+
+impl Coordinator {
+    pub fn new(
+        room_id: String,
+        db_pool: DieselPool,
+        state: SoftState,
+        slot_names: Arc<RwLock<HashMap<SlotId, String>>>,
+        bounce_recorder: Arc<BounceRecorder>,
+    ) -> Self {
+        Self {
+            instance: Uuid::new_v4(),
+            room_id,
+            db_pool,
+            state,
+            slot_names,
+            bounce_recorder,
+        }
+    }
+
+    /// Tells the other instances `key` changed. The change stays local to this instance when
+    /// the database can't be reached.
+    pub async fn announce(&self, key: SoftStateKey) {
+        let change = Change {
+            instance: self.instance,
+            room_id: self.room_id.clone(),
+            key,
+        };
+        let payload = serde_json::to_string(&change).unwrap();
+        if let Err(e) = db::models::notify(&self.db_pool, CHANNEL, &payload).await {
+            log::warn!(
+                "Failed to announce the {:?} change to other instances: {:?}, it only applies here",
+                key,
+                e
+            );
+        }
+    }
+
+    async fn apply(&self, payload: &str) {
+        let change: Change = match serde_json::from_str(payload) {
+            Ok(change) => change,
+            Err(e) => {
+                log::warn!("Ignoring announced change {}: {}", payload, e);
+                return;
+            }
+        };
+        if change.instance == self.instance
+            || (change.room_id != self.room_id
+                && change.key != SoftStateKey::DeferredDatapackageGames)
+        {
+            return;
+        }
+
+        match self.reload(change.key).await {
+            Ok(()) => log::info!("Reloaded {:?} changed by another instance", change.key),
+            Err(e) => log::warn!(
+                "Failed to reload {:?} changed by another instance: {:?}",
+                change.key,
+                e
+            ),
+        }
+    }
+
+    /// Replaces `key` with what's stored, settings that were never stored are left alone
+    async fn reload(&self, key: SoftStateKey) -> Result<()> {
+        let (pool, room_id) = (&self.db_pool, self.room_id.as_str());
+        match key {
+            SoftStateKey::Motd => {
+                if let Some(value) =
+                    db::models::get_room_setting(pool, room_id, motd::MOTD_SETTING_KEY).await?
+                {
+                    *self.state.motd.write().await = value.as_str().map(str::to_string);
+                }
+            }
+            SoftStateKey::RecordBounces => {
+                if let Some(value) = db::models::get_room_setting(
+                    pool,
+                    room_id,
+                    bounce_log::RECORD_BOUNCES_SETTING_KEY,
+                )
+                .await?
+                {
+                    let slots: RecordedSlots = value
+                        .as_str()
+                        .with_context(|| {
+                            format!("Recorded Bounce slots {} aren't a string", value)
+                        })?
+                        .parse()?;
+                    self.bounce_recorder.set_slots(slots);
+                }
+            }
+            SoftStateKey::DeathlinkExclusions => {
+                let exclusions = db::models::get_room_deathlink_exclusions(pool, room_id).await?;
+                *self.state.deathlink_exclusions.write().await = exclusions.into_iter().collect();
+            }
+            SoftStateKey::DeathlinkProbability => {
+                if let Some(settings) = db::models::get_deathlink_settings(pool, room_id).await? {
+                    self.state.deathlink_probability.set(settings.probability);
+                }
+            }
+            SoftStateKey::DeferredDatapackageGames => {
+                let games = db::models::get_deferred_datapackage_games(pool).await?;
+                *self.state.deferred_datapackage_games.write().await = games;
+            }
+            SoftStateKey::Preferences => {
+                let slot_names = self.slot_names.read().await.clone();
+                let effective = preferences::load_effective(pool, room_id, &slot_names).await?;
+                *self.state.preferences.write().await = effective;
+            }
+        }
+        Ok(())
+    }
+
+    /// Catches up on whatever changed while nothing was listening
+    async fn reload_all(&self) {
+        for key in ALL_KEYS {
+            if let Err(e) = self.reload(key).await {
+                log::warn!("Failed to reload {:?}: {:?}", key, e);
+            }
+        }
+    }
+}
+
+/// Applies the changes other instances announce, reconnecting whenever the connection drops
+pub async fn listen(coordinator: Arc<Coordinator>, database_url: String) {
+    loop {
+        if let Err(e) = listen_once(&coordinator, &database_url).await {
+            log::warn!(
+                "Stopped listening to other instances: {:?}, retrying in {:?}",
+                e,
+                RECONNECT_DELAY
+            );
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn listen_once(coordinator: &Coordinator, database_url: &str) -> Result<()> {
+    let (client, mut connection) = tokio_postgres::connect(database_url, db::tls())
+        .await
+        .context("Failed to connect to the database")?;
+
+    // Notifications only come out of the connection while it's polled
+    let (notifications_tx, mut notifications) = mpsc::unbounded_channel();
+    let driver = tokio::spawn(async move {
+        let mut messages = futures_util::stream::poll_fn(|cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            if let AsyncMessage::Notification(notification) = message?
+                && notifications_tx.send(notification).is_err()
+            {
+                break;
+            }
+        }
+        Ok::<_, tokio_postgres::Error>(())
+    });
+
+    client.batch_execute(&format!("LISTEN {}", CHANNEL)).await?;
+    log::info!("Listening to soft state changes of other instances");
+    coordinator.reload_all().await;
+
+    while let Some(notification) = notifications.recv().await {
+        coordinator.apply(notification.payload()).await;
+    }
+    driver.await??;
+    bail!("The database closed the connection")
+}
@@ -0,0 +1,159 @@
+use futures_util::{Stream, StreamExt, stream};
+use rocket::http::{ContentType, Header};
+use rocket::response::stream::ByteStream;
+use rocket::response::{self, Responder};
+use rocket::{Request, Response};
+
+/// A table row, written in `HEADER` order
+pub trait CsvRow {
+    const HEADER: &'static [&'static str];
+
+    fn fields(&self) -> Vec<String>;
+}
+
+/// Quotes a field if it contains a delimiter, a quote or a line break, doubling its quotes
+fn escape(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+/// One RFC 4180 record, CRLF included
+pub fn record<I, F>(fields: I) -> String
+where
+    I: IntoIterator<Item = F>,
+    F: AsRef<str>,
+{
+    let mut line = fields
+        .into_iter()
+        .map(|field| escape(field.as_ref()).into_owned())
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+    line
+}
+
+/// Only keeps characters that are safe in a Content-Disposition filename
+fn sanitize_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Streams rows as a CSV attachment, header row first. Rows are encoded as they come, nothing is
+/// buffered. An error once the response started can't change its status anymore, the download
+/// is cut short and the error logged.
+pub struct CsvDownload<S> {
+    filename: String,
+    rows: S,
+}
+
+impl<S, T> CsvDownload<S>
+where
+    S: Stream<Item = anyhow::Result<T>>,
+    T: CsvRow,
+{
+    pub fn new(filename: &str, rows: S) -> Self {
+        Self {
+            filename: sanitize_filename(filename),
+            rows,
+        }
+    }
+
+    fn lines(self) -> impl Stream<Item = String> {
+        let filename = self.filename;
+        let rows = self.rows.scan((), move |_, row| {
+            std::future::ready(match row {
+                Ok(row) => Some(record(row.fields())),
+                Err(e) => {
+                    log::error!("Failed to read rows for {}: {:?}", filename, e);
+                    None
+                }
+            })
+        });
+        stream::once(std::future::ready(record(T::HEADER))).chain(rows)
+    }
+}
+
+impl<'r, S, T> Responder<'r, 'r> for CsvDownload<S>
+where
+    S: Stream<Item = anyhow::Result<T>> + Send + 'r,
+    T: CsvRow + Send + 'r,
+{
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        let disposition = format!("attachment; filename=\"{}\"", self.filename);
+        let body = ByteStream(Box::pin(self.lines().map(String::into_bytes)));
+        Response::build_from(body.respond_to(req)?)
+            .header(ContentType::new("text", "csv").with_params(("charset", "utf-8")))
+            .header(Header::new("Content-Disposition", disposition))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Row(&'static str, Option<&'static str>);
+
+    impl CsvRow for Row {
+        const HEADER: &'static [&'static str] = &["name", "cause"];
+
+        fn fields(&self) -> Vec<String> {
+            vec![self.0.to_string(), self.1.unwrap_or_default().to_string()]
+        }
+    }
+
+    async fn download(rows: Vec<anyhow::Result<Row>>) -> String {
+        CsvDownload::new("test.csv", stream::iter(rows))
+            .lines()
+            .collect::<Vec<_>>()
+            .await
+            .concat()
+    }
+
+    #[test]
+    fn test_quoting() {
+        assert_eq!(record(["plain", "", "with space"]), "plain,,with space\r\n");
+        assert_eq!(
+            record(["a,b", "say \"hi\"", "two\nlines", "cr\rlf"]),
+            "\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\",\"cr\rlf\"\r\n"
+        );
+        assert_eq!(record(["\""]), "\"\"\"\"\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_empty_download_still_has_a_header() {
+        assert_eq!(download(vec![]).await, "name,cause\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_rows_and_errors() {
+        let rows = vec![
+            Ok(Row("Alice", Some("fell, then burned"))),
+            Ok(Row("Bob", None)),
+            Err(anyhow::anyhow!("connection lost")),
+            Ok(Row("Carol", None)),
+        ];
+        assert_eq!(
+            download(rows).await,
+            "name,cause\r\nAlice,\"fell, then burned\"\r\nBob,\r\n"
+        );
+    }
+
+    #[test]
+    fn test_filenames_are_sanitized() {
+        assert_eq!(
+            sanitize_filename("my room/\"x\"-2024.csv"),
+            "my_room__x_-2024.csv"
+        );
+    }
+}
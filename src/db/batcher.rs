@@ -0,0 +1,192 @@
+use anyhow::Result;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::metrics;
+
+/// A batch is flushed once it holds `max_rows`, or once its oldest row waited `max_delay`
+#[derive(Clone, Copy, Debug)]
+pub struct BatchLimits {
+    pub max_rows: usize,
+    pub max_delay: Duration,
+}
+
+impl Default for BatchLimits {
+    /// Flushed as soon as the caller checks
+    fn default() -> Self {
+        Self {
+            max_rows: 100,
+            max_delay: Duration::ZERO,
+        }
+    }
+}
+
+/// Writes rows of a table in one go
+pub trait BatchInsert<T>: Send {
+    /// Labels the batch metrics
+    const TABLE: &'static str;
+
+    fn insert(&mut self, rows: &[T]) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Accumulates rows and hands them to an inserter together. Rows of a failed flush stay in the
+/// batch and go with the next one, the caller decides when to try again.
+pub struct Batcher<T> {
+    limits: BatchLimits,
+    rows: Vec<T>,
+    oldest: Option<Instant>,
+}
+
+impl<T: Send + Sync> Batcher<T> {
+    pub fn new(limits: BatchLimits) -> Self {
+        Self {
+            limits,
+            rows: Vec::new(),
+            oldest: None,
+        }
+    }
+
+    /// When the time trigger fires, for callers that sleep until then
+    pub fn deadline(&self) -> Option<Instant> {
+        self.oldest.map(|oldest| oldest + self.limits.max_delay)
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        self.rows.len() >= self.limits.max_rows || self.deadline().is_some_and(|at| now >= at)
+    }
+
+    /// Adds a row, flushing if the batch is full. Producers wait on the flush rather than
+    /// growing the batch past its size.
+    pub async fn push<I: BatchInsert<T>>(&mut self, row: T, inserter: &mut I) -> Result<()> {
+        self.oldest.get_or_insert_with(Instant::now);
+        self.rows.push(row);
+        if self.rows.len() >= self.limits.max_rows {
+            self.flush(inserter).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn flush_if_due<I: BatchInsert<T>>(&mut self, inserter: &mut I) -> Result<()> {
+        if self.is_due(Instant::now()) {
+            self.flush(inserter).await?;
+        }
+        Ok(())
+    }
+
+    /// Inserts everything in the batch, whatever the triggers say
+    pub async fn flush<I: BatchInsert<T>>(&mut self, inserter: &mut I) -> Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let result = inserter.insert(&self.rows).await;
+        metrics::record_db_flush(I::TABLE, self.rows.len(), started.elapsed(), result.is_ok());
+        result?;
+        self.rows.clear();
+        self.oldest = None;
+        Ok(())
+    }
+
+    /// Forgets the batch, for callers that keep the rows elsewhere until they're stored
+    pub fn discard(&mut self) {
+        self.rows.clear();
+        self.oldest = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records batches, failing the next `failures` of them
+    #[derive(Default)]
+    struct Recorder {
+        batches: Vec<Vec<u32>>,
+        failures: usize,
+    }
+
+    impl BatchInsert<u32> for Recorder {
+        const TABLE: &'static str = "test";
+
+        async fn insert(&mut self, rows: &[u32]) -> Result<()> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                anyhow::bail!("database is down");
+            }
+            self.batches.push(rows.to_vec());
+            Ok(())
+        }
+    }
+
+    fn batcher(max_rows: usize, max_delay: Duration) -> Batcher<u32> {
+        Batcher::new(BatchLimits {
+            max_rows,
+            max_delay,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_size_triggered_flush() {
+        let mut recorder = Recorder::default();
+        let mut batcher = batcher(3, Duration::from_secs(3600));
+        for row in 0..7 {
+            batcher.push(row, &mut recorder).await.unwrap();
+        }
+        assert_eq!(recorder.batches, vec![vec![0, 1, 2], vec![3, 4, 5]]);
+        assert_eq!(batcher.rows, vec![6]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_time_triggered_flush() {
+        let mut recorder = Recorder::default();
+        let mut batcher = batcher(100, Duration::from_millis(50));
+        assert_eq!(batcher.deadline(), None);
+        batcher.push(1, &mut recorder).await.unwrap();
+        batcher.flush_if_due(&mut recorder).await.unwrap();
+        assert!(recorder.batches.is_empty());
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        batcher.push(2, &mut recorder).await.unwrap();
+        // The deadline is the oldest row's
+        let deadline = batcher.deadline().unwrap();
+        tokio::time::sleep_until(deadline - Duration::from_millis(1)).await;
+        batcher.flush_if_due(&mut recorder).await.unwrap();
+        assert!(recorder.batches.is_empty());
+
+        tokio::time::sleep_until(deadline).await;
+        batcher.flush_if_due(&mut recorder).await.unwrap();
+        assert_eq!(recorder.batches, vec![vec![1, 2]]);
+        assert_eq!(batcher.deadline(), None);
+    }
+
+    #[tokio::test]
+    async fn test_flush_on_shutdown_ignores_triggers() {
+        let mut recorder = Recorder::default();
+        let mut batcher = batcher(100, Duration::from_secs(3600));
+        batcher.flush(&mut recorder).await.unwrap();
+        assert!(recorder.batches.is_empty());
+
+        batcher.push(1, &mut recorder).await.unwrap();
+        batcher.push(2, &mut recorder).await.unwrap();
+        assert!(!batcher.is_due(Instant::now()));
+        batcher.flush(&mut recorder).await.unwrap();
+        assert_eq!(recorder.batches, vec![vec![1, 2]]);
+        assert!(batcher.rows.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_flush_keeps_rows() {
+        let mut recorder = Recorder {
+            failures: 2,
+            ..Default::default()
+        };
+        let mut batcher = batcher(2, Duration::from_secs(3600));
+        batcher.push(1, &mut recorder).await.unwrap();
+        assert!(batcher.push(2, &mut recorder).await.is_err());
+        assert!(batcher.push(3, &mut recorder).await.is_err());
+        assert_eq!(batcher.rows, vec![1, 2, 3]);
+
+        batcher.flush(&mut recorder).await.unwrap();
+        assert_eq!(recorder.batches, vec![vec![1, 2, 3]]);
+    }
+}
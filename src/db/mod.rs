@@ -64,6 +64,16 @@ impl rustls::client::danger::ServerCertVerifier for NoCertificateVerification {
     }
 }
 
+/// TLS of connections to the database, whichever certificate it presents
+pub fn tls() -> tokio_postgres_rustls::MakeRustlsConnect {
+    let rustls_config = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+        .with_no_client_auth();
+
+    tokio_postgres_rustls::MakeRustlsConnect::new(rustls_config)
+}
+
 fn establish_connection(
     config: &str,
 ) -> futures_util::future::BoxFuture<'_, Result<AsyncPgConnection, diesel::ConnectionError>> {
@@ -71,14 +81,7 @@ fn establish_connection(
 
     let fut =
         async move {
-            let rustls_config = rustls::ClientConfig::builder()
-                .dangerous()
-                .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
-                .with_no_client_auth();
-
-            let tls = tokio_postgres_rustls::MakeRustlsConnect::new(rustls_config);
-
-            let (client, conn) = tokio_postgres::connect(config, tls).await.map_err(
+            let (client, conn) = tokio_postgres::connect(config, tls()).await.map_err(
                 |e: tokio_postgres::Error| diesel::ConnectionError::BadConnection(e.to_string()),
             )?;
 
@@ -94,7 +97,9 @@ fn establish_connection(
     fut.boxed()
 }
 
-pub async fn init_pool(database_url: &str) -> Result<DieselPool> {
+/// `partitioned` asks the migrations to hash partition the event tables by room, which they only
+/// do on a fresh install
+pub async fn init_pool(database_url: &str, partitioned: bool) -> Result<DieselPool> {
     ring::default_provider()
         .install_default()
         .expect("Failed to set ring as crypto provider");
@@ -118,6 +123,12 @@ pub async fn init_pool(database_url: &str) -> Result<DieselPool> {
             AsyncConnectionWrapper::from(connection);
 
         task::spawn_blocking(move || {
+            if partitioned {
+                use diesel::RunQueryDsl;
+                diesel::sql_query("SET apx.partitioned = 'on'")
+                    .execute(&mut async_wrapper)
+                    .expect("Failed to ask migrations for partitioned tables");
+            }
             async_wrapper.run_pending_migrations(MIGRATIONS).unwrap();
         })
         .await?;
@@ -126,5 +137,31 @@ pub async fn init_pool(database_url: &str) -> Result<DieselPool> {
     Ok(db_pool)
 }
 
+pub mod batcher;
 pub mod models;
 pub mod schema;
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use diesel::Connection;
+
+    /// Connection to a throwaway database with the migrations applied. Tests needing one are
+    /// skipped unless `TEST_DATABASE_URL` is set.
+    pub(crate) fn test_database() -> Option<AsyncConnectionWrapper<AsyncPgConnection>> {
+        let url = std::env::var("TEST_DATABASE_URL").ok()?;
+        let mut conn = AsyncConnectionWrapper::<AsyncPgConnection>::establish(&url).unwrap();
+        conn.run_pending_migrations(MIGRATIONS).unwrap();
+        Some(conn)
+    }
+
+    #[test]
+    fn test_migrations_roundtrip() {
+        let Some(mut conn) = test_database() else {
+            return;
+        };
+        conn.revert_all_migrations(MIGRATIONS).unwrap();
+        conn.run_pending_migrations(MIGRATIONS).unwrap();
+        assert!(!conn.has_pending_migration(MIGRATIONS).unwrap());
+    }
+}
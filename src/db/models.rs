@@ -1,10 +1,14 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use aprs_proto::primitives::SlotId;
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
 use diesel::prelude::*;
+use diesel::query_builder::{BoxedSqlQuery, SqlQuery, UncheckedBind};
+use diesel::sql_types::{Array, BigInt, Integer, Nullable, Text, Timestamptz};
 use diesel_async::RunQueryDsl;
+use futures_util::{Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 #[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
 #[diesel(table_name = super::schema::deathlinks)]
@@ -15,7 +19,8 @@ pub struct DeathLink {
     pub slot: i32,
     pub source: String,
     pub cause: Option<String>,
-    pub created_at: NaiveDateTime,
+    pub created_at: DateTime<Utc>,
+    pub event_id: Option<Uuid>,
 }
 
 #[derive(Debug, Clone, Insertable)]
@@ -25,6 +30,7 @@ pub struct NewDeathLink {
     pub slot: i32,
     pub source: String,
     pub cause: Option<String>,
+    pub event_id: Option<Uuid>,
 }
 
 impl NewDeathLink {
@@ -34,6 +40,7 @@ impl NewDeathLink {
             slot: slot.0 as i32,
             source,
             cause,
+            event_id: None,
         }
     }
 }
@@ -45,7 +52,8 @@ pub struct Countdown {
     pub id: i32,
     pub room_id: String,
     pub slot: i32,
-    pub created_at: NaiveDateTime,
+    pub created_at: DateTime<Utc>,
+    pub event_id: Option<Uuid>,
 }
 
 #[derive(Debug, Clone, Insertable)]
@@ -53,6 +61,7 @@ pub struct Countdown {
 pub struct NewCountdown {
     pub room_id: String,
     pub slot: i32,
+    pub event_id: Option<Uuid>,
 }
 
 impl NewCountdown {
@@ -60,76 +69,285 @@ impl NewCountdown {
         Self {
             room_id,
             slot: slot.0 as i32,
+            event_id: None,
         }
     }
 }
 
-pub async fn insert_deathlink(
+/// Inserting an event already stored under the same `event_id` does nothing
+pub async fn insert_deathlinks(
     pool: &crate::db::DieselPool,
-    new_deathlink: NewDeathLink,
-) -> anyhow::Result<DeathLink> {
+    new_deathlinks: &[NewDeathLink],
+) -> anyhow::Result<()> {
     use super::schema::deathlinks;
 
     let mut conn = pool.get().await?;
 
-    let deathlink = diesel::insert_into(deathlinks::table)
-        .values(&new_deathlink)
-        .get_result(&mut conn)
+    diesel::insert_into(deathlinks::table)
+        .values(new_deathlinks)
+        .on_conflict_do_nothing()
+        .execute(&mut conn)
         .await?;
 
-    Ok(deathlink)
+    Ok(())
 }
 
-pub async fn insert_countdown(
+pub async fn insert_countdowns(
     pool: &crate::db::DieselPool,
-    new_countdown: NewCountdown,
-) -> anyhow::Result<Countdown> {
+    new_countdowns: &[NewCountdown],
+) -> anyhow::Result<()> {
     use super::schema::countdowns;
 
     let mut conn = pool.get().await?;
 
-    let countdown = diesel::insert_into(countdowns::table)
-        .values(&new_countdown)
-        .get_result(&mut conn)
+    diesel::insert_into(countdowns::table)
+        .values(new_countdowns)
+        .on_conflict_do_nothing()
+        .execute(&mut conn)
         .await?;
 
-    Ok(countdown)
+    Ok(())
+}
+
+/// Which rows of a history table to return, `from` and `to` are inclusive days
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HistoryFilter {
+    pub slot: Option<i32>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    /// Rows from this instant on, for clients that only need what's new
+    pub since: Option<DateTime<Utc>>,
 }
 
+impl HistoryFilter {
+    /// The later of `from` and `since`
+    fn start(&self) -> Option<DateTime<Utc>> {
+        let from = self.from.map(|day| day.and_time(NaiveTime::MIN).and_utc());
+        from.max(self.since)
+    }
+
+    /// First instant after `to`
+    fn end(&self) -> Option<DateTime<Utc>> {
+        self.to
+            .and_then(|day| day.succ_opt())
+            .map(|day| day.and_time(NaiveTime::MIN).and_utc())
+    }
+}
+
+/// Ordered like `idx_deathlinks_room_created` so rows are read off the index without sorting,
+/// `idx_deathlinks_room_slot` when filtering on a slot:
+///
+/// ```text
+/// EXPLAIN SELECT ... FROM deathlinks WHERE room_id = $1 ORDER BY created_at DESC
+///  Index Scan using idx_deathlinks_room_created on deathlinks
+///    Index Cond: ((room_id)::text = $1)
+/// ```
+fn room_deathlinks_query<'a>(
+    room_id: &'a str,
+    filter: &HistoryFilter,
+) -> super::schema::deathlinks::BoxedQuery<'a, diesel::pg::Pg> {
+    use super::schema::deathlinks::dsl;
+
+    filtered_room_deathlinks(room_id, filter).order(dsl::created_at.desc())
+}
+
+/// Rows of `room_deathlinks_query` in no particular order, which counting them wants
+fn filtered_room_deathlinks<'a>(
+    room_id: &'a str,
+    filter: &HistoryFilter,
+) -> super::schema::deathlinks::BoxedQuery<'a, diesel::pg::Pg> {
+    use super::schema::deathlinks::dsl;
+
+    let mut query = dsl::deathlinks
+        .filter(dsl::room_id.eq(room_id))
+        .into_boxed();
+    if let Some(slot) = filter.slot {
+        query = query.filter(dsl::slot.eq(slot));
+    }
+    if let Some(start) = filter.start() {
+        query = query.filter(dsl::created_at.ge(start));
+    }
+    if let Some(end) = filter.end() {
+        query = query.filter(dsl::created_at.lt(end));
+    }
+    query
+}
+
+/// `limit` deathlinks of the room after the first `offset`, newest first, along with how many
+/// the filter matches in total
 pub async fn get_room_deathlinks(
     pool: &crate::db::DieselPool,
     room_id: &str,
-) -> anyhow::Result<Vec<DeathLink>> {
-    use super::schema::deathlinks::dsl;
-
+    filter: HistoryFilter,
+    limit: i64,
+    offset: i64,
+) -> anyhow::Result<(Vec<DeathLink>, i64)> {
     let mut conn = pool.get().await?;
 
-    let deathlinks = dsl::deathlinks
-        .filter(dsl::room_id.eq(room_id))
-        .order(dsl::created_at.desc())
+    let total = filtered_room_deathlinks(room_id, &filter)
+        .count()
+        .get_result::<i64>(&mut conn)
+        .await?;
+    let deathlinks = room_deathlinks_query(room_id, &filter)
+        .limit(limit)
+        .offset(offset)
         .load::<DeathLink>(&mut conn)
         .await?;
 
-    Ok(deathlinks)
+    Ok((deathlinks, total))
+}
+
+/// Every row `get_room_deathlinks` pages through, read from a cursor as they're consumed
+pub fn stream_room_deathlinks(
+    pool: crate::db::DieselPool,
+    room_id: String,
+    filter: HistoryFilter,
+) -> impl Stream<Item = anyhow::Result<DeathLink>> + Send {
+    async_stream::try_stream! {
+        let mut conn = pool.get().await?;
+        let mut rows = room_deathlinks_query(&room_id, &filter)
+            .load_stream::<DeathLink>(&mut conn)
+            .await?;
+        while let Some(row) = rows.try_next().await? {
+            yield row;
+        }
+    }
 }
 
+/// Deaths of a slot no further apart than this make a streak
+pub const DEATH_STREAK_GAP_SECONDS: i64 = 5 * 60;
+
+/// Deathlinks a slot sent, as overlays show them
+#[derive(Debug, Clone, PartialEq, QueryableByName, Serialize)]
+pub struct SlotDeathSummary {
+    #[diesel(sql_type = Integer)]
+    pub slot: i32,
+    #[diesel(sql_type = BigInt)]
+    pub deaths: i64,
+    #[diesel(sql_type = Nullable<Timestamptz>)]
+    pub last_death_sent_at: Option<DateTime<Utc>>,
+    /// Between two deaths, `None` until the slot died twice
+    #[diesel(sql_type = Nullable<BigInt>)]
+    pub longest_gap_seconds: Option<i64>,
+    /// Since the last death, `None` until the slot died
+    #[diesel(sql_type = Nullable<BigInt>)]
+    pub survival_seconds: Option<i64>,
+    /// Deaths in a row, each within `DEATH_STREAK_GAP_SECONDS` of the next. The streak ends once
+    /// the slot survives that long, it's 0 then.
+    #[diesel(sql_type = BigInt)]
+    pub current_streak: i64,
+}
+
+/// Windows run over the deaths of each slot newest first, which is how
+/// `idx_deathlinks_room_slot` has them, so they're read off the index without sorting. Slots
+/// missing from `$2` are still there when they died, those of `$2` are even when they never did.
+const DEATHLINK_SUMMARY_SQL: &str = "\
+WITH timeline AS ( \
+    SELECT slot, created_at, \
+        ROW_NUMBER() OVER newest_first AS newest, \
+        EXTRACT(EPOCH FROM created_at - LEAD(created_at) OVER newest_first)::BIGINT AS gap \
+    FROM deathlinks \
+    WHERE room_id = $1 \
+    WINDOW newest_first AS (PARTITION BY slot ORDER BY created_at DESC) \
+), summaries AS ( \
+    SELECT slot, \
+        COUNT(*) AS deaths, \
+        MAX(created_at) AS last_death_sent_at, \
+        MAX(gap) AS longest_gap_seconds, \
+        MIN(newest) FILTER (WHERE gap IS NULL OR gap > $3) AS streak \
+    FROM timeline \
+    GROUP BY slot \
+) \
+SELECT slot, \
+    COALESCE(deaths, 0) AS deaths, \
+    last_death_sent_at, \
+    longest_gap_seconds, \
+    EXTRACT(EPOCH FROM $4 - last_death_sent_at)::BIGINT AS survival_seconds, \
+    CASE WHEN EXTRACT(EPOCH FROM $4 - last_death_sent_at) <= $3 THEN streak ELSE 0 END \
+        AS current_streak \
+FROM summaries \
+FULL JOIN UNNEST($2::INTEGER[]) AS known(slot) USING (slot) \
+ORDER BY slot";
+
+fn deathlink_summary_query<'a>(
+    room_id: &'a str,
+    slots: Vec<i32>,
+    now: DateTime<Utc>,
+) -> BoxedSqlQuery<'a, diesel::pg::Pg, SqlQuery> {
+    diesel::sql_query(DEATHLINK_SUMMARY_SQL)
+        .into_boxed()
+        .bind::<Text, _>(room_id)
+        .bind::<Array<Integer>, _>(slots)
+        .bind::<BigInt, _>(DEATH_STREAK_GAP_SECONDS)
+        .bind::<Timestamptz, _>(now)
+}
+
+/// Summary of the deathlinks of every slot of the room, as of `now`. `slots` are the slots of
+/// the room, they're listed whether or not they died.
+pub async fn get_room_deathlink_summary(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+    slots: Vec<i32>,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Vec<SlotDeathSummary>> {
+    let mut conn = pool.get().await?;
+
+    let summary = deathlink_summary_query(room_id, slots, now)
+        .load::<SlotDeathSummary>(&mut conn)
+        .await?;
+
+    Ok(summary)
+}
+
+/// The latest `limit` countdowns of the room, or of one of its slots, newest first
 pub async fn get_room_countdowns(
     pool: &crate::db::DieselPool,
     room_id: &str,
+    slot: Option<i32>,
+    limit: i64,
 ) -> anyhow::Result<Vec<Countdown>> {
     use super::schema::countdowns::dsl;
 
     let mut conn = pool.get().await?;
 
-    let countdowns = dsl::countdowns
+    let mut query = dsl::countdowns
         .filter(dsl::room_id.eq(room_id))
         .order(dsl::created_at.desc())
-        .load::<Countdown>(&mut conn)
-        .await?;
+        .into_boxed();
+    if let Some(slot) = slot {
+        query = query.filter(dsl::slot.eq(slot));
+    }
+    let countdowns = query.limit(limit).load::<Countdown>(&mut conn).await?;
 
     Ok(countdowns)
 }
 
+#[derive(Debug, Clone, Queryable, Serialize, PartialEq)]
+pub struct SlotCountdowns {
+    pub slot: i32,
+    pub attempts: i64,
+}
+
+/// How many countdowns each slot of the room started, only listing those that did
+pub async fn get_room_countdown_summary(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+) -> anyhow::Result<Vec<SlotCountdowns>> {
+    use super::schema::countdowns::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let summary = dsl::countdowns
+        .filter(dsl::room_id.eq(room_id))
+        .group_by(dsl::slot)
+        .select((dsl::slot, diesel::dsl::count_star()))
+        .order(dsl::slot)
+        .load::<SlotCountdowns>(&mut conn)
+        .await?;
+
+    Ok(summary)
+}
+
 #[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
 #[diesel(table_name = super::schema::deathlink_exclusions)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
@@ -323,3 +541,1061 @@ pub async fn set_deathlink_probability(
 
     Ok(clamped)
 }
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = super::schema::slot_preferences)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct SlotPreferenceRow {
+    pub id: i32,
+    pub room_id: String,
+    pub slot: i32,
+    pub player_name: String,
+    pub deathlink_opt_out: bool,
+    pub muted: bool,
+    pub alias: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = super::schema::slot_preferences)]
+pub struct NewSlotPreference {
+    pub room_id: String,
+    pub slot: i32,
+    pub player_name: String,
+    pub deathlink_opt_out: bool,
+    pub muted: bool,
+    pub alias: Option<String>,
+}
+
+pub async fn get_room_slot_preferences(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+) -> anyhow::Result<Vec<SlotPreferenceRow>> {
+    use super::schema::slot_preferences::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let preferences = dsl::slot_preferences
+        .filter(dsl::room_id.eq(room_id))
+        .load::<SlotPreferenceRow>(&mut conn)
+        .await?;
+
+    Ok(preferences)
+}
+
+pub async fn upsert_slot_preference(
+    pool: &crate::db::DieselPool,
+    new_preference: NewSlotPreference,
+) -> anyhow::Result<()> {
+    use super::schema::slot_preferences::dsl;
+
+    let mut conn = pool.get().await?;
+
+    diesel::insert_into(dsl::slot_preferences)
+        .values(&new_preference)
+        .on_conflict((dsl::room_id, dsl::slot, dsl::player_name))
+        .do_update()
+        .set((
+            dsl::deathlink_opt_out.eq(new_preference.deathlink_opt_out),
+            dsl::muted.eq(new_preference.muted),
+            dsl::alias.eq(&new_preference.alias),
+            dsl::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(&mut conn)
+        .await?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = super::schema::room_settings)]
+pub struct NewRoomSetting {
+    pub room_id: String,
+    pub key: String,
+    pub value: serde_json::Value,
+}
+
+pub async fn get_room_setting(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+    key: &str,
+) -> anyhow::Result<Option<serde_json::Value>> {
+    use super::schema::room_settings::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let value = dsl::room_settings
+        .filter(dsl::room_id.eq(room_id))
+        .filter(dsl::key.eq(key))
+        .select(dsl::value)
+        .first::<serde_json::Value>(&mut conn)
+        .await
+        .optional()?;
+
+    Ok(value)
+}
+
+pub async fn set_room_setting(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+    key: &str,
+    value: serde_json::Value,
+) -> anyhow::Result<()> {
+    use super::schema::room_settings::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let setting = NewRoomSetting {
+        room_id: room_id.to_string(),
+        key: key.to_string(),
+        value,
+    };
+
+    diesel::insert_into(dsl::room_settings)
+        .values(&setting)
+        .on_conflict((dsl::room_id, dsl::key))
+        .do_update()
+        .set((
+            dsl::value.eq(&setting.value),
+            dsl::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(&mut conn)
+        .await?;
+
+    Ok(())
+}
+
+/// NOTIFY on `channel`, received by every connection listening on it
+pub async fn notify(
+    pool: &crate::db::DieselPool,
+    channel: &str,
+    payload: &str,
+) -> anyhow::Result<()> {
+    let mut conn = pool.get().await?;
+
+    diesel::sql_query("SELECT pg_notify($1, $2)")
+        .bind::<Text, _>(channel)
+        .bind::<Text, _>(payload)
+        .execute(&mut conn)
+        .await?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = super::schema::connection_attempts)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ConnectionAttempt {
+    pub id: i32,
+    pub room_id: String,
+    pub slot: Option<i32>,
+    pub name: String,
+    pub outcome: String,
+    pub errors: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub event_id: Option<Uuid>,
+    /// HMAC of the name and password tried, see `audit::AuditKey`
+    pub attempt_hmac: Option<String>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = super::schema::connection_attempts)]
+pub struct NewConnectionAttempt {
+    pub room_id: String,
+    pub slot: Option<i32>,
+    pub name: String,
+    pub outcome: String,
+    pub errors: Vec<String>,
+    pub event_id: Option<Uuid>,
+    pub attempt_hmac: Option<String>,
+}
+
+impl NewConnectionAttempt {
+    pub fn refused(
+        room_id: String,
+        slot: Option<SlotId>,
+        name: String,
+        errors: Vec<String>,
+    ) -> Self {
+        Self {
+            room_id,
+            slot: slot.map(|slot| slot.0 as i32),
+            name,
+            outcome: "refused".to_string(),
+            errors,
+            event_id: None,
+            attempt_hmac: None,
+        }
+    }
+
+    pub fn accepted(room_id: String, slot: SlotId, name: String, attempt_hmac: String) -> Self {
+        Self {
+            room_id,
+            slot: Some(slot.0 as i32),
+            name,
+            outcome: "accepted".to_string(),
+            errors: Vec::new(),
+            event_id: None,
+            attempt_hmac: Some(attempt_hmac),
+        }
+    }
+}
+
+pub async fn insert_connection_attempt(
+    pool: &crate::db::DieselPool,
+    new_attempt: NewConnectionAttempt,
+) -> anyhow::Result<()> {
+    use super::schema::connection_attempts;
+
+    let mut conn = pool.get().await?;
+
+    diesel::insert_into(connection_attempts::table)
+        .values(&new_attempt)
+        .on_conflict_do_nothing()
+        .execute(&mut conn)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_connection_attempt(
+    pool: &crate::db::DieselPool,
+    id: i32,
+) -> anyhow::Result<Option<ConnectionAttempt>> {
+    use super::schema::connection_attempts::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let attempt = dsl::connection_attempts
+        .find(id)
+        .select(ConnectionAttempt::as_select())
+        .first::<ConnectionAttempt>(&mut conn)
+        .await
+        .optional()?;
+
+    Ok(attempt)
+}
+
+/// Most recent refused attempt for each slot of the room
+pub async fn get_latest_refusals(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+) -> anyhow::Result<Vec<ConnectionAttempt>> {
+    use super::schema::connection_attempts::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let attempts = dsl::connection_attempts
+        .filter(dsl::room_id.eq(room_id))
+        .filter(dsl::outcome.eq("refused"))
+        .filter(dsl::slot.is_not_null())
+        .distinct_on(dsl::slot)
+        .order((dsl::slot, dsl::created_at.desc()))
+        .select(ConnectionAttempt::as_select())
+        .load::<ConnectionAttempt>(&mut conn)
+        .await?;
+
+    Ok(attempts)
+}
+
+fn room_connection_attempts_query<'a>(
+    room_id: &'a str,
+    filter: &HistoryFilter,
+) -> super::schema::connection_attempts::BoxedQuery<'a, diesel::pg::Pg> {
+    use super::schema::connection_attempts::dsl;
+
+    let mut query = dsl::connection_attempts
+        .filter(dsl::room_id.eq(room_id))
+        .order(dsl::created_at.desc())
+        .into_boxed();
+    if let Some(slot) = filter.slot {
+        query = query.filter(dsl::slot.eq(slot));
+    }
+    if let Some(start) = filter.start() {
+        query = query.filter(dsl::created_at.ge(start));
+    }
+    if let Some(end) = filter.end() {
+        query = query.filter(dsl::created_at.lt(end));
+    }
+    query
+}
+
+pub async fn get_room_connection_attempts(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+    filter: HistoryFilter,
+) -> anyhow::Result<Vec<ConnectionAttempt>> {
+    let mut conn = pool.get().await?;
+
+    let attempts = room_connection_attempts_query(room_id, &filter)
+        .load::<ConnectionAttempt>(&mut conn)
+        .await?;
+
+    Ok(attempts)
+}
+
+pub fn stream_room_connection_attempts(
+    pool: crate::db::DieselPool,
+    room_id: String,
+    filter: HistoryFilter,
+) -> impl Stream<Item = anyhow::Result<ConnectionAttempt>> + Send {
+    async_stream::try_stream! {
+        let mut conn = pool.get().await?;
+        let mut rows = room_connection_attempts_query(&room_id, &filter)
+            .load_stream::<ConnectionAttempt>(&mut conn)
+            .await?;
+        while let Some(row) = rows.try_next().await? {
+            yield row;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = super::schema::traffic_snapshots)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct TrafficSnapshot {
+    pub id: i32,
+    pub room_id: String,
+    pub messages_proxied: i64,
+    pub chat_messages: i64,
+    pub sessions: i64,
+    pub slots: Vec<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = super::schema::traffic_snapshots)]
+pub struct NewTrafficSnapshot {
+    pub room_id: String,
+    pub messages_proxied: i64,
+    pub chat_messages: i64,
+    pub sessions: i64,
+    pub slots: Vec<i32>,
+}
+
+pub async fn insert_traffic_snapshot(
+    pool: &crate::db::DieselPool,
+    new_snapshot: NewTrafficSnapshot,
+) -> anyhow::Result<()> {
+    use super::schema::traffic_snapshots;
+
+    let mut conn = pool.get().await?;
+
+    diesel::insert_into(traffic_snapshots::table)
+        .values(&new_snapshot)
+        .execute(&mut conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Snapshots of the room taken in `[from, to)`
+pub async fn get_room_traffic_snapshots(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> anyhow::Result<Vec<TrafficSnapshot>> {
+    use super::schema::traffic_snapshots::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let snapshots = dsl::traffic_snapshots
+        .filter(dsl::room_id.eq(room_id))
+        .filter(dsl::created_at.ge(from))
+        .filter(dsl::created_at.lt(to))
+        .select(TrafficSnapshot::as_select())
+        .load::<TrafficSnapshot>(&mut conn)
+        .await?;
+
+    Ok(snapshots)
+}
+
+/// Deathlinks of the room received in `[from, to)`
+pub async fn count_room_deathlinks(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> anyhow::Result<i64> {
+    use super::schema::deathlinks::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let count = dsl::deathlinks
+        .filter(dsl::room_id.eq(room_id))
+        .filter(dsl::created_at.ge(from))
+        .filter(dsl::created_at.lt(to))
+        .count()
+        .get_result(&mut conn)
+        .await?;
+
+    Ok(count)
+}
+
+/// Oldest deathlink or traffic snapshot of the room, where a first aggregation starts from
+pub async fn get_room_first_activity(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+) -> anyhow::Result<Option<DateTime<Utc>>> {
+    use super::schema::{deathlinks, traffic_snapshots};
+
+    let mut conn = pool.get().await?;
+
+    let first_deathlink: Option<DateTime<Utc>> = deathlinks::table
+        .filter(deathlinks::room_id.eq(room_id))
+        .select(diesel::dsl::min(deathlinks::created_at))
+        .first(&mut conn)
+        .await?;
+    let first_snapshot: Option<DateTime<Utc>> = traffic_snapshots::table
+        .filter(traffic_snapshots::room_id.eq(room_id))
+        .select(diesel::dsl::min(traffic_snapshots::created_at))
+        .first(&mut conn)
+        .await?;
+
+    Ok(first_deathlink.into_iter().chain(first_snapshot).min())
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = super::schema::daily_stats)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DailyStats {
+    pub room_id: String,
+    pub day: NaiveDate,
+    pub deathlinks: i64,
+    pub chat_messages: i64,
+    pub sessions: i64,
+    pub unique_slots: i64,
+    pub messages_proxied: i64,
+}
+
+pub async fn get_last_daily_stats_day(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+) -> anyhow::Result<Option<NaiveDate>> {
+    use super::schema::daily_stats::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let day = dsl::daily_stats
+        .filter(dsl::room_id.eq(room_id))
+        .select(diesel::dsl::max(dsl::day))
+        .first(&mut conn)
+        .await?;
+
+    Ok(day)
+}
+
+/// Replaces the row of the same room and day, aggregating a day again is always safe
+pub async fn upsert_daily_stats(
+    pool: &crate::db::DieselPool,
+    stats: &DailyStats,
+) -> anyhow::Result<()> {
+    use super::schema::daily_stats::dsl;
+
+    let mut conn = pool.get().await?;
+
+    diesel::insert_into(dsl::daily_stats)
+        .values(stats)
+        .on_conflict((dsl::room_id, dsl::day))
+        .do_update()
+        .set((
+            dsl::deathlinks.eq(stats.deathlinks),
+            dsl::chat_messages.eq(stats.chat_messages),
+            dsl::sessions.eq(stats.sessions),
+            dsl::unique_slots.eq(stats.unique_slots),
+            dsl::messages_proxied.eq(stats.messages_proxied),
+            dsl::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(&mut conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Rows of the room for days in `[from, to]`, oldest first
+pub async fn get_room_daily_stats(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+) -> anyhow::Result<Vec<DailyStats>> {
+    use super::schema::daily_stats::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let mut query = dsl::daily_stats
+        .filter(dsl::room_id.eq(room_id))
+        .select(DailyStats::as_select())
+        .order(dsl::day.asc())
+        .into_boxed();
+    if let Some(from) = from {
+        query = query.filter(dsl::day.ge(from));
+    }
+    if let Some(to) = to {
+        query = query.filter(dsl::day.le(to));
+    }
+
+    Ok(query.load::<DailyStats>(&mut conn).await?)
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable)]
+#[diesel(table_name = super::schema::slot_bandwidth)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DailySlotBandwidth {
+    pub room_id: String,
+    pub slot: i32,
+    pub day: NaiveDate,
+    pub client_to_upstream: i64,
+    pub upstream_to_client: i64,
+}
+
+/// Replaces the totals of the same slot and day, they're counted in memory and only written back
+pub async fn upsert_slot_bandwidth(
+    pool: &crate::db::DieselPool,
+    bandwidth: &DailySlotBandwidth,
+) -> anyhow::Result<()> {
+    use super::schema::slot_bandwidth::dsl;
+
+    let mut conn = pool.get().await?;
+
+    diesel::insert_into(dsl::slot_bandwidth)
+        .values(bandwidth)
+        .on_conflict((dsl::room_id, dsl::slot, dsl::day))
+        .do_update()
+        .set((
+            dsl::client_to_upstream.eq(bandwidth.client_to_upstream),
+            dsl::upstream_to_client.eq(bandwidth.upstream_to_client),
+            dsl::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(&mut conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Totals of every slot of every room for `day`
+pub async fn get_slot_bandwidth(
+    pool: &crate::db::DieselPool,
+    day: NaiveDate,
+) -> anyhow::Result<Vec<DailySlotBandwidth>> {
+    use super::schema::slot_bandwidth::dsl;
+
+    let mut conn = pool.get().await?;
+
+    Ok(dsl::slot_bandwidth
+        .filter(dsl::day.eq(day))
+        .select(DailySlotBandwidth::as_select())
+        .load(&mut conn)
+        .await?)
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = super::schema::slot_notes)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct SlotNote {
+    pub id: i32,
+    pub room_id: String,
+    pub slot: i32,
+    pub author: String,
+    pub note: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = super::schema::slot_notes)]
+pub struct NewSlotNote {
+    pub room_id: String,
+    pub slot: i32,
+    pub author: String,
+    pub note: String,
+}
+
+pub async fn insert_slot_note(
+    pool: &crate::db::DieselPool,
+    new_note: NewSlotNote,
+) -> anyhow::Result<SlotNote> {
+    use super::schema::slot_notes;
+
+    let mut conn = pool.get().await?;
+
+    let note = diesel::insert_into(slot_notes::table)
+        .values(&new_note)
+        .returning(SlotNote::as_returning())
+        .get_result(&mut conn)
+        .await?;
+
+    Ok(note)
+}
+
+/// Up to `limit` notes of the slot, newest first, only those older than the note `before` when
+/// given
+pub async fn get_slot_notes(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+    slot: SlotId,
+    before: Option<i32>,
+    limit: i64,
+) -> anyhow::Result<Vec<SlotNote>> {
+    use super::schema::slot_notes::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let mut query = dsl::slot_notes
+        .filter(dsl::room_id.eq(room_id))
+        .filter(dsl::slot.eq(slot.0 as i32))
+        .order(dsl::id.desc())
+        .limit(limit)
+        .into_boxed();
+    if let Some(before) = before {
+        query = query.filter(dsl::id.lt(before));
+    }
+
+    let notes = query
+        .select(SlotNote::as_select())
+        .load::<SlotNote>(&mut conn)
+        .await?;
+
+    Ok(notes)
+}
+
+/// Most recent note of each slot of the room
+pub async fn get_latest_slot_notes(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+) -> anyhow::Result<Vec<SlotNote>> {
+    use super::schema::slot_notes::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let notes = dsl::slot_notes
+        .filter(dsl::room_id.eq(room_id))
+        .distinct_on(dsl::slot)
+        .order((dsl::slot, dsl::id.desc()))
+        .select(SlotNote::as_select())
+        .load::<SlotNote>(&mut conn)
+        .await?;
+
+    Ok(notes)
+}
+
+/// Returns whether the room had that note
+pub async fn delete_slot_note(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+    id: i32,
+) -> anyhow::Result<bool> {
+    use super::schema::slot_notes::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let result = diesel::delete(
+        dsl::slot_notes
+            .filter(dsl::room_id.eq(room_id))
+            .filter(dsl::id.eq(id)),
+    )
+    .execute(&mut conn)
+    .await?;
+
+    Ok(result > 0)
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize, Deserialize)]
+#[diesel(table_name = super::schema::bounces)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Bounce {
+    pub id: i32,
+    pub room_id: String,
+    pub slot: i32,
+    pub tags: Vec<String>,
+    pub data: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+    pub event_id: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = super::schema::bounces)]
+pub struct NewBounce {
+    pub room_id: String,
+    pub slot: i32,
+    pub tags: Vec<String>,
+    pub data: serde_json::Value,
+    pub event_id: Option<Uuid>,
+}
+
+/// Inserting an event already stored under the same `event_id` does nothing
+pub async fn insert_bounces(
+    pool: &crate::db::DieselPool,
+    new_bounces: &[NewBounce],
+) -> anyhow::Result<()> {
+    use super::schema::bounces;
+
+    let mut conn = pool.get().await?;
+
+    diesel::insert_into(bounces::table)
+        .values(new_bounces)
+        .on_conflict_do_nothing()
+        .execute(&mut conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Up to `limit` recorded Bounces of the room, newest first, only those of `slot` and recorded
+/// from `since` on when given
+pub async fn get_room_bounces(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+    slot: Option<SlotId>,
+    since: Option<DateTime<Utc>>,
+    limit: i64,
+) -> anyhow::Result<Vec<Bounce>> {
+    use super::schema::bounces::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let mut query = dsl::bounces
+        .filter(dsl::room_id.eq(room_id))
+        .order(dsl::created_at.desc())
+        .limit(limit)
+        .into_boxed();
+    if let Some(slot) = slot {
+        query = query.filter(dsl::slot.eq(slot.0 as i32));
+    }
+    if let Some(since) = since {
+        query = query.filter(dsl::created_at.ge(since));
+    }
+
+    let bounces = query
+        .select(Bounce::as_select())
+        .load::<Bounce>(&mut conn)
+        .await?;
+
+    Ok(bounces)
+}
+
+/// Bounces recorded by slot from `since` on
+pub async fn count_room_bounces_by_slot(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+    since: DateTime<Utc>,
+) -> anyhow::Result<HashMap<SlotId, usize>> {
+    use super::schema::bounces::dsl;
+
+    let mut conn = pool.get().await?;
+
+    let counts = dsl::bounces
+        .filter(dsl::room_id.eq(room_id))
+        .filter(dsl::created_at.ge(since))
+        .group_by(dsl::slot)
+        .select((dsl::slot, diesel::dsl::count_star()))
+        .load::<(i32, i64)>(&mut conn)
+        .await?;
+
+    Ok(counts
+        .into_iter()
+        .map(|(slot, count)| (SlotId(slot as i64), count as usize))
+        .collect())
+}
+
+/// History tables with a `created_at`, a retention policy can delete their older rows
+pub const PRUNABLE_TABLES: &[&str] = &[
+    "bounces",
+    "connection_attempts",
+    "countdowns",
+    "deathlinks",
+    "traffic_snapshots",
+];
+
+fn prune_query(
+    table: &str,
+    before: DateTime<Utc>,
+) -> anyhow::Result<UncheckedBind<SqlQuery, DateTime<Utc>, Timestamptz>> {
+    // The name ends up in the SQL, it has to be one of ours
+    anyhow::ensure!(
+        PRUNABLE_TABLES.contains(&table),
+        "{} can't be pruned",
+        table
+    );
+    Ok(
+        diesel::sql_query(format!("DELETE FROM {} WHERE created_at < $1", table))
+            .bind::<Timestamptz, _>(before),
+    )
+}
+
+/// Deletes the rows of `table`, one of `PRUNABLE_TABLES`, created before `before`. Returns how
+/// many there were.
+pub async fn prune_table(
+    pool: &crate::db::DieselPool,
+    table: &str,
+    before: DateTime<Utc>,
+) -> anyhow::Result<usize> {
+    let query = prune_query(table, before)?;
+    let mut conn = pool.get().await?;
+
+    Ok(query.execute(&mut conn).await?)
+}
+
+#[derive(QueryableByName)]
+struct RowEstimate {
+    #[diesel(sql_type = Text)]
+    table_name: String,
+    #[diesel(sql_type = BigInt)]
+    estimate: i64,
+}
+
+/// Rows of each of `PRUNABLE_TABLES` as far as the planner's statistics know, which unlike
+/// counting them doesn't scan anything. Partitioned tables add up their partitions.
+pub async fn estimated_row_counts(
+    pool: &crate::db::DieselPool,
+) -> anyhow::Result<HashMap<String, i64>> {
+    let mut conn = pool.get().await?;
+
+    // reltuples is -1 until a table was first analyzed
+    let estimates = diesel::sql_query(
+        "SELECT parent.relname::TEXT AS table_name, \
+         COALESCE(SUM(GREATEST(child.reltuples, 0)), 0)::BIGINT AS estimate \
+         FROM pg_class parent \
+         LEFT JOIN pg_inherits i ON i.inhparent = parent.oid \
+         JOIN pg_class child ON child.oid = COALESCE(i.inhrelid, parent.oid) \
+         WHERE parent.relname = ANY($1) AND pg_table_is_visible(parent.oid) \
+         GROUP BY parent.relname",
+    )
+    .bind::<Array<Text>, _>(PRUNABLE_TABLES)
+    .load::<RowEstimate>(&mut conn)
+    .await?;
+
+    Ok(estimates
+        .into_iter()
+        .map(|row| (row.table_name, row.estimate))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::pg::Pg;
+    use diesel::query_builder::QueryFragment;
+    use diesel_async::AsyncPgConnection;
+    use diesel_async::async_connection_wrapper::AsyncConnectionWrapper;
+
+    #[derive(QueryableByName)]
+    struct PlanLine {
+        #[diesel(sql_type = Text, column_name = "QUERY PLAN")]
+        line: String,
+    }
+
+    /// Plan of a history query of the `main` room, with sequential scans off so an empty table
+    /// doesn't get one anyway
+    fn plan(
+        conn: &mut AsyncConnectionWrapper<AsyncPgConnection>,
+        query: impl QueryFragment<Pg>,
+    ) -> String {
+        let sql = diesel::debug_query::<Pg, _>(&query).to_string();
+        let sql = sql.split(" -- binds").next().unwrap();
+        diesel::RunQueryDsl::execute(diesel::sql_query("SET enable_seqscan = off"), conn).unwrap();
+        let explain = diesel::sql_query(format!("EXPLAIN {}", sql)).bind::<Text, _>("main");
+        diesel::RunQueryDsl::load::<PlanLine>(explain, conn)
+            .unwrap()
+            .into_iter()
+            .map(|plan| plan.line)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_history_queries_use_the_room_indexes() {
+        let Some(mut conn) = crate::db::tests::test_database() else {
+            return;
+        };
+        let filter = HistoryFilter::default();
+        let deathlinks = plan(&mut conn, room_deathlinks_query("main", &filter));
+        assert!(
+            deathlinks.contains("idx_deathlinks_room_created"),
+            "{}",
+            deathlinks
+        );
+        assert!(!deathlinks.contains("Sort"), "{}", deathlinks);
+        let attempts = plan(&mut conn, room_connection_attempts_query("main", &filter));
+        assert!(
+            attempts.contains("idx_connection_attempts_room_created"),
+            "{}",
+            attempts
+        );
+    }
+
+    #[test]
+    fn test_deathlink_summary() {
+        use super::super::schema::deathlinks;
+        use diesel::Connection;
+
+        let Some(mut conn) = crate::db::tests::test_database() else {
+            return;
+        };
+        conn.begin_test_transaction().unwrap();
+        let at = |time: &str| -> DateTime<Utc> { format!("2026-04-10T{}Z", time).parse().unwrap() };
+        let deaths = [
+            ("summary", 1, "10:00:00"),
+            ("summary", 1, "11:00:00"),
+            ("summary", 1, "11:50:00"),
+            ("summary", 1, "11:54:00"),
+            ("summary", 1, "11:59:00"),
+            ("summary", 2, "11:54:58"),
+            ("summary", 2, "11:59:59"),
+            ("summary", 4, "08:00:00"),
+            // Would split the streak of slot 1 if it counted
+            ("other", 1, "11:57:00"),
+        ];
+        for (room_id, slot, time) in deaths {
+            let deathlink = NewDeathLink::new(room_id.into(), SlotId(slot), "Alice".into(), None);
+            diesel::RunQueryDsl::execute(
+                diesel::insert_into(deathlinks::table)
+                    .values((&deathlink, deathlinks::created_at.eq(at(time)))),
+                &mut conn,
+            )
+            .unwrap();
+        }
+
+        let summary: Vec<SlotDeathSummary> = diesel::RunQueryDsl::load(
+            deathlink_summary_query("summary", vec![1, 2, 3], at("12:00:00")),
+            &mut conn,
+        )
+        .unwrap();
+        let summary = |slot: i32| summary.iter().find(|s| s.slot == slot).unwrap().clone();
+        assert_eq!(
+            summary(1),
+            SlotDeathSummary {
+                slot: 1,
+                deaths: 5,
+                last_death_sent_at: Some(at("11:59:00")),
+                longest_gap_seconds: Some(3600),
+                survival_seconds: Some(60),
+                // Exactly 5 minutes apart is still a streak
+                current_streak: 3,
+            }
+        );
+        // A second over breaks it
+        assert_eq!(summary(2).current_streak, 1);
+        assert_eq!(summary(2).longest_gap_seconds, Some(301));
+        assert_eq!(
+            summary(3),
+            SlotDeathSummary {
+                slot: 3,
+                deaths: 0,
+                last_death_sent_at: None,
+                longest_gap_seconds: None,
+                survival_seconds: None,
+                current_streak: 0,
+            }
+        );
+        // Long survived, and not a slot the room knows of
+        let slot4 = summary(4);
+        assert_eq!((slot4.deaths, slot4.current_streak), (1, 0));
+        assert_eq!(slot4.longest_gap_seconds, None);
+        assert_eq!(slot4.survival_seconds, Some(4 * 3600));
+
+        diesel::RunQueryDsl::execute(diesel::sql_query("SET enable_seqscan = off"), &mut conn)
+            .unwrap();
+        let explain = diesel::sql_query(format!("EXPLAIN {}", DEATHLINK_SUMMARY_SQL))
+            .bind::<Text, _>("summary")
+            .bind::<Array<Integer>, _>(vec![1, 2, 3])
+            .bind::<BigInt, _>(DEATH_STREAK_GAP_SECONDS)
+            .bind::<Timestamptz, _>(at("12:00:00"));
+        let plan = diesel::RunQueryDsl::load::<PlanLine>(explain, &mut conn)
+            .unwrap()
+            .into_iter()
+            .map(|plan| plan.line)
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert!(plan.contains("idx_deathlinks_room_slot"), "{}", plan);
+    }
+
+    #[test]
+    fn test_timestamps_roundtrip_as_utc() {
+        use super::super::schema::deathlinks;
+        use diesel::Connection;
+
+        let Some(mut conn) = crate::db::tests::test_database() else {
+            return;
+        };
+        conn.begin_test_transaction().unwrap();
+        // Whatever the session's time zone, rows are read back in UTC
+        diesel::RunQueryDsl::execute(diesel::sql_query("SET TIME ZONE 'Europe/Paris'"), &mut conn)
+            .unwrap();
+        let at: DateTime<Utc> = "2026-04-04T23:30:00Z".parse().unwrap();
+        let new = NewDeathLink::new("main".into(), SlotId(1), "Alice".into(), None);
+        let inserted: DeathLink = diesel::RunQueryDsl::get_result(
+            diesel::insert_into(deathlinks::table)
+                .values((&new, deathlinks::created_at.eq(at)))
+                .returning(DeathLink::as_returning()),
+            &mut conn,
+        )
+        .unwrap();
+        assert_eq!(inserted.created_at, at);
+
+        let filter = HistoryFilter {
+            from: "2026-04-04".parse().ok(),
+            to: "2026-04-04".parse().ok(),
+            ..Default::default()
+        };
+        let found: Vec<DeathLink> =
+            diesel::RunQueryDsl::load(room_deathlinks_query("main", &filter), &mut conn).unwrap();
+        assert_eq!(found.len(), 1);
+        let json = serde_json::to_value(&found[0]).unwrap();
+        assert_eq!(json["created_at"], "2026-04-04T23:30:00Z");
+    }
+
+    #[test]
+    fn test_pruning_only_deletes_old_rows_of_the_table() {
+        use super::super::schema::{countdowns, deathlinks};
+        use diesel::Connection;
+
+        let Some(mut conn) = crate::db::tests::test_database() else {
+            return;
+        };
+        conn.begin_test_transaction().unwrap();
+        let now = Utc::now();
+        for days in [1, 20, 40, 400] {
+            let at = now - chrono::Duration::days(days);
+            let deathlink = NewDeathLink::new("retention".into(), SlotId(1), "Alice".into(), None);
+            diesel::RunQueryDsl::execute(
+                diesel::insert_into(deathlinks::table)
+                    .values((&deathlink, deathlinks::created_at.eq(at))),
+                &mut conn,
+            )
+            .unwrap();
+            let countdown = NewCountdown::new("retention".into(), SlotId(1));
+            diesel::RunQueryDsl::execute(
+                diesel::insert_into(countdowns::table)
+                    .values((&countdown, countdowns::created_at.eq(at))),
+                &mut conn,
+            )
+            .unwrap();
+        }
+
+        let before = now - chrono::Duration::days(30);
+        let pruned =
+            diesel::RunQueryDsl::execute(prune_query("deathlinks", before).unwrap(), &mut conn)
+                .unwrap();
+        assert!(pruned >= 2, "{}", pruned);
+        let kept: Vec<DateTime<Utc>> = diesel::RunQueryDsl::load(
+            deathlinks::table
+                .filter(deathlinks::room_id.eq("retention"))
+                .select(deathlinks::created_at),
+            &mut conn,
+        )
+        .unwrap();
+        assert_eq!(kept.len(), 2);
+        assert!(kept.iter().all(|at| *at >= before));
+        let countdowns: i64 = diesel::RunQueryDsl::get_result(
+            countdowns::table
+                .filter(countdowns::room_id.eq("retention"))
+                .count(),
+            &mut conn,
+        )
+        .unwrap();
+        assert_eq!(countdowns, 4);
+
+        assert!(prune_query("slot_notes", before).is_err());
+    }
+}
@@ -5,7 +5,8 @@ diesel::table! {
         slot -> Int4,
         source -> Varchar,
         cause -> Nullable<Varchar>,
-        created_at -> Timestamp,
+        created_at -> Timestamptz,
+        event_id -> Nullable<Uuid>,
     }
 }
 
@@ -14,7 +15,8 @@ diesel::table! {
         id -> Int4,
         room_id -> Varchar,
         slot -> Int4,
-        created_at -> Timestamp,
+        created_at -> Timestamptz,
+        event_id -> Nullable<Uuid>,
     }
 }
 
@@ -39,3 +41,98 @@ diesel::table! {
         game_name -> Varchar,
     }
 }
+
+diesel::table! {
+    slot_preferences (id) {
+        id -> Int4,
+        room_id -> Varchar,
+        slot -> Int4,
+        player_name -> Varchar,
+        deathlink_opt_out -> Bool,
+        muted -> Bool,
+        alias -> Nullable<Varchar>,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    room_settings (room_id, key) {
+        room_id -> Varchar,
+        key -> Varchar,
+        value -> Jsonb,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    connection_attempts (id) {
+        id -> Int4,
+        room_id -> Varchar,
+        slot -> Nullable<Int4>,
+        name -> Varchar,
+        outcome -> Varchar,
+        errors -> Array<Text>,
+        created_at -> Timestamptz,
+        event_id -> Nullable<Uuid>,
+        attempt_hmac -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    traffic_snapshots (id) {
+        id -> Int4,
+        room_id -> Varchar,
+        messages_proxied -> Int8,
+        chat_messages -> Int8,
+        sessions -> Int8,
+        slots -> Array<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    daily_stats (room_id, day) {
+        room_id -> Varchar,
+        day -> Date,
+        deathlinks -> Int8,
+        chat_messages -> Int8,
+        sessions -> Int8,
+        unique_slots -> Int8,
+        messages_proxied -> Int8,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    slot_bandwidth (room_id, slot, day) {
+        room_id -> Varchar,
+        slot -> Int4,
+        day -> Date,
+        client_to_upstream -> Int8,
+        upstream_to_client -> Int8,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    slot_notes (id) {
+        id -> Int4,
+        room_id -> Varchar,
+        slot -> Int4,
+        author -> Varchar,
+        note -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    bounces (id) {
+        id -> Int4,
+        room_id -> Varchar,
+        slot -> Int4,
+        tags -> Array<Text>,
+        data -> Jsonb,
+        created_at -> Timestamptz,
+        event_id -> Nullable<Uuid>,
+    }
+}
@@ -0,0 +1,628 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+use uuid::Uuid;
+
+use crate::db::batcher::{BatchInsert, BatchLimits, Batcher};
+use crate::db::{DieselPool, models};
+use crate::diagnostics;
+use crate::events::{self, RoomEvent};
+use crate::preferences;
+use crate::ready;
+use crate::spill::SpillDir;
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Events stored together at most, whatever else is waiting goes in the next batch
+const MAX_BATCH: usize = 100;
+
+/// An event waiting to be stored. The id goes into the row so replaying an event that was
+/// already stored, after a crash or a partially replayed spill file, doesn't store it twice.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingWrite {
+    pub id: Uuid,
+    pub event: RoomEvent,
+}
+
+impl PendingWrite {
+    pub fn new(event: RoomEvent) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            event,
+        }
+    }
+}
+
+/// Where events end up. Sinks keep a single copy of each event, so a batch that failed partway
+/// is stored again as a whole.
+pub trait EventSink: Send {
+    /// Names the sink in logs and metrics
+    const NAME: &'static str;
+
+    fn store(&mut self, write: &PendingWrite) -> impl Future<Output = Result<()>> + Send;
+
+    /// Stores events in order, one at a time unless the sink can do better
+    fn store_batch(&mut self, batch: &[PendingWrite]) -> impl Future<Output = Result<()>> + Send {
+        async move {
+            for write in batch {
+                self.store(write).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Sinks events are written to, each with its own retry queue and spill directory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EventSinks {
+    pub database: bool,
+    pub http: bool,
+}
+
+impl Default for EventSinks {
+    fn default() -> Self {
+        Self {
+            database: true,
+            http: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidSinks(String);
+
+impl std::fmt::Display for InvalidSinks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidSinks {}
+
+/// Comma separated, `database` and `http`
+impl std::str::FromStr for EventSinks {
+    type Err = InvalidSinks;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut sinks = EventSinks {
+            database: false,
+            http: false,
+        };
+        for sink in s.split(',').map(str::trim).filter(|sink| !sink.is_empty()) {
+            match sink {
+                "database" => sinks.database = true,
+                "http" => sinks.http = true,
+                _ => return Err(InvalidSinks(format!("unknown event sink {}", sink))),
+            }
+        }
+        if sinks
+            == (EventSinks {
+                database: false,
+                http: false,
+            })
+        {
+            return Err(InvalidSinks("no event sink".to_string()));
+        }
+        Ok(sinks)
+    }
+}
+
+/// Inserts batches into the database
+struct Tables(DieselPool);
+
+impl BatchInsert<models::NewDeathLink> for Tables {
+    const TABLE: &'static str = "deathlinks";
+
+    async fn insert(&mut self, rows: &[models::NewDeathLink]) -> Result<()> {
+        models::insert_deathlinks(&self.0, rows).await
+    }
+}
+
+impl BatchInsert<models::NewCountdown> for Tables {
+    const TABLE: &'static str = "countdowns";
+
+    async fn insert(&mut self, rows: &[models::NewCountdown]) -> Result<()> {
+        models::insert_countdowns(&self.0, rows).await
+    }
+}
+
+impl BatchInsert<models::NewBounce> for Tables {
+    const TABLE: &'static str = "bounces";
+
+    async fn insert(&mut self, rows: &[models::NewBounce]) -> Result<()> {
+        models::insert_bounces(&self.0, rows).await
+    }
+}
+
+/// Deathlinks, countdowns and bounces of a batch are inserted together, the other events one by
+/// one
+pub struct DbSink {
+    tables: Tables,
+    room_id: String,
+    deathlinks: Batcher<models::NewDeathLink>,
+    countdowns: Batcher<models::NewCountdown>,
+    bounces: Batcher<models::NewBounce>,
+}
+
+impl DbSink {
+    pub fn new(pool: DieselPool, room_id: String) -> Self {
+        Self {
+            tables: Tables(pool),
+            room_id,
+            deathlinks: Batcher::new(BatchLimits::default()),
+            countdowns: Batcher::new(BatchLimits::default()),
+            bounces: Batcher::new(BatchLimits::default()),
+        }
+    }
+
+    async fn add(&mut self, write: &PendingWrite) -> Result<()> {
+        let room_id = self.room_id.clone();
+        let pool = &self.tables.0;
+        match write.event.clone() {
+            RoomEvent::DeathLink {
+                slot,
+                source,
+                cause,
+            } => {
+                let new_deathlink = models::NewDeathLink {
+                    event_id: Some(write.id),
+                    ..models::NewDeathLink::new(room_id, slot, source, cause)
+                };
+                self.deathlinks.push(new_deathlink, &mut self.tables).await
+            }
+            RoomEvent::CountdownInit { slot } => {
+                let new_countdown = models::NewCountdown {
+                    event_id: Some(write.id),
+                    ..models::NewCountdown::new(room_id, slot)
+                };
+                self.countdowns.push(new_countdown, &mut self.tables).await
+            }
+            RoomEvent::LoginRefused {
+                slot,
+                name,
+                errors,
+                attempt,
+            } => {
+                let new_attempt = models::NewConnectionAttempt {
+                    event_id: Some(write.id),
+                    attempt_hmac: attempt,
+                    ..models::NewConnectionAttempt::refused(room_id, slot, name, errors)
+                };
+                models::insert_connection_attempt(pool, new_attempt).await
+            }
+            RoomEvent::LoginAccepted {
+                slot,
+                name,
+                attempt,
+            } => {
+                let new_attempt = models::NewConnectionAttempt {
+                    event_id: Some(write.id),
+                    ..models::NewConnectionAttempt::accepted(room_id, slot, name, attempt)
+                };
+                models::insert_connection_attempt(pool, new_attempt).await
+            }
+            // An upsert, storing it again is harmless
+            RoomEvent::PreferencesChanged {
+                slot,
+                name,
+                preferences: updated,
+            } => {
+                let new_preference = models::NewSlotPreference {
+                    room_id,
+                    slot: slot.0 as i32,
+                    player_name: preferences::canonical_name(&name),
+                    deathlink_opt_out: updated.deathlink_opt_out,
+                    muted: updated.muted,
+                    alias: updated.alias,
+                };
+                models::upsert_slot_preference(pool, new_preference).await
+            }
+            // Only of interest to live subscribers, the lobby keeps the passwords
+            RoomEvent::PasswordsRefreshed { .. } => Ok(()),
+            RoomEvent::BounceRecorded { slot, tags, data } => {
+                let new_bounce = models::NewBounce {
+                    room_id,
+                    slot: slot.0 as i32,
+                    tags,
+                    data,
+                    event_id: Some(write.id),
+                };
+                self.bounces.push(new_bounce, &mut self.tables).await
+            }
+            // Always the whole set, storing it again is harmless
+            RoomEvent::ReadyChanged { ready } => {
+                let value = serde_json::to_value(ready).unwrap();
+                models::set_room_setting(pool, &room_id, ready::READY_SETTING_KEY, value).await
+            }
+        }
+    }
+
+    async fn store_all(&mut self, batch: &[PendingWrite]) -> Result<()> {
+        for write in batch {
+            self.add(write).await?;
+        }
+        self.deathlinks.flush(&mut self.tables).await?;
+        self.countdowns.flush(&mut self.tables).await?;
+        self.bounces.flush(&mut self.tables).await
+    }
+}
+
+impl EventSink for DbSink {
+    const NAME: &'static str = "database";
+
+    async fn store(&mut self, write: &PendingWrite) -> Result<()> {
+        self.store_batch(std::slice::from_ref(write)).await
+    }
+
+    async fn store_batch(&mut self, batch: &[PendingWrite]) -> Result<()> {
+        let result = self.store_all(batch).await;
+        if result.is_err() {
+            // The writer keeps the whole batch until it's stored
+            self.deathlinks.discard();
+            self.countdowns.discard();
+            self.bounces.discard();
+        }
+        result
+    }
+}
+
+/// Stores events in order, keeping those that failed for later. Past `max_queued` events, the
+/// oldest half of the queue goes to the spill directory if there is one, or is dropped.
+pub struct DbWriter<S> {
+    sink: S,
+    queue: VecDeque<PendingWrite>,
+    max_queued: usize,
+    spill: Option<SpillDir>,
+    /// Whether the spill directory may hold batches, they're older than anything queued
+    spilled: bool,
+}
+
+impl<S: EventSink> DbWriter<S> {
+    pub fn new(sink: S, max_queued: usize, spill: Option<SpillDir>) -> Self {
+        // Left over by a previous run
+        let spilled = spill
+            .as_ref()
+            .is_some_and(|spill| !spill.is_empty().unwrap_or(false));
+        Self {
+            sink,
+            queue: VecDeque::new(),
+            max_queued,
+            spill,
+            spilled,
+        }
+    }
+
+    fn is_backlogged(&self) -> bool {
+        self.spilled || !self.queue.is_empty()
+    }
+
+    pub async fn write(&mut self, batch: Vec<PendingWrite>) {
+        // Going ahead of the backlog would store events out of order
+        if self.is_backlogged() {
+            self.enqueue(batch);
+            return;
+        }
+        if let Err(e) = self.sink.store_batch(&batch).await {
+            log::warn!(
+                "Failed to store {} events in {}, retrying later: {:?}",
+                batch.len(),
+                S::NAME,
+                e
+            );
+            self.enqueue(batch);
+        }
+    }
+
+    fn enqueue(&mut self, batch: Vec<PendingWrite>) {
+        self.queue.extend(batch);
+        while self.queue.len() > self.max_queued {
+            let overflow = (self.queue.len() / 2).max(1);
+            match &mut self.spill {
+                Some(spill) => {
+                    let batch: Vec<PendingWrite> = self.queue.drain(..overflow).collect();
+                    match spill.write(&batch) {
+                        Ok(()) => self.spilled = true,
+                        Err(e) => log::error!(
+                            "Failed to spill {} events to disk, dropping them: {:?}",
+                            batch.len(),
+                            e
+                        ),
+                    }
+                }
+                None => {
+                    log::error!(
+                        "{} retry queue is full, dropping the {} oldest events",
+                        S::NAME,
+                        overflow
+                    );
+                    self.queue.drain(..overflow);
+                }
+            }
+        }
+    }
+
+    /// Last chance before exiting, stores what it can and spills the rest
+    pub async fn shutdown(&mut self) {
+        self.retry().await;
+        if self.queue.is_empty() {
+            return;
+        }
+        let queued: Vec<PendingWrite> = self.queue.drain(..).collect();
+        match &mut self.spill {
+            Some(spill) => match spill.write(&queued) {
+                Ok(()) => log::info!("Spilled {} unstored events before exiting", queued.len()),
+                Err(e) => log::error!(
+                    "Failed to spill {} events before exiting, they're lost: {:?}",
+                    queued.len(),
+                    e
+                ),
+            },
+            None => log::error!(
+                "Exiting with {} events {} couldn't store",
+                queued.len(),
+                S::NAME
+            ),
+        }
+    }
+
+    /// Stores the backlog, spilled batches first, until a write fails
+    pub async fn retry(&mut self) {
+        if self.spilled
+            && let Some(spill) = &self.spill
+        {
+            loop {
+                let (path, batch) = match spill.oldest::<PendingWrite>() {
+                    Ok(Some(oldest)) => oldest,
+                    Ok(None) => {
+                        self.spilled = false;
+                        break;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to read the spill directory: {:?}", e);
+                        return;
+                    }
+                };
+                if let Err(e) = self.sink.store_batch(&batch).await {
+                    log::debug!("Replaying spilled events failed: {:?}", e);
+                    return;
+                }
+                if let Err(e) = spill.remove(&path) {
+                    // Its events would be replayed again, the database keeps a single copy
+                    log::error!("{:?}", e);
+                    return;
+                }
+                log::info!("Replayed {} spilled events", batch.len());
+            }
+        }
+
+        while !self.queue.is_empty() {
+            let count = self.queue.len().min(MAX_BATCH);
+            let batch = &self.queue.make_contiguous()[..count];
+            if let Err(e) = self.sink.store_batch(batch).await {
+                log::debug!("Retrying queued events failed: {:?}", e);
+                return;
+            }
+            self.queue.drain(..count);
+        }
+    }
+}
+
+/// Batches go to the writer as a whole, it keeps whatever it couldn't store
+impl<S: EventSink> BatchInsert<PendingWrite> for DbWriter<S> {
+    const TABLE: &'static str = S::NAME;
+
+    async fn insert(&mut self, rows: &[PendingWrite]) -> Result<()> {
+        self.write(rows.to_vec()).await;
+        Ok(())
+    }
+}
+
+/// Writes events from the bus until it closes or `shutdown` turns true. Events are held for up
+/// to `limits.max_delay` to be written together.
+pub async fn run<S: EventSink>(
+    mut receiver: broadcast::Receiver<RoomEvent>,
+    mut writer: DbWriter<S>,
+    limits: BatchLimits,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut retry = tokio::time::interval(RETRY_INTERVAL);
+    retry.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut pending = Batcher::new(BatchLimits {
+        max_rows: limits.max_rows.min(MAX_BATCH),
+        ..limits
+    });
+    let task = format!("{}_writer", S::NAME);
+    loop {
+        diagnostics::heartbeat(&task);
+        let deadline = pending.deadline();
+        // The writer never fails a batch, results are only there for the batcher
+        tokio::select! {
+            event = events::next_event(&mut receiver, S::NAME) => {
+                let Some(event) = event else {
+                    log::warn!("Event bus has been closed");
+                    break;
+                };
+                let _ = pending.push(PendingWrite::new(event), &mut writer).await;
+                for event in events::ready_events(&mut receiver, S::NAME, MAX_BATCH - 1) {
+                    let _ = pending.push(PendingWrite::new(event), &mut writer).await;
+                }
+                let _ = pending.flush_if_due(&mut writer).await;
+            }
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)),
+                if deadline.is_some() => {
+                let _ = pending.flush(&mut writer).await;
+            }
+            _ = retry.tick() => {
+                if writer.is_backlogged() {
+                    writer.retry().await;
+                }
+            }
+            _ = shutdown.wait_for(|shutdown| *shutdown) => {
+                log::info!("Flushing {} events before exiting", S::NAME);
+                for event in events::ready_events(&mut receiver, S::NAME, usize::MAX) {
+                    let _ = pending.push(PendingWrite::new(event), &mut writer).await;
+                }
+                break;
+            }
+        }
+    }
+
+    let _ = pending.flush(&mut writer).await;
+    writer.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spill::tests::temp_dir;
+    use aprs_proto::primitives::SlotId;
+    use std::collections::HashSet;
+
+    /// Keeps a single copy of each event like the database's unique `event_id`
+    #[derive(Default)]
+    struct MemorySink {
+        stored: Vec<i64>,
+        ids: HashSet<Uuid>,
+        /// Writes that succeed before the sink starts failing, `None` for never
+        remaining: Option<usize>,
+    }
+
+    impl EventSink for MemorySink {
+        const NAME: &'static str = "memory";
+
+        async fn store(&mut self, write: &PendingWrite) -> Result<()> {
+            if let Some(remaining) = &mut self.remaining {
+                if *remaining == 0 {
+                    anyhow::bail!("database is down");
+                }
+                *remaining -= 1;
+            }
+            let RoomEvent::CountdownInit { slot } = write.event else {
+                unreachable!();
+            };
+            if self.ids.insert(write.id) {
+                self.stored.push(slot.0);
+            }
+            Ok(())
+        }
+    }
+
+    fn countdown(slot: i64) -> PendingWrite {
+        PendingWrite::new(RoomEvent::CountdownInit { slot: SlotId(slot) })
+    }
+
+    fn down() -> MemorySink {
+        MemorySink {
+            remaining: Some(0),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spilled_events_survive_a_restart() {
+        let dir = temp_dir();
+        let mut writer = DbWriter::new(down(), 2, Some(SpillDir::open(&dir).unwrap()));
+        for slot in 1..=6 {
+            writer.write(vec![countdown(slot)]).await;
+        }
+        assert_eq!(writer.queue.len(), 2);
+        // Crash, losing what was only in memory
+        drop(writer);
+
+        let mut writer = DbWriter::new(
+            MemorySink::default(),
+            2,
+            Some(SpillDir::open(&dir).unwrap()),
+        );
+        assert!(writer.is_backlogged());
+        // New events wait for the spilled ones
+        writer.write(vec![countdown(7)]).await;
+        writer.retry().await;
+        assert_eq!(writer.sink.stored, vec![1, 2, 3, 4, 7]);
+        assert!(!writer.is_backlogged());
+        assert!(SpillDir::open(&dir).unwrap().is_empty().unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_partial_replay_stores_events_once() {
+        let dir = temp_dir();
+        let mut spill = SpillDir::open(&dir).unwrap();
+        spill
+            .write(&[countdown(1), countdown(2), countdown(3)])
+            .unwrap();
+        spill.write(&[countdown(0)]).unwrap();
+        spill.write(&[countdown(4)]).unwrap();
+        // Truncated by a full disk
+        std::fs::write(dir.join(format!("{:020}.json", 1)), "[{\"id\":").unwrap();
+
+        let sink = MemorySink {
+            remaining: Some(2),
+            ..Default::default()
+        };
+        let mut writer = DbWriter::new(sink, 10, Some(spill));
+        writer.retry().await;
+        assert_eq!(writer.sink.stored, vec![1, 2]);
+
+        // The database is back, the first file is replayed from the start
+        writer.sink.remaining = None;
+        writer.retry().await;
+        assert_eq!(writer.sink.stored, vec![1, 2, 3, 4]);
+        assert!(!writer.is_backlogged());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_event_sinks() {
+        assert_eq!(
+            "database, http".parse::<EventSinks>().unwrap(),
+            EventSinks {
+                database: true,
+                http: true,
+            }
+        );
+        assert!(!"http".parse::<EventSinks>().unwrap().database);
+        assert!("".parse::<EventSinks>().is_err());
+        assert!("database,kafka".parse::<EventSinks>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_without_spill_oldest_events_are_dropped() {
+        let mut writer = DbWriter::new(down(), 4, None);
+        for slot in 1..=5 {
+            writer.write(vec![countdown(slot)]).await;
+        }
+        writer.sink.remaining = None;
+        writer.retry().await;
+        assert_eq!(writer.sink.stored, vec![3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_flushes_held_events_and_spills_the_rest() {
+        let dir = temp_dir();
+        let bus = crate::events::EventBus::with_capacity(16);
+        let writer = DbWriter::new(down(), 10, Some(SpillDir::open(&dir).unwrap()));
+        let limits = BatchLimits {
+            max_rows: 100,
+            max_delay: Duration::from_secs(3600),
+        };
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let task = tokio::spawn(run(bus.subscribe(), writer, limits, shutdown_rx));
+        for slot in 1..=3 {
+            bus.publish(RoomEvent::CountdownInit { slot: SlotId(slot) });
+        }
+        shutdown_tx.send(true).unwrap();
+        task.await.unwrap();
+
+        // Held for an hour, but the database was down so it went to disk
+        let mut writer = DbWriter::new(
+            MemorySink::default(),
+            10,
+            Some(SpillDir::open(&dir).unwrap()),
+        );
+        writer.retry().await;
+        assert_eq!(writer.sink.stored, vec![1, 2, 3]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
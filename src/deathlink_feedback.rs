@@ -0,0 +1,169 @@
+use aprs_proto::primitives::SlotId;
+use serde_json::Value;
+use std::collections::HashSet;
+
+use crate::proto::PrintJSON;
+
+/// Who is told when a DeathLink doesn't reach a slot
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DeathlinkFeedback {
+    /// Only the grace period says anything, like before feedback existed
+    #[default]
+    Off,
+    /// The slot that was spared
+    Receiver,
+    /// The slot that died, with how many slots its death reached
+    Sender,
+    Both,
+}
+
+impl DeathlinkFeedback {
+    pub fn tells_receiver(self) -> bool {
+        matches!(self, DeathlinkFeedback::Receiver | DeathlinkFeedback::Both)
+    }
+
+    pub fn tells_sender(self) -> bool {
+        matches!(self, DeathlinkFeedback::Sender | DeathlinkFeedback::Both)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidDeathlinkFeedback(String);
+
+impl std::fmt::Display for InvalidDeathlinkFeedback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected off, receiver, sender or both, got {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidDeathlinkFeedback {}
+
+impl std::str::FromStr for DeathlinkFeedback {
+    type Err = InvalidDeathlinkFeedback;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Ok(DeathlinkFeedback::Off),
+            "receiver" => Ok(DeathlinkFeedback::Receiver),
+            "sender" => Ok(DeathlinkFeedback::Sender),
+            "both" => Ok(DeathlinkFeedback::Both),
+            _ => Err(InvalidDeathlinkFeedback(s.to_string())),
+        }
+    }
+}
+
+/// Why a DeathLink didn't reach a slot
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Absorbed {
+    /// The slot, or a group it's addressed through, is excluded or opted out
+    Exclusion,
+    /// The DeathLink probability roll failed
+    Probability,
+    /// The client is still within its grace period
+    Grace,
+}
+
+impl Absorbed {
+    /// What the receiver is told, `source` being who died
+    pub fn to_print_json(self, source: &str) -> Value {
+        let why = match self {
+            Absorbed::Exclusion => "your slot doesn't receive DeathLinks in this room",
+            Absorbed::Probability => "of the room's DeathLink probability",
+            Absorbed::Grace => "you were still loading in",
+        };
+        let text = format!("A death from {} was absorbed, because {}.", source, why);
+        serde_json::to_value(PrintJSON::with_color(&text, "yellow")).unwrap()
+    }
+}
+
+/// What became of one DeathLink, by slot. Only lives while the Bounce is routed, so the sender
+/// summary can count the slots it reached.
+#[derive(Debug, Default)]
+pub struct Deliveries {
+    reached: HashSet<SlotId>,
+    absorbed: HashSet<SlotId>,
+}
+
+impl Deliveries {
+    pub fn reached(&mut self, slot: SlotId) {
+        self.absorbed.remove(&slot);
+        self.reached.insert(slot);
+    }
+
+    /// A slot counts as reached as soon as one of its clients is
+    pub fn absorbed(&mut self, slot: SlotId) {
+        if !self.reached.contains(&slot) {
+            self.absorbed.insert(slot);
+        }
+    }
+
+    /// Slots reached, out of the slots the DeathLink was for
+    pub fn counts(&self) -> (usize, usize) {
+        let reached = self.reached.len();
+        (reached, reached + self.absorbed.len())
+    }
+
+    /// What the sender is told, nothing when every slot was reached
+    pub fn summary(&self) -> Option<Value> {
+        if self.absorbed.is_empty() {
+            return None;
+        }
+        let (reached, targets) = self.counts();
+        let players = if targets == 1 { "player" } else { "players" };
+        let text = format!(
+            "Your death reached {} of {} {}, the others were spared by the room's DeathLink settings.",
+            reached, targets, players
+        );
+        Some(serde_json::to_value(PrintJSON::with_color(&text, "yellow")).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_feedback_parse() {
+        assert_eq!(
+            "off".parse::<DeathlinkFeedback>().unwrap(),
+            DeathlinkFeedback::Off
+        );
+        assert_eq!(
+            " Both ".parse::<DeathlinkFeedback>().unwrap(),
+            DeathlinkFeedback::Both
+        );
+        assert!("everyone".parse::<DeathlinkFeedback>().is_err());
+
+        assert!(DeathlinkFeedback::Receiver.tells_receiver());
+        assert!(!DeathlinkFeedback::Receiver.tells_sender());
+        assert!(DeathlinkFeedback::Sender.tells_sender());
+        assert!(!DeathlinkFeedback::Off.tells_receiver() && !DeathlinkFeedback::Off.tells_sender());
+    }
+
+    #[test]
+    fn test_slots_are_counted_once() {
+        let mut deliveries = Deliveries::default();
+        assert_eq!(deliveries.summary(), None);
+
+        // Two clients of slot 2, one of them loading in
+        deliveries.absorbed(SlotId(2));
+        deliveries.reached(SlotId(2));
+        deliveries.reached(SlotId(3));
+        deliveries.reached(SlotId(4));
+        deliveries.absorbed(SlotId(4));
+        assert_eq!(deliveries.counts(), (3, 3));
+        assert_eq!(deliveries.summary(), None);
+
+        deliveries.absorbed(SlotId(5));
+        deliveries.absorbed(SlotId(6));
+        assert_eq!(deliveries.counts(), (3, 5));
+        let summary = deliveries.summary().unwrap();
+        assert_eq!(summary["cmd"], "PrintJSON");
+        assert!(
+            summary["data"][0]["text"]
+                .as_str()
+                .unwrap()
+                .starts_with("Your death reached 3 of 5 players")
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use chrono::{DateTime, Utc};
+use futures_util::FutureExt;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+use crate::events::EventBus;
+use crate::metrics;
+use crate::outbox;
+use crate::shedding::{DegradedStatus, LoadShedding};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How long a task has to run before a panic starts the backoff over
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+const GAUGE_INTERVAL: Duration = Duration::from_secs(15);
+
+static TASKS: LazyLock<Mutex<BTreeMap<String, TaskHealth>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct TaskHealth {
+    pub running: bool,
+    /// Last heartbeat, tasks beat once per iteration of their loop
+    pub last_run: Option<DateTime<Utc>>,
+    pub panics: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ChannelDepth {
+    pub depth: usize,
+    pub capacity: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ResponseQueues {
+    pub connections: usize,
+    /// Responses queued across all connections
+    pub depth: usize,
+    /// Deepest queue of a single connection
+    pub max_depth: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Diagnostics {
+    pub events: ChannelDepth,
+    pub responses: ResponseQueues,
+    pub degraded_mode: DegradedStatus,
+    pub tasks: BTreeMap<String, TaskHealth>,
+}
+
+fn update(task: &str, update: impl FnOnce(&mut TaskHealth)) {
+    let mut tasks = TASKS.lock().unwrap();
+    update(tasks.entry(task.to_string()).or_default());
+}
+
+pub fn heartbeat(task: &str) {
+    let now = Utc::now();
+    update(task, |health| health.last_run = Some(now));
+    metrics::record_task_heartbeat(task, now);
+}
+
+pub fn task_health(task: &str) -> Option<TaskHealth> {
+    TASKS.lock().unwrap().get(task).cloned()
+}
+
+/// Spawns the task made by `make`, making another one if it panics. A task that returns is
+/// done and isn't restarted.
+pub fn supervise<F, Fut>(task: &'static str, make: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    supervise_with(task, MIN_BACKOFF, make)
+}
+
+fn supervise_with<F, Fut>(task: &'static str, min_backoff: Duration, mut make: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut backoff = min_backoff;
+        loop {
+            update(task, |health| health.running = true);
+            let started = Instant::now();
+            let outcome = AssertUnwindSafe(make()).catch_unwind().await;
+            update(task, |health| health.running = false);
+            if outcome.is_ok() {
+                log::info!("Background task {} finished", task);
+                return;
+            }
+
+            update(task, |health| health.panics += 1);
+            metrics::record_task_panic(task);
+            if started.elapsed() >= HEALTHY_RUN {
+                backoff = min_backoff;
+            }
+            log::error!(
+                "Background task {} panicked, restarting it in {:?}",
+                task,
+                backoff
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF.max(min_backoff));
+        }
+    })
+}
+
+pub fn collect(events: &EventBus, load_shedding: &LoadShedding) -> Diagnostics {
+    Diagnostics {
+        events: ChannelDepth {
+            depth: events.depth(),
+            capacity: events.capacity(),
+        },
+        responses: response_queues(),
+        degraded_mode: load_shedding.status(),
+        tasks: TASKS.lock().unwrap().clone(),
+    }
+}
+
+fn response_queues() -> ResponseQueues {
+    let depths = outbox::queue_depths();
+    ResponseQueues {
+        connections: depths.len(),
+        depth: depths.iter().sum(),
+        max_depth: depths.iter().copied().max().unwrap_or(0),
+    }
+}
+
+/// Keeps the channel depth gauges current between scrapes
+pub async fn update_gauges(events: EventBus) {
+    let mut ticker = tokio::time::interval(GAUGE_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        heartbeat("diagnostics");
+        let responses = response_queues();
+        metrics::set_channel_depth("events", events.depth());
+        metrics::set_channel_depth("responses", responses.depth);
+        metrics::set_channel_depth("responses_max", responses.max_depth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    async fn wait_for(mut condition: impl FnMut() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("condition never became true");
+    }
+
+    #[tokio::test]
+    async fn test_panicking_task_is_restarted() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let runs_task = runs.clone();
+        let handle = supervise_with("test_panicking", Duration::from_millis(10), move || {
+            let runs = runs_task.clone();
+            async move {
+                if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("killed");
+                }
+                heartbeat("test_panicking");
+                std::future::pending::<()>().await
+            }
+        });
+
+        // Only the restarted task beats
+        wait_for(|| task_health("test_panicking").is_some_and(|health| health.last_run.is_some()))
+            .await;
+        let health = task_health("test_panicking").unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(health.panics, 1);
+        assert!(health.running);
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_finished_task_stays_finished() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let runs_task = runs.clone();
+        let handle = supervise_with("test_finished", Duration::from_millis(10), move || {
+            let runs = runs_task.clone();
+            async move {
+                runs.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        handle.await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        let health = task_health("test_finished").unwrap();
+        assert!(!health.running);
+        assert_eq!(health.panics, 0);
+    }
+
+    #[test]
+    fn test_collect_reports_event_bus_depth() {
+        let bus = EventBus::with_capacity(8);
+        let _receiver = bus.subscribe();
+        bus.publish(crate::events::RoomEvent::CountdownInit {
+            slot: aprs_proto::primitives::SlotId(1),
+        });
+        let diagnostics = collect(&bus, &LoadShedding::new(None, Duration::ZERO));
+        assert_eq!(diagnostics.events.depth, 1);
+        assert_eq!(diagnostics.events.capacity, 8);
+        assert!(!diagnostics.degraded_mode.degraded);
+    }
+}
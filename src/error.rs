@@ -0,0 +1,393 @@
+use std::fmt;
+use tungstenite::error::ProtocolError;
+use tungstenite::protocol::frame::coding::CloseCode;
+
+use crate::metrics;
+
+pub type ProxyResult<T> = Result<T, ProxyError>;
+
+/// Why a proxied connection ended. Close codes, log levels and metric labels are all derived
+/// from the variant so call sites only have to say whose fault it was.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The client broke the protocol, nothing we can do about it
+    ClientProtocol(String),
+    /// The client sent a message past the limits we set, not a broken client
+    ClientLimit(String),
+    /// Upstream sent something we couldn't make sense of, or couldn't be reached
+    UpstreamProtocol(String),
+    /// The client didn't authenticate
+    Auth(String),
+    /// The client socket failed or was closed abruptly
+    ClientIo(tungstenite::Error),
+    /// The upstream socket failed or was closed abruptly
+    UpstreamIo(tungstenite::Error),
+    /// Upstream accepted the connection but never sent its RoomInfo
+    UpstreamTimeout(String),
+    /// A bug on our side
+    Internal(String),
+}
+
+impl ProxyError {
+    pub fn client(error: impl fmt::Display) -> Self {
+        ProxyError::ClientProtocol(error.to_string())
+    }
+
+    pub fn upstream(error: impl fmt::Display) -> Self {
+        ProxyError::UpstreamProtocol(error.to_string())
+    }
+
+    pub fn internal(error: impl fmt::Display) -> Self {
+        ProxyError::Internal(error.to_string())
+    }
+
+    /// Classifies an error from the client socket
+    pub fn from_client(error: tungstenite::Error) -> Self {
+        match WsErrorCause::of(&error) {
+            WsErrorCause::Limit => ProxyError::ClientLimit(error.to_string()),
+            WsErrorCause::Utf8 | WsErrorCause::Protocol => {
+                ProxyError::ClientProtocol(error.to_string())
+            }
+            WsErrorCause::Io | WsErrorCause::Closed => ProxyError::ClientIo(error),
+        }
+    }
+
+    /// Classifies an error from the upstream socket
+    pub fn from_upstream(error: tungstenite::Error) -> Self {
+        match WsErrorCause::of(&error) {
+            WsErrorCause::Limit | WsErrorCause::Utf8 | WsErrorCause::Protocol => {
+                ProxyError::UpstreamProtocol(error.to_string())
+            }
+            WsErrorCause::Io | WsErrorCause::Closed => ProxyError::UpstreamIo(error),
+        }
+    }
+
+    /// Like `from_client`, for an error reading the client socket, which is also counted
+    pub fn read_from_client(error: tungstenite::Error) -> Self {
+        record_read_error(Side::Client, &error);
+        Self::from_client(error)
+    }
+
+    /// Like `from_upstream`, for an error reading the upstream socket, which is also counted
+    pub fn read_from_upstream(error: tungstenite::Error) -> Self {
+        record_read_error(Side::Upstream, &error);
+        Self::from_upstream(error)
+    }
+
+    /// Close code sent to the client, `None` when the socket can't be written to anymore
+    pub fn close_code(&self) -> Option<CloseCode> {
+        match self {
+            ProxyError::ClientProtocol(_) => Some(CloseCode::Protocol),
+            ProxyError::ClientLimit(_) => Some(CloseCode::Size),
+            ProxyError::Auth(_) => Some(CloseCode::Policy),
+            ProxyError::UpstreamProtocol(_) | ProxyError::Internal(_) => Some(CloseCode::Error),
+            // Nothing is wrong with the client, it may well work a bit later
+            ProxyError::UpstreamTimeout(_) => Some(CloseCode::Again),
+            ProxyError::ClientIo(_) | ProxyError::UpstreamIo(_) => None,
+        }
+    }
+
+    pub fn log_level(&self) -> log::Level {
+        match self {
+            ProxyError::ClientProtocol(_) | ProxyError::ClientIo(_) | ProxyError::UpstreamIo(_) => {
+                log::Level::Debug
+            }
+            ProxyError::Auth(_) | ProxyError::ClientLimit(_) => log::Level::Info,
+            ProxyError::UpstreamProtocol(_) | ProxyError::UpstreamTimeout(_) => log::Level::Warn,
+            ProxyError::Internal(_) => log::Level::Error,
+        }
+    }
+
+    pub fn disconnect_cause(&self) -> DisconnectCause {
+        match self {
+            ProxyError::ClientProtocol(_) => DisconnectCause::ClientProtocol,
+            ProxyError::ClientIo(_) => DisconnectCause::ClientIo,
+            ProxyError::UpstreamProtocol(_) | ProxyError::UpstreamIo(_) => {
+                DisconnectCause::UpstreamError
+            }
+            ProxyError::UpstreamTimeout(_) => DisconnectCause::UpstreamTimeout,
+            ProxyError::Auth(_) | ProxyError::ClientLimit(_) => DisconnectCause::ProxyPolicy,
+            ProxyError::Internal(_) => DisconnectCause::Internal,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ProxyError::ClientProtocol(_) => "client_protocol",
+            ProxyError::ClientLimit(_) => "client_limit",
+            ProxyError::UpstreamProtocol(_) => "upstream_protocol",
+            ProxyError::UpstreamTimeout(_) => "upstream_timeout",
+            ProxyError::Auth(_) => "auth",
+            ProxyError::ClientIo(_) | ProxyError::UpstreamIo(_) => "io",
+            ProxyError::Internal(_) => "internal",
+        }
+    }
+}
+
+/// Why a connection ended, whether it was an error or not
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisconnectCause {
+    /// The client sent a Close or its stream ended
+    ClientClose,
+    ClientIo,
+    ClientProtocol,
+    /// Upstream sent a Close or its stream ended
+    UpstreamClose,
+    UpstreamError,
+    /// Upstream never sent its RoomInfo
+    UpstreamTimeout,
+    /// The proxy ended it, the client didn't authenticate or broke a limit
+    ProxyPolicy,
+    /// The buffers of the connection grew past PER_CONNECTION_MEMORY_CAP
+    MemoryCap,
+    Internal,
+}
+
+impl DisconnectCause {
+    pub fn label(&self) -> &'static str {
+        match self {
+            DisconnectCause::ClientClose => "client_close",
+            DisconnectCause::ClientIo => "client_error_io",
+            DisconnectCause::ClientProtocol => "client_error_protocol",
+            DisconnectCause::UpstreamClose => "upstream_close",
+            DisconnectCause::UpstreamError => "upstream_error",
+            DisconnectCause::UpstreamTimeout => "upstream_timeout",
+            DisconnectCause::ProxyPolicy => "proxy_policy",
+            DisconnectCause::MemoryCap => "memory_cap",
+            DisconnectCause::Internal => "internal",
+        }
+    }
+}
+
+/// Which socket of a connection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    Client,
+    Upstream,
+}
+
+impl Side {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Side::Client => "client",
+            Side::Upstream => "upstream",
+        }
+    }
+}
+
+/// What went wrong on a WebSocket, as counted in `apx_ws_errors_total`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WsErrorCause {
+    /// A frame or message past the sizes we configured, the peer didn't break the protocol
+    Limit,
+    /// A text message that isn't UTF-8
+    Utf8,
+    /// Any other broken frame or handshake
+    Protocol,
+    /// The socket failed, or the peer vanished without a Close
+    Io,
+    /// The socket was used after it closed
+    Closed,
+}
+
+impl WsErrorCause {
+    pub fn of(error: &tungstenite::Error) -> Self {
+        match error {
+            tungstenite::Error::Capacity(_) => WsErrorCause::Limit,
+            tungstenite::Error::Utf8 { .. } => WsErrorCause::Utf8,
+            tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake) => {
+                WsErrorCause::Io
+            }
+            tungstenite::Error::Protocol(_)
+            | tungstenite::Error::AttackAttempt
+            | tungstenite::Error::Http(_)
+            | tungstenite::Error::HttpFormat(_) => WsErrorCause::Protocol,
+            tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+                WsErrorCause::Closed
+            }
+            _ => WsErrorCause::Io,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            WsErrorCause::Limit => "limit",
+            WsErrorCause::Utf8 => "utf8",
+            WsErrorCause::Protocol => "protocol",
+            WsErrorCause::Io => "io",
+            WsErrorCause::Closed => "closed",
+        }
+    }
+
+    /// A limit cutting off upstream is most likely set too low, while clients breaking one or
+    /// the protocol can't be helped
+    fn log_level(&self, side: Side) -> log::Level {
+        match (self, side) {
+            (WsErrorCause::Io | WsErrorCause::Closed, _) => log::Level::Debug,
+            (_, Side::Upstream) => log::Level::Warn,
+            (WsErrorCause::Limit, Side::Client) => log::Level::Info,
+            (WsErrorCause::Utf8 | WsErrorCause::Protocol, Side::Client) => log::Level::Debug,
+        }
+    }
+}
+
+/// Logs and counts an error reading the `side` socket, which ends the connection
+pub fn record_read_error(side: Side, error: &tungstenite::Error) {
+    let cause = WsErrorCause::of(error);
+    log::log!(
+        cause.log_level(side),
+        "WebSocket error reading {} ({}): {}",
+        side.label(),
+        cause.label(),
+        error
+    );
+    metrics::record_ws_error(side.label(), cause.label());
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::ClientProtocol(e) => write!(f, "client protocol error: {}", e),
+            ProxyError::ClientLimit(e) => write!(f, "client past our limits: {}", e),
+            ProxyError::UpstreamProtocol(e) => write!(f, "upstream protocol error: {}", e),
+            ProxyError::Auth(e) => write!(f, "authentication error: {}", e),
+            ProxyError::ClientIo(e) => write!(f, "client connection error: {}", e),
+            ProxyError::UpstreamIo(e) => write!(f, "upstream connection error: {}", e),
+            ProxyError::UpstreamTimeout(e) => write!(f, "upstream timeout: {}", e),
+            ProxyError::Internal(e) => write!(f, "internal error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProxyError::ClientIo(e) | ProxyError::UpstreamIo(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tungstenite::error::CapacityError;
+
+    #[test]
+    fn test_socket_errors_are_classified_by_side() {
+        let protocol = || tungstenite::Error::Protocol(ProtocolError::NonZeroReservedBits);
+        assert!(matches!(
+            ProxyError::from_client(protocol()),
+            ProxyError::ClientProtocol(_)
+        ));
+        assert!(matches!(
+            ProxyError::from_upstream(protocol()),
+            ProxyError::UpstreamProtocol(_)
+        ));
+
+        let capacity = tungstenite::Error::Capacity(CapacityError::MessageTooLong {
+            size: 2,
+            max_size: 1,
+        });
+        assert!(matches!(
+            ProxyError::from_client(capacity),
+            ProxyError::ClientLimit(_)
+        ));
+
+        assert!(matches!(
+            ProxyError::from_client(tungstenite::Error::ConnectionClosed),
+            ProxyError::ClientIo(_)
+        ));
+        assert!(matches!(
+            ProxyError::from_upstream(tungstenite::Error::AlreadyClosed),
+            ProxyError::UpstreamIo(_)
+        ));
+        let reset = tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake);
+        assert!(matches!(
+            ProxyError::from_client(reset),
+            ProxyError::ClientIo(_)
+        ));
+    }
+
+    #[test]
+    fn test_close_codes() {
+        assert_eq!(
+            ProxyError::client("garbage").close_code(),
+            Some(CloseCode::Protocol)
+        );
+        assert_eq!(
+            ProxyError::Auth("timeout".into()).close_code(),
+            Some(CloseCode::Policy)
+        );
+        assert_eq!(
+            ProxyError::upstream("garbage").close_code(),
+            Some(CloseCode::Error)
+        );
+        assert_eq!(
+            ProxyError::UpstreamTimeout("silent".into()).close_code(),
+            Some(CloseCode::Again)
+        );
+        assert_eq!(
+            ProxyError::internal("bug").close_code(),
+            Some(CloseCode::Error)
+        );
+        assert_eq!(
+            ProxyError::ClientIo(tungstenite::Error::ConnectionClosed).close_code(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_client_errors_are_not_logged_as_errors() {
+        assert!(ProxyError::client("garbage").log_level() > log::Level::Warn);
+        assert!(
+            ProxyError::ClientIo(tungstenite::Error::ConnectionClosed).log_level()
+                > log::Level::Warn
+        );
+        assert_eq!(
+            ProxyError::upstream("garbage").log_level(),
+            log::Level::Warn
+        );
+        assert_eq!(ProxyError::internal("bug").log_level(), log::Level::Error);
+    }
+
+    #[test]
+    fn test_socket_error_causes() {
+        let too_long = || {
+            tungstenite::Error::Capacity(CapacityError::MessageTooLong {
+                size: 2,
+                max_size: 1,
+            })
+        };
+        let cause = |error| WsErrorCause::of(&error).label();
+        assert_eq!(cause(too_long()), "limit");
+        assert_eq!(
+            cause(tungstenite::Error::Protocol(
+                ProtocolError::NonZeroReservedBits
+            )),
+            "protocol"
+        );
+        assert_eq!(
+            cause(tungstenite::Error::Protocol(
+                ProtocolError::ResetWithoutClosingHandshake
+            )),
+            "io"
+        );
+        assert_eq!(
+            cause(tungstenite::Error::Io(
+                std::io::ErrorKind::ConnectionReset.into()
+            )),
+            "io"
+        );
+        assert_eq!(cause(tungstenite::Error::AlreadyClosed), "closed");
+
+        // A client past our limits is let go by policy, upstream past them is still its error
+        let client = ProxyError::from_client(too_long());
+        assert_eq!(client.close_code(), Some(CloseCode::Size));
+        assert_eq!(client.disconnect_cause(), DisconnectCause::ProxyPolicy);
+        assert_eq!(
+            ProxyError::from_upstream(too_long()).disconnect_cause(),
+            DisconnectCause::UpstreamError
+        );
+    }
+}
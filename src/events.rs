@@ -0,0 +1,336 @@
+use aprs_proto::primitives::SlotId;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+use crate::diagnostics;
+use crate::lobby::RefreshedSlot;
+use crate::metrics;
+use crate::preferences::SlotPreferences;
+
+const EVENT_BUS_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RoomEvent {
+    DeathLink {
+        slot: SlotId,
+        source: String,
+        cause: Option<String>,
+    },
+    CountdownInit {
+        slot: SlotId,
+    },
+    /// A login was refused, either by upstream or by the proxy's password check
+    LoginRefused {
+        slot: Option<SlotId>,
+        name: String,
+        errors: Vec<String>,
+        /// The audit HMAC of the attempt, when AUDIT_HMAC_KEY is set
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        attempt: Option<String>,
+    },
+    /// A login went through, only published with AUDIT_HMAC_KEY set so its attempt is on record
+    LoginAccepted {
+        slot: SlotId,
+        name: String,
+        attempt: String,
+    },
+    /// A player changed their own preferences in game
+    PreferencesChanged {
+        slot: SlotId,
+        name: String,
+        preferences: SlotPreferences,
+    },
+    /// The slot passwords were reloaded from the lobby, `total` is how many slots it has now
+    PasswordsRefreshed {
+        changed_slots: Vec<RefreshedSlot>,
+        total: usize,
+    },
+    /// A Bounce of a slot listed in RECORD_BOUNCES, as the proxy saw it
+    BounceRecorded {
+        slot: SlotId,
+        tags: Vec<String>,
+        data: Value,
+    },
+    /// Slots marked ready for the start changed, `ready` is all of them now
+    ReadyChanged {
+        ready: Vec<SlotId>,
+    },
+}
+
+impl RoomEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RoomEvent::DeathLink { .. } => "deathlink",
+            RoomEvent::CountdownInit { .. } => "countdown_init",
+            RoomEvent::LoginRefused { .. } => "login_refused",
+            RoomEvent::LoginAccepted { .. } => "login_accepted",
+            RoomEvent::PreferencesChanged { .. } => "preferences_changed",
+            RoomEvent::PasswordsRefreshed { .. } => "passwords_refreshed",
+            RoomEvent::BounceRecorded { .. } => "bounce_recorded",
+            RoomEvent::ReadyChanged { .. } => "ready_changed",
+        }
+    }
+}
+
+/// In-process fan-out of room events. Publishing never blocks and never fails, subscribers that
+/// fall behind lose the oldest events without affecting the others.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<RoomEvent>,
+    capacity: usize,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::with_capacity(EVENT_BUS_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender, capacity }
+    }
+
+    pub fn publish(&self, event: RoomEvent) {
+        // An error only means there are no subscribers right now
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RoomEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Receivers for a supervised subscriber. The first one is subscribed right away rather
+    /// than when the task first runs, so it sees everything published from now on.
+    pub fn subscriber(&self) -> impl FnMut() -> broadcast::Receiver<RoomEvent> + Send + 'static {
+        let sender = self.sender.clone();
+        let mut first = Some(sender.subscribe());
+        move || first.take().unwrap_or_else(|| sender.subscribe())
+    }
+
+    /// Events not yet seen by the slowest subscriber
+    pub fn depth(&self) -> usize {
+        self.sender.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Waits for the next event, skipping over lag. Returns `None` once the bus is gone.
+pub async fn next_event(
+    receiver: &mut broadcast::Receiver<RoomEvent>,
+    subscriber: &str,
+) -> Option<RoomEvent> {
+    loop {
+        match receiver.recv().await {
+            Ok(event) => return Some(event),
+            Err(RecvError::Lagged(skipped)) => {
+                log::warn!(
+                    "Event subscriber {} lagged behind, skipped {} events",
+                    subscriber,
+                    skipped
+                );
+                metrics::record_events_lagged(subscriber, skipped);
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Events already waiting, up to `max`, without waiting for more
+pub fn ready_events(
+    receiver: &mut broadcast::Receiver<RoomEvent>,
+    subscriber: &str,
+    max: usize,
+) -> Vec<RoomEvent> {
+    let mut events = Vec::new();
+    while events.len() < max {
+        match receiver.try_recv() {
+            Ok(event) => events.push(event),
+            Err(TryRecvError::Lagged(skipped)) => {
+                log::warn!(
+                    "Event subscriber {} lagged behind, skipped {} events",
+                    subscriber,
+                    skipped
+                );
+                metrics::record_events_lagged(subscriber, skipped);
+            }
+            Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+        }
+    }
+    events
+}
+
+pub async fn metrics_subscriber(mut receiver: broadcast::Receiver<RoomEvent>, room_id: String) {
+    while let Some(event) = next_event(&mut receiver, "metrics").await {
+        diagnostics::heartbeat("event_metrics");
+        metrics::record_event(&room_id, event.kind());
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    room_id: &'a str,
+    #[serde(flatten)]
+    event: &'a RoomEvent,
+}
+
+pub async fn webhook_sender(
+    mut receiver: broadcast::Receiver<RoomEvent>,
+    url: Url,
+    room_id: String,
+) {
+    let client = reqwest::Client::new();
+    while let Some(event) = next_event(&mut receiver, "webhook").await {
+        diagnostics::heartbeat("webhook");
+        let payload = WebhookPayload {
+            room_id: &room_id,
+            event: &event,
+        };
+        match client.post(url.clone()).json(&payload).send().await {
+            Ok(response) if !response.status().is_success() => {
+                log::warn!(
+                    "Webhook rejected {} event: HTTP {}",
+                    event.kind(),
+                    response.status()
+                );
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to send {} event to webhook: {:?}", event.kind(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn countdown(slot: i64) -> RoomEvent {
+        RoomEvent::CountdownInit { slot: SlotId(slot) }
+    }
+
+    fn slot_of(event: &RoomEvent) -> i64 {
+        match event {
+            RoomEvent::DeathLink { slot, .. } | RoomEvent::CountdownInit { slot } => slot.0,
+            RoomEvent::LoginRefused { .. }
+            | RoomEvent::LoginAccepted { .. }
+            | RoomEvent::PreferencesChanged { .. }
+            | RoomEvent::PasswordsRefreshed { .. }
+            | RoomEvent::BounceRecorded { .. }
+            | RoomEvent::ReadyChanged { .. } => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events_are_delivered_in_order() {
+        let bus = EventBus::new();
+        let mut receiver = bus.subscribe();
+        for slot in 0..5 {
+            bus.publish(countdown(slot));
+        }
+        for slot in 0..5 {
+            let event = next_event(&mut receiver, "test").await.unwrap();
+            assert_eq!(slot_of(&event), slot);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_are_independent() {
+        let bus = EventBus::new();
+        let mut first = bus.subscribe();
+        let mut second = bus.subscribe();
+        bus.publish(countdown(1));
+        bus.publish(countdown(2));
+
+        assert_eq!(slot_of(&next_event(&mut first, "first").await.unwrap()), 1);
+        assert_eq!(slot_of(&next_event(&mut first, "first").await.unwrap()), 2);
+        assert_eq!(
+            slot_of(&next_event(&mut second, "second").await.unwrap()),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber_skips_oldest_events() {
+        let bus = EventBus::with_capacity(2);
+        let mut slow = bus.subscribe();
+        for slot in 0..5 {
+            bus.publish(countdown(slot));
+        }
+
+        assert_eq!(slot_of(&next_event(&mut slow, "slow").await.unwrap()), 3);
+        assert_eq!(slot_of(&next_event(&mut slow, "slow").await.unwrap()), 4);
+
+        bus.publish(countdown(5));
+        assert_eq!(slot_of(&next_event(&mut slow, "slow").await.unwrap()), 5);
+    }
+
+    #[test]
+    fn test_ready_events_are_taken_without_waiting() {
+        let bus = EventBus::with_capacity(4);
+        let mut receiver = bus.subscribe();
+        assert!(ready_events(&mut receiver, "test", 10).is_empty());
+
+        for slot in 0..6 {
+            bus.publish(countdown(slot));
+        }
+        let slots = |events: Vec<RoomEvent>| events.iter().map(slot_of).collect::<Vec<_>>();
+        // Lagged past the first two
+        assert_eq!(slots(ready_events(&mut receiver, "test", 3)), vec![2, 3, 4]);
+        assert_eq!(slots(ready_events(&mut receiver, "test", 3)), vec![5]);
+    }
+
+    #[tokio::test]
+    async fn test_publish_without_subscribers() {
+        let bus = EventBus::new();
+        bus.publish(countdown(1));
+        let mut late = bus.subscribe();
+        bus.publish(countdown(2));
+        assert_eq!(slot_of(&next_event(&mut late, "late").await.unwrap()), 2);
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_sees_events_published_before_it_runs() {
+        let bus = EventBus::new();
+        let mut subscribe = bus.subscriber();
+        bus.publish(countdown(1));
+        let mut first = subscribe();
+        assert_eq!(slot_of(&next_event(&mut first, "first").await.unwrap()), 1);
+
+        // A restarted subscriber picks up from there
+        let mut restarted = subscribe();
+        bus.publish(countdown(2));
+        assert_eq!(
+            slot_of(&next_event(&mut restarted, "restarted").await.unwrap()),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_closed_bus_ends_subscription() {
+        let bus = EventBus::new();
+        let mut receiver = bus.subscribe();
+        bus.publish(countdown(1));
+        drop(bus);
+        assert!(next_event(&mut receiver, "test").await.is_some());
+        assert!(next_event(&mut receiver, "test").await.is_none());
+    }
+
+    #[test]
+    fn test_event_serialization() {
+        let event = RoomEvent::DeathLink {
+            slot: SlotId(3),
+            source: "Player".into(),
+            cause: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({"type": "deathlink", "slot": 3, "source": "Player", "cause": null})
+        );
+    }
+}
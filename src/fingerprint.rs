@@ -0,0 +1,195 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::{LazyLock, Mutex};
+
+use crate::proto::Version;
+
+/// Tags telling client software apart, the others are left out of fingerprints
+const NOTABLE_TAGS: &[&str] = &[
+    "AP",
+    "DeathLink",
+    "HintGame",
+    "NoText",
+    "TextOnly",
+    "Tracker",
+];
+const MAX_GAME_LABELS: usize = 100;
+const MAX_VERSION_LABELS: usize = 32;
+const MAX_GAME_LENGTH: usize = 64;
+/// Label for values past a guard's cap, or too odd to be worth a label of their own
+pub const OTHER: &str = "other";
+
+static GAME_LABELS: LazyLock<LabelGuard> = LazyLock::new(|| LabelGuard::new(MAX_GAME_LABELS));
+static VERSION_LABELS: LazyLock<LabelGuard> = LazyLock::new(|| LabelGuard::new(MAX_VERSION_LABELS));
+
+/// Client identification sent in Connect, as is
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ClientSoftware {
+    pub uuid: String,
+    pub version: Option<Version>,
+}
+
+/// Normalized client identification, safe to use as metric labels
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Fingerprint {
+    pub game: String,
+    pub version: String,
+    pub tags: Vec<String>,
+}
+
+/// Caps the number of distinct values a label takes, the first ones seen keep theirs
+pub struct LabelGuard {
+    max: usize,
+    seen: Mutex<HashSet<String>>,
+}
+
+impl LabelGuard {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn label(&self, value: String) -> String {
+        let mut seen = self.seen.lock().unwrap();
+        if seen.contains(&value) {
+            return value;
+        }
+        if seen.len() >= self.max {
+            return OTHER.to_string();
+        }
+        seen.insert(value.clone());
+        value
+    }
+}
+
+/// Dev builds report all sorts of versions, anything outside of what a release could plausibly
+/// be is lumped together
+fn version_label(version: Option<&Version>) -> String {
+    match version {
+        None => "unknown".to_string(),
+        Some(v) if v.major > 9 || v.minor > 99 || v.build > 999 => OTHER.to_string(),
+        Some(v) => format!("{}.{}.{}", v.major, v.minor, v.build),
+    }
+}
+
+fn game_label(game: &str) -> String {
+    let game = game.trim();
+    if game.is_empty() {
+        // Trackers and text clients
+        return "none".to_string();
+    }
+    if game.chars().count() > MAX_GAME_LENGTH || game.chars().any(char::is_control) {
+        return OTHER.to_string();
+    }
+    game.to_string()
+}
+
+fn fingerprint_with(
+    games: &LabelGuard,
+    versions: &LabelGuard,
+    game: &str,
+    software: &ClientSoftware,
+    tags: &[String],
+) -> Fingerprint {
+    let mut tags: Vec<String> = tags
+        .iter()
+        .filter(|tag| NOTABLE_TAGS.contains(&tag.as_str()))
+        .cloned()
+        .collect();
+    tags.sort_unstable();
+    tags.dedup();
+
+    let game = game_label(game);
+    let version = version_label(software.version.as_ref());
+    Fingerprint {
+        game: if game == OTHER {
+            game
+        } else {
+            games.label(game)
+        },
+        version: if version == OTHER {
+            version
+        } else {
+            versions.label(version)
+        },
+        tags,
+    }
+}
+
+pub fn fingerprint(game: &str, software: &ClientSoftware, tags: &[String]) -> Fingerprint {
+    fingerprint_with(&GAME_LABELS, &VERSION_LABELS, game, software, tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn software(major: u32, minor: u32, build: u32) -> ClientSoftware {
+        ClientSoftware {
+            uuid: "uuid".to_string(),
+            version: Some(Version {
+                major,
+                minor,
+                build,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let (games, versions) = (LabelGuard::new(10), LabelGuard::new(10));
+        let tags = vec![
+            "DeathLink".to_string(),
+            "AP".to_string(),
+            "CustomTag".to_string(),
+            "AP".to_string(),
+        ];
+        assert_eq!(
+            fingerprint_with(
+                &games,
+                &versions,
+                " A Link to the Past ",
+                &software(0, 6, 1),
+                &tags
+            ),
+            Fingerprint {
+                game: "A Link to the Past".to_string(),
+                version: "0.6.1".to_string(),
+                tags: vec!["AP".to_string(), "DeathLink".to_string()],
+            }
+        );
+
+        let tracker = fingerprint_with(&games, &versions, "", &ClientSoftware::default(), &[]);
+        assert_eq!(tracker.game, "none");
+        assert_eq!(tracker.version, "unknown");
+    }
+
+    #[test]
+    fn test_odd_values_are_other() {
+        let (games, versions) = (LabelGuard::new(10), LabelGuard::new(10));
+        let long_game = "a".repeat(MAX_GAME_LENGTH + 1);
+        let fingerprint =
+            fingerprint_with(&games, &versions, &long_game, &software(0, 5, 99999), &[]);
+        assert_eq!(fingerprint.game, OTHER);
+        assert_eq!(fingerprint.version, OTHER);
+        assert_eq!(
+            fingerprint_with(&games, &versions, "Ga\nme", &software(0, 6, 0), &[]).game,
+            OTHER
+        );
+    }
+
+    #[test]
+    fn test_distinct_values_are_capped() {
+        let (games, versions) = (LabelGuard::new(10), LabelGuard::new(2));
+        let version = |build| {
+            fingerprint_with(&games, &versions, "Game", &software(0, 6, build), &[]).version
+        };
+        assert_eq!(version(1), "0.6.1");
+        assert_eq!(version(2), "0.6.2");
+        assert_eq!(version(3), OTHER);
+        // Values seen before the cap was reached keep their label
+        assert_eq!(version(1), "0.6.1");
+    }
+}
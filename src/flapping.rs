@@ -0,0 +1,204 @@
+use aprs_proto::primitives::SlotId;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// A slot is flapping once it reconnected more than `threshold` times within `window`. Logging
+/// in within `window` of one of the slot's connections dropping counts as a reconnect.
+#[derive(Clone, Copy, Debug)]
+pub struct FlapLimits {
+    pub window: Duration,
+    pub threshold: usize,
+    /// Whether flapping players are told about it, once per window
+    pub notify: bool,
+}
+
+impl Default for FlapLimits {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10 * 60),
+            threshold: 5,
+            notify: false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct SlotFlaps {
+    last_disconnect: Option<Instant>,
+    reconnects: VecDeque<Instant>,
+    notified_at: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Login {
+    pub reconnect: bool,
+    /// The slot just started flapping, or is still flapping a window after it was last told
+    pub notify: bool,
+}
+
+/// Reconnects per slot, only kept in memory
+pub struct FlapTracker {
+    limits: FlapLimits,
+    slots: HashMap<SlotId, SlotFlaps>,
+}
+
+impl FlapTracker {
+    pub fn new(limits: FlapLimits) -> Self {
+        Self {
+            limits,
+            slots: HashMap::new(),
+        }
+    }
+
+    fn within_window(&self, at: Instant, now: Instant) -> bool {
+        now.saturating_duration_since(at) <= self.limits.window
+    }
+
+    fn recent_reconnects(&self, flaps: &SlotFlaps, now: Instant) -> usize {
+        flaps
+            .reconnects
+            .iter()
+            .filter(|at| self.within_window(**at, now))
+            .count()
+    }
+
+    pub fn disconnected(&mut self, slot: SlotId, now: Instant) {
+        self.slots.entry(slot).or_default().last_disconnect = Some(now);
+    }
+
+    pub fn logged_in(&mut self, slot: SlotId, now: Instant) -> Login {
+        let window = self.limits.window;
+        let flaps = self.slots.entry(slot).or_default();
+        while flaps
+            .reconnects
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) > window)
+        {
+            flaps.reconnects.pop_front();
+        }
+
+        // Taken so a login without a disconnect in between, like an upstream reconnect, isn't
+        // counted again
+        let reconnect = flaps
+            .last_disconnect
+            .take()
+            .is_some_and(|at| now.saturating_duration_since(at) <= window);
+        if !reconnect {
+            return Login {
+                reconnect,
+                notify: false,
+            };
+        }
+        flaps.reconnects.push_back(now);
+
+        let notify = self.limits.notify
+            && flaps.reconnects.len() > self.limits.threshold
+            && !flaps
+                .notified_at
+                .is_some_and(|at| now.saturating_duration_since(at) <= window);
+        if notify {
+            flaps.notified_at = Some(now);
+        }
+        Login { reconnect, notify }
+    }
+
+    pub fn is_flapping(&self, slot: SlotId, now: Instant) -> bool {
+        self.slots
+            .get(&slot)
+            .is_some_and(|flaps| self.recent_reconnects(flaps, now) > self.limits.threshold)
+    }
+
+    pub fn flapping(&self, now: Instant) -> HashSet<SlotId> {
+        self.slots
+            .keys()
+            .copied()
+            .filter(|slot| self.is_flapping(*slot, now))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLOT: SlotId = SlotId(1);
+
+    fn tracker(notify: bool) -> FlapTracker {
+        FlapTracker::new(FlapLimits {
+            window: Duration::from_secs(60),
+            threshold: 3,
+            notify,
+        })
+    }
+
+    /// Drops and logs back in `cycles` times, a second apart
+    fn flap(tracker: &mut FlapTracker, start: Instant, cycles: u64) -> Vec<Login> {
+        (0..cycles)
+            .map(|i| {
+                let at = start + Duration::from_secs(i);
+                tracker.disconnected(SLOT, at);
+                tracker.logged_in(SLOT, at)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rapid_reconnects_flag_the_slot_until_the_window_passes() {
+        let mut tracker = tracker(false);
+        let start = Instant::now();
+        assert!(!tracker.logged_in(SLOT, start).reconnect);
+
+        flap(&mut tracker, start, 3);
+        assert!(!tracker.is_flapping(SLOT, start + Duration::from_secs(3)));
+        flap(&mut tracker, start + Duration::from_secs(3), 1);
+        assert!(tracker.is_flapping(SLOT, start + Duration::from_secs(4)));
+        assert_eq!(
+            tracker.flapping(start + Duration::from_secs(4)),
+            HashSet::from([SLOT])
+        );
+        assert!(!tracker.is_flapping(SlotId(2), start + Duration::from_secs(4)));
+
+        // The oldest reconnects fall out of the window one by one
+        assert!(!tracker.is_flapping(SLOT, start + Duration::from_secs(61)));
+        assert!(
+            tracker
+                .flapping(start + Duration::from_secs(120))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_only_logins_soon_after_a_drop_are_reconnects() {
+        let mut tracker = tracker(false);
+        let start = Instant::now();
+        tracker.disconnected(SLOT, start);
+        assert!(
+            !tracker
+                .logged_in(SLOT, start + Duration::from_secs(61))
+                .reconnect
+        );
+
+        tracker.disconnected(SLOT, start + Duration::from_secs(70));
+        let later = start + Duration::from_secs(75);
+        assert!(tracker.logged_in(SLOT, later).reconnect);
+        // Logged in again without dropping in between
+        assert!(!tracker.logged_in(SLOT, later).reconnect);
+    }
+
+    #[test]
+    fn test_players_are_notified_once_per_window() {
+        let start = Instant::now();
+        let mut quiet = tracker(false);
+        assert!(flap(&mut quiet, start, 6).iter().all(|login| !login.notify));
+
+        let mut tracker = tracker(true);
+        let notified: Vec<bool> = flap(&mut tracker, start, 6)
+            .iter()
+            .map(|login| login.notify)
+            .collect();
+        assert_eq!(notified, vec![false, false, false, true, false, false]);
+
+        let notified = flap(&mut tracker, start + Duration::from_secs(70), 4);
+        assert!(notified[3].notify);
+    }
+}
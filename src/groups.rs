@@ -0,0 +1,131 @@
+use aprs_proto::primitives::SlotId;
+use std::collections::{HashMap, HashSet};
+
+use crate::preferences::{self, PreferenceMap};
+use crate::proto::{Connected, SlotInfo};
+
+/// Item-link groups of the room, group slot to member slots. Groups are slots of their own, so a
+/// decision about a member has to be carried over to anything addressed to its groups.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SlotGroups {
+    members: HashMap<SlotId, Vec<SlotId>>,
+}
+
+impl SlotGroups {
+    pub fn from_slot_info(slot_info: &HashMap<String, SlotInfo>) -> Self {
+        let members = slot_info
+            .iter()
+            .filter_map(|(slot, info)| {
+                let slot = SlotId(slot.parse().ok()?);
+                let members = info.group_members.as_ref()?;
+                if members.is_empty() {
+                    return None;
+                }
+                Some((slot, members.iter().map(|m| SlotId(*m as i64)).collect()))
+            })
+            .collect();
+        Self { members }
+    }
+
+    pub fn from_connected(connected: &Connected) -> Self {
+        Self::from_slot_info(&connected.slot_info)
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_group(&self, slot: &SlotId) -> bool {
+        self.members.contains_key(slot)
+    }
+
+    /// Whether `slot`, or any member if it's a group, satisfies `predicate`
+    pub fn any(&self, slot: &SlotId, predicate: impl Fn(&SlotId) -> bool) -> bool {
+        predicate(slot)
+            || self
+                .members
+                .get(slot)
+                .is_some_and(|members| members.iter().any(&predicate))
+    }
+
+    /// Whether deathlinks to `slot` are blocked by an exclusion or an opt-out of the slot
+    /// or, for a group, of any of its members
+    pub fn deathlink_blocked(
+        &self,
+        slot: &SlotId,
+        exclusions: &HashSet<SlotId>,
+        preferences: &PreferenceMap,
+    ) -> bool {
+        self.any(slot, |slot| {
+            exclusions.contains(slot) || preferences::opts_out_of_deathlink(preferences, slot)
+        })
+    }
+
+    pub fn is_muted(&self, slot: &SlotId, preferences: &PreferenceMap) -> bool {
+        self.any(slot, |slot| preferences::is_muted(preferences, slot))
+    }
+
+    /// Whether `slot` is `group` itself or one of its members
+    pub fn covers(&self, group: &SlotId, slot: &SlotId) -> bool {
+        group == slot
+            || self
+                .members
+                .get(group)
+                .is_some_and(|members| members.contains(slot))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Slots 1 and 2 share the item-link group 3, slot 4 plays alone
+    pub(crate) fn fixture_slot_info() -> HashMap<String, SlotInfo> {
+        let slot = |name: &str, type_, group_members| SlotInfo {
+            name: name.to_string(),
+            game: "Game".to_string(),
+            type_,
+            group_members,
+        };
+        HashMap::from([
+            ("1".to_string(), slot("Alice", 1, None)),
+            ("2".to_string(), slot("Bob", 1, None)),
+            ("3".to_string(), slot("Link", 2, Some(vec![1, 2]))),
+            ("4".to_string(), slot("Carol", 1, Some(vec![]))),
+        ])
+    }
+
+    pub(crate) fn fixture() -> SlotGroups {
+        SlotGroups::from_slot_info(&fixture_slot_info())
+    }
+
+    #[test]
+    fn test_from_slot_info() {
+        let groups = fixture();
+        assert_eq!(groups.len(), 1);
+        assert!(groups.is_group(&SlotId(3)));
+        assert!(!groups.is_group(&SlotId(4)));
+    }
+
+    #[test]
+    fn test_member_decisions_apply_to_the_group() {
+        let groups = fixture();
+        let excluded = HashSet::from([SlotId(2)]);
+        let excluded = |slot: &SlotId| excluded.contains(slot);
+
+        assert!(groups.any(&SlotId(2), excluded));
+        assert!(groups.any(&SlotId(3), excluded));
+        // Sharing a group doesn't make the other member excluded
+        assert!(!groups.any(&SlotId(1), excluded));
+        assert!(!groups.any(&SlotId(4), excluded));
+    }
+
+    #[test]
+    fn test_covers() {
+        let groups = fixture();
+        assert!(groups.covers(&SlotId(3), &SlotId(3)));
+        assert!(groups.covers(&SlotId(3), &SlotId(1)));
+        assert!(!groups.covers(&SlotId(3), &SlotId(4)));
+        assert!(!groups.covers(&SlotId(1), &SlotId(3)));
+    }
+}
@@ -0,0 +1,137 @@
+use aprs_proto::primitives::SlotId;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::groups::SlotGroups;
+use crate::proto::{Hint, UpdateHint};
+
+const HINTS_KEY_PREFIX: &str = "_read_hints_";
+
+/// Receiving player of the hints a connection was sent, by finding player and location. An
+/// UpdateHint only names the location, this is how the proxy tells whose item it's about.
+#[derive(Default, Debug)]
+pub struct HintReceivers(HashMap<(SlotId, i64), SlotId>);
+
+impl HintReceivers {
+    fn learn_list(&mut self, hints: &Value) {
+        let Ok(hints) = Vec::<Hint>::deserialize(hints) else {
+            return;
+        };
+        for hint in hints {
+            self.0
+                .insert((hint.finding_player, hint.location), hint.receiving_player);
+        }
+    }
+
+    /// Learns the hints in the hint keys of a Retrieved or SetReply, anything else is ignored
+    pub fn learn(&mut self, cmd: &Value) {
+        match cmd.get("cmd").and_then(Value::as_str) {
+            Some("Retrieved") => {
+                let Some(keys) = cmd.get("keys").and_then(Value::as_object) else {
+                    return;
+                };
+                for (key, hints) in keys {
+                    if key.starts_with(HINTS_KEY_PREFIX) {
+                        self.learn_list(hints);
+                    }
+                }
+            }
+            Some("SetReply") => {
+                let key = cmd.get("key").and_then(Value::as_str).unwrap_or_default();
+                if key.starts_with(HINTS_KEY_PREFIX)
+                    && let Some(hints) = cmd.get("value")
+                {
+                    self.learn_list(hints);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether `slot` may change the hint `update` is about: only its receiving player can, or
+    /// a member of the receiving group for items sent to an item link. Updates for hints the
+    /// connection was never sent can't be checked and aren't allowed either.
+    pub fn may_update(&self, update: &UpdateHint, slot: &SlotId, slot_groups: &SlotGroups) -> bool {
+        self.0
+            .get(&(update.player, update.location))
+            .is_some_and(|receiving| slot_groups.covers(receiving, slot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups;
+    use serde_json::json;
+
+    fn hint(receiving: i64, finding: i64, location: i64) -> Value {
+        json!({
+            "receiving_player": receiving,
+            "finding_player": finding,
+            "location": location,
+            "item": 1,
+            "found": false,
+            "entrance": "",
+            "item_flags": 0,
+            "status": 0,
+            "class": "Hint",
+        })
+    }
+
+    fn update(player: i64, location: i64) -> UpdateHint {
+        UpdateHint::deserialize(json!({
+            "cmd": "UpdateHint",
+            "player": player,
+            "location": location,
+            "status": 20,
+        }))
+        .unwrap()
+    }
+
+    /// Slot 1's hints: its own item in slot 4's world, slot 2's item in its own world and an
+    /// item for the group of slots 1 and 2
+    fn receivers() -> HintReceivers {
+        let mut receivers = HintReceivers::default();
+        receivers.learn(&json!({
+            "cmd": "Retrieved",
+            "keys": {
+                "_read_hints_0_1": [hint(1, 4, 100), hint(2, 1, 200)],
+                "other": [hint(4, 4, 300)],
+            },
+        }));
+        receivers.learn(&json!({
+            "cmd": "SetReply",
+            "key": "_read_hints_0_1",
+            "value": [hint(3, 4, 400)],
+            "original_value": [],
+        }));
+        receivers
+    }
+
+    #[test]
+    fn test_own_hints_can_be_updated() {
+        let groups = groups::tests::fixture();
+        assert!(receivers().may_update(&update(4, 100), &SlotId(1), &groups));
+    }
+
+    #[test]
+    fn test_group_hints_can_be_updated_by_members() {
+        let groups = groups::tests::fixture();
+        let receivers = receivers();
+        assert!(receivers.may_update(&update(4, 400), &SlotId(1), &groups));
+        assert!(receivers.may_update(&update(4, 400), &SlotId(2), &groups));
+        assert!(!receivers.may_update(&update(4, 400), &SlotId(4), &groups));
+    }
+
+    #[test]
+    fn test_cross_slot_and_unknown_hints_are_blocked() {
+        let groups = groups::tests::fixture();
+        let receivers = receivers();
+        // Found in slot 1's world, but slot 2 receives it
+        assert!(!receivers.may_update(&update(1, 200), &SlotId(1), &groups));
+        assert!(receivers.may_update(&update(1, 200), &SlotId(2), &groups));
+        // Only sent under a key that isn't a hint key
+        assert!(!receivers.may_update(&update(4, 300), &SlotId(4), &groups));
+    }
+}
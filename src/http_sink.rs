@@ -0,0 +1,177 @@
+use anyhow::{Context, Result};
+use reqwest::Url;
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::db_writer::{EventSink, PendingWrite};
+use crate::events::RoomEvent;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One line of a batch. The id lets the collector drop events it already has, batches that
+/// failed partway are sent again as a whole.
+#[derive(Serialize)]
+struct EventLine<'a> {
+    id: Uuid,
+    room_id: &'a str,
+    #[serde(flatten)]
+    event: &'a RoomEvent,
+}
+
+/// Posts events to a remote collector as NDJSON, one request per batch. Failed batches are
+/// retried and spilled by the writer like failed database writes.
+pub struct HttpSink {
+    client: reqwest::Client,
+    url: Url,
+    /// Sent as a bearer token
+    token: Option<String>,
+    room_id: String,
+}
+
+impl HttpSink {
+    pub fn new(url: Url, token: Option<String>, room_id: String) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("Failed to build the event sink HTTP client")?;
+        Ok(Self {
+            client,
+            url,
+            token,
+            room_id,
+        })
+    }
+
+    fn ndjson(&self, batch: &[PendingWrite]) -> Result<String> {
+        let mut body = String::new();
+        for write in batch {
+            let line = EventLine {
+                id: write.id,
+                room_id: &self.room_id,
+                event: &write.event,
+            };
+            body.push_str(&serde_json::to_string(&line)?);
+            body.push('\n');
+        }
+        Ok(body)
+    }
+}
+
+impl EventSink for HttpSink {
+    const NAME: &'static str = "http";
+
+    async fn store(&mut self, write: &PendingWrite) -> Result<()> {
+        self.store_batch(std::slice::from_ref(write)).await
+    }
+
+    async fn store_batch(&mut self, batch: &[PendingWrite]) -> Result<()> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "application/x-ndjson")
+            .body(self.ndjson(batch)?);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request
+            .send()
+            .await
+            .context("Failed to reach the event collector")?;
+        if !response.status().is_success() {
+            anyhow::bail!("Event collector answered HTTP {}", response.status());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_writer::DbWriter;
+    use aprs_proto::primitives::SlotId;
+    use serde_json::Value;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn countdown(slot: i64) -> PendingWrite {
+        PendingWrite::new(RoomEvent::CountdownInit { slot: SlotId(slot) })
+    }
+
+    fn sink(server: &MockServer) -> HttpSink {
+        let url = format!("{}/events", server.uri()).parse().unwrap();
+        HttpSink::new(url, Some("secret".to_string()), "room".to_string()).unwrap()
+    }
+
+    /// Slots of the events in each request the server received
+    async fn received_slots(server: &MockServer) -> Vec<Vec<i64>> {
+        server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| {
+                std::str::from_utf8(&request.body)
+                    .unwrap()
+                    .lines()
+                    .map(|line| {
+                        let line: Value = serde_json::from_str(line).unwrap();
+                        assert_eq!(line["room_id"], "room");
+                        assert_eq!(line["type"], "countdown_init");
+                        line["slot"].as_i64().unwrap()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_posted_as_ndjson() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/events"))
+            .and(header("authorization", "Bearer secret"))
+            .and(header("content-type", "application/x-ndjson"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut sink = sink(&server);
+        sink.store_batch(&[countdown(1), countdown(2)])
+            .await
+            .unwrap();
+        assert_eq!(received_slots(&server).await, vec![vec![1, 2]]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_batches_are_retried_with_what_came_after() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let mut writer = DbWriter::new(sink(&server), 10, None);
+        writer.write(vec![countdown(1), countdown(2)]).await;
+        // Queued behind the failed batch without being sent
+        writer.write(vec![countdown(3)]).await;
+        assert_eq!(received_slots(&server).await, vec![vec![1, 2]]);
+
+        writer.retry().await;
+        assert_eq!(
+            received_slots(&server).await,
+            vec![vec![1, 2], vec![1, 2, 3]]
+        );
+        // Nothing left to send
+        writer.retry().await;
+        assert_eq!(received_slots(&server).await.len(), 2);
+    }
+}
@@ -0,0 +1,390 @@
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Bounds checked before handing a message to serde_json, whose own recursion limit turns a
+/// deeply nested packet into a hard error with no way of telling it apart from garbage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParseLimits {
+    pub max_depth: usize,
+    pub max_size: usize,
+    /// Longest string, keys included, as it's written in the message
+    pub max_string: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LimitExceeded {
+    Depth(usize),
+    Size(usize),
+    String(usize),
+    /// A number too large for an f64, which serde_json can't represent
+    NonFinite,
+}
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitExceeded::Depth(max) => write!(f, "nested deeper than {} levels", max),
+            LimitExceeded::Size(max) => write!(f, "larger than {} bytes", max),
+            LimitExceeded::String(max) => write!(f, "has a string longer than {} bytes", max),
+            LimitExceeded::NonFinite => write!(f, "has a number that isn't finite"),
+        }
+    }
+}
+
+impl LimitExceeded {
+    pub fn label(&self) -> &'static str {
+        match self {
+            LimitExceeded::Depth(_) => "depth",
+            LimitExceeded::Size(_) => "size",
+            LimitExceeded::String(_) => "string",
+            LimitExceeded::NonFinite => "non_finite",
+        }
+    }
+}
+
+impl ParseLimits {
+    pub fn check(&self, text: &str) -> Result<(), LimitExceeded> {
+        if text.len() > self.max_size {
+            return Err(LimitExceeded::Size(self.max_size));
+        }
+
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+        let mut string_start = 0;
+        // Start of the number being scanned
+        let mut number_start = None;
+        for (i, byte) in text.bytes().enumerate() {
+            if in_string {
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+                if i - string_start > self.max_string {
+                    return Err(LimitExceeded::String(self.max_string));
+                }
+                continue;
+            }
+
+            if let Some(start) = number_start {
+                if matches!(byte, b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E') {
+                    continue;
+                }
+                finite_number(&text[start..i])?;
+                number_start = None;
+            }
+
+            match byte {
+                b'"' => {
+                    in_string = true;
+                    string_start = i + 1;
+                }
+                b'[' | b'{' => {
+                    depth += 1;
+                    if depth > self.max_depth {
+                        return Err(LimitExceeded::Depth(self.max_depth));
+                    }
+                }
+                b']' | b'}' => depth = depth.saturating_sub(1),
+                b'0'..=b'9' | b'-' => number_start = Some(i),
+                _ => {}
+            }
+        }
+        match number_start {
+            Some(start) => finite_number(&text[start..]),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Numbers that aren't numbers are left to serde_json to refuse
+fn finite_number(number: &str) -> Result<(), LimitExceeded> {
+    match number.parse::<f64>() {
+        Ok(number) if !number.is_finite() => Err(LimitExceeded::NonFinite),
+        _ => Ok(()),
+    }
+}
+
+/// Largest serialized size of a command, by `cmd`. Commands without a limit are only bound by
+/// the size of the message they came in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommandSizeLimits(HashMap<String, usize>);
+
+impl Default for CommandSizeLimits {
+    fn default() -> Self {
+        Self(HashMap::from([
+            ("Say".to_string(), 4 * 1024),
+            ("Bounce".to_string(), 64 * 1024),
+            ("Set".to_string(), 256 * 1024),
+        ]))
+    }
+}
+
+/// A JSON object like `{"Say": 8192, "LocationScouts": 16384}`, on top of the defaults
+impl std::str::FromStr for CommandSizeLimits {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limits = Self::default();
+        limits
+            .0
+            .extend(serde_json::from_str::<HashMap<String, usize>>(s)?);
+        Ok(limits)
+    }
+}
+
+impl CommandSizeLimits {
+    fn limit(&self, command: &Value) -> Option<usize> {
+        let cmd = command.get("cmd")?.as_str()?;
+        self.0.get(cmd).copied()
+    }
+
+    /// Commands parsed from `text` that are larger than their limit, by index along with their
+    /// size. Sizes are those of the commands in `text`, they're only serialized again when
+    /// `text` can't be split into the same commands.
+    pub fn oversized(&self, text: &str, commands: &[Value]) -> Vec<(usize, usize)> {
+        if !commands.iter().any(|command| self.limit(command).is_some()) {
+            return Vec::new();
+        }
+        let mut lengths = command_lengths(text);
+        if lengths.len() != commands.len() {
+            lengths = commands
+                .iter()
+                .map(|command| serde_json::to_string(command).map_or(0, |json| json.len()))
+                .collect();
+        }
+        commands
+            .iter()
+            .zip(lengths)
+            .enumerate()
+            .filter(|(_, (command, length))| self.limit(command).is_some_and(|max| *length > max))
+            .map(|(index, (_, length))| (index, length))
+            .collect()
+    }
+}
+
+/// Length of each command of a message that parsed fine, either an array of commands or a
+/// single one. Whitespace between commands isn't counted.
+fn command_lengths(text: &str) -> Vec<usize> {
+    let text = text.trim();
+    if !text.starts_with('[') {
+        return vec![text.len()];
+    }
+
+    let mut lengths = Vec::new();
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    // Bounds of the command being scanned
+    let mut start = None;
+    let mut end = 0;
+    for (i, byte) in text.bytes().enumerate() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            end = i + 1;
+            continue;
+        }
+
+        match byte {
+            b' ' | b'\t' | b'\n' | b'\r' => continue,
+            b',' if depth == 1 => {
+                lengths.extend(start.take().map(|start| end - start));
+                continue;
+            }
+            b']' if depth == 1 => {
+                lengths.extend(start.take().map(|start| end - start));
+                depth = 0;
+                continue;
+            }
+            _ => {}
+        }
+        if depth == 1 && start.is_none() {
+            start = Some(i);
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => depth += 1,
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        end = i + 1;
+    }
+    lengths
+}
+
+/// Start of `text` for logs, cut on a char boundary
+pub fn sample(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}... ({} bytes)", &text[..end], text.len()),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: ParseLimits = ParseLimits {
+        max_depth: 4,
+        max_size: 64,
+        max_string: 16,
+    };
+
+    #[test]
+    fn test_depth() {
+        assert_eq!(LIMITS.check(r#"[{"cmd": "Say", "a": [[1]]}]"#), Ok(()));
+        assert_eq!(LIMITS.check("[[[[[1]]]]]"), Err(LimitExceeded::Depth(4)));
+        // Depth is checked while scanning, unbalanced inputs don't get further
+        assert_eq!(LIMITS.check("[[[[[[[[[["), Err(LimitExceeded::Depth(4)));
+    }
+
+    #[test]
+    fn test_brackets_in_strings_are_ignored() {
+        assert_eq!(LIMITS.check(r#"[{"text": "[[[[[[[[{{{{"}]"#), Ok(()));
+        assert_eq!(LIMITS.check(r#"[{"text": "\"[[[[[[[["}]"#), Ok(()));
+        assert_eq!(
+            LIMITS.check(r#"[{"text": "\\"}, [[[[1]]]]]"#),
+            Err(LimitExceeded::Depth(4))
+        );
+    }
+
+    #[test]
+    fn test_size() {
+        let long = format!("[\"{}\"]", "a".repeat(64));
+        assert_eq!(LIMITS.check(&long), Err(LimitExceeded::Size(64)));
+    }
+
+    #[test]
+    fn test_string_length() {
+        let at_limit = format!(r#"[{{"text": "{}"}}]"#, "a".repeat(16));
+        assert_eq!(LIMITS.check(&at_limit), Ok(()));
+        let over_limit = format!(r#"[{{"text": "{}"}}]"#, "a".repeat(17));
+        assert_eq!(LIMITS.check(&over_limit), Err(LimitExceeded::String(16)));
+        let long_key = format!(r#"[{{"{}": 1}}]"#, "k".repeat(17));
+        assert_eq!(LIMITS.check(&long_key), Err(LimitExceeded::String(16)));
+        // Escapes count as they're written
+        let escaped = format!(r#"["{}"]"#, r"\n".repeat(9));
+        assert_eq!(LIMITS.check(&escaped), Err(LimitExceeded::String(16)));
+    }
+
+    #[test]
+    fn test_numbers_have_to_be_finite() {
+        assert_eq!(
+            LIMITS.check("[1e308, -0.5e-400, 98765432109876543210]"),
+            Ok(())
+        );
+        assert_eq!(LIMITS.check("[1e400]"), Err(LimitExceeded::NonFinite));
+        assert_eq!(
+            LIMITS.check(r#"{"a":-1E+400}"#),
+            Err(LimitExceeded::NonFinite)
+        );
+        assert_eq!(LIMITS.check("1e400"), Err(LimitExceeded::NonFinite));
+        assert_eq!(LIMITS.check(r#"["1e400"]"#), Ok(()));
+        // Not a number at all, serde_json refuses it
+        assert_eq!(LIMITS.check("[1-2e]"), Ok(()));
+    }
+
+    #[test]
+    fn test_adversarial_client_messages() {
+        let limits = ParseLimits {
+            max_depth: 32,
+            max_size: 15 * 1024 * 1024,
+            max_string: 64 * 1024,
+        };
+        let nested = format!("{}{}", "[".repeat(1000), "]".repeat(1000));
+        assert_eq!(limits.check(&nested), Err(LimitExceeded::Depth(32)));
+        let huge_string = format!(
+            r#"[{{"cmd":"Say","text":"{}"}}]"#,
+            "a".repeat(10 * 1024 * 1024)
+        );
+        assert_eq!(
+            limits.check(&huge_string),
+            Err(LimitExceeded::String(64 * 1024))
+        );
+        let huge_number = r#"[{"cmd":"LocationChecks","locations":[1e400]}]"#;
+        assert_eq!(limits.check(huge_number), Err(LimitExceeded::NonFinite));
+    }
+
+    #[test]
+    fn test_sample() {
+        assert_eq!(sample("short", 10), "short");
+        assert_eq!(sample("ééééé", 2), "éé... (10 bytes)");
+    }
+
+    fn say(text: &str) -> String {
+        format!(r#"{{"cmd":"Say","text":"{}"}}"#, text)
+    }
+
+    /// A Say exactly `size` bytes long
+    fn say_of_size(size: usize) -> String {
+        say(&"a".repeat(size - say("").len()))
+    }
+
+    fn oversized(limits: &CommandSizeLimits, text: &str) -> Vec<(usize, usize)> {
+        let commands = serde_json::from_str::<Vec<Value>>(text)
+            .unwrap_or_else(|_| vec![serde_json::from_str(text).unwrap()]);
+        limits.oversized(text, &commands)
+    }
+
+    #[test]
+    fn test_command_lengths_follow_the_original_text() {
+        let text = r#" [ {"cmd": "Say", "text": "a, [b] {c}"} ,{"cmd":"Sync"}, {"cmd": "Say", "text": "\"]"}]"#;
+        assert_eq!(
+            command_lengths(text),
+            vec![
+                r#"{"cmd": "Say", "text": "a, [b] {c}"}"#.len(),
+                r#"{"cmd":"Sync"}"#.len(),
+                r#"{"cmd": "Say", "text": "\"]"}"#.len(),
+            ]
+        );
+        assert_eq!(command_lengths(r#" {"cmd":"Sync"} "#), vec![14]);
+        assert_eq!(command_lengths("[]"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_boundary_sizes() {
+        let limits = CommandSizeLimits::default();
+        let at_limit = format!("[{}]", say_of_size(4 * 1024));
+        assert!(oversized(&limits, &at_limit).is_empty());
+        let over_limit = format!("[{}]", say_of_size(4 * 1024 + 1));
+        assert_eq!(oversized(&limits, &over_limit), vec![(0, 4 * 1024 + 1)]);
+        // A single command outside of an array
+        assert_eq!(
+            oversized(&limits, &say_of_size(4 * 1024 + 1)),
+            vec![(0, 4 * 1024 + 1)]
+        );
+    }
+
+    #[test]
+    fn test_mixed_batch() {
+        let limits: CommandSizeLimits = r#"{"Say": 64}"#.parse().unwrap();
+        let sync = r#"{"cmd":"Sync"}"#;
+        let huge_unlimited = format!(r#"{{"cmd":"Get","keys":["{}"]}}"#, "k".repeat(1000));
+        let text = format!(
+            "[{},{},{},{},{}]",
+            say_of_size(64),
+            say_of_size(65),
+            sync,
+            huge_unlimited,
+            say_of_size(200)
+        );
+        assert_eq!(oversized(&limits, &text), vec![(1, 65), (4, 200)]);
+    }
+
+    #[test]
+    fn test_parse_command_size_limits() {
+        let limits: CommandSizeLimits = r#"{"Say": 100, "LocationScouts": 10}"#.parse().unwrap();
+        assert_eq!(limits.0["Say"], 100);
+        assert_eq!(limits.0["LocationScouts"], 10);
+        assert_eq!(limits.0["Bounce"], 64 * 1024);
+        assert!("{\"Say\": -1}".parse::<CommandSizeLimits>().is_err());
+        assert!("[]".parse::<CommandSizeLimits>().is_err());
+    }
+}
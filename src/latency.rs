@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use aprs_proto::primitives::SlotId;
+
+use crate::proto::{NetworkItem, ReceivedItems};
+use crate::registry::ClientId;
+
+/// Checks nothing was heard about for this long are forgotten
+const CHECK_EXPIRY: Duration = Duration::from_secs(60);
+/// Checks of a single connection waiting for their item, later ones aren't timed
+const MAX_OUTSTANDING_CHECKS: usize = 256;
+/// Samples kept for each connection's rolling stats
+const ROLLING_SAMPLES: usize = 50;
+
+/// Where an item came from: the slot that found it and the location it was at
+type Origin = (SlotId, i64);
+
+struct Outstanding {
+    client: ClientId,
+    checked_at: Instant,
+}
+
+/// About what each check waiting for its item holds in the map
+pub const OUTSTANDING_CHECK_BYTES: usize = size_of::<(Origin, Outstanding)>();
+
+#[derive(Default)]
+struct ClientLatency {
+    outstanding: usize,
+    samples: VecDeque<Duration>,
+}
+
+/// Rolling stats of the time from a connection's checks to their items going out
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct LatencyStats {
+    pub samples: usize,
+    pub avg_ms: u64,
+    pub max_ms: u64,
+}
+
+/// Times how long upstream takes to send out the items of checked locations, from the
+/// LocationChecks to the first ReceivedItems or ItemSend that mentions them. A room's checks all
+/// go in one, as the item often goes out on another slot's connection.
+#[derive(Default)]
+pub struct CheckLatency {
+    outstanding: HashMap<Origin, Outstanding>,
+    clients: HashMap<ClientId, ClientLatency>,
+}
+
+impl CheckLatency {
+    /// `client`, logged in as `slot`, checked `locations`
+    pub fn checked(&mut self, client: ClientId, slot: SlotId, locations: &[i64], now: Instant) {
+        self.expire(now);
+        let latency = self.clients.entry(client).or_default();
+        for location in locations {
+            if latency.outstanding >= MAX_OUTSTANDING_CHECKS {
+                break;
+            }
+            // A location checked again keeps its first timestamp
+            if let Entry::Vacant(entry) = self.outstanding.entry((slot, *location)) {
+                entry.insert(Outstanding {
+                    client,
+                    checked_at: now,
+                });
+                latency.outstanding += 1;
+            }
+        }
+    }
+
+    /// Upstream sent out the items found at `origins`. Returns how long each one that was
+    /// waiting took.
+    pub fn sent(
+        &mut self,
+        origins: impl IntoIterator<Item = Origin>,
+        now: Instant,
+    ) -> Vec<Duration> {
+        if self.outstanding.is_empty() {
+            return Vec::new();
+        }
+        let mut elapsed = Vec::new();
+        for origin in origins {
+            let Some(check) = self.outstanding.remove(&origin) else {
+                continue;
+            };
+            let took = now.saturating_duration_since(check.checked_at);
+            if let Some(latency) = self.clients.get_mut(&check.client) {
+                latency.outstanding -= 1;
+                if latency.samples.len() == ROLLING_SAMPLES {
+                    latency.samples.pop_front();
+                }
+                latency.samples.push_back(took);
+            }
+            elapsed.push(took);
+        }
+        elapsed
+    }
+
+    /// The connection went away, its checks aren't waited on anymore
+    pub fn forget(&mut self, client: ClientId) {
+        if self.clients.remove(&client).is_some() {
+            self.outstanding.retain(|_, check| check.client != client);
+        }
+    }
+
+    /// Checks of `client` waiting for their item
+    pub fn outstanding(&self, client: ClientId) -> usize {
+        self.clients
+            .get(&client)
+            .map_or(0, |latency| latency.outstanding)
+    }
+
+    pub fn stats(&self, client: ClientId) -> Option<LatencyStats> {
+        let samples = &self.clients.get(&client)?.samples;
+        let max = samples.iter().max()?;
+        let total: Duration = samples.iter().sum();
+        Some(LatencyStats {
+            samples: samples.len(),
+            avg_ms: (total / samples.len() as u32).as_millis() as u64,
+            max_ms: max.as_millis() as u64,
+        })
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let clients = &mut self.clients;
+        self.outstanding.retain(|_, check| {
+            let fresh = now.saturating_duration_since(check.checked_at) < CHECK_EXPIRY;
+            if !fresh && let Some(latency) = clients.get_mut(&check.client) {
+                latency.outstanding -= 1;
+            }
+            fresh
+        });
+    }
+}
+
+/// Items an upstream message says were sent, by where they were found
+pub fn sent_items(cmd: &Value) -> Vec<Origin> {
+    let origin = |item: &NetworkItem| (item.player, item.location);
+    match cmd.get("cmd").and_then(Value::as_str) {
+        Some("ReceivedItems") => ReceivedItems::deserialize(cmd)
+            .map(|received| received.items.iter().map(origin).collect())
+            .unwrap_or_default(),
+        Some("PrintJSON") if cmd.get("type").and_then(Value::as_str) == Some("ItemSend") => cmd
+            .get("item")
+            .and_then(|item| NetworkItem::deserialize(item).ok())
+            .map(|item| vec![origin(&item)])
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_interleaved_checks_of_several_slots() {
+        let mut latency = CheckLatency::default();
+        let start = Instant::now();
+        latency.checked(1, SlotId(1), &[10, 11], start);
+        latency.checked(2, SlotId(2), &[10], start + SECOND);
+        latency.checked(1, SlotId(1), &[12], start + 2 * SECOND);
+
+        // Slot 2's location 10 isn't slot 1's
+        assert_eq!(
+            latency.sent([(SlotId(2), 10)], start + 3 * SECOND),
+            [2 * SECOND]
+        );
+        assert_eq!(
+            latency.sent([(SlotId(1), 12), (SlotId(1), 10)], start + 4 * SECOND),
+            [2 * SECOND, 4 * SECOND]
+        );
+        // Already accounted for, or never checked through us
+        assert!(
+            latency
+                .sent([(SlotId(1), 10), (SlotId(3), 11)], start + 5 * SECOND)
+                .is_empty()
+        );
+
+        assert_eq!(
+            latency.stats(1),
+            Some(LatencyStats {
+                samples: 2,
+                avg_ms: 3000,
+                max_ms: 4000,
+            })
+        );
+        assert_eq!(latency.stats(2).unwrap().samples, 1);
+        assert_eq!(latency.stats(3), None);
+    }
+
+    #[test]
+    fn test_checks_expire_and_are_capped() {
+        let mut latency = CheckLatency::default();
+        let start = Instant::now();
+        let locations: Vec<i64> = (0..MAX_OUTSTANDING_CHECKS as i64 + 10).collect();
+        latency.checked(1, SlotId(1), &locations, start);
+        assert_eq!(latency.outstanding.len(), MAX_OUTSTANDING_CHECKS);
+
+        // Expired checks make room for new ones
+        latency.checked(1, SlotId(1), &[1000], start + CHECK_EXPIRY);
+        assert_eq!(latency.outstanding.len(), 1);
+        assert!(
+            latency
+                .sent([(SlotId(1), 0)], start + CHECK_EXPIRY)
+                .is_empty()
+        );
+
+        latency.forget(1);
+        assert!(latency.outstanding.is_empty());
+        assert!(
+            latency
+                .sent([(SlotId(1), 1000)], start + CHECK_EXPIRY)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_sent_items() {
+        let item = json!({"item": 5, "location": 10, "player": 2, "flags": 0});
+        assert_eq!(
+            sent_items(&json!({"cmd": "ReceivedItems", "index": 0, "items": [item]})),
+            [(SlotId(2), 10)]
+        );
+        assert_eq!(
+            sent_items(&json!({
+                "cmd": "PrintJSON",
+                "type": "ItemSend",
+                "data": [],
+                "receiving": 1,
+                "item": item,
+            })),
+            [(SlotId(2), 10)]
+        );
+        assert!(sent_items(&json!({"cmd": "PrintJSON", "type": "Chat", "data": []})).is_empty());
+    }
+}
@@ -0,0 +1,676 @@
+use anyhow::{Context, Result, bail};
+use futures_util::{SinkExt, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tungstenite::Message;
+
+use crate::proto::{Bounced, Connected, LocationChecks, Say, VersionWithClass};
+
+const USAGE: &str = "\
+Usage: apx loadtest [options]
+
+  --target <url>         APX to connect to, ws://127.0.0.1:38281 by default
+  --clients <n>          Simulated clients, 10 by default
+  --passwords <file>     JSON object of slot names to passwords, clients log in as its names
+  --names <prefix>       Without --passwords, clients log in as <prefix>1, <prefix>2...
+  --game <game>          Game of the Connect
+  --rate <n>             Commands each client sends per second, 1 by default
+  --duration <seconds>   How long the traffic lasts once logged in, 30 by default
+  --mix <mix>            Share of each command, checks=5,say=1,bounce=4 by default
+  --seed <n>             Seeds what the clients send, runs with the same seed send the same
+  --report <file>        Also writes the report there as JSON";
+
+/// How long a client waits on each step of the login
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long Bounces still in flight are waited for once the traffic stops
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Options of `apx loadtest`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub target: String,
+    pub clients: usize,
+    pub passwords: BTreeMap<String, String>,
+    pub names: String,
+    pub game: String,
+    pub rate: f64,
+    pub duration: Duration,
+    pub mix: Mix,
+    pub seed: u64,
+    pub report: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            target: "ws://127.0.0.1:38281".to_string(),
+            clients: 10,
+            passwords: BTreeMap::new(),
+            names: "Player".to_string(),
+            game: String::new(),
+            rate: 1.0,
+            duration: Duration::from_secs(30),
+            mix: Mix::default(),
+            seed: 0,
+            report: None,
+        }
+    }
+}
+
+impl Options {
+    /// Reads the arguments that follow `loadtest`
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut options = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--help" {
+                bail!("{}", USAGE);
+            }
+            let value = args
+                .next()
+                .with_context(|| format!("{} needs a value\n\n{}", arg, USAGE))?;
+            let invalid = || format!("invalid {} {:?}", arg, value);
+            match arg.as_str() {
+                "--target" => options.target = value,
+                "--clients" => options.clients = value.parse().with_context(invalid)?,
+                "--passwords" => {
+                    let contents = std::fs::read_to_string(&value).with_context(invalid)?;
+                    options.passwords = serde_json::from_str(&contents).with_context(invalid)?;
+                }
+                "--names" => options.names = value,
+                "--game" => options.game = value,
+                "--rate" => {
+                    options.rate = value.parse().with_context(invalid)?;
+                    if !(options.rate > 0.0 && options.rate.is_finite()) {
+                        bail!("{}, it has to be above 0", invalid());
+                    }
+                }
+                "--duration" => {
+                    options.duration =
+                        Duration::try_from_secs_f64(value.parse().with_context(invalid)?)
+                            .with_context(invalid)?;
+                }
+                "--mix" => options.mix = value.parse().with_context(invalid)?,
+                "--seed" => options.seed = value.parse().with_context(invalid)?,
+                "--report" => options.report = Some(value.into()),
+                _ => bail!("unknown option {}\n\n{}", arg, USAGE),
+            }
+        }
+        Ok(options)
+    }
+
+    /// Who the client logs in as, and with which password
+    fn login(&self, client: usize) -> (String, String) {
+        match self
+            .passwords
+            .iter()
+            .nth(client % self.passwords.len().max(1))
+        {
+            Some((name, password)) => (name.clone(), password.clone()),
+            None => (format!("{}{}", self.names, client + 1), String::new()),
+        }
+    }
+}
+
+/// Relative share of each command in the traffic, like `checks=5,say=1,bounce=4`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mix {
+    pub checks: u32,
+    pub says: u32,
+    pub bounces: u32,
+}
+
+impl Default for Mix {
+    fn default() -> Self {
+        Self {
+            checks: 5,
+            says: 1,
+            bounces: 4,
+        }
+    }
+}
+
+impl std::str::FromStr for Mix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut mix = Self {
+            checks: 0,
+            says: 0,
+            bounces: 0,
+        };
+        for share in s.split(',') {
+            let (command, weight) = share
+                .split_once('=')
+                .with_context(|| format!("expected command=weight, got {:?}", share))?;
+            let weight = weight.trim().parse()?;
+            match command.trim() {
+                "checks" => mix.checks = weight,
+                "say" => mix.says = weight,
+                "bounce" => mix.bounces = weight,
+                command => bail!(
+                    "unknown command {:?}, expected checks, say or bounce",
+                    command
+                ),
+            }
+        }
+        if mix.checks + mix.says + mix.bounces == 0 {
+            bail!("the mix doesn't send anything");
+        }
+        Ok(mix)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Traffic {
+    Check,
+    Say,
+    Bounce,
+}
+
+impl Mix {
+    fn pick(&self, rng: &mut StdRng) -> Traffic {
+        let roll = rng.random_range(0..self.checks + self.says + self.bounces);
+        if roll < self.checks {
+            Traffic::Check
+        } else if roll < self.checks + self.says {
+            Traffic::Say
+        } else {
+            Traffic::Bounce
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sent {
+    pub location_checks: usize,
+    pub says: usize,
+    pub bounces: usize,
+}
+
+impl Sent {
+    fn add(&mut self, other: Sent) {
+        self.location_checks += other.location_checks;
+        self.says += other.says;
+        self.bounces += other.bounces;
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Latencies {
+    pub samples: usize,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+impl Latencies {
+    fn of(mut samples: Vec<Duration>) -> Self {
+        samples.sort_unstable();
+        let at = |quantile: f64| match samples.len() {
+            0 => 0.0,
+            len => {
+                let index = ((len - 1) as f64 * quantile).round() as usize;
+                samples[index].as_secs_f64() * 1000.0
+            }
+        };
+        Self {
+            samples: samples.len(),
+            p50_ms: at(0.5),
+            p95_ms: at(0.95),
+            p99_ms: at(0.99),
+            max_ms: at(1.0),
+        }
+    }
+}
+
+/// What `apx loadtest` prints, and writes with `--report`
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub clients: usize,
+    pub logged_in: usize,
+    /// Answered with a ConnectionRefused
+    pub refused: usize,
+    /// Couldn't connect, or lost their connection before the end
+    pub failed: usize,
+    /// Share of the clients that failed
+    pub error_rate: f64,
+    pub sent: Sent,
+    /// From opening the socket to Connected
+    pub handshake: Latencies,
+    /// From sending a Bounce to the sender's slot to getting it back
+    pub round_trip: Latencies,
+    /// Bounces that never came back
+    pub bounces_lost: usize,
+    pub seconds: f64,
+}
+
+impl Report {
+    fn table(&self) -> String {
+        let latencies = |name: &str, latencies: &Latencies| {
+            format!(
+                "{:<12} {:>8} {:>10.2} {:>10.2} {:>10.2} {:>10.2}",
+                name,
+                latencies.samples,
+                latencies.p50_ms,
+                latencies.p95_ms,
+                latencies.p99_ms,
+                latencies.max_ms
+            )
+        };
+        [
+            format!(
+                "{} clients in {:.1}s: {} logged in, {} refused, {} failed ({:.2}% errors)",
+                self.clients,
+                self.seconds,
+                self.logged_in,
+                self.refused,
+                self.failed,
+                self.error_rate * 100.0
+            ),
+            format!(
+                "Sent {} LocationChecks, {} Say, {} Bounce, {} Bounces lost",
+                self.sent.location_checks, self.sent.says, self.sent.bounces, self.bounces_lost
+            ),
+            String::new(),
+            format!(
+                "{:<12} {:>8} {:>10} {:>10} {:>10} {:>10}",
+                "", "samples", "p50 ms", "p95 ms", "p99 ms", "max ms"
+            ),
+            latencies("handshake", &self.handshake),
+            latencies("round trip", &self.round_trip),
+        ]
+        .join("\n")
+    }
+}
+
+/// `apx loadtest`, with the arguments after it
+pub async fn run(args: impl IntoIterator<Item = String>) -> Result<()> {
+    let options = Options::parse(args)?;
+    log::info!(
+        "Load testing {} with {} clients for {:?}",
+        options.target,
+        options.clients,
+        options.duration
+    );
+    let report = load_test(&options).await;
+    println!("{}", report.table());
+    if let Some(path) = &options.report {
+        std::fs::write(path, serde_json::to_string_pretty(&report)?)
+            .with_context(|| format!("Failed to write the report to {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Runs every client at once and sums up how they did
+pub async fn load_test(options: &Options) -> Report {
+    let options = Arc::new(options.clone());
+    let started = Instant::now();
+    let clients: Vec<_> = (0..options.clients)
+        .map(|client| tokio::spawn(simulate(options.clone(), client)))
+        .collect();
+
+    let mut outcomes = Vec::with_capacity(clients.len());
+    for client in clients {
+        outcomes.push(client.await.unwrap_or_else(|e| {
+            log::error!("A simulated client panicked: {:?}", e);
+            Outcome {
+                failed: true,
+                ..Default::default()
+            }
+        }));
+    }
+
+    let mut sent = Sent::default();
+    let mut handshakes = Vec::new();
+    let mut round_trips = Vec::new();
+    for outcome in &mut outcomes {
+        sent.add(outcome.sent);
+        handshakes.extend(outcome.handshake);
+        round_trips.append(&mut outcome.round_trips);
+    }
+    let failed = outcomes.iter().filter(|outcome| outcome.failed).count();
+    Report {
+        clients: options.clients,
+        logged_in: handshakes.len(),
+        refused: outcomes.iter().filter(|outcome| outcome.refused).count(),
+        failed,
+        error_rate: failed as f64 / options.clients.max(1) as f64,
+        sent,
+        handshake: Latencies::of(handshakes),
+        round_trip: Latencies::of(round_trips),
+        bounces_lost: outcomes.iter().map(|outcome| outcome.bounces_lost).sum(),
+        seconds: started.elapsed().as_secs_f64(),
+    }
+}
+
+/// How a single client did
+#[derive(Default)]
+struct Outcome {
+    handshake: Option<Duration>,
+    refused: bool,
+    failed: bool,
+    sent: Sent,
+    round_trips: Vec<Duration>,
+    bounces_lost: usize,
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn simulate(options: Arc<Options>, client: usize) -> Outcome {
+    let mut outcome = Outcome::default();
+    let started = Instant::now();
+    let (name, password) = options.login(client);
+    let connected = match handshake(&options, &name, &password).await {
+        Ok(Ok((ws, connected))) => (ws, connected),
+        Ok(Err(errors)) => {
+            log::info!("Client {} was refused as {}: {:?}", client, name, errors);
+            outcome.refused = true;
+            return outcome;
+        }
+        Err(e) => {
+            log::warn!("Client {} failed to log in as {}: {:?}", client, name, e);
+            outcome.failed = true;
+            return outcome;
+        }
+    };
+    outcome.handshake = Some(started.elapsed());
+    let (mut ws, connected) = connected;
+
+    if let Err(e) = traffic(&options, client, &mut ws, &connected, &mut outcome).await {
+        log::warn!("Client {} lost its connection: {:?}", client, e);
+        outcome.failed = true;
+    }
+    let _ = ws.close(None).await;
+    outcome
+}
+
+/// Goes through RoomInfo, Connect and Connected. The inner error is what a ConnectionRefused
+/// said.
+async fn handshake(
+    options: &Options,
+    name: &str,
+    password: &str,
+) -> Result<Result<(Socket, Connected), Vec<Value>>> {
+    let (mut ws, _) = tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        tokio_tungstenite::connect_async(&options.target),
+    )
+    .await
+    .context("Timed out connecting")??;
+    next_command(&mut ws, "RoomInfo").await?;
+
+    let version = VersionWithClass {
+        major: 0,
+        minor: 6,
+        build: 0,
+        class_name: "Version".to_string(),
+    };
+    let connect = json!({
+        "cmd": "Connect",
+        "password": password,
+        "name": name,
+        "game": options.game,
+        "uuid": format!("apx-loadtest-{}", name),
+        "version": version,
+        "tags": ["AP"],
+        "items_handling": 7,
+    });
+    ws.send(packet(&[connect])).await?;
+
+    let answer = next_command(&mut ws, "Connected").await?;
+    match answer["cmd"].as_str() {
+        Some("Connected") => Ok(Ok((ws, serde_json::from_value(answer)?))),
+        _ => Ok(Err(answer["errors"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default())),
+    }
+}
+
+/// Sends the scripted mix until the duration is over, then waits a little for the Bounces
+/// still in flight
+async fn traffic(
+    options: &Options,
+    client: usize,
+    ws: &mut Socket,
+    connected: &Connected,
+    outcome: &mut Outcome,
+) -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(options.seed.wrapping_add(client as u64));
+    let mut ticks = tokio::time::interval(Duration::from_secs_f64(1.0 / options.rate));
+    let mut locations = connected.missing_locations.iter().cycle();
+    let mut in_flight: HashMap<u64, Instant> = HashMap::new();
+    let mut next_bounce = 0;
+    let end = Instant::now() + options.duration;
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(end) => break,
+            _ = ticks.tick() => {
+                let mut traffic = options.mix.pick(&mut rng);
+                let location = locations.next();
+                if traffic == Traffic::Check && location.is_none() {
+                    traffic = Traffic::Say;
+                }
+                let command = match (traffic, location) {
+                    (Traffic::Check, Some(location)) => {
+                        outcome.sent.location_checks += 1;
+                        serde_json::to_value(LocationChecks {
+                            cmd: "LocationChecks".to_string(),
+                            locations: vec![*location],
+                        })?
+                    }
+                    (Traffic::Bounce, _) => {
+                        outcome.sent.bounces += 1;
+                        next_bounce += 1;
+                        in_flight.insert(next_bounce, Instant::now());
+                        json!({
+                            "cmd": "Bounce",
+                            "slots": [connected.slot],
+                            "data": {"apx_loadtest": {"client": client, "bounce": next_bounce}},
+                        })
+                    }
+                    _ => {
+                        outcome.sent.says += 1;
+                        serde_json::to_value(Say {
+                            cmd: "Say".to_string(),
+                            text: format!("Load test message {}", outcome.sent.says),
+                        })?
+                    }
+                };
+                ws.send(packet(&[command])).await?;
+            }
+            message = ws.next() => {
+                receive(message, client, &mut in_flight, &mut outcome.round_trips)?;
+            }
+        }
+    }
+
+    let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+        while !in_flight.is_empty() {
+            receive(
+                ws.next().await,
+                client,
+                &mut in_flight,
+                &mut outcome.round_trips,
+            )?;
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    outcome.bounces_lost = in_flight.len();
+    drained.unwrap_or(Ok(()))
+}
+
+/// Times the Bounces of `client` that came back. Every client of the slot gets them, those of
+/// other clients are left alone.
+fn receive(
+    message: Option<tungstenite::Result<Message>>,
+    client: usize,
+    in_flight: &mut HashMap<u64, Instant>,
+    round_trips: &mut Vec<Duration>,
+) -> Result<()> {
+    let text = match message {
+        Some(Ok(Message::Text(text))) => text,
+        Some(Ok(Message::Close(frame))) => bail!("Closed by the server: {:?}", frame),
+        Some(Ok(_)) => return Ok(()),
+        Some(Err(e)) => return Err(e.into()),
+        None => bail!("Connection closed"),
+    };
+    let commands: Vec<Value> = serde_json::from_str(&text)?;
+    for command in commands {
+        if command["cmd"] != "Bounced" {
+            continue;
+        }
+        let Ok(bounced) = serde_json::from_value::<Bounced>(command) else {
+            continue;
+        };
+        let ours = &bounced.data["apx_loadtest"];
+        if ours["client"].as_u64() != Some(client as u64) {
+            continue;
+        }
+        if let Some(sent) = ours["bounce"].as_u64().and_then(|id| in_flight.remove(&id)) {
+            round_trips.push(sent.elapsed());
+        }
+    }
+    Ok(())
+}
+
+/// The next command read off `ws` that's `cmd` or a ConnectionRefused, skipping anything else
+async fn next_command(ws: &mut Socket, cmd: &str) -> Result<Value> {
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    loop {
+        let message = tokio::time::timeout_at(deadline, ws.next())
+            .await
+            .with_context(|| format!("Timed out waiting for {}", cmd))?;
+        let Message::Text(text) = message.context("Connection closed")?? else {
+            continue;
+        };
+        let commands: Vec<Value> = serde_json::from_str(&text)?;
+        if let Some(command) = commands
+            .into_iter()
+            .find(|command| command["cmd"] == cmd || command["cmd"] == "ConnectionRefused")
+        {
+            return Ok(command);
+        }
+    }
+}
+
+fn packet(commands: &[Value]) -> Message {
+    Message::Text(serde_json::to_string(commands).unwrap().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_options_parse() {
+        assert_eq!(parse(&[]).unwrap(), Options::default());
+        let options = parse(&[
+            "--clients",
+            "50",
+            "--rate",
+            "2.5",
+            "--duration",
+            "0.5",
+            "--mix",
+            "bounce=1, say=0",
+        ])
+        .unwrap();
+        assert_eq!(options.clients, 50);
+        assert_eq!(options.rate, 2.5);
+        assert_eq!(options.duration, Duration::from_millis(500));
+        assert_eq!(
+            options.mix,
+            Mix {
+                checks: 0,
+                says: 0,
+                bounces: 1
+            }
+        );
+
+        for args in [
+            &["--clients"][..],
+            &["--clients", "many"],
+            &["--rate", "0"],
+            &["--duration", "-1"],
+            &["--mix", "say=0"],
+            &["--mix", "hint=1"],
+            &["--frobnicate", "1"],
+            &["--help"],
+        ] {
+            assert!(parse(args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_clients_log_in_with_the_passwords() {
+        let options = Options {
+            passwords: BTreeMap::from([
+                ("Bob".to_string(), "b".to_string()),
+                ("Alice".to_string(), "a".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let logins: Vec<_> = (0..3).map(|client| options.login(client)).collect();
+        assert_eq!(
+            logins,
+            [
+                ("Alice".to_string(), "a".to_string()),
+                ("Bob".to_string(), "b".to_string()),
+                ("Alice".to_string(), "a".to_string()),
+            ]
+        );
+        assert_eq!(
+            Options::default().login(1),
+            ("Player2".to_string(), String::new())
+        );
+    }
+
+    #[test]
+    fn test_the_mix_is_reproducible() {
+        let picks = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..50)
+                .map(|_| Mix::default().pick(&mut rng))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(picks(7), picks(7));
+        assert!(picks(7).contains(&Traffic::Bounce));
+        let only_says = Mix {
+            checks: 0,
+            says: 1,
+            bounces: 0,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!((0..20).all(|_| only_says.pick(&mut rng) == Traffic::Say));
+    }
+
+    #[test]
+    fn test_latencies() {
+        let samples = (1..=100).map(Duration::from_millis).collect();
+        let latencies = Latencies::of(samples);
+        assert_eq!(latencies.samples, 100);
+        assert_eq!(latencies.p50_ms, 51.0);
+        assert_eq!(latencies.p99_ms, 99.0);
+        assert_eq!(latencies.max_ms, 100.0);
+        assert_eq!(Latencies::of(Vec::new()), Latencies::default());
+    }
+}
@@ -1,49 +1,825 @@
-use anyhow::{Result, bail};
+use anyhow::{Result, anyhow, bail};
 use aprs_proto::primitives::SlotId;
-use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
 
 use crate::config::Config;
+use crate::db::DieselPool;
+use crate::diagnostics;
+use crate::events::{EventBus, RoomEvent};
+use crate::metrics;
+use crate::preferences::{self, PreferenceMap};
 use crate::proto::SlotPasswordInfo;
 
-pub async fn refresh_login_info(config: &Config) -> Result<HashMap<SlotId, String>> {
-    let url = config
-        .lobby_root_url
-        .join(&format!("/api/room/{}/slots_passwords", config.room_id))?;
+/// Wait after the first failure in a row, doubled with each one after it
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(300);
+/// Wait between the attempts at startup, doubled with each one
+const STARTUP_RETRY_BASE: Duration = Duration::from_secs(1);
+const STARTUP_RETRY_MAX: Duration = Duration::from_secs(30);
 
-    log::info!("Fetching slot passwords from {}", url);
+#[derive(Clone)]
+pub struct LoginInfo {
+    pub passwords: HashMap<SlotId, String>,
+    pub names: HashMap<SlotId, String>,
+}
 
-    let client = reqwest::Client::new();
-    let response = client
-        .get(url)
-        .header("X-Api-Key", &config.lobby_api_key)
-        .send()
-        .await?;
+/// A slot whose password was added, changed or removed by a refresh. Never carries the password.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RefreshedSlot {
+    pub slot: SlotId,
+    pub name: String,
+}
 
-    if !response.status().is_success() {
-        bail!("Failed to fetch slot passwords: HTTP {}", response.status());
+impl LoginInfo {
+    /// Slots whose password differs from `old_passwords`, in slot order. Slots that are gone
+    /// are named after `old_names`, as the lobby doesn't know them anymore.
+    pub fn changed_slots(
+        &self,
+        old_passwords: &HashMap<SlotId, String>,
+        old_names: &HashMap<SlotId, String>,
+    ) -> Vec<RefreshedSlot> {
+        let slots = old_passwords
+            .keys()
+            .chain(self.passwords.keys())
+            .map(|slot| slot.0)
+            .collect::<BTreeSet<_>>();
+        slots
+            .into_iter()
+            .map(SlotId)
+            .filter(|slot| old_passwords.get(slot) != self.passwords.get(slot))
+            .map(|slot| RefreshedSlot {
+                name: self
+                    .names
+                    .get(&slot)
+                    .or_else(|| old_names.get(&slot))
+                    .cloned()
+                    .unwrap_or_default(),
+                slot,
+            })
+            .collect()
     }
 
-    let slots: Vec<SlotPasswordInfo> = response.json().await?;
+    /// The event published once this replaced `old_passwords`
+    pub fn refreshed_event(
+        &self,
+        old_passwords: &HashMap<SlotId, String>,
+        old_names: &HashMap<SlotId, String>,
+    ) -> RoomEvent {
+        RoomEvent::PasswordsRefreshed {
+            changed_slots: self.changed_slots(old_passwords, old_names),
+            total: self.passwords.len(),
+        }
+    }
+}
 
-    let mut password_map = HashMap::new();
-    for slot_info in slots {
-        let password = slot_info.password.unwrap_or_default();
-        if password.is_empty() {
-            log::debug!(
-                "Slot {} ({}) has no password",
-                slot_info.slot_number,
-                slot_info.player_name
+/// Why the slots a refresh returned look like a lobby bug rather than the room changing
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum SuspiciousRefresh {
+    NoSlots,
+    /// Fewer than PASSWORD_MIN_SLOTS
+    TooFewSlots {
+        slots: usize,
+        minimum: usize,
+    },
+    /// Less than half of the slots loaded before
+    Shrunk {
+        slots: usize,
+        previous: usize,
+    },
+}
+
+impl SuspiciousRefresh {
+    /// `previous` is the slot count of the map the refresh would replace, `None` at startup
+    pub fn check(slots: usize, previous: Option<usize>, minimum: usize) -> Option<Self> {
+        if slots == 0 {
+            return Some(SuspiciousRefresh::NoSlots);
+        }
+        if slots < minimum {
+            return Some(SuspiciousRefresh::TooFewSlots { slots, minimum });
+        }
+        match previous {
+            Some(previous) if slots * 2 < previous => {
+                Some(SuspiciousRefresh::Shrunk { slots, previous })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SuspiciousRefresh::NoSlots => "no_slots",
+            SuspiciousRefresh::TooFewSlots { .. } => "too_few_slots",
+            SuspiciousRefresh::Shrunk { .. } => "shrunk",
+        }
+    }
+}
+
+impl std::fmt::Display for SuspiciousRefresh {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SuspiciousRefresh::NoSlots => write!(f, "the lobby returned no slots"),
+            SuspiciousRefresh::TooFewSlots { slots, minimum } => write!(
+                f,
+                "the lobby returned {} slots, fewer than PASSWORD_MIN_SLOTS ({})",
+                slots, minimum
+            ),
+            SuspiciousRefresh::Shrunk { slots, previous } => write!(
+                f,
+                "the lobby returned {} slots, less than half of the {} loaded before",
+                slots, previous
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SuspiciousRefresh {}
+
+/// The last refresh of a room that was refused, until one goes through
+#[derive(Clone, Debug, Serialize)]
+pub struct RejectedRefresh {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub reason: SuspiciousRefresh,
+}
+
+struct Fetched {
+    at: Instant,
+    /// Errors are kept as their message, to be handed to every caller
+    result: Result<LoginInfo, String>,
+}
+
+#[derive(Default)]
+struct LobbyState {
+    /// Last fetch of each room
+    fetched: HashMap<String, Fetched>,
+    /// Failures in a row, of any room
+    failures: u32,
+    /// The lobby isn't asked again before this, after a failure
+    retry_at: Option<Instant>,
+    last_error: String,
+}
+
+/// Every fetch from the lobby goes through here, so a lobby that fails isn't asked again by
+/// each caller. Fetches are made one at a time: callers that asked while one was in progress
+/// get its result. After a failure, callers get that failure back until the negative cache and
+/// the backoff are both over.
+pub struct LobbyClient {
+    http: reqwest::Client,
+    root_url: Url,
+    api_key: String,
+    negative_cache: Duration,
+    backoff_base: Duration,
+    backoff_max: Duration,
+    startup_retry_base: Duration,
+    /// How long startup keeps retrying a lobby that fails
+    startup_timeout: Duration,
+    state: Mutex<LobbyState>,
+    password_min_slots: usize,
+    /// Kept apart from `state`, which is held during fetches, so health checks never wait
+    rejected: std::sync::Mutex<BTreeMap<String, RejectedRefresh>>,
+}
+
+impl LobbyClient {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            root_url: config.lobby_root_url.clone(),
+            api_key: config.lobby_api_key.clone(),
+            negative_cache: config.lobby_negative_cache,
+            backoff_base: BACKOFF_BASE,
+            backoff_max: BACKOFF_MAX,
+            startup_retry_base: STARTUP_RETRY_BASE,
+            startup_timeout: config.lobby_fetch_timeout,
+            state: Mutex::new(LobbyState::default()),
+            password_min_slots: config.password_min_slots,
+            rejected: Default::default(),
+        }
+    }
+
+    /// Login info of the room to replace a password map of `previous` slots with, `None` when
+    /// there's none yet. Slots that look like a lobby bug are refused unless forced, whoever
+    /// asked keeps the passwords it has.
+    pub async fn refresh(
+        &self,
+        room_id: &str,
+        previous: Option<usize>,
+        force: bool,
+    ) -> Result<Result<LoginInfo, SuspiciousRefresh>> {
+        let login_info = self.login_info(room_id).await?;
+        let suspicious = SuspiciousRefresh::check(
+            login_info.passwords.len(),
+            previous,
+            self.password_min_slots,
+        );
+        match suspicious {
+            Some(reason) if !force => {
+                log::error!(
+                    "Refusing the slot passwords of room {}, the previous ones are kept: {}",
+                    room_id,
+                    reason
+                );
+                metrics::record_password_refresh_rejected(room_id, reason.label());
+                self.rejected.lock().unwrap().insert(
+                    room_id.to_string(),
+                    RejectedRefresh {
+                        at: Utc::now(),
+                        reason,
+                    },
+                );
+                return Ok(Err(reason));
+            }
+            Some(reason) => log::warn!(
+                "Forced to take the slot passwords of room {}: {}",
+                room_id,
+                reason
+            ),
+            None => {}
+        }
+        self.rejected.lock().unwrap().remove(room_id);
+        Ok(Ok(login_info))
+    }
+
+    /// The first login info of the room. A lobby that fails is asked again with an exponential
+    /// backoff until LOBBY_FETCH_TIMEOUT is over, as it may well be restarting along with us.
+    /// Slots that look like a lobby bug are refused right away.
+    pub async fn refresh_at_startup(
+        &self,
+        room_id: &str,
+    ) -> Result<Result<LoginInfo, SuspiciousRefresh>> {
+        let deadline = Instant::now() + self.startup_timeout;
+        let mut wait = self.startup_retry_base;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let error = match self.refresh(room_id, None, false).await {
+                Err(e) => e,
+                refreshed => return refreshed,
+            };
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(
+                    error.context(format!("Gave up on the lobby after {} attempts", attempt))
+                );
+            }
+            // The lobby isn't asked again before its own backoff is over either
+            let retry_in = wait.max(self.retry_in().await).min(deadline - now);
+            log::warn!(
+                "Failed to fetch the login info of room {} (attempt {}), retrying in {:.1?}: {:#}",
+                room_id,
+                attempt,
+                retry_in,
+                error
             );
-        } else {
-            log::debug!(
-                "Loaded password for slot {} ({})",
-                slot_info.slot_number,
-                slot_info.player_name
+            tokio::time::sleep(retry_in).await;
+            wait = (wait * 2).min(STARTUP_RETRY_MAX);
+        }
+    }
+
+    async fn retry_in(&self) -> Duration {
+        let retry_at = self.state.lock().await.retry_at;
+        retry_at.map_or(Duration::ZERO, |retry_at| {
+            retry_at.saturating_duration_since(Instant::now())
+        })
+    }
+
+    /// Rooms whose last refresh was refused, by room
+    pub fn rejected_refreshes(&self) -> BTreeMap<String, RejectedRefresh> {
+        self.rejected.lock().unwrap().clone()
+    }
+
+    pub async fn login_info(&self, room_id: &str) -> Result<LoginInfo> {
+        let asked_at = Instant::now();
+        let mut state = self.state.lock().await;
+        if let Some(fetched) = state.fetched.get(room_id)
+            && fetched.at >= asked_at
+        {
+            metrics::record_lobby_fetch("coalesced");
+            return fetched.result.clone().map_err(|e| anyhow!(e));
+        }
+        let now = Instant::now();
+        if let Some(retry_at) = state.retry_at.filter(|retry_at| now < *retry_at) {
+            metrics::record_lobby_fetch("backed_off");
+            bail!(
+                "Not asking the lobby again for {:.1?} after: {}",
+                retry_at - now,
+                state.last_error
             );
         }
-        password_map.insert(SlotId(slot_info.slot_number as i64), password);
+
+        let result = self.fetch(room_id).await.map_err(|e| format!("{:#}", e));
+        let at = Instant::now();
+        match &result {
+            Ok(_) => {
+                metrics::record_lobby_fetch("ok");
+                state.failures = 0;
+                state.retry_at = None;
+            }
+            Err(e) => {
+                metrics::record_lobby_fetch("error");
+                state.last_error = e.clone();
+                state.failures += 1;
+                let backoff = self.backoff(state.failures);
+                state.retry_at = Some(at + backoff.max(self.negative_cache));
+            }
+        }
+        state.fetched.insert(
+            room_id.to_string(),
+            Fetched {
+                at,
+                result: result.clone(),
+            },
+        );
+        result.map_err(|e| anyhow!(e))
+    }
+
+    /// Wait after `failures` in a row, between half and all of the exponential backoff so
+    /// callers don't all come back at once
+    fn backoff(&self, failures: u32) -> Duration {
+        let exponential = self
+            .backoff_base
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .min(self.backoff_max);
+        exponential.mul_f64(rand::rng().random_range(0.5..=1.0))
+    }
+
+    async fn fetch(&self, room_id: &str) -> Result<LoginInfo> {
+        let url = self
+            .root_url
+            .join(&format!("/api/room/{}/slots_passwords", room_id))?;
+
+        log::info!("Fetching slot passwords from {}", url);
+
+        let response = self
+            .http
+            .get(url)
+            .header("X-Api-Key", &self.api_key)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            bail!("Failed to fetch slot passwords: HTTP {}", response.status());
+        }
+
+        let slots: Vec<SlotPasswordInfo> = response.json().await?;
+
+        let mut password_map = HashMap::new();
+        let mut names = HashMap::new();
+        for slot_info in slots {
+            let password = slot_info.password.unwrap_or_default();
+            if password.is_empty() {
+                log::debug!(
+                    "Slot {} ({}) has no password",
+                    slot_info.slot_number,
+                    slot_info.player_name
+                );
+            } else {
+                log::debug!(
+                    "Loaded password for slot {} ({})",
+                    slot_info.slot_number,
+                    slot_info.player_name
+                );
+            }
+            let slot = SlotId(slot_info.slot_number as i64);
+            password_map.insert(slot, password);
+            names.insert(slot, slot_info.player_name);
+        }
+
+        log::info!("Loaded passwords for {} slots", password_map.len());
+        Ok(LoginInfo {
+            passwords: password_map,
+            names,
+        })
+    }
+}
+
+/// What a password refresh of the primary room replaces
+pub struct RoomLogins<'a> {
+    pub room_id: &'a str,
+    pub passwords: &'a RwLock<HashMap<SlotId, String>>,
+    pub slot_names: &'a RwLock<HashMap<SlotId, String>>,
+    pub preferences: &'a RwLock<PreferenceMap>,
+    pub db_pool: &'a DieselPool,
+    pub events: &'a EventBus,
+}
+
+impl RoomLogins<'_> {
+    /// Swaps in the passwords and names of `login_info`, remaps the slot preferences to the new
+    /// names and publishes which slots changed
+    pub async fn replace(&self, login_info: LoginInfo) {
+        let mut passwords = self.passwords.write().await;
+        let refreshed = login_info.refreshed_event(&passwords, &*self.slot_names.read().await);
+        *passwords = login_info.passwords;
+        drop(passwords);
+
+        match preferences::load_effective(self.db_pool, self.room_id, &login_info.names).await {
+            Ok(effective) => *self.preferences.write().await = effective,
+            Err(e) => log::warn!("Failed to remap slot preferences: {:?}", e),
+        }
+        *self.slot_names.write().await = login_info.names;
+        self.events.publish(refreshed);
+    }
+
+    /// Whether `login_info` has the passwords and names there already are
+    async fn unchanged(&self, login_info: &LoginInfo) -> bool {
+        *self.passwords.read().await == login_info.passwords
+            && *self.slot_names.read().await == login_info.names
+    }
+}
+
+/// Fetches the slot passwords of the primary room again every `interval`. A failed or refused
+/// refresh keeps the passwords there are until the next one, so a lobby outage can't empty
+/// them mid-session.
+pub async fn refresh_periodically(
+    lobby: Arc<LobbyClient>,
+    interval: Duration,
+    room_id: String,
+    passwords: Arc<RwLock<HashMap<SlotId, String>>>,
+    slot_names: Arc<RwLock<HashMap<SlotId, String>>>,
+    preferences: Arc<RwLock<PreferenceMap>>,
+    db_pool: DieselPool,
+    events: EventBus,
+) {
+    let room = RoomLogins {
+        room_id: &room_id,
+        passwords: &passwords,
+        slot_names: &slot_names,
+        preferences: &preferences,
+        db_pool: &db_pool,
+        events: &events,
+    };
+    // The passwords were just fetched at startup
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        diagnostics::heartbeat("password_refresh");
+        refresh_once(&lobby, &room).await;
+    }
+}
+
+async fn refresh_once(lobby: &LobbyClient, room: &RoomLogins<'_>) {
+    let previous = room.passwords.read().await.len();
+    match lobby.refresh(room.room_id, Some(previous), false).await {
+        Ok(Ok(login_info)) if room.unchanged(&login_info).await => {
+            log::debug!("The slot passwords of room {} didn't change", room.room_id);
+        }
+        Ok(Ok(login_info)) => {
+            room.replace(login_info).await;
+            log::info!("Refreshed the slot passwords of room {}", room.room_id);
+        }
+        // Already logged by the lobby client
+        Ok(Err(_)) => {}
+        Err(e) => log::error!(
+            "Failed to refresh the slot passwords of room {}, retrying in the next interval: {:?}",
+            room.room_id,
+            e
+        ),
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::test_config;
+    use diesel_async::AsyncPgConnection;
+    use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn lobby_client(lobby: &MockServer) -> LobbyClient {
+        let mut config = test_config("main");
+        config.lobby_root_url = lobby.uri().parse().unwrap();
+        LobbyClient {
+            backoff_base: Duration::from_millis(100),
+            startup_retry_base: Duration::from_millis(10),
+            ..LobbyClient::new(&config)
+        }
+    }
+
+    fn slots_response() -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!([
+            {"slot_number": 1, "player_name": "Alice", "password": "a"},
+        ]))
+    }
+
+    async fn requests(lobby: &MockServer) -> usize {
+        lobby.received_requests().await.unwrap().len()
+    }
+
+    fn slots(entries: &[(i64, &str)]) -> HashMap<SlotId, String> {
+        entries
+            .iter()
+            .map(|(slot, value)| (SlotId(*slot), value.to_string()))
+            .collect()
+    }
+
+    fn refreshed(slot: i64, name: &str) -> RefreshedSlot {
+        RefreshedSlot {
+            slot: SlotId(slot),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_changed_slots() {
+        let old_passwords = slots(&[(1, "a"), (2, "b"), (3, ""), (4, "d")]);
+        let old_names = slots(&[(1, "Alice"), (2, "Bob"), (3, "Carol"), (4, "Dave")]);
+        let login_info = LoginInfo {
+            passwords: slots(&[(1, "a"), (2, "new"), (3, "c"), (5, "")]),
+            names: slots(&[(1, "Alice"), (2, "Bob"), (3, "Carol"), (5, "Eve")]),
+        };
+        assert_eq!(
+            login_info.changed_slots(&old_passwords, &old_names),
+            vec![
+                refreshed(2, "Bob"),
+                refreshed(3, "Carol"),
+                refreshed(4, "Dave"),
+                refreshed(5, "Eve"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_refreshed_event_never_carries_passwords() {
+        let login_info = LoginInfo {
+            passwords: slots(&[(1, "hunter2")]),
+            names: slots(&[(1, "Alice")]),
+        };
+        let event = login_info.refreshed_event(&HashMap::new(), &HashMap::new());
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "type": "passwords_refreshed",
+                "changed_slots": [{"slot": 1, "name": "Alice"}],
+                "total": 1,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_fetches_are_coalesced() {
+        let lobby = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/room/main/slots_passwords"))
+            .respond_with(slots_response().set_delay(Duration::from_millis(200)))
+            .mount(&lobby)
+            .await;
+        let client = lobby_client(&lobby);
+
+        let (first, second, third) = tokio::join!(
+            client.login_info("main"),
+            client.login_info("main"),
+            client.login_info("main"),
+        );
+        for login_info in [first, second, third] {
+            assert_eq!(login_info.unwrap().names[&SlotId(1)], "Alice");
+        }
+        assert_eq!(requests(&lobby).await, 1);
+
+        // Once it's done, the next caller asks the lobby again
+        client.login_info("main").await.unwrap();
+        assert_eq!(requests(&lobby).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_failures_back_off_until_a_success() {
+        let lobby = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(2)
+            .mount(&lobby)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(slots_response())
+            .mount(&lobby)
+            .await;
+        let client = lobby_client(&lobby);
+        let retry_at = async || client.state.lock().await.retry_at;
 
-    log::info!("Loaded passwords for {} slots", password_map.len());
-    Ok(password_map)
+        for failures in 1..=2 {
+            let error = client.login_info("main").await.unwrap_err();
+            assert!(error.to_string().contains("HTTP 500"), "{}", error);
+            // Callers during the backoff get the failure back without the lobby being asked
+            let error = client.login_info("main").await.unwrap_err();
+            assert!(error.to_string().contains("HTTP 500"), "{}", error);
+            assert_eq!(requests(&lobby).await, failures);
+            assert_eq!(client.state.lock().await.failures, failures as u32);
+            tokio::time::sleep_until(retry_at().await.unwrap().into()).await;
+        }
+
+        client.login_info("main").await.unwrap();
+        assert_eq!(client.state.lock().await.failures, 0);
+        assert_eq!(retry_at().await, None);
+        client.login_info("main").await.unwrap();
+        assert_eq!(requests(&lobby).await, 4);
+    }
+
+    #[tokio::test]
+    async fn test_startup_retries_a_failing_lobby() {
+        let lobby = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(3)
+            .mount(&lobby)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(slots_response())
+            .mount(&lobby)
+            .await;
+        let client = LobbyClient {
+            startup_timeout: Duration::from_secs(10),
+            ..lobby_client(&lobby)
+        };
+
+        let login_info = client.refresh_at_startup("main").await.unwrap().unwrap();
+        assert_eq!(login_info.names[&SlotId(1)], "Alice");
+        assert_eq!(requests(&lobby).await, 4);
+    }
+
+    #[tokio::test]
+    async fn test_startup_gives_up_after_the_timeout() {
+        let lobby = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&lobby)
+            .await;
+        let client = LobbyClient {
+            startup_timeout: Duration::from_millis(300),
+            ..lobby_client(&lobby)
+        };
+
+        let started = Instant::now();
+        let error = client.refresh_at_startup("main").await.unwrap_err();
+        assert!(format!("{:#}", error).contains("HTTP 503"), "{:#}", error);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(requests(&lobby).await > 1);
+
+        // Without a timeout there's a single attempt
+        let client = lobby_client(&lobby);
+        let asked = requests(&lobby).await;
+        client.refresh_at_startup("main").await.unwrap_err();
+        assert_eq!(requests(&lobby).await, asked + 1);
+    }
+
+    #[tokio::test]
+    async fn test_startup_doesnt_retry_suspicious_slots() {
+        let lobby = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([])))
+            .mount(&lobby)
+            .await;
+        let client = LobbyClient {
+            startup_timeout: Duration::from_secs(10),
+            ..lobby_client(&lobby)
+        };
+
+        let refused = client.refresh_at_startup("main").await.unwrap();
+        assert_eq!(refused.unwrap_err(), SuspiciousRefresh::NoSlots);
+        assert_eq!(requests(&lobby).await, 1);
+    }
+
+    #[test]
+    fn test_suspicious_refreshes() {
+        let check = SuspiciousRefresh::check;
+        assert_eq!(check(0, None, 0), Some(SuspiciousRefresh::NoSlots));
+        assert_eq!(check(0, Some(0), 0), Some(SuspiciousRefresh::NoSlots));
+
+        assert_eq!(check(3, None, 0), None);
+        assert_eq!(
+            check(3, None, 4),
+            Some(SuspiciousRefresh::TooFewSlots {
+                slots: 3,
+                minimum: 4
+            })
+        );
+        assert_eq!(check(4, None, 4), None);
+
+        // Half of the previous slots is still taken, less isn't
+        assert_eq!(check(5, Some(10), 0), None);
+        assert_eq!(
+            check(4, Some(10), 0),
+            Some(SuspiciousRefresh::Shrunk {
+                slots: 4,
+                previous: 10
+            })
+        );
+        assert_eq!(check(20, Some(10), 0), None);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_refreshes_are_kept_until_one_goes_through() {
+        let lobby = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([])))
+            .up_to_n_times(2)
+            .mount(&lobby)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(slots_response())
+            .mount(&lobby)
+            .await;
+        let client = lobby_client(&lobby);
+
+        let refused = client.refresh("main", Some(1), false).await.unwrap();
+        assert_eq!(refused.unwrap_err(), SuspiciousRefresh::NoSlots);
+        assert_eq!(
+            client.rejected_refreshes()["main"].reason,
+            SuspiciousRefresh::NoSlots
+        );
+
+        // Forcing takes whatever the lobby returned
+        let forced = client.refresh("main", Some(1), true).await.unwrap();
+        assert!(forced.unwrap().passwords.is_empty());
+        assert!(client.rejected_refreshes().is_empty());
+
+        let login_info = client.refresh("main", None, false).await.unwrap().unwrap();
+        assert_eq!(login_info.names[&SlotId(1)], "Alice");
+    }
+
+    #[tokio::test]
+    async fn test_periodic_refreshes_keep_the_passwords_through_outages() {
+        let lobby = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([])))
+            .up_to_n_times(1)
+            .mount(&lobby)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&lobby)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(slots_response())
+            .mount(&lobby)
+            .await;
+        let client = lobby_client(&lobby);
+
+        let config = test_config("main");
+        let db_pool = DieselPool::builder(AsyncDieselConnectionManager::<AsyncPgConnection>::new(
+            &config.db_url,
+        ))
+        .build()
+        .unwrap();
+        let (passwords, slot_names) = (
+            RwLock::new(slots(&[(1, "old"), (2, "b")])),
+            RwLock::new(slots(&[(1, "Alice"), (2, "Bob")])),
+        );
+        let preferences = RwLock::new(PreferenceMap::new());
+        let events = EventBus::new();
+        let mut published = events.subscribe();
+        let room = RoomLogins {
+            room_id: "main",
+            passwords: &passwords,
+            slot_names: &slot_names,
+            preferences: &preferences,
+            db_pool: &db_pool,
+            events: &events,
+        };
+
+        // The lobby returns nothing, then fails
+        refresh_once(&client, &room).await;
+        assert_eq!(*passwords.read().await, slots(&[(1, "old"), (2, "b")]));
+        refresh_once(&client, &room).await;
+        assert_eq!(*passwords.read().await, slots(&[(1, "old"), (2, "b")]));
+        assert!(published.try_recv().is_err());
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        refresh_once(&client, &room).await;
+        assert_eq!(*passwords.read().await, slots(&[(1, "a")]));
+        assert_eq!(*slot_names.read().await, slots(&[(1, "Alice")]));
+        assert!(matches!(
+            published.try_recv(),
+            Ok(RoomEvent::PasswordsRefreshed { total: 1, .. })
+        ));
+
+        // Nothing changed, nothing is published
+        refresh_once(&client, &room).await;
+        assert!(published.try_recv().is_err());
+        assert_eq!(requests(&lobby).await, 4);
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let config = test_config("main");
+        let client = LobbyClient::new(&config);
+        for failures in 1..=4 {
+            let backoff = client.backoff(failures);
+            let exponential = BACKOFF_BASE * 2u32.pow(failures - 1);
+            assert!(
+                backoff >= exponential / 2 && backoff <= exponential,
+                "{:?}",
+                backoff
+            );
+        }
+        assert!(client.backoff(u32::MAX) <= BACKOFF_MAX);
+    }
 }
@@ -0,0 +1,319 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::metrics;
+use crate::proto::PrintJSON;
+use crate::registry::ClientId;
+
+/// Where a queued connection stands, `None` once it got a seat
+type Position = Option<usize>;
+
+struct Waiter {
+    client: ClientId,
+    name: String,
+    since: Instant,
+    position: watch::Sender<Position>,
+}
+
+#[derive(Default)]
+struct Seats {
+    taken: usize,
+    waiting: VecDeque<Waiter>,
+}
+
+/// Caps the logged in connections of a room. Logins past the cap wait their turn, first come
+/// first served, and get the seat of the next connection that ends.
+pub struct LoginQueue {
+    room_id: String,
+    max: Option<usize>,
+    max_wait: Duration,
+    seats: Mutex<Seats>,
+}
+
+/// Taken by a login, freed when the connection ends
+pub struct Seat(Arc<LoginQueue>);
+
+impl Drop for Seat {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// A login waiting in the queue, it leaves the queue when dropped
+pub struct Ticket {
+    queue: Arc<LoginQueue>,
+    client: ClientId,
+    position: watch::Receiver<Position>,
+    /// Whether the seat it was given was handed over to a `Seat`
+    seated: bool,
+}
+
+pub enum Admission {
+    Seated(Seat),
+    Queued(Ticket),
+}
+
+/// Where a ticket got to when the queue moved
+pub enum Turn {
+    Waiting(usize),
+    Seated(Seat),
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct QueuedLogin {
+    pub position: usize,
+    pub client_id: ClientId,
+    pub name: String,
+    pub waiting_secs: u64,
+}
+
+impl LoginQueue {
+    pub fn new(room_id: &str, max: Option<usize>, max_wait: Duration) -> Self {
+        Self {
+            room_id: room_id.to_string(),
+            max,
+            max_wait,
+            seats: Mutex::new(Seats::default()),
+        }
+    }
+
+    /// How long a login waits before it's refused
+    pub fn max_wait(&self) -> Duration {
+        self.max_wait
+    }
+
+    /// Seats `client`, logging in as `name`, or queues it behind the others
+    pub fn admit(self: &Arc<Self>, client: ClientId, name: &str) -> Admission {
+        let mut seats = self.seats.lock().unwrap();
+        let full = self.max.is_some_and(|max| seats.taken >= max);
+        if !full && seats.waiting.is_empty() {
+            seats.taken += 1;
+            return Admission::Seated(Seat(self.clone()));
+        }
+
+        let (position, receiver) = watch::channel(Some(seats.waiting.len() + 1));
+        seats.waiting.push_back(Waiter {
+            client,
+            name: name.to_string(),
+            since: Instant::now(),
+            position,
+        });
+        metrics::set_login_queue_length(&self.room_id, seats.waiting.len());
+        Admission::Queued(Ticket {
+            queue: self.clone(),
+            client,
+            position: receiver,
+            seated: false,
+        })
+    }
+
+    /// Moves `client` to the front of the queue, returns whether it was waiting
+    pub fn promote(&self, client: ClientId) -> bool {
+        let mut seats = self.seats.lock().unwrap();
+        let Some(index) = seats.waiting.iter().position(|w| w.client == client) else {
+            return false;
+        };
+        let waiter = seats.waiting.remove(index).expect("index is in the queue");
+        seats.waiting.push_front(waiter);
+        self.seat_waiting(&mut seats);
+        true
+    }
+
+    pub fn waiting(&self) -> Vec<QueuedLogin> {
+        let now = Instant::now();
+        self.seats
+            .lock()
+            .unwrap()
+            .waiting
+            .iter()
+            .enumerate()
+            .map(|(index, waiter)| QueuedLogin {
+                position: index + 1,
+                client_id: waiter.client,
+                name: waiter.name.clone(),
+                waiting_secs: now.saturating_duration_since(waiter.since).as_secs(),
+            })
+            .collect()
+    }
+
+    fn release(&self) {
+        let mut seats = self.seats.lock().unwrap();
+        seats.taken -= 1;
+        self.seat_waiting(&mut seats);
+    }
+
+    fn leave(&self, client: ClientId) {
+        let mut seats = self.seats.lock().unwrap();
+        seats.waiting.retain(|waiter| waiter.client != client);
+        self.seat_waiting(&mut seats);
+    }
+
+    /// Hands free seats to the front of the queue and tells the others where they are now
+    fn seat_waiting(&self, seats: &mut Seats) {
+        while self.max.is_none_or(|max| seats.taken < max) {
+            let Some(waiter) = seats.waiting.pop_front() else {
+                break;
+            };
+            seats.taken += 1;
+            waiter.position.send_replace(None);
+        }
+        for (index, waiter) in seats.waiting.iter().enumerate() {
+            waiter.position.send_if_modified(|position| {
+                let moved = *position != Some(index + 1);
+                *position = Some(index + 1);
+                moved
+            });
+        }
+        metrics::set_login_queue_length(&self.room_id, seats.waiting.len());
+    }
+}
+
+impl Ticket {
+    pub fn position(&self) -> usize {
+        self.position.borrow().unwrap_or(0)
+    }
+
+    /// Waits for the queue to move
+    pub async fn moved(&mut self) -> Turn {
+        // The sender is only dropped once this ticket got its seat
+        let _ = self.position.changed().await;
+        match *self.position.borrow_and_update() {
+            Some(position) => Turn::Waiting(position),
+            None => {
+                self.seated = true;
+                Turn::Seated(Seat(self.queue.clone()))
+            }
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if self.seated {
+            return;
+        }
+        match *self.position.borrow() {
+            Some(_) => self.queue.leave(self.client),
+            // Seated in the meantime, nobody is going to take it
+            None => self.queue.release(),
+        }
+    }
+}
+
+/// PrintJSON telling a queued player where they stand
+pub fn position_notice(position: usize) -> Value {
+    let text = format!(
+        "The room is full, you are number {} in the queue. You will be logged in once someone leaves.",
+        position
+    );
+    serde_json::to_value(PrintJSON::with_color(&text, "yellow")).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(max: usize) -> Arc<LoginQueue> {
+        Arc::new(LoginQueue::new("test", Some(max), Duration::from_secs(60)))
+    }
+
+    fn seated(admission: Admission) -> Seat {
+        match admission {
+            Admission::Seated(seat) => seat,
+            Admission::Queued(_) => panic!("expected a seat"),
+        }
+    }
+
+    fn queued(admission: Admission) -> Ticket {
+        match admission {
+            Admission::Queued(ticket) => ticket,
+            Admission::Seated(_) => panic!("expected to be queued"),
+        }
+    }
+
+    async fn moved_to(ticket: &mut Ticket) -> usize {
+        match ticket.moved().await {
+            Turn::Waiting(position) => position,
+            Turn::Seated(_) => panic!("expected to be waiting"),
+        }
+    }
+
+    async fn given_seat(ticket: &mut Ticket) -> Seat {
+        match ticket.moved().await {
+            Turn::Seated(seat) => seat,
+            Turn::Waiting(position) => panic!("expected a seat, at {}", position),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_seats_go_first_come_first_served() {
+        let queue = queue(1);
+        let first = seated(queue.admit(1, "Alice"));
+        let mut second = queued(queue.admit(2, "Bob"));
+        let mut third = queued(queue.admit(3, "Carol"));
+        assert_eq!((second.position(), third.position()), (1, 2));
+
+        drop(first);
+        let second_seat = given_seat(&mut second).await;
+        assert_eq!(moved_to(&mut third).await, 1);
+        assert_eq!(queue.waiting()[0].client_id, 3);
+
+        // Nobody gets in ahead of the queue
+        let mut fourth = queued(queue.admit(4, "Dave"));
+        assert_eq!(fourth.position(), 2);
+        drop(second_seat);
+        let _third_seat = given_seat(&mut third).await;
+        assert_eq!(moved_to(&mut fourth).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_leaving_the_queue_moves_it_up() {
+        let queue = queue(1);
+        let first = seated(queue.admit(1, "Alice"));
+        let second = queued(queue.admit(2, "Bob"));
+        let mut third = queued(queue.admit(3, "Carol"));
+
+        drop(second);
+        assert_eq!(moved_to(&mut third).await, 1);
+        assert_eq!(queue.waiting().len(), 1);
+
+        // A seat given to a ticket that's gone goes to the next one
+        let fourth = queued(queue.admit(4, "Dave"));
+        drop(first);
+        drop(third);
+        assert!(queue.waiting().is_empty());
+        drop(fourth);
+        let _seat = seated(queue.admit(5, "Erin"));
+        assert!(matches!(queue.admit(6, "Frank"), Admission::Queued(_)));
+    }
+
+    #[tokio::test]
+    async fn test_promoted_login_gets_the_next_seat() {
+        let queue = queue(1);
+        let first = seated(queue.admit(1, "Alice"));
+        let mut second = queued(queue.admit(2, "Bob"));
+        let mut third = queued(queue.admit(3, "Carol"));
+
+        assert!(queue.promote(3));
+        assert!(!queue.promote(1));
+        assert_eq!(moved_to(&mut third).await, 1);
+        assert_eq!(moved_to(&mut second).await, 2);
+
+        drop(first);
+        let _seat = given_seat(&mut third).await;
+        assert_eq!(second.position(), 1);
+    }
+
+    #[test]
+    fn test_unlimited_queue_seats_everyone() {
+        let queue = Arc::new(LoginQueue::new("test", None, Duration::ZERO));
+        let _seats: Vec<Seat> = (0..100)
+            .map(|id| seated(queue.admit(id, "Alice")))
+            .collect();
+        assert!(queue.waiting().is_empty());
+    }
+}
@@ -1,38 +1,102 @@
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
 use rocket::config::ShutdownConfig;
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tokio::{
-    net::TcpListener,
+    net::{TcpListener, TcpStream},
     signal,
-    sync::mpsc::{Receiver, channel},
 };
 
+mod admin_ops;
 mod api;
+mod audit;
+mod bandwidth;
+mod bounce_log;
+mod bridge;
+mod budget;
+mod chain;
+mod claims;
 mod config;
+mod console;
+mod coordination;
+mod csv;
 mod db;
+mod db_writer;
+mod deathlink_feedback;
+mod diagnostics;
+mod error;
+mod events;
+mod fingerprint;
+mod flapping;
+mod groups;
+mod hints;
+mod http_sink;
+mod json_limits;
+mod latency;
+mod loadtest;
 mod lobby;
+mod login_queue;
+mod memory;
+mod messages;
 mod metrics;
+mod mirror;
+mod motd;
+mod net;
+mod origin;
+mod outbox;
+mod pace;
+mod password_audit;
+mod permissions;
+mod player_commands;
+mod preferences;
+mod progress;
 mod proto;
 mod proxy;
+mod ready;
 mod registry;
+mod release_pacing;
+mod reload;
+mod resume;
+mod retention;
+mod scheduled_messages;
+mod selftest;
+mod send;
+mod session;
+mod shedding;
+mod signing;
+mod spill;
+mod standby;
+mod stats;
+mod supervisor;
+#[cfg(test)]
+mod tests;
 mod tls;
+mod token;
+mod transforms;
+mod upstream;
 
-use config::{AppState, Config, DeathlinkProbability, Signal};
+use config::{AppState, Config, DeathlinkProbability, Vars};
+use db::batcher::BatchLimits;
+use events::EventBus;
 use futures_util::{SinkExt, StreamExt};
-use lobby::refresh_login_info;
-use proxy::handle_client;
+use proxy::{ProxyContext, RoomRoute, handle_client, utc_timestamp};
 use std::collections::HashMap;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio_tungstenite::tungstenite::Message;
+use upstream::UpstreamServers;
 
 pub struct DataPackageCache {
     full_response: Arc<str>,
     game_fragments: HashMap<String, std::ops::Range<usize>>,
 }
 
+/// How long event writers get to store what they hold once asked to stop
+const WRITER_SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
 const DATAPACKAGE_PREFIX: &str = r#"[{"cmd":"DataPackage","data":{"games":{"#;
 const DATAPACKAGE_SUFFIX: &str = "}}}]";
+/// Ports the proxy listens on, and whether NoText is injected on each
+const PROXY_PORTS: [(u16, bool); 2] = [(36000, false), (36001, true)];
 
 impl DataPackageCache {
     fn from_response(cmd: serde_json::Value) -> anyhow::Result<Self> {
@@ -78,6 +142,26 @@ impl DataPackageCache {
         self.build_response(|name| !exclusions.contains(name))
     }
 
+    /// Names of the `locations` of `game` its datapackage has, the others are left out
+    pub fn location_names(&self, game: &str, locations: &[i64]) -> HashMap<i64, String> {
+        let Some(range) = self.game_fragments.get(game) else {
+            return HashMap::new();
+        };
+        let fragment = format!("{{{}}}", &self.full_response[range.clone()]);
+        let Ok(package) = serde_json::from_str::<serde_json::Value>(&fragment) else {
+            return HashMap::new();
+        };
+        let Some(names) = package[game]["location_name_to_id"].as_object() else {
+            return HashMap::new();
+        };
+        names
+            .iter()
+            .filter_map(|(name, id)| Some((id.as_i64()?, name)))
+            .filter(|(id, _)| locations.contains(id))
+            .map(|(id, name)| (id, name.clone()))
+            .collect()
+    }
+
     fn build_response(&self, filter: impl Fn(&String) -> bool) -> Arc<str> {
         let mut response = String::from(DATAPACKAGE_PREFIX);
         let mut first = true;
@@ -103,18 +187,84 @@ async fn main() -> Result<()> {
 
     env_logger::init();
 
-    let config = Config::from_env()?;
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("loadtest") {
+        return loadtest::run(args).await;
+    }
+
+    let vars = Vars::load()?;
+    let config = Config::from_vars(&vars)?;
 
-    let db_pool = db::init_pool(&config.db_url).await?;
+    let db_pool = db::init_pool(&config.db_url, config.db_partitioned).await?;
 
-    let passwords = match refresh_login_info(&config).await {
-        Ok(info) => Arc::new(RwLock::new(info)),
+    let lobby = Arc::new(lobby::LobbyClient::new(&config));
+    let login_info = match lobby.refresh_at_startup(&config.room_id).await {
+        Ok(Ok(info)) => info,
+        Ok(Err(reason)) => {
+            log::error!("Refusing the slot passwords from the lobby: {}", reason);
+            bail!("Refusing the slot passwords from the lobby");
+        }
         Err(e) => {
             log::error!("Failed to fetch login info: {:?}", e);
             bail!("Failed to fetch login info");
         }
     };
 
+    let preferences = match preferences::load_effective(
+        &db_pool,
+        &config.room_id,
+        &login_info.names,
+    )
+    .await
+    {
+        Ok(preferences) => {
+            log::info!("Loaded preferences for {} slots", preferences.len());
+            Arc::new(RwLock::new(preferences))
+        }
+        Err(e) => {
+            log::warn!(
+                "Failed to load slot preferences from database: {:?}, starting without preferences",
+                e
+            );
+            Arc::new(RwLock::new(HashMap::new()))
+        }
+    };
+    // Everything is new to a fresh start, published once the sinks are there
+    let startup_refresh = login_info.refreshed_event(&HashMap::new(), &HashMap::new());
+    let passwords = Arc::new(RwLock::new(login_info.passwords));
+
+    let proxy_ports = PROXY_PORTS.map(|(port, _)| port);
+    for ap_server in std::iter::once(&config.ap_server).chain(&config.ap_server_fallbacks) {
+        chain::refuse_self_connection(&ap_server.to_string(), &proxy_ports).await?;
+    }
+    for route in &config.room_routes {
+        chain::refuse_self_connection(&route.ap_server.to_string(), &proxy_ports)
+            .await
+            .with_context(|| format!("Refusing route for room {}", route.room_id))?;
+    }
+
+    let selftest = if config.startup_selftest {
+        let report = selftest::run(&config.ap_server.url(), &config.selftest).await;
+        if report.ok {
+            log::info!("Upstream self-test passed");
+        } else {
+            log::error!(
+                "Upstream self-test failed at {:?}: {}",
+                report.failed_step,
+                report.error.as_deref().unwrap_or_default()
+            );
+            if config.selftest_failure == selftest::FailureMode::Abort {
+                bail!("Upstream self-test failed");
+            }
+        }
+        Some(report)
+    } else {
+        None
+    };
+    let selftest = Arc::new(RwLock::new(selftest));
+    let slot_names = Arc::new(RwLock::new(login_info.names));
+    let slot_groups = Arc::new(RwLock::new(groups::SlotGroups::default()));
+
     let deathlink_exclusions = match db::models::get_room_deathlink_exclusions(
         &db_pool,
         &config.room_id,
@@ -161,24 +311,572 @@ async fn main() -> Result<()> {
         db::models::get_deferred_datapackage_games(&db_pool).await?,
     ));
 
-    let upstream_url = format!("ws://{}", config.ap_server);
+    // A motd set through the API, including an explicitly cleared one, overrides MOTD
+    let motd = match db::models::get_room_setting(&db_pool, &config.room_id, motd::MOTD_SETTING_KEY)
+        .await
+    {
+        Ok(Some(value)) => value.as_str().map(str::to_string),
+        Ok(None) => config.motd.clone(),
+        Err(e) => {
+            log::warn!(
+                "Failed to load motd from database: {:?}, falling back to MOTD",
+                e
+            );
+            config.motd.clone()
+        }
+    };
+    let motd = Arc::new(RwLock::new(motd));
+
+    // Slots set through the API override RECORD_BOUNCES
+    let recorded_slots = match db::models::get_room_setting(
+        &db_pool,
+        &config.room_id,
+        bounce_log::RECORD_BOUNCES_SETTING_KEY,
+    )
+    .await
+    {
+        Ok(Some(value)) => match value.as_str().map(str::parse) {
+            Some(Ok(slots)) => slots,
+            _ => {
+                log::warn!(
+                    "Ignoring recorded Bounce slots {} from database, falling back to RECORD_BOUNCES",
+                    value
+                );
+                config.record_bounces.clone()
+            }
+        },
+        Ok(None) => config.record_bounces.clone(),
+        Err(e) => {
+            log::warn!(
+                "Failed to load recorded Bounce slots from database: {:?}, falling back to RECORD_BOUNCES",
+                e
+            );
+            config.record_bounces.clone()
+        }
+    };
+    let bounce_recorder = Arc::new(bounce_log::BounceRecorder::new(
+        recorded_slots,
+        config.bounce_daily_cap,
+    ));
+    let today = chrono::Utc::now().date_naive();
+    match db::models::count_room_bounces_by_slot(
+        &db_pool,
+        &config.room_id,
+        today.and_time(chrono::NaiveTime::MIN).and_utc(),
+    )
+    .await
+    {
+        Ok(recorded) => bounce_recorder.restore(today, recorded),
+        Err(e) => log::warn!(
+            "Failed to count today's recorded Bounces: {:?}, their caps start over",
+            e
+        ),
+    }
+
+    let ready =
+        match db::models::get_room_setting(&db_pool, &config.room_id, ready::READY_SETTING_KEY)
+            .await
+        {
+            Ok(Some(value)) => match ready::from_setting(&value) {
+                Some(slots) => slots,
+                None => {
+                    log::warn!("Ignoring ready slots {} from database", value);
+                    BTreeSet::new()
+                }
+            },
+            Ok(None) => BTreeSet::new(),
+            Err(e) => {
+                log::warn!(
+                    "Failed to load ready slots from database: {:?}, everyone starts not ready",
+                    e
+                );
+                BTreeSet::new()
+            }
+        };
+    let ready = Arc::new(ready::ReadyStates::new(ready));
 
-    let datapackage_cache = fetch_datapackage(&upstream_url).await?;
+    let upstream = Arc::new(UpstreamServers::new(
+        config.ap_server.clone(),
+        config.ap_server_fallbacks.clone(),
+        config.upstream_resolve_interval,
+    ));
+
+    let datapackage_cache = fetch_datapackage(&upstream).await?;
     log::info!(
         "Cached DataPackage at startup ({} bytes, {} games)",
         datapackage_cache.full_response().len(),
         datapackage_cache.game_fragments.len(),
     );
     let datapackage_cache = Arc::new(datapackage_cache);
+
+    let mut rooms = HashMap::new();
+    for route in &config.room_routes {
+        let login_info = lobby
+            .refresh_at_startup(&route.room_id)
+            .await
+            .with_context(|| format!("Failed to fetch login info for room {}", route.room_id))?
+            .with_context(|| format!("Refusing the slot passwords of room {}", route.room_id))?;
+        let route_upstream = Arc::new(UpstreamServers::new(
+            route.ap_server.clone(),
+            Vec::new(),
+            config.upstream_resolve_interval,
+        ));
+        let route_datapackage_cache = fetch_datapackage(&route_upstream)
+            .await
+            .with_context(|| format!("Failed to fetch DataPackage for room {}", route.room_id))?;
+        log::info!(
+            "Routing /room/{} to {} ({} slots)",
+            route.room_id,
+            route_upstream,
+            login_info.passwords.len()
+        );
+        rooms.insert(
+            route.room_id.clone(),
+            RoomRoute {
+                upstream: route_upstream,
+                passwords: Arc::new(RwLock::new(login_info.passwords)),
+                datapackage_cache: Arc::new(route_datapackage_cache),
+                client_registry: Arc::new(registry::ClientRegistry::new(config.flap_limits)),
+                login_queue: Arc::new(login_queue::LoginQueue::new(
+                    &route.room_id,
+                    config.max_logged_in_clients,
+                    config.login_queue_max_wait,
+                )),
+                slot_groups: Default::default(),
+            },
+        );
+    }
+    let rooms = Arc::new(rooms);
     let room_id = config.room_id.clone();
+    let listen_dual_stack = config.listen_dual_stack;
+    let (connection_supervisor, connection_tasks) =
+        supervisor::Supervisor::new(config.max_concurrent_connections);
+    let stale_connection_age = config.stale_connection_age;
+    if let Some(max) = config.max_concurrent_connections {
+        log::info!("Accepting up to {} connections at once", max);
+    }
+    let max_upstream_connections = config.max_upstream_connections;
+    let upstream_queue_wait = config.upstream_queue_wait;
+    let token_key = config.token_secret.as_deref().map(token::TokenKey::new);
+    let audit_key = config.audit_hmac_key.as_deref().map(audit::AuditKey::new);
+    let live = reload::Live::new(reload::LiveSettings::from(&config));
+    let tracker_mirrors = config.mirror_trackers.then(mirror::TrackerMirrors::default);
+    if tracker_mirrors.is_some() {
+        log::info!("Trackers share one upstream connection per slot once logged in");
+    }
+    let session_limits = &config.session_limits;
+    if let Some(max) = session_limits.max_duration {
+        log::info!("Sessions are closed after {:?}", max);
+    }
+    if let Some(close_at) = session_limits.close_at {
+        log::info!("Closing the room at {}", close_at.to_rfc3339());
+    }
+    let chain_mode = config.chain_mode;
+    if chain_mode == chain::ChainMode::Passthrough {
+        log::info!("Logins are handed over to the upstream when it's another APX");
+    }
+    if token_key.is_some() {
+        log::info!("Accepting lobby-issued connection tokens");
+    }
+    if audit_key.is_some() {
+        log::info!("Recording login attempts as HMACs for /api/verify_attempt");
+    }
+    let bridge = match &config.bridge_peer_url {
+        Some(peer) => {
+            let key = config
+                .bridge_shared_secret
+                .as_deref()
+                .map(bridge::BridgeKey::new)
+                .context("BRIDGE_PEER_URL is set but BRIDGE_SHARED_SECRET isn't")?;
+            let (bridge, outgoing) = bridge::Bridge::new(config.room_id.clone());
+            let peer = peer.clone();
+            log::info!("Relaying chat to the peer room at {}", peer);
+            tokio::spawn(async move {
+                if let Err(e) = bridge::run(peer, key, outgoing).await {
+                    log::error!("Chat bridge stopped: {:?}", e);
+                }
+            });
+            Some(bridge)
+        }
+        None => None,
+    };
+    let upstream_room_password = config.upstream_room_password.clone();
+    if upstream_room_password.is_some() {
+        log::info!("Logging in to upstream with UPSTREAM_ROOM_PASSWORD");
+    }
+    if !rooms.is_empty() && (upstream_room_password.is_some() || config.resume_window.is_some()) {
+        log::warn!(
+            "UPSTREAM_ROOM_PASSWORD and RESUME_WINDOW_SECONDS only apply to room {}, not to the rooms of ROOM_ROUTES",
+            config.room_id
+        );
+    }
+    let allowed_origins = config.allowed_origins.clone();
+    if allowed_origins.is_some() {
+        log::info!("Only letting browsers in from ALLOWED_ORIGINS");
+    }
+    if let Some(max) = max_upstream_connections {
+        log::info!("Limiting upstream connections to {}", max);
+    }
+    if let Some(max) = config.max_logged_in_clients {
+        log::info!(
+            "Queueing logins past {} logged in clients for up to {:?}",
+            max,
+            config.login_queue_max_wait
+        );
+    }
+    if let Some(overrides) = &config.override_permissions {
+        log::info!("Overriding room permissions with {:?}", overrides);
+    }
+    if !config.transforms.is_empty() {
+        log::info!(
+            "Transforming packets with {} rules from TRANSFORMS_PATH",
+            config.transforms.len()
+        );
+    }
+
+    // Subscribers are attached before anything can publish so no event is missed
+    let events = EventBus::new();
+    if let Some(spill_dir) = &config.spill_dir {
+        log::info!("Spilling unstored events to {}", spill_dir.display());
+    }
+    // Each sink gets its own retry queue and spill directory, one being down doesn't hold
+    // back the others. Writers are rebuilt after a panic, only what they hadn't spilled yet is
+    // lost.
+    let spill_path = |subdir: Option<&str>| -> Result<Option<std::path::PathBuf>> {
+        let Some(dir) = &config.spill_dir else {
+            return Ok(None);
+        };
+        let dir = match subdir {
+            Some(subdir) => dir.join(subdir),
+            None => dir.clone(),
+        };
+        // Checked up front so a bad directory fails startup rather than every restart
+        spill::SpillDir::open(&dir)?;
+        Ok(Some(dir))
+    };
+    // Writers hold events for `db_batch_delay`, they're told to flush them before exiting
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let batch_limits = BatchLimits {
+        max_delay: config.db_batch_delay,
+        ..BatchLimits::default()
+    };
+    let mut writers = Vec::new();
+    if config.event_sinks.database {
+        let spill_dir = spill_path(None)?;
+        let (mut subscribe, db_pool, room_id) =
+            (events.subscriber(), db_pool.clone(), room_id.clone());
+        let retry_queue = config.db_retry_queue;
+        let shutdown = shutdown_rx.clone();
+        writers.push(diagnostics::supervise("database_writer", move || {
+            let sink = db_writer::DbSink::new(db_pool.clone(), room_id.clone());
+            let writer = db_writer::DbWriter::new(sink, retry_queue, reopen_spill(&spill_dir));
+            db_writer::run(subscribe(), writer, batch_limits, shutdown.clone())
+        }));
+    }
+    if config.event_sinks.http {
+        let url = config
+            .event_sink_url
+            .clone()
+            .context("EVENT_SINKS includes http but EVENT_SINK_URL isn't set")?;
+        log::info!("Sending room events to the collector at {}", url);
+        let spill_dir = spill_path(Some("http"))?;
+        let (mut subscribe, room_id) = (events.subscriber(), room_id.clone());
+        let token = config.event_sink_token.clone();
+        // Built once up front so a broken client fails startup
+        http_sink::HttpSink::new(url.clone(), token.clone(), room_id.clone())?;
+        let retry_queue = config.db_retry_queue;
+        let shutdown = shutdown_rx.clone();
+        writers.push(diagnostics::supervise("http_writer", move || {
+            let sink = http_sink::HttpSink::new(url.clone(), token.clone(), room_id.clone())
+                .expect("HTTP client was built at startup");
+            let writer = db_writer::DbWriter::new(sink, retry_queue, reopen_spill(&spill_dir));
+            db_writer::run(subscribe(), writer, batch_limits, shutdown.clone())
+        }));
+    }
+    {
+        let (mut subscribe, room_id) = (events.subscriber(), room_id.clone());
+        diagnostics::supervise("event_metrics", move || {
+            events::metrics_subscriber(subscribe(), room_id.clone())
+        });
+    }
+    if let Some(webhook_url) = &config.webhook_url {
+        log::info!("Sending room events to webhook at {}", webhook_url);
+        let (mut subscribe, webhook_url, room_id) =
+            (events.subscriber(), webhook_url.clone(), room_id.clone());
+        diagnostics::supervise("webhook", move || {
+            events::webhook_sender(subscribe(), webhook_url.clone(), room_id.clone())
+        });
+    }
+    events.publish(startup_refresh);
+    {
+        let events = events.clone();
+        diagnostics::supervise("diagnostics", move || {
+            diagnostics::update_gauges(events.clone())
+        });
+    }
+
+    // The primary room and every routed one are aggregated by this instance
+    let stats_rooms: Vec<String> = std::iter::once(room_id.clone())
+        .chain(rooms.keys().cloned())
+        .collect();
+    {
+        let (db_pool, interval) = (db_pool.clone(), config.stats_snapshot_interval);
+        diagnostics::supervise("stats_snapshots", move || {
+            stats::persist_snapshots(db_pool.clone(), interval)
+        });
+    }
+    match bandwidth::load(&db_pool).await {
+        Ok(count) => log::info!("Loaded today's bandwidth of {} slots", count),
+        Err(e) => log::warn!(
+            "Failed to load slot bandwidth from database: {:?}, counting from zero",
+            e
+        ),
+    }
+    if let Some(daily_bytes) = bandwidth_quota.daily_bytes {
+        log::info!("Limiting slots to {} bytes a day", daily_bytes);
+    }
+    {
+        let (db_pool, interval) = (db_pool.clone(), config.stats_snapshot_interval);
+        diagnostics::supervise("bandwidth", move || {
+            bandwidth::persist(db_pool.clone(), interval)
+        });
+    }
+    {
+        let (db_pool, schedule) = (db_pool.clone(), config.stats_schedule);
+        diagnostics::supervise("stats_aggregation", move || {
+            stats::run_aggregation(db_pool.clone(), stats_rooms.clone(), schedule)
+        });
+    }
+    if !config.password_refresh_interval.is_zero() {
+        log::info!(
+            "Refreshing the slot passwords every {:?}",
+            config.password_refresh_interval
+        );
+        let (lobby, interval, room_id) = (
+            lobby.clone(),
+            config.password_refresh_interval,
+            room_id.clone(),
+        );
+        let (passwords, slot_names, preferences, db_pool, events) = (
+            passwords.clone(),
+            slot_names.clone(),
+            preferences.clone(),
+            db_pool.clone(),
+            events.clone(),
+        );
+        diagnostics::supervise("password_refresh", move || {
+            lobby::refresh_periodically(
+                lobby.clone(),
+                interval,
+                room_id.clone(),
+                passwords.clone(),
+                slot_names.clone(),
+                preferences.clone(),
+                db_pool.clone(),
+                events.clone(),
+            )
+        });
+    }
+    if !config.retention.is_empty() {
+        log::info!("Pruning history past {:?}", config.retention);
+        let (db_pool, policy) = (db_pool.clone(), config.retention.clone());
+        diagnostics::supervise("retention", move || {
+            retention::run(db_pool.clone(), policy.clone())
+        });
+    }
+
+    let client_registry = Arc::new(registry::ClientRegistry::new(config.flap_limits));
+    {
+        let pace_rooms: Vec<_> = std::iter::once((room_id.clone(), client_registry.clone()))
+            .chain(
+                rooms
+                    .iter()
+                    .map(|(room_id, route)| (room_id.clone(), route.client_registry.clone())),
+            )
+            .collect();
+        diagnostics::supervise("pace", move || pace::run(pace_rooms.clone()));
+    }
+    let login_queue = Arc::new(login_queue::LoginQueue::new(
+        &config.room_id,
+        config.max_logged_in_clients,
+        config.login_queue_max_wait,
+    ));
+    let password_failures = Arc::new(password_audit::PasswordFailures::new(
+        config.password_failure_history,
+    ));
+    let load_shedding = Arc::new(shedding::LoadShedding::new(
+        config.degrade_messages_per_second,
+        config.degrade_recovery,
+    ));
+    if let Some(threshold) = config.degrade_messages_per_second {
+        log::info!(
+            "Switching to degraded mode past {} upstream messages/s",
+            threshold
+        );
+    }
+    {
+        let load_shedding = load_shedding.clone();
+        diagnostics::supervise("load_shedding", move || {
+            shedding::run(load_shedding.clone())
+        });
+    }
+    let slot_claims = config
+        .slot_claiming
+        .then(|| Arc::new(claims::SlotClaims::new(config.claim_ttl)));
+    if let Some(slot_claims) = &slot_claims {
+        log::info!(
+            "Locking slots to the client that first logs in, until {:?} after their last session",
+            config.claim_ttl
+        );
+        let restored = db::models::get_room_setting(&db_pool, &room_id, claims::CLAIMS_SETTING_KEY)
+            .await
+            .and_then(|setting| setting.map(|s| slot_claims.restore(s)).transpose());
+        match restored {
+            Ok(count) => log::info!("Loaded {} slot claims", count.unwrap_or(0)),
+            Err(e) => log::warn!(
+                "Failed to load slot claims from database: {:?}, starting without any",
+                e
+            ),
+        }
+        let (db_pool, room_id, slot_claims) =
+            (db_pool.clone(), room_id.clone(), slot_claims.clone());
+        diagnostics::supervise("slot_claims", move || {
+            claims::persist(db_pool.clone(), room_id.clone(), slot_claims.clone())
+        });
+    }
+    let resumption = config.resume_window.map(|window| {
+        log::info!(
+            "Letting clients resume their session for {:?} after it ended",
+            window
+        );
+        Arc::new(resume::ResumableSessions::new(window))
+    });
+    if let Some(resumption) = &resumption {
+        let restored = db::models::get_room_setting(&db_pool, &room_id, resume::RESUME_SETTING_KEY)
+            .await
+            .and_then(|setting| setting.map(|s| resumption.restore(s)).transpose());
+        match restored {
+            Ok(count) => log::info!("Loaded {} resumable sessions", count.unwrap_or(0)),
+            Err(e) => log::warn!(
+                "Failed to load resumable sessions from database: {:?}, starting without any",
+                e
+            ),
+        }
+        let (db_pool, room_id, resumption) = (db_pool.clone(), room_id.clone(), resumption.clone());
+        diagnostics::supervise("resumable_sessions", move || {
+            resume::persist(db_pool.clone(), room_id.clone(), resumption.clone())
+        });
+    }
+
+    // Messages changed through the API, including all of them removed, override
+    // SCHEDULED_MESSAGES
+    let scheduled_messages = Arc::new(scheduled_messages::ScheduledMessages::new(
+        config.scheduled_messages.0.clone(),
+        chrono::Utc::now(),
+    ));
+    let restored = db::models::get_room_setting(
+        &db_pool,
+        &room_id,
+        scheduled_messages::SCHEDULED_MESSAGES_SETTING_KEY,
+    )
+    .await
+    .and_then(|setting| {
+        setting
+            .map(|s| scheduled_messages.restore(s, chrono::Utc::now()))
+            .transpose()
+    });
+    match restored {
+        Ok(Some(count)) => log::info!("Loaded {} scheduled messages", count),
+        Ok(None) => {}
+        Err(e) => log::warn!(
+            "Failed to load scheduled messages from database: {:?}, falling back to SCHEDULED_MESSAGES",
+            e
+        ),
+    }
+    {
+        let (scheduled_messages, client_registry) =
+            (scheduled_messages.clone(), client_registry.clone());
+        diagnostics::supervise("scheduled_messages", move || {
+            scheduled_messages::run(scheduled_messages.clone(), client_registry.clone())
+        });
+    }
+
+    let soft_state = standby::SoftState {
+        deathlink_exclusions: deathlink_exclusions.clone(),
+        deathlink_probability: deathlink_probability.clone(),
+        deferred_datapackage_games: deferred_datapackage_games.clone(),
+        preferences: preferences.clone(),
+        motd: motd.clone(),
+    };
+    let standby = Arc::new(standby::Standby::new(
+        room_id.clone(),
+        soft_state.clone(),
+        config.follow_url.is_some(),
+    ));
+    if let Some(follow_url) = &config.follow_url {
+        let (standby, follow_url) = (standby.clone(), follow_url.clone());
+        let (api_key, interval) = (config.apx_api_key.clone(), config.follow_interval);
+        diagnostics::supervise("standby_follow", move || {
+            standby::follow(
+                standby.clone(),
+                follow_url.clone(),
+                api_key.clone(),
+                interval,
+            )
+        });
+    }
+
+    let coordinator = config.coordinate_instances.then(|| {
+        Arc::new(coordination::Coordinator::new(
+            room_id.clone(),
+            db_pool.clone(),
+            soft_state,
+            slot_names.clone(),
+            bounce_recorder.clone(),
+        ))
+    });
+    if let Some(coordinator) = &coordinator {
+        log::info!("Keeping soft state in sync with the instances sharing the database");
+        let (coordinator, db_url) = (coordinator.clone(), config.db_url.clone());
+        diagnostics::supervise("coordination", move || {
+            coordination::listen(coordinator.clone(), db_url.clone())
+        });
+    }
+
+    let reloader = Arc::new(reload::Reloader::new(vars, live.clone(), motd.clone()));
+    let reload_on_hangup = reloader.clone();
+    diagnostics::supervise("reload_on_hangup", move || {
+        reload::on_hangup(reload_on_hangup.clone())
+    });
 
     let app_state = AppState {
         config,
+        live: live.clone(),
+        reloader,
         passwords: passwords.clone(),
         deathlink_exclusions: deathlink_exclusions.clone(),
         deathlink_probability: deathlink_probability.clone(),
         deferred_datapackage_games: deferred_datapackage_games.clone(),
+        slot_names: slot_names.clone(),
+        preferences: preferences.clone(),
+        slot_groups: slot_groups.clone(),
+        motd: motd.clone(),
+        bounce_recorder: bounce_recorder.clone(),
+        scheduled_messages,
         db_pool: db_pool.clone(),
+        events: events.clone(),
+        client_registry: client_registry.clone(),
+        login_queue: login_queue.clone(),
+        load_shedding: load_shedding.clone(),
+        standby: standby.clone(),
+        password_failures: password_failures.clone(),
+        slot_claims: slot_claims.clone(),
+        coordinator,
+        ready: ready.clone(),
+        selftest,
+        upstream: upstream.clone(),
+        rooms: rooms.clone(),
+        lobby,
     };
 
     let shutdown_config = ShutdownConfig {
@@ -187,35 +885,62 @@ async fn main() -> Result<()> {
         ..Default::default()
     };
 
-    let figment = rocket::Config::figment().merge(("shutdown", shutdown_config));
+    let figment = api::listener_figment(
+        rocket::Config::figment().merge(("shutdown", shutdown_config)),
+        &app_state.config,
+    )?;
 
-    let message_counter = metrics::init_metrics();
-    let prometheus = rocket_prometheus::PrometheusMetrics::with_registry(
-        rocket_prometheus::prometheus::Registry::new(),
-    );
-    prometheus
-        .registry()
-        .register(Box::new(message_counter))
-        .expect("Failed to register message counter");
+    let metrics_registry = Arc::new(metrics::Registry::new(&app_state.config.room_id));
+    metrics::init_metrics(&metrics_registry, app_state.config.per_slot_gauges);
+    let prometheus = metrics_registry.http_metrics();
 
-    // Load TLS config if provided (before moving app_state)
+    // Load TLS config if provided (before moving app_state). Manual cert paths take precedence
+    // over ACME.
     let tls_acceptor = if let (Some(cert_path), Some(key_path)) = (
         &app_state.config.tls_cert_path,
         &app_state.config.tls_key_path,
     ) {
         let tls_config = tls::load_tls_config(cert_path, key_path)?;
-        Some(tokio_rustls::TlsAcceptor::from(tls_config))
+        diagnostics::supervise("tls_expiry", tls::expiry_reminder);
+        Some(tls::TlsAcceptor::Static(tokio_rustls::TlsAcceptor::from(
+            tls_config,
+        )))
+    } else if let (Some(domain), Some(contact_email)) = (
+        &app_state.config.acme_domain,
+        &app_state.config.acme_contact_email,
+    ) {
+        log::info!(
+            "Requesting TLS certificate for {} via ACME (cache: {})",
+            domain,
+            app_state.config.acme_cache_dir
+        );
+        Some(tls::start_acme(
+            domain,
+            contact_email,
+            &app_state.config.acme_cache_dir,
+        ))
     } else {
         log::warn!("TLS not configured - only plain WebSocket will be available");
         None
     };
+    let tls_detection = app_state.config.tls_detection;
+    match (tls_detection, &tls_acceptor) {
+        (net::TlsDetection::Always, None) => {
+            anyhow::bail!("TLS_DETECTION=always requires TLS to be configured")
+        }
+        (net::TlsDetection::Never, Some(_)) => {
+            log::warn!("TLS is configured but TLS_DETECTION=never, WSS connections will fail");
+        }
+        _ => {}
+    }
 
     tokio::spawn(async move {
         if let Err(e) = rocket::custom(figment)
             .manage(app_state)
-            .attach(prometheus.clone())
-            .mount("/api", api::routes())
-            .mount("/metrics", api::MetricsRoute(prometheus))
+            .attach(prometheus)
+            .mount(api::BASE, api::routes())
+            .register(api::BASE, api::catchers())
+            .mount("/metrics", api::MetricsRoute(metrics_registry))
             .launch()
             .await
         {
@@ -223,158 +948,225 @@ async fn main() -> Result<()> {
         }
     });
 
-    let listener = TcpListener::bind("0.0.0.0:36000").await?;
-    let notext_listener = TcpListener::bind("0.0.0.0:36001").await?;
-
-    log::info!("WebSocket proxy listening on 0.0.0.0:36000");
-    log::info!("WebSocket proxy (NoText) listening on 0.0.0.0:36001");
-    if tls_acceptor.is_some() {
-        log::info!("TLS enabled - supporting both WS and WSS");
-    }
-    log::info!("Forwarding to {}", upstream_url);
-    let (signal_sender, signal_receiver) = channel::<Signal>(1024);
-    tokio::spawn(signal_handler(signal_receiver, db_pool, room_id.clone()));
-
-    let client_registry = Arc::new(registry::ClientRegistry::new());
+    let listen_addrs: &[&str] = if listen_dual_stack {
+        &["0.0.0.0", "[::]"]
+    } else {
+        &["0.0.0.0"]
+    };
 
-    loop {
-        let (socket, addr, inject_notext) = tokio::select! {
-            result = listener.accept() => {
-                match result {
-                    Ok((socket, addr)) => (socket, addr, false),
-                    Err(e) => {
-                        log::error!("Failed to accept connection: {:?}", e);
-                        continue;
-                    }
-                }
-            }
-            result = notext_listener.accept() => {
-                match result {
-                    Ok((socket, addr)) => (socket, addr, true),
-                    Err(e) => {
-                        log::error!("Failed to accept NoText connection: {:?}", e);
-                        continue;
-                    }
-                }
-            }
-            _ = signal::ctrl_c() => {
-                log::info!("Received Ctrl+C, shutting down...");
-                break;
-            }
-        };
+    let proxy_context = ProxyContext {
+        upstream: upstream.clone(),
+        events,
+        passwords,
+        upstream_room_password,
+        deathlink_exclusions,
+        deathlink_probability,
+        deferred_datapackage_games,
+        preferences,
+        slot_groups,
+        slot_names,
+        motd,
+        bounce_recorder,
+        datapackage_cache,
+        room_id,
+        client_registry,
+        login_queue,
+        load_shedding,
+        rooms,
+        upstream_limiter: Arc::new(upstream::UpstreamLimiter::new(
+            max_upstream_connections,
+            upstream_queue_wait,
+        )),
+        token_key,
+        audit_key,
+        password_failures,
+        tracker_mirrors,
+        slot_claims,
+        resumption,
+        allowed_origins,
+        live,
+        chain_mode,
+        identity: chain::Identity::default(),
+        bridge,
+        ready,
+    };
 
-        let signal_sender = signal_sender.clone();
-        let passwords = passwords.clone();
-        let deathlink_exclusions = deathlink_exclusions.clone();
-        let deathlink_probability = deathlink_probability.clone();
-        let deferred_datapackage_games = deferred_datapackage_games.clone();
-        let datapackage_cache = datapackage_cache.clone();
-        let upstream_url = upstream_url.clone();
-        let tls_acceptor = tls_acceptor.clone();
-        let room_id = room_id.clone();
-        let client_registry = client_registry.clone();
-
-        tokio::spawn(async move {
+    let connections = tokio::spawn(connection_tasks.run(stale_connection_age, shutdown_rx));
+    for host in listen_addrs {
+        for (port, inject_notext) in PROXY_PORTS {
+            let addr: SocketAddr = format!("{}:{}", host, port).parse()?;
+            let listener = net::bind_listener(addr)?;
             if inject_notext {
-                log::debug!("New NoText connection from {}", addr);
+                log::info!("WebSocket proxy (NoText) listening on {}", addr);
             } else {
-                log::debug!("New connection from {}", addr);
+                log::info!("WebSocket proxy listening on {}", addr);
             }
+            tokio::spawn(accept_loop(
+                listener,
+                inject_notext,
+                proxy_context.clone(),
+                tls_acceptor.clone(),
+                tls_detection,
+                standby.clone(),
+                connection_supervisor.clone(),
+            ));
+        }
+    }
 
-            // Peek at first byte to detect TLS
-            let mut buf = [0u8; 1];
-            if let Err(e) = socket.peek(&mut buf).await {
-                log::error!("Failed to peek at connection from {}: {:?}", addr, e);
-                return;
-            }
+    match (tls_detection, &tls_acceptor) {
+        (net::TlsDetection::Auto, Some(_)) => {
+            log::info!("TLS enabled - supporting both WS and WSS")
+        }
+        (net::TlsDetection::Always, _) => log::info!("TLS required - only supporting WSS"),
+        _ => {}
+    }
+    log::info!("Forwarding to {}", upstream);
 
-            // 0x16 is the TLS handshake record type
-            let is_tls = buf[0] == 0x16;
-
-            if is_tls {
-                if let Some(acceptor) = tls_acceptor {
-                    log::debug!("Accepting TLS connection from {}", addr);
-                    match acceptor.accept(socket).await {
-                        Ok(tls_stream) => {
-                            if let Err(e) = handle_client(
-                                tls_stream,
-                                &upstream_url,
-                                signal_sender,
-                                passwords,
-                                deathlink_exclusions,
-                                deathlink_probability,
-                                deferred_datapackage_games,
-                                datapackage_cache,
-                                room_id,
-                                inject_notext,
-                                client_registry,
-                            )
-                            .await
-                            {
-                                log::error!("Error handling TLS client {}: {:?}", addr, e);
-                            }
-                        }
-                        Err(e) => {
-                            log::error!("TLS handshake failed for {}: {:?}", addr, e);
-                        }
-                    }
-                } else {
-                    log::warn!("Client {} attempted TLS but TLS is not configured", addr);
-                }
-            } else {
-                log::debug!("Accepting plain connection from {}", addr);
-                if let Err(e) = handle_client(
-                    socket,
-                    &upstream_url,
-                    signal_sender,
-                    passwords,
-                    deathlink_exclusions,
-                    deathlink_probability,
-                    deferred_datapackage_games,
-                    datapackage_cache,
-                    room_id,
-                    inject_notext,
-                    client_registry,
-                )
-                .await
-                {
-                    log::error!("Error handling client {}: {:?}", addr, e);
-                }
-            }
-        });
+    signal::ctrl_c().await?;
+    log::info!("Received Ctrl+C, shutting down...");
+    let _ = shutdown_tx.send(true);
+    if let Err(e) = connections.await {
+        log::error!("Connection tasks didn't shut down cleanly: {:?}", e);
+    }
+    let flushed = tokio::time::timeout(
+        WRITER_SHUTDOWN_TIMEOUT,
+        futures_util::future::join_all(writers),
+    );
+    if flushed.await.is_err() {
+        log::warn!("Event writers didn't finish flushing in time, unstored events are lost");
     }
 
     Ok(())
 }
 
-async fn signal_handler(mut receiver: Receiver<Signal>, db_pool: db::DieselPool, room_id: String) {
-    while let Some(signal) = receiver.recv().await {
-        match signal {
-            Signal::DeathLink {
-                slot,
-                source,
-                cause,
-            } => {
-                let new_deathlink =
-                    db::models::NewDeathLink::new(room_id.clone(), slot, source, cause);
-                if let Err(e) = db::models::insert_deathlink(&db_pool, new_deathlink).await {
-                    log::error!("Failed to insert deathlink into database: {:?}", e);
+/// Spill directory of a restarted writer, which runs without one if it can't be opened anymore
+fn reopen_spill(dir: &Option<std::path::PathBuf>) -> Option<spill::SpillDir> {
+    let dir = dir.as_ref()?;
+    spill::SpillDir::open(dir)
+        .inspect_err(|e| log::error!("Failed to reopen spill directory: {:?}", e))
+        .ok()
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    inject_notext: bool,
+    proxy_context: ProxyContext,
+    tls_acceptor: Option<tls::TlsAcceptor>,
+    tls_detection: net::TlsDetection,
+    standby: Arc<standby::Standby>,
+    supervisor: supervisor::Supervisor,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                // Players retry on their own, by then the IP has moved to the promoted instance
+                if standby.is_following() {
+                    log::debug!(
+                        "Refusing connection from {} at {} while following a primary",
+                        addr,
+                        utc_timestamp()
+                    );
+                    continue;
+                }
+                let (proxy_context, tls_acceptor) = (proxy_context.clone(), tls_acceptor.clone());
+                let submitted = supervisor.submit(addr, move |login| {
+                    handle_connection(
+                        socket,
+                        addr,
+                        inject_notext,
+                        proxy_context,
+                        tls_acceptor,
+                        tls_detection,
+                        login,
+                    )
+                });
+                if submitted.is_err() {
+                    log::warn!(
+                        "Refusing connection from {} at {}, too many connections are open",
+                        addr,
+                        utc_timestamp()
+                    );
                 }
             }
-            Signal::CountdownInit { slot } => {
-                let new_countdown = db::models::NewCountdown::new(room_id.clone(), slot);
-                if let Err(e) = db::models::insert_countdown(&db_pool, new_countdown).await {
-                    log::error!("Failed to insert countdown into database: {:?}", e);
+            Err(e) => {
+                if inject_notext {
+                    log::error!("Failed to accept NoText connection: {:?}", e);
+                } else {
+                    log::error!("Failed to accept connection: {:?}", e);
                 }
             }
         }
     }
+}
+
+async fn handle_connection(
+    socket: TcpStream,
+    addr: SocketAddr,
+    inject_notext: bool,
+    proxy_context: ProxyContext,
+    tls_acceptor: Option<tls::TlsAcceptor>,
+    tls_detection: net::TlsDetection,
+    login: supervisor::LoginMark,
+) {
+    let family = net::family(addr.ip());
+    if inject_notext {
+        log::debug!(
+            "New NoText connection from {} ({}) at {}",
+            addr,
+            family,
+            utc_timestamp()
+        );
+    } else {
+        log::debug!(
+            "New connection from {} ({}) at {}",
+            addr,
+            family,
+            utc_timestamp()
+        );
+    }
 
-    log::warn!("Signal channel has been closed")
+    let sniffed = net::sniff(&socket, tls_detection).await;
+    if sniffed == net::Sniffed::Probe {
+        log::debug!("Connection from {} closed before sending anything", addr);
+        metrics::record_probe_connection(if inject_notext { "notext" } else { "default" });
+        return;
+    }
+    if sniffed == net::Sniffed::NotTls {
+        log::debug!("Closing plain connection from {}, TLS is required", addr);
+        return;
+    }
+
+    if sniffed == net::Sniffed::Tls {
+        if let Some(acceptor) = tls_acceptor {
+            log::debug!("Accepting TLS connection from {}", addr);
+            match acceptor.accept(socket).await {
+                Ok(None) => {
+                    log::debug!("Answered ACME challenge from {}", addr);
+                }
+                Ok(Some(tls_stream)) => {
+                    if let Err(e) =
+                        handle_client(tls_stream, &proxy_context, inject_notext, login).await
+                    {
+                        log::log!(e.log_level(), "Error handling TLS client {}: {}", addr, e);
+                    }
+                }
+                Err(e) => {
+                    log::error!("TLS handshake failed for {}: {:?}", addr, e);
+                }
+            }
+        } else {
+            log::warn!("Client {} attempted TLS but TLS is not configured", addr);
+        }
+    } else {
+        log::debug!("Accepting plain connection from {}", addr);
+        if let Err(e) = handle_client(socket, &proxy_context, inject_notext, login).await {
+            log::log!(e.log_level(), "Error handling client {}: {}", addr, e);
+        }
+    }
 }
 
-async fn fetch_datapackage(upstream_url: &str) -> Result<DataPackageCache> {
-    let (ws, _) = connect_async(upstream_url).await?;
+async fn fetch_datapackage(upstream: &UpstreamServers) -> Result<DataPackageCache> {
+    let (ws, _) = proxy::connect_upstream(upstream, None).await?;
     let (mut write, mut read) = ws.split();
 
     while let Some(msg) = read.next().await {
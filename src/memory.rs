@@ -0,0 +1,162 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tokio::sync::Notify;
+
+use crate::metrics;
+
+/// What the memory of a connection is held by
+#[derive(Clone, Copy, Debug)]
+pub enum Account {
+    /// Responses the proxy queued for the client
+    Outbox,
+    /// Checks of the connection waiting for their item to go out
+    OutstandingChecks,
+}
+
+/// Approximate bytes held for a connection, in `/api/rooms/<room_id>/clients`
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct MemoryUsage {
+    pub outbox_bytes: usize,
+    pub outstanding_checks_bytes: usize,
+    pub total_bytes: usize,
+    /// PER_CONNECTION_MEMORY_CAP as the connection started
+    pub cap_bytes: Option<usize>,
+}
+
+/// The memory the buffers of a single connection hold, as each of them accounts for what it
+/// stores. The counters are only approximate, they don't chase every allocation, but they let a
+/// connection that keeps growing be told apart and closed once it's over the cap.
+pub struct MemoryBudget {
+    outbox: AtomicUsize,
+    outstanding_checks: AtomicUsize,
+    cap: Option<usize>,
+    exceeded: AtomicBool,
+    notify: Notify,
+}
+
+impl MemoryBudget {
+    pub fn new(cap: Option<usize>) -> Self {
+        Self {
+            outbox: AtomicUsize::new(0),
+            outstanding_checks: AtomicUsize::new(0),
+            cap,
+            exceeded: AtomicBool::new(false),
+            notify: Notify::new(),
+        }
+    }
+
+    fn counter(&self, account: Account) -> &AtomicUsize {
+        match account {
+            Account::Outbox => &self.outbox,
+            Account::OutstandingChecks => &self.outstanding_checks,
+        }
+    }
+
+    pub fn add(&self, account: Account, bytes: usize) {
+        self.counter(account).fetch_add(bytes, Ordering::Relaxed);
+        metrics::add_connection_memory(bytes as i64);
+        self.check_cap();
+    }
+
+    /// Releases `bytes` an `add` to the same account accounted for
+    pub fn remove(&self, account: Account, bytes: usize) {
+        self.counter(account).fetch_sub(bytes, Ordering::Relaxed);
+        metrics::add_connection_memory(-(bytes as i64));
+    }
+
+    /// For buffers that are easier to measure as a whole than to follow
+    pub fn set(&self, account: Account, bytes: usize) {
+        let previous = self.counter(account).swap(bytes, Ordering::Relaxed);
+        metrics::add_connection_memory(bytes as i64 - previous as i64);
+        self.check_cap();
+    }
+
+    pub fn total(&self) -> usize {
+        self.outbox.load(Ordering::Relaxed) + self.outstanding_checks.load(Ordering::Relaxed)
+    }
+
+    pub fn usage(&self) -> MemoryUsage {
+        let outbox_bytes = self.outbox.load(Ordering::Relaxed);
+        let outstanding_checks_bytes = self.outstanding_checks.load(Ordering::Relaxed);
+        MemoryUsage {
+            outbox_bytes,
+            outstanding_checks_bytes,
+            total_bytes: outbox_bytes + outstanding_checks_bytes,
+            cap_bytes: self.cap,
+        }
+    }
+
+    fn check_cap(&self) {
+        if let Some(cap) = self.cap
+            && self.total() > cap
+            && !self.exceeded.swap(true, Ordering::Relaxed)
+        {
+            self.notify.notify_one();
+        }
+    }
+
+    /// Resolves once the connection went over its cap, which it stays over even if the memory
+    /// is released since. Never resolves without a cap.
+    pub async fn exceeded(&self) {
+        while !self.exceeded.load(Ordering::Relaxed) {
+            self.notify.notified().await;
+        }
+    }
+}
+
+impl Drop for MemoryBudget {
+    fn drop(&mut self) {
+        metrics::add_connection_memory(-(self.total() as i64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_accounts_add_up() {
+        let budget = MemoryBudget::new(None);
+        budget.add(Account::Outbox, 100);
+        budget.add(Account::Outbox, 50);
+        budget.remove(Account::Outbox, 100);
+        budget.set(Account::OutstandingChecks, 30);
+        budget.set(Account::OutstandingChecks, 20);
+        assert_eq!(
+            budget.usage(),
+            MemoryUsage {
+                outbox_bytes: 50,
+                outstanding_checks_bytes: 20,
+                total_bytes: 70,
+                cap_bytes: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_going_over_the_cap_is_noticed() {
+        let budget = MemoryBudget::new(Some(100));
+        budget.add(Account::Outbox, 60);
+        budget.set(Account::OutstandingChecks, 40);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), budget.exceeded())
+                .await
+                .is_err()
+        );
+
+        budget.add(Account::Outbox, 1);
+        budget.remove(Account::Outbox, 61);
+        tokio::time::timeout(Duration::from_secs(1), budget.exceeded())
+            .await
+            .unwrap();
+
+        let unlimited = MemoryBudget::new(None);
+        unlimited.add(Account::Outbox, usize::MAX / 2);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), unlimited.exceeded())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::proto::{InvalidPacket, PrintJSON};
+
+/// Messages synthesized by the proxy and sent to clients as PrintJSON
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Notice {
+    CountdownBlocked,
+    NoTextConnected,
+    Muted,
+    RoomFullWaiting,
+    RoomFullRefused,
+    DataPackageChanged,
+    QuotaExceeded,
+    CommandNotPermitted,
+    UnstableConnection,
+    CommandTooLarge,
+    DeathLinkAbsorbed,
+    HintUpdateBlocked,
+    TrackerReadOnly,
+    RoomClosed,
+    UpstreamPasswordRejected,
+    SlotClaimed,
+    UpstreamNotResponding,
+}
+
+impl Notice {
+    pub fn id(self) -> &'static str {
+        match self {
+            Notice::CountdownBlocked => "countdown_blocked",
+            Notice::NoTextConnected => "notext_connected",
+            Notice::Muted => "muted",
+            Notice::RoomFullWaiting => "room_full_waiting",
+            Notice::RoomFullRefused => "room_full_refused",
+            Notice::DataPackageChanged => "datapackage_changed",
+            Notice::QuotaExceeded => "quota_exceeded",
+            Notice::CommandNotPermitted => "command_not_permitted",
+            Notice::UnstableConnection => "unstable_connection",
+            Notice::CommandTooLarge => "command_too_large",
+            Notice::DeathLinkAbsorbed => "deathlink_absorbed",
+            Notice::HintUpdateBlocked => "hint_update_blocked",
+            Notice::TrackerReadOnly => "tracker_read_only",
+            Notice::RoomClosed => "room_closed",
+            Notice::UpstreamPasswordRejected => "upstream_password_rejected",
+            Notice::SlotClaimed => "slot_claimed",
+            Notice::UpstreamNotResponding => "upstream_not_responding",
+        }
+    }
+
+    pub fn text(self) -> &'static str {
+        match self {
+            Notice::CountdownBlocked => {
+                "Starting countdowns is not allowed. This attempt has been logged."
+            }
+            Notice::NoTextConnected => "Connected to APX proxy (NoText mode)",
+            Notice::Muted => "You are muted in this room, your message was not sent.",
+            Notice::RoomFullWaiting => "The room is full, waiting for a free connection...",
+            Notice::RoomFullRefused => "The room is full, please try again later.",
+            Notice::DataPackageChanged => {
+                "The room's data package changed, reconnect to load the new one."
+            }
+            Notice::QuotaExceeded => {
+                "Your slot used up its traffic for today, only gameplay messages are sent."
+            }
+            Notice::CommandNotPermitted => "This command is not permitted in this room.",
+            Notice::CommandTooLarge => "Your message is too large and was not sent.",
+            Notice::DeathLinkAbsorbed => {
+                "A DeathLink arrived while you were loading in, it was absorbed by your grace period."
+            }
+            Notice::HintUpdateBlocked => {
+                "Only the player receiving a hinted item can change its hint. This attempt has been logged."
+            }
+            Notice::RoomClosed => "The room is closed, no new sessions are allowed.",
+            Notice::UpstreamPasswordRejected => {
+                "The Archipelago server refused the room's own password, not yours. Please let the organizers know."
+            }
+            Notice::SlotClaimed => {
+                "This slot is being played from another client. If it's yours, ask the organizers to release it."
+            }
+            Notice::UpstreamNotResponding => {
+                "The Archipelago server isn't responding, it may still be starting. Please try again later."
+            }
+            Notice::UnstableConnection => {
+                "Your connection keeps dropping, it seems to be unstable. Consider switching networks."
+            }
+            Notice::TrackerReadOnly => {
+                "Trackers can only read from the room here, your command was not sent."
+            }
+        }
+    }
+
+    pub fn color(self) -> &'static str {
+        match self {
+            Notice::CountdownBlocked
+            | Notice::Muted
+            | Notice::RoomFullRefused
+            | Notice::RoomClosed
+            | Notice::UpstreamPasswordRejected
+            | Notice::SlotClaimed
+            | Notice::UpstreamNotResponding
+            | Notice::CommandNotPermitted
+            | Notice::CommandTooLarge
+            | Notice::HintUpdateBlocked
+            | Notice::TrackerReadOnly => "red",
+            Notice::NoTextConnected => "green",
+            Notice::RoomFullWaiting
+            | Notice::DataPackageChanged
+            | Notice::QuotaExceeded
+            | Notice::UnstableConnection
+            | Notice::DeathLinkAbsorbed => "yellow",
+        }
+    }
+
+    pub fn to_print_json(self) -> Value {
+        serde_json::to_value(PrintJSON::with_color(self.text(), self.color())).unwrap()
+    }
+}
+
+/// Per connection suppression of repeated denials. Clients that auto-retry a blocked command
+/// would otherwise receive one denial per attempt.
+pub struct DenialCooldown {
+    window: Duration,
+    last_sent: HashMap<Notice, Instant>,
+    /// InvalidPackets by type and command, whatever their text says
+    last_invalid: HashMap<(String, Option<String>), Instant>,
+}
+
+impl DenialCooldown {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last_sent: HashMap::new(),
+            last_invalid: HashMap::new(),
+        }
+    }
+
+    /// Returns whether the denial should be sent to the client. Suppressed denials don't extend
+    /// the window, so a client retrying in a loop still gets one denial per window.
+    pub fn should_send(&mut self, notice: Notice, now: Instant) -> bool {
+        due(&mut self.last_sent, notice, self.window, now)
+    }
+
+    /// Same as `should_send`, for the InvalidPacket answering a command
+    pub fn should_send_invalid(&mut self, invalid: &InvalidPacket, now: Instant) -> bool {
+        let key = (invalid.type_.clone(), invalid.original_cmd.clone());
+        due(&mut self.last_invalid, key, self.window, now)
+    }
+}
+
+fn due<K: Eq + std::hash::Hash>(
+    last_sent: &mut HashMap<K, Instant>,
+    key: K,
+    window: Duration,
+    now: Instant,
+) -> bool {
+    match last_sent.get(&key) {
+        Some(last) if now.duration_since(*last) < window => false,
+        _ => {
+            last_sent.insert(key, now);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_denial_is_sent() {
+        let mut cooldown = DenialCooldown::new(Duration::from_secs(5));
+        assert!(cooldown.should_send(Notice::CountdownBlocked, Instant::now()));
+    }
+
+    #[test]
+    fn test_repeated_denial_within_window_is_suppressed() {
+        let mut cooldown = DenialCooldown::new(Duration::from_secs(5));
+        let start = Instant::now();
+        assert!(cooldown.should_send(Notice::CountdownBlocked, start));
+        assert!(!cooldown.should_send(Notice::CountdownBlocked, start + Duration::from_secs(1)));
+        assert!(!cooldown.should_send(Notice::CountdownBlocked, start + Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn test_window_resets() {
+        let mut cooldown = DenialCooldown::new(Duration::from_secs(5));
+        let start = Instant::now();
+        assert!(cooldown.should_send(Notice::CountdownBlocked, start));
+        assert!(!cooldown.should_send(Notice::CountdownBlocked, start + Duration::from_secs(3)));
+        assert!(cooldown.should_send(Notice::CountdownBlocked, start + Duration::from_secs(5)));
+        assert!(!cooldown.should_send(Notice::CountdownBlocked, start + Duration::from_secs(6)));
+    }
+
+    #[test]
+    fn test_different_denials_are_independent() {
+        let mut cooldown = DenialCooldown::new(Duration::from_secs(5));
+        let start = Instant::now();
+        assert!(cooldown.should_send(Notice::CountdownBlocked, start));
+        assert!(cooldown.should_send(Notice::Muted, start));
+        assert!(!cooldown.should_send(Notice::Muted, start));
+    }
+
+    #[test]
+    fn test_repeated_invalid_packets_are_suppressed() {
+        let mut cooldown = DenialCooldown::new(Duration::from_secs(5));
+        let start = Instant::now();
+        let unreadable = |text: &str| InvalidPacket::new("arguments", Some("LocationChecks"), text);
+        assert!(cooldown.should_send_invalid(&unreadable("locations"), start));
+        assert!(!cooldown.should_send_invalid(&unreadable("items"), start));
+        assert!(
+            cooldown
+                .should_send_invalid(&InvalidPacket::new("arguments", Some("Say"), "text"), start)
+        );
+        assert!(cooldown.should_send(Notice::CountdownBlocked, start));
+        assert!(
+            cooldown.should_send_invalid(&unreadable("locations"), start + Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn test_zero_window_never_suppresses() {
+        let mut cooldown = DenialCooldown::new(Duration::ZERO);
+        let start = Instant::now();
+        assert!(cooldown.should_send(Notice::CountdownBlocked, start));
+        assert!(cooldown.should_send(Notice::CountdownBlocked, start));
+    }
+}
@@ -1,24 +1,1099 @@
 use aprs_proto::primitives::SlotId;
-use rocket_prometheus::prometheus::{IntCounterVec, opts};
+use rocket_prometheus::PrometheusMetrics;
+use rocket_prometheus::prometheus::{
+    self, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    opts,
+};
+use std::collections::HashMap;
 use std::sync::OnceLock;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
 
 static MESSAGE_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static DENIAL_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static EVENT_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static EVENTS_LAGGED_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static PAYLOAD_BYTES_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static UPSTREAM_REFUSAL_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static UPSTREAM_ADMISSION_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static CONNECTION_ERROR_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static DISCONNECT_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static UPSTREAM_PARSE_FAILURE_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static ROOMINFO_TIMEOUT_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static OVERSIZED_COMMAND_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static SUPPRESSED_DEATHLINK_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static PRELOGIN_BUDGET_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static DROPPED_RESPONSE_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static PROBE_CONNECTION_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static CLIENT_VERSION_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static RECONNECT_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static TASK_PANIC_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static POST_CLOSE_FRAME_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static DB_FLUSH_FAILURE_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static LOGIN_QUEUE_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static REJECTED_ORIGIN_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static LOBBY_FETCH_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static PASSWORD_REFRESH_REJECTED_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static WS_ERROR_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static SEND_RETRY_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static TRANSFORM_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static JSON_LIMIT_COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+static UPSTREAM_CONNECTIONS_GAUGE: OnceLock<IntGauge> = OnceLock::new();
+static CONNECTION_TASKS_GAUGE: OnceLock<IntGauge> = OnceLock::new();
+static CONNECTION_MEMORY_GAUGE: OnceLock<IntGauge> = OnceLock::new();
+static TLS_CERT_EXPIRY_GAUGE: OnceLock<IntGauge> = OnceLock::new();
+static DEGRADED_MODE_GAUGE: OnceLock<IntGauge> = OnceLock::new();
+static SLOT_CHECKED_LOCATIONS_GAUGE: OnceLock<IntGaugeVec> = OnceLock::new();
+static SLOT_ITEMS_RECEIVED_GAUGE: OnceLock<IntGaugeVec> = OnceLock::new();
+static SLOT_BANDWIDTH_GAUGE: OnceLock<IntGaugeVec> = OnceLock::new();
+static ROOM_RECENT_CHECKS_GAUGE: OnceLock<IntGaugeVec> = OnceLock::new();
+static SLOT_RECENT_CHECKS_GAUGE: OnceLock<IntGaugeVec> = OnceLock::new();
+static CHANNEL_DEPTH_GAUGE: OnceLock<IntGaugeVec> = OnceLock::new();
+static TASK_LAST_RUN_GAUGE: OnceLock<IntGaugeVec> = OnceLock::new();
+static LOGIN_QUEUE_GAUGE: OnceLock<IntGaugeVec> = OnceLock::new();
+static UPSTREAM_PASSWORD_GAUGE: OnceLock<IntGaugeVec> = OnceLock::new();
+static DB_BATCH_ROWS_HISTOGRAM: OnceLock<HistogramVec> = OnceLock::new();
+static DB_FLUSH_SECONDS_HISTOGRAM: OnceLock<HistogramVec> = OnceLock::new();
+static CHECK_TO_ITEM_HISTOGRAM: OnceLock<HistogramVec> = OnceLock::new();
+static SAMPLES_DROPPED_COUNTER: OnceLock<IntCounter> = OnceLock::new();
 
-pub fn init_metrics() -> IntCounterVec {
-    let counter = IntCounterVec::new(
-        opts!("apx_messages_total", "Total number of messages processed"),
+/// Samples waiting for the recorder thread, past that they're dropped and counted
+const SAMPLE_QUEUE: usize = 64 * 1024;
+static SAMPLES: OnceLock<SyncSender<Sample>> = OnceLock::new();
+
+/// Message types of the AP protocol, anything else is counted as `unknown`
+const MESSAGE_TYPES: &[&str] = &[
+    "Bounce",
+    "Bounced",
+    "Connect",
+    "ConnectUpdate",
+    "Connected",
+    "ConnectionRefused",
+    "CreateHints",
+    "DataPackage",
+    "Get",
+    "GetDataPackage",
+    "InvalidPacket",
+    "LocationChecks",
+    "LocationInfo",
+    "LocationScouts",
+    "PrintJSON",
+    "ReceivedItems",
+    "Retrieved",
+    "RoomInfo",
+    "RoomUpdate",
+    "Say",
+    "Set",
+    "SetNotify",
+    "SetReply",
+    "StatusUpdate",
+    "Sync",
+    "UpdateHint",
+];
+
+/// Everything exported on `/metrics`. Proxy metrics carry their own `room_id` label, Rocket's request
+/// metrics can't so they live in a second registry adding it as a constant label, along with the
+/// `apx_` prefix. Both are gathered into a single scrape.
+pub struct Registry {
+    proxy: prometheus::Registry,
+    http: prometheus::Registry,
+}
+
+impl Registry {
+    pub fn new(room_id: &str) -> Self {
+        let labels = HashMap::from([("room_id".to_string(), room_id.to_string())]);
+        let http = prometheus::Registry::new_custom(Some("apx".to_string()), Some(labels))
+            .expect("Failed to create the HTTP metrics registry");
+        Self {
+            proxy: prometheus::Registry::new(),
+            http,
+        }
+    }
+
+    /// Fairing recording Rocket's request metrics into this registry
+    pub fn http_metrics(&self) -> PrometheusMetrics {
+        PrometheusMetrics::with_registry(self.http.clone())
+    }
+
+    pub fn gather(&self) -> Vec<prometheus::proto::MetricFamily> {
+        let mut families = self.proxy.gather();
+        families.extend(self.http.gather());
+        families
+    }
+
+    /// Text exposition format
+    pub fn encode(&self) -> prometheus::Result<String> {
+        let mut buffer = Vec::new();
+        prometheus::TextEncoder::new().encode(&self.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer).expect("Prometheus text output is UTF-8"))
+    }
+
+    fn register(&self, collector: Box<dyn prometheus::core::Collector>) -> prometheus::Result<()> {
+        self.proxy.register(collector)
+    }
+}
+
+fn register_counter(
+    registry: &Registry,
+    cell: &OnceLock<IntCounterVec>,
+    name: &str,
+    help: &str,
+    labels: &[&str],
+) {
+    let counter = IntCounterVec::new(opts!(name, help), labels)
+        .unwrap_or_else(|e| panic!("Failed to create {}: {:?}", name, e));
+    registry
+        .register(Box::new(counter.clone()))
+        .unwrap_or_else(|e| panic!("Failed to register {}: {:?}", name, e));
+    cell.get_or_init(|| counter);
+}
+
+fn register_gauge_vec(
+    registry: &Registry,
+    cell: &OnceLock<IntGaugeVec>,
+    name: &str,
+    help: &str,
+    labels: &[&str],
+) {
+    let gauge = IntGaugeVec::new(opts!(name, help), labels)
+        .unwrap_or_else(|e| panic!("Failed to create {}: {:?}", name, e));
+    registry
+        .register(Box::new(gauge.clone()))
+        .unwrap_or_else(|e| panic!("Failed to register {}: {:?}", name, e));
+    cell.get_or_init(|| gauge);
+}
+
+fn register_histogram(
+    registry: &Registry,
+    cell: &OnceLock<HistogramVec>,
+    opts: HistogramOpts,
+    labels: &[&str],
+) {
+    let name = opts.common_opts.name.clone();
+    let histogram = HistogramVec::new(opts, labels)
+        .unwrap_or_else(|e| panic!("Failed to create {}: {:?}", name, e));
+    registry
+        .register(Box::new(histogram.clone()))
+        .unwrap_or_else(|e| panic!("Failed to register {}: {:?}", name, e));
+    cell.get_or_init(|| histogram);
+}
+
+fn register_gauge(registry: &Registry, cell: &OnceLock<IntGauge>, name: &str, help: &str) {
+    let gauge =
+        IntGauge::new(name, help).unwrap_or_else(|e| panic!("Failed to create {}: {:?}", name, e));
+    registry
+        .register(Box::new(gauge.clone()))
+        .unwrap_or_else(|e| panic!("Failed to register {}: {:?}", name, e));
+    cell.get_or_init(|| gauge);
+}
+
+/// `per_slot_gauges` controls gauges with one series per slot, which large rooms may not want
+pub fn init_metrics(registry: &Registry, per_slot_gauges: bool) {
+    register_counter(
+        registry,
+        &MESSAGE_COUNTER,
+        "apx_messages_total",
+        "Total number of messages processed",
         &["room_id", "slot", "message_type", "direction"],
+    );
+    register_counter(
+        registry,
+        &DENIAL_COUNTER,
+        "apx_denials_total",
+        "Total number of client commands denied by the proxy",
+        &["room_id", "slot", "denial"],
+    );
+    register_counter(
+        registry,
+        &EVENT_COUNTER,
+        "apx_events_total",
+        "Total number of room events published",
+        &["room_id", "event"],
+    );
+    register_counter(
+        registry,
+        &EVENTS_LAGGED_COUNTER,
+        "apx_events_lagged_total",
+        "Total number of room events skipped by lagging subscribers",
+        &["subscriber"],
+    );
+    // tungstenite inflates frames before handing them over and doesn't report compressed frame
+    // sizes, so there is no apx_wire_bytes_total. Payload bytes are labelled with the compression
+    // negotiated on the connection instead, comparing the two populations gives the savings.
+    register_counter(
+        registry,
+        &PAYLOAD_BYTES_COUNTER,
+        "apx_payload_bytes_total",
+        "Total number of uncompressed payload bytes exchanged with clients",
+        &["room_id", "direction", "compression"],
+    );
+    register_counter(
+        registry,
+        &UPSTREAM_REFUSAL_COUNTER,
+        "apx_upstream_refusals_total",
+        "Total number of errors in ConnectionRefused packets sent by upstream",
+        &["room_id", "error"],
+    );
+    register_counter(
+        registry,
+        &UPSTREAM_ADMISSION_COUNTER,
+        "apx_upstream_admissions_total",
+        "Total number of client connections by how they obtained an upstream connection",
+        &["outcome"],
+    );
+    register_counter(
+        registry,
+        &CONNECTION_ERROR_COUNTER,
+        "apx_connection_errors_total",
+        "Total number of proxied connections that ended with an error, by error kind",
+        &["room_id", "kind"],
+    );
+    register_counter(
+        registry,
+        &DISCONNECT_COUNTER,
+        "apx_disconnects_total",
+        "Total number of proxied connections that ended, by cause",
+        &["room_id", "cause"],
+    );
+    register_counter(
+        registry,
+        &UPSTREAM_PARSE_FAILURE_COUNTER,
+        "apx_upstream_parse_failures_total",
+        "Total number of upstream messages that couldn't be parsed",
+        &["room_id"],
+    );
+    register_counter(
+        registry,
+        &ROOMINFO_TIMEOUT_COUNTER,
+        "apx_roominfo_timeouts_total",
+        "Total number of upstream connections that never sent their RoomInfo",
+        &["room_id"],
+    );
+    register_counter(
+        registry,
+        &OVERSIZED_COMMAND_COUNTER,
+        "apx_oversized_commands_total",
+        "Total number of commands over their size limit, dropped from clients and forwarded from upstream",
+        &["room_id", "cmd", "direction"],
+    );
+    register_counter(
+        registry,
+        &SUPPRESSED_DEATHLINK_COUNTER,
+        "apx_suppressed_deathlinks_total",
+        "Total number of deathlinks held back from a client that would otherwise have received them",
+        &["room_id", "reason"],
+    );
+    register_counter(
+        registry,
+        &PRELOGIN_BUDGET_COUNTER,
+        "apx_prelogin_budget_exceeded_total",
+        "Total number of connections closed for sending too much before logging in",
+        &["room_id"],
+    );
+    register_counter(
+        registry,
+        &DROPPED_RESPONSE_COUNTER,
+        "apx_dropped_responses_total",
+        "Total number of messages queued for clients that were dropped instead of sent",
+        &["room_id", "reason"],
+    );
+    register_counter(
+        registry,
+        &PROBE_CONNECTION_COUNTER,
+        "apx_probe_connections_total",
+        "Total number of connections closed before sending anything, like TCP health checks",
+        &["listener"],
+    );
+    // Labels are normalized and capped by the fingerprint module, clients can't make them grow
+    register_counter(
+        registry,
+        &CLIENT_VERSION_COUNTER,
+        "apx_client_versions_total",
+        "Total number of logins by client game and version",
+        &["game", "version"],
+    );
+    register_counter(
+        registry,
+        &RECONNECT_COUNTER,
+        "apx_slot_reconnects_total",
+        "Total number of logins shortly after one of the slot's connections dropped",
+        &["room_id", "slot"],
+    );
+    register_counter(
+        registry,
+        &TASK_PANIC_COUNTER,
+        "apx_task_panics_total",
+        "Total number of background task panics, each is followed by a restart",
+        &["task"],
+    );
+    register_counter(
+        registry,
+        &POST_CLOSE_FRAME_COUNTER,
+        "apx_post_close_frames_total",
+        "Total number of frames dropped because their sender had already sent a Close",
+        &["direction"],
+    );
+    register_counter(
+        registry,
+        &DB_FLUSH_FAILURE_COUNTER,
+        "apx_db_flush_failures_total",
+        "Total number of batched inserts that failed, their rows are kept for the next one",
+        &["table"],
+    );
+    register_counter(
+        registry,
+        &LOGIN_QUEUE_COUNTER,
+        "apx_login_queue_total",
+        "Total number of logins that waited in the login queue, by how their wait ended",
+        &["room_id", "outcome"],
+    );
+    register_counter(
+        registry,
+        &REJECTED_ORIGIN_COUNTER,
+        "apx_rejected_origins_total",
+        "Total number of WebSocket upgrades refused for an Origin outside of ALLOWED_ORIGINS",
+        &["room_id"],
+    );
+    register_counter(
+        registry,
+        &LOBBY_FETCH_COUNTER,
+        "apx_lobby_fetches_total",
+        "Total number of slot password fetches from the lobby, by whether the lobby was asked and how it went",
+        &["outcome"],
+    );
+    register_counter(
+        registry,
+        &PASSWORD_REFRESH_REJECTED_COUNTER,
+        "apx_password_refresh_rejected_total",
+        "Total number of slot password refreshes refused for looking like a lobby bug, by why",
+        &["room_id", "reason"],
+    );
+    register_counter(
+        registry,
+        &WS_ERROR_COUNTER,
+        "apx_ws_errors_total",
+        "Total number of WebSocket errors that ended a connection, by the socket and the cause",
+        &["side", "cause"],
+    );
+    register_counter(
+        registry,
+        &SEND_RETRY_COUNTER,
+        "apx_send_retries_total",
+        "Total number of writes tried again after a transient socket error, by the socket",
+        &["side"],
+    );
+    register_counter(
+        registry,
+        &TRANSFORM_COUNTER,
+        "apx_transforms_total",
+        "Total number of packets changed by a rule of TRANSFORMS_PATH, by rule",
+        &["room_id", "rule"],
+    );
+    register_counter(
+        registry,
+        &JSON_LIMIT_COUNTER,
+        "apx_json_limit_violations_total",
+        "Total number of messages past a JSON parse limit, closing clients and logged from upstream",
+        &["room_id", "direction", "limit"],
+    );
+    register_histogram(
+        registry,
+        &DB_BATCH_ROWS_HISTOGRAM,
+        HistogramOpts::new("apx_db_batch_rows", "Number of rows in each batched insert")
+            .buckets(vec![1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0]),
+        &["table"],
+    );
+    register_histogram(
+        registry,
+        &DB_FLUSH_SECONDS_HISTOGRAM,
+        HistogramOpts::new("apx_db_flush_seconds", "Time taken by each batched insert"),
+        &["table"],
+    );
+    register_histogram(
+        registry,
+        &CHECK_TO_ITEM_HISTOGRAM,
+        HistogramOpts::new(
+            "apx_check_to_item_seconds",
+            "Time from a LocationChecks to upstream sending out the item of the location",
+        )
+        .buckets(vec![
+            0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+        ]),
+        &["room_id"],
+    );
+    register_gauge_vec(
+        registry,
+        &CHANNEL_DEPTH_GAUGE,
+        "apx_channel_depth",
+        "Number of messages waiting in internal channels",
+        &["channel"],
+    );
+    register_gauge_vec(
+        registry,
+        &TASK_LAST_RUN_GAUGE,
+        "apx_task_last_run_seconds",
+        "Unix time of the last heartbeat of each background task",
+        &["task"],
+    );
+    register_gauge_vec(
+        registry,
+        &LOGIN_QUEUE_GAUGE,
+        "apx_login_queue_length",
+        "Number of logins waiting for a free seat in the room",
+        &["room_id"],
+    );
+    register_gauge_vec(
+        registry,
+        &ROOM_RECENT_CHECKS_GAUGE,
+        "apx_room_checks_last_10m",
+        "Number of locations checked in the room over the last 10 minutes",
+        &["room_id"],
+    );
+    register_gauge_vec(
+        registry,
+        &UPSTREAM_PASSWORD_GAUGE,
+        "apx_upstream_password_required",
+        "Whether the upstream AP room asks for a password of its own, as of its last RoomInfo",
+        &["room_id"],
+    );
+    register_gauge(
+        registry,
+        &UPSTREAM_CONNECTIONS_GAUGE,
+        "apx_upstream_connections",
+        "Number of live upstream connections",
+    );
+    register_gauge(
+        registry,
+        &CONNECTION_TASKS_GAUGE,
+        "apx_connection_tasks",
+        "Number of running connection tasks, from the accept to the end of the connection",
+    );
+    register_gauge(
+        registry,
+        &CONNECTION_MEMORY_GAUGE,
+        "apx_connection_memory_bytes",
+        "Approximate bytes held by the buffers of every open connection",
+    );
+    register_gauge(
+        registry,
+        &TLS_CERT_EXPIRY_GAUGE,
+        "apx_tls_cert_expiry_timestamp_seconds",
+        "When the TLS certificate loaded from disk expires, as a Unix timestamp",
+    );
+    register_gauge(
+        registry,
+        &DEGRADED_MODE_GAUGE,
+        "apx_degraded_mode",
+        "Whether bulk upstream messages are forwarded without being parsed, to shed load",
+    );
+    let dropped = IntCounter::new(
+        "apx_metric_samples_dropped_total",
+        "Message and payload samples dropped because the metrics recorder fell behind",
     )
-    .expect("Failed to create message counter");
+    .expect("Failed to create apx_metric_samples_dropped_total");
+    registry
+        .register(Box::new(dropped.clone()))
+        .expect("Failed to register apx_metric_samples_dropped_total");
+    SAMPLES_DROPPED_COUNTER.get_or_init(|| dropped);
+
+    if per_slot_gauges {
+        let gauge = IntGaugeVec::new(
+            opts!(
+                "apx_slot_checked_locations",
+                "Number of locations checked by each slot"
+            ),
+            &["room_id", "slot"],
+        )
+        .expect("Failed to create apx_slot_checked_locations");
+        registry
+            .register(Box::new(gauge.clone()))
+            .expect("Failed to register apx_slot_checked_locations");
+        SLOT_CHECKED_LOCATIONS_GAUGE.get_or_init(|| gauge);
+
+        let gauge = IntGaugeVec::new(
+            opts!(
+                "apx_slot_items_received",
+                "Number of items received by each slot"
+            ),
+            &["room_id", "slot"],
+        )
+        .expect("Failed to create apx_slot_items_received");
+        registry
+            .register(Box::new(gauge.clone()))
+            .expect("Failed to register apx_slot_items_received");
+        SLOT_ITEMS_RECEIVED_GAUGE.get_or_init(|| gauge);
 
-    MESSAGE_COUNTER.get_or_init(|| counter.clone());
-    counter
+        let gauge = IntGaugeVec::new(
+            opts!(
+                "apx_slot_bandwidth_bytes",
+                "Bytes exchanged by each slot today, counted against the daily quota"
+            ),
+            &["room_id", "slot", "direction"],
+        )
+        .expect("Failed to create apx_slot_bandwidth_bytes");
+        registry
+            .register(Box::new(gauge.clone()))
+            .expect("Failed to register apx_slot_bandwidth_bytes");
+        SLOT_BANDWIDTH_GAUGE.get_or_init(|| gauge);
+
+        register_gauge_vec(
+            registry,
+            &SLOT_RECENT_CHECKS_GAUGE,
+            "apx_slot_checks_last_10m",
+            "Number of locations checked by each slot over the last 10 minutes",
+            &["room_id", "slot"],
+        );
+    }
+}
+
+/// Counted by forwarding loops for every message and frame. Looking up label sets takes the
+/// registry's lock, so they only queue a sample and the recorder thread does the rest.
+enum Sample {
+    Message {
+        room_id: Box<str>,
+        slot: SlotId,
+        /// Index in `MESSAGE_TYPES`, `None` for unknown types
+        message_type: Option<u8>,
+        direction: &'static str,
+    },
+    PayloadBytes {
+        room_id: Box<str>,
+        direction: &'static str,
+        compression: &'static str,
+        bytes: usize,
+    },
+}
+
+impl Sample {
+    fn record(self) {
+        match self {
+            Sample::Message {
+                room_id,
+                slot,
+                message_type,
+                direction,
+            } => {
+                let message_type = message_type.map_or("unknown", |i| MESSAGE_TYPES[i as usize]);
+                crate::stats::record_message(&room_id, slot, message_type, direction);
+                if let Some(counter) = MESSAGE_COUNTER.get() {
+                    counter
+                        .with_label_values(&[
+                            &room_id,
+                            &slot.0.to_string(),
+                            message_type,
+                            direction,
+                        ])
+                        .inc();
+                }
+            }
+            Sample::PayloadBytes {
+                room_id,
+                direction,
+                compression,
+                bytes,
+            } => {
+                if let Some(counter) = PAYLOAD_BYTES_COUNTER.get() {
+                    counter
+                        .with_label_values(&[&room_id, direction, compression])
+                        .inc_by(bytes as u64);
+                }
+            }
+        }
+    }
+}
+
+/// Hands the sample to the recorder thread, started on first use. Never blocks, a full queue
+/// drops the sample.
+fn queue(sample: Sample) {
+    let samples = SAMPLES.get_or_init(|| {
+        let (tx, rx) = mpsc::sync_channel::<Sample>(SAMPLE_QUEUE);
+        std::thread::Builder::new()
+            .name("metrics".to_string())
+            .spawn(move || {
+                for sample in rx {
+                    sample.record();
+                }
+            })
+            .expect("Failed to start the metrics recorder");
+        tx
+    });
+    if let Err(TrySendError::Full(_)) = samples.try_send(sample)
+        && let Some(counter) = SAMPLES_DROPPED_COUNTER.get()
+    {
+        counter.inc();
+    }
+}
+
+pub fn record_message(room_id: &str, slot: SlotId, message_type: &str, direction: &'static str) {
+    queue(Sample::Message {
+        room_id: room_id.into(),
+        slot,
+        message_type: MESSAGE_TYPES
+            .iter()
+            .position(|known| *known == message_type)
+            .map(|i| i as u8),
+        direction,
+    });
 }
 
-pub fn record_message(room_id: &str, slot: SlotId, message_type: &str, direction: &str) {
-    if let Some(counter) = MESSAGE_COUNTER.get() {
+pub fn record_denial(room_id: &str, slot: Option<SlotId>, denial: &str) {
+    if let Some(counter) = DENIAL_COUNTER.get() {
+        let slot = slot.map_or_else(|| "none".to_string(), |slot| slot.0.to_string());
+        counter.with_label_values(&[room_id, &slot, denial]).inc();
+    }
+}
+
+pub fn record_reconnect(room_id: &str, slot: SlotId) {
+    if let Some(counter) = RECONNECT_COUNTER.get() {
         counter
-            .with_label_values(&[room_id, &slot.0.to_string(), message_type, direction])
+            .with_label_values(&[room_id, &slot.0.to_string()])
             .inc();
     }
 }
+
+pub fn record_event(room_id: &str, event: &str) {
+    if let Some(counter) = EVENT_COUNTER.get() {
+        counter.with_label_values(&[room_id, event]).inc();
+    }
+}
+
+pub fn record_events_lagged(subscriber: &str, skipped: u64) {
+    if let Some(counter) = EVENTS_LAGGED_COUNTER.get() {
+        counter.with_label_values(&[subscriber]).inc_by(skipped);
+    }
+}
+
+pub fn record_payload_bytes(
+    room_id: &str,
+    direction: &'static str,
+    compression: &'static str,
+    bytes: usize,
+) {
+    queue(Sample::PayloadBytes {
+        room_id: room_id.into(),
+        direction,
+        compression,
+        bytes,
+    });
+}
+
+pub fn record_upstream_refusal(room_id: &str, error: &str) {
+    if let Some(counter) = UPSTREAM_REFUSAL_COUNTER.get() {
+        counter.with_label_values(&[room_id, error]).inc();
+    }
+}
+
+pub fn record_upstream_admission(outcome: &str) {
+    if let Some(counter) = UPSTREAM_ADMISSION_COUNTER.get() {
+        counter.with_label_values(&[outcome]).inc();
+    }
+}
+
+pub fn record_connection_error(room_id: &str, kind: &str) {
+    if let Some(counter) = CONNECTION_ERROR_COUNTER.get() {
+        counter.with_label_values(&[room_id, kind]).inc();
+    }
+}
+
+pub fn record_disconnect(room_id: &str, cause: &str) {
+    if let Some(counter) = DISCONNECT_COUNTER.get() {
+        counter.with_label_values(&[room_id, cause]).inc();
+    }
+}
+
+pub fn record_upstream_parse_failure(room_id: &str) {
+    if let Some(counter) = UPSTREAM_PARSE_FAILURE_COUNTER.get() {
+        counter.with_label_values(&[room_id]).inc();
+    }
+}
+
+pub fn record_roominfo_timeout(room_id: &str) {
+    if let Some(counter) = ROOMINFO_TIMEOUT_COUNTER.get() {
+        counter.with_label_values(&[room_id]).inc();
+    }
+}
+
+pub fn record_oversized_command(room_id: &str, cmd: &str, direction: &str) {
+    if let Some(counter) = OVERSIZED_COMMAND_COUNTER.get() {
+        counter.with_label_values(&[room_id, cmd, direction]).inc();
+    }
+}
+
+pub fn record_suppressed_deathlink(room_id: &str, reason: &str) {
+    if let Some(counter) = SUPPRESSED_DEATHLINK_COUNTER.get() {
+        counter.with_label_values(&[room_id, reason]).inc();
+    }
+}
+
+pub fn record_prelogin_budget_exceeded(room_id: &str) {
+    if let Some(counter) = PRELOGIN_BUDGET_COUNTER.get() {
+        counter.with_label_values(&[room_id]).inc();
+    }
+}
+
+pub fn record_dropped_responses(room_id: &str, reason: &str, count: usize) {
+    if let Some(counter) = DROPPED_RESPONSE_COUNTER.get() {
+        counter
+            .with_label_values(&[room_id, reason])
+            .inc_by(count as u64);
+    }
+}
+
+pub fn record_probe_connection(listener: &str) {
+    if let Some(counter) = PROBE_CONNECTION_COUNTER.get() {
+        counter.with_label_values(&[listener]).inc();
+    }
+}
+
+pub fn record_client_version(fingerprint: &crate::fingerprint::Fingerprint) {
+    if let Some(counter) = CLIENT_VERSION_COUNTER.get() {
+        counter
+            .with_label_values(&[&fingerprint.game, &fingerprint.version])
+            .inc();
+    }
+}
+
+pub fn record_task_panic(task: &str) {
+    if let Some(counter) = TASK_PANIC_COUNTER.get() {
+        counter.with_label_values(&[task]).inc();
+    }
+}
+
+pub fn record_post_close_frames(direction: &str, count: usize) {
+    if let Some(counter) = POST_CLOSE_FRAME_COUNTER.get() {
+        counter.with_label_values(&[direction]).inc_by(count as u64);
+    }
+}
+
+pub fn record_db_flush(table: &str, rows: usize, took: std::time::Duration, ok: bool) {
+    if let Some(histogram) = DB_BATCH_ROWS_HISTOGRAM.get() {
+        histogram.with_label_values(&[table]).observe(rows as f64);
+    }
+    if let Some(histogram) = DB_FLUSH_SECONDS_HISTOGRAM.get() {
+        histogram
+            .with_label_values(&[table])
+            .observe(took.as_secs_f64());
+    }
+    if !ok && let Some(counter) = DB_FLUSH_FAILURE_COUNTER.get() {
+        counter.with_label_values(&[table]).inc();
+    }
+}
+
+pub fn record_check_to_item(room_id: &str, took: std::time::Duration) {
+    if let Some(histogram) = CHECK_TO_ITEM_HISTOGRAM.get() {
+        histogram
+            .with_label_values(&[room_id])
+            .observe(took.as_secs_f64());
+    }
+}
+
+pub fn record_login_queue(room_id: &str, outcome: &str) {
+    if let Some(counter) = LOGIN_QUEUE_COUNTER.get() {
+        counter.with_label_values(&[room_id, outcome]).inc();
+    }
+}
+
+pub fn record_rejected_origin(room_id: &str) {
+    if let Some(counter) = REJECTED_ORIGIN_COUNTER.get() {
+        counter.with_label_values(&[room_id]).inc();
+    }
+}
+
+pub fn record_lobby_fetch(outcome: &str) {
+    if let Some(counter) = LOBBY_FETCH_COUNTER.get() {
+        counter.with_label_values(&[outcome]).inc();
+    }
+}
+
+pub fn record_password_refresh_rejected(room_id: &str, reason: &str) {
+    if let Some(counter) = PASSWORD_REFRESH_REJECTED_COUNTER.get() {
+        counter.with_label_values(&[room_id, reason]).inc();
+    }
+}
+
+pub fn record_ws_error(side: &str, cause: &str) {
+    if let Some(counter) = WS_ERROR_COUNTER.get() {
+        counter.with_label_values(&[side, cause]).inc();
+    }
+}
+
+pub fn record_send_retry(side: &str) {
+    if let Some(counter) = SEND_RETRY_COUNTER.get() {
+        counter.with_label_values(&[side]).inc();
+    }
+}
+
+pub fn record_transform(room_id: &str, rule: &str) {
+    if let Some(counter) = TRANSFORM_COUNTER.get() {
+        counter.with_label_values(&[room_id, rule]).inc();
+    }
+}
+
+pub fn record_json_limit(room_id: &str, direction: &str, limit: &str) {
+    if let Some(counter) = JSON_LIMIT_COUNTER.get() {
+        counter
+            .with_label_values(&[room_id, direction, limit])
+            .inc();
+    }
+}
+
+pub fn record_task_heartbeat(task: &str, at: chrono::DateTime<chrono::Utc>) {
+    if let Some(gauge) = TASK_LAST_RUN_GAUGE.get() {
+        gauge.with_label_values(&[task]).set(at.timestamp());
+    }
+}
+
+pub fn set_channel_depth(channel: &str, depth: usize) {
+    if let Some(gauge) = CHANNEL_DEPTH_GAUGE.get() {
+        gauge.with_label_values(&[channel]).set(depth as i64);
+    }
+}
+
+pub fn set_login_queue_length(room_id: &str, waiting: usize) {
+    if let Some(gauge) = LOGIN_QUEUE_GAUGE.get() {
+        gauge.with_label_values(&[room_id]).set(waiting as i64);
+    }
+}
+
+pub fn set_upstream_password_required(room_id: &str, required: bool) {
+    if let Some(gauge) = UPSTREAM_PASSWORD_GAUGE.get() {
+        gauge.with_label_values(&[room_id]).set(required as i64);
+    }
+}
+
+pub fn set_tls_cert_expiry(at: chrono::DateTime<chrono::Utc>) {
+    if let Some(gauge) = TLS_CERT_EXPIRY_GAUGE.get() {
+        gauge.set(at.timestamp());
+    }
+}
+
+pub fn set_degraded_mode(degraded: bool) {
+    if let Some(gauge) = DEGRADED_MODE_GAUGE.get() {
+        gauge.set(degraded as i64);
+    }
+}
+
+pub fn set_upstream_connections(live: usize) {
+    if let Some(gauge) = UPSTREAM_CONNECTIONS_GAUGE.get() {
+        gauge.set(live as i64);
+    }
+}
+
+pub fn set_connection_tasks(running: usize) {
+    if let Some(gauge) = CONNECTION_TASKS_GAUGE.get() {
+        gauge.set(running as i64);
+    }
+}
+
+/// By how much the memory held for connections changed, in bytes
+pub fn add_connection_memory(delta: i64) {
+    if let Some(gauge) = CONNECTION_MEMORY_GAUGE.get() {
+        gauge.add(delta);
+    }
+}
+
+pub fn set_slot_checked_locations(room_id: &str, slot: SlotId, checked: usize) {
+    if let Some(gauge) = SLOT_CHECKED_LOCATIONS_GAUGE.get() {
+        gauge
+            .with_label_values(&[room_id, &slot.0.to_string()])
+            .set(checked as i64);
+    }
+}
+
+pub fn set_slot_items_received(room_id: &str, slot: SlotId, received: usize) {
+    if let Some(gauge) = SLOT_ITEMS_RECEIVED_GAUGE.get() {
+        gauge
+            .with_label_values(&[room_id, &slot.0.to_string()])
+            .set(received as i64);
+    }
+}
+
+pub fn set_room_recent_checks(room_id: &str, checks: usize) {
+    if let Some(gauge) = ROOM_RECENT_CHECKS_GAUGE.get() {
+        gauge.with_label_values(&[room_id]).set(checks as i64);
+    }
+}
+
+pub fn set_slot_recent_checks(room_id: &str, slot: SlotId, checks: usize) {
+    if let Some(gauge) = SLOT_RECENT_CHECKS_GAUGE.get() {
+        gauge
+            .with_label_values(&[room_id, &slot.0.to_string()])
+            .set(checks as i64);
+    }
+}
+
+pub fn record_slot_bandwidth(
+    room_id: &str,
+    slot: SlotId,
+    bandwidth: &crate::bandwidth::SlotBandwidth,
+) {
+    if let Some(gauge) = SLOT_BANDWIDTH_GAUGE.get() {
+        let slot = slot.0.to_string();
+        gauge
+            .with_label_values(&[room_id, &slot, "client_to_upstream"])
+            .set(bandwidth.client_to_upstream as i64);
+        gauge
+            .with_label_values(&[room_id, &slot, "upstream_to_client"])
+            .set(bandwidth.upstream_to_client as i64);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, Instant};
+
+    /// The payload bytes recorded so far for `room_id`, as exported
+    pub(crate) fn payload_bytes(room_id: &str, direction: &str, compression: &str) -> u64 {
+        PAYLOAD_BYTES_COUNTER.get().map_or(0, |counter| {
+            counter
+                .with_label_values(&[room_id, direction, compression])
+                .get()
+        })
+    }
+
+    /// Messages are recorded in the background, this waits for the scrape to show them
+    fn scrape_when(registry: &Registry, ready: impl Fn(&str) -> bool) -> String {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let scrape = registry.encode().unwrap();
+            if ready(&scrape) || Instant::now() > deadline {
+                return scrape;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_proxy_and_http_metrics_share_one_scrape() {
+        let registry = Registry::new("room");
+        init_metrics(&registry, false);
+        let http = registry.http_metrics();
+
+        record_message("room", SlotId(1), "Say", "client_to_upstream");
+        http.http_requests_total()
+            .with_label_values(&["/api/slots", "GET", "200"])
+            .inc();
+
+        let scrape = scrape_when(&registry, |scrape| scrape.contains("apx_messages_total{"));
+        let samples: Vec<&str> = scrape.lines().filter(|l| !l.starts_with('#')).collect();
+        assert!(samples.iter().any(|l| l.starts_with("apx_messages_total{")));
+        assert!(
+            samples
+                .iter()
+                .any(|l| l.starts_with("apx_rocket_http_requests_total{")
+                    && l.contains(r#"room_id="room""#))
+        );
+        assert!(samples.iter().all(|l| l.starts_with("apx_")));
+    }
+
+    #[test]
+    fn test_unknown_message_types_share_a_label() {
+        let registry = Registry::new("room");
+        init_metrics(&registry, false);
+
+        record_message(
+            "unknown_types",
+            SlotId(1),
+            "Frobnicate",
+            "client_to_upstream",
+        );
+        let scrape = scrape_when(&registry, |scrape| scrape.contains("unknown_types"));
+        assert!(
+            scrape
+                .lines()
+                .any(|l| l.contains(r#"room_id="unknown_types""#)
+                    && l.contains(r#"message_type="unknown""#))
+        );
+    }
+
+    #[test]
+    fn test_login_queue_metrics() {
+        init_metrics(&Registry::new("room"), false);
+        // Whichever registry was first holds the metrics
+        let length = || {
+            LOGIN_QUEUE_GAUGE
+                .get()
+                .unwrap()
+                .with_label_values(&["queue_metrics"])
+                .get()
+        };
+
+        let queue = Arc::new(crate::login_queue::LoginQueue::new(
+            "queue_metrics",
+            Some(0),
+            Duration::from_secs(60),
+        ));
+        let first = queue.admit(1, "Alice");
+        let second = queue.admit(2, "Bob");
+        assert_eq!(length(), 2);
+        drop(first);
+        assert_eq!(length(), 1);
+        drop(second);
+        assert_eq!(length(), 0);
+
+        record_login_queue("queue_metrics", "timed_out");
+        let timed_out = LOGIN_QUEUE_COUNTER
+            .get()
+            .unwrap()
+            .with_label_values(&["queue_metrics", "timed_out"])
+            .get();
+        assert_eq!(timed_out, 1);
+    }
+
+    /// Compares forwarding a message straight into the registry with queueing it, while another
+    /// thread keeps the registry's lock busy creating label sets. Run with
+    /// `cargo test --release bench_record_message -- --ignored`, it fails with both timings if
+    /// queueing isn't the faster of the two.
+    #[test]
+    #[ignore]
+    fn bench_record_message_under_label_churn() {
+        const ITERATIONS: u32 = 200_000;
+        let registry = Registry::new("bench");
+        init_metrics(&registry, false);
+        let counter = MESSAGE_COUNTER.get().unwrap().clone();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let churn = {
+            let (stop, counter) = (stop.clone(), counter.clone());
+            std::thread::spawn(move || {
+                let mut slot = 0u64;
+                while !stop.load(Ordering::Relaxed) {
+                    slot += 1;
+                    counter
+                        .with_label_values(&[
+                            "churn",
+                            &slot.to_string(),
+                            "Say",
+                            "client_to_upstream",
+                        ])
+                        .inc();
+                }
+            })
+        };
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            counter
+                .with_label_values(&["bench", "1", "Say", "client_to_upstream"])
+                .inc();
+        }
+        let direct = start.elapsed() / ITERATIONS;
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            record_message("bench", SlotId(1), "Say", "client_to_upstream");
+        }
+        let queued = start.elapsed() / ITERATIONS;
+
+        stop.store(true, Ordering::Relaxed);
+        churn.join().unwrap();
+        assert!(
+            queued < direct,
+            "straight into the registry: {:?}/message, queued for the recorder: {:?}/message",
+            direct,
+            queued
+        );
+    }
+}
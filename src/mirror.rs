@@ -0,0 +1,573 @@
+use aprs_proto::primitives::SlotId;
+use futures_util::{SinkExt, StreamExt, sink, stream};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tungstenite::Message;
+
+use crate::proxy::{UpstreamRead, UpstreamWrite, reconnect_upstream};
+use crate::upstream::UpstreamServers;
+
+/// Messages a tracker may fall behind by before it's dropped from the mirror
+const TRACKER_QUEUE: usize = 256;
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+static NEXT_TRACKER: AtomicU64 = AtomicU64::new(0);
+
+/// Whether a client only follows the room rather than playing in it
+pub fn is_tracker(tags: &[String]) -> bool {
+    tags.iter().any(|tag| tag == "Tracker" || tag == "TextOnly")
+}
+
+/// Whether a tracker on a shared connection may send `cmd`. They only read from the room, what
+/// they'd change would be changed for the slot.
+pub fn is_read(cmd: &str) -> bool {
+    matches!(cmd, "Get" | "SetNotify" | "Sync" | "GetDataPackage")
+}
+
+fn cmd_of(cmd: &Value) -> Option<&str> {
+    cmd.get("cmd").and_then(Value::as_str)
+}
+
+fn string_set(values: Option<&Value>) -> BTreeSet<String> {
+    values
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(str::to_string)
+        .collect()
+}
+
+/// Who gets an upstream command
+#[derive(Debug, PartialEq)]
+enum Recipients {
+    All,
+    Only(Vec<u64>),
+}
+
+struct Route {
+    wants_items: bool,
+    notified: HashSet<String>,
+}
+
+/// Which tracker an answer on the shared connection is for. Requests are forwarded one by one,
+/// answers go back to whoever asked: Retrieved by the keys of the Get, SetReply to the trackers
+/// watching the key.
+#[derive(Default)]
+struct Router {
+    trackers: HashMap<u64, Route>,
+    gets: Vec<(u64, BTreeSet<String>)>,
+}
+
+impl Router {
+    fn attach(&mut self, tracker: u64, wants_items: bool) {
+        let route = Route {
+            wants_items,
+            notified: HashSet::new(),
+        };
+        self.trackers.insert(tracker, route);
+    }
+
+    fn detach(&mut self, tracker: u64) {
+        self.trackers.remove(&tracker);
+        self.gets.retain(|(asker, _)| *asker != tracker);
+    }
+
+    /// Forgets the requests of a connection that's gone, their answers won't come
+    fn reset(&mut self) {
+        self.gets.clear();
+    }
+
+    /// Keys any tracker watches, to watch again on a new connection
+    fn notified(&self) -> BTreeSet<String> {
+        self.trackers
+            .values()
+            .flat_map(|route| route.notified.iter().cloned())
+            .collect()
+    }
+
+    /// Notes what `tracker` asked for, and keeps the reads, the only commands that go upstream.
+    /// The shared connection's login is its own too.
+    fn outgoing(&mut self, tracker: u64, commands: Vec<Value>) -> Vec<Value> {
+        commands
+            .into_iter()
+            .filter(|cmd| match cmd_of(cmd) {
+                Some("Get") => {
+                    self.gets.push((tracker, string_set(cmd.get("keys"))));
+                    true
+                }
+                Some("SetNotify") => {
+                    if let Some(route) = self.trackers.get_mut(&tracker) {
+                        route.notified.extend(string_set(cmd.get("keys")));
+                    }
+                    true
+                }
+                Some(cmd) => is_read(cmd),
+                None => false,
+            })
+            .collect()
+    }
+
+    fn recipients(&mut self, cmd: &Value) -> Recipients {
+        match cmd_of(cmd) {
+            Some("Retrieved") => {
+                let keys = cmd
+                    .get("keys")
+                    .and_then(Value::as_object)
+                    .map(|keys| keys.keys().cloned().collect::<BTreeSet<_>>())
+                    .unwrap_or_default();
+                let asker = self.gets.iter().position(|(_, asked)| *asked == keys);
+                Recipients::Only(asker.map(|i| self.gets.remove(i).0).into_iter().collect())
+            }
+            Some("SetReply") => {
+                let key = cmd.get("key").and_then(Value::as_str).unwrap_or_default();
+                let trackers: BTreeSet<u64> = self
+                    .trackers
+                    .iter()
+                    .filter(|(_, route)| route.notified.contains(key))
+                    .map(|(tracker, _)| *tracker)
+                    .collect();
+                Recipients::Only(trackers.into_iter().collect())
+            }
+            Some("ReceivedItems") => Recipients::Only(
+                self.trackers
+                    .iter()
+                    .filter(|(_, route)| route.wants_items)
+                    .map(|(tracker, _)| *tracker)
+                    .collect(),
+            ),
+            _ => Recipients::All,
+        }
+    }
+}
+
+enum Control {
+    Attach {
+        tracker: u64,
+        wants_items: bool,
+        queue: mpsc::Sender<Message>,
+    },
+    Detach(u64),
+    Send {
+        tracker: u64,
+        text: String,
+    },
+}
+
+type MirrorKey = (String, SlotId);
+
+/// Shared upstream connections for the trackers of a slot, so they don't each hold one. Game
+/// clients are never attached, they keep their own connection.
+#[derive(Clone, Default)]
+pub struct TrackerMirrors(Arc<Mutex<HashMap<MirrorKey, mpsc::UnboundedSender<Control>>>>);
+
+impl TrackerMirrors {
+    /// Attaches a logged in tracker to the mirror of its slot, starting it with the tracker's own
+    /// Connect if there's none yet. Returns what replaces the tracker's upstream connection.
+    pub fn attach(
+        &self,
+        room_id: &str,
+        upstream: &Arc<UpstreamServers>,
+        slot: SlotId,
+        connect: &str,
+    ) -> (UpstreamWrite, UpstreamRead) {
+        let tracker = NEXT_TRACKER.fetch_add(1, Ordering::Relaxed);
+        let (queue, queued) = mpsc::channel(TRACKER_QUEUE);
+        let key = (room_id.to_string(), slot);
+
+        // Held while attaching, so a mirror can't stop with an attach on the way
+        let mut mirrors = self.0.lock().unwrap();
+        let control = match mirrors.get(&key) {
+            Some(control) if !control.is_closed() => control.clone(),
+            _ => {
+                log::info!("Starting the shared tracker connection for slot {}", slot.0);
+                let (control, controls) = mpsc::unbounded_channel();
+                let mirror = Mirror {
+                    key: key.clone(),
+                    upstream: upstream.clone(),
+                    connect: shared_connect(connect),
+                    mirrors: self.clone(),
+                    own: control.clone(),
+                    router: Router::default(),
+                    queues: HashMap::new(),
+                };
+                tokio::spawn(mirror.run(controls));
+                mirrors.insert(key, control.clone());
+                control
+            }
+        };
+        let _ = control.send(Control::Attach {
+            tracker,
+            wants_items: wants_items(connect),
+            queue,
+        });
+        drop(mirrors);
+
+        let detach = DetachOnDrop {
+            tracker,
+            control: control.clone(),
+        };
+        let read = stream::unfold((queued, detach), |(mut queued, detach)| async move {
+            let msg = queued.recv().await?;
+            Some((Ok(msg), (queued, detach)))
+        });
+        let write = sink::unfold(control, move |control, msg: Message| async move {
+            match msg {
+                Message::Text(text) => control
+                    .send(Control::Send {
+                        tracker,
+                        text: text.to_string(),
+                    })
+                    .map_err(|_| tungstenite::Error::ConnectionClosed)?,
+                Message::Close(_) => {
+                    let _ = control.send(Control::Detach(tracker));
+                }
+                _ => {}
+            }
+            Ok::<_, tungstenite::Error>(control)
+        });
+        (Box::pin(write), Box::pin(read))
+    }
+}
+
+/// Leaves the mirror once the tracker stops reading
+struct DetachOnDrop {
+    tracker: u64,
+    control: mpsc::UnboundedSender<Control>,
+}
+
+impl Drop for DetachOnDrop {
+    fn drop(&mut self) {
+        let _ = self.control.send(Control::Detach(self.tracker));
+    }
+}
+
+fn connect_commands(connect: &str) -> Vec<Value> {
+    serde_json::from_str(connect).unwrap_or_default()
+}
+
+fn wants_items(connect: &str) -> bool {
+    connect_commands(connect)
+        .iter()
+        .filter_map(|cmd| cmd.get("items_handling").and_then(Value::as_u64))
+        .any(|handling| handling != 0)
+}
+
+/// The Connect of the first tracker, asking for every item so any later one can have them
+fn shared_connect(connect: &str) -> String {
+    let mut commands = connect_commands(connect);
+    for cmd in &mut commands {
+        if let Some(obj) = cmd.as_object_mut() {
+            obj.insert("items_handling".into(), 7.into());
+        }
+    }
+    serde_json::to_string(&commands).unwrap_or_else(|_| connect.to_string())
+}
+
+struct Mirror {
+    key: MirrorKey,
+    upstream: Arc<UpstreamServers>,
+    connect: String,
+    mirrors: TrackerMirrors,
+    own: mpsc::UnboundedSender<Control>,
+    router: Router,
+    queues: HashMap<u64, mpsc::Sender<Message>>,
+}
+
+impl Mirror {
+    async fn run(mut self, mut controls: mpsc::UnboundedReceiver<Control>) {
+        let slot = self.key.1;
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let connection = reconnect_upstream(&self.upstream, &self.connect, Some(slot)).await;
+            let (mut write, mut read, remaining) = match connection {
+                Ok(connection) => connection,
+                Err(e) => {
+                    log::warn!(
+                        "Failed to connect the shared tracker connection for slot {}: {}",
+                        slot.0,
+                        e
+                    );
+                    let retry = tokio::time::sleep(backoff);
+                    tokio::pin!(retry);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    loop {
+                        tokio::select! {
+                            _ = &mut retry => break,
+                            Some(control) = controls.recv() => {
+                                self.handle(control, None).await;
+                                if self.finished(&controls) {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                    continue;
+                }
+            };
+            backoff = MIN_BACKOFF;
+            self.router.reset();
+            if let Some(Message::Text(text)) = remaining {
+                self.deliver(&text);
+            }
+            if self.resync(&mut write).await.is_err() {
+                continue;
+            }
+
+            loop {
+                tokio::select! {
+                    msg = read.next() => match msg {
+                        Some(Ok(Message::Text(text))) => self.deliver(&text),
+                        Some(Ok(Message::Close(_))) | None => {
+                            log::info!("Upstream closed the shared tracker connection for slot {}", slot.0);
+                            break;
+                        }
+                        Some(Err(e)) => {
+                            log::info!("Shared tracker connection for slot {} failed: {}", slot.0, e);
+                            break;
+                        }
+                        Some(Ok(_)) => {}
+                    },
+                    Some(control) = controls.recv() => {
+                        self.handle(control, Some(&mut write)).await;
+                    }
+                }
+                if self.finished(&controls) {
+                    let _ = write.close().await;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Sends the items and watched keys again, for whoever just joined or after reconnecting
+    async fn resync(&self, write: &mut UpstreamWrite) -> tungstenite::Result<()> {
+        let mut commands = vec![serde_json::json!({"cmd": "Sync"})];
+        let notified = self.router.notified();
+        if !notified.is_empty() {
+            commands.push(serde_json::json!({"cmd": "SetNotify", "keys": notified}));
+        }
+        let text = serde_json::to_string(&commands).expect("commands serialize");
+        write.send(Message::Text(text.into())).await
+    }
+
+    async fn handle(&mut self, control: Control, write: Option<&mut UpstreamWrite>) {
+        match control {
+            Control::Attach {
+                tracker,
+                wants_items,
+                queue,
+            } => {
+                self.router.attach(tracker, wants_items);
+                self.queues.insert(tracker, queue);
+                if let Some(write) = write {
+                    let _ = self.resync(write).await;
+                }
+            }
+            Control::Detach(tracker) => self.detach(tracker),
+            Control::Send { tracker, text } => {
+                let Some(write) = write else {
+                    log::debug!("Dropping tracker message, the shared connection is down");
+                    return;
+                };
+                let Ok(commands) = serde_json::from_str::<Vec<Value>>(&text) else {
+                    return;
+                };
+                let commands = self.router.outgoing(tracker, commands);
+                if commands.is_empty() {
+                    return;
+                }
+                let text = serde_json::to_string(&commands).expect("commands serialize");
+                let _ = write.send(Message::Text(text.into())).await;
+            }
+        }
+    }
+
+    fn detach(&mut self, tracker: u64) {
+        self.router.detach(tracker);
+        self.queues.remove(&tracker);
+    }
+
+    /// Hands upstream commands to the trackers they're for. Trackers that can't keep up are
+    /// dropped, their connection ends and they reconnect on their own.
+    fn deliver(&mut self, text: &str) {
+        let Ok(commands) = serde_json::from_str::<Vec<Value>>(text) else {
+            log::warn!("Invalid JSON on the shared tracker connection");
+            return;
+        };
+        let mut routed: HashMap<u64, Vec<&Value>> = HashMap::new();
+        let mut broadcast = true;
+        for cmd in &commands {
+            match self.router.recipients(cmd) {
+                Recipients::All => {
+                    for tracker in self.queues.keys() {
+                        routed.entry(*tracker).or_default().push(cmd);
+                    }
+                }
+                Recipients::Only(trackers) => {
+                    broadcast = false;
+                    for tracker in trackers {
+                        routed.entry(tracker).or_default().push(cmd);
+                    }
+                }
+            }
+        }
+
+        let mut dropped = Vec::new();
+        for (tracker, queue) in &self.queues {
+            let msg = if broadcast {
+                Message::Text(text.into())
+            } else {
+                let Some(commands) = routed.get(tracker) else {
+                    continue;
+                };
+                Message::Text(
+                    serde_json::to_string(commands)
+                        .expect("commands serialize")
+                        .into(),
+                )
+            };
+            match queue.try_send(msg) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    log::warn!("Dropping a tracker that fell behind the shared connection");
+                    dropped.push(*tracker);
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => dropped.push(*tracker),
+            }
+        }
+        for tracker in dropped {
+            self.detach(tracker);
+        }
+    }
+
+    /// Whether the last tracker left, in which case the mirror is gone for good
+    fn finished(&self, controls: &mpsc::UnboundedReceiver<Control>) -> bool {
+        if !self.queues.is_empty() {
+            return false;
+        }
+        let mut mirrors = self.mirrors.0.lock().unwrap();
+        // Someone attached in the meantime
+        if !controls.is_empty() {
+            return false;
+        }
+        if mirrors
+            .get(&self.key)
+            .is_some_and(|control| control.same_channel(&self.own))
+        {
+            mirrors.remove(&self.key);
+        }
+        log::info!(
+            "Closing the shared tracker connection for slot {}, no tracker is left",
+            self.key.1.0
+        );
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn router() -> Router {
+        let mut router = Router::default();
+        router.attach(1, true);
+        router.attach(2, false);
+        router
+    }
+
+    #[test]
+    fn test_trackers() {
+        assert!(is_tracker(&["Tracker".into()]));
+        assert!(is_tracker(&["DeathLink".into(), "TextOnly".into()]));
+        assert!(!is_tracker(&["DeathLink".into()]));
+    }
+
+    #[test]
+    fn test_retrieved_goes_to_whoever_asked_for_its_keys() {
+        let mut router = router();
+        let get = |keys: Value| vec![json!({"cmd": "Get", "keys": keys})];
+        assert_eq!(router.outgoing(1, get(json!(["a"]))).len(), 1);
+        router.outgoing(2, get(json!(["b", "c"])));
+
+        let retrieved = |keys: Value| json!({"cmd": "Retrieved", "keys": keys});
+        assert_eq!(
+            router.recipients(&retrieved(json!({"c": 1, "b": 2}))),
+            Recipients::Only(vec![2])
+        );
+        assert_eq!(
+            router.recipients(&retrieved(json!({"a": 1}))),
+            Recipients::Only(vec![1])
+        );
+        // Answered already
+        assert_eq!(
+            router.recipients(&retrieved(json!({"a": 1}))),
+            Recipients::Only(vec![])
+        );
+    }
+
+    #[test]
+    fn test_set_replies_go_to_watchers() {
+        let mut router = router();
+        router.attach(3, false);
+        router.outgoing(1, vec![json!({"cmd": "SetNotify", "keys": ["k"]})]);
+        let reply = json!({"cmd": "SetReply", "key": "k", "value": 1});
+        assert_eq!(router.recipients(&reply), Recipients::Only(vec![1]));
+        assert_eq!(router.notified(), BTreeSet::from(["k".to_string()]));
+    }
+
+    #[test]
+    fn test_items_and_broadcasts() {
+        let mut router = router();
+        assert_eq!(
+            router.recipients(&json!({"cmd": "ReceivedItems", "index": 0, "items": []})),
+            Recipients::Only(vec![1])
+        );
+        for cmd in ["PrintJSON", "RoomUpdate", "Bounced", "RoomInfo"] {
+            assert_eq!(router.recipients(&json!({"cmd": cmd})), Recipients::All);
+        }
+    }
+
+    #[test]
+    fn test_detached_trackers_get_no_answers() {
+        let mut router = router();
+        router.outgoing(2, vec![json!({"cmd": "Get", "keys": ["a"]})]);
+        router.outgoing(1, vec![json!({"cmd": "Get", "keys": ["a"]})]);
+        router.detach(2);
+        assert_eq!(
+            router.recipients(&json!({"cmd": "Retrieved", "keys": {"a": 1}})),
+            Recipients::Only(vec![1])
+        );
+    }
+
+    #[test]
+    fn test_only_reads_go_upstream() {
+        let mut router = router();
+        let forwarded = router.outgoing(
+            1,
+            vec![
+                json!({"cmd": "ConnectUpdate", "tags": []}),
+                json!({"cmd": "Set", "key": "k", "operations": []}),
+                json!({"cmd": "LocationChecks", "locations": [1]}),
+                json!({"cmd": "Say", "text": "hi"}),
+                json!({"cmd": "Sync"}),
+            ],
+        );
+        assert_eq!(forwarded, vec![json!({"cmd": "Sync"})]);
+    }
+
+    #[test]
+    fn test_shared_connect_asks_for_every_item() {
+        let connect = r#"[{"cmd":"Connect","name":"Alice","items_handling":0}]"#;
+        assert!(!wants_items(connect));
+        let shared = shared_connect(connect);
+        assert!(wants_items(&shared));
+        assert_eq!(connect_commands(&shared)[0]["name"], "Alice");
+    }
+}
@@ -0,0 +1,188 @@
+use serde_json::Value;
+
+use crate::proto::{JSONMessagePart, PrintJSON};
+
+pub const MOTD_SETTING_KEY: &str = "motd";
+pub const MAX_MOTD_LENGTH: usize = 2000;
+
+/// Colors understood by Archipelago clients for `color` message parts
+const COLORS: &[&str] = &[
+    "bold",
+    "underline",
+    "black",
+    "red",
+    "green",
+    "yellow",
+    "blue",
+    "magenta",
+    "cyan",
+    "white",
+    "black_bg",
+    "red_bg",
+    "green_bg",
+    "yellow_bg",
+    "blue_bg",
+    "magenta_bg",
+    "cyan_bg",
+    "white_bg",
+];
+
+pub fn is_color(name: &str) -> bool {
+    COLORS.contains(&name)
+}
+
+/// Truncates the motd to `MAX_MOTD_LENGTH` characters
+pub fn cap_length(text: &str) -> &str {
+    match text.char_indices().nth(MAX_MOTD_LENGTH) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+fn plain_part(text: &str) -> JSONMessagePart {
+    JSONMessagePart {
+        text: text.to_string(),
+        type_: None,
+        color: None,
+    }
+}
+
+fn color_part(text: &str, color: &str) -> JSONMessagePart {
+    JSONMessagePart {
+        text: text.to_string(),
+        type_: Some("color".to_string()),
+        color: Some(color.to_string()),
+    }
+}
+
+/// Parses `[color]text[/color]` markup into message parts. Tags don't nest, and anything that
+/// isn't a known, closed color tag is kept as literal text.
+pub fn parse_markup(line: &str) -> Vec<JSONMessagePart> {
+    let mut parts = Vec::new();
+    let mut plain_start = 0;
+    let mut cursor = 0;
+
+    while let Some(offset) = line[cursor..].find('[') {
+        let open = cursor + offset;
+        let tag = line[open + 1..]
+            .find(']')
+            .map(|end| &line[open + 1..open + 1 + end])
+            .filter(|tag| COLORS.contains(tag));
+
+        let Some(color) = tag else {
+            cursor = open + 1;
+            continue;
+        };
+
+        let content_start = open + color.len() + 2;
+        let closing = format!("[/{}]", color);
+        let Some(content_len) = line[content_start..].find(&closing) else {
+            cursor = open + 1;
+            continue;
+        };
+
+        if open > plain_start {
+            parts.push(plain_part(&line[plain_start..open]));
+        }
+        let content = &line[content_start..content_start + content_len];
+        if !content.is_empty() {
+            parts.push(color_part(content, color));
+        }
+
+        cursor = content_start + content_len + closing.len();
+        plain_start = cursor;
+    }
+
+    if plain_start < line.len() {
+        parts.push(plain_part(&line[plain_start..]));
+    }
+
+    parts
+}
+
+/// Builds the PrintJSON messages sent after login, one per non-empty line
+pub fn build_messages(text: &str) -> Vec<Value> {
+    cap_length(text)
+        .lines()
+        .map(parse_markup)
+        .filter(|parts| !parts.is_empty())
+        .map(|data| {
+            serde_json::to_value(PrintJSON {
+                cmd: "PrintJSON".to_string(),
+                data,
+                type_: None,
+                tags: Vec::new(),
+            })
+            .unwrap()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(parts: &[JSONMessagePart]) -> Vec<(&str, Option<&str>)> {
+        parts
+            .iter()
+            .map(|p| (p.text.as_str(), p.color.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn test_plain_text() {
+        let parts = parse_markup("Welcome to the race");
+        assert_eq!(texts(&parts), vec![("Welcome to the race", None)]);
+    }
+
+    #[test]
+    fn test_color_markup() {
+        let parts = parse_markup("Rules: [red]no countdowns[/red], have [green]fun[/green]!");
+        assert_eq!(
+            texts(&parts),
+            vec![
+                ("Rules: ", None),
+                ("no countdowns", Some("red")),
+                (", have ", None),
+                ("fun", Some("green")),
+                ("!", None),
+            ]
+        );
+        assert_eq!(parts[1].type_.as_deref(), Some("color"));
+    }
+
+    #[test]
+    fn test_unknown_and_unclosed_tags_are_literal() {
+        assert_eq!(
+            texts(&parse_markup("[info] see [red]the rules")),
+            vec![("[info] see [red]the rules", None)]
+        );
+        assert_eq!(
+            texts(&parse_markup("[[red]x[/red]")),
+            vec![("[", None), ("x", Some("red"))]
+        );
+    }
+
+    #[test]
+    fn test_multiline_becomes_separate_messages() {
+        let messages = build_messages("Line one\n\n[yellow]Line two[/yellow]\n");
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["cmd"], "PrintJSON");
+        assert_eq!(messages[0]["data"][0]["text"], "Line one");
+        assert_eq!(messages[1]["data"][0]["text"], "Line two");
+        assert_eq!(messages[1]["data"][0]["color"], "yellow");
+    }
+
+    #[test]
+    fn test_length_is_capped() {
+        let long = "é".repeat(MAX_MOTD_LENGTH + 10);
+        assert_eq!(cap_length(&long).chars().count(), MAX_MOTD_LENGTH);
+        assert_eq!(cap_length("short"), "short");
+    }
+
+    #[test]
+    fn test_empty_motd() {
+        assert!(build_messages("").is_empty());
+        assert!(build_messages("\n\n").is_empty());
+    }
+}
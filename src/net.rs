@@ -0,0 +1,229 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::{TcpListener, TcpStream};
+
+/// First byte of a TLS handshake record
+const TLS_HANDSHAKE: u8 = 0x16;
+
+/// Binds a listening socket. IPv6 sockets are made v6-only so that they can coexist with an
+/// IPv4 socket on the same port when listening dual-stack.
+pub fn bind_listener(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Whether an address is only reachable from the host or a private network. Unspecified
+/// addresses listen on every interface, public ones included.
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => v4.is_loopback() || v4.is_private() || v4.is_link_local(),
+        IpAddr::V6(v6) => v6.is_loopback() || v6.is_unique_local() || v6.is_unicast_link_local(),
+    }
+}
+
+/// Whether an address belongs to this host, which is only the case if something can be bound
+/// to it
+pub fn is_local(ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    ip.is_loopback() || ip.is_unspecified() || std::net::UdpSocket::bind((ip, 0)).is_ok()
+}
+
+/// What connections are logged as arriving over, IPv4-mapped IPv6 addresses count as IPv4
+pub fn family(ip: IpAddr) -> &'static str {
+    match ip.to_canonical() {
+        IpAddr::V4(_) => "ipv4",
+        IpAddr::V6(_) => "ipv6",
+    }
+}
+
+/// How connections are told apart between WS and WSS
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TlsDetection {
+    /// Peek at the first byte and accept both
+    Auto,
+    /// Only accept TLS connections
+    Always,
+    /// Treat every connection as plain WS without peeking
+    Never,
+}
+
+#[derive(Debug)]
+pub struct InvalidTlsDetection(String);
+
+impl std::fmt::Display for InvalidTlsDetection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected auto, always or never, got {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidTlsDetection {}
+
+impl std::str::FromStr for TlsDetection {
+    type Err = InvalidTlsDetection;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "auto" => Ok(TlsDetection::Auto),
+            "always" => Ok(TlsDetection::Always),
+            "never" => Ok(TlsDetection::Never),
+            _ => Err(InvalidTlsDetection(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Sniffed {
+    Tls,
+    Plain,
+    /// Not a TLS handshake while TLS is required
+    NotTls,
+    /// Closed or reset before sending anything, which is what TCP health checks do
+    Probe,
+}
+
+pub async fn sniff(socket: &TcpStream, mode: TlsDetection) -> Sniffed {
+    if mode == TlsDetection::Never {
+        return Sniffed::Plain;
+    }
+
+    let mut buf = [0u8; 1];
+    match socket.peek(&mut buf).await {
+        Ok(0) => Sniffed::Probe,
+        Err(e) => {
+            log::debug!("Failed to peek at connection: {:?}", e);
+            Sniffed::Probe
+        }
+        Ok(_) if buf[0] == TLS_HANDSHAKE => Sniffed::Tls,
+        Ok(_) if mode == TlsDetection::Always => Sniffed::NotTls,
+        Ok(_) => Sniffed::Plain,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_family() {
+        assert_eq!(family("192.0.2.10".parse().unwrap()), "ipv4");
+        assert_eq!(family("::ffff:192.0.2.10".parse().unwrap()), "ipv4");
+        assert_eq!(family("2001:db8:1:2::1".parse().unwrap()), "ipv6");
+    }
+
+    #[test]
+    fn test_private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "::1",
+            "fd00::1",
+        ] {
+            assert!(is_private(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["0.0.0.0", "::", "192.0.2.10", "172.32.0.1", "2001:db8::1"] {
+            assert!(!is_private(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(is_private("::ffff:10.0.0.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_connection_over_ipv6_loopback() {
+        let listener = bind_listener("[::1]:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"ping").await.unwrap();
+        });
+
+        let (mut socket, peer) = listener.accept().await.unwrap();
+        assert!(peer.is_ipv6());
+        assert_eq!(family(peer.ip()), "ipv6");
+
+        let mut buf = [0u8; 4];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        client.await.unwrap();
+    }
+
+    /// Connects a raw TCP client sending `first` (or closing right away) and sniffs it
+    async fn sniff_client(first: Option<&'static [u8]>, mode: TlsDetection) -> Sniffed {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            if let Some(first) = first {
+                stream.write_all(first).await.unwrap();
+                // Kept open until the server is done peeking
+                let _ = stream.read(&mut [0u8; 1]).await;
+            }
+        });
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let sniffed = sniff(&socket, mode).await;
+        drop(socket);
+        client.await.unwrap();
+        sniffed
+    }
+
+    const CLIENT_HELLO: &[u8] = &[0x16, 0x03, 0x01];
+    const UPGRADE: &[u8] = b"GET / HTTP/1.1";
+
+    #[tokio::test]
+    async fn test_sniff_auto() {
+        let mode = TlsDetection::Auto;
+        assert_eq!(sniff_client(Some(CLIENT_HELLO), mode).await, Sniffed::Tls);
+        assert_eq!(sniff_client(Some(UPGRADE), mode).await, Sniffed::Plain);
+        assert_eq!(sniff_client(None, mode).await, Sniffed::Probe);
+    }
+
+    #[tokio::test]
+    async fn test_sniff_always() {
+        let mode = TlsDetection::Always;
+        assert_eq!(sniff_client(Some(CLIENT_HELLO), mode).await, Sniffed::Tls);
+        assert_eq!(sniff_client(Some(UPGRADE), mode).await, Sniffed::NotTls);
+        assert_eq!(sniff_client(None, mode).await, Sniffed::Probe);
+    }
+
+    #[tokio::test]
+    async fn test_sniff_never() {
+        let mode = TlsDetection::Never;
+        assert_eq!(sniff_client(Some(CLIENT_HELLO), mode).await, Sniffed::Plain);
+        assert_eq!(sniff_client(Some(UPGRADE), mode).await, Sniffed::Plain);
+        // Nothing is read, probes are left to fail the WebSocket handshake
+        assert_eq!(sniff_client(None, mode).await, Sniffed::Plain);
+    }
+
+    #[test]
+    fn test_parse_tls_detection() {
+        assert_eq!("auto".parse::<TlsDetection>().unwrap(), TlsDetection::Auto);
+        assert_eq!(
+            " Always".parse::<TlsDetection>().unwrap(),
+            TlsDetection::Always
+        );
+        assert_eq!(
+            "never".parse::<TlsDetection>().unwrap(),
+            TlsDetection::Never
+        );
+        assert!("sometimes".parse::<TlsDetection>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_same_port() {
+        let v4 = bind_listener("127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6 = bind_listener(format!("[::1]:{}", port).parse().unwrap()).unwrap();
+        assert_eq!(v6.local_addr().unwrap().port(), port);
+    }
+}
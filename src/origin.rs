@@ -0,0 +1,107 @@
+#[derive(Debug)]
+pub struct InvalidOrigins(String);
+
+impl std::fmt::Display for InvalidOrigins {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid allowed origin {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidOrigins {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Pattern {
+    Exact(String),
+    /// `scheme://*.domain`, kept as `scheme://` and `.domain`
+    Subdomains {
+        scheme: String,
+        suffix: String,
+    },
+}
+
+/// Origins browsers may open a WebSocket from, so pages elsewhere can't use the room to phish
+/// for passwords. Clients outside of browsers don't send an Origin and are always let in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AllowedOrigins(Vec<Pattern>);
+
+/// Comma separated, like `https://archipelago.gg, https://*.example.com`. `*.` matches any
+/// subdomain, but not the domain itself.
+impl std::str::FromStr for AllowedOrigins {
+    type Err = InvalidOrigins;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(|origin| {
+                let invalid = || InvalidOrigins(origin.to_string());
+                let origin = origin.to_ascii_lowercase();
+                let Some((scheme, host)) = origin.split_once("://") else {
+                    return Err(invalid());
+                };
+                if scheme.is_empty() || host.is_empty() || host.contains('/') {
+                    return Err(invalid());
+                }
+                match host.strip_prefix('*') {
+                    Some(suffix) if suffix.starts_with('.') && !suffix.contains('*') => {
+                        Ok(Pattern::Subdomains {
+                            scheme: format!("{}://", scheme),
+                            suffix: suffix.to_string(),
+                        })
+                    }
+                    Some(_) => Err(invalid()),
+                    None if host.contains('*') => Err(invalid()),
+                    None => Ok(Pattern::Exact(origin)),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl AllowedOrigins {
+    pub fn allows(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        self.0.iter().any(|pattern| match pattern {
+            Pattern::Exact(allowed) => origin == *allowed,
+            Pattern::Subdomains { scheme, suffix } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|host| host.strip_suffix(suffix.as_str()))
+                .is_some_and(|subdomain| {
+                    !subdomain.is_empty() && !subdomain.contains(['/', ':', '@'])
+                }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_origins() {
+        let allowed: AllowedOrigins = "https://archipelago.gg, https://*.example.com:8443"
+            .parse()
+            .unwrap();
+        assert!(allowed.allows("https://archipelago.gg"));
+        assert!(allowed.allows("HTTPS://Archipelago.GG"));
+        assert!(!allowed.allows("http://archipelago.gg"));
+        assert!(!allowed.allows("https://archipelago.gg.evil.com"));
+
+        assert!(allowed.allows("https://tracker.example.com:8443"));
+        assert!(allowed.allows("https://a.b.example.com:8443"));
+        assert!(!allowed.allows("https://example.com:8443"));
+        assert!(!allowed.allows("https://tracker.example.com"));
+        assert!(!allowed.allows("https://evil.com/.example.com:8443"));
+        assert!(!allowed.allows("null"));
+
+        for invalid in [
+            "archipelago.gg",
+            "https://",
+            "https://a*.example.com",
+            "https://*",
+        ] {
+            assert!(invalid.parse::<AllowedOrigins>().is_err(), "{}", invalid);
+        }
+    }
+}
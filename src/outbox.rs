@@ -0,0 +1,362 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+use tokio::sync::Notify;
+use tungstenite::Message;
+
+use crate::memory::{Account, MemoryBudget};
+use crate::metrics;
+use crate::registry::ClientResponse;
+
+/// Bounds on what the proxy queues for a client on top of the upstream traffic
+#[derive(Clone, Copy, Debug)]
+pub struct ResponseLimits {
+    /// Queued messages beyond which the oldest non-critical one is dropped
+    pub queue_capacity: usize,
+    /// Most messages a single synthesized batch may carry
+    pub max_batch: usize,
+    /// Share of the bytes sent to a client that synthesized messages may take
+    pub synthesized_share: f64,
+    /// Synthesized bytes allowed on top of that share, so the first notices of a connection
+    /// aren't dropped because nothing else was sent yet
+    pub synthesized_burst: usize,
+}
+
+impl Default for ResponseLimits {
+    fn default() -> Self {
+        Self {
+            queue_capacity: 64,
+            max_batch: 20,
+            synthesized_share: 0.25,
+            synthesized_burst: 64 * 1024,
+        }
+    }
+}
+
+/// Datapackage responses and pongs are answers the client is waiting on, they are never dropped.
+/// Bounces and notices are.
+fn is_critical(response: &ClientResponse) -> bool {
+    matches!(response, ClientResponse::Raw(_) | ClientResponse::Pong(_))
+}
+
+/// About what `response` holds while queued, the bytes it's written as
+fn approximate_size(response: &ClientResponse) -> usize {
+    match response {
+        ClientResponse::Values(values) => serde_json::to_vec(values).map_or(0, |text| text.len()),
+        ClientResponse::Raw(raw) | ClientResponse::Bounced(raw) => raw.len(),
+        ClientResponse::Pong(data) => data.len(),
+    }
+}
+
+/// Every live queue, for diagnostics. Entries of closed connections are pruned as queues are
+/// created and listed.
+static QUEUES: LazyLock<Mutex<Vec<Weak<Shared>>>> = LazyLock::new(|| Mutex::new(Vec::new()));
+
+struct Shared {
+    /// With the size each response was accounted for
+    queue: Mutex<VecDeque<(ClientResponse, usize)>>,
+    notify: Notify,
+    closed: AtomicBool,
+    /// Everything written to the client, queued or forwarded from upstream
+    outbound_bytes: AtomicU64,
+    limits: ResponseLimits,
+    room_id: String,
+    memory: Arc<MemoryBudget>,
+}
+
+impl Shared {
+    fn pop(&self) -> Option<ClientResponse> {
+        let (response, size) = self.queue.lock().unwrap().pop_front()?;
+        self.memory.remove(Account::Outbox, size);
+        Some(response)
+    }
+}
+
+/// Sending half of a connection's response queue. Sending never waits, a client that doesn't
+/// read loses its oldest non-critical messages instead.
+#[derive(Clone)]
+pub struct ResponseSender(Arc<Shared>);
+
+pub struct ResponseReceiver {
+    shared: Arc<Shared>,
+    synthesized_bytes: u64,
+}
+
+pub fn response_queue(
+    limits: ResponseLimits,
+    room_id: &str,
+    memory: Arc<MemoryBudget>,
+) -> (ResponseSender, ResponseReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::new()),
+        notify: Notify::new(),
+        closed: AtomicBool::new(false),
+        outbound_bytes: AtomicU64::new(0),
+        limits,
+        room_id: room_id.to_string(),
+        memory,
+    });
+    let mut queues = QUEUES.lock().unwrap();
+    queues.retain(|queue| queue.strong_count() > 0);
+    queues.push(Arc::downgrade(&shared));
+    drop(queues);
+    let receiver = ResponseReceiver {
+        shared: shared.clone(),
+        synthesized_bytes: 0,
+    };
+    (ResponseSender(shared), receiver)
+}
+
+/// Number of queued responses of each open connection
+pub fn queue_depths() -> Vec<usize> {
+    let mut queues = QUEUES.lock().unwrap();
+    queues.retain(|queue| queue.strong_count() > 0);
+    queues
+        .iter()
+        .filter_map(Weak::upgrade)
+        .filter(|shared| !shared.closed.load(Ordering::Relaxed))
+        .map(|shared| shared.queue.lock().unwrap().len())
+        .collect()
+}
+
+impl ResponseSender {
+    /// Queues `response`, returns false once the connection is gone
+    pub fn send(&self, mut response: ClientResponse) -> bool {
+        let shared = &*self.0;
+        if shared.closed.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        if let ClientResponse::Values(values) = &mut response
+            && values.len() > shared.limits.max_batch
+        {
+            let dropped = values.len() - shared.limits.max_batch;
+            values.truncate(shared.limits.max_batch);
+            metrics::record_dropped_responses(&shared.room_id, "batch_cap", dropped);
+        }
+
+        let mut queue = shared.queue.lock().unwrap();
+        if !is_critical(&response) {
+            while queue.len() >= shared.limits.queue_capacity {
+                metrics::record_dropped_responses(&shared.room_id, "queue_full", 1);
+                match queue.iter().position(|(queued, _)| !is_critical(queued)) {
+                    Some(oldest) => {
+                        if let Some((_, size)) = queue.remove(oldest) {
+                            shared.memory.remove(Account::Outbox, size);
+                        }
+                    }
+                    // Only critical messages are queued, the new one goes
+                    None => return true,
+                }
+            }
+        }
+        let size = approximate_size(&response);
+        shared.memory.add(Account::Outbox, size);
+        queue.push_back((response, size));
+        drop(queue);
+        shared.notify.notify_one();
+        true
+    }
+
+    /// Accounts for a message written to the client, whatever its origin
+    pub fn record_outbound(&self, bytes: usize) {
+        self.0
+            .outbound_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl ResponseReceiver {
+    /// Next message to write to the client. Synthesized messages going over their share of the
+    /// outbound traffic are dropped here rather than when queued, the share is only known once
+    /// the messages before them have been written.
+    pub async fn recv(&mut self) -> Message {
+        loop {
+            let Some(response) = self.shared.pop() else {
+                self.shared.notify.notified().await;
+                continue;
+            };
+
+            match response {
+                ClientResponse::Values(values) => {
+                    let text = serde_json::to_string(&values).unwrap();
+                    if !self.allow_synthesized(text.len()) {
+                        metrics::record_dropped_responses(&self.shared.room_id, "rate", 1);
+                        continue;
+                    }
+                    return Message::Text(text.into());
+                }
+                ClientResponse::Raw(raw) | ClientResponse::Bounced(raw) => {
+                    return Message::Text((*raw).into());
+                }
+                ClientResponse::Pong(data) => return Message::Pong(data),
+            }
+        }
+    }
+
+    fn allow_synthesized(&mut self, bytes: usize) -> bool {
+        let limits = &self.shared.limits;
+        let outbound = self.shared.outbound_bytes.load(Ordering::Relaxed);
+        let allowed =
+            (outbound as f64 * limits.synthesized_share) as u64 + limits.synthesized_burst as u64;
+        if self.synthesized_bytes + bytes as u64 > allowed {
+            return false;
+        }
+        self.synthesized_bytes += bytes as u64;
+        true
+    }
+}
+
+impl Drop for ResponseReceiver {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Relaxed);
+        // Nothing reads what's left anymore
+        while self.shared.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn notice(i: usize) -> ClientResponse {
+        ClientResponse::Values(vec![
+            json!({"cmd": "PrintJSON", "data": [{"text": i.to_string()}]}),
+        ])
+    }
+
+    fn text(message: Message) -> String {
+        let Message::Text(text) = message else {
+            panic!("Expected a text message, got {:?}", message);
+        };
+        text.to_string()
+    }
+
+    fn unlimited() -> Arc<MemoryBudget> {
+        Arc::new(MemoryBudget::new(None))
+    }
+
+    const LIMITS: ResponseLimits = ResponseLimits {
+        queue_capacity: 4,
+        max_batch: 2,
+        synthesized_share: 0.5,
+        synthesized_burst: 0,
+    };
+
+    #[tokio::test]
+    async fn test_slow_client_drops_oldest_non_critical() {
+        let (sender, mut receiver) = response_queue(
+            ResponseLimits {
+                synthesized_burst: usize::MAX / 2,
+                ..LIMITS
+            },
+            "test",
+            unlimited(),
+        );
+
+        let writer = tokio::spawn(async move {
+            let mut written = Vec::new();
+            loop {
+                let message = receiver.recv().await;
+                let text = text(message);
+                let done = text == "done";
+                written.push(text);
+                if done {
+                    return written;
+                }
+                // Slow client
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        });
+
+        for i in 0..50 {
+            tokio::task::yield_now().await;
+            assert!(sender.send(notice(i)));
+            if i == 25 {
+                assert!(sender.send(ClientResponse::Raw("datapackage".into())));
+            }
+        }
+        assert!(sender.send(ClientResponse::Raw("done".into())));
+        assert!(sender.0.queue.lock().unwrap().len() <= LIMITS.queue_capacity + 1);
+
+        let written = writer.await.unwrap();
+        assert!(written.len() < 10, "{:?}", written);
+        assert!(written.contains(&"datapackage".to_string()));
+        // The most recent notices survive
+        assert!(written.iter().any(|text| text.contains("\"49\"")));
+    }
+
+    #[tokio::test]
+    async fn test_critical_messages_are_never_dropped() {
+        let (sender, mut receiver) = response_queue(LIMITS, "test", unlimited());
+        for i in 0..10 {
+            sender.send(ClientResponse::Raw(format!("raw{}", i).into()));
+        }
+        sender.send(notice(0));
+
+        sender.record_outbound(1 << 20);
+        for i in 0..10 {
+            assert_eq!(text(receiver.recv().await), format!("raw{}", i));
+        }
+        assert!(sender.0.queue.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_capped() {
+        let (sender, mut receiver) = response_queue(LIMITS, "test", unlimited());
+        sender.record_outbound(1 << 20);
+        sender.send(ClientResponse::Values(vec![json!(1), json!(2), json!(3)]));
+        assert_eq!(text(receiver.recv().await), "[1,2]");
+    }
+
+    #[tokio::test]
+    async fn test_synthesized_share() {
+        let (sender, mut receiver) = response_queue(LIMITS, "test", unlimited());
+
+        // Nothing was sent yet, no room for synthesized messages
+        assert!(!receiver.allow_synthesized(1));
+
+        sender.record_outbound(100);
+        assert!(receiver.allow_synthesized(30));
+        assert!(receiver.allow_synthesized(20));
+        assert!(!receiver.allow_synthesized(1));
+
+        sender.record_outbound(100);
+        assert!(receiver.allow_synthesized(50));
+    }
+
+    #[tokio::test]
+    async fn test_queued_responses_are_accounted_for() {
+        let memory = Arc::new(MemoryBudget::new(None));
+        let (sender, mut receiver) = response_queue(LIMITS, "test", memory.clone());
+        sender.record_outbound(1 << 20);
+        for i in 0..6 {
+            sender.send(notice(i));
+        }
+        sender.send(ClientResponse::Raw("datapackage".into()));
+        let queued: usize = sender
+            .0
+            .queue
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, size)| size)
+            .sum();
+        assert!(queued > "datapackage".len());
+        assert_eq!(memory.total(), queued);
+
+        receiver.recv().await;
+        assert!(memory.total() < queued);
+        drop(receiver);
+        assert_eq!(memory.total(), 0);
+    }
+
+    #[test]
+    fn test_closed_queue() {
+        let (sender, receiver) = response_queue(LIMITS, "test", unlimited());
+        drop(receiver);
+        assert!(!sender.send(notice(0)));
+    }
+}
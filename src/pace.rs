@@ -0,0 +1,194 @@
+use aprs_proto::primitives::SlotId;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::diagnostics;
+use crate::metrics;
+use crate::registry::ClientRegistry;
+
+/// How far back pace is measured
+const WINDOW_MINUTES: i64 = 10;
+/// How often the pace gauges are updated, so they fall back to zero once nothing is checked
+const GAUGE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Locations newly checked over the last `WINDOW_MINUTES`, by when they were
+#[derive(Debug, Default)]
+struct RateWindow {
+    checks: VecDeque<(DateTime<Utc>, usize)>,
+    last_check_at: Option<DateTime<Utc>>,
+}
+
+impl RateWindow {
+    fn record(&mut self, checks: usize, at: DateTime<Utc>) {
+        self.checks.push_back((at, checks));
+        self.last_check_at = self.last_check_at.max(Some(at));
+    }
+
+    /// Forgets the checks from before the window ending at `now`
+    fn rate(&mut self, now: DateTime<Utc>) -> Rate {
+        let start = now - chrono::Duration::minutes(WINDOW_MINUTES);
+        while self.checks.front().is_some_and(|(at, _)| *at <= start) {
+            self.checks.pop_front();
+        }
+        let checks_last_10m = self.checks.iter().map(|(_, checks)| checks).sum();
+        Rate {
+            checks_last_10m,
+            checks_per_minute: checks_last_10m as f64 / WINDOW_MINUTES as f64,
+            last_check_at: self.last_check_at,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub checks_last_10m: usize,
+    pub checks_per_minute: f64,
+    pub last_check_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SlotPace {
+    pub slot: SlotId,
+    #[serde(flatten)]
+    pub rate: Rate,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RoomPace {
+    #[serde(flatten)]
+    pub rate: Rate,
+    /// Of the slots that logged in since the proxy started
+    pub remaining_locations: usize,
+    /// Seconds until every remaining location is checked at the current pace, `None` while
+    /// nothing is being checked
+    pub eta_seconds: Option<u64>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PaceReport {
+    pub room: RoomPace,
+    pub slots: Vec<SlotPace>,
+}
+
+/// Check rates of a room and each of its slots, for restream overlays
+#[derive(Debug, Default)]
+pub struct Pace {
+    room: RateWindow,
+    slots: HashMap<SlotId, RateWindow>,
+}
+
+impl Pace {
+    pub fn record(&mut self, slot: SlotId, checks: usize, at: DateTime<Utc>) {
+        self.room.record(checks, at);
+        self.slots.entry(slot).or_default().record(checks, at);
+    }
+
+    /// Slots stay in the report once they checked anything, their rate falling to zero
+    pub fn report(&mut self, remaining_locations: usize, now: DateTime<Utc>) -> PaceReport {
+        let rate = self.room.rate(now);
+        let mut slots: Vec<SlotPace> = self
+            .slots
+            .iter_mut()
+            .map(|(slot, window)| SlotPace {
+                slot: *slot,
+                rate: window.rate(now),
+            })
+            .collect();
+        slots.sort_unstable_by_key(|pace| pace.slot);
+        PaceReport {
+            room: RoomPace {
+                rate,
+                remaining_locations,
+                eta_seconds: eta_seconds(remaining_locations, rate.checks_per_minute),
+            },
+            slots,
+        }
+    }
+}
+
+fn eta_seconds(remaining_locations: usize, checks_per_minute: f64) -> Option<u64> {
+    if remaining_locations == 0 {
+        return Some(0);
+    }
+    (checks_per_minute > 0.0)
+        .then(|| (remaining_locations as f64 * 60.0 / checks_per_minute).ceil() as u64)
+}
+
+/// Keeps the pace gauges of every room up to date
+pub async fn run(rooms: Vec<(String, Arc<ClientRegistry>)>) {
+    let mut ticker = tokio::time::interval(GAUGE_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        diagnostics::heartbeat("pace");
+        for (room_id, client_registry) in &rooms {
+            let report = client_registry.pace(Utc::now()).await;
+            metrics::set_room_recent_checks(room_id, report.room.rate.checks_last_10m);
+            for slot in &report.slots {
+                metrics::set_slot_recent_checks(room_id, slot.slot, slot.rate.checks_last_10m);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(minutes: i64) -> DateTime<Utc> {
+        "2026-04-10T12:00:00Z".parse::<DateTime<Utc>>().unwrap()
+            + chrono::Duration::minutes(minutes)
+    }
+
+    #[test]
+    fn test_rate_over_the_last_ten_minutes() {
+        let mut window = RateWindow::default();
+        window.record(5, at(0));
+        window.record(10, at(4));
+        window.record(5, at(9));
+        assert_eq!(
+            window.rate(at(9)),
+            Rate {
+                checks_last_10m: 20,
+                checks_per_minute: 2.0,
+                last_check_at: Some(at(9)),
+            }
+        );
+        // The first checks are out of the window
+        assert_eq!(window.rate(at(10)).checks_last_10m, 15);
+    }
+
+    #[test]
+    fn test_rate_decays_to_zero_once_checks_stop() {
+        let mut window = RateWindow::default();
+        window.record(10, at(0));
+        window.record(10, at(5));
+        assert_eq!(window.rate(at(12)).checks_last_10m, 10);
+        let idle = window.rate(at(15));
+        assert_eq!((idle.checks_last_10m, idle.checks_per_minute), (0, 0.0));
+        assert_eq!(idle.last_check_at, Some(at(5)));
+        assert!(window.checks.is_empty());
+    }
+
+    #[test]
+    fn test_room_eta_follows_its_pace() {
+        let mut pace = Pace::default();
+        pace.record(SlotId(2), 15, at(0));
+        pace.record(SlotId(1), 5, at(1));
+
+        let report = pace.report(100, at(2));
+        assert_eq!(report.room.rate.checks_per_minute, 2.0);
+        assert_eq!(report.room.eta_seconds, Some(50 * 60));
+        let slots: Vec<_> = report.slots.iter().map(|pace| pace.slot).collect();
+        assert_eq!(slots, [SlotId(1), SlotId(2)]);
+
+        // Without checks there's no telling when the room finishes, unless it did
+        let report = pace.report(100, at(20));
+        assert_eq!(report.room.eta_seconds, None);
+        assert_eq!(report.slots[1].rate.checks_last_10m, 0);
+        assert_eq!(pace.report(0, at(20)).room.eta_seconds, Some(0));
+    }
+}
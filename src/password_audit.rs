@@ -0,0 +1,79 @@
+use aprs_proto::primitives::SlotId;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// A failed password validation. The attempted password itself is never kept, its length is
+/// usually enough to spot a stray space or a password from another room.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PasswordFailure {
+    pub slot: SlotId,
+    pub name: String,
+    pub at: DateTime<Utc>,
+    pub expected_empty: bool,
+    pub attempted_length: usize,
+}
+
+/// In-memory history of the last failed validations, oldest first. A capacity of 0 disables it.
+pub struct PasswordFailures {
+    capacity: usize,
+    failures: Mutex<VecDeque<PasswordFailure>>,
+}
+
+impl PasswordFailures {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            failures: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn record(&self, failure: PasswordFailure) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() == self.capacity {
+            failures.pop_front();
+        }
+        failures.push_back(failure);
+    }
+
+    pub fn recent(&self) -> Vec<PasswordFailure> {
+        self.failures.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(slot: i64) -> PasswordFailure {
+        PasswordFailure {
+            slot: SlotId(slot),
+            name: format!("Player{}", slot),
+            at: Utc::now(),
+            expected_empty: false,
+            attempted_length: 8,
+        }
+    }
+
+    #[test]
+    fn test_keeps_the_last_failures() {
+        let failures = PasswordFailures::new(2);
+        failures.record(failure(1));
+        failures.record(failure(2));
+        failures.record(failure(3));
+
+        let slots: Vec<SlotId> = failures.recent().iter().map(|f| f.slot).collect();
+        assert_eq!(slots, vec![SlotId(2), SlotId(3)]);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let failures = PasswordFailures::new(0);
+        failures.record(failure(1));
+        assert!(failures.recent().is_empty());
+    }
+}
@@ -0,0 +1,272 @@
+use serde_json::Value;
+
+use crate::proto::{CommandPermission, Permissions, RemainingCommandPermission};
+
+const MANUAL: u8 = 0b001;
+const AFTER_GOAL: u8 = 0b010;
+const AUTO: u8 = 0b100;
+
+/// Stricter permissions than upstream's, presented to clients and enforced on their commands.
+/// Commands that are left out keep whatever upstream has.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PermissionOverrides {
+    pub release: Option<CommandPermission>,
+    pub collect: Option<CommandPermission>,
+    pub remaining: Option<RemainingCommandPermission>,
+}
+
+#[derive(Debug)]
+pub struct InvalidOverrides(String);
+
+impl std::fmt::Display for InvalidOverrides {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid permission overrides: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidOverrides {}
+
+/// A JSON object like `{"release": "goal", "collect": 0}`
+impl std::str::FromStr for PermissionOverrides {
+    type Err = InvalidOverrides;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: serde_json::Map<String, Value> =
+            serde_json::from_str(s).map_err(|e| InvalidOverrides(e.to_string()))?;
+
+        let mut overrides = Self::default();
+        for (command, value) in values {
+            let value = match value {
+                Value::String(value) => value,
+                Value::Number(value) => value.to_string(),
+                _ => return Err(InvalidOverrides(format!("{} isn't a permission", command))),
+            };
+            let invalid = |e: crate::proto::InvalidPermission| {
+                InvalidOverrides(format!("{}: {}", command, e))
+            };
+            match command.as_str() {
+                "release" => overrides.release = Some(value.parse().map_err(invalid)?),
+                "collect" => overrides.collect = Some(value.parse().map_err(invalid)?),
+                "remaining" => {
+                    overrides.remaining = Some(value.parse().map_err(invalid)?);
+                }
+                _ => return Err(InvalidOverrides(format!("unknown command {}", command))),
+            }
+        }
+        Ok(overrides)
+    }
+}
+
+/// What both allow. The proxy has no say over what upstream does on its own once a slot goals,
+/// so automatic use is upstream's alone.
+fn stricter(upstream: CommandPermission, limit: CommandPermission) -> CommandPermission {
+    let (upstream, limit) = (upstream as u8, limit as u8);
+    let anytime = upstream & limit & MANUAL != 0;
+    let after_goal =
+        anytime || (upstream & (MANUAL | AFTER_GOAL) != 0 && limit & (MANUAL | AFTER_GOAL) != 0);
+    match (upstream & AUTO != 0, anytime, after_goal) {
+        (true, true, _) => CommandPermission::AutoEnabled,
+        (true, false, _) => CommandPermission::Auto,
+        (false, true, _) => CommandPermission::Enabled,
+        (false, false, true) => CommandPermission::Goal,
+        (false, false, false) => CommandPermission::Disabled,
+    }
+}
+
+fn stricter_remaining(
+    upstream: RemainingCommandPermission,
+    limit: RemainingCommandPermission,
+) -> RemainingCommandPermission {
+    use RemainingCommandPermission::*;
+    match (upstream, limit) {
+        (Disabled, _) | (_, Disabled) => Disabled,
+        (Enabled, Enabled) => Enabled,
+        _ => Goal,
+    }
+}
+
+fn allows_manual(permission: u8, goal_reached: bool) -> bool {
+    permission & MANUAL != 0 || (goal_reached && permission & AFTER_GOAL != 0)
+}
+
+impl PermissionOverrides {
+    pub fn apply(&self, upstream: Permissions) -> Permissions {
+        Permissions {
+            release: self
+                .release
+                .map_or(upstream.release, |limit| stricter(upstream.release, limit)),
+            collect: self
+                .collect
+                .map_or(upstream.collect, |limit| stricter(upstream.collect, limit)),
+            remaining: self.remaining.map_or(upstream.remaining, |limit| {
+                stricter_remaining(upstream.remaining, limit)
+            }),
+        }
+    }
+
+    /// Rewrites the permissions of a RoomInfo or RoomUpdate, returns whether anything changed
+    pub fn restrict(&self, cmd: &mut Value) -> bool {
+        if !matches!(
+            cmd.get("cmd").and_then(Value::as_str),
+            Some("RoomInfo" | "RoomUpdate")
+        ) {
+            return false;
+        }
+        let Some(permissions) = cmd.get_mut("permissions") else {
+            return false;
+        };
+        let Ok(upstream) = serde_json::from_value::<Permissions>(permissions.clone()) else {
+            log::warn!("Couldn't parse upstream permissions: {}", permissions);
+            return false;
+        };
+
+        let restricted = self.apply(upstream);
+        if restricted == upstream {
+            return false;
+        }
+        match serde_json::to_value(restricted) {
+            Ok(value) => {
+                *permissions = value;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Whether a client may use `!<command>` as far as the overrides go, upstream still checks
+    /// its own permissions
+    pub fn allows(&self, command: &str, goal_reached: bool) -> bool {
+        let permission = match command {
+            "release" => self.release.map(|p| p as u8),
+            "collect" => self.collect.map(|p| p as u8),
+            "remaining" => self.remaining.map(|p| p as u8),
+            _ => None,
+        };
+        permission.is_none_or(|permission| allows_manual(permission, goal_reached))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CommandPermission::*;
+    use serde_json::json;
+
+    const LEVELS: [CommandPermission; 5] = [Disabled, Enabled, Goal, Auto, AutoEnabled];
+
+    #[test]
+    fn test_every_combination_is_at_most_as_permissive() {
+        for upstream in LEVELS {
+            for limit in LEVELS {
+                let combined = stricter(upstream, limit);
+                // Upstream uses the command by itself at goal, manual use is moot after that
+                let goals = if upstream as u8 & AUTO != 0 {
+                    &[false][..]
+                } else {
+                    &[false, true][..]
+                };
+                for &goal_reached in goals {
+                    let manual = |p: CommandPermission| allows_manual(p as u8, goal_reached);
+                    assert_eq!(
+                        manual(combined),
+                        manual(upstream) && manual(limit),
+                        "{:?} limited to {:?}, goal reached: {}",
+                        upstream,
+                        limit,
+                        goal_reached
+                    );
+                }
+                assert_eq!(combined as u8 & AUTO, upstream as u8 & AUTO);
+            }
+        }
+    }
+
+    #[test]
+    fn test_combinations() {
+        assert_eq!(stricter(Enabled, Goal), Goal);
+        assert_eq!(stricter(Goal, Enabled), Goal);
+        assert_eq!(stricter(Goal, Disabled), Disabled);
+        assert_eq!(stricter(AutoEnabled, Disabled), Auto);
+        assert_eq!(stricter(AutoEnabled, Goal), Auto);
+        assert_eq!(stricter(AutoEnabled, Enabled), AutoEnabled);
+        assert_eq!(stricter(Disabled, AutoEnabled), Disabled);
+
+        use RemainingCommandPermission as R;
+        assert_eq!(stricter_remaining(R::Enabled, R::Goal), R::Goal);
+        assert_eq!(stricter_remaining(R::Goal, R::Enabled), R::Goal);
+        assert_eq!(stricter_remaining(R::Enabled, R::Enabled), R::Enabled);
+        assert_eq!(stricter_remaining(R::Goal, R::Disabled), R::Disabled);
+    }
+
+    #[test]
+    fn test_parse() {
+        let overrides: PermissionOverrides =
+            r#"{"release": "goal", "collect": 0, "remaining": "Enabled"}"#
+                .parse()
+                .unwrap();
+        assert_eq!(
+            overrides,
+            PermissionOverrides {
+                release: Some(Goal),
+                collect: Some(Disabled),
+                remaining: Some(RemainingCommandPermission::Enabled),
+            }
+        );
+        assert_eq!(
+            "{}".parse::<PermissionOverrides>().unwrap(),
+            PermissionOverrides::default()
+        );
+        assert_eq!(
+            "auto_enabled".parse::<CommandPermission>().unwrap(),
+            AutoEnabled
+        );
+        assert!("{\"release\": 3}".parse::<PermissionOverrides>().is_err());
+        // Remaining can't be automatic
+        assert!(
+            "{\"remaining\": \"auto\"}"
+                .parse::<PermissionOverrides>()
+                .is_err()
+        );
+        assert!("{\"hint\": 1}".parse::<PermissionOverrides>().is_err());
+        assert!("[1]".parse::<PermissionOverrides>().is_err());
+    }
+
+    #[test]
+    fn test_restrict() {
+        let overrides = PermissionOverrides {
+            collect: Some(Goal),
+            ..Default::default()
+        };
+        let mut room_info = json!({
+            "cmd": "RoomInfo",
+            "permissions": {"release": 7, "collect": 1, "remaining": 1},
+        });
+        assert!(overrides.restrict(&mut room_info));
+        assert_eq!(
+            room_info["permissions"],
+            json!({"release": 7, "collect": 2, "remaining": 1})
+        );
+        // Already strict enough
+        assert!(!overrides.restrict(&mut room_info));
+
+        let mut room_update = json!({"cmd": "RoomUpdate", "checked_locations": [1]});
+        assert!(!overrides.restrict(&mut room_update));
+        let mut room_update = json!({"cmd": "RoomUpdate", "permissions": {"release": 0, "collect": 7, "remaining": 0}});
+        assert!(overrides.restrict(&mut room_update));
+        assert_eq!(room_update["permissions"]["collect"], json!(6));
+    }
+
+    #[test]
+    fn test_allows() {
+        let overrides = PermissionOverrides {
+            release: Some(Goal),
+            collect: Some(Disabled),
+            ..Default::default()
+        };
+        assert!(!overrides.allows("release", false));
+        assert!(overrides.allows("release", true));
+        assert!(!overrides.allows("collect", true));
+        assert!(overrides.allows("remaining", false));
+        assert!(overrides.allows("hint", false));
+    }
+}
@@ -0,0 +1,302 @@
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::proto::PrintJSON;
+
+/// `!apx` commands, answered by the proxy and never forwarded upstream
+#[derive(Clone, Debug, PartialEq)]
+pub enum ApxCommand {
+    Status,
+    /// `!apx deathlink on|off`, same as the deathlink opt-out preference
+    Deathlink(bool),
+    /// `!apx missing [count]`, how many locations are left and which are the first `count`
+    Missing(usize),
+    /// `!apx ready` and `!apx unready`, for synchronized starts
+    Ready(bool),
+    Unknown(String),
+}
+
+/// A Say that's a command, `!` for the server's and `/` for a client's
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatCommand {
+    /// Lowercased, without its prefix
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+/// Parses a Say as close to the way Archipelago parses commands as we can, a bit more lenient
+/// so blocked commands can't sneak past with a `/` or odd whitespace. `None` when it's not a
+/// command at all.
+pub fn chat_command(text: &str) -> Option<ChatCommand> {
+    // Python only splits on ASCII whitespace, clients may still send any other kind
+    let text: String = text
+        .trim()
+        .chars()
+        .map(|c| if c.is_whitespace() { ' ' } else { c })
+        .collect();
+
+    // try:
+    //     command = shlex.split(raw, comments=False)
+    // except ValueError:  # most likely: "ValueError: No closing quotation"
+    //     command = raw.split()
+    let mut parts = shlex::split(&text)
+        .unwrap_or_else(|| text.split_whitespace().map(String::from).collect())
+        .into_iter();
+
+    // basecommand = command[0]
+    // if basecommand[0] == self.marker:
+    //     method = self.commands.get(basecommand[1:].lower(), None)
+    let first = parts.next()?;
+    let name = first.strip_prefix(['!', '/'])?;
+    if name.is_empty() {
+        return None;
+    }
+    Some(ChatCommand {
+        name: name.to_lowercase(),
+        args: parts.collect(),
+    })
+}
+
+/// Parses the arguments of an `!apx` command
+pub fn parse(args: &[String]) -> ApxCommand {
+    let lowercase: Vec<String> = args.iter().map(|arg| arg.to_lowercase()).collect();
+    let lowercase: Vec<&str> = lowercase.iter().map(String::as_str).collect();
+
+    if let ["missing", count] = lowercase.as_slice()
+        && let Ok(count) = count.parse()
+    {
+        return ApxCommand::Missing(count);
+    }
+    match lowercase.as_slice() {
+        [] | ["status"] => ApxCommand::Status,
+        ["deathlink", "on"] => ApxCommand::Deathlink(true),
+        ["deathlink", "off"] => ApxCommand::Deathlink(false),
+        ["missing"] => ApxCommand::Missing(0),
+        ["ready"] => ApxCommand::Ready(true),
+        ["unready"] => ApxCommand::Ready(false),
+        _ => ApxCommand::Unknown(args.join(" ")),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeathlinkStatus {
+    On,
+    OptedOut,
+    /// Excluded by the room's admins, the player can't turn it back on
+    Excluded,
+}
+
+/// What the proxy does to a slot. There's no hint limit in the proxy, so nothing to report
+/// about hints.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SlotStatus {
+    pub muted: bool,
+    pub deathlink: DeathlinkStatus,
+    pub deathlink_probability: f64,
+    /// Commands refused on this connection so far
+    pub blocked_commands: usize,
+}
+
+fn line(text: &str) -> Value {
+    serde_json::to_value(PrintJSON::with_color(text, "white")).unwrap()
+}
+
+pub fn status_report(status: &SlotStatus) -> Vec<Value> {
+    let deathlink = match status.deathlink {
+        DeathlinkStatus::On => "on",
+        DeathlinkStatus::OptedOut => "off (opted out, `!apx deathlink on` to turn it back on)",
+        DeathlinkStatus::Excluded => "off (excluded by the room's admins)",
+    };
+    vec![
+        line(&format!("APX proxy {}", env!("CARGO_PKG_VERSION"))),
+        line(&format!(
+            "Muted: {}",
+            if status.muted { "yes" } else { "no" }
+        )),
+        line(&format!("DeathLink: {}", deathlink)),
+        line(&format!(
+            "DeathLink probability: {:.0}%",
+            status.deathlink_probability * 100.0
+        )),
+        line(&format!("Blocked commands: {}", status.blocked_commands)),
+    ]
+}
+
+pub fn deathlink_report(enabled: bool, excluded: bool) -> Vec<Value> {
+    let text = match (enabled, excluded) {
+        (_, true) => "DeathLink preference saved, but the room's admins excluded you from it.",
+        (true, false) => "DeathLink turned on.",
+        (false, false) => "DeathLink turned off.",
+    };
+    vec![line(text)]
+}
+
+pub fn ready_report(ready: bool, changed: bool) -> Vec<Value> {
+    let text = match (ready, changed) {
+        (true, true) => "You're ready. The race starts once everyone is.",
+        (true, false) => "You were already ready.",
+        (false, true) => "You're no longer ready.",
+        (false, false) => "You weren't ready.",
+    };
+    vec![line(text)]
+}
+
+/// Most locations `!apx missing` lists, so it can't flood the client
+const MAX_LISTED_MISSING: usize = 20;
+
+/// Count of the `missing` locations, followed by the first `listed` of them. Named when `names`
+/// has them, by id otherwise.
+pub fn missing_report(missing: &[i64], listed: usize, names: &HashMap<i64, String>) -> Vec<Value> {
+    let count = match missing.len() {
+        0 => "You have no locations left to check.".to_string(),
+        1 => "You have 1 location left to check.".to_string(),
+        n => format!("You have {} locations left to check.", n),
+    };
+    let shown = listed.min(MAX_LISTED_MISSING).min(missing.len());
+    let mut report = vec![line(&count)];
+    report.extend(
+        missing[..shown]
+            .iter()
+            .map(|location| match names.get(location) {
+                Some(name) => line(&format!("  {} ({})", name, location)),
+                None => line(&format!("  Location {}", location)),
+            }),
+    );
+    if shown < missing.len() && listed > shown {
+        report.push(line(&format!("  ...and {} more", missing.len() - shown)));
+    }
+    report
+}
+
+pub fn usage(command: &str) -> Vec<Value> {
+    vec![line(&format!(
+        "Unknown command `!apx {}`. Available: `!apx status`, `!apx deathlink on|off`, `!apx missing [count]`, `!apx ready`, `!apx unready`.",
+        command
+    ))]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(text: &str) -> Option<String> {
+        chat_command(text).map(|command| command.name)
+    }
+
+    #[test]
+    fn test_chat_command() {
+        for text in [
+            "!countdown",
+            "  !countdown  ",
+            "!COUNTDOWN",
+            "!CoUnTdOwN",
+            "/countdown",
+            "\"!COUNTDOWN\"",
+            "'!COUNTDOWN'",
+            "  !\\countdown  ",
+            "  !\"\"countdown  ",
+            "\u{3000}!countdown\u{a0}10",
+            "\t!countdown\n",
+        ] {
+            assert_eq!(name(text).as_deref(), Some("countdown"), "{:?}", text);
+        }
+
+        assert_eq!(
+            chat_command("!countdown 10 \"some message\"").unwrap().args,
+            ["10", "some message"]
+        );
+        assert_eq!(chat_command("!countdown 10 '").unwrap().args, ["10", "'"]);
+        assert_eq!(chat_command("!countdown\u{2003}10").unwrap().args, ["10"]);
+
+        assert_eq!(name("!countdownfoo").as_deref(), Some("countdownfoo"));
+        for text in [
+            "",
+            "  ",
+            "\u{3000}",
+            "countdown",
+            "!",
+            "/",
+            "! countdown",
+            "hi !countdown",
+        ] {
+            assert_eq!(name(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let apx = |text: &str| parse(&chat_command(text).unwrap().args);
+        assert_eq!(apx("!apx"), ApxCommand::Status);
+        assert_eq!(apx("  /APX Status "), ApxCommand::Status);
+        assert_eq!(apx("!apx deathlink off"), ApxCommand::Deathlink(false));
+        assert_eq!(apx("!apx DeathLink On"), ApxCommand::Deathlink(true));
+        assert_eq!(
+            apx("!apx deathlink maybe"),
+            ApxCommand::Unknown("deathlink maybe".to_string())
+        );
+        assert_eq!(apx("!apx missing"), ApxCommand::Missing(0));
+        assert_eq!(apx("!apx missing 5"), ApxCommand::Missing(5));
+        assert_eq!(apx("!apx Ready"), ApxCommand::Ready(true));
+        assert_eq!(apx("!apx unready"), ApxCommand::Ready(false));
+        assert_eq!(
+            apx("!apx missing all"),
+            ApxCommand::Unknown("missing all".to_string())
+        );
+        assert_eq!(
+            apx("!apx 'quoted arg"),
+            ApxCommand::Unknown("'quoted arg".to_string())
+        );
+    }
+
+    #[test]
+    fn test_status_report() {
+        let report = status_report(&SlotStatus {
+            muted: true,
+            deathlink: DeathlinkStatus::Excluded,
+            deathlink_probability: 0.5,
+            blocked_commands: 2,
+        });
+        let text: Vec<&str> = report
+            .iter()
+            .map(|line| line["data"][0]["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            text[1..],
+            [
+                "Muted: yes",
+                "DeathLink: off (excluded by the room's admins)",
+                "DeathLink probability: 50%",
+                "Blocked commands: 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_report() {
+        let text = |report: Vec<Value>| -> Vec<String> {
+            report
+                .iter()
+                .map(|line| line["data"][0]["text"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let names = HashMap::from([(10, "Chest".to_string())]);
+
+        assert_eq!(
+            text(missing_report(&[10, 11, 12], 0, &names)),
+            ["You have 3 locations left to check."]
+        );
+        assert_eq!(
+            text(missing_report(&[10, 11, 12], 2, &names)),
+            [
+                "You have 3 locations left to check.",
+                "  Chest (10)",
+                "  Location 11",
+                "  ...and 1 more",
+            ]
+        );
+        assert_eq!(
+            text(missing_report(&[], 5, &names)),
+            ["You have no locations left to check."]
+        );
+    }
+}
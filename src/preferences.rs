@@ -0,0 +1,228 @@
+use aprs_proto::primitives::SlotId;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::db::models::SlotPreferenceRow;
+
+/// Soft per-player preferences. They are stored by slot along with the player name, so they follow
+/// the player across seeds of a recurring room: they're mapped onto the current slot numbers
+/// whenever passwords (which carry the names) are refreshed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SlotPreferences {
+    #[serde(default)]
+    pub deathlink_opt_out: bool,
+    #[serde(default)]
+    pub muted: bool,
+    #[serde(default)]
+    pub alias: Option<String>,
+}
+
+pub type PreferenceMap = HashMap<SlotId, SlotPreferences>;
+
+pub struct StoredPreference {
+    /// Slot the preference was saved for
+    pub slot: SlotId,
+    /// Canonical player name
+    pub player_name: String,
+    pub preferences: SlotPreferences,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<SlotPreferenceRow> for StoredPreference {
+    fn from(row: SlotPreferenceRow) -> Self {
+        Self {
+            slot: SlotId(row.slot as i64),
+            player_name: row.player_name,
+            preferences: SlotPreferences {
+                deathlink_opt_out: row.deathlink_opt_out,
+                muted: row.muted,
+                alias: row.alias,
+            },
+            updated_at: row.updated_at,
+        }
+    }
+}
+
+pub fn canonical_name(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+pub fn is_muted(preferences: &PreferenceMap, slot: &SlotId) -> bool {
+    preferences.get(slot).is_some_and(|p| p.muted)
+}
+
+pub fn opts_out_of_deathlink(preferences: &PreferenceMap, slot: &SlotId) -> bool {
+    preferences.get(slot).is_some_and(|p| p.deathlink_opt_out)
+}
+
+/// Maps the stored preferences onto the current slot numbers. A slot gets what was saved for it
+/// under its player's name. Otherwise the latest saved for that name under another slot follows
+/// the player, unless several current slots share the name.
+pub fn remap(stored: &[StoredPreference], slot_names: &HashMap<SlotId, String>) -> PreferenceMap {
+    let mut slots_by_name: HashMap<String, Vec<SlotId>> = HashMap::new();
+    for (slot, name) in slot_names {
+        slots_by_name
+            .entry(canonical_name(name))
+            .or_default()
+            .push(*slot);
+    }
+
+    let mut effective = PreferenceMap::new();
+    for (name, mut slots) in slots_by_name {
+        slots.sort_unstable();
+        let (exact, elsewhere): (Vec<_>, Vec<_>) = stored
+            .iter()
+            .filter(|p| p.player_name == name)
+            .partition(|p| slots.contains(&p.slot));
+        for saved in &exact {
+            effective.insert(saved.slot, saved.preferences.clone());
+        }
+
+        if let [slot] = slots[..] {
+            if !exact.is_empty() {
+                continue;
+            }
+            let Some(latest) = elsewhere.iter().max_by_key(|p| p.updated_at) else {
+                continue;
+            };
+            log::info!(
+                "Remapped preferences for {} from slot {} to slot {}",
+                name,
+                latest.slot.0,
+                slot.0
+            );
+            effective.insert(slot, latest.preferences.clone());
+        } else if !elsewhere.is_empty() {
+            log::warn!(
+                "Slots {:?} share the player name {}, only applying the preferences saved for each of them",
+                slots,
+                name
+            );
+        }
+    }
+
+    effective
+}
+
+pub async fn load_effective(
+    pool: &crate::db::DieselPool,
+    room_id: &str,
+    slot_names: &HashMap<SlotId, String>,
+) -> anyhow::Result<PreferenceMap> {
+    let stored: Vec<StoredPreference> = crate::db::models::get_room_slot_preferences(pool, room_id)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+
+    Ok(remap(&stored, slot_names))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(slot: i64, name: &str, preferences: SlotPreferences) -> StoredPreference {
+        StoredPreference {
+            slot: SlotId(slot),
+            player_name: canonical_name(name),
+            preferences,
+            updated_at: DateTime::<Utc>::UNIX_EPOCH,
+        }
+    }
+
+    fn names(entries: &[(i64, &str)]) -> HashMap<SlotId, String> {
+        entries
+            .iter()
+            .map(|(slot, name)| (SlotId(*slot), name.to_string()))
+            .collect()
+    }
+
+    fn muted() -> SlotPreferences {
+        SlotPreferences {
+            muted: true,
+            ..Default::default()
+        }
+    }
+
+    fn opted_out() -> SlotPreferences {
+        SlotPreferences {
+            deathlink_opt_out: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_same_seed_keeps_slots() {
+        let stored = vec![stored(1, "Alice", muted()), stored(2, "Bob", opted_out())];
+        let effective = remap(&stored, &names(&[(1, "Alice"), (2, "Bob")]));
+        assert_eq!(effective.get(&SlotId(1)), Some(&muted()));
+        assert_eq!(effective.get(&SlotId(2)), Some(&opted_out()));
+    }
+
+    #[test]
+    fn test_next_seed_remaps_by_name() {
+        // Saved during the first seed
+        let stored = vec![stored(1, "Alice", muted()), stored(2, "Bob", opted_out())];
+
+        // Second seed shuffles slots and adds a new player
+        let effective = remap(&stored, &names(&[(1, "Bob"), (2, "Carol"), (3, "Alice")]));
+        assert_eq!(effective.len(), 2);
+        assert_eq!(effective.get(&SlotId(1)), Some(&opted_out()));
+        assert_eq!(effective.get(&SlotId(3)), Some(&muted()));
+        assert!(!effective.contains_key(&SlotId(2)));
+    }
+
+    #[test]
+    fn test_names_are_canonicalized() {
+        let stored = vec![stored(1, "Alice", muted())];
+        let effective = remap(&stored, &names(&[(4, "  ALICE ")]));
+        assert_eq!(effective.get(&SlotId(4)), Some(&muted()));
+    }
+
+    #[test]
+    fn test_missing_player_is_dropped() {
+        let stored = vec![stored(1, "Alice", muted())];
+        let effective = remap(&stored, &names(&[(1, "Bob")]));
+        assert!(effective.is_empty());
+    }
+
+    #[test]
+    fn test_ambiguous_name_prefers_exact_slot() {
+        let stored = vec![stored(2, "Alice", muted())];
+        let effective = remap(&stored, &names(&[(1, "alice"), (2, "Alice")]));
+        assert_eq!(effective.len(), 1);
+        assert_eq!(effective.get(&SlotId(2)), Some(&muted()));
+    }
+
+    #[test]
+    fn test_ambiguous_name_without_exact_slot_is_skipped() {
+        let stored = vec![stored(5, "Alice", muted())];
+        let effective = remap(&stored, &names(&[(1, "alice"), (2, "Alice")]));
+        assert!(effective.is_empty());
+    }
+
+    #[test]
+    fn test_slots_sharing_a_name_keep_their_own() {
+        let stored = vec![stored(1, "Alice", muted()), stored(2, "Alice", opted_out())];
+        let effective = remap(&stored, &names(&[(1, "Alice"), (2, "alice")]));
+        assert_eq!(effective.get(&SlotId(1)), Some(&muted()));
+        assert_eq!(effective.get(&SlotId(2)), Some(&opted_out()));
+    }
+
+    #[test]
+    fn test_latest_preferences_follow_the_player() {
+        // Saved over two seeds under different slots
+        let mut latest = stored(3, "Alice", opted_out());
+        latest.updated_at = DateTime::<Utc>::UNIX_EPOCH + chrono::Duration::days(7);
+        let stored = vec![stored(1, "Alice", muted()), latest];
+
+        let effective = remap(&stored, &names(&[(1, "Bob"), (5, "Alice")]));
+        assert_eq!(effective.len(), 1);
+        assert_eq!(effective.get(&SlotId(5)), Some(&opted_out()));
+        // Back to a slot it saved for, that's what it gets
+        let effective = remap(&stored, &names(&[(1, "Alice")]));
+        assert_eq!(effective.get(&SlotId(1)), Some(&muted()));
+    }
+}
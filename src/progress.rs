@@ -0,0 +1,183 @@
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// Location progress of a slot, seeded from Connected. Checks are tracked by location id so
+/// clients resending their whole check list don't get counted twice.
+#[derive(Clone, Debug, Default)]
+pub struct LocationProgress {
+    checked: HashSet<i64>,
+    missing: HashSet<i64>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct ProgressSummary {
+    pub checked: usize,
+    pub total: usize,
+    pub percentage: f64,
+}
+
+impl LocationProgress {
+    pub fn new(checked: &[i64], missing: &[i64]) -> Self {
+        let checked: HashSet<i64> = checked.iter().copied().collect();
+        let missing = missing
+            .iter()
+            .copied()
+            .filter(|location| !checked.contains(location))
+            .collect();
+        Self { checked, missing }
+    }
+
+    /// Marks locations as checked. Unknown locations are ignored, they don't belong to the slot.
+    /// Returns whether anything changed.
+    pub fn check(&mut self, locations: &[i64]) -> bool {
+        let mut changed = false;
+        for location in locations {
+            if self.missing.remove(location) {
+                self.checked.insert(*location);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    pub fn checked(&self) -> usize {
+        self.checked.len()
+    }
+
+    /// Locations left to check, lowest id first
+    pub fn missing(&self) -> Vec<i64> {
+        let mut missing: Vec<i64> = self.missing.iter().copied().collect();
+        missing.sort_unstable();
+        missing
+    }
+
+    pub fn total(&self) -> usize {
+        self.checked.len() + self.missing.len()
+    }
+
+    pub fn summary(&self) -> ProgressSummary {
+        let (checked, total) = (self.checked(), self.total());
+        let percentage = if total == 0 {
+            100.0
+        } else {
+            checked as f64 * 100.0 / total as f64
+        };
+        ProgressSummary {
+            checked,
+            total,
+            percentage,
+        }
+    }
+}
+
+/// Items a slot received so far. ReceivedItems can replay items already counted, so this is the
+/// furthest index reached rather than a sum.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ItemsReceived(usize);
+
+impl ItemsReceived {
+    /// Returns whether the count went up
+    pub fn record(&mut self, index: usize, items: usize) -> bool {
+        let end = index + items;
+        if end <= self.0 {
+            return false;
+        }
+        self.0 = end;
+        true
+    }
+
+    pub fn count(&self) -> usize {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_from_connected() {
+        let progress = LocationProgress::new(&[1, 2], &[3, 4, 5, 6]);
+        assert_eq!(
+            progress.summary(),
+            ProgressSummary {
+                checked: 2,
+                total: 6,
+                percentage: 2.0 * 100.0 / 6.0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_location_checks() {
+        let mut progress = LocationProgress::new(&[1], &[2, 3, 4]);
+        assert!(progress.check(&[2]));
+        assert!(progress.check(&[3, 4]));
+        assert_eq!(progress.checked(), 4);
+        assert_eq!(progress.summary().percentage, 100.0);
+    }
+
+    #[test]
+    fn test_resent_checks_are_not_double_counted() {
+        let mut progress = LocationProgress::new(&[], &[1, 2, 3, 4]);
+        assert!(progress.check(&[1, 2]));
+        // Clients resend everything they checked after reconnecting
+        assert!(!progress.check(&[1, 2]));
+        assert!(progress.check(&[1, 2, 3]));
+        // RoomUpdate echoes the same checks back
+        assert!(!progress.check(&[3]));
+        assert_eq!(progress.checked(), 3);
+        assert_eq!(progress.total(), 4);
+    }
+
+    #[test]
+    fn test_unknown_locations_are_ignored() {
+        let mut progress = LocationProgress::new(&[1], &[2]);
+        assert!(!progress.check(&[99]));
+        assert_eq!(progress.total(), 2);
+    }
+
+    #[test]
+    fn test_overlapping_connected_lists() {
+        let progress = LocationProgress::new(&[1, 2], &[2, 3]);
+        assert_eq!(progress.checked(), 2);
+        assert_eq!(progress.total(), 3);
+    }
+
+    #[test]
+    fn test_slot_without_locations() {
+        let progress = LocationProgress::new(&[], &[]);
+        assert_eq!(progress.summary().percentage, 100.0);
+    }
+
+    /// Items received by a slot over `(index, items)` ReceivedItems
+    fn received(sequence: &[(usize, usize)]) -> usize {
+        let mut received = ItemsReceived::default();
+        for (index, items) in sequence {
+            received.record(*index, *items);
+        }
+        received.count()
+    }
+
+    #[test]
+    fn test_items_received_in_order() {
+        assert_eq!(received(&[]), 0);
+        assert_eq!(received(&[(0, 3), (3, 1), (4, 2)]), 6);
+    }
+
+    #[test]
+    fn test_resyncs_are_not_double_counted() {
+        // Login, two items, then a Sync replaying everything from 0
+        assert_eq!(received(&[(0, 3), (3, 1), (4, 1), (0, 5)]), 5);
+        // A reconnect whose list grew while the slot was away
+        assert_eq!(received(&[(0, 3), (0, 7), (7, 1)]), 8);
+        // Empty ReceivedItems on login before anything was sent
+        assert_eq!(received(&[(0, 0), (0, 0)]), 0);
+
+        let mut received = ItemsReceived::default();
+        assert!(received.record(0, 2));
+        assert!(!received.record(0, 2));
+        assert!(!received.record(1, 1));
+        assert!(received.record(2, 1));
+    }
+}
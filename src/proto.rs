@@ -3,7 +3,7 @@ use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use std::collections::HashMap;
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
 pub struct Permissions {
     /// permission for the `release` command
     pub release: CommandPermission,
@@ -14,7 +14,7 @@ pub struct Permissions {
 }
 
 #[repr(u8)]
-#[derive(Serialize_repr, Deserialize_repr, Copy, Clone, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, Copy, Clone, Debug, PartialEq)]
 pub enum CommandPermission {
     Disabled = 0b000,    // 0, completely disables access
     Enabled = 0b001,     // 1, allows manual use
@@ -24,13 +24,53 @@ pub enum CommandPermission {
 }
 
 #[repr(u8)]
-#[derive(Serialize_repr, Deserialize_repr, Copy, Clone, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, Copy, Clone, Debug, PartialEq)]
 pub enum RemainingCommandPermission {
     Disabled = 0b000, // 0, completely disables access
     Enabled = 0b001,  // 1, allows manual use
     Goal = 0b010,     // 2, allows manual use after goal completion
 }
 
+#[derive(Debug)]
+pub struct InvalidPermission(String);
+
+impl std::fmt::Display for InvalidPermission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown permission {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidPermission {}
+
+/// By name like in the AP host settings, or by value
+impl std::str::FromStr for CommandPermission {
+    type Err = InvalidPermission;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "disabled" | "0" => Ok(CommandPermission::Disabled),
+            "enabled" | "1" => Ok(CommandPermission::Enabled),
+            "goal" | "2" => Ok(CommandPermission::Goal),
+            "auto" | "6" => Ok(CommandPermission::Auto),
+            "auto_enabled" | "auto-enabled" | "7" => Ok(CommandPermission::AutoEnabled),
+            _ => Err(InvalidPermission(s.to_string())),
+        }
+    }
+}
+
+impl std::str::FromStr for RemainingCommandPermission {
+    type Err = InvalidPermission;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "disabled" | "0" => Ok(RemainingCommandPermission::Disabled),
+            "enabled" | "1" => Ok(RemainingCommandPermission::Enabled),
+            "goal" | "2" => Ok(RemainingCommandPermission::Goal),
+            _ => Err(InvalidPermission(s.to_string())),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct VersionWithClass {
     pub major: u32,
@@ -62,6 +102,10 @@ pub struct RoomInfo {
 }
 
 impl RoomInfo {
+    pub fn password(&self) -> bool {
+        self.password
+    }
+
     pub fn set_password(&mut self, password: bool) {
         self.password = password;
     }
@@ -74,7 +118,7 @@ pub struct Version {
     pub build: u32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Connect {
     pub cmd: String,
     pub password: String,
@@ -92,6 +136,24 @@ pub struct Connect {
     pub slot_data: bool,
 }
 
+// Written by hand so the password never ends up in logs
+impl std::fmt::Debug for Connect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connect")
+            .field("cmd", &self.cmd)
+            .field("password", &"<redacted>")
+            .field("name", &self.name)
+            .field("version", &self.version)
+            .field("tags", &self.tags)
+            .field("uuid", &self.uuid)
+            .field("game", &self.game)
+            .field("slot", &self.slot)
+            .field("items_handling", &self.items_handling)
+            .field("slot_data", &self.slot_data)
+            .finish()
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SlotInfo {
     pub name: String,
@@ -125,12 +187,60 @@ pub struct NetworkPlayer {
     pub name: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NetworkItem {
+    pub item: i64,
+    pub location: i64,
+    pub player: SlotId,
+    pub flags: u8,
+}
+
+/// Items sent to the slot, starting at `index` in its list of received items. Upstream resends
+/// the whole list from 0 on login and Sync.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReceivedItems {
+    pub cmd: String,
+    pub index: usize,
+    pub items: Vec<NetworkItem>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ConnectionRefused {
     pub cmd: String,
     pub errors: Vec<String>,
 }
 
+/// What AP answers a packet it can't make sense of with, the connection stays open
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InvalidPacket {
+    pub cmd: String,
+    /// `cmd` for a command the server doesn't know, `arguments` for one it couldn't read
+    #[serde(rename = "type")]
+    pub type_: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original_cmd: Option<String>,
+    pub text: String,
+}
+
+impl InvalidPacket {
+    pub fn new(type_: &str, original_cmd: Option<&str>, text: &str) -> Self {
+        InvalidPacket {
+            cmd: "InvalidPacket".to_string(),
+            type_: type_.to_string(),
+            original_cmd: original_cmd.map(str::to_string),
+            text: text.to_string(),
+        }
+    }
+
+    pub fn unknown_cmd(original_cmd: Option<&str>) -> Self {
+        Self::new("cmd", original_cmd, "Unknown command")
+    }
+
+    pub fn arguments(original_cmd: &str, text: &str) -> Self {
+        Self::new("arguments", Some(original_cmd), text)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PrintJSON {
     pub cmd: String,
@@ -222,3 +332,65 @@ pub struct ConnectUpdate {
     #[serde(default)]
     pub items_handling: Option<u8>,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LocationChecks {
+    pub cmd: String,
+    pub locations: Vec<i64>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Get {
+    pub keys: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SetNotify {
+    pub keys: Vec<String>,
+}
+
+/// Answer to a Get, the values of the requested keys
+#[derive(Deserialize, Clone, Debug)]
+pub struct Retrieved {
+    pub keys: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Sent for keys subscribed to with SetNotify when they change, other fields are left as is
+#[derive(Deserialize, Clone, Debug)]
+pub struct SetReply {
+    pub key: String,
+}
+
+/// `ClientStatus.CLIENT_GOAL`
+pub const CLIENT_GOAL: u8 = 30;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct StatusUpdate {
+    pub status: u8,
+}
+
+/// Only the fields the proxy looks at, RoomUpdate carries many more
+#[derive(Deserialize, Clone, Debug)]
+pub struct RoomUpdate {
+    #[serde(default)]
+    pub checked_locations: Vec<i64>,
+}
+
+/// A client changing the status of a hint, like marking its item as one to avoid. The hint is
+/// the one for `location` in the world of `player`, the finding player.
+#[derive(Deserialize, Clone, Debug)]
+pub struct UpdateHint {
+    pub player: SlotId,
+    pub location: i64,
+    #[serde(default)]
+    pub status: Option<u8>,
+}
+
+/// A hint as kept in the `_read_hints_<team>_<slot>` data storage keys, only the fields the
+/// proxy looks at
+#[derive(Deserialize, Clone, Debug)]
+pub struct Hint {
+    pub receiving_player: SlotId,
+    pub finding_player: SlotId,
+    pub location: i64,
+}
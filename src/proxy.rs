@@ -1,32 +1,116 @@
-use anyhow::{Result, bail};
-use futures_util::{SinkExt, StreamExt};
+use chrono::{SecondsFormat, Utc};
+use futures_util::{FutureExt, Sink, SinkExt, Stream, StreamExt};
 use rand::Rng;
 use serde_json::Value;
 use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::mpsc::Sender;
+use tokio::net::TcpStream;
 use tokio::sync::{Mutex, RwLock};
-use tokio_tungstenite::accept_async_with_config;
-use tokio_tungstenite::{connect_async_with_config, tungstenite::Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, accept_hdr_async_with_config};
+use tokio_tungstenite::{client_async_with_config, tungstenite::Message};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::error::ProtocolError;
 use tungstenite::extensions::compression::deflate::DeflateConfig;
-use tungstenite::protocol::WebSocketConfig;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
+use tungstenite::http::header::ORIGIN;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, WebSocketConfig};
 
 const AUTH_TIMEOUT: Duration = Duration::from_secs(60);
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long tearing a connection down waits on each side to finish the close handshake
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Close reason of connections that went over PER_CONNECTION_MEMORY_CAP
+const MEMORY_CAP_REASON: &str = "Memory budget exceeded";
 
 use aprs_proto::primitives::SlotId;
 
 use crate::DataPackageCache;
-use crate::config::{DeathlinkProbability, Signal};
+use crate::audit::AuditKey;
+use crate::bandwidth::{self, Meter};
+use crate::bounce_log::BounceRecorder;
+use crate::bridge::Bridge;
+use crate::budget::PreLoginBudget;
+use crate::chain::{self, ChainMode, Identity, Peer};
+use crate::claims::{ClaimHold, ClaimRefused, SlotClaims};
+use crate::config::DeathlinkProbability;
+use crate::error::{self, DisconnectCause, ProxyError, ProxyResult, Side};
+use crate::events::{EventBus, RoomEvent};
+use crate::fingerprint::{self, ClientSoftware};
+use crate::groups::SlotGroups;
+use crate::hints::HintReceivers;
+use crate::json_limits::{self, CommandSizeLimits, LimitExceeded, ParseLimits};
+use crate::login_queue::{self, Admission, LoginQueue, Seat, Ticket, Turn};
+use crate::memory::MemoryBudget;
+use crate::messages::{DenialCooldown, Notice};
 use crate::metrics;
-use crate::proto::{Bounced, ConnectUpdate, Connected, GetDataPackage, PrintJSON, RoomInfo, Say};
-use crate::registry::{ClientEntry, ClientRegistry, ClientResponse};
+use crate::mirror::{self, TrackerMirrors};
+use crate::motd;
+use crate::origin::AllowedOrigins;
+use crate::outbox::{self, ResponseSender};
+use crate::password_audit::{PasswordFailure, PasswordFailures};
+use crate::permissions::PermissionOverrides;
+use crate::player_commands::{self, ApxCommand, DeathlinkStatus, SlotStatus, chat_command};
+use crate::preferences::{self, PreferenceMap};
+use crate::progress::LocationProgress;
+use crate::proto::{
+    Bounced, CLIENT_GOAL, ConnectUpdate, Connected, ConnectionRefused, Get, GetDataPackage,
+    InvalidPacket, LocationChecks, NetworkPlayer, PrintJSON, ReceivedItems, Retrieved, RoomInfo,
+    RoomUpdate, Say, SetNotify, SetReply, StatusUpdate, UpdateHint,
+};
+use crate::ready::ReadyStates;
+use crate::registry::{
+    ClientControl, ClientEntry, ClientId, ClientRegistry, ClientResponse, ReconnectError,
+};
+use crate::release_pacing::{self, Pacer};
+use crate::reload::{Live, LiveSettings};
+use crate::resume::{self, ResumableSessions, ResumeHold};
+use crate::send::{self, DroppableCommands};
+use crate::session::{SessionEnd, SessionStep, SessionTimer};
+use crate::shedding::LoadShedding;
+use crate::stats;
+use crate::supervisor::LoginMark;
+use crate::token::{self, TokenError, TokenKey};
+use crate::transforms::{Direction, Transforms};
+use crate::upstream::{UpstreamLimiter, UpstreamPermit, UpstreamServers};
 
 const MAX_MESSAGE_SIZE: usize = 15 * 1024 * 1024; // 15 MB
-const MAX_SAY_LENGTH: usize = 2000;
+pub const MAX_SAY_LENGTH: usize = 2000;
+/// Commands clients may send, with STRICT_COMMANDS anything else gets an InvalidPacket
+const CLIENT_COMMANDS: &[&str] = &[
+    "Bounce",
+    "Connect",
+    "ConnectUpdate",
+    "CreateHints",
+    "Get",
+    "GetDataPackage",
+    "LocationChecks",
+    "LocationScouts",
+    "Say",
+    "Set",
+    "SetNotify",
+    "StatusUpdate",
+    "Sync",
+    "UpdateHint",
+];
+const ROOM_PATH_PREFIX: &str = "/room/";
 
-#[derive(Clone, Debug)]
+type UpstreamStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+/// Either half of a connection of the client's own, or its share of a tracker mirror
+pub(crate) type UpstreamWrite = Pin<Box<dyn Sink<Message, Error = tungstenite::Error> + Send>>;
+pub(crate) type UpstreamRead = Pin<Box<dyn Stream<Item = tungstenite::Result<Message>> + Send>>;
+
+fn split_upstream(upstream_ws: UpstreamStream) -> (UpstreamWrite, UpstreamRead) {
+    let (write, read) = upstream_ws.split();
+    (Box::pin(write), Box::pin(read))
+}
+
+#[derive(Clone)]
 pub enum ConnectionState {
     WaitingForRoomInfo,
     WaitingForConnect,
@@ -34,16 +118,149 @@ pub enum ConnectionState {
         password: String,
         tags: Vec<String>,
         game: String,
+        name: String,
+        /// The `slot` of the Connect, AP 0.6+ clients can log in with it in place of the name
+        slot: Option<SlotId>,
+        software: ClientSoftware,
+        /// Upstream logged the connection in before, it may still send what's meant for that
+        /// login until it answers this one
+        relogin: bool,
     },
     LoggedIn,
 }
 
+// Written by hand so the password never ends up in logs
+impl std::fmt::Debug for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionState::WaitingForRoomInfo => f.write_str("WaitingForRoomInfo"),
+            ConnectionState::WaitingForConnect => f.write_str("WaitingForConnect"),
+            ConnectionState::WaitingForConnected {
+                password: _,
+                tags,
+                game,
+                name,
+                slot,
+                software,
+                relogin,
+            } => f
+                .debug_struct("WaitingForConnected")
+                .field("password", &"<redacted>")
+                .field("tags", tags)
+                .field("game", game)
+                .field("name", name)
+                .field("slot", slot)
+                .field("software", software)
+                .field("relogin", relogin)
+                .finish(),
+            ConnectionState::LoggedIn => f.write_str("LoggedIn"),
+        }
+    }
+}
+
 enum PendingDataPackageRequest {
     Games(Vec<String>),
     Exclusions(Vec<String>),
     All,
 }
 
+/// Data storage keys a connection asked for before logging in. Until then it's only sent the
+/// answers to its own requests, whatever else upstream sends about storage is dropped.
+#[derive(Default)]
+struct StorageRequests {
+    /// Gets waiting for their Retrieved, by key
+    gets: HashMap<String, usize>,
+    /// Subscribed to with SetNotify
+    notify: HashSet<String>,
+    /// Upstream logged the connection in, to a slot APX may well have refused. Reads before the
+    /// next login would be answered for that slot, so none go out anymore.
+    upstream_logged_in: bool,
+}
+
+impl StorageRequests {
+    /// Records the keys of a Get or SetNotify, returns whether the command was one that can go
+    /// upstream
+    fn request(&mut self, cmd: &Value) -> bool {
+        if self.upstream_logged_in {
+            return false;
+        }
+        match get_cmd(cmd) {
+            Some("Get") => match parse_as::<Get>(cmd) {
+                Ok(get) => {
+                    for key in get.keys {
+                        *self.gets.entry(key).or_default() += 1;
+                    }
+                    true
+                }
+                Err(_) => false,
+            },
+            Some("SetNotify") => match parse_as::<SetNotify>(cmd) {
+                Ok(set_notify) => {
+                    self.notify.extend(set_notify.keys);
+                    true
+                }
+                Err(_) => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Called when upstream sends a Connected, whether or not APX goes on to accept the login.
+    /// What was asked before is forgotten, as upstream now answers for the slot it logged in to.
+    fn logged_in(&mut self) {
+        self.gets.clear();
+        self.notify.clear();
+        self.upstream_logged_in = true;
+    }
+
+    /// What to do with a Retrieved or SetReply, `None` for anything else. Keys of a Retrieved
+    /// that weren't asked for are removed from it.
+    fn filter_response(&mut self, cmd: &mut Value) -> Option<MessageDecision> {
+        match get_cmd(cmd)? {
+            "Retrieved" => {
+                let Ok(retrieved) = parse_as::<Retrieved>(cmd) else {
+                    return Some(MessageDecision::Drop);
+                };
+                let total = retrieved.keys.len();
+                let requested: serde_json::Map<String, Value> = retrieved
+                    .keys
+                    .into_iter()
+                    .filter(|(key, _)| match self.gets.get_mut(key) {
+                        Some(count) => {
+                            *count -= 1;
+                            if *count == 0 {
+                                self.gets.remove(key);
+                            }
+                            true
+                        }
+                        None => false,
+                    })
+                    .collect();
+
+                if requested.is_empty() {
+                    log::debug!("Dropping Retrieved for keys never requested before login");
+                    return Some(MessageDecision::Drop);
+                }
+                if requested.len() == total {
+                    return Some(MessageDecision::Forward);
+                }
+                cmd["keys"] = Value::Object(requested);
+                Some(MessageDecision::Modified)
+            }
+            "SetReply" => {
+                let requested =
+                    parse_as::<SetReply>(cmd).is_ok_and(|reply| self.notify.contains(&reply.key));
+                Some(if requested {
+                    MessageDecision::Forward
+                } else {
+                    MessageDecision::Drop
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
 enum MessageDecision {
     Forward,
     ForwardWithRegistration {
@@ -53,10 +270,48 @@ enum MessageDecision {
     Modified,
     Drop,
     DropAndRoute,
-    DropWithResponse(Value),
+    DropWithResponse(Notice),
     DropWithRawResponse(Arc<str>),
+    /// A packet the client can recover from, answered the way AP would
+    DropWithInvalidPacket(InvalidPacket),
     DeferDataPackage(PendingDataPackageRequest),
-    SendConnectionRefused,
+    /// `!apx` command, answered once the handler's locks are released
+    Apx(ApxCommand),
+    SendConnectionRefused(Refusal),
+}
+
+/// Why APX refuses a login upstream accepted
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Refusal {
+    InvalidPassword,
+    /// Upstream logged the connection into another slot than the Connect asked for, or the
+    /// Connect named another player than its slot
+    InvalidSlot,
+    /// Claimed by another client, with SLOT_CLAIMING
+    SlotClaimed,
+}
+
+impl Refusal {
+    /// What the ConnectionRefused says
+    fn error(self) -> &'static str {
+        match self {
+            Refusal::InvalidPassword => "InvalidPassword",
+            Refusal::InvalidSlot => "InvalidSlot",
+            Refusal::SlotClaimed => "SlotClaimed",
+        }
+    }
+}
+
+/// What the Connect password is checked against once upstream tells us the slot
+struct LoginCheck<'a> {
+    passwords: &'a HashMap<SlotId, String>,
+    tokens: Option<&'a TokenKey>,
+    room_id: &'a str,
+    /// Upstream is another APX, which checks the password and rewrites RoomInfo itself
+    chained: bool,
+    claims: Option<&'a Arc<SlotClaims>>,
+    /// Sessions that can be resumed with their token in place of the password
+    resumption: Option<&'a Arc<ResumableSessions>>,
 }
 
 struct RegistrationData {
@@ -64,15 +319,28 @@ struct RegistrationData {
     team: aprs_proto::primitives::TeamId,
     game: String,
     tags: Vec<String>,
+    software: ClientSoftware,
+    progress: LocationProgress,
+    /// Keeps the slot's claim from expiring while the session lasts
+    claim: Option<ClaimHold>,
+    /// Keeps the session's token from expiring while the session lasts
+    resume: Option<ResumeHold>,
+    /// Preferences the session had when it was resumed
+    resumed_preferences: Option<preferences::SlotPreferences>,
 }
 
 #[derive(Default)]
 struct ClientHandlerResult {
     modified: bool,
     responses: Vec<ClientResponse>,
+    denials: Vec<Notice>,
+    invalid_packets: Vec<InvalidPacket>,
     bounces_to_route: Vec<Value>,
     tag_update: Option<HashSet<String>>,
     pending_dp_requests: Vec<PendingDataPackageRequest>,
+    apx_commands: Vec<ApxCommand>,
+    /// What the player said in the chat, for the bridge
+    chat: Vec<String>,
 }
 
 enum UpstreamResult {
@@ -80,68 +348,493 @@ enum UpstreamResult {
         modified: bool,
         inject_response: Option<Value>,
         registration: Option<RegistrationData>,
+        /// Checksums of the last RoomInfo in the batch
+        datapackage_checksums: Option<Value>,
     },
-    SendConnectionRefused,
+    SendConnectionRefused(Refusal),
+}
+
+/// Compression used on the client side of a connection. Upstream never compresses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    None,
+    Deflate,
+}
+
+impl Compression {
+    /// We always accept permessage-deflate, so it's in use whenever the client offers it
+    fn negotiated(request: &Request) -> Self {
+        let offered = request
+            .headers()
+            .get_all("Sec-WebSocket-Extensions")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|extension| extension.split(';').next())
+            .any(|name| name.trim().eq_ignore_ascii_case("permessage-deflate"));
+
+        if offered {
+            Compression::Deflate
+        } else {
+            Compression::None
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Deflate => "deflate",
+        }
+    }
+}
+
+/// Upstream used by connections that request `/room/<room_id>`. Room events and preferences
+/// are still those of the default room.
+pub struct RoomRoute {
+    pub upstream: Arc<UpstreamServers>,
+    pub passwords: Arc<RwLock<HashMap<SlotId, String>>>,
+    pub datapackage_cache: Arc<DataPackageCache>,
+    /// Kept apart from the default room so bounces never cross AP servers
+    pub client_registry: Arc<ClientRegistry>,
+    pub login_queue: Arc<LoginQueue>,
+    pub slot_groups: Arc<RwLock<SlotGroups>>,
+}
+
+/// Shared state handed to every proxied connection
+#[derive(Clone)]
+pub struct ProxyContext {
+    pub upstream: Arc<UpstreamServers>,
+    pub events: EventBus,
+    pub passwords: Arc<RwLock<HashMap<SlotId, String>>>,
+    /// Server password of the AP room, what the Connect carries upstream once APX took the
+    /// client's for checking
+    pub upstream_room_password: Option<String>,
+    pub deathlink_exclusions: Arc<RwLock<HashSet<SlotId>>>,
+    pub deathlink_probability: Arc<DeathlinkProbability>,
+    pub deferred_datapackage_games: Arc<RwLock<HashSet<String>>>,
+    pub preferences: Arc<RwLock<PreferenceMap>>,
+    /// Item-link groups of the room, refreshed on every login
+    pub slot_groups: Arc<RwLock<SlotGroups>>,
+    pub slot_names: Arc<RwLock<HashMap<SlotId, String>>>,
+    pub motd: Arc<RwLock<Option<String>>>,
+    pub bounce_recorder: Arc<BounceRecorder>,
+    pub datapackage_cache: Arc<DataPackageCache>,
+    pub room_id: String,
+    pub client_registry: Arc<ClientRegistry>,
+    pub login_queue: Arc<LoginQueue>,
+    /// Tells whether upstream messages are to be forwarded unparsed to shed load
+    pub load_shedding: Arc<LoadShedding>,
+    pub rooms: Arc<HashMap<String, RoomRoute>>,
+    pub upstream_limiter: Arc<UpstreamLimiter>,
+    pub token_key: Option<TokenKey>,
+    pub audit_key: Option<AuditKey>,
+    pub password_failures: Arc<PasswordFailures>,
+    /// Shared upstream connections trackers are moved to once logged in, when enabled
+    pub tracker_mirrors: Option<TrackerMirrors>,
+    /// Slots locked to the client that first logged in to them, with SLOT_CLAIMING
+    pub slot_claims: Option<Arc<SlotClaims>>,
+    /// Sessions clients can resume after a restart, with RESUME_WINDOW_SECONDS
+    pub resumption: Option<Arc<ResumableSessions>>,
+    /// Origins browsers may connect from, any when `None`
+    pub allowed_origins: Option<AllowedOrigins>,
+    /// Settings that can change on reload, read once when the connection starts
+    pub live: Live,
+    pub chain_mode: ChainMode,
+    pub identity: Identity,
+    /// Where the players' chat goes to the peer room, with BRIDGE_PEER_URL
+    pub bridge: Option<Bridge>,
+    /// Slots marked ready with `!apx ready`
+    pub ready: Arc<ReadyStates>,
 }
 
 pub async fn handle_client<S>(
     socket: S,
-    upstream_url: &str,
-    signal_sender: Sender<Signal>,
-    passwords: Arc<RwLock<HashMap<SlotId, String>>>,
-    deathlink_exclusions: Arc<RwLock<HashSet<SlotId>>>,
-    deathlink_probability: Arc<DeathlinkProbability>,
-    deferred_datapackage_games: Arc<RwLock<HashSet<String>>>,
-    datapackage_cache: Arc<DataPackageCache>,
-    room_id: String,
+    context: &ProxyContext,
     inject_notext: bool,
-    client_registry: Arc<ClientRegistry>,
-) -> Result<()>
+    login_mark: LoginMark,
+) -> ProxyResult<DisconnectCause>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
+    let ProxyContext {
+        upstream,
+        events,
+        passwords,
+        upstream_room_password,
+        deathlink_exclusions,
+        deathlink_probability,
+        deferred_datapackage_games,
+        preferences,
+        slot_groups,
+        slot_names,
+        motd,
+        bounce_recorder,
+        datapackage_cache,
+        room_id,
+        client_registry,
+        login_queue,
+        load_shedding,
+        rooms,
+        upstream_limiter,
+        token_key,
+        audit_key,
+        password_failures,
+        tracker_mirrors,
+        slot_claims,
+        resumption,
+        allowed_origins,
+        live,
+        chain_mode,
+        identity,
+        bridge,
+        ready,
+    } = context.clone();
+    let LiveSettings {
+        denial_cooldown,
+        deathlink_grace,
+        deathlink_feedback,
+        room_info_timeout,
+        upstream_parse_limits,
+        client_parse_limits,
+        command_size_limits,
+        client_max_message_size,
+        strict_commands,
+        invalid_packet_limit,
+        prelogin_limits,
+        response_limits,
+        memory_cap,
+        bandwidth_quota,
+        client_droppable_commands,
+        permission_overrides,
+        transforms,
+        allow_cross_slot_hint_updates,
+        release_pacing,
+        session_limits,
+    } = live.snapshot().as_ref().clone();
+
     let state = Arc::new(Mutex::new(ConnectionState::WaitingForRoomInfo));
     let slot_info = Arc::new(Mutex::new(None::<(SlotId, String)>));
     let mut config = WebSocketConfig::default();
     config.extensions.permessage_deflate = Some(DeflateConfig::default());
-    let client_ws = accept_async_with_config(socket, Some(config)).await?;
+    if let Some(max) = client_max_message_size {
+        config.max_message_size = Some(max);
+        config.max_frame_size = Some(max);
+    }
+    let mut compression = Compression::None;
+    let mut route = None;
+    let mut origin = None;
+    let mut client_ws = accept_hdr_async_with_config(
+        socket,
+        |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+            compression = Compression::negotiated(request);
+            match identity.peer(request.headers()) {
+                Peer::Itself => {
+                    log::error!(
+                        "Refusing a connection from this very proxy at {}, its upstream loops back to it",
+                        utc_timestamp()
+                    );
+                    let mut response = ErrorResponse::new(Some("Proxy loop".to_string()));
+                    *response.status_mut() = StatusCode::LOOP_DETECTED;
+                    return Err(response);
+                }
+                Peer::Apx(version) => {
+                    log::debug!("Connection from another APX ({})", version)
+                }
+                Peer::Plain => {}
+            }
+            route = select_route(request.uri().path(), &rooms)?;
+            if let Some(value) = request.headers().get(ORIGIN) {
+                let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                if allowed_origins
+                    .as_ref()
+                    .is_some_and(|allowed| !allowed.allows(&value))
+                {
+                    let room = route.map_or(room_id.as_str(), |(room, _)| room);
+                    log::warn!(
+                        "Refusing a connection to room {} from origin {:?} at {}",
+                        room,
+                        value,
+                        utc_timestamp()
+                    );
+                    metrics::record_rejected_origin(room);
+                    let mut response = ErrorResponse::new(Some("Origin not allowed".to_string()));
+                    *response.status_mut() = StatusCode::FORBIDDEN;
+                    return Err(response);
+                }
+                log::debug!("Connection from origin {}", value);
+                origin = Some(value);
+            }
+            identity.mark(response.headers_mut());
+            Ok(response)
+        },
+        Some(config),
+    )
+    .await
+    .map_err(ProxyError::from_client)?;
+    let compression = compression.label();
 
-    let config = WebSocketConfig::default();
-    let (upstream_ws, _) = connect_async_with_config(upstream_url, Some(config), false).await?;
+    let (
+        login_room_id,
+        upstream,
+        passwords,
+        upstream_room_password,
+        datapackage_cache,
+        client_registry,
+        login_queue,
+        slot_groups,
+        slot_claims,
+        resumption,
+    ) = match route {
+        Some((room, route)) => {
+            log::debug!("Routing connection to room {} at {}", room, route.upstream);
+            // The room password, resumption and claims are the default room's. Claims can't be
+            // configured along with routes, the others are only warned about at startup.
+            (
+                room.to_string(),
+                route.upstream.clone(),
+                route.passwords.clone(),
+                None,
+                route.datapackage_cache.clone(),
+                route.client_registry.clone(),
+                route.login_queue.clone(),
+                route.slot_groups.clone(),
+                None,
+                None,
+            )
+        }
+        None => (
+            room_id.clone(),
+            upstream,
+            passwords,
+            upstream_room_password,
+            datapackage_cache,
+            client_registry,
+            login_queue,
+            slot_groups,
+            slot_claims,
+            resumption,
+        ),
+    };
+
+    // Past closing time the proxy stays up, but nobody new gets in
+    if session_limits.closed(Utc::now()) {
+        log::info!(
+            "Refusing connection at {}, the room is closed",
+            utc_timestamp()
+        );
+        let closed = serde_json::to_string(&[Notice::RoomClosed.to_print_json()])
+            .map_err(ProxyError::internal)?;
+        send::to_client(
+            &mut client_ws,
+            Message::Text(closed.into()),
+            &client_droppable_commands,
+            &room_id,
+        )
+        .await?;
+        let frame = CloseFrame {
+            code: CloseCode::Normal,
+            reason: SessionEnd::ClosingTime.reason().into(),
+        };
+        let _ = client_ws.close(Some(frame)).await;
+        metrics::record_disconnect(&room_id, DisconnectCause::ProxyPolicy.label());
+        return Ok(DisconnectCause::ProxyPolicy);
+    }
+
+    // Held until the connection ends, traded for the permit of any upstream connection that
+    // replaces this one
+    let mut upstream_permit = match upstream_limiter.try_acquire() {
+        Some(permit) => {
+            metrics::record_upstream_admission("immediate");
+            permit
+        }
+        None => {
+            log::info!(
+                "Upstream connection limit reached, queueing client for up to {:?}",
+                upstream_limiter.queue_wait()
+            );
+            let waiting = serde_json::to_string(&[Notice::RoomFullWaiting.to_print_json()])
+                .map_err(ProxyError::internal)?;
+            send::to_client(
+                &mut client_ws,
+                Message::Text(waiting.into()),
+                &client_droppable_commands,
+                &room_id,
+            )
+            .await?;
+
+            match upstream_limiter.acquire_queued().await {
+                Some(permit) => {
+                    metrics::record_upstream_admission("queued");
+                    permit
+                }
+                None => {
+                    metrics::record_upstream_admission("refused");
+                    log::warn!(
+                        "No upstream connection freed up in time, refusing client at {}",
+                        utc_timestamp()
+                    );
+                    let refused = serde_json::to_string(&[Notice::RoomFullRefused.to_print_json()])
+                        .map_err(ProxyError::internal)?;
+                    send::to_client(
+                        &mut client_ws,
+                        Message::Text(refused.into()),
+                        &client_droppable_commands,
+                        &room_id,
+                    )
+                    .await?;
+                    let _ = client_ws.close(None).await;
+                    metrics::record_disconnect(&room_id, DisconnectCause::ProxyPolicy.label());
+                    return Ok(DisconnectCause::ProxyPolicy);
+                }
+            }
+        }
+    };
+
+    let (upstream_ws, upstream_is_apx) = connect_upstream(&upstream, Some(&identity)).await?;
+    // With another APX upstream, the login is left to it
+    let chained = match (upstream_is_apx, chain_mode) {
+        (false, _) => false,
+        (true, ChainMode::Passthrough) => {
+            log::info!("Upstream is another APX, handing the login over to it");
+            true
+        }
+        (true, ChainMode::Deny) => {
+            log::warn!(
+                "Upstream {} is another APX, refusing the connection at {}. Set CHAIN_MODE=passthrough to chain proxies",
+                upstream,
+                utc_timestamp()
+            );
+            return Err(ProxyError::upstream("Upstream is another APX"));
+        }
+    };
+    let has_room_password = upstream_room_password.is_some();
+    // The Connect's password once APX has it, an upstream APX checks it itself
+    let upstream_password = (!chained).then(|| upstream_room_password.unwrap_or_default());
+
+    let (upstream_write, mut upstream_read) = split_upstream(upstream_ws);
+    let upstream_write = Arc::new(Mutex::new(upstream_write));
+    let (client_write, client_read) = client_ws.split();
 
-    let (mut upstream_write, mut upstream_read) = upstream_ws.split();
-    let (mut client_write, mut client_read) = client_ws.split();
+    let meter = Meter::new(&login_room_id);
+    let room_id_read = room_id.clone();
+    let meter_read = meter.clone();
+    let mut client_read = client_read.inspect(move |msg| {
+        if let Ok(msg) = msg {
+            meter_read.record(bandwidth::Direction::ClientToUpstream, msg.len());
+            metrics::record_payload_bytes(
+                &room_id_read,
+                "client_to_upstream",
+                compression,
+                msg.len(),
+            );
+        }
+    });
+    // What the buffers of the connection hold, it's closed once that goes over the cap
+    let memory = Arc::new(MemoryBudget::new(memory_cap));
+    // Queue for responses the proxy sends on its own, bounded so a stalled client can't make it
+    // grow without limit
+    let (response_tx, mut response_rx) =
+        outbox::response_queue(response_limits, &room_id, memory.clone());
+    let response_tx_upstream = response_tx.clone();
 
-    // Channel for sending responses back to client
-    let (response_tx, mut response_rx) = tokio::sync::mpsc::channel::<ClientResponse>(32);
-    let response_tx_for_registry = response_tx.clone();
+    let room_id_write = room_id.clone();
+    let outbound = response_tx.clone();
+    let meter_write = meter.clone();
+    let mut client_write = client_write.with(move |msg: Message| {
+        meter_write.record(bandwidth::Direction::UpstreamToClient, msg.len());
+        metrics::record_payload_bytes(&room_id_write, "upstream_to_client", compression, msg.len());
+        outbound.record_outbound(msg.len());
+        std::future::ready(Ok::<_, tungstenite::Error>(msg))
+    });
+    let (control_tx, mut control_rx) = tokio::sync::mpsc::channel::<ClientControl>(4);
     let client_id = ClientRegistry::allocate_id();
 
+    // The Connect as forwarded upstream, replayed when the upstream connection is re-established
+    let last_connect = Arc::new(Mutex::new(None::<String>));
+    // Set once a tracker moved to the shared upstream connection of its slot
+    let mirrored = Arc::new(AtomicBool::new(false));
+
     let pending_dp_requests: Arc<Mutex<Vec<PendingDataPackageRequest>>> =
         Arc::new(Mutex::new(Vec::new()));
+    let storage_requests = Arc::new(Mutex::new(StorageRequests::default()));
+    let hint_receivers = Arc::new(Mutex::new(HintReceivers::default()));
 
     let state_client = state.clone();
     let slot_info_client = slot_info.clone();
-    let signal_sender_client = signal_sender.clone();
+    let events_client = events.clone();
     let deathlink_exclusions_client = deathlink_exclusions.clone();
     let deathlink_probability_client = deathlink_probability.clone();
     let deferred_datapackage_games_client = deferred_datapackage_games.clone();
+    let preferences_client = preferences.clone();
+    let slot_groups_client = slot_groups.clone();
     let datapackage_cache_client = datapackage_cache.clone();
     let room_id_client = room_id.clone();
     let client_registry_client = client_registry.clone();
     let pending_dp_requests_client = pending_dp_requests.clone();
+    let storage_requests_client = storage_requests.clone();
+    let hint_receivers_client = hint_receivers.clone();
+    let upstream_write_client = upstream_write.clone();
+    let last_connect_client = last_connect.clone();
+    let mirrored_client = mirrored.clone();
+    let meter_client = meter.clone();
+    // Pushed back while the login waits in the queue
+    let auth_deadline = Arc::new(Mutex::new(Instant::now() + AUTH_TIMEOUT));
+    let auth_deadline_client = auth_deadline.clone();
+    // Taken by the client task
+    let permission_overrides_upstream = permission_overrides.clone();
+    let transforms_upstream = transforms.clone();
+    let command_size_limits_upstream = command_size_limits.clone();
     let client_to_upstream = async move {
+        let mut denial_cooldown = DenialCooldown::new(denial_cooldown);
+        // Reported by `!apx status`
+        let mut blocked_commands = 0;
+        // Past INVALID_PACKET_LIMIT, the next one closes the connection
+        let mut invalid_packets = 0;
+        // Dropped once logged in, authenticated clients are unaffected
+        let mut prelogin_budget = Some(PreLoginBudget::new(prelogin_limits));
+        // Taken with the first Connect, kept until the connection ends
+        let mut seat = None::<Seat>;
+        let replies = ClientReplies {
+            client_id,
+            room_id: &room_id_client,
+            client_registry: &client_registry_client,
+            events: &events_client,
+            preferences: &preferences_client,
+            deathlink_probability: &deathlink_probability_client,
+            datapackage_cache: &datapackage_cache_client,
+            ready: &ready,
+            bridge: bridge.as_ref(),
+        };
         while let Some(msg) = client_read.next().await {
-            let msg = match msg {
-                Ok(msg) => msg,
-                Err(_) => break,
-            };
+            let msg = msg.map_err(ProxyError::read_from_client)?;
+
+            if let Some(budget) = &mut prelogin_budget {
+                if matches!(*state_client.lock().await, ConnectionState::LoggedIn) {
+                    prelogin_budget = None;
+                } else if let Err(e) = budget.spend(msg.len()) {
+                    metrics::record_prelogin_budget_exceeded(&room_id_client);
+                    return Err(ProxyError::Auth(format!("Client {}", e)));
+                }
+            }
+
+            // Nothing the client sends after its Close is from the player anymore
+            if let Message::Close(frame) = msg {
+                log::debug!("Client sent a close frame, closing upstream");
+                // Upstream may well be gone already
+                let _ = upstream_write_client
+                    .lock()
+                    .await
+                    .send(Message::Close(frame))
+                    .await;
+                drop_after_close(&mut client_read, Side::Client);
+                break;
+            }
 
             // Handle ping frames directly. Respond with pong without forwarding to upstream
             // This should keep clients alive even when the upstream AP server is slow/overloaded
             if let Message::Ping(data) = &msg {
                 log::trace!("Responding to client ping directly");
-                let _ = response_tx.send(ClientResponse::Pong(data.clone())).await;
+                response_tx.send(ClientResponse::Pong(data.clone()));
                 continue;
             }
 
@@ -153,10 +846,7 @@ where
                     );
                     continue;
                 }
-                if upstream_write.send(msg).await.is_err() {
-                    log::error!("Error while writing non text message");
-                    break;
-                }
+                send::to_upstream(&upstream_write_client, msg).await?;
                 continue;
             };
 
@@ -168,66 +858,202 @@ where
                 continue;
             }
 
-            let Ok(mut commands) = parse_message(&text) else {
-                log::error!("Invalid JSON received from client, closing connection");
-                break;
+            if let Err(exceeded) = client_parse_limits.check(&text) {
+                metrics::record_json_limit(&room_id_client, "client_to_upstream", exceeded.label());
+                return Err(ProxyError::client(format!("Client message {}", exceeded)));
+            }
+            let Some(mut commands) = parse_message(&text) else {
+                return Err(ProxyError::client("Invalid JSON received from client"));
             };
+            let too_large =
+                drop_oversized(&mut commands, &text, &command_size_limits, &room_id_client);
+
+            // Past its quota, a slot is only left with gameplay
+            let mut over_quota = false;
+            if meter_client.is_over(&bandwidth_quota) {
+                let count = commands.len();
+                commands.retain(|cmd| !get_cmd(cmd).is_some_and(|cmd| bandwidth_quota.limits(cmd)));
+                over_quota = commands.len() != count;
+            }
+
+            // The stricter permissions clients are shown would be easy to get around otherwise
+            let mut not_permitted = false;
+            if let Some(overrides) = &permission_overrides {
+                let slot = slot_info_client
+                    .lock()
+                    .await
+                    .as_ref()
+                    .map(|(slot, _)| *slot);
+                let goal_reached = match slot {
+                    Some(slot) => client_registry_client.reached_goal(slot).await,
+                    None => false,
+                };
+                let count = commands.len();
+                commands.retain(|cmd| permitted(cmd, overrides, goal_reached));
+                not_permitted = commands.len() != count;
+            }
+
+            // Some upstream versions let anyone change the priority of anyone's hints
+            let mut hint_blocked = false;
+            let logged_in_as = slot_info_client.lock().await.clone();
+            if !allow_cross_slot_hint_updates && let Some((slot, name)) = logged_in_as {
+                let receivers = hint_receivers_client.lock().await;
+                let slot_groups = slot_groups_client.read().await;
+                let count = commands.len();
+                commands.retain(|cmd| {
+                    let Some(update) = update_hint(cmd) else {
+                        return true;
+                    };
+                    let allowed = receivers.may_update(&update, &slot, &slot_groups);
+                    if !allowed {
+                        log::warn!(
+                            "Dropping UpdateHint from slot {} ({}) for location {} of slot {} (status {:?}), it doesn't receive the item",
+                            slot.0,
+                            name,
+                            update.location,
+                            update.player.0,
+                            update.status
+                        );
+                    }
+                    allowed
+                });
+                hint_blocked = commands.len() != count;
+            }
+
+            // The shared connection is logged in to the slot, the trackers on it only read
+            let mut tracker_denied = false;
+            if mirrored_client.load(Ordering::Relaxed) {
+                let count = commands.len();
+                commands.retain(|cmd| get_cmd(cmd).is_some_and(mirror::is_read));
+                tracker_denied = commands.len() != count;
+                if tracker_denied {
+                    log::warn!(
+                        "Dropping {} commands other than reads from a tracker on the shared upstream connection",
+                        count - commands.len()
+                    );
+                }
+            }
 
-            let (mut handler_result, slot_info_snapshot, exclusions_snapshot) = {
+            let (mut handler_result, relogin, snapshot) = {
                 let mut state = state_client.lock().await;
+                let was_logged_in = matches!(*state, ConnectionState::LoggedIn);
+                let mut storage = storage_requests_client.lock().await;
                 let slot_info = slot_info_client.lock().await;
                 let exclusions = deathlink_exclusions_client.read().await;
+                let preferences = preferences_client.read().await;
+                let slot_groups = slot_groups_client.read().await;
                 let deferred_dp_games = deferred_datapackage_games_client.read().await;
-                match handle_client_messages(
+                let result = handle_client_messages(
                     &mut state,
+                    &mut storage,
                     &mut commands,
                     &slot_info,
-                    &signal_sender_client,
-                    &exclusions,
+                    &events_client,
+                    &preferences,
+                    &slot_groups,
                     &deferred_dp_games,
                     &datapackage_cache_client,
                     inject_notext,
+                    strict_commands,
+                    upstream_password.as_deref(),
+                )
+                .await?;
+                let snapshot = SlotSnapshot {
+                    slot_info: slot_info.clone(),
+                    exclusions: exclusions.clone(),
+                    preferences: preferences.clone(),
+                    slot_groups: slot_groups.clone(),
+                };
+                (
+                    result,
+                    was_logged_in && !matches!(*state, ConnectionState::LoggedIn),
+                    snapshot,
                 )
-                .await
-                {
-                    Ok(result) => {
-                        let si = slot_info.clone();
-                        let excl = exclusions.clone();
-                        (result, si, excl)
-                    }
-                    Err(e) => {
-                        log::error!("Error while handling message from client: {}", e);
-                        break;
-                    }
-                }
             };
 
+            // The session ends with the Connect, the next one starts with its Connected
+            if relogin {
+                if let Some((slot, name)) = &snapshot.slot_info {
+                    log::info!(
+                        "Slot {} ({}) is logging in again on the same connection",
+                        slot.0,
+                        name
+                    );
+                }
+                client_registry_client.deregister(client_id).await;
+            }
+
+            if tracker_denied {
+                handler_result.denials.push(Notice::TrackerReadOnly);
+                handler_result.modified = true;
+            }
+            if too_large {
+                handler_result.denials.push(Notice::CommandTooLarge);
+                handler_result.modified = true;
+            }
+            if over_quota {
+                handler_result.denials.push(Notice::QuotaExceeded);
+                handler_result.modified = true;
+            }
+            if not_permitted {
+                handler_result.denials.push(Notice::CommandNotPermitted);
+                handler_result.modified = true;
+            }
+            if hint_blocked {
+                handler_result.denials.push(Notice::HintUpdateBlocked);
+                handler_result.modified = true;
+            }
+            if transforms.apply(&room_id_client, Direction::FromClient, &mut commands) {
+                handler_result.modified = true;
+            }
+
             if !handler_result.pending_dp_requests.is_empty() {
                 let mut pending = pending_dp_requests_client.lock().await;
                 pending.append(&mut handler_result.pending_dp_requests);
             }
 
             for bounce in &handler_result.bounces_to_route {
-                if let Some((slot, _)) = &slot_info_snapshot {
+                if let Some((slot, _)) = &snapshot.slot_info {
                     metrics::record_message(&room_id_client, *slot, "Bounce", "client_to_upstream");
+                    if let Some(event) = bounce_recorder.record(*slot, bounce, Utc::now()) {
+                        events_client.publish(event);
+                    }
                 }
                 client_registry_client
                     .route_bounce(
                         client_id,
                         bounce,
-                        &exclusions_snapshot,
+                        &snapshot.exclusions,
+                        &snapshot.preferences,
+                        &snapshot.slot_groups,
                         &deathlink_probability_client,
+                        deathlink_grace,
+                        deathlink_feedback,
                         &room_id_client,
                     )
                     .await;
             }
 
-            if let Some(tags) = handler_result.tag_update {
-                client_registry_client.update_tags(client_id, tags).await;
+            // The client gets the chance to fix what it sends, up to a point
+            invalid_packets += handler_result.invalid_packets.len();
+            if invalid_packets > invalid_packet_limit {
+                return Err(ProxyError::client(format!(
+                    "Sent {} invalid packets",
+                    invalid_packets
+                )));
             }
+            blocked_commands += handler_result.denials.len() + handler_result.invalid_packets.len();
+            replies
+                .answer(
+                    &mut handler_result,
+                    &snapshot,
+                    &mut denial_cooldown,
+                    blocked_commands,
+                )
+                .await?;
 
             for response in handler_result.responses {
-                if response_tx.send(response).await.is_err() {
+                if !response_tx.send(response) {
                     break;
                 }
             }
@@ -237,7 +1063,60 @@ where
                 continue;
             }
 
-            if let Some((slot, name)) = &slot_info_snapshot {
+            // Past the room's capacity, the Connect waits for a seat before going out
+            if seat.is_none() && commands.iter().any(|cmd| get_cmd(cmd) == Some("Connect")) {
+                let player = match &*state_client.lock().await {
+                    ConnectionState::WaitingForConnected { tags, name, .. }
+                        if !mirror::is_tracker(tags) =>
+                    {
+                        Some(name.clone())
+                    }
+                    _ => None,
+                };
+                if let Some(name) = player {
+                    seat = match login_queue.admit(client_id, &name) {
+                        Admission::Seated(seat) => Some(seat),
+                        Admission::Queued(ticket) => {
+                            let max_wait = login_queue.max_wait();
+                            *auth_deadline_client.lock().await =
+                                Instant::now() + max_wait + AUTH_TIMEOUT;
+                            let waited = wait_for_seat(
+                                ticket,
+                                &mut client_read,
+                                &response_tx,
+                                max_wait,
+                                &room_id_client,
+                            )
+                            .await?;
+                            *auth_deadline_client.lock().await = Instant::now() + AUTH_TIMEOUT;
+                            match waited {
+                                Ok(seat) => Some(seat),
+                                Err(cause) => return Ok(cause),
+                            }
+                        }
+                    };
+                }
+            }
+
+            if let Some(connect) = commands.iter().find(|cmd| get_cmd(cmd) == Some("Connect")) {
+                *last_connect_client.lock().await = serde_json::to_string(&[connect]).ok();
+            }
+
+            if let Some((slot, _)) = &snapshot.slot_info {
+                for locations in commands.iter().filter_map(checked_locations) {
+                    client_registry_client
+                        .time_checks(client_id, *slot, &locations)
+                        .await;
+                    client_registry_client
+                        .record_checks(*slot, &locations, &room_id_client)
+                        .await;
+                }
+                if commands.iter().any(reports_goal) {
+                    client_registry_client.record_goal(*slot).await;
+                }
+            }
+
+            if let Some((slot, name)) = &snapshot.slot_info {
                 log::debug!(
                     "[slot {} ({})] Forwarding {} ({:?}) commands to upstream (modified: {})",
                     slot.0,
@@ -268,42 +1147,95 @@ where
             }
 
             let msg_to_send = if handler_result.modified {
-                let Ok(serialized) = serde_json::to_string(&commands) else {
-                    log::error!("Error while reserializing commands");
-                    break;
-                };
+                let serialized = serde_json::to_string(&commands).map_err(ProxyError::internal)?;
                 Message::Text(serialized.into())
             } else {
                 Message::Text(text)
             };
 
-            if upstream_write.send(msg_to_send).await.is_err() {
-                break;
-            }
+            send::to_upstream(&upstream_write_client, msg_to_send).await?;
         }
+        Ok::<_, ProxyError>(DisconnectCause::ClientClose)
     };
 
     let state_upstream = state.clone();
     let passwords_upstream = passwords.clone();
     let deathlink_exclusions_upstream = deathlink_exclusions.clone();
     let deathlink_probability_upstream = deathlink_probability.clone();
+    let preferences_upstream = preferences.clone();
+    let slot_groups_upstream = slot_groups.clone();
     let slot_info_upstream = slot_info.clone();
+    let meter_upstream = meter.clone();
     let room_id_upstream = room_id.clone();
     let inject_notext_upstream = inject_notext;
     let client_registry_cleanup = client_registry.clone();
     let datapackage_cache_upstream = datapackage_cache.clone();
     let pending_dp_requests_upstream = pending_dp_requests.clone();
+    let storage_requests_upstream = storage_requests.clone();
+    let hint_receivers_upstream = hint_receivers.clone();
+    let upstream_write_upstream = upstream_write.clone();
+    let events_upstream = events.clone();
+    let last_connect_upstream = last_connect.clone();
+    let mirrored_upstream = mirrored.clone();
+    let memory_upstream = memory.clone();
+    let client_write_upstream = &mut client_write;
+    let response_rx_upstream = &mut response_rx;
     let upstream_to_client = async move {
+        let client_write = client_write_upstream;
+        let response_rx = response_rx_upstream;
+        // Commands that arrived with the Connected of a re-established upstream connection
+        let mut replayed = None;
+        // From the last RoomInfo, to notice the datapackage changing under the client
+        let mut datapackage_checksums: Option<Value> = None;
+        // Started by the first login, neither a new upstream nor logging in again resets it
+        let mut session: Option<SessionTimer> = None;
+        // Those of the slot the connection is logged in to, swapped when it logs in again
+        let mut claim: Option<ClaimHold> = None;
+        let mut resume_hold: Option<ResumeHold> = None;
+        let mut pacer = release_pacing.map(Pacer::new);
+        // A half started AP server accepts the connection and then says nothing
+        let mut room_info_deadline = Some(Instant::now() + room_info_timeout);
+        let logins = Logins {
+            state: &state_upstream,
+            storage: &storage_requests_upstream,
+            slot_info: &slot_info_upstream,
+            meter: &meter_upstream,
+            passwords: &passwords_upstream,
+            deathlink_exclusions: &deathlink_exclusions_upstream,
+            deathlink_probability: &deathlink_probability_upstream,
+            preferences: &preferences_upstream,
+            slot_groups: &slot_groups_upstream,
+            slot_names: &slot_names,
+            tokens: token_key.as_ref(),
+            audit_key: audit_key.as_ref(),
+            claims: slot_claims.as_ref(),
+            resumption: resumption.as_ref(),
+            login_room_id: &login_room_id,
+            room_id: &room_id_upstream,
+            chained,
+            has_room_password,
+            inject_notext: inject_notext_upstream,
+            events: &events_upstream,
+            password_failures: &password_failures,
+        };
         loop {
+            let session_step_at = session.as_ref().map(SessionTimer::next);
+            let paced_chunk_at = pacer
+                .as_ref()
+                .and_then(|pacer| pacer.next_at(Instant::now()));
             tokio::select! {
-                msg = upstream_read.next() => {
+                msg = next_upstream_message(&mut replayed, &mut upstream_read) => {
                     let Some(msg) = msg else {
                         break;
                     };
-                    let msg = match msg {
-                        Ok(msg) => msg,
-                        Err(_) => break,
-                    };
+                    let msg = msg.map_err(ProxyError::read_from_upstream)?;
+
+                    if let Message::Close(frame) = msg {
+                        log::debug!("Upstream sent a close frame, closing the client connection");
+                        let _ = client_write.send(Message::Close(frame)).await;
+                        drop_after_close(&mut upstream_read, Side::Upstream);
+                        break;
+                    }
 
                     let Message::Text(text) = msg else {
                         if msg.len() > MAX_MESSAGE_SIZE {
@@ -313,10 +1245,7 @@ where
                             );
                             continue;
                         }
-                        if client_write.send(msg).await.is_err() {
-                            log::error!("Error while writing non text message");
-                            break;
-                        }
+                        send::to_client(&mut *client_write, msg, &client_droppable_commands, &room_id_upstream).await?;
                         continue;
                     };
 
@@ -327,81 +1256,184 @@ where
                         );
                         continue;
                     }
+                    load_shedding.count();
 
-                    let Ok(mut commands) = parse_message(&text) else {
-                        log::error!("Invalid JSON received from upstream, closing connection");
-                        break;
-                    };
+                    // Goals of the other slots are only seen through the registry
+                    if let Some(pacer) = pacer.as_mut()
+                        && let Some(at) = client_registry.last_goal_at().await
+                    {
+                        pacer.goal_reached(at, Instant::now());
+                    }
+                    // What's paced has to be parsed, to be split into chunks
+                    let pacing = pacer.as_ref().is_some_and(|pacer| pacer.is_pacing(Instant::now()));
 
-                    // Extract slot info from Connected message
-                    for cmd in &commands {
-                        if get_cmd(cmd) == Some("Connected")
-                            && let Ok(connected) = parse_as::<Connected>(cmd)
-                        {
-                            let player_name = connected
-                                .players
-                                .iter()
-                                .find(|p| p.slot == connected.slot)
-                                .map(|p| p.name.clone())
-                                .unwrap_or_else(|| format!("Unknown-{}", connected.slot.0));
-
-                            let mut info = slot_info_upstream.lock().await;
-                            *info = Some((connected.slot, player_name));
-                            break;
+                    if !pacing
+                        && let Some(cmd_type) = passthrough_cmd(&text, &transforms_upstream)
+                        && matches!(*state_upstream.lock().await, ConnectionState::LoggedIn)
+                    {
+                        if let Some((slot, _)) = &*slot_info_upstream.lock().await {
+                            metrics::record_message(&room_id_upstream, *slot, cmd_type, "upstream_to_client");
+                        }
+                        log::debug!("Forwarding {} ({} bytes) to client unparsed", cmd_type, text.len());
+                        send::to_client(&mut *client_write, Message::Text(text), &client_droppable_commands, &room_id_upstream).await?;
+                        continue;
+                    }
+
+                    // Under pressure only what APX rewrites or drops is still looked into, the
+                    // progress and latency of what's skipped go unrecorded until it's over
+                    if !pacing
+                        && load_shedding.is_degraded()
+                        && let Some(cmd_type) = degraded_cmd(&text, &transforms_upstream)
+                        && matches!(*state_upstream.lock().await, ConnectionState::LoggedIn)
+                    {
+                        if let Some((slot, _)) = &*slot_info_upstream.lock().await {
+                            metrics::record_message(&room_id_upstream, *slot, &cmd_type, "upstream_to_client");
                         }
+                        send::to_client(&mut *client_write, Message::Text(text), &client_droppable_commands, &room_id_upstream).await?;
+                        continue;
                     }
 
-                    let (result, slot_info_snapshot) = {
-                        let mut state = state_upstream.lock().await;
-                        let passwords_read = passwords_upstream.read().await;
-                        let exclusions = deathlink_exclusions_upstream.read().await;
-                        let slot_info_read = slot_info_upstream.lock().await;
-                        let r = match handle_upstream_messages(
-                            &mut state,
-                            &mut commands,
-                            &passwords_read,
-                            &exclusions,
-                            &slot_info_read,
-                            &deathlink_probability_upstream,
-                            inject_notext_upstream,
-                        ) {
-                            Ok(result) => result,
-                            Err(e) => {
-                                log::error!("Error while validating upstream message: {}", e);
-                                break;
+                    // The client may well cope with whatever we can't parse. During login we have to
+                    // inspect every packet though, so there is no choice but to give up.
+                    let mut commands = match parse_upstream(&text, &upstream_parse_limits, &room_id_upstream) {
+                        Ok(commands) => commands,
+                        Err(reason) => {
+                            metrics::record_upstream_parse_failure(&room_id_upstream);
+                            log::warn!(
+                                "Failed to parse upstream message ({}): {}",
+                                reason,
+                                json_limits::sample(&text, 200)
+                            );
+                            if !matches!(*state_upstream.lock().await, ConnectionState::LoggedIn) {
+                                return Err(ProxyError::upstream(format!(
+                                    "Unparsable upstream message during login: {}",
+                                    reason
+                                )));
                             }
-                        };
-                        (r, slot_info_read.clone())
+                            send::to_client(&mut *client_write, Message::Text(text), &client_droppable_commands, &room_id_upstream).await?;
+                            continue;
+                        }
                     };
 
-                    let (mut modified, inject_response, registration) = match result {
-                        UpstreamResult::Continue { modified, inject_response, registration } => (modified, inject_response, registration),
-                        UpstreamResult::SendConnectionRefused => {
-                            // Send ConnectionRefused to client and revert state to allow retry
-                            let refused = serde_json::json!({
-                                "cmd": "ConnectionRefused",
-                                "errors": ["InvalidPassword"]
-                            });
-                            let refused_msg =
-                                Message::Text(serde_json::to_string(&[refused]).unwrap().into());
-                            if client_write.send(refused_msg).await.is_err() {
-                                break;
+                    if room_info_deadline.is_some()
+                        && commands.iter().any(|cmd| get_cmd(cmd) == Some("RoomInfo"))
+                    {
+                        room_info_deadline = None;
+                        upstream.room_info_received();
+                    }
+
+                    // Not ours to police, but worth knowing about
+                    for (index, size) in command_size_limits_upstream.oversized(&text, &commands) {
+                        let cmd = get_cmd(&commands[index]).unwrap_or("Unknown");
+                        log::warn!("Forwarding oversized {} ({} bytes) from upstream", cmd, size);
+                        metrics::record_oversized_command(&room_id_upstream, cmd, "upstream_to_client");
+                    }
+
+                    // Slots that didn't go through the proxy are announced before they release
+                    if let Some(pacer) = pacer.as_mut()
+                        && commands.iter().any(release_pacing::is_goal_notice)
+                    {
+                        pacer.goal_reached(Instant::now(), Instant::now());
+                    }
+
+                    let (result, mut login) = logins.check(&mut commands).await?;
+                    let room_password_refused = logins.report_upstream_refusals(&commands, &login).await;
+
+                    let (mut modified, inject_response, registration, checksums) = match result {
+                        UpstreamResult::Continue {
+                            modified,
+                            inject_response,
+                            registration,
+                            datapackage_checksums,
+                        } => (modified, inject_response, registration, datapackage_checksums),
+                        UpstreamResult::SendConnectionRefused(refusal) => {
+                            // Upstream is logged in to the refused slot. A connection that was
+                            // logged in before starts over on a new one, rather than staying on
+                            // that slot until the next Connect.
+                            if login.relogin {
+                                // The old connection only gives its permit back once it's replaced
+                                let Some(permit) = upstream_limiter.acquire_queued().await else {
+                                    log::warn!("No upstream connection freed up to start over after a refused login");
+                                    logins.refuse(&mut *client_write, &client_droppable_commands, refusal, login).await?;
+                                    let refused = serde_json::to_string(&[Notice::RoomFullRefused.to_print_json()])
+                                        .map_err(ProxyError::internal)?;
+                                    send::to_client(&mut *client_write, Message::Text(refused.into()), &client_droppable_commands, &room_id_upstream).await?;
+                                    let _ = client_write.send(Message::Close(None)).await;
+                                    return Ok(DisconnectCause::ProxyPolicy);
+                                };
+                                let mut upstream_write = upstream_write_upstream.lock().await;
+                                let (new_write, new_read) = fresh_upstream(&upstream).await?;
+                                let _ = upstream_write.close().await;
+                                *upstream_write = new_write;
+                                drop(std::mem::replace(&mut upstream_permit, permit));
+                                upstream_read = new_read;
+                                replayed = None;
+                                *slot_info_upstream.lock().await = None;
+                                *storage_requests_upstream.lock().await = StorageRequests::default();
+                                log::info!("Started over on a new upstream connection after a refused login");
                             }
 
-                            // Revert state back to WaitingForConnect to allow retry
-                            let mut state = state_upstream.lock().await;
-                            *state = ConnectionState::WaitingForConnect;
+                            logins.refuse(&mut *client_write, &client_droppable_commands, refusal, login).await?;
                             continue;
                         }
                     };
 
+                    // Only now that APX accepted the login is the slot the connection's
+                    let slot_info_snapshot = match (&registration, login.connected_as.take()) {
+                        (Some(_), Some(connected_as)) => logins.accept(connected_as).await,
+                        _ => slot_info_upstream.lock().await.clone(),
+                    };
+
                     if let Some(response) = inject_response {
                         commands.push(response);
                         modified = true;
                     }
+                    if room_password_refused {
+                        commands.push(Notice::UpstreamPasswordRejected.to_print_json());
+                        modified = true;
+                    }
+
+                    if let Some(checksums) = checksums {
+                        if datapackage_checksums.as_ref().is_some_and(|last| *last != checksums) {
+                            log::info!("Upstream datapackage checksums changed mid-session");
+                            commands.push(Notice::DataPackageChanged.to_print_json());
+                            modified = true;
+                        }
+                        datapackage_checksums = Some(checksums);
+                    }
+
+                    if let Some(overrides) = &permission_overrides_upstream {
+                        for cmd in commands.iter_mut() {
+                            modified |= overrides.restrict(cmd);
+                        }
+                    }
+                    modified |= transforms_upstream.apply(&room_id_upstream, Direction::FromUpstream, &mut commands);
 
                     let just_connected = registration.is_some();
+                    let mirrored = tracker_mirrors.as_ref().filter(|_| {
+                        registration.as_ref().is_some_and(|reg| mirror::is_tracker(&reg.tags))
+                    });
                     if let Some(reg) = registration {
+                        login_mark.set();
+                        if let (Some(name), Some(attempt)) = (login.name, login.audit) {
+                            events_upstream.publish(RoomEvent::LoginAccepted {
+                                slot: reg.slot,
+                                name,
+                                attempt,
+                            });
+                        }
+                        let logged_in_at = Instant::now();
+                        if session.is_none() {
+                            session = session_limits.timer(logged_in_at, Utc::now());
+                        }
+                        claim = reg.claim;
+                        resume_hold = reg.resume;
+                        if let Some(resumed) = reg.resumed_preferences {
+                            // Preferences changed since the session ended are kept
+                            preferences_upstream.write().await.entry(reg.slot).or_insert(resumed);
+                        }
+                        let fingerprint = fingerprint::fingerprint(&reg.game, &reg.software, &reg.tags);
+                        metrics::record_client_version(&fingerprint);
                         client_registry.register(
                             client_id,
                             ClientEntry {
@@ -409,9 +1441,38 @@ where
                                 team: reg.team,
                                 game: reg.game,
                                 tags: reg.tags.into_iter().collect(),
-                                sender: response_tx_for_registry.clone(),
+                                software: reg.software,
+                                fingerprint,
+                                origin: origin.clone(),
+                                sender: response_tx_upstream.clone(),
+                                control: control_tx.clone(),
+                                logged_in_at,
+                                session_ends_at: session.as_ref().map(SessionTimer::deadline),
+                                memory: memory_upstream.clone(),
                             },
                         ).await;
+                        client_registry.init_progress(reg.slot, reg.progress, &room_id_upstream).await;
+                        if client_registry.record_login(reg.slot, &room_id_upstream).await {
+                            log::info!("Slot {} keeps reconnecting, telling the player", reg.slot.0);
+                            commands.push(Notice::UnstableConnection.to_print_json());
+                        }
+                        stats::record_session(&room_id_upstream, reg.slot);
+                    } else if let Some((slot, _)) = &slot_info_snapshot {
+                        for locations in commands.iter().filter_map(checked_locations) {
+                            client_registry.record_checks(*slot, &locations, &room_id_upstream).await;
+                        }
+                    }
+                    if let Some((slot, _)) = &slot_info_snapshot {
+                        for received in commands.iter().filter_map(received_items) {
+                            client_registry.record_received(*slot, &received, &room_id_upstream).await;
+                        }
+                        client_registry.record_items_sent(&commands, &room_id_upstream).await;
+                    }
+                    {
+                        let mut receivers = hint_receivers_upstream.lock().await;
+                        for cmd in &commands {
+                            receivers.learn(cmd);
+                        }
                     }
 
                     if let Some((slot, name)) = &slot_info_snapshot {
@@ -448,23 +1509,32 @@ where
                         continue;
                     }
 
-                    let msg_to_send = if modified {
-                        let Ok(serialized) = serde_json::to_string(&commands) else {
-                            log::error!("Error while reserializing commands");
-                            break;
-                        };
-                        drop(commands);
-                        Message::Text(serialized.into())
+                    // The login itself is never held back
+                    if let Some(pacer) = pacer.as_mut()
+                        && !just_connected
+                        && pacer.is_pacing(Instant::now())
+                        && matches!(*state_upstream.lock().await, ConnectionState::LoggedIn)
+                    {
+                        pacer.push(commands);
                     } else {
-                        drop(commands);
-                        Message::Text(text)
-                    };
+                        let msg_to_send = if modified {
+                            let serialized = serde_json::to_string(&commands).map_err(ProxyError::internal)?;
+                            drop(commands);
+                            Message::Text(serialized.into())
+                        } else {
+                            drop(commands);
+                            Message::Text(text)
+                        };
 
-                    if client_write.send(msg_to_send).await.is_err() {
-                        break;
+                        send::to_client(&mut *client_write, msg_to_send, &client_droppable_commands, &room_id_upstream).await?;
                     }
 
                     if just_connected {
+                        let motd_messages = motd.read().await.as_deref().map(motd::build_messages);
+                        if let Some(messages) = motd_messages.filter(|m| !m.is_empty()) {
+                            response_tx_upstream.send(ClientResponse::Values(messages));
+                        }
+
                         let pending: Vec<_> = std::mem::take(&mut *pending_dp_requests_upstream.lock().await);
                         for req in pending {
                             let response = match req {
@@ -481,72 +1551,966 @@ where
                                     Arc::clone(datapackage_cache_upstream.full_response())
                                 }
                             };
-                            if client_write.send(Message::Text((*response).into())).await.is_err() {
-                                break;
-                            }
+                            send::to_client(&mut *client_write, Message::Text((*response).into()), &client_droppable_commands, &room_id_upstream).await?;
                         }
                     }
+
+                    // The login is done on the client's own connection, the password check included
+                    if let Some(mirrors) = mirrored
+                        && let Some((slot, _)) = &slot_info_snapshot
+                        && let Some(connect) = last_connect_upstream.lock().await.clone()
+                    {
+                        let (mirror_write, mirror_read) =
+                            mirrors.attach(&login_room_id, &upstream, *slot, &connect);
+                        let mut upstream_write = upstream_write_upstream.lock().await;
+                        let _ = upstream_write.close().await;
+                        *upstream_write = mirror_write;
+                        upstream_read = mirror_read;
+                        replayed = None;
+                        mirrored_upstream.store(true, Ordering::Relaxed);
+                        log::info!("Moved tracker for slot {} to the shared upstream connection", slot.0);
+                    }
+                }
+                response_msg = response_rx.recv() => {
+                    send::to_client(&mut *client_write, response_msg, &client_droppable_commands, &room_id_upstream).await?;
+                }
+                _ = sleep_until(room_info_deadline) => {
+                    log::warn!("Upstream {} sent no RoomInfo within {:?}", upstream, room_info_timeout);
+                    metrics::record_roominfo_timeout(&room_id_upstream);
+                    if upstream.room_info_timed_out() {
+                        log::error!(
+                            "Upstream {} is unresponsive, none of the last connections got a RoomInfo",
+                            upstream
+                        );
+                    }
+                    let notice = serde_json::to_string(&[Notice::UpstreamNotResponding.to_print_json()])
+                        .map_err(ProxyError::internal)?;
+                    send::to_client(&mut *client_write, Message::Text(notice.into()), &client_droppable_commands, &room_id_upstream).await?;
+                    return Err(ProxyError::UpstreamTimeout(format!(
+                        "No RoomInfo within {:?}",
+                        room_info_timeout
+                    )));
+                }
+                _ = sleep_until(paced_chunk_at) => {
+                    if let Some(chunk) = pacer.as_mut().and_then(|pacer| pacer.pop(Instant::now())) {
+                        send::to_client(&mut *client_write, chunk, &client_droppable_commands, &room_id_upstream).await?;
+                    }
                 }
-                Some(response) = response_rx.recv() => {
-                    let response_msg = match response {
-                        ClientResponse::Values(values) => {
-                            Message::Text(serde_json::to_string(&values).unwrap().into())
+                _ = sleep_until(session_step_at) => {
+                    let Some(timer) = session.as_mut() else {
+                        continue;
+                    };
+                    match timer.step(Instant::now()) {
+                        SessionStep::Warn(left) => {
+                            let warning = serde_json::to_string(&[timer.warning(left)])
+                                .map_err(ProxyError::internal)?;
+                            send::to_client(&mut *client_write, Message::Text(warning.into()), &client_droppable_commands, &room_id_upstream).await?;
+                        }
+                        SessionStep::End(end) => {
+                            log::info!("Closing connection of client {}: {}", client_id, end.reason());
+                            let frame = CloseFrame {
+                                code: CloseCode::Normal,
+                                reason: end.reason().into(),
+                            };
+                            let _ = client_write.send(Message::Close(Some(frame))).await;
+                            return Ok(DisconnectCause::ProxyPolicy);
                         }
-                        ClientResponse::Raw(raw) => {
-                            Message::Text((*raw).into())
+                    }
+                }
+                Some(control) = control_rx.recv() => {
+                    let reply = match control {
+                        ClientControl::ReconnectUpstream(reply) => reply,
+                        ClientControl::Kick => {
+                            log::info!("Closing connection of client {}: kicked", client_id);
+                            let _ = client_write.send(Message::Close(Some(kick_frame()))).await;
+                            return Ok(DisconnectCause::ProxyPolicy);
                         }
-                        ClientResponse::Pong(data) => {
-                            Message::Pong(data)
+                        ClientControl::Say(text) => {
+                            send::to_upstream(&upstream_write_upstream, say_message(&text)).await?;
+                            continue;
                         }
                     };
-                    if client_write.send(response_msg).await.is_err() {
-                        break;
+                    let result: Result<(UpstreamRead, Option<Message>, UpstreamPermit), ReconnectError> = async {
+                        if !matches!(*state_upstream.lock().await, ConnectionState::LoggedIn) {
+                            return Err(ReconnectError::NotReady);
+                        }
+                        let connect = last_connect_upstream
+                            .lock()
+                            .await
+                            .clone()
+                            .ok_or(ReconnectError::NotReady)?;
+                        let slot = slot_info_upstream.lock().await.as_ref().map(|(slot, _)| *slot);
+                        // Both connections are open until the new one is logged in
+                        let permit = upstream_limiter.try_acquire().ok_or_else(|| {
+                            ReconnectError::Failed("Upstream connection limit reached".to_string())
+                        })?;
+
+                        // Holding the writer keeps client messages queued until the new
+                        // connection is logged in
+                        let mut upstream_write = upstream_write_upstream.lock().await;
+                        let (new_write, new_read, remaining) =
+                            reconnect_upstream(&upstream, &connect, slot)
+                                .await
+                                .map_err(|e| ReconnectError::Failed(e.to_string()))?;
+                        let _ = upstream_write.close().await;
+                        *upstream_write = new_write;
+                        Ok((new_read, remaining, permit))
                     }
-                }
-            }
+                    .await;
+
+                    let result = match result {
+                        Ok((new_read, remaining, permit)) => {
+                            log::info!("Re-established upstream connection for client {}", client_id);
+                            upstream_read = new_read;
+                            replayed = remaining;
+                            drop(std::mem::replace(&mut upstream_permit, permit));
+                            Ok(())
+                        }
+                        Err(ReconnectError::Failed(e)) => {
+                            log::warn!(
+                                "Failed to re-establish upstream connection for client {}, keeping the old one: {}",
+                                client_id,
+                                e
+                            );
+                            Err(ReconnectError::Failed(e))
+                        }
+                        Err(e) => Err(e),
+                    };
+                    let _ = reply.send(result);
+
+                    // Requests that queued up while reconnecting are refused rather than
+                    // reconnecting again right away, a kick still goes through
+                    let mut kicked = false;
+                    while let Ok(control) = control_rx.try_recv() {
+                        match control {
+                            ClientControl::ReconnectUpstream(reply) => {
+                                let _ = reply.send(Err(ReconnectError::NotReady));
+                            }
+                            ClientControl::Kick => kicked = true,
+                            ClientControl::Say(text) => {
+                                send::to_upstream(&upstream_write_upstream, say_message(&text))
+                                    .await?;
+                            }
+                        }
+                    }
+                    if kicked {
+                        log::info!("Closing connection of client {}: kicked", client_id);
+                        let _ = client_write.send(Message::Close(Some(kick_frame()))).await;
+                        return Ok(DisconnectCause::ProxyPolicy);
+                    }
+                }
+            }
         }
+        Ok::<_, ProxyError>(DisconnectCause::UpstreamClose)
     };
 
     let state_timeout = state.clone();
     let auth_timeout = async move {
-        tokio::time::sleep(AUTH_TIMEOUT).await;
-        let state = state_timeout.lock().await;
-        if !matches!(*state, ConnectionState::LoggedIn) {
-            log::warn!(
-                "Client failed to authenticate within {:?}, closing connection",
-                AUTH_TIMEOUT
-            );
-            true
-        } else {
-            drop(state);
-            std::future::pending::<bool>().await
+        loop {
+            let deadline = *auth_deadline.lock().await;
+            tokio::time::sleep_until(deadline.into()).await;
+            if matches!(*state_timeout.lock().await, ConnectionState::LoggedIn) {
+                return std::future::pending::<bool>().await;
+            }
+            if *auth_deadline.lock().await <= Instant::now() {
+                return true;
+            }
         }
     };
 
-    tokio::select! {
-        _ = client_to_upstream => log::debug!("Client connection closed"),
-        _ = upstream_to_client => log::debug!("Upstream connection closed"),
+    let result = tokio::select! {
+        result = client_to_upstream => result,
+        result = upstream_to_client => result,
         timed_out = auth_timeout => {
             if timed_out {
-                log::debug!("Connection closed due to auth timeout");
+                Err(ProxyError::Auth(format!(
+                    "Client failed to authenticate within {:?}",
+                    AUTH_TIMEOUT
+                )))
+            } else {
+                Ok(DisconnectCause::ProxyPolicy)
             }
         }
-    }
+        () = memory.exceeded() => {
+            let usage = memory.usage();
+            log::warn!(
+                "Closing connection of client {}: it holds about {} bytes, over the cap of {:?}",
+                client_id,
+                usage.total_bytes,
+                usage.cap_bytes
+            );
+            Ok(DisconnectCause::MemoryCap)
+        }
+    };
 
     client_registry_cleanup.deregister(client_id).await;
-    Ok(())
+
+    // The proxy letting the client go usually has it queue a notice saying why
+    if matches!(result, Ok(DisconnectCause::ProxyPolicy)) {
+        while let Some(msg) = response_rx.recv().now_or_never() {
+            if client_write.send(msg).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    let cause = match &result {
+        Ok(cause) => *cause,
+        Err(e) => e.disconnect_cause(),
+    };
+    log::debug!("Connection ended at {}: {}", utc_timestamp(), cause.label());
+    metrics::record_disconnect(&room_id, cause.label());
+
+    // What's queued is what went over the cap, it isn't flushed
+    if matches!(result, Ok(DisconnectCause::MemoryCap)) {
+        let frame = CloseFrame {
+            code: CloseCode::Policy,
+            reason: MEMORY_CAP_REASON.into(),
+        };
+        let _ = client_write.send(Message::Close(Some(frame))).await;
+    }
+
+    if let Err(e) = &result {
+        metrics::record_connection_error(&room_id, e.label());
+        if let Some(code) = e.close_code() {
+            let frame = CloseFrame {
+                code,
+                reason: e.label().into(),
+            };
+            let _ = client_write.send(Message::Close(Some(frame))).await;
+        }
+    }
+    // Sends the reply to a Close one side started, or starts the handshake if neither did
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, client_write.close()).await;
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        upstream_write.lock().await.close().await
+    })
+    .await;
+
+    result
+}
+
+/// Holds a Connect until the login gets a seat, telling the player where they stand as the
+/// queue moves. Only pings are answered meanwhile, anything else the client sends is dropped.
+async fn wait_for_seat<S>(
+    mut ticket: Ticket,
+    client_read: &mut S,
+    response_tx: &ResponseSender,
+    max_wait: Duration,
+    room_id: &str,
+) -> ProxyResult<Result<Seat, DisconnectCause>>
+where
+    S: Stream<Item = tungstenite::Result<Message>> + Unpin,
+{
+    log::info!(
+        "Room is full, queueing the login at position {}",
+        ticket.position()
+    );
+    let position = |position| ClientResponse::Values(vec![login_queue::position_notice(position)]);
+    response_tx.send(position(ticket.position()));
+    let deadline = tokio::time::Instant::now() + max_wait;
+    loop {
+        tokio::select! {
+            turn = ticket.moved() => match turn {
+                Turn::Waiting(at) => {
+                    response_tx.send(position(at));
+                }
+                Turn::Seated(seat) => {
+                    log::info!("Queued login got a seat");
+                    metrics::record_login_queue(room_id, "admitted");
+                    return Ok(Ok(seat));
+                }
+            },
+            msg = client_read.next() => match msg {
+                Some(Ok(Message::Ping(data))) => {
+                    response_tx.send(ClientResponse::Pong(data));
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    log::info!("Queued client left before getting a seat");
+                    metrics::record_login_queue(room_id, "abandoned");
+                    return Ok(Err(DisconnectCause::ClientClose));
+                }
+                Some(Ok(msg)) => {
+                    log::debug!("Dropping message from a queued client ({} bytes)", msg.len());
+                }
+                Some(Err(e)) => {
+                    metrics::record_login_queue(room_id, "abandoned");
+                    return Err(ProxyError::read_from_client(e));
+                }
+            },
+            _ = tokio::time::sleep_until(deadline) => {
+                log::info!(
+                    "Queued login got no seat within {:?}, refusing it at {}",
+                    max_wait,
+                    utc_timestamp()
+                );
+                metrics::record_login_queue(room_id, "timed_out");
+                response_tx.send(ClientResponse::Values(vec![
+                    Notice::RoomFullRefused.to_print_json(),
+                ]));
+                return Ok(Err(DisconnectCause::ProxyPolicy));
+            }
+        }
+    }
+}
+
+/// Never resolves without a deadline
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Now in RFC3339 with an explicit `Z`, how connection events are stamped in the logs and the API
+pub(crate) fn utc_timestamp() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Drops the frames a peer sent after its Close that are already buffered, tungstenite can still
+/// hand those out. Waiting for more would only hold the connection open for frames that are
+/// dropped anyway.
+fn drop_after_close<S>(read: &mut S, side: Side)
+where
+    S: Stream<Item = tungstenite::Result<Message>> + Unpin,
+{
+    let direction = match side {
+        Side::Client => "client_to_upstream",
+        Side::Upstream => "upstream_to_client",
+    };
+    let mut dropped = 0;
+    while let Some(Some(frame)) = read.next().now_or_never() {
+        match frame {
+            Ok(_) | Err(tungstenite::Error::Protocol(ProtocolError::ReceivedAfterClosing)) => {
+                dropped += 1;
+            }
+            Err(e) => {
+                error::record_read_error(side, &e);
+                break;
+            }
+        }
+    }
+    if dropped > 0 {
+        log::info!(
+            "Dropped {} frames sent after a close ({})",
+            dropped,
+            direction
+        );
+        metrics::record_post_close_frames(direction, dropped);
+    }
+}
+
+/// A Say sent upstream on behalf of the client
+fn say_message(text: &str) -> Message {
+    let say = Say {
+        cmd: "Say".to_string(),
+        text: text.to_string(),
+    };
+    Message::Text(serde_json::to_string(&[say]).unwrap().into())
+}
+
+/// Close frame of a connection an admin kicked
+fn kick_frame() -> CloseFrame {
+    CloseFrame {
+        code: CloseCode::Policy,
+        reason: "Kicked by an admin".into(),
+    }
+}
+
+/// Picks the room requested through the upgrade path. Anything outside of `/room/` is a legacy
+/// client and goes to the default room, unknown rooms are refused with a 404.
+fn select_route<'a>(
+    path: &str,
+    rooms: &'a HashMap<String, RoomRoute>,
+) -> Result<Option<(&'a str, &'a RoomRoute)>, ErrorResponse> {
+    let Some(room) = path.strip_prefix(ROOM_PATH_PREFIX) else {
+        return Ok(None);
+    };
+    let room = room.trim_end_matches('/');
+
+    match rooms.get_key_value(room) {
+        Some((room, route)) => Ok(Some((room.as_str(), route))),
+        None => {
+            log::debug!("Refusing connection to unknown room {:?}", room);
+            let mut response = ErrorResponse::new(Some("Unknown room".to_string()));
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            Err(response)
+        }
+    }
+}
+
+/// Connects to upstream, telling whether it's another APX. Connections that announce themselves
+/// with `identity` are refused if they loop back to this proxy.
+pub(crate) async fn connect_upstream(
+    upstream: &UpstreamServers,
+    identity: Option<&Identity>,
+) -> ProxyResult<(UpstreamStream, bool)> {
+    let (stream, server) = upstream.connect().await.map_err(|e| {
+        ProxyError::upstream(format!("Failed to connect to upstream {}: {}", upstream, e))
+    })?;
+    let upstream_url = server.url();
+    let request = match identity {
+        Some(identity) => identity.upstream_request(&upstream_url),
+        None => upstream_url.into_client_request(),
+    }
+    .map_err(ProxyError::upstream)?;
+    let config = WebSocketConfig::default();
+    match client_async_with_config(request, MaybeTlsStream::Plain(stream), Some(config)).await {
+        Ok((upstream_ws, response)) => {
+            let is_apx = response.headers().contains_key(chain::HEADER);
+            Ok((upstream_ws, is_apx))
+        }
+        Err(tungstenite::Error::Http(response))
+            if response.status() == StatusCode::LOOP_DETECTED =>
+        {
+            Err(ProxyError::upstream(
+                "Upstream loops back to this proxy, AP_SERVER points at itself",
+            ))
+        }
+        Err(e) => Err(ProxyError::upstream(format!(
+            "Failed to connect to upstream {}: {}",
+            server, e
+        ))),
+    }
+}
+
+async fn next_upstream_message(
+    replayed: &mut Option<Message>,
+    upstream_read: &mut UpstreamRead,
+) -> Option<tungstenite::Result<Message>> {
+    if let Some(msg) = replayed.take() {
+        return Some(Ok(msg));
+    }
+    upstream_read.next().await
+}
+
+/// Opens a new upstream connection and logs it in with the Connect the client sent originally.
+/// The client already has the RoomInfo and Connected, so they're swallowed. Whatever came along
+/// with the new Connected is returned so it can go through the regular upstream path.
+pub(crate) async fn reconnect_upstream(
+    upstream: &UpstreamServers,
+    connect: &str,
+    slot: Option<SlotId>,
+) -> ProxyResult<(UpstreamWrite, UpstreamRead, Option<Message>)> {
+    let (mut upstream_write, mut upstream_read) = fresh_upstream(upstream).await?;
+    send::write(
+        &mut upstream_write,
+        Message::Text(connect.to_string().into()),
+        Side::Upstream,
+    )
+    .await
+    .map_err(ProxyError::from_upstream)?;
+
+    let login = tokio::time::timeout(RECONNECT_TIMEOUT, async {
+        loop {
+            let Some(msg) = upstream_read.next().await else {
+                return Err(ProxyError::upstream(
+                    "Upstream closed the connection while logging in again",
+                ));
+            };
+            let Message::Text(text) = msg.map_err(ProxyError::from_upstream)? else {
+                continue;
+            };
+            let Some(mut commands) = parse_message(&text) else {
+                return Err(ProxyError::upstream("Invalid JSON received from upstream"));
+            };
+
+            if let Some(refused) = commands
+                .iter()
+                .find(|cmd| get_cmd(cmd) == Some("ConnectionRefused"))
+            {
+                return Err(ProxyError::upstream(format!(
+                    "Upstream refused the connection: {}",
+                    refused["errors"]
+                )));
+            }
+
+            let Some(position) = commands
+                .iter()
+                .position(|cmd| get_cmd(cmd) == Some("Connected"))
+            else {
+                continue;
+            };
+
+            let connected =
+                parse_as::<Connected>(&commands[position]).map_err(ProxyError::upstream)?;
+            if slot.is_some_and(|slot| slot != connected.slot) {
+                return Err(ProxyError::upstream(format!(
+                    "Upstream logged in to slot {} instead",
+                    connected.slot.0
+                )));
+            }
+
+            let remaining = commands.split_off(position + 1);
+            let remaining = if remaining.is_empty() {
+                None
+            } else {
+                let serialized = serde_json::to_string(&remaining).map_err(ProxyError::internal)?;
+                Some(Message::Text(serialized.into()))
+            };
+            return Ok(remaining);
+        }
+    })
+    .await;
+
+    let remaining = login.map_err(|_| {
+        ProxyError::upstream(format!(
+            "Timed out logging in again after {:?}",
+            RECONNECT_TIMEOUT
+        ))
+    })??;
+    Ok((upstream_write, upstream_read, remaining))
+}
+
+/// The slot a connection is logged in to and the room's settings for it, as they were when its
+/// commands were handled
+struct SlotSnapshot {
+    slot_info: Option<(SlotId, String)>,
+    exclusions: HashSet<SlotId>,
+    preferences: PreferenceMap,
+    slot_groups: SlotGroups,
+}
+
+/// What the client task answers with once it handled the client's commands, for what they asked
+/// of APX rather than upstream
+struct ClientReplies<'a> {
+    client_id: ClientId,
+    room_id: &'a str,
+    client_registry: &'a ClientRegistry,
+    events: &'a EventBus,
+    preferences: &'a RwLock<PreferenceMap>,
+    deathlink_probability: &'a DeathlinkProbability,
+    datapackage_cache: &'a DataPackageCache,
+    ready: &'a ReadyStates,
+    bridge: Option<&'a Bridge>,
+}
+
+impl ClientReplies<'_> {
+    /// Adds the answers to the client's denials, invalid packets and `!apx` commands to its
+    /// responses. Its tags and chat go where they're due.
+    async fn answer(
+        &self,
+        result: &mut ClientHandlerResult,
+        snapshot: &SlotSnapshot,
+        denial_cooldown: &mut DenialCooldown,
+        blocked_commands: usize,
+    ) -> ProxyResult<()> {
+        let slot = snapshot.slot_info.as_ref().map(|(slot, _)| *slot);
+
+        // Denials are always counted, but identical ones are only sent once per cooldown
+        // window so auto-retrying clients don't get flooded
+        for notice in std::mem::take(&mut result.denials) {
+            metrics::record_denial(self.room_id, slot, notice.id());
+            if denial_cooldown.should_send(notice, Instant::now()) {
+                result
+                    .responses
+                    .push(ClientResponse::Values(vec![notice.to_print_json()]));
+            } else {
+                log::debug!("Suppressing repeated {} denial", notice.id());
+            }
+        }
+
+        for invalid in std::mem::take(&mut result.invalid_packets) {
+            metrics::record_denial(self.room_id, slot, "invalid_packet");
+            if !denial_cooldown.should_send_invalid(&invalid, Instant::now()) {
+                log::debug!(
+                    "Suppressing repeated InvalidPacket for {:?}",
+                    invalid.original_cmd
+                );
+                continue;
+            }
+            let invalid = serde_json::to_value(invalid).map_err(ProxyError::internal)?;
+            result.responses.push(ClientResponse::Values(vec![invalid]));
+        }
+
+        if let Some(tags) = result.tag_update.take() {
+            self.client_registry.update_tags(self.client_id, tags).await;
+        }
+
+        let Some((slot, name)) = &snapshot.slot_info else {
+            return Ok(());
+        };
+        if let Some(bridge) = self.bridge {
+            for text in &result.chat {
+                bridge.forward(name, text);
+            }
+        }
+        for command in std::mem::take(&mut result.apx_commands) {
+            let reply = self
+                .apx_reply(command, *slot, name, snapshot, blocked_commands)
+                .await;
+            result.responses.push(ClientResponse::Values(reply));
+        }
+        Ok(())
+    }
+
+    /// The reply to a `!apx` command of `slot`
+    async fn apx_reply(
+        &self,
+        command: ApxCommand,
+        slot: SlotId,
+        name: &str,
+        snapshot: &SlotSnapshot,
+        blocked_commands: usize,
+    ) -> Vec<Value> {
+        match command {
+            ApxCommand::Status => {
+                let excluded = |slot: &SlotId| snapshot.exclusions.contains(slot);
+                let opted_out =
+                    |slot: &SlotId| preferences::opts_out_of_deathlink(&snapshot.preferences, slot);
+                let deathlink = if snapshot.slot_groups.any(&slot, excluded) {
+                    DeathlinkStatus::Excluded
+                } else if snapshot.slot_groups.any(&slot, opted_out) {
+                    DeathlinkStatus::OptedOut
+                } else {
+                    DeathlinkStatus::On
+                };
+                player_commands::status_report(&SlotStatus {
+                    muted: snapshot.slot_groups.is_muted(&slot, &snapshot.preferences),
+                    deathlink,
+                    deathlink_probability: self.deathlink_probability.get(),
+                    blocked_commands,
+                })
+            }
+            ApxCommand::Deathlink(enabled) => {
+                let updated = {
+                    let mut preferences = self.preferences.write().await;
+                    let entry = preferences.entry(slot).or_default();
+                    entry.deathlink_opt_out = !enabled;
+                    entry.clone()
+                };
+                log::info!(
+                    "Slot {} ({}) turned deathlink {}",
+                    slot.0,
+                    name,
+                    if enabled { "on" } else { "off" }
+                );
+                self.events.publish(RoomEvent::PreferencesChanged {
+                    slot,
+                    name: name.to_string(),
+                    preferences: updated,
+                });
+                player_commands::deathlink_report(enabled, snapshot.exclusions.contains(&slot))
+            }
+            ApxCommand::Missing(listed) => {
+                let missing = self
+                    .client_registry
+                    .missing_locations(slot)
+                    .await
+                    .unwrap_or_default();
+                let shown = &missing[..listed.min(missing.len())];
+                let names = match self.client_registry.game(self.client_id).await {
+                    Some(game) if !shown.is_empty() => {
+                        self.datapackage_cache.location_names(&game, shown)
+                    }
+                    _ => HashMap::new(),
+                };
+                player_commands::missing_report(&missing, listed, &names)
+            }
+            ApxCommand::Ready(is_ready) => {
+                let changed = self.ready.set(self.events, slot, is_ready);
+                if changed {
+                    log::info!(
+                        "Slot {} ({}) is {}",
+                        slot.0,
+                        name,
+                        if is_ready { "ready" } else { "no longer ready" }
+                    );
+                }
+                player_commands::ready_report(is_ready, changed)
+            }
+            ApxCommand::Unknown(command) => player_commands::usage(&command),
+        }
+    }
+}
+
+/// What the upstream task checks the logins upstream answers with, and keeps what it accepts in
+struct Logins<'a> {
+    state: &'a Mutex<ConnectionState>,
+    storage: &'a Mutex<StorageRequests>,
+    slot_info: &'a Mutex<Option<(SlotId, String)>>,
+    meter: &'a Meter,
+    passwords: &'a RwLock<HashMap<SlotId, String>>,
+    deathlink_exclusions: &'a RwLock<HashSet<SlotId>>,
+    deathlink_probability: &'a DeathlinkProbability,
+    preferences: &'a RwLock<PreferenceMap>,
+    slot_groups: &'a RwLock<SlotGroups>,
+    slot_names: &'a RwLock<HashMap<SlotId, String>>,
+    tokens: Option<&'a TokenKey>,
+    audit_key: Option<&'a AuditKey>,
+    claims: Option<&'a Arc<SlotClaims>>,
+    resumption: Option<&'a Arc<ResumableSessions>>,
+    /// Room the passwords and tokens are those of
+    login_room_id: &'a str,
+    /// Room the metrics are counted for
+    room_id: &'a str,
+    chained: bool,
+    has_room_password: bool,
+    inject_notext: bool,
+    events: &'a EventBus,
+    password_failures: &'a PasswordFailures,
+}
+
+/// A login upstream answered, as APX checked it
+struct Login {
+    /// The slot upstream logged in to with its item-link groups, if it did
+    connected_as: Option<((SlotId, String), SlotGroups)>,
+    /// That slot, or the one the connection is on when upstream didn't log in
+    checked_slot: Option<(SlotId, String)>,
+    /// Name the client logged in with, `None` when it wasn't logging in
+    name: Option<String>,
+    /// What's recorded of the password tried, with AUDIT_KEY
+    audit: Option<String>,
+    failure: Option<PasswordFailure>,
+    /// The connection was logged in before the Connect
+    relogin: bool,
+}
+
+impl Logins<'_> {
+    /// Handles the commands of an upstream message, checking the login they answer if they do
+    async fn check(&self, commands: &mut Vec<Value>) -> ProxyResult<(UpstreamResult, Login)> {
+        let connected_as = commands
+            .iter()
+            .filter(|cmd| get_cmd(cmd) == Some("Connected"))
+            .find_map(|cmd| parse_as::<Connected>(cmd).ok())
+            .map(|connected| {
+                let player_name = connected
+                    .players
+                    .iter()
+                    .find(|p| p.slot == connected.slot)
+                    .map(|p| p.name.clone())
+                    .unwrap_or_else(|| format!("Unknown-{}", connected.slot.0));
+                (
+                    (connected.slot, player_name),
+                    SlotGroups::from_connected(&connected),
+                )
+            });
+
+        let mut state = self.state.lock().await;
+        let (name, attempted_length, audit, relogin) = match &*state {
+            ConnectionState::WaitingForConnected {
+                name,
+                password,
+                slot,
+                relogin,
+                ..
+            } => {
+                // Clients that only sent the slot go by the name it has
+                let name = match slot {
+                    Some(slot) if name.is_empty() => self
+                        .slot_names
+                        .read()
+                        .await
+                        .get(slot)
+                        .cloned()
+                        .unwrap_or_default(),
+                    _ => name.clone(),
+                };
+                let audit = self.audit_key.map(|key| key.attempt(&name, password));
+                (Some(name), password.len(), audit, *relogin)
+            }
+            _ => (None, 0, None, false),
+        };
+        let passwords = self.passwords.read().await;
+        let exclusions = self.deathlink_exclusions.read().await;
+        let preferences = self.preferences.read().await;
+        let slot_groups = self.slot_groups.read().await;
+        let slot_info = self.slot_info.lock().await;
+        // The login is checked for the slot upstream logged in to
+        let checked_slot = match &connected_as {
+            Some((info, _)) => Some(info.clone()),
+            None => slot_info.clone(),
+        };
+        let mut storage = self.storage.lock().await;
+        let login_check = LoginCheck {
+            passwords: &passwords,
+            tokens: self.tokens,
+            room_id: self.login_room_id,
+            chained: self.chained,
+            claims: self.claims,
+            resumption: self.resumption.filter(|_| !self.chained),
+        };
+        let result = handle_upstream_messages(
+            &mut state,
+            &mut storage,
+            commands,
+            &login_check,
+            &exclusions,
+            &preferences,
+            &slot_groups,
+            &checked_slot,
+            self.deathlink_probability,
+            self.inject_notext,
+        )?;
+        let failure = match (&result, &checked_slot) {
+            (
+                UpstreamResult::SendConnectionRefused(Refusal::InvalidPassword),
+                Some((slot, name)),
+            ) => Some(PasswordFailure {
+                slot: *slot,
+                name: name.clone(),
+                at: Utc::now(),
+                expected_empty: passwords
+                    .get(slot)
+                    .is_none_or(|expected| expected.is_empty()),
+                attempted_length,
+            }),
+            _ => None,
+        };
+        let login = Login {
+            connected_as,
+            checked_slot,
+            name,
+            audit,
+            failure,
+            relogin,
+        };
+        Ok((result, login))
+    }
+
+    /// Logs and publishes what upstream refused of the login itself, returns whether it refused
+    /// the room's password. Upstream never sees the slot passwords, unless it's an APX checking
+    /// them.
+    async fn report_upstream_refusals(&self, commands: &[Value], login: &Login) -> bool {
+        let Some(name) = &login.name else {
+            return false;
+        };
+        let mut room_password_refused = false;
+        for errors in commands.iter().filter_map(refusal_errors) {
+            log::info!(
+                "Upstream refused login for {} at {}: {:?}",
+                name,
+                utc_timestamp(),
+                errors
+            );
+            if !self.chained && errors.iter().any(|error| error == "InvalidPassword") {
+                room_password_refused = true;
+                if self.has_room_password {
+                    log::error!(
+                        "Upstream refused UPSTREAM_ROOM_PASSWORD, check that it matches the room's server password"
+                    );
+                } else {
+                    log::error!(
+                        "Upstream room has a server password, set UPSTREAM_ROOM_PASSWORD to it"
+                    );
+                }
+            }
+            for error in &errors {
+                metrics::record_upstream_refusal(self.room_id, refusal_label(error));
+            }
+            let slot = slot_for_name(&*self.slot_names.read().await, name);
+            self.events.publish(RoomEvent::LoginRefused {
+                slot,
+                name: name.clone(),
+                errors,
+                attempt: login.audit.clone(),
+            });
+        }
+        room_password_refused
+    }
+
+    /// Sends the client the ConnectionRefused of a login APX refused, the connection goes back
+    /// to waiting for a Connect to allow a retry
+    async fn refuse<W>(
+        &self,
+        client_write: &mut W,
+        droppable: &DroppableCommands,
+        refusal: Refusal,
+        login: Login,
+    ) -> ProxyResult<()>
+    where
+        W: Sink<Message, Error = tungstenite::Error> + Unpin,
+    {
+        let refused = serde_json::json!({
+            "cmd": "ConnectionRefused",
+            "errors": [refusal.error()]
+        });
+        let refused = match refusal {
+            Refusal::InvalidPassword | Refusal::InvalidSlot => vec![refused],
+            Refusal::SlotClaimed => vec![Notice::SlotClaimed.to_print_json(), refused],
+        };
+        let refused_msg = Message::Text(serde_json::to_string(&refused).unwrap().into());
+        send::to_client(client_write, refused_msg, droppable, self.room_id).await?;
+
+        if let Some(failure) = login.failure {
+            self.password_failures.record(failure);
+        }
+
+        self.events.publish(RoomEvent::LoginRefused {
+            slot: login.checked_slot.as_ref().map(|(slot, _)| *slot),
+            name: login.name.unwrap_or_default(),
+            errors: vec![refusal.error().to_string()],
+            attempt: login.audit,
+        });
+
+        *self.state.lock().await = ConnectionState::WaitingForConnect;
+        Ok(())
+    }
+
+    /// Makes the slot upstream logged in to the connection's, once APX accepted the login
+    async fn accept(
+        &self,
+        ((slot, name), groups): ((SlotId, String), SlotGroups),
+    ) -> Option<(SlotId, String)> {
+        let mut info = self.slot_info.lock().await;
+        *info = Some((slot, name));
+        self.meter.set_slot(slot);
+
+        let mut slot_groups = self.slot_groups.write().await;
+        if *slot_groups != groups {
+            log::info!("Loaded {} item-link groups", groups.len());
+            *slot_groups = groups;
+        }
+        info.clone()
+    }
+}
+
+/// Opens a new upstream connection that isn't logged in to anything. The client already has the
+/// RoomInfo, so it's swallowed.
+async fn fresh_upstream(upstream: &UpstreamServers) -> ProxyResult<(UpstreamWrite, UpstreamRead)> {
+    let (upstream_ws, _) = connect_upstream(upstream, None).await?;
+    let (upstream_write, mut upstream_read) = split_upstream(upstream_ws);
+
+    let room_info = tokio::time::timeout(RECONNECT_TIMEOUT, async {
+        loop {
+            let Some(msg) = upstream_read.next().await else {
+                return Err(ProxyError::upstream(
+                    "Upstream closed the connection before sending RoomInfo",
+                ));
+            };
+            let Message::Text(text) = msg.map_err(ProxyError::from_upstream)? else {
+                continue;
+            };
+            let Some(commands) = parse_message(&text) else {
+                return Err(ProxyError::upstream("Invalid JSON received from upstream"));
+            };
+            if commands.iter().any(|cmd| get_cmd(cmd) == Some("RoomInfo")) {
+                return Ok(());
+            }
+        }
+    })
+    .await;
+
+    room_info.map_err(|_| {
+        ProxyError::upstream(format!("No RoomInfo within {:?}", RECONNECT_TIMEOUT))
+    })??;
+    Ok((upstream_write, upstream_read))
 }
 
 async fn handle_client_messages(
     state: &mut ConnectionState,
+    storage: &mut StorageRequests,
     messages: &mut Vec<Value>,
     slot_info: &Option<(SlotId, String)>,
-    signal_sender: &Sender<Signal>,
-    deathlink_exclusions: &HashSet<SlotId>,
+    events: &EventBus,
+    preferences: &PreferenceMap,
+    slot_groups: &SlotGroups,
     deferred_datapackage_games: &HashSet<String>,
     datapackage_cache: &Arc<DataPackageCache>,
     inject_notext: bool,
-) -> Result<ClientHandlerResult> {
+    strict_commands: bool,
+    upstream_password: Option<&str>,
+) -> ProxyResult<ClientHandlerResult> {
     let mut result = ClientHandlerResult::default();
     let mut error = None;
 
@@ -557,13 +2521,17 @@ async fn handle_client_messages(
 
         let decision = match handle_client_message(
             state,
+            storage,
             message,
             slot_info,
-            signal_sender,
-            deathlink_exclusions,
+            events,
+            preferences,
+            slot_groups,
             deferred_datapackage_games,
             datapackage_cache,
             inject_notext,
+            strict_commands,
+            upstream_password,
         ) {
             Ok(decision) => decision,
             Err(e) => {
@@ -582,8 +2550,8 @@ async fn handle_client_messages(
                 result.modified = true;
                 false
             }
-            MessageDecision::DropWithResponse(value) => {
-                result.responses.push(ClientResponse::Values(vec![value]));
+            MessageDecision::DropWithResponse(notice) => {
+                result.denials.push(notice);
                 result.modified = true;
                 false
             }
@@ -592,11 +2560,21 @@ async fn handle_client_messages(
                 result.modified = true;
                 false
             }
+            MessageDecision::DropWithInvalidPacket(invalid) => {
+                result.invalid_packets.push(invalid);
+                result.modified = true;
+                false
+            }
             MessageDecision::DeferDataPackage(req) => {
                 result.pending_dp_requests.push(req);
                 result.modified = true;
                 false
             }
+            MessageDecision::Apx(command) => {
+                result.apx_commands.push(command);
+                result.modified = true;
+                false
+            }
             MessageDecision::Forward | MessageDecision::Modified => {
                 if get_cmd(message) == Some("ConnectUpdate") {
                     if let Ok(update) = parse_as::<ConnectUpdate>(message) {
@@ -606,10 +2584,15 @@ async fn handle_client_messages(
                 if matches!(decision, MessageDecision::Modified) {
                     result.modified = true;
                 }
+                if matches!(state, ConnectionState::LoggedIn)
+                    && let Some(text) = chat_text(message)
+                {
+                    result.chat.push(text.to_string());
+                }
                 true
             }
             MessageDecision::ForwardWithRegistration { .. }
-            | MessageDecision::SendConnectionRefused => {
+            | MessageDecision::SendConnectionRefused(_) => {
                 unreachable!(
                     "Client messages should never return ForwardWithRegistration or SendConnectionRefused"
                 )
@@ -626,16 +2609,31 @@ async fn handle_client_messages(
 
 fn handle_client_message(
     state: &mut ConnectionState,
+    storage: &mut StorageRequests,
     cmd: &mut Value,
     slot_info: &Option<(SlotId, String)>,
-    signal_sender: &Sender<Signal>,
-    deathlink_exclusions: &HashSet<SlotId>,
+    events: &EventBus,
+    preferences: &PreferenceMap,
+    slot_groups: &SlotGroups,
     deferred_datapackage_games: &HashSet<String>,
     datapackage_cache: &Arc<DataPackageCache>,
     inject_notext: bool,
-) -> Result<MessageDecision> {
+    strict_commands: bool,
+    upstream_password: Option<&str>,
+) -> ProxyResult<MessageDecision> {
     let cmd_type = get_cmd(cmd);
 
+    if matches!(state, ConnectionState::LoggedIn)
+        && let Some(invalid) = invalid_packet(cmd, strict_commands)
+    {
+        log::info!(
+            "Answering {:?} with an InvalidPacket: {}",
+            cmd_type,
+            invalid.text
+        );
+        return Ok(MessageDecision::DropWithInvalidPacket(invalid));
+    }
+
     if cmd_type == Some("GetDataPackage") {
         if let Ok(request) = parse_as::<GetDataPackage>(cmd) {
             let should_defer = match state {
@@ -695,26 +2693,45 @@ fn handle_client_message(
         if let Ok(bounced) = parse_as::<Bounced>(cmd) {
             if bounced.tags.iter().any(|t| t == "DeathLink") {
                 if let Some((slot, name)) = slot_info {
-                    if deathlink_exclusions.contains(slot) {
+                    // Exclusions only keep deathlinks from reaching the slot, opting out goes
+                    // both ways
+                    let opted_out =
+                        |slot: &SlotId| preferences::opts_out_of_deathlink(preferences, slot);
+                    if slot_groups.any(slot, opted_out) {
                         log::info!(
-                            "Dropping outgoing DeathLink from excluded slot {} ({})",
+                            "Dropping outgoing DeathLink from opted out slot {} ({})",
                             slot.0,
                             name
                         );
                         return Ok(MessageDecision::Drop);
                     }
 
-                    let source = bounced
-                        .data
-                        .get("source")
-                        .and_then(|v| v.as_str())
-                        .unwrap_or("Unknown")
-                        .to_string();
-                    let cause = bounced
-                        .data
-                        .get("cause")
-                        .and_then(|v| v.as_str())
-                        .map(|s| s.to_string());
+                    // A broken DeathLink still goes out, other clients may make sense of it
+                    let source = match bounced.data.get("source").and_then(|v| v.as_str()) {
+                        Some(source) => source.to_string(),
+                        None => {
+                            log::error!(
+                                "DeathLink from slot {} ({}) has no source: {}",
+                                slot.0,
+                                name,
+                                bounced.data
+                            );
+                            "Unknown".to_string()
+                        }
+                    };
+                    let cause = match bounced.data.get("cause") {
+                        None | Some(Value::Null) => None,
+                        Some(Value::String(cause)) => Some(cause.clone()),
+                        Some(cause) => {
+                            log::error!(
+                                "DeathLink from slot {} ({}) has a cause that isn't a string: {}",
+                                slot.0,
+                                name,
+                                cause
+                            );
+                            None
+                        }
+                    };
 
                     log::info!(
                         "DeathLink sent from slot {} ({}): source={}, cause={:?}",
@@ -723,7 +2740,7 @@ fn handle_client_message(
                         source,
                         cause
                     );
-                    let _ = signal_sender.try_send(Signal::DeathLink {
+                    events.publish(RoomEvent::DeathLink {
                         slot: *slot,
                         source,
                         cause,
@@ -735,38 +2752,52 @@ fn handle_client_message(
     }
 
     if cmd_type == Some("Say") {
+        // Answered even for muted players, it's how they find out
+        if matches!(state, ConnectionState::LoggedIn)
+            && let Some(text) = cmd.get("text").and_then(|text| text.as_str())
+            && let Some(command) = chat_command(text)
+            && command.name == "apx"
+        {
+            return Ok(MessageDecision::Apx(player_commands::parse(&command.args)));
+        }
+
+        if let Some((slot, name)) = slot_info
+            && slot_groups.is_muted(slot, preferences)
+        {
+            log::info!("Dropping Say from muted slot {} ({})", slot.0, name);
+            return Ok(MessageDecision::DropWithResponse(Notice::Muted));
+        }
+
         if let Ok(say) = parse_as::<Say>(cmd) {
             if say.text.len() > MAX_SAY_LENGTH {
                 log::warn!("Dropping oversized Say message ({} chars)", say.text.len());
-                let denial =
-                    PrintJSON::with_color("Your message is too long. Please reconsider.", "red");
-                let denial_value = serde_json::to_value(denial).unwrap();
-                return Ok(MessageDecision::DropWithResponse(denial_value));
+                return Ok(MessageDecision::DropWithInvalidPacket(
+                    InvalidPacket::arguments("Say", "Your message is too long. Please reconsider."),
+                ));
             }
 
-            if is_command(&say.text, "countdown") {
+            if chat_command(&say.text).is_some_and(|command| command.name == "countdown") {
                 if let Some((slot, name)) = slot_info {
                     log::info!("Intercepted !countdown from slot {} ({})", slot.0, name);
-                    let _ = signal_sender.try_send(Signal::CountdownInit { slot: *slot });
+                    events.publish(RoomEvent::CountdownInit { slot: *slot });
                 } else {
                     log::warn!("Received !countdown but slot info not available yet");
                 }
 
-                let denial = PrintJSON::with_color(
-                    "Starting countdowns is not allowed. This attempt has been logged.",
-                    "red",
-                );
-                let denial_value = serde_json::to_value(denial).unwrap();
-                return Ok(MessageDecision::DropWithResponse(denial_value));
+                return Ok(MessageDecision::DropWithResponse(Notice::CountdownBlocked));
             }
         }
     }
 
     match state {
-        ConnectionState::WaitingForRoomInfo => {
-            bail!("Received message from client while waiting for RoomInfo. This is a client bug.")
-        }
+        ConnectionState::WaitingForRoomInfo => Err(ProxyError::client(
+            "Received message from client while waiting for RoomInfo. This is a client bug.",
+        )),
         ConnectionState::WaitingForConnect => {
+            // Trackers read storage before logging in, as long as upstream never logged in
+            if storage.request(cmd) {
+                return Ok(MessageDecision::Forward);
+            }
             if cmd_type != Some("Connect") {
                 log::debug!(
                     "Received non Connect ({:?}) client message while waiting for connect, dropping it.",
@@ -776,71 +2807,41 @@ fn handle_client_message(
             }
 
             log::debug!("Intercepted Connect packet");
-
-            let password = cmd
-                .get("password")
-                .and_then(|v| v.as_str())
-                .unwrap_or("")
-                .to_string();
-
-            let game = cmd
-                .get("game")
-                .and_then(|v| v.as_str())
-                .unwrap_or("")
-                .to_string();
-
-            if let Some(obj) = cmd.as_object_mut() {
-                // Empty the password before forwarding to upstream
-                obj.insert(
-                    "password".to_string(),
-                    serde_json::Value::String("".to_string()),
-                );
-
-                if inject_notext {
-                    let tags = obj
-                        .entry("tags")
-                        .or_insert_with(|| serde_json::Value::Array(vec![]));
-                    if let Some(tags_array) = tags.as_array_mut() {
-                        if !tags_array.iter().any(|v| v.as_str() == Some("NoText")) {
-                            tags_array.push(serde_json::Value::String("NoText".into()));
-                        }
-                        log::debug!("Injected NoText tag into Connect");
-                    }
-                }
-            }
-
-            // Extract tags after NoText injection so they reflect actual state
-            let tags: Vec<String> = cmd
-                .get("tags")
-                .and_then(|v| v.as_array())
-                .map(|arr| {
-                    arr.iter()
-                        .filter_map(|v| v.as_str().map(String::from))
-                        .collect()
-                })
-                .unwrap_or_default();
-
-            *state = ConnectionState::WaitingForConnected {
-                password,
-                tags,
-                game,
-            };
-            Ok(MessageDecision::Modified)
+            Ok(intercept_connect(
+                state,
+                cmd,
+                slot_info,
+                inject_notext,
+                upstream_password,
+            ))
         }
         ConnectionState::WaitingForConnected { .. } => {
+            if storage.request(cmd) {
+                return Ok(MessageDecision::Forward);
+            }
             log::debug!(
                 "Dropping client message {:?} while waiting for authentication",
                 cmd_type
             );
             Ok(MessageDecision::Drop)
         }
+        ConnectionState::LoggedIn if cmd_type == Some("Connect") => {
+            log::info!("Client sent another Connect, logging it in again");
+            Ok(intercept_connect(
+                state,
+                cmd,
+                slot_info,
+                inject_notext,
+                upstream_password,
+            ))
+        }
         ConnectionState::LoggedIn => {
             if inject_notext && cmd_type == Some("ConnectUpdate") {
                 if let Ok(mut update) = parse_as::<ConnectUpdate>(cmd) {
                     if !update.tags.iter().any(|t| t == "NoText") {
                         log::debug!("Injecting NoText tag into ConnectUpdate");
                         update.tags.push("NoText".to_string());
-                        *cmd = serde_json::to_value(update)?;
+                        *cmd = serde_json::to_value(update).map_err(ProxyError::internal)?;
                         return Ok(MessageDecision::Modified);
                     }
                 }
@@ -850,31 +2851,150 @@ fn handle_client_message(
     }
 }
 
+/// Why a logged in client's `cmd` should get an InvalidPacket, if it should. Known commands
+/// whose fields the proxy reads have to parse, unknown ones only matter with STRICT_COMMANDS.
+fn invalid_packet(cmd: &Value, strict_commands: bool) -> Option<InvalidPacket> {
+    let Some(cmd_type) = get_cmd(cmd).filter(|cmd| CLIENT_COMMANDS.contains(cmd)) else {
+        return strict_commands.then(|| InvalidPacket::unknown_cmd(get_cmd(cmd)));
+    };
+    let parsed = match cmd_type {
+        "Bounce" => parse_as::<Bounced>(cmd).map(drop),
+        "ConnectUpdate" => parse_as::<ConnectUpdate>(cmd).map(drop),
+        "Get" => parse_as::<Get>(cmd).map(drop),
+        "GetDataPackage" => parse_as::<GetDataPackage>(cmd).map(drop),
+        "LocationChecks" => parse_as::<LocationChecks>(cmd).map(drop),
+        "Say" => parse_as::<Say>(cmd).map(drop),
+        "SetNotify" => parse_as::<SetNotify>(cmd).map(drop),
+        "StatusUpdate" => parse_as::<StatusUpdate>(cmd).map(drop),
+        "UpdateHint" => parse_as::<UpdateHint>(cmd).map(drop),
+        _ => Ok(()),
+    };
+    parsed
+        .err()
+        .map(|e| InvalidPacket::arguments(cmd_type, &e.to_string()))
+}
+
+/// Takes the password out of a Connect and waits for upstream to tell which slot it's for. A
+/// connection that logged in before is logged in again, its password checked for the new slot.
+fn intercept_connect(
+    state: &mut ConnectionState,
+    cmd: &mut Value,
+    slot_info: &Option<(SlotId, String)>,
+    inject_notext: bool,
+    upstream_password: Option<&str>,
+) -> MessageDecision {
+    let password = cmd
+        .get("password")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string();
+
+    let game = cmd
+        .get("game")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string();
+
+    let name = cmd
+        .get("name")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string();
+
+    // 0 is what clients that log in by name send, or leave it out
+    let slot = cmd
+        .get("slot")
+        .and_then(|v| v.as_i64())
+        .filter(|slot| *slot > 0)
+        .map(SlotId);
+
+    let software = ClientSoftware {
+        uuid: cmd
+            .get("uuid")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string(),
+        version: cmd.get("version").and_then(|v| parse_as(v).ok()),
+    };
+
+    if let Some(obj) = cmd.as_object_mut() {
+        // The client's password stays with APX, upstream gets the room's own if it has one. An
+        // upstream APX checks it itself and gets it as is.
+        if let Some(upstream_password) = upstream_password {
+            obj.insert(
+                "password".to_string(),
+                serde_json::Value::String(upstream_password.to_string()),
+            );
+        }
+
+        if inject_notext {
+            let tags = obj
+                .entry("tags")
+                .or_insert_with(|| serde_json::Value::Array(vec![]));
+            if let Some(tags_array) = tags.as_array_mut() {
+                if !tags_array.iter().any(|v| v.as_str() == Some("NoText")) {
+                    tags_array.push(serde_json::Value::String("NoText".into()));
+                }
+                log::debug!("Injected NoText tag into Connect");
+            }
+        }
+    }
+
+    // Extract tags after NoText injection so they reflect actual state
+    let tags: Vec<String> = cmd
+        .get("tags")
+        .and_then(|v| v.as_array())
+        .map(|arr| {
+            arr.iter()
+                .filter_map(|v| v.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    *state = ConnectionState::WaitingForConnected {
+        password,
+        tags,
+        game,
+        name,
+        slot,
+        software,
+        relogin: slot_info.is_some(),
+    };
+    MessageDecision::Modified
+}
+
 fn handle_upstream_messages(
     state: &mut ConnectionState,
+    storage: &mut StorageRequests,
     messages: &mut Vec<Value>,
-    login_info: &HashMap<SlotId, String>,
+    login_check: &LoginCheck,
     deathlink_exclusions: &HashSet<SlotId>,
+    preferences: &PreferenceMap,
+    slot_groups: &SlotGroups,
     slot_info: &Option<(SlotId, String)>,
     deathlink_probability: &DeathlinkProbability,
     inject_notext: bool,
-) -> Result<UpstreamResult> {
+) -> ProxyResult<UpstreamResult> {
     let mut modified = false;
-    let mut send_refused = false;
+    let mut refused = None;
     let mut error = None;
     let mut inject_response = None;
     let mut registration = None;
+    let mut datapackage_checksums = None;
 
     messages.retain_mut(|message| {
-        if send_refused || error.is_some() {
+        if refused.is_some() || error.is_some() {
             return false;
         }
 
         let decision = match handle_upstream_message(
             state,
+            storage,
             message,
-            login_info,
+            login_check,
             deathlink_exclusions,
+            preferences,
+            slot_groups,
             slot_info,
             deathlink_probability,
             inject_notext,
@@ -886,6 +3006,10 @@ fn handle_upstream_messages(
             }
         };
 
+        if get_cmd(message) == Some("RoomInfo") {
+            datapackage_checksums = message.get("datapackage_checksums").cloned();
+        }
+
         match decision {
             MessageDecision::Drop => {
                 modified = true;
@@ -893,10 +3017,12 @@ fn handle_upstream_messages(
             }
             MessageDecision::DropWithResponse(_)
             | MessageDecision::DropWithRawResponse(_)
+            | MessageDecision::DropWithInvalidPacket(_)
             | MessageDecision::DropAndRoute
-            | MessageDecision::DeferDataPackage(_) => {
+            | MessageDecision::DeferDataPackage(_)
+            | MessageDecision::Apx(_) => {
                 unreachable!(
-                    "Upstream messages should never return DropWithResponse, DropAndRoute, or DeferDataPackage"
+                    "Upstream messages should never return DropWithResponse, DropAndRoute, DeferDataPackage or Apx"
                 )
             }
             MessageDecision::Forward => true,
@@ -915,8 +3041,8 @@ fn handle_upstream_messages(
                 modified = true;
                 true
             }
-            MessageDecision::SendConnectionRefused => {
-                send_refused = true;
+            MessageDecision::SendConnectionRefused(refusal) => {
+                refused = Some(refusal);
                 false
             }
         }
@@ -926,26 +3052,30 @@ fn handle_upstream_messages(
         return Err(e);
     }
 
-    if send_refused {
-        return Ok(UpstreamResult::SendConnectionRefused);
+    if let Some(refusal) = refused {
+        return Ok(UpstreamResult::SendConnectionRefused(refusal));
     }
 
     Ok(UpstreamResult::Continue {
         modified,
         inject_response,
         registration,
+        datapackage_checksums,
     })
 }
 
 fn handle_upstream_message(
     state: &mut ConnectionState,
+    storage: &mut StorageRequests,
     cmd: &mut Value,
-    login_info: &HashMap<SlotId, String>,
+    login_check: &LoginCheck,
     deathlink_exclusions: &HashSet<SlotId>,
+    preferences: &PreferenceMap,
+    slot_groups: &SlotGroups,
     slot_info: &Option<(SlotId, String)>,
     deathlink_probability: &DeathlinkProbability,
     inject_notext: bool,
-) -> Result<MessageDecision> {
+) -> ProxyResult<MessageDecision> {
     let cmd_type = get_cmd(cmd);
 
     if cmd_type == Some("Bounced") {
@@ -955,7 +3085,7 @@ fn handle_upstream_message(
         if let Ok(bounced) = parse_as::<Bounced>(cmd) {
             if bounced.tags.iter().any(|t| t == "DeathLink") {
                 if let Some((slot, name)) = slot_info {
-                    if deathlink_exclusions.contains(slot) {
+                    if slot_groups.deathlink_blocked(slot, deathlink_exclusions, preferences) {
                         log::info!(
                             "Dropping incoming DeathLink for excluded slot {} ({})",
                             slot.0,
@@ -1016,20 +3146,32 @@ fn handle_upstream_message(
         }
     }
 
-    match state {
-        ConnectionState::WaitingForRoomInfo => {
-            if get_cmd(cmd) != Some("RoomInfo") {
-                bail!("Received non RoomInfo as the first upstream message, this is a bug")
-            }
-
-            let mut room_info = parse_as::<RoomInfo>(cmd)?;
+    // Some servers resend RoomInfo after admin actions, so it's rewritten in every state
+    if cmd_type == Some("RoomInfo") {
+        if !login_check.chained {
+            rewrite_room_info(cmd, login_check.room_id)?;
+        }
+        if matches!(state, ConnectionState::WaitingForRoomInfo) {
             log::debug!("Intercepted RoomInfo packet");
-
-            room_info.set_password(true);
-            *cmd = serde_json::to_value(room_info)?;
             *state = ConnectionState::WaitingForConnect;
-            Ok(MessageDecision::Modified)
+        } else {
+            log::info!("Upstream resent RoomInfo");
         }
+        return Ok(MessageDecision::Modified);
+    }
+
+    if matches!(
+        state,
+        ConnectionState::WaitingForConnect | ConnectionState::WaitingForConnected { .. }
+    ) && let Some(decision) = storage.filter_response(cmd)
+    {
+        return Ok(decision);
+    }
+
+    match state {
+        ConnectionState::WaitingForRoomInfo => Err(ProxyError::upstream(
+            "Received non RoomInfo as the first upstream message",
+        )),
         ConnectionState::WaitingForConnect => {
             log::debug!(
                 "Dropping upstream message {:?} while waiting for Connect",
@@ -1038,47 +3180,181 @@ fn handle_upstream_message(
             Ok(MessageDecision::Drop)
         }
         ConnectionState::WaitingForConnected {
+            name,
             password,
             tags,
             game,
+            slot,
+            software,
+            relogin,
         } => {
             let cmd_type = get_cmd(cmd);
+            let name = name.clone();
+            let requested_slot = *slot;
             let password = password.clone();
             let connect_tags = tags.clone();
             let connect_game = game.clone();
+            let mut connect_software = software.clone();
+            let relogin = *relogin;
 
             if cmd_type == Some("Connected") {
-                let connected = parse_as::<Connected>(cmd)?;
+                let connected = parse_as::<Connected>(cmd).map_err(ProxyError::upstream)?;
                 log::debug!("Intercepted Connected packet for slot {}", connected.slot.0);
+                storage.logged_in();
 
-                let expected_password = login_info.get(&connected.slot);
+                // From here on the slot upstream reports is the one the password is checked for
+                if let Err(refusal) = check_requested_slot(&name, requested_slot, &connected) {
+                    log::warn!(
+                        "Connect for slot {:?} as {:?} doesn't match slot {}",
+                        requested_slot,
+                        name,
+                        connected.slot.0
+                    );
+                    return Ok(MessageDecision::SendConnectionRefused(refusal));
+                }
+                // Clients that only sent the slot go by the name the slot has
+                let name = if name.is_empty() {
+                    connected_player(&connected)
+                        .map(|player| player.name.clone())
+                        .unwrap_or_default()
+                } else {
+                    name
+                };
 
-                match expected_password {
-                    Some(expected) if !expected.is_empty() => {
-                        if password != *expected {
-                            log::warn!("Invalid password provided for slot {}", connected.slot.0);
-                            return Ok(MessageDecision::SendConnectionRefused);
-                        }
+                // Resuming clients send their token as uuid
+                let resumed = login_check.resumption.and_then(|sessions| {
+                    sessions.resume(&connect_software.uuid, connected.slot, &name, Utc::now())
+                });
+
+                // Tokens are tried first, anything that isn't a valid one is checked as a
+                // plain password
+                let token = login_check.tokens.map(|key| {
+                    key.verify(&password, login_check.room_id, connected.slot, token::now())
+                });
+                match token {
+                    // Connected only comes back once the upstream APX accepted the password
+                    _ if login_check.chained => {
                         log::info!(
-                            "Password validated successfully for slot {} (notext: {})",
+                            "Login checked by the upstream APX for slot {} (notext: {})",
                             connected.slot.0,
                             inject_notext
                         );
                     }
-                    Some(_) | None => {
+                    _ if resumed.is_some() => {
                         log::info!(
-                            "No password required for slot {}, allowing connection (notext: {})",
+                            "Session resumed for slot {} (notext: {})",
                             connected.slot.0,
                             inject_notext
                         );
                     }
+                    Some(Ok(_)) => {
+                        log::info!(
+                            "Token validated successfully for slot {} (notext: {})",
+                            connected.slot.0,
+                            inject_notext
+                        );
+                    }
+                    token => {
+                        if let Some(Err(e)) = &token
+                            && *e != TokenError::Malformed
+                        {
+                            log::warn!("Rejected token for slot {}: {}", connected.slot.0, e);
+                        }
+
+                        match login_check.passwords.get(&connected.slot) {
+                            Some(expected) if !expected.is_empty() => {
+                                if password != *expected {
+                                    log::warn!(
+                                        "Invalid password provided for slot {}",
+                                        connected.slot.0
+                                    );
+                                    return Ok(MessageDecision::SendConnectionRefused(
+                                        Refusal::InvalidPassword,
+                                    ));
+                                }
+                                log::info!(
+                                    "Password validated successfully for slot {} (notext: {})",
+                                    connected.slot.0,
+                                    inject_notext
+                                );
+                            }
+                            Some(_) | None => {
+                                log::info!(
+                                    "No password required for slot {}, allowing connection (notext: {})",
+                                    connected.slot.0,
+                                    inject_notext
+                                );
+                            }
+                        }
+                    }
+                }
+
+                // Trackers only watch, they can follow a slot from anywhere
+                let claim = match login_check.claims {
+                    Some(claims) if !mirror::is_tracker(&connect_tags) => {
+                        // A resumed session keeps the claim of the uuid it first logged in with
+                        let uuid = match &resumed {
+                            Some(session) => claims
+                                .claimant(connected.slot, &session.uuid_hash)
+                                .unwrap_or_default(),
+                            None => connect_software.uuid.clone(),
+                        };
+                        match claims.admit(connected.slot, &uuid, Utc::now()) {
+                            Ok(claim) => claim,
+                            Err(ClaimRefused) => {
+                                log::warn!(
+                                    "Slot {} is claimed by another client, refusing {:?}",
+                                    connected.slot.0,
+                                    connect_software.uuid
+                                );
+                                return Ok(MessageDecision::SendConnectionRefused(
+                                    Refusal::SlotClaimed,
+                                ));
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+
+                let resumed_preferences = resumed
+                    .as_ref()
+                    .and_then(|session| session.preferences.clone());
+                // The token stood in for the client's uuid, it has no business in the registry
+                if resumed.is_some() {
+                    connect_software.uuid.clear();
                 }
+                let resume_hold = login_check.resumption.map(|sessions| {
+                    let (uuid_hash, soft_preferences) = match resumed {
+                        Some(session) => (session.uuid_hash, session.preferences),
+                        None => (
+                            resume::hash(&connect_software.uuid),
+                            preferences.get(&connected.slot).cloned(),
+                        ),
+                    };
+                    let (token, hold) = sessions.issue(
+                        connected.slot,
+                        &name,
+                        uuid_hash,
+                        soft_preferences,
+                        Utc::now(),
+                    );
+                    cmd["apx_resume"] = token.into();
+                    hold
+                });
 
                 let registration = RegistrationData {
                     slot: connected.slot,
                     team: connected.team,
                     game: connect_game,
                     tags: connect_tags,
+                    software: connect_software,
+                    progress: LocationProgress::new(
+                        &connected.checked_locations,
+                        &connected.missing_locations,
+                    ),
+                    claim,
+                    resume: resume_hold,
+                    resumed_preferences,
                 };
 
                 *state = ConnectionState::LoggedIn;
@@ -1094,9 +3370,7 @@ fn handle_upstream_message(
                 reorder_slot_first(cmd);
 
                 let inject_response = if inject_notext {
-                    let confirmation =
-                        PrintJSON::with_color("Connected to APX proxy (NoText mode)", "green");
-                    Some(serde_json::to_value(confirmation)?)
+                    Some(Notice::NoTextConnected.to_print_json())
                 } else {
                     None
                 };
@@ -1106,18 +3380,149 @@ fn handle_upstream_message(
                 })
             } else if cmd_type == Some("ConnectionRefused") {
                 log::debug!("Connection refused by upstream");
+                // Upstream keeps the connection open for another Connect
+                *state = ConnectionState::WaitingForConnect;
                 Ok(MessageDecision::Forward)
+            } else if relogin {
+                log::debug!(
+                    "Dropping upstream message {:?} of the previous login",
+                    cmd_type
+                );
+                Ok(MessageDecision::Drop)
             } else {
-                bail!(
+                Err(ProxyError::upstream(format!(
                     "Expected Connected, ConnectionRefused, or DataPackage, got {:?}",
                     cmd_type
-                );
+                )))
             }
         }
         ConnectionState::LoggedIn => Ok(MessageDecision::Forward),
     }
 }
 
+/// APX checks passwords itself, so clients are always told the room has one. Whether upstream
+/// asks for one of its own, which UPSTREAM_ROOM_PASSWORD answers, goes to the metrics.
+fn rewrite_room_info(cmd: &mut Value, room_id: &str) -> ProxyResult<()> {
+    let mut room_info = parse_as::<RoomInfo>(cmd).map_err(ProxyError::upstream)?;
+    metrics::set_upstream_password_required(room_id, room_info.password());
+    room_info.set_password(true);
+    *cmd = serde_json::to_value(room_info).map_err(ProxyError::internal)?;
+    Ok(())
+}
+
+/// Locations reported as checked by a client's LocationChecks or upstream's RoomUpdate
+fn checked_locations(cmd: &Value) -> Option<Vec<i64>> {
+    match get_cmd(cmd)? {
+        "LocationChecks" => parse_as::<LocationChecks>(cmd).ok().map(|c| c.locations),
+        "RoomUpdate" => parse_as::<RoomUpdate>(cmd)
+            .ok()
+            .map(|u| u.checked_locations)
+            .filter(|locations| !locations.is_empty()),
+        _ => None,
+    }
+}
+
+fn update_hint(cmd: &Value) -> Option<UpdateHint> {
+    if get_cmd(cmd)? != "UpdateHint" {
+        return None;
+    }
+    parse_as::<UpdateHint>(cmd).ok()
+}
+
+fn received_items(cmd: &Value) -> Option<ReceivedItems> {
+    if get_cmd(cmd)? != "ReceivedItems" {
+        return None;
+    }
+    parse_as::<ReceivedItems>(cmd).ok()
+}
+
+fn reports_goal(cmd: &Value) -> bool {
+    get_cmd(cmd) == Some("StatusUpdate")
+        && parse_as::<StatusUpdate>(cmd).is_ok_and(|update| update.status == CLIENT_GOAL)
+}
+
+/// Text of a Say that's chat rather than a command
+fn chat_text(cmd: &Value) -> Option<&str> {
+    if get_cmd(cmd) != Some("Say") {
+        return None;
+    }
+    let text = cmd.get("text")?.as_str()?;
+    chat_command(text).is_none().then_some(text)
+}
+
+/// Whether a client command gets past the permission overrides
+fn permitted(cmd: &Value, overrides: &PermissionOverrides, goal_reached: bool) -> bool {
+    if get_cmd(cmd) != Some("Say") {
+        return true;
+    }
+    let Some(text) = cmd.get("text").and_then(|text| text.as_str()) else {
+        return true;
+    };
+    chat_command(text).is_none_or(|command| overrides.allows(&command.name, goal_reached))
+}
+
+/// Errors listed in a ConnectionRefused packet
+fn refusal_errors(cmd: &Value) -> Option<Vec<String>> {
+    if get_cmd(cmd) != Some("ConnectionRefused") {
+        return None;
+    }
+    parse_as::<ConnectionRefused>(cmd)
+        .ok()
+        .map(|refused| refused.errors)
+}
+
+/// Refusal errors defined by the protocol, anything else is counted as "other" to keep the
+/// metric's cardinality bounded
+fn refusal_label(error: &str) -> &str {
+    match error {
+        "InvalidSlot"
+        | "InvalidGame"
+        | "IncompatibleVersion"
+        | "InvalidPassword"
+        | "InvalidItemsHandling"
+        | "SlotAlreadyTaken" => error,
+        _ => "other",
+    }
+}
+
+fn slot_for_name(slot_names: &HashMap<SlotId, String>, name: &str) -> Option<SlotId> {
+    slot_names
+        .iter()
+        .find(|(_, slot_name)| slot_name.as_str() == name)
+        .map(|(slot, _)| *slot)
+}
+
+/// The player of the slot a Connected logged into
+fn connected_player(connected: &Connected) -> Option<&NetworkPlayer> {
+    connected
+        .players
+        .iter()
+        .find(|player| player.team == connected.team && player.slot == connected.slot)
+}
+
+/// Checks a Connect that came with a slot against the login upstream answered it with. The
+/// slot takes precedence, the name is only checked when the Connect has both and matches the
+/// player's alias as well as their slot name. A player the Connected doesn't list isn't
+/// checked by name.
+fn check_requested_slot(
+    name: &str,
+    requested_slot: Option<SlotId>,
+    connected: &Connected,
+) -> Result<(), Refusal> {
+    let Some(requested_slot) = requested_slot else {
+        return Ok(());
+    };
+    if connected.slot != requested_slot {
+        return Err(Refusal::InvalidSlot);
+    }
+    match connected_player(connected) {
+        Some(player) if !name.is_empty() && player.name != name && player.alias != name => {
+            Err(Refusal::InvalidSlot)
+        }
+        _ => Ok(()),
+    }
+}
+
 fn reorder_slot_first(cmd: &mut Value) {
     let Value::Object(obj) = cmd else { return };
     let Some(slot_val) = obj.shift_remove("slot") else {
@@ -1142,98 +3547,1275 @@ impl<'a> std::fmt::Debug for CommandList<'a> {
     }
 }
 
-fn parse_as<T: serde::de::DeserializeOwned>(value: &serde_json::Value) -> Result<T> {
-    T::deserialize(value).map_err(Into::into)
+fn parse_as<T: serde::de::DeserializeOwned>(value: &serde_json::Value) -> serde_json::Result<T> {
+    T::deserialize(value)
 }
 
-fn parse_message(text: &str) -> Result<Vec<serde_json::Value>> {
+fn parse_message(text: &str) -> Option<Vec<serde_json::Value>> {
     if let Ok(commands) = serde_json::from_str::<Vec<serde_json::Value>>(text) {
-        return Ok(commands);
+        return Some(commands);
     }
 
-    if let Ok(single) = serde_json::from_str::<serde_json::Value>(text) {
-        return Ok(vec![single]);
-    }
+    serde_json::from_str::<serde_json::Value>(text)
+        .ok()
+        .map(|single| vec![single])
+}
 
-    bail!("Could not parse message as JSON")
+/// Drops the client commands over their size limit, returns whether there were any
+fn drop_oversized(
+    commands: &mut Vec<Value>,
+    text: &str,
+    limits: &CommandSizeLimits,
+    room_id: &str,
+) -> bool {
+    let oversized = limits.oversized(text, commands);
+    for (index, size) in oversized.iter().rev() {
+        let command = commands.remove(*index);
+        let cmd = get_cmd(&command).unwrap_or("Unknown");
+        log::warn!("Dropping oversized {} from client ({} bytes)", cmd, size);
+        metrics::record_oversized_command(room_id, cmd, "client_to_upstream");
+    }
+    !oversized.is_empty()
 }
 
-fn is_command(text: &str, command_name: &str) -> bool {
-    // This matches as best we can the way archipelago does command parsing
-    let trimmed = text.trim();
-    if trimmed.is_empty() {
-        return false;
+/// Only the depth and the size keep a message from being parsed, anything else past the limits
+/// is logged and parsed anyway
+fn parse_upstream(text: &str, limits: &ParseLimits, room_id: &str) -> Result<Vec<Value>, String> {
+    if let Err(exceeded) = limits.check(text) {
+        metrics::record_json_limit(room_id, "upstream_to_client", exceeded.label());
+        if matches!(exceeded, LimitExceeded::Depth(_) | LimitExceeded::Size(_)) {
+            return Err(exceeded.to_string());
+        }
+        log::warn!(
+            "Upstream message {}: {}",
+            exceeded,
+            json_limits::sample(text, 200)
+        );
     }
+    parse_message(text).ok_or_else(|| "invalid JSON".to_string())
+}
 
-    // try:
-    //     command = shlex.split(raw, comments=False)
-    // except ValueError:  # most likely: "ValueError: No closing quotation"
-    //     command = raw.split()
-    let parts = match shlex::split(trimmed) {
-        Some(parts) => parts,
-        None => trimmed.split_whitespace().map(String::from).collect(),
-    };
+/// Upstream commands forwarded untouched once logged in, which don't need to be parsed at all.
+/// DataPackage is worth it, parsing it is tens of MB of `Value`s per connecting client.
+const PASSTHROUGH_COMMANDS: &[&str] = &["DataPackage"];
+/// Where the `cmd` of a passthrough command is looked for before checking the whole message
+const PASSTHROUGH_PEEK_BYTES: usize = 256;
+
+#[derive(serde::Deserialize)]
+pub(crate) struct CmdOnly<'a> {
+    #[serde(borrow)]
+    pub(crate) cmd: std::borrow::Cow<'a, str>,
+}
 
-    if parts.is_empty() {
-        return false;
+/// The command of a message made of a single passthrough command. The message is still checked
+/// to be valid JSON, but the rest of the command is skipped over rather than built. Commands a
+/// rule of `transforms` rewrites aren't passed through.
+fn passthrough_cmd(text: &str, transforms: &Transforms) -> Option<&'static str> {
+    let head = &text.as_bytes()[..text.len().min(PASSTHROUGH_PEEK_BYTES)];
+    let candidate = PASSTHROUGH_COMMANDS.iter().find(|cmd| {
+        let quoted = format!("\"{}\"", cmd);
+        head.windows(quoted.len())
+            .any(|window| window == quoted.as_bytes())
+    })?;
+    if transforms.rewrites(Direction::FromUpstream, candidate) {
+        return None;
     }
 
-    // basecommand = command[0]
-    // if basecommand[0] == self.marker:
-    //     method = self.commands.get(basecommand[1:].lower(), None)
-    let first = &parts[0];
-    first.starts_with('!') && first[1..].eq_ignore_ascii_case(command_name)
+    let [command] = serde_json::from_str::<[CmdOnly; 1]>(text).ok()?;
+    (command.cmd == *candidate).then_some(*candidate)
+}
+
+/// Upstream commands that are parsed even in degraded mode: they're rewritten or dropped, or
+/// what they say is checked against later (hints, permissions). The permission overrides only
+/// restrict RoomInfo and RoomUpdate, so they're in here too.
+const ALWAYS_INSPECTED: &[&str] = &[
+    "Bounced",
+    "Connected",
+    "PrintJSON",
+    "Retrieved",
+    "RoomInfo",
+    "RoomUpdate",
+    "SetReply",
+];
+
+/// The first command of a message that can be forwarded unparsed in degraded mode. Every
+/// command's `cmd` is read, a single one that must be inspected or that a rule of `transforms`
+/// rewrites gets the whole message parsed.
+fn degraded_cmd(text: &str, transforms: &Transforms) -> Option<String> {
+    let commands = serde_json::from_str::<Vec<CmdOnly>>(text).ok()?;
+    if commands.iter().any(|command| {
+        ALWAYS_INSPECTED.contains(&command.cmd.as_ref())
+            || transforms.rewrites(Direction::FromUpstream, &command.cmd)
+    }) {
+        return None;
+    }
+    commands.first().map(|command| command.cmd.to_string())
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use crate::flapping::FlapLimits;
+    use crate::groups;
+    use crate::tests::fixtures;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+    use tungstenite::protocol::frame::coding::CloseCode;
+
+    pub(crate) fn packet(commands: Vec<Value>) -> Message {
+        Message::Text(serde_json::to_string(&commands).unwrap().into())
+    }
+
+    pub(crate) fn mock_room_info() -> Value {
+        let version = json!({"major": 0, "minor": 6, "build": 0, "class": "Version"});
+        json!({
+            "cmd": "RoomInfo",
+            "password": false,
+            "games": ["Test"],
+            "tags": [],
+            "version": version.clone(),
+            "generator_version": version,
+            "permissions": {"release": 1, "collect": 1, "remaining": 1},
+        })
+    }
+
+    pub(crate) fn mock_connected() -> Value {
+        json!({
+            "cmd": "Connected",
+            "team": 0,
+            "slot": 1,
+            "players": [{"team": 0, "slot": 1, "alias": "Alice", "name": "Alice"}],
+            "missing_locations": [],
+            "checked_locations": [],
+            "slot_info": {},
+        })
+    }
+
+    pub(crate) fn mock_received_items(index: u64, items: &[i64]) -> Value {
+        let items: Vec<Value> = items
+            .iter()
+            .map(|item| json!({"item": item, "location": 0, "player": 1, "flags": 0}))
+            .collect();
+        json!({"cmd": "ReceivedItems", "index": index, "items": items})
+    }
+
+    /// Upstream that accepts two logins. The second one keeps sending items after Connected.
+    pub(crate) async fn spawn_mock_upstream() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for connection in 0..2 {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+                ws.send(packet(vec![mock_room_info()])).await.unwrap();
+                while let Some(Ok(msg)) = ws.next().await {
+                    if msg.to_text().unwrap_or("").contains("\"Connect\"") {
+                        break;
+                    }
+                }
+                ws.send(packet(vec![mock_connected(), mock_received_items(0, &[1])]))
+                    .await
+                    .unwrap();
+                if connection == 1 {
+                    ws.send(packet(vec![mock_received_items(1, &[2])]))
+                        .await
+                        .unwrap();
+                }
+                tokio::spawn(async move { while let Some(Ok(_)) = ws.next().await {} });
+            }
+        });
+        format!("ws://{}", addr)
+    }
+
+    fn test_rooms() -> HashMap<String, RoomRoute> {
+        let route = RoomRoute {
+            upstream: Arc::new(UpstreamServers::new(
+                "ap2:38281".parse().unwrap(),
+                Vec::new(),
+                Duration::ZERO,
+            )),
+            passwords: Default::default(),
+            datapackage_cache: Arc::new(DataPackageCache::from_response(json!({})).unwrap()),
+            client_registry: Arc::new(ClientRegistry::new(FlapLimits::default())),
+            login_queue: Arc::new(LoginQueue::new("race", None, Duration::ZERO)),
+            slot_groups: Default::default(),
+        };
+        HashMap::from([("race".to_string(), route)])
+    }
 
     #[test]
-    fn test_is_command_basic() {
-        assert!(is_command("!countdown", "countdown"));
-        assert!(is_command("  !countdown", "countdown"));
-        assert!(is_command("!countdown 10", "countdown"));
-        assert!(is_command("!COUNTDOWN", "countdown"));
-        assert!(is_command("\"!COUNTDOWN\"", "countdown"));
-        assert!(is_command("'!COUNTDOWN'", "countdown"));
-        assert!(is_command("  !countdown  ", "countdown"));
-        assert!(is_command("  !\\countdown  ", "countdown"));
-        assert!(is_command("  !\"\"countdown  ", "countdown"));
+    fn test_select_route_default() {
+        let rooms = test_rooms();
+        assert!(select_route("/", &rooms).unwrap().is_none());
+        assert!(select_route("", &rooms).unwrap().is_none());
+        assert!(select_route("/race", &rooms).unwrap().is_none());
     }
 
     #[test]
-    fn test_is_command_with_args() {
-        assert!(is_command("!countdown 30", "countdown"));
-        assert!(is_command("!countdown 30 message", "countdown"));
-        assert!(is_command("!countdown 10 '", "countdown"));
+    fn test_select_route_known_room() {
+        let rooms = test_rooms();
+        let (room, route) = select_route("/room/race", &rooms).unwrap().unwrap();
+        assert_eq!(room, "race");
+        assert_eq!(route.upstream.primary().url(), "ws://ap2:38281");
+
+        let (room, _) = select_route("/room/race/", &rooms).unwrap().unwrap();
+        assert_eq!(room, "race");
     }
 
     #[test]
-    fn test_is_command_with_quotes() {
-        assert!(is_command("!countdown \"some message\"", "countdown"));
-        assert!(is_command("!countdown 'message with spaces'", "countdown"));
+    fn test_select_route_unknown_room() {
+        let rooms = test_rooms();
+        let response = select_route("/room/other", &rooms).unwrap_err();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            select_route("/room/", &rooms).unwrap_err().status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn test_progress_from_connected_and_checks() {
+        let mut connected = mock_connected();
+        connected["checked_locations"] = json!([1]);
+        connected["missing_locations"] = json!([2, 3, 4]);
+        let connected = parse_as::<Connected>(&connected).unwrap();
+        let mut progress =
+            LocationProgress::new(&connected.checked_locations, &connected.missing_locations);
+
+        let sequence = [
+            json!({"cmd": "LocationChecks", "locations": [2]}),
+            json!({"cmd": "RoomUpdate", "checked_locations": [2]}),
+            json!({"cmd": "LocationChecks", "locations": [1, 2, 3]}),
+            json!({"cmd": "RoomUpdate", "hint_points": 3}),
+            json!({"cmd": "Say", "text": "hi"}),
+        ];
+        for cmd in &sequence {
+            if let Some(locations) = checked_locations(cmd) {
+                progress.check(&locations);
+            }
+        }
+
+        let summary = progress.summary();
+        assert_eq!(summary.checked, 3);
+        assert_eq!(summary.total, 4);
+        assert_eq!(summary.percentage, 75.0);
+        assert_eq!(checked_locations(&sequence[3]), None);
     }
 
     #[test]
-    fn test_is_command_not_matching() {
-        assert!(!is_command("countdown", "countdown"));
-        assert!(!is_command("!other", "countdown"));
-        assert!(!is_command("!countdownfoo", "countdown"));
-        assert!(!is_command("", "countdown"));
-        assert!(!is_command("  ", "countdown"));
+    fn test_only_oversized_client_commands_are_dropped() {
+        let limits: CommandSizeLimits = r#"{"Say": 40}"#.parse().unwrap();
+        let text = r#"[{"cmd":"Say","text":"short"},{"cmd":"Say","text":"far too long for the limit"},{"cmd":"Sync"}]"#;
+        let mut commands = parse_message(text).unwrap();
+        assert!(drop_oversized(&mut commands, text, &limits, "test"));
+        assert_eq!(
+            commands,
+            vec![
+                json!({"cmd": "Say", "text": "short"}),
+                json!({"cmd": "Sync"})
+            ]
+        );
     }
 
     #[test]
-    fn test_is_command_embedded_in_text() {
-        assert!(is_command("!countdown", "countdown"));
-        assert!(!is_command("!countdowntest", "countdown"));
+    fn test_items_received_over_a_resync() {
+        let item =
+            |location: i64| json!({"item": 7, "location": location, "player": 2, "flags": 1});
+        let sequence = [
+            json!({"cmd": "ReceivedItems", "index": 0, "items": [item(1), item(2)]}),
+            json!({"cmd": "ReceivedItems", "index": 2, "items": [item(3)]}),
+            json!({"cmd": "Say", "text": "hi"}),
+            // Sync
+            json!({"cmd": "ReceivedItems", "index": 0, "items": [item(1), item(2), item(3)]}),
+        ];
+        let mut received = crate::progress::ItemsReceived::default();
+        for cmd in &sequence {
+            if let Some(items) = received_items(cmd) {
+                received.record(items.index, items.items.len());
+            }
+        }
+        assert_eq!(received.count(), 3);
+        assert!(received_items(&sequence[2]).is_none());
     }
 
     #[test]
-    fn test_is_command_case_insensitive() {
-        assert!(is_command("!CoUnTdOwN", "countdown"));
-        assert!(is_command("!COUNTDOWN", "countdown"));
-        assert!(is_command("!countdown", "COUNTDOWN"));
+    fn test_refusal_errors() {
+        let refused = json!({
+            "cmd": "ConnectionRefused",
+            "errors": ["InvalidGame", "IncompatibleVersion"],
+        });
+        assert_eq!(
+            refusal_errors(&refused),
+            Some(vec![
+                "InvalidGame".to_string(),
+                "IncompatibleVersion".to_string()
+            ])
+        );
+        assert_eq!(refusal_errors(&mock_connected()), None);
+
+        let labels: Vec<&str> = ["InvalidSlot", "SlotAlreadyTaken", "Something new"]
+            .iter()
+            .map(|error| refusal_label(error))
+            .collect();
+        assert_eq!(labels, vec!["InvalidSlot", "SlotAlreadyTaken", "other"]);
+    }
+
+    fn upstream_error(state: &mut ConnectionState, mut cmd: Value) -> ProxyError {
+        let login_check = LoginCheck {
+            passwords: &HashMap::new(),
+            tokens: None,
+            room_id: "test",
+            chained: false,
+            claims: None,
+            resumption: None,
+        };
+        let result = handle_upstream_message(
+            state,
+            &mut StorageRequests::default(),
+            &mut cmd,
+            &login_check,
+            &HashSet::new(),
+            &PreferenceMap::new(),
+            &SlotGroups::default(),
+            &None,
+            &DeathlinkProbability::default(),
+            false,
+        );
+        let Err(error) = result else {
+            panic!("Expected {} to be rejected", cmd);
+        };
+        error
+    }
+
+    fn login_with(password: &str, tokens: Option<&TokenKey>) -> MessageDecision {
+        let passwords = HashMap::from([(SlotId(1), "hunter2".to_string())]);
+        let login_check = LoginCheck {
+            passwords: &passwords,
+            tokens,
+            room_id: "test",
+            chained: false,
+            claims: None,
+            resumption: None,
+        };
+        let mut state = ConnectionState::WaitingForConnected {
+            password: password.to_string(),
+            tags: Vec::new(),
+            game: String::new(),
+            name: "Alice".to_string(),
+            slot: None,
+            software: Default::default(),
+            relogin: false,
+        };
+        handle_upstream_message(
+            &mut state,
+            &mut StorageRequests::default(),
+            &mut mock_connected(),
+            &login_check,
+            &HashSet::new(),
+            &PreferenceMap::new(),
+            &SlotGroups::default(),
+            &None,
+            &DeathlinkProbability::default(),
+            false,
+        )
+        .ok()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_token_login_falls_back_to_password() {
+        let key = TokenKey::new("secret");
+        let mint = |room_id: &str, slot: i64, exp: u64| {
+            key.mint(&token::TokenClaims {
+                room_id: room_id.to_string(),
+                slot: SlotId(slot),
+                exp,
+            })
+        };
+        let valid = mint("test", 1, u64::MAX);
+        let accepted = |decision: MessageDecision| {
+            matches!(decision, MessageDecision::ForwardWithRegistration { .. })
+        };
+
+        assert!(accepted(login_with(&valid, Some(&key))));
+        assert!(accepted(login_with("hunter2", Some(&key))));
+        assert!(!accepted(login_with(&mint("test", 1, 1), Some(&key))));
+        assert!(!accepted(login_with(
+            &mint("other", 1, u64::MAX),
+            Some(&key)
+        )));
+        assert!(!accepted(login_with(
+            &mint("test", 2, u64::MAX),
+            Some(&key)
+        )));
+        // Tokens aren't accepted when TOKEN_SECRET isn't set
+        assert!(!accepted(login_with(&valid, None)));
+    }
+
+    #[test]
+    fn test_connect_slot_takes_precedence_over_the_name() {
+        let mut connected = mock_connected();
+        connected["players"][0]["alias"] = "Ali".into();
+        let connected = parse_as::<Connected>(&connected).unwrap();
+        let check = |name: &str, slot: Option<i64>| {
+            check_requested_slot(name, slot.map(SlotId), &connected).map_err(Refusal::error)
+        };
+
+        // Name only
+        assert_eq!(check("Alice", None), Ok(()));
+        // Slot only
+        assert_eq!(check("", Some(1)), Ok(()));
+        assert_eq!(check("", Some(2)), Err("InvalidSlot"));
+        // Both, consistent by name or by alias
+        assert_eq!(check("Alice", Some(1)), Ok(()));
+        assert_eq!(check("Ali", Some(1)), Ok(()));
+        // Both, conflicting
+        assert_eq!(check("Bob", Some(1)), Err("InvalidSlot"));
+        assert_eq!(check("Alice", Some(2)), Err("InvalidSlot"));
+    }
+
+    #[test]
+    fn test_slot_only_connect_is_checked_by_slot() {
+        let passwords = HashMap::from([(SlotId(1), "hunter2".to_string())]);
+        let login_check = LoginCheck {
+            passwords: &passwords,
+            tokens: None,
+            room_id: "test",
+            chained: false,
+            claims: None,
+            resumption: None,
+        };
+        let login = |password: &str| {
+            let mut state = ConnectionState::WaitingForConnect;
+            let mut connect = json!({
+                "cmd": "Connect",
+                "password": password,
+                "name": "",
+                "slot": 1,
+                "uuid": "",
+                "tags": [],
+            });
+            intercept_connect(&mut state, &mut connect, &None, false, None);
+            let ConnectionState::WaitingForConnected { slot, .. } = &state else {
+                panic!("expected to wait for Connected");
+            };
+            assert_eq!(*slot, Some(SlotId(1)));
+            handle_upstream_message(
+                &mut state,
+                &mut StorageRequests::default(),
+                &mut mock_connected(),
+                &login_check,
+                &HashSet::new(),
+                &PreferenceMap::new(),
+                &SlotGroups::default(),
+                &None,
+                &DeathlinkProbability::default(),
+                false,
+            )
+            .ok()
+            .unwrap()
+        };
+
+        assert!(matches!(
+            login("hunter2"),
+            MessageDecision::ForwardWithRegistration { .. }
+        ));
+        assert!(matches!(
+            login("wrong"),
+            MessageDecision::SendConnectionRefused(Refusal::InvalidPassword)
+        ));
+    }
+
+    fn upstream_batch(
+        state: &mut ConnectionState,
+        storage: &mut StorageRequests,
+        messages: &mut Vec<Value>,
+    ) -> UpstreamResult {
+        let login_check = LoginCheck {
+            passwords: &HashMap::new(),
+            tokens: None,
+            room_id: "test",
+            chained: false,
+            claims: None,
+            resumption: None,
+        };
+        handle_upstream_messages(
+            state,
+            storage,
+            messages,
+            &login_check,
+            &HashSet::new(),
+            &PreferenceMap::new(),
+            &SlotGroups::default(),
+            &None,
+            &DeathlinkProbability::default(),
+            false,
+        )
+        .ok()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_unparsable_upstream_messages() {
+        let limits = ParseLimits {
+            max_depth: 100,
+            max_size: MAX_MESSAGE_SIZE,
+            max_string: 16,
+        };
+
+        let fine = serde_json::to_string(&[mock_received_items(0, &[1])]).unwrap();
+        assert_eq!(parse_upstream(&fine, &limits, "test").unwrap().len(), 1);
+
+        let truncated = &fine[..fine.len() / 2];
+        assert_eq!(
+            parse_upstream(truncated, &limits, "test"),
+            Err("invalid JSON".to_string())
+        );
+
+        // Past serde_json's own recursion limit
+        let nested = format!("{}{}", "[".repeat(1000), "]".repeat(1000));
+        assert_eq!(
+            parse_upstream(&nested, &limits, "test"),
+            Err("nested deeper than 100 levels".to_string())
+        );
+
+        // Long strings from upstream are only logged
+        let long =
+            serde_json::to_string(&[json!({"cmd": "Print", "text": "a".repeat(100)})]).unwrap();
+        assert_eq!(parse_upstream(&long, &limits, "test").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_passthrough_cmd() {
+        let games: serde_json::Map<String, Value> = (0..200)
+            .map(|i| {
+                let names: HashMap<String, i64> =
+                    (0..100).map(|j| (format!("Item {}", j), j)).collect();
+                (format!("Game {}", i), json!({"item_name_to_id": names}))
+            })
+            .collect();
+        let datapackage = json!({"cmd": "DataPackage", "data": {"games": games}});
+        let text = serde_json::to_string(&[&datapackage]).unwrap();
+        let none = Transforms::default();
+        assert_eq!(passthrough_cmd(&text, &none), Some("DataPackage"));
+
+        // Anything the proxy may have to look into goes through the full parse
+        let batch = serde_json::to_string(&[&datapackage, &json!({"cmd": "Bounced"})]).unwrap();
+        assert_eq!(passthrough_cmd(&batch, &none), None);
+        assert_eq!(passthrough_cmd(&text[..text.len() - 1], &none), None);
+        let mention = json!([{"cmd": "PrintJSON", "data": [{"text": "\"DataPackage\""}]}]);
+        assert_eq!(passthrough_cmd(&mention.to_string(), &none), None);
+        let rewritten = json!([{"name": "r", "direction": "from_upstream", "cmd": "DataPackage",
+            "operations": [{"op": "remove", "path": "data.games.*.checksum"}]}]);
+        let transforms = Transforms::parse(&rewritten.to_string()).unwrap();
+        assert_eq!(passthrough_cmd(&text, &transforms), None);
+    }
+
+    /// Compares peeking at a captured DataPackage with parsing it whole, as it was before it was
+    /// passed through. Run with `cargo test --release bench_passthrough_cmd -- --ignored`, it
+    /// fails with both timings if peeking isn't the faster of the two.
+    #[test]
+    #[ignore]
+    fn bench_passthrough_cmd_on_captured_datapackage() {
+        const ITERATIONS: u32 = 10_000;
+        let fixture = fixtures::load(Side::Upstream)
+            .into_iter()
+            .find(|fixture| fixture.name == "datapackage_clique.json")
+            .expect("the captured DataPackage");
+        let limits = crate::config::tests::test_config("bench").upstream_parse_limits;
+        let none = Transforms::default();
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            std::hint::black_box(passthrough_cmd(std::hint::black_box(&fixture.text), &none));
+        }
+        let passthrough = start.elapsed() / ITERATIONS;
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            std::hint::black_box(parse_upstream(
+                std::hint::black_box(&fixture.text),
+                &limits,
+                "bench",
+            ))
+            .unwrap();
+        }
+        let parsed = start.elapsed() / ITERATIONS;
+
+        assert!(
+            passthrough < parsed,
+            "{}: passed through in {:?}, parsed in {:?}",
+            fixture.name,
+            passthrough,
+            parsed
+        );
+    }
+
+    #[test]
+    fn test_degraded_cmd() {
+        let none = Transforms::default();
+        let received = json!({"cmd": "ReceivedItems", "index": 0, "items": []});
+        let text = serde_json::to_string(&[&received, &json!({"cmd": "LocationInfo"})]).unwrap();
+        assert_eq!(degraded_cmd(&text, &none).as_deref(), Some("ReceivedItems"));
+
+        // A bounce further down still gets the whole message parsed
+        let batch = serde_json::to_string(&[&received, &json!({"cmd": "Bounced"})]).unwrap();
+        assert_eq!(degraded_cmd(&batch, &none), None);
+        assert_eq!(degraded_cmd("[]", &none), None);
+        assert_eq!(degraded_cmd(&text[..text.len() - 1], &none), None);
+
+        // So does a command a transform rewrites, only in the direction it's for
+        let rule = |direction: &str| {
+            json!([{"name": "r", "direction": direction, "cmd": "LocationInfo",
+                "operations": [{"op": "remove", "path": "locations"}]}])
+            .to_string()
+        };
+        let from_upstream = Transforms::parse(&rule("from_upstream")).unwrap();
+        assert_eq!(degraded_cmd(&text, &from_upstream), None);
+        let from_client = Transforms::parse(&rule("from_client")).unwrap();
+        assert_eq!(
+            degraded_cmd(&text, &from_client).as_deref(),
+            Some("ReceivedItems")
+        );
+    }
+
+    #[test]
+    fn test_mid_session_room_info_keeps_password_flag() {
+        let mut room_info = mock_room_info();
+        room_info["datapackage_checksums"] = json!({"Test": "abc"});
+
+        for mut state in [
+            ConnectionState::WaitingForConnect,
+            ConnectionState::LoggedIn,
+        ] {
+            let mut messages = vec![room_info.clone()];
+            let UpstreamResult::Continue {
+                modified,
+                datapackage_checksums,
+                ..
+            } = upstream_batch(&mut state, &mut StorageRequests::default(), &mut messages)
+            else {
+                panic!("RoomInfo shouldn't refuse the connection");
+            };
+
+            assert!(modified);
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0]["password"], true);
+            assert_eq!(datapackage_checksums, Some(json!({"Test": "abc"})));
+        }
+
+        let mut state = ConnectionState::LoggedIn;
+        let mut messages = vec![mock_received_items(0, &[1])];
+        let UpstreamResult::Continue {
+            datapackage_checksums,
+            ..
+        } = upstream_batch(&mut state, &mut StorageRequests::default(), &mut messages)
+        else {
+            panic!("ReceivedItems shouldn't refuse the connection");
+        };
+        assert_eq!(datapackage_checksums, None);
+    }
+
+    #[test]
+    fn test_upstream_failures_are_upstream_protocol_errors() {
+        let mut state = ConnectionState::WaitingForRoomInfo;
+        let error = upstream_error(&mut state, mock_connected());
+        assert!(matches!(error, ProxyError::UpstreamProtocol(_)));
+
+        let mut state = ConnectionState::WaitingForRoomInfo;
+        let error = upstream_error(&mut state, json!({"cmd": "RoomInfo"}));
+        assert!(matches!(error, ProxyError::UpstreamProtocol(_)));
+
+        let mut state = ConnectionState::WaitingForConnected {
+            password: String::new(),
+            tags: Vec::new(),
+            game: String::new(),
+            name: String::new(),
+            slot: None,
+            software: Default::default(),
+            relogin: false,
+        };
+        let error = upstream_error(&mut state, json!({"cmd": "PrintJSON", "data": []}));
+        assert!(matches!(error, ProxyError::UpstreamProtocol(_)));
+    }
+
+    /// Fails unless the commands the proxy deserializes still do
+    fn assert_deserializes(fixture: &str, cmd: &Value) {
+        let result = match get_cmd(cmd) {
+            Some("RoomInfo") => parse_as::<RoomInfo>(cmd).map(drop),
+            Some("Connected") => parse_as::<Connected>(cmd).map(drop),
+            Some("ReceivedItems") => parse_as::<ReceivedItems>(cmd).map(drop),
+            Some("PrintJSON") => parse_as::<PrintJSON>(cmd).map(drop),
+            Some("Bounce" | "Bounced") => parse_as::<Bounced>(cmd).map(drop),
+            Some("Say") => parse_as::<Say>(cmd).map(drop),
+            Some("LocationChecks") => parse_as::<LocationChecks>(cmd).map(drop),
+            Some("StatusUpdate") => parse_as::<StatusUpdate>(cmd).map(drop),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            panic!(
+                "{}: {:?} no longer deserializes: {}",
+                fixture,
+                get_cmd(cmd),
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn test_captured_upstream_traffic_round_trips() {
+        for fixture in fixtures::load(Side::Upstream) {
+            let name = fixture.name.as_str();
+            let captured = parse_message(&fixture.text).unwrap_or_else(|| panic!("{}", name));
+            for cmd in &captured {
+                assert_deserializes(name, cmd);
+            }
+
+            let mut state = ConnectionState::LoggedIn;
+            let mut forwarded = captured.clone();
+            let UpstreamResult::Continue {
+                modified,
+                inject_response: None,
+                registration: None,
+                ..
+            } = upstream_batch(&mut state, &mut StorageRequests::default(), &mut forwarded)
+            else {
+                panic!("{}: unexpected result once logged in", name);
+            };
+
+            // Clients are always told the room has a password, it's the only rewrite
+            let rewritten = captured.iter().any(|cmd| get_cmd(cmd) == Some("RoomInfo"));
+            let expected: Vec<Value> = captured
+                .iter()
+                .cloned()
+                .map(|mut cmd| {
+                    if get_cmd(&cmd) == Some("RoomInfo") {
+                        cmd["password"] = true.into();
+                    }
+                    cmd
+                })
+                .collect();
+            assert_eq!(forwarded, expected, "{}", name);
+            // Left alone, the client gets the very bytes upstream sent
+            if !rewritten {
+                assert!(!modified, "{} was serialized again", name);
+            }
+            if captured.len() == 1 && get_cmd(&captured[0]) == Some("DataPackage") {
+                assert_eq!(
+                    passthrough_cmd(&fixture.text, &Transforms::default()),
+                    Some("DataPackage"),
+                    "{}",
+                    name
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_captured_client_traffic_round_trips() {
+        for fixture in fixtures::load(Side::Client) {
+            let name = fixture.name.as_str();
+            let captured = parse_message(&fixture.text).unwrap_or_else(|| panic!("{}", name));
+            for cmd in &captured {
+                assert_deserializes(name, cmd);
+            }
+
+            let mut state = ConnectionState::LoggedIn;
+            let mut forwarded = captured.clone();
+            let result = handle_client_messages(
+                &mut state,
+                &mut StorageRequests::default(),
+                &mut forwarded,
+                &Some((SlotId(1), "Player1".to_string())),
+                &EventBus::new(),
+                &PreferenceMap::new(),
+                &SlotGroups::default(),
+                &HashSet::new(),
+                &Arc::new(DataPackageCache::from_response(json!({})).unwrap()),
+                false,
+                false,
+                Some(""),
+            )
+            .await
+            .unwrap_or_else(|e| panic!("{}: {}", name, e));
+
+            // Bounces are routed by the proxy itself, as they came
+            let (bounces, expected): (Vec<Value>, Vec<Value>) = captured
+                .iter()
+                .cloned()
+                .partition(|cmd| get_cmd(cmd) == Some("Bounce"));
+            assert_eq!(result.bounces_to_route, bounces, "{}", name);
+            assert_eq!(forwarded, expected, "{}", name);
+            assert!(result.denials.is_empty(), "{}", name);
+            if bounces.is_empty() {
+                assert!(!result.modified, "{} was serialized again", name);
+            }
+        }
+    }
+
+    async fn client_batch(
+        state: &mut ConnectionState,
+        storage: &mut StorageRequests,
+        mut messages: Vec<Value>,
+    ) -> Vec<Value> {
+        handle_client_messages(
+            state,
+            storage,
+            &mut messages,
+            &None,
+            &EventBus::new(),
+            &PreferenceMap::new(),
+            &SlotGroups::default(),
+            &HashSet::new(),
+            &Arc::new(DataPackageCache::from_response(json!({})).unwrap()),
+            false,
+            false,
+            Some(""),
+        )
+        .await
+        .ok()
+        .unwrap();
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_storage_is_only_answered_before_login_when_requested() {
+        let mut state = ConnectionState::WaitingForConnect;
+        let mut storage = StorageRequests::default();
+        let requests = vec![
+            json!({"cmd": "Get", "keys": ["_read_race_mode", "checksum"]}),
+            json!({"cmd": "SetNotify", "keys": ["hints"]}),
+            json!({"cmd": "Set", "key": "hints", "operations": []}),
+        ];
+        let forwarded = client_batch(&mut state, &mut storage, requests).await;
+        assert_eq!(
+            forwarded
+                .iter()
+                .map(|cmd| get_cmd(cmd).unwrap())
+                .collect::<Vec<_>>(),
+            vec!["Get", "SetNotify"]
+        );
+
+        let mut messages = vec![
+            json!({"cmd": "Retrieved", "keys": {"_read_race_mode": 0, "checksum": "abc"}}),
+            json!({"cmd": "SetReply", "key": "hints", "value": [], "original_value": []}),
+        ];
+        upstream_batch(&mut state, &mut storage, &mut messages);
+        assert_eq!(messages.len(), 2);
+
+        // Answered already, or never asked for
+        let mut messages = vec![
+            json!({"cmd": "Retrieved", "keys": {"checksum": "abc"}}),
+            json!({"cmd": "Retrieved", "keys": {"other_slot_data": 1}}),
+            json!({"cmd": "SetReply", "key": "other", "value": 1}),
+        ];
+        upstream_batch(&mut state, &mut storage, &mut messages);
+        assert!(messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_no_storage_reads_once_upstream_logged_in() {
+        let mut state = ConnectionState::WaitingForConnect;
+        let mut storage = StorageRequests::default();
+        let get = json!({"cmd": "Get", "keys": ["_read_hints_0_1"]});
+        client_batch(&mut state, &mut storage, vec![get.clone()]).await;
+
+        // Upstream logged in to a slot APX then refused
+        storage.logged_in();
+        let mut messages = vec![json!({"cmd": "Retrieved", "keys": {"_read_hints_0_1": []}})];
+        upstream_batch(&mut state, &mut storage, &mut messages);
+        assert!(messages.is_empty());
+
+        let requests = vec![
+            get,
+            json!({"cmd": "SetNotify", "keys": ["_read_hints_0_1"]}),
+        ];
+        assert!(
+            client_batch(&mut state, &mut storage, requests)
+                .await
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unrequested_keys_are_removed_from_retrieved() {
+        let mut state = ConnectionState::WaitingForConnect;
+        let mut storage = StorageRequests::default();
+        let get = json!({"cmd": "Get", "keys": ["checksum"]});
+        client_batch(&mut state, &mut storage, vec![get]).await;
+
+        // Still waiting for Connected
+        let mut state = ConnectionState::WaitingForConnected {
+            password: String::new(),
+            tags: Vec::new(),
+            game: String::new(),
+            name: String::new(),
+            slot: None,
+            software: Default::default(),
+            relogin: false,
+        };
+        let mut messages =
+            vec![json!({"cmd": "Retrieved", "keys": {"checksum": "abc", "secret": 1}})];
+        let UpstreamResult::Continue { modified, .. } =
+            upstream_batch(&mut state, &mut storage, &mut messages)
+        else {
+            panic!("Expected the Retrieved to be forwarded");
+        };
+        assert!(modified);
+        assert_eq!(
+            messages,
+            vec![json!({"cmd": "Retrieved", "keys": {"checksum": "abc"}})]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_message_before_room_info_is_a_client_error() {
+        let mut state = ConnectionState::WaitingForRoomInfo;
+        let result = handle_client_messages(
+            &mut state,
+            &mut StorageRequests::default(),
+            &mut vec![json!({"cmd": "Connect"})],
+            &None,
+            &EventBus::new(),
+            &PreferenceMap::new(),
+            &SlotGroups::default(),
+            &HashSet::new(),
+            &Arc::new(DataPackageCache::from_response(json!({})).unwrap()),
+            false,
+            false,
+            Some(""),
+        )
+        .await;
+        let Err(error) = result else {
+            panic!("Expected the client message to be rejected");
+        };
+        assert!(matches!(error, ProxyError::ClientProtocol(_)));
+        assert_eq!(error.close_code(), Some(CloseCode::Protocol));
+    }
+
+    fn upgrade_request(extensions: &[&str]) -> Request {
+        let mut builder = Request::builder().uri("ws://localhost/");
+        for extension in extensions {
+            builder = builder.header("Sec-WebSocket-Extensions", *extension);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn test_compression_negotiated() {
+        assert_eq!(
+            Compression::negotiated(&upgrade_request(&[])),
+            Compression::None
+        );
+        assert_eq!(
+            Compression::negotiated(&upgrade_request(&["permessage-deflate"])),
+            Compression::Deflate
+        );
+        assert_eq!(
+            Compression::negotiated(&upgrade_request(&[
+                "permessage-deflate; client_max_window_bits"
+            ])),
+            Compression::Deflate
+        );
+        assert_eq!(
+            Compression::negotiated(&upgrade_request(&["x-webkit-deflate-frame"])),
+            Compression::None
+        );
+        assert_eq!(
+            Compression::negotiated(&upgrade_request(&[
+                "x-webkit-deflate-frame",
+                "foo, Permessage-Deflate"
+            ])),
+            Compression::Deflate
+        );
+    }
+
+    #[test]
+    fn test_overridden_permissions_block_commands() {
+        let overrides: PermissionOverrides =
+            r#"{"release": "goal", "remaining": "disabled"}"#.parse().unwrap();
+        let say = |text: &str| json!({"cmd": "Say", "text": text});
+        assert!(!permitted(&say("!release"), &overrides, false));
+        assert!(permitted(&say("!release"), &overrides, true));
+        assert!(!permitted(&say("  !REMAINING"), &overrides, true));
+        assert!(!permitted(&say("/remaining\u{3000}"), &overrides, true));
+        assert!(permitted(&say("!remainingfoo"), &overrides, true));
+        assert!(permitted(&say("!collect"), &overrides, false));
+        assert!(permitted(&say("release me"), &overrides, false));
+        assert!(permitted(&json!({"cmd": "Sync"}), &overrides, false));
+
+        assert!(reports_goal(&json!({"cmd": "StatusUpdate", "status": 30})));
+        assert!(!reports_goal(&json!({"cmd": "StatusUpdate", "status": 20})));
+    }
+
+    fn deathlink_bounce(cmd: &str, slots: &[i64]) -> Value {
+        json!({
+            "cmd": cmd,
+            "tags": ["DeathLink"],
+            "slots": slots,
+            "data": {"source": "Alice", "cause": "fell", "time": 0.0},
+        })
+    }
+
+    fn incoming_deathlink(
+        slot: i64,
+        exclusions: &HashSet<SlotId>,
+        preferences: &PreferenceMap,
+    ) -> MessageDecision {
+        let login_check = LoginCheck {
+            passwords: &HashMap::new(),
+            tokens: None,
+            room_id: "test",
+            chained: false,
+            claims: None,
+            resumption: None,
+        };
+        handle_upstream_message(
+            &mut ConnectionState::LoggedIn,
+            &mut StorageRequests::default(),
+            &mut deathlink_bounce("Bounced", &[slot]),
+            &login_check,
+            exclusions,
+            preferences,
+            &groups::tests::fixture(),
+            &Some((SlotId(slot), format!("Player{}", slot))),
+            &DeathlinkProbability::default(),
+            false,
+        )
+        .ok()
+        .unwrap()
+    }
+
+    async fn outgoing(slot: i64, cmd: Value, preferences: &PreferenceMap) -> ClientHandlerResult {
+        let mut messages = vec![cmd];
+        handle_client_messages(
+            &mut ConnectionState::LoggedIn,
+            &mut StorageRequests::default(),
+            &mut messages,
+            &Some((SlotId(slot), format!("Player{}", slot))),
+            &EventBus::new(),
+            preferences,
+            &groups::tests::fixture(),
+            &HashSet::new(),
+            &Arc::new(DataPackageCache::from_response(json!({})).unwrap()),
+            false,
+            false,
+            Some(""),
+        )
+        .await
+        .ok()
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_excluded_group_member_blocks_group_deathlinks() {
+        // Bob (2) shares the item-link group 3 with Alice (1)
+        let exclusions = HashSet::from([SlotId(2)]);
+        let preferences = PreferenceMap::new();
+
+        // Deathlinks delivered to the group are dropped, Alice still gets her own
+        assert!(matches!(
+            incoming_deathlink(3, &exclusions, &preferences),
+            MessageDecision::Drop
+        ));
+        assert!(matches!(
+            incoming_deathlink(1, &exclusions, &preferences),
+            MessageDecision::Forward
+        ));
+
+        // Excluded slots still send theirs
+        let sent = outgoing(3, deathlink_bounce("Bounce", &[]), &preferences).await;
+        assert_eq!(sent.bounces_to_route.len(), 1);
+        let sent = outgoing(2, deathlink_bounce("Bounce", &[]), &preferences).await;
+        assert_eq!(sent.bounces_to_route.len(), 1);
+
+        // Opting out works the same way
+        let opted_out = PreferenceMap::from([(
+            SlotId(1),
+            preferences::SlotPreferences {
+                deathlink_opt_out: true,
+                ..Default::default()
+            },
+        )]);
+        assert!(matches!(
+            incoming_deathlink(3, &HashSet::new(), &opted_out),
+            MessageDecision::Drop
+        ));
+        assert!(matches!(
+            incoming_deathlink(2, &HashSet::new(), &opted_out),
+            MessageDecision::Forward
+        ));
+
+        // But an opted out member keeps the group from sending any
+        let sent = outgoing(3, deathlink_bounce("Bounce", &[]), &opted_out).await;
+        assert!(sent.bounces_to_route.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_muted_group_member_mutes_the_group() {
+        let muted = PreferenceMap::from([(
+            SlotId(1),
+            preferences::SlotPreferences {
+                muted: true,
+                ..Default::default()
+            },
+        )]);
+        let say = || json!({"cmd": "Say", "text": "hello"});
+
+        let result = outgoing(3, say(), &muted).await;
+        assert_eq!(result.denials, vec![Notice::Muted]);
+
+        // The other member isn't muted by sharing the group
+        let result = outgoing(2, say(), &muted).await;
+        assert!(result.denials.is_empty());
+    }
+
+    async fn logged_in_batch(
+        mut messages: Vec<Value>,
+        strict_commands: bool,
+    ) -> (ClientHandlerResult, Vec<Value>) {
+        let result = handle_client_messages(
+            &mut ConnectionState::LoggedIn,
+            &mut StorageRequests::default(),
+            &mut messages,
+            &Some((SlotId(1), "Alice".to_string())),
+            &EventBus::new(),
+            &PreferenceMap::new(),
+            &SlotGroups::default(),
+            &HashSet::new(),
+            &Arc::new(DataPackageCache::from_response(json!({})).unwrap()),
+            false,
+            strict_commands,
+            Some(""),
+        )
+        .await
+        .ok()
+        .unwrap();
+        (result, messages)
+    }
+
+    #[tokio::test]
+    async fn test_recoverable_packets_get_an_invalid_packet() {
+        let batch = || {
+            vec![
+                json!({"cmd": "Say", "text": "x".repeat(MAX_SAY_LENGTH + 1)}),
+                json!({"cmd": "LocationChecks", "locations": "all of them"}),
+                json!({"cmd": "Teleport"}),
+                json!({"cmd": "Sync"}),
+            ]
+        };
+
+        let (result, forwarded) = logged_in_batch(batch(), false).await;
+        assert_eq!(
+            forwarded,
+            [json!({"cmd": "Teleport"}), json!({"cmd": "Sync"})]
+        );
+        assert!(result.denials.is_empty());
+        let [too_long, unreadable] = result.invalid_packets.as_slice() else {
+            panic!(
+                "expected two InvalidPackets, got {:?}",
+                result.invalid_packets
+            );
+        };
+        assert_eq!(
+            *too_long,
+            InvalidPacket::arguments("Say", "Your message is too long. Please reconsider.")
+        );
+        assert_eq!(unreadable.type_, "arguments");
+        assert_eq!(unreadable.original_cmd.as_deref(), Some("LocationChecks"));
+
+        // Unknown commands only get one with STRICT_COMMANDS
+        let (result, forwarded) = logged_in_batch(batch(), true).await;
+        assert_eq!(forwarded, [json!({"cmd": "Sync"})]);
+        assert_eq!(
+            result.invalid_packets[2],
+            InvalidPacket::unknown_cmd(Some("Teleport"))
+        );
+        let value = serde_json::to_value(&result.invalid_packets[2]).unwrap();
+        assert_eq!(
+            value,
+            json!({"cmd": "InvalidPacket", "type": "cmd", "original_cmd": "Teleport", "text": "Unknown command"})
+        );
+    }
+
+    async fn say(state: &mut ConnectionState, text: &str) -> (ClientHandlerResult, Vec<Value>) {
+        let muted = PreferenceMap::from([(
+            SlotId(1),
+            preferences::SlotPreferences {
+                muted: true,
+                ..Default::default()
+            },
+        )]);
+        let mut messages = vec![json!({"cmd": "Say", "text": text})];
+        let result = handle_client_messages(
+            state,
+            &mut StorageRequests::default(),
+            &mut messages,
+            &Some((SlotId(1), "Alice".to_string())),
+            &EventBus::new(),
+            &muted,
+            &SlotGroups::default(),
+            &HashSet::new(),
+            &Arc::new(DataPackageCache::from_response(json!({})).unwrap()),
+            false,
+            false,
+            Some(""),
+        )
+        .await
+        .ok()
+        .unwrap();
+        (result, messages)
+    }
+
+    #[tokio::test]
+    async fn test_apx_command() {
+        // Muted players still get an answer
+        let (result, forwarded) = say(&mut ConnectionState::LoggedIn, "!apx").await;
+        assert_eq!(result.apx_commands, vec![ApxCommand::Status]);
+        assert!(result.denials.is_empty());
+        assert!(forwarded.is_empty());
+
+        let (result, forwarded) = say(&mut ConnectionState::LoggedIn, "!apx deathlink off").await;
+        assert_eq!(result.apx_commands, vec![ApxCommand::Deathlink(false)]);
+        assert!(forwarded.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_apx_unknown_subcommand() {
+        let (result, forwarded) = say(&mut ConnectionState::LoggedIn, "!apx frobnicate").await;
+        assert_eq!(
+            result.apx_commands,
+            vec![ApxCommand::Unknown("frobnicate".to_string())]
+        );
+        assert!(forwarded.is_empty());
+
+        // Only answered once logged in
+        let mut state = ConnectionState::WaitingForConnected {
+            password: String::new(),
+            tags: Vec::new(),
+            game: String::new(),
+            name: "Alice".to_string(),
+            slot: None,
+            software: Default::default(),
+            relogin: false,
+        };
+        let (result, _) = say(&mut state, "!apx").await;
+        assert!(result.apx_commands.is_empty());
+    }
+
+    #[test]
+    fn test_debug_redacts_password() {
+        let state = ConnectionState::WaitingForConnected {
+            password: "hunter2".to_string(),
+            tags: vec![],
+            game: "Game".to_string(),
+            name: "Player".to_string(),
+            slot: None,
+            software: Default::default(),
+            relogin: false,
+        };
+        let debug = format!("{:?}", state);
+        assert!(!debug.contains("hunter2"));
+        assert!(debug.contains("Player"));
     }
 }
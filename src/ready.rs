@@ -0,0 +1,204 @@
+use aprs_proto::primitives::SlotId;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use crate::events::{EventBus, RoomEvent};
+
+/// Room setting holding the slots marked ready, so a restart before the start keeps them
+pub const READY_SETTING_KEY: &str = "ready";
+
+/// What START_ANNOUNCEMENT defaults to
+pub const DEFAULT_START_ANNOUNCEMENT: &str = "Everyone is ready, go!";
+
+/// Seconds of the countdown started upstream along with the start, with START_COUNTDOWN
+pub const START_COUNTDOWN_SECONDS: u64 = 10;
+
+#[derive(Debug)]
+pub struct InvalidSlots(String);
+
+impl std::fmt::Display for InvalidSlots {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "expected a comma separated list of slots, got {}",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidSlots {}
+
+/// READY_EXEMPT_SLOTS, slots a start doesn't wait for, like `1,4,7`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExemptSlots(pub BTreeSet<SlotId>);
+
+impl std::str::FromStr for ExemptSlots {
+    type Err = InvalidSlots;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|slot| !slot.is_empty())
+            .map(|slot| slot.parse().map(SlotId))
+            .collect::<Result<_, _>>()
+            .map(ExemptSlots)
+            .map_err(|_| InvalidSlots(s.to_string()))
+    }
+}
+
+/// Slots whose players marked themselves ready with `!apx ready`, for synchronized starts.
+/// Every change is published as `RoomEvent::ReadyChanged`, which stores it.
+#[derive(Default)]
+pub struct ReadyStates {
+    ready: Mutex<BTreeSet<SlotId>>,
+}
+
+impl ReadyStates {
+    pub fn new(ready: BTreeSet<SlotId>) -> Self {
+        Self {
+            ready: Mutex::new(ready),
+        }
+    }
+
+    pub fn slots(&self) -> BTreeSet<SlotId> {
+        self.ready.lock().unwrap().clone()
+    }
+
+    /// Marks `slot` ready or not. Returns whether that changed anything.
+    pub fn set(&self, events: &EventBus, slot: SlotId, ready: bool) -> bool {
+        let mut slots = self.ready.lock().unwrap();
+        let changed = if ready {
+            slots.insert(slot)
+        } else {
+            slots.remove(&slot)
+        };
+        if changed {
+            events.publish(RoomEvent::ReadyChanged {
+                ready: slots.iter().copied().collect(),
+            });
+        }
+        changed
+    }
+
+    /// Marks every slot not ready, after a start or through the API
+    pub fn reset(&self, events: &EventBus) {
+        self.ready.lock().unwrap().clear();
+        events.publish(RoomEvent::ReadyChanged { ready: Vec::new() });
+    }
+}
+
+/// Reads the slots stored under READY_SETTING_KEY
+pub fn from_setting(value: &Value) -> Option<BTreeSet<SlotId>> {
+    serde_json::from_value(value.clone()).ok()
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SlotReadiness {
+    pub slot: SlotId,
+    pub name: String,
+    pub ready: bool,
+    pub connected: bool,
+    /// Listed in READY_EXEMPT_SLOTS, the start doesn't wait for it
+    pub exempt: bool,
+}
+
+impl SlotReadiness {
+    /// Whether the start waits for this slot. A player who readied and then left isn't there
+    /// for the start, so they hold it back until they're back.
+    pub fn holds_back(&self) -> bool {
+        !self.exempt && !(self.ready && self.connected)
+    }
+}
+
+/// Readiness of every slot of the room, by slot
+pub fn matrix(
+    slot_names: &HashMap<SlotId, String>,
+    ready: &BTreeSet<SlotId>,
+    connected: &HashMap<SlotId, usize>,
+    exempt: &ExemptSlots,
+) -> Vec<SlotReadiness> {
+    let mut matrix: Vec<SlotReadiness> = slot_names
+        .iter()
+        .map(|(slot, name)| SlotReadiness {
+            slot: *slot,
+            name: name.clone(),
+            ready: ready.contains(slot),
+            connected: connected.get(slot).is_some_and(|count| *count > 0),
+            exempt: exempt.0.contains(slot),
+        })
+        .collect();
+    matrix.sort_unstable_by_key(|readiness| readiness.slot);
+    matrix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exempt_slots_parse() {
+        assert_eq!("".parse::<ExemptSlots>().unwrap(), ExemptSlots::default());
+        assert_eq!(
+            "4, 1,".parse::<ExemptSlots>().unwrap(),
+            ExemptSlots(BTreeSet::from([SlotId(1), SlotId(4)]))
+        );
+        assert!("1,two".parse::<ExemptSlots>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_published() {
+        let events = EventBus::new();
+        let mut receiver = events.subscribe();
+        let ready = ReadyStates::default();
+
+        assert!(ready.set(&events, SlotId(2), true));
+        assert!(!ready.set(&events, SlotId(2), true));
+        assert!(ready.set(&events, SlotId(1), true));
+        for expected in [vec![SlotId(2)], vec![SlotId(1), SlotId(2)]] {
+            let RoomEvent::ReadyChanged { ready } = receiver.recv().await.unwrap() else {
+                panic!("expected a ready change");
+            };
+            assert_eq!(ready, expected);
+        }
+
+        ready.reset(&events);
+        assert!(ready.slots().is_empty());
+        assert!(!ready.set(&events, SlotId(1), false));
+        let stored = serde_json::to_value([SlotId(3)]).unwrap();
+        assert_eq!(from_setting(&stored), Some(BTreeSet::from([SlotId(3)])));
+    }
+
+    #[test]
+    fn test_only_ready_and_connected_slots_let_the_start_through() {
+        let names = HashMap::from([
+            (SlotId(1), "Alice".to_string()),
+            (SlotId(2), "Bob".to_string()),
+            (SlotId(3), "Carol".to_string()),
+            (SlotId(4), "Dave".to_string()),
+        ]);
+        let ready = BTreeSet::from([SlotId(1), SlotId(2)]);
+        // Bob readied, then left
+        let connected = HashMap::from([(SlotId(1), 1), (SlotId(3), 2)]);
+        let exempt = ExemptSlots(BTreeSet::from([SlotId(4)]));
+
+        let matrix = matrix(&names, &ready, &connected, &exempt);
+        let holding_back: Vec<SlotId> = matrix
+            .iter()
+            .filter(|readiness| readiness.holds_back())
+            .map(|readiness| readiness.slot)
+            .collect();
+        assert_eq!(holding_back, [SlotId(2), SlotId(3)]);
+        assert_eq!(
+            matrix[1],
+            SlotReadiness {
+                slot: SlotId(2),
+                name: "Bob".to_string(),
+                ready: true,
+                connected: false,
+                exempt: false,
+            }
+        );
+    }
+}
@@ -1,36 +1,81 @@
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use aprs_proto::client::Bounce;
 use aprs_proto::primitives::{SlotId, TeamId};
 use aprs_server_core::bounce_matches;
 use aprs_server_core::traits::{GetGame, GetSlotId, GetTeamId, HasTag};
+use chrono::{DateTime, Utc};
 use rand::Rng;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tokio::sync::RwLock;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tungstenite::Bytes;
 
 use crate::config::DeathlinkProbability;
+use crate::deathlink_feedback::{Absorbed, DeathlinkFeedback, Deliveries};
+use crate::fingerprint::{ClientSoftware, Fingerprint};
+use crate::flapping::{FlapLimits, FlapTracker};
+use crate::groups::SlotGroups;
+use crate::latency::{self, CheckLatency, LatencyStats};
+use crate::memory::{Account, MemoryBudget, MemoryUsage};
+use crate::messages::Notice;
+use crate::outbox::ResponseSender;
+use crate::pace::{Pace, PaceReport};
+use crate::preferences::PreferenceMap;
+use crate::progress::{ItemsReceived, LocationProgress, ProgressSummary};
+use crate::proto::{ReceivedItems, Version};
 
 pub type ClientId = u64;
 
 static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(0);
 
 pub enum ClientResponse {
+    /// Synthesized by the proxy: notices, denials, the motd
     Values(Vec<Value>),
+    /// Answer to a client request served by the proxy, like datapackages from the cache
     Raw(Arc<str>),
+    /// Routed from another client
+    Bounced(Arc<str>),
     Pong(Bytes),
 }
 
+/// Requests handled by the connection task itself
+pub enum ClientControl {
+    ReconnectUpstream(oneshot::Sender<Result<(), ReconnectError>>),
+    /// Closes the connection
+    Kick,
+    /// Sends a Say upstream as if the client had, past the proxy's own filtering
+    Say(String),
+}
+
+#[derive(Debug)]
+pub enum ReconnectError {
+    /// The connection isn't logged in, or is already re-establishing its upstream
+    NotReady,
+    /// The new upstream connection couldn't be logged in, the old one is kept
+    Failed(String),
+}
+
 pub struct ClientEntry {
     pub slot: SlotId,
     pub team: TeamId,
     pub game: String,
     pub tags: HashSet<String>,
-    pub sender: mpsc::Sender<ClientResponse>,
+    pub software: ClientSoftware,
+    pub fingerprint: Fingerprint,
+    /// Origin header of the WebSocket upgrade, sent by browsers
+    pub origin: Option<String>,
+    pub sender: ResponseSender,
+    pub control: mpsc::Sender<ClientControl>,
+    /// When upstream accepted the login, deathlinks are held off for a while after
+    pub logged_in_at: Instant,
+    /// When the session limits close the connection, if they do
+    pub session_ends_at: Option<Instant>,
+    pub memory: Arc<MemoryBudget>,
 }
 
 impl GetSlotId for ClientEntry {
@@ -57,14 +102,59 @@ impl HasTag for ClientEntry {
     }
 }
 
+#[derive(Serialize)]
+pub struct ClientSummary {
+    pub client_id: ClientId,
+    pub slot: SlotId,
+    pub team: TeamId,
+    pub game: String,
+    pub tags: Vec<String>,
+    pub uuid: String,
+    pub version: Option<Version>,
+    pub fingerprint: Fingerprint,
+    pub origin: Option<String>,
+    pub flapping: bool,
+    /// Seconds until the connection is closed for its session limits
+    pub session_remaining_secs: Option<u64>,
+    /// How long its latest checks took to have their items sent out
+    pub check_to_item: Option<LatencyStats>,
+    pub memory: MemoryUsage,
+}
+
+/// Whether a connection that logged in at `logged_in_at` is still in its deathlink grace period,
+/// which is over once `grace` went by
+fn in_grace(logged_in_at: Instant, grace: Duration, now: Instant) -> bool {
+    now.saturating_duration_since(logged_in_at) < grace
+}
+
 pub struct ClientRegistry {
     clients: RwLock<HashMap<ClientId, ClientEntry>>,
+    /// Kept per slot rather than per client, trackers and the game client share it. The last
+    /// known progress stays around after the slot disconnects.
+    progress: RwLock<HashMap<SlotId, LocationProgress>>,
+    /// Kept per slot like `progress`
+    items_received: RwLock<HashMap<SlotId, ItemsReceived>>,
+    /// Slots whose client reported their goal. Only seen as it goes through the proxy, a slot
+    /// that goaled before a restart of the proxy is missing until it reports it again.
+    goals: RwLock<HashSet<SlotId>>,
+    /// When a slot last joined `goals`
+    last_goal_at: RwLock<Option<Instant>>,
+    pace: RwLock<Pace>,
+    flaps: RwLock<FlapTracker>,
+    check_latency: RwLock<CheckLatency>,
 }
 
 impl ClientRegistry {
-    pub fn new() -> Self {
+    pub fn new(flap_limits: FlapLimits) -> Self {
         Self {
             clients: RwLock::new(HashMap::new()),
+            progress: RwLock::new(HashMap::new()),
+            items_received: RwLock::new(HashMap::new()),
+            goals: RwLock::new(HashSet::new()),
+            last_goal_at: RwLock::new(None),
+            pace: RwLock::new(Pace::default()),
+            flaps: RwLock::new(FlapTracker::new(flap_limits)),
+            check_latency: RwLock::new(CheckLatency::default()),
         }
     }
 
@@ -77,7 +167,215 @@ impl ClientRegistry {
     }
 
     pub async fn deregister(&self, id: ClientId) {
-        self.clients.write().await.remove(&id);
+        let entry = self.clients.write().await.remove(&id);
+        self.check_latency.write().await.forget(id);
+        if let Some(entry) = entry {
+            self.flaps
+                .write()
+                .await
+                .disconnected(entry.slot, Instant::now());
+        }
+    }
+
+    /// Counts the login if it follows one of the slot's connections dropping, returns whether
+    /// the player should be told their connection is unstable
+    pub async fn record_login(&self, slot: SlotId, room_id: &str) -> bool {
+        let login = self.flaps.write().await.logged_in(slot, Instant::now());
+        if login.reconnect {
+            crate::metrics::record_reconnect(room_id, slot);
+        }
+        login.notify
+    }
+
+    pub async fn flapping_slots(&self) -> HashSet<SlotId> {
+        self.flaps.read().await.flapping(Instant::now())
+    }
+
+    pub async fn controls_for_slot(
+        &self,
+        slot: SlotId,
+    ) -> Vec<(ClientId, mpsc::Sender<ClientControl>)> {
+        let mut controls: Vec<_> = self
+            .clients
+            .read()
+            .await
+            .iter()
+            .filter(|(_, entry)| entry.slot == slot)
+            .map(|(id, entry)| (*id, entry.control.clone()))
+            .collect();
+        controls.sort_unstable_by_key(|(id, _)| *id);
+        controls
+    }
+
+    pub async fn control(&self, id: ClientId) -> Option<mpsc::Sender<ClientControl>> {
+        self.clients
+            .read()
+            .await
+            .get(&id)
+            .map(|entry| entry.control.clone())
+    }
+
+    pub async fn slot_client_counts(&self) -> HashMap<SlotId, usize> {
+        let mut counts = HashMap::new();
+        for entry in self.clients.read().await.values() {
+            *counts.entry(entry.slot).or_default() += 1;
+        }
+        counts
+    }
+
+    pub async fn clients(&self) -> Vec<ClientSummary> {
+        let flapping = self.flapping_slots().await;
+        let check_latency = self.check_latency.read().await;
+        let now = Instant::now();
+        let mut clients: Vec<ClientSummary> = self
+            .clients
+            .read()
+            .await
+            .iter()
+            .map(|(id, entry)| {
+                let mut tags: Vec<String> = entry.tags.iter().cloned().collect();
+                tags.sort_unstable();
+                ClientSummary {
+                    client_id: *id,
+                    slot: entry.slot,
+                    team: entry.team,
+                    game: entry.game.clone(),
+                    tags,
+                    uuid: entry.software.uuid.clone(),
+                    version: entry.software.version.clone(),
+                    fingerprint: entry.fingerprint.clone(),
+                    origin: entry.origin.clone(),
+                    flapping: flapping.contains(&entry.slot),
+                    session_remaining_secs: entry
+                        .session_ends_at
+                        .map(|ends_at| ends_at.saturating_duration_since(now).as_secs()),
+                    check_to_item: check_latency.stats(*id),
+                    memory: entry.memory.usage(),
+                }
+            })
+            .collect();
+        clients.sort_unstable_by_key(|c| c.client_id);
+        clients
+    }
+
+    /// Replaces the slot's progress with what Connected reported
+    pub async fn init_progress(&self, slot: SlotId, progress: LocationProgress, room_id: &str) {
+        crate::metrics::set_slot_checked_locations(room_id, slot, progress.checked());
+        self.progress.write().await.insert(slot, progress);
+    }
+
+    pub async fn record_checks(&self, slot: SlotId, locations: &[i64], room_id: &str) {
+        let mut progress = self.progress.write().await;
+        let Some(progress) = progress.get_mut(&slot) else {
+            return;
+        };
+        let before = progress.checked();
+        if progress.check(locations) {
+            crate::metrics::set_slot_checked_locations(room_id, slot, progress.checked());
+            self.pace
+                .write()
+                .await
+                .record(slot, progress.checked() - before, Utc::now());
+        }
+    }
+
+    /// How fast the room and its slots have been checking locations
+    pub async fn pace(&self, now: DateTime<Utc>) -> PaceReport {
+        let remaining = self
+            .progress
+            .read()
+            .await
+            .values()
+            .map(|progress| progress.total() - progress.checked())
+            .sum();
+        self.pace.write().await.report(remaining, now)
+    }
+
+    /// Starts timing the checks a logged in client sent, until their items go out
+    pub async fn time_checks(&self, id: ClientId, slot: SlotId, locations: &[i64]) {
+        let mut check_latency = self.check_latency.write().await;
+        check_latency.checked(id, slot, locations, Instant::now());
+        self.account_checks(&check_latency).await;
+    }
+
+    /// Stops timing the checks whose items are among what upstream sent
+    pub async fn record_items_sent(&self, commands: &[Value], room_id: &str) {
+        let origins: Vec<_> = commands.iter().flat_map(latency::sent_items).collect();
+        if origins.is_empty() {
+            return;
+        }
+        let mut check_latency = self.check_latency.write().await;
+        let elapsed = check_latency.sent(origins, Instant::now());
+        self.account_checks(&check_latency).await;
+        drop(check_latency);
+        for took in elapsed {
+            crate::metrics::record_check_to_item(room_id, took);
+        }
+    }
+
+    /// Has the memory budget of each client follow its checks waiting for their item
+    async fn account_checks(&self, check_latency: &CheckLatency) {
+        for (id, entry) in self.clients.read().await.iter() {
+            let bytes = check_latency.outstanding(*id) * latency::OUTSTANDING_CHECK_BYTES;
+            entry.memory.set(Account::OutstandingChecks, bytes);
+        }
+    }
+
+    pub async fn record_received(&self, slot: SlotId, received: &ReceivedItems, room_id: &str) {
+        let mut items_received = self.items_received.write().await;
+        let count = items_received.entry(slot).or_default();
+        if count.record(received.index, received.items.len()) {
+            crate::metrics::set_slot_items_received(room_id, slot, count.count());
+        }
+    }
+
+    pub async fn items_received(&self) -> HashMap<SlotId, usize> {
+        self.items_received
+            .read()
+            .await
+            .iter()
+            .map(|(slot, received)| (*slot, received.count()))
+            .collect()
+    }
+
+    pub async fn record_goal(&self, slot: SlotId) {
+        if self.goals.write().await.insert(slot) {
+            *self.last_goal_at.write().await = Some(Instant::now());
+        }
+    }
+
+    pub async fn last_goal_at(&self) -> Option<Instant> {
+        *self.last_goal_at.read().await
+    }
+
+    pub async fn reached_goal(&self, slot: SlotId) -> bool {
+        self.goals.read().await.contains(&slot)
+    }
+
+    pub async fn progress(&self) -> HashMap<SlotId, ProgressSummary> {
+        self.progress
+            .read()
+            .await
+            .iter()
+            .map(|(slot, progress)| (*slot, progress.summary()))
+            .collect()
+    }
+
+    /// Locations the slot has left to check, None before any of its clients logged in
+    pub async fn missing_locations(&self, slot: SlotId) -> Option<Vec<i64>> {
+        self.progress
+            .read()
+            .await
+            .get(&slot)
+            .map(LocationProgress::missing)
+    }
+
+    pub async fn game(&self, id: ClientId) -> Option<String> {
+        self.clients
+            .read()
+            .await
+            .get(&id)
+            .map(|entry| entry.game.clone())
     }
 
     pub async fn update_tags(&self, id: ClientId, tags: HashSet<String>) {
@@ -86,12 +384,26 @@ impl ClientRegistry {
         }
     }
 
+    /// Sends `values` to every logged in client, returns how many there were
+    pub async fn broadcast(&self, values: &[Value]) -> usize {
+        self.clients
+            .read()
+            .await
+            .values()
+            .filter(|client| client.sender.send(ClientResponse::Values(values.to_vec())))
+            .count()
+    }
+
     pub async fn route_bounce(
         &self,
         sender_id: ClientId,
         bounce_value: &Value,
         deathlink_exclusions: &HashSet<SlotId>,
+        preferences: &PreferenceMap,
+        slot_groups: &SlotGroups,
         deathlink_probability: &DeathlinkProbability,
+        deathlink_grace: Duration,
+        deathlink_feedback: DeathlinkFeedback,
         room_id: &str,
     ) {
         let Ok(bounce) = Bounce::deserialize(bounce_value) else {
@@ -101,6 +413,17 @@ impl ClientRegistry {
 
         let is_deathlink = bounce.tags.iter().any(|t| t == "DeathLink");
 
+        // A deathlink addressed to a group is delivered to the whole group or not at all: it's
+        // dropped when any member is excluded and the probability roll is shared
+        let target_groups: Vec<SlotId> = bounce_value
+            .get("slots")
+            .and_then(|slots| Vec::<SlotId>::deserialize(slots).ok())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|slot| slot_groups.is_group(slot))
+            .collect();
+        let mut group_rolls: HashMap<SlotId, bool> = HashMap::new();
+
         // Build bounced message from raw JSON to preserve data exactly
         let mut bounced = bounce_value.clone();
         if let Some(obj) = bounced.as_object_mut() {
@@ -118,29 +441,83 @@ impl ClientRegistry {
             return;
         };
         let sender_team = sender.team;
+        let now = Instant::now();
+        let source = bounce_value["data"]["source"]
+            .as_str()
+            .unwrap_or("another player");
+        let mut deliveries = Deliveries::default();
 
         for (_id, client) in clients.iter() {
             if !bounce_matches(&bounce, sender_team, client) {
                 continue;
             }
 
+            // The sender's own slot isn't one its death reaches
+            let counted = is_deathlink && client.slot != sender.slot;
+            let absorbed = |deliveries: &mut Deliveries, reason: Absorbed| {
+                if counted {
+                    deliveries.absorbed(client.slot);
+                }
+                if deathlink_feedback.tells_receiver() {
+                    client
+                        .sender
+                        .send(ClientResponse::Values(vec![reason.to_print_json(source)]));
+                }
+            };
+
             if is_deathlink {
-                if deathlink_exclusions.contains(&client.slot) {
+                let group = target_groups
+                    .iter()
+                    .find(|group| slot_groups.covers(group, &client.slot));
+                let blocked = match group {
+                    Some(group) => {
+                        slot_groups.deathlink_blocked(group, deathlink_exclusions, preferences)
+                    }
+                    None => slot_groups.deathlink_blocked(
+                        &client.slot,
+                        deathlink_exclusions,
+                        preferences,
+                    ),
+                };
+                if blocked {
+                    absorbed(&mut deliveries, Absorbed::Exclusion);
                     continue;
                 }
                 let probability = deathlink_probability.get();
                 if probability < 1.0 {
-                    let roll: f64 = rand::rng().random();
-                    if roll >= probability {
+                    let passes = match group {
+                        Some(group) => *group_rolls
+                            .entry(*group)
+                            .or_insert_with(|| rand::rng().random::<f64>() < probability),
+                        None => rand::rng().random::<f64>() < probability,
+                    };
+                    if !passes {
+                        absorbed(&mut deliveries, Absorbed::Probability);
                         continue;
                     }
                 }
+                if in_grace(client.logged_in_at, deathlink_grace, now) {
+                    log::info!(
+                        "Slot {} is still loading in, absorbing a deathlink",
+                        client.slot.0
+                    );
+                    crate::metrics::record_suppressed_deathlink(room_id, "grace");
+                    absorbed(&mut deliveries, Absorbed::Grace);
+                    if !deathlink_feedback.tells_receiver() {
+                        client.sender.send(ClientResponse::Values(vec![
+                            Notice::DeathLinkAbsorbed.to_print_json(),
+                        ]));
+                    }
+                    continue;
+                }
             }
 
+            if counted {
+                deliveries.reached(client.slot);
+            }
             if client
                 .sender
-                .try_send(ClientResponse::Raw(Arc::clone(&serialized)))
-                .is_ok()
+                .send(ClientResponse::Bounced(Arc::clone(&serialized)))
             {
                 crate::metrics::record_message(
                     room_id,
@@ -150,5 +527,213 @@ impl ClientRegistry {
                 );
             }
         }
+
+        if deathlink_feedback.tells_sender()
+            && let Some(summary) = deliveries.summary()
+        {
+            sender.sender.send(ClientResponse::Values(vec![summary]));
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::outbox::{ResponseLimits, ResponseReceiver, response_queue};
+    use tungstenite::Message;
+
+    const GRACE: Duration = Duration::from_secs(30);
+
+    pub(crate) fn entry(slot: i64, logged_in_at: Instant) -> (ClientEntry, ResponseReceiver) {
+        let memory = Arc::new(MemoryBudget::new(None));
+        let (sender, receiver) = response_queue(ResponseLimits::default(), "test", memory.clone());
+        let (control, _) = mpsc::channel(1);
+        let entry = ClientEntry {
+            slot: SlotId(slot),
+            team: TeamId(0),
+            game: "Game".to_string(),
+            tags: HashSet::from(["DeathLink".to_string()]),
+            software: ClientSoftware::default(),
+            fingerprint: Fingerprint {
+                game: "Game".to_string(),
+                version: "0.6.0".to_string(),
+                tags: vec!["DeathLink".to_string()],
+            },
+            origin: None,
+            sender,
+            control,
+            logged_in_at,
+            session_ends_at: None,
+            memory,
+        };
+        (entry, receiver)
+    }
+
+    async fn next_cmd(receiver: &mut ResponseReceiver) -> String {
+        let Message::Text(text) = receiver.recv().await else {
+            panic!("expected a text message");
+        };
+        let messages: Vec<Value> = serde_json::from_str(&text).unwrap();
+        messages[0]["cmd"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_grace_period_ends_at_its_boundary() {
+        let logged_in_at = Instant::now();
+        assert!(in_grace(logged_in_at, GRACE, logged_in_at));
+        assert!(in_grace(
+            logged_in_at,
+            GRACE,
+            logged_in_at + GRACE - Duration::from_millis(1)
+        ));
+        assert!(!in_grace(logged_in_at, GRACE, logged_in_at + GRACE));
+        assert!(!in_grace(logged_in_at, Duration::ZERO, logged_in_at));
+    }
+
+    #[tokio::test]
+    async fn test_deathlinks_are_absorbed_only_by_clients_in_grace() {
+        let registry = ClientRegistry::new(FlapLimits::default());
+        let now = Instant::now();
+        let (sender, _sender_receiver) = entry(1, now - GRACE * 2);
+        let (settled, mut settled_receiver) = entry(2, now - GRACE * 2);
+        let (loading, mut loading_receiver) = entry(3, now);
+        registry.register(1, sender).await;
+        registry.register(2, settled).await;
+        registry.register(3, loading).await;
+
+        let bounce = serde_json::json!({
+            "cmd": "Bounce",
+            "tags": ["DeathLink"],
+            "slots": [2, 3],
+            "data": {"source": "Alice", "cause": "fell", "time": 0.0},
+        });
+        registry
+            .route_bounce(
+                1,
+                &bounce,
+                &HashSet::new(),
+                &PreferenceMap::new(),
+                &SlotGroups::default(),
+                &DeathlinkProbability::default(),
+                GRACE,
+                DeathlinkFeedback::Off,
+                "test",
+            )
+            .await;
+
+        assert_eq!(next_cmd(&mut settled_receiver).await, "Bounced");
+        assert_eq!(next_cmd(&mut loading_receiver).await, "PrintJSON");
+    }
+
+    /// Alice on slot 1 dies, slot 2 gets it, slot 3 is excluded and slot 4 is loading in.
+    /// Returns the receivers of the four slots.
+    async fn route_deathlink(feedback: DeathlinkFeedback) -> Vec<ResponseReceiver> {
+        let registry = ClientRegistry::new(FlapLimits::default());
+        let now = Instant::now();
+        let mut receivers = Vec::new();
+        for slot in 1..=4 {
+            let logged_in_at = if slot == 4 { now } else { now - GRACE * 2 };
+            let (client, receiver) = entry(slot, logged_in_at);
+            registry.register(slot as ClientId, client).await;
+            receivers.push(receiver);
+        }
+
+        let bounce = serde_json::json!({
+            "cmd": "Bounce",
+            "tags": ["DeathLink"],
+            "slots": [2, 3, 4],
+            "data": {"source": "Alice", "cause": "fell", "time": 0.0},
+        });
+        registry
+            .route_bounce(
+                1,
+                &bounce,
+                &HashSet::from([SlotId(3)]),
+                &PreferenceMap::new(),
+                &SlotGroups::default(),
+                &DeathlinkProbability::default(),
+                GRACE,
+                feedback,
+                "test",
+            )
+            .await;
+        receivers
+    }
+
+    async fn next_text(receiver: &mut ResponseReceiver) -> String {
+        let Message::Text(text) = receiver.recv().await else {
+            panic!("expected a text message");
+        };
+        let messages: Vec<Value> = serde_json::from_str(&text).unwrap();
+        assert_eq!(messages[0]["cmd"], "PrintJSON");
+        messages[0]["data"][0]["text"].as_str().unwrap().to_string()
+    }
+
+    async fn assert_nothing(receiver: &mut ResponseReceiver) {
+        let received = tokio::time::timeout(Duration::from_millis(50), receiver.recv()).await;
+        assert!(received.is_err(), "unexpected {:?}", received);
+    }
+
+    #[tokio::test]
+    async fn test_deathlink_feedback_off() {
+        let mut receivers = route_deathlink(DeathlinkFeedback::Off).await;
+        assert_nothing(&mut receivers[0]).await;
+        assert_eq!(next_cmd(&mut receivers[1]).await, "Bounced");
+        assert_nothing(&mut receivers[2]).await;
+        assert_eq!(
+            next_text(&mut receivers[3]).await,
+            Notice::DeathLinkAbsorbed.text()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deathlink_feedback_to_receivers() {
+        let mut receivers = route_deathlink(DeathlinkFeedback::Receiver).await;
+        assert_nothing(&mut receivers[0]).await;
+        assert_eq!(next_cmd(&mut receivers[1]).await, "Bounced");
+        assert_eq!(
+            next_text(&mut receivers[2]).await,
+            "A death from Alice was absorbed, because your slot doesn't receive DeathLinks in this room."
+        );
+        assert_eq!(
+            next_text(&mut receivers[3]).await,
+            "A death from Alice was absorbed, because you were still loading in."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deathlink_feedback_to_the_sender() {
+        let mut receivers = route_deathlink(DeathlinkFeedback::Sender).await;
+        assert!(
+            next_text(&mut receivers[0])
+                .await
+                .starts_with("Your death reached 1 of 3 players")
+        );
+        assert_eq!(next_cmd(&mut receivers[1]).await, "Bounced");
+        assert_nothing(&mut receivers[2]).await;
+        assert_eq!(
+            next_text(&mut receivers[3]).await,
+            Notice::DeathLinkAbsorbed.text()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deathlink_feedback_to_both() {
+        let mut receivers = route_deathlink(DeathlinkFeedback::Both).await;
+        assert!(
+            next_text(&mut receivers[0])
+                .await
+                .starts_with("Your death reached 1 of 3 players")
+        );
+        assert!(
+            next_text(&mut receivers[2])
+                .await
+                .starts_with("A death from Alice")
+        );
+        assert!(
+            next_text(&mut receivers[3])
+                .await
+                .starts_with("A death from Alice")
+        );
     }
 }
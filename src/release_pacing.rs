@@ -0,0 +1,300 @@
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+
+/// How often a chunk of paced items is sent
+const CHUNK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// SUPPRESS_AUTO_RELEASE, what a goal releasing its items to everyone is slowed down to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReleasePacing {
+    /// How long after a goal the items sent to clients are paced
+    pub window: Duration,
+    pub items_per_second: NonZeroUsize,
+}
+
+impl Default for ReleasePacing {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(120),
+            items_per_second: NonZeroUsize::new(50).unwrap(),
+        }
+    }
+}
+
+/// Items a command carries to the client, what pacing counts
+fn items(cmd: &Value) -> usize {
+    match cmd.get("cmd").and_then(Value::as_str) {
+        Some("ReceivedItems") => cmd
+            .get("items")
+            .and_then(Value::as_array)
+            .map_or(0, Vec::len),
+        Some("PrintJSON") if cmd.get("type").and_then(Value::as_str) == Some("ItemSend") => 1,
+        _ => 0,
+    }
+}
+
+/// A goal announced to the room, which comes before whatever it releases
+pub fn is_goal_notice(cmd: &Value) -> bool {
+    cmd.get("cmd").and_then(Value::as_str) == Some("PrintJSON")
+        && cmd.get("type").and_then(Value::as_str) == Some("Goal")
+}
+
+/// Splits a ReceivedItems so no part has more than `max` items. Each part starts where the
+/// previous one ended, so clients take them as if they came one after the other.
+fn split_received_items(mut cmd: Value, max: usize) -> Vec<Value> {
+    let index = cmd.get("index").and_then(Value::as_u64);
+    let Some((index, items)) = index.zip(cmd.get_mut("items").and_then(Value::as_array_mut)) else {
+        return vec![cmd];
+    };
+    if items.len() <= max {
+        return vec![cmd];
+    }
+    let items = std::mem::take(items);
+    items
+        .chunks(max)
+        .enumerate()
+        .map(|(chunk, items)| {
+            let mut part = cmd.clone();
+            part["index"] = (index + (chunk * max) as u64).into();
+            part["items"] = items.into();
+            part
+        })
+        .collect()
+}
+
+/// Holds back what upstream sends a client for `window` after a goal of the room, letting
+/// items through at `items_per_second`. Once something is held back everything after it is
+/// too, until the queue is drained, so nothing reaches the client out of order.
+pub struct Pacer {
+    pacing: ReleasePacing,
+    /// Until when arriving commands are held back
+    until: Option<Instant>,
+    queue: VecDeque<Value>,
+    next_chunk_at: Option<Instant>,
+}
+
+impl Pacer {
+    pub fn new(pacing: ReleasePacing) -> Self {
+        Self {
+            pacing,
+            until: None,
+            queue: VecDeque::new(),
+            next_chunk_at: None,
+        }
+    }
+
+    /// Paces what arrives over the window following a goal reached `at`
+    pub fn goal_reached(&mut self, at: Instant, now: Instant) {
+        let until = at + self.pacing.window;
+        if until > now && self.until.is_none_or(|current| current < until) {
+            if !self.is_pacing(now) {
+                log::info!(
+                    "Pacing items for {}s after a goal",
+                    self.pacing.window.as_secs()
+                );
+            }
+            self.until = Some(until);
+        }
+    }
+
+    /// Whether commands from upstream go through the pacer rather than straight to the client
+    pub fn is_pacing(&self, now: Instant) -> bool {
+        self.until.is_some_and(|until| now < until) || !self.queue.is_empty()
+    }
+
+    pub fn push(&mut self, commands: Vec<Value>) {
+        let max = self.pacing.items_per_second.get();
+        for cmd in commands {
+            if items(&cmd) > max {
+                self.queue.extend(split_received_items(cmd, max));
+            } else {
+                self.queue.push_back(cmd);
+            }
+        }
+    }
+
+    /// When the next chunk is due, `None` with nothing queued
+    pub fn next_at(&self, now: Instant) -> Option<Instant> {
+        if self.queue.is_empty() {
+            return None;
+        }
+        Some(self.next_chunk_at.unwrap_or(now))
+    }
+
+    /// The next chunk, up to `items_per_second` items along with the commands between them
+    pub fn pop(&mut self, now: Instant) -> Option<Message> {
+        if self.queue.is_empty() || self.next_chunk_at.is_some_and(|at| now < at) {
+            return None;
+        }
+        let max = self.pacing.items_per_second.get();
+        let mut chunk = Vec::new();
+        let mut sent = 0;
+        while let Some(cmd) = self.queue.front() {
+            let count = items(cmd);
+            if sent > 0 && sent + count > max {
+                break;
+            }
+            sent += count;
+            chunk.extend(self.queue.pop_front());
+        }
+        // What carries no item doesn't hold back the next chunk
+        if sent > 0 {
+            self.next_chunk_at = Some(now + CHUNK_INTERVAL);
+        }
+        let text = serde_json::to_string(&chunk).expect("commands always serialize");
+        Some(Message::Text(text.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn pacing(items_per_second: usize) -> ReleasePacing {
+        ReleasePacing {
+            window: Duration::from_secs(60),
+            items_per_second: NonZeroUsize::new(items_per_second).unwrap(),
+        }
+    }
+
+    fn received_items(index: u64, count: u64) -> Value {
+        let items: Vec<Value> = (index..index + count)
+            .map(|item| json!({"item": item, "location": item, "player": 1, "flags": 0}))
+            .collect();
+        json!({"cmd": "ReceivedItems", "index": index, "items": items})
+    }
+
+    fn item_send(item: u64) -> Value {
+        json!({"cmd": "PrintJSON", "type": "ItemSend", "data": [{"text": item.to_string()}]})
+    }
+
+    fn commands(message: Message) -> Vec<Value> {
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    /// Everything the pacer sends from `start` on, by when it's sent
+    fn drain(pacer: &mut Pacer, start: Instant) -> Vec<(Duration, Vec<Value>)> {
+        let mut sent = Vec::new();
+        while let Some(at) = pacer.next_at(start) {
+            sent.push((at - start, commands(pacer.pop(at).unwrap())));
+        }
+        sent
+    }
+
+    #[test]
+    fn test_received_items_are_split_at_their_index() {
+        let parts = split_received_items(received_items(10, 5), 2);
+        let indexes: Vec<(u64, usize)> = parts
+            .iter()
+            .map(|part| (part["index"].as_u64().unwrap(), items(part)))
+            .collect();
+        assert_eq!(indexes, [(10, 2), (12, 2), (14, 1)]);
+        assert_eq!(parts[2]["items"][0]["item"], 14);
+    }
+
+    #[test]
+    fn test_release_burst_is_paced() {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(pacing(100));
+        assert!(!pacer.is_pacing(start));
+        pacer.goal_reached(start, start);
+        assert!(pacer.is_pacing(start));
+
+        // A release of 1000 items, and as many ItemSend notices each in their own message
+        pacer.push(vec![received_items(0, 1000)]);
+        for item in 0..1000 {
+            pacer.push(vec![item_send(item)]);
+        }
+        let sent = drain(&mut pacer, start);
+
+        assert_eq!(sent.len(), 20);
+        for (chunk, (at, commands)) in sent.iter().enumerate() {
+            assert_eq!(*at, CHUNK_INTERVAL * chunk as u32);
+            assert_eq!(commands.iter().map(items).sum::<usize>(), 100);
+        }
+
+        // Nothing lost nor reordered
+        let received: Vec<u64> = sent
+            .iter()
+            .flat_map(|(_, commands)| commands)
+            .filter(|cmd| cmd["cmd"] == "ReceivedItems")
+            .flat_map(|cmd| cmd["items"].as_array().unwrap().clone())
+            .map(|item| item["item"].as_u64().unwrap())
+            .collect();
+        assert_eq!(received, (0..1000).collect::<Vec<_>>());
+        let notices: Vec<String> = sent
+            .iter()
+            .flat_map(|(_, commands)| commands)
+            .filter(|cmd| cmd["type"] == "ItemSend")
+            .map(|cmd| cmd["data"][0]["text"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            notices,
+            (0..1000).map(|i| i.to_string()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_commands_without_items_ride_along() {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(pacing(2));
+        pacer.goal_reached(start, start);
+        pacer.push(vec![
+            item_send(1),
+            json!({"cmd": "RoomUpdate"}),
+            item_send(2),
+            item_send(3),
+            json!({"cmd": "Bounced"}),
+        ]);
+        let sent = drain(&mut pacer, start);
+        let cmds: Vec<Vec<&str>> = sent
+            .iter()
+            .map(|(_, commands)| {
+                commands
+                    .iter()
+                    .map(|cmd| cmd["cmd"].as_str().unwrap())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            cmds,
+            [
+                vec!["PrintJSON", "RoomUpdate", "PrintJSON"],
+                vec!["PrintJSON", "Bounced"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pacing_ends_once_the_window_passed_and_the_queue_drained() {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(pacing(1));
+        pacer.goal_reached(start, start);
+        pacer.push(vec![item_send(1), item_send(2)]);
+
+        let after_window = start + Duration::from_secs(61);
+        assert!(pacer.is_pacing(after_window));
+        assert!(pacer.pop(after_window).is_some());
+        assert!(pacer.pop(after_window).is_none());
+        assert_eq!(
+            pacer.next_at(after_window),
+            Some(after_window + CHUNK_INTERVAL)
+        );
+        assert!(pacer.pop(after_window + CHUNK_INTERVAL).is_some());
+        assert!(!pacer.is_pacing(after_window + CHUNK_INTERVAL));
+
+        // A goal from before the connection doesn't hold anything back
+        let mut late = Pacer::new(pacing(1));
+        late.goal_reached(start, after_window);
+        assert!(!late.is_pacing(after_window));
+
+        assert!(is_goal_notice(
+            &json!({"cmd": "PrintJSON", "type": "Goal", "data": []})
+        ));
+        assert!(!is_goal_notice(&item_send(1)));
+    }
+}
@@ -0,0 +1,300 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::Mutex;
+
+use crate::bandwidth::Quota;
+use crate::budget::PreLoginLimits;
+use crate::config::{Config, Vars};
+use crate::deathlink_feedback::DeathlinkFeedback;
+use crate::json_limits::{CommandSizeLimits, ParseLimits};
+use crate::outbox::ResponseLimits;
+use crate::permissions::PermissionOverrides;
+use crate::release_pacing::ReleasePacing;
+use crate::send::DroppableCommands;
+use crate::session::SessionLimits;
+use crate::transforms::Transforms;
+
+/// Settings behind `LiveSettings` and the motd, changing anything else needs a restart
+const LIVE_VARS: &[&str] = &[
+    "DENIAL_COOLDOWN_SECONDS",
+    "DEATHLINK_GRACE_SECONDS",
+    "DEATHLINK_FEEDBACK",
+    "ROOMINFO_TIMEOUT",
+    "UPSTREAM_MAX_JSON_DEPTH",
+    "UPSTREAM_MAX_PARSE_BYTES",
+    "UPSTREAM_MAX_JSON_STRING_BYTES",
+    "CLIENT_MAX_JSON_DEPTH",
+    "CLIENT_MAX_JSON_STRING_BYTES",
+    "COMMAND_SIZE_LIMITS",
+    "CLIENT_MAX_MESSAGE_BYTES",
+    "STRICT_COMMANDS",
+    "INVALID_PACKET_LIMIT",
+    "PRELOGIN_MAX_MESSAGES",
+    "PRELOGIN_MAX_BYTES",
+    "RESPONSE_QUEUE_CAPACITY",
+    "SYNTHESIZED_MAX_BATCH",
+    "SYNTHESIZED_MAX_SHARE",
+    "SYNTHESIZED_BURST_BYTES",
+    "PER_CONNECTION_MEMORY_CAP",
+    "DAILY_BYTE_QUOTA_PER_SLOT",
+    "QUOTA_LIMITED_COMMANDS",
+    "CLIENT_DROPPABLE_COMMANDS",
+    "OVERRIDE_PERMISSIONS",
+    "TRANSFORMS_PATH",
+    "ALLOW_CROSS_SLOT_HINT_UPDATES",
+    "SUPPRESS_AUTO_RELEASE",
+    "RELEASE_PACING_WINDOW_SECONDS",
+    "RELEASE_PACING_ITEMS_PER_SECOND",
+    "MAX_SESSION_SECONDS",
+    "CLOSE_AT",
+    "MOTD",
+];
+
+/// What a connection reads from the configuration. It takes a snapshot when it starts, so a
+/// reload only changes what later connections see.
+#[derive(Clone)]
+pub struct LiveSettings {
+    pub denial_cooldown: Duration,
+    pub deathlink_grace: Duration,
+    pub deathlink_feedback: DeathlinkFeedback,
+    pub room_info_timeout: Duration,
+    pub upstream_parse_limits: ParseLimits,
+    pub client_parse_limits: ParseLimits,
+    pub command_size_limits: CommandSizeLimits,
+    pub client_max_message_size: Option<usize>,
+    pub strict_commands: bool,
+    pub invalid_packet_limit: usize,
+    pub prelogin_limits: PreLoginLimits,
+    pub response_limits: ResponseLimits,
+    pub memory_cap: Option<usize>,
+    pub bandwidth_quota: Quota,
+    pub client_droppable_commands: DroppableCommands,
+    pub permission_overrides: Option<PermissionOverrides>,
+    pub transforms: Transforms,
+    pub allow_cross_slot_hint_updates: bool,
+    pub release_pacing: Option<ReleasePacing>,
+    pub session_limits: SessionLimits,
+}
+
+impl From<&Config> for LiveSettings {
+    fn from(config: &Config) -> Self {
+        Self {
+            denial_cooldown: config.denial_cooldown,
+            deathlink_grace: config.deathlink_grace,
+            deathlink_feedback: config.deathlink_feedback,
+            room_info_timeout: config.room_info_timeout,
+            upstream_parse_limits: config.upstream_parse_limits,
+            client_parse_limits: config.client_parse_limits,
+            command_size_limits: config.command_size_limits.clone(),
+            client_max_message_size: config.client_max_message_size,
+            strict_commands: config.strict_commands,
+            invalid_packet_limit: config.invalid_packet_limit,
+            prelogin_limits: config.prelogin_limits,
+            response_limits: config.response_limits,
+            memory_cap: config.per_connection_memory_cap,
+            bandwidth_quota: config.bandwidth_quota.clone(),
+            client_droppable_commands: config.client_droppable_commands.clone(),
+            permission_overrides: config.override_permissions.clone(),
+            transforms: config.transforms.clone(),
+            allow_cross_slot_hint_updates: config.allow_cross_slot_hint_updates,
+            release_pacing: config.release_pacing,
+            session_limits: config.session_limits.clone(),
+        }
+    }
+}
+
+/// The running `LiveSettings`, replaced as a whole on reload
+#[derive(Clone)]
+pub struct Live(Arc<RwLock<Arc<LiveSettings>>>);
+
+impl Live {
+    pub fn new(settings: LiveSettings) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(settings))))
+    }
+
+    pub fn snapshot(&self) -> Arc<LiveSettings> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn replace(&self, settings: LiveSettings) {
+        *self.0.write().unwrap() = Arc::new(settings);
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ReloadReport {
+    /// Settings new connections now see
+    pub applied: Vec<String>,
+    /// Settings that changed but are only read at startup
+    pub restart_required: Vec<String>,
+}
+
+/// Re-reads the settings on SIGHUP or `POST /api/reload_config`
+pub struct Reloader {
+    /// As last applied, restart-only settings keep their startup value so they're reported
+    /// until the restart
+    vars: Mutex<Vars>,
+    live: Live,
+    motd: Arc<tokio::sync::RwLock<Option<String>>>,
+}
+
+impl Reloader {
+    pub fn new(vars: Vars, live: Live, motd: Arc<tokio::sync::RwLock<Option<String>>>) -> Self {
+        Self {
+            vars: Mutex::new(vars),
+            live,
+            motd,
+        }
+    }
+
+    pub async fn reload(&self) -> Result<ReloadReport> {
+        self.apply(Vars::load()?).await
+    }
+
+    /// The whole configuration is checked first, nothing is applied if any of it is invalid
+    pub async fn apply(&self, vars: Vars) -> Result<ReloadReport> {
+        let config = Config::from_vars(&vars)?;
+        let mut current = self.vars.lock().await;
+        let (mut applied, restart_required): (Vec<String>, Vec<String>) = vars
+            .changed(&current)
+            .into_iter()
+            .partition(|name| LIVE_VARS.contains(&name.as_str()));
+        // The rules can change without their path changing
+        if !applied.iter().any(|name| name == "TRANSFORMS_PATH")
+            && config.transforms != self.live.snapshot().transforms
+        {
+            applied.push("TRANSFORMS_PATH".to_string());
+            applied.sort_unstable();
+        }
+
+        if !applied.is_empty() {
+            self.live.replace(LiveSettings::from(&config));
+            if applied.iter().any(|name| name == "MOTD") {
+                *self.motd.write().await = config.motd.clone();
+            }
+            current.update(&vars, &applied);
+        }
+        for name in &applied {
+            log::info!("Reloaded {}", name);
+        }
+        for name in &restart_required {
+            log::warn!("{} changed, it only takes effect after a restart", name);
+        }
+        Ok(ReloadReport {
+            applied,
+            restart_required,
+        })
+    }
+}
+
+/// Reloads on every SIGHUP
+pub async fn on_hangup(reloader: Arc<Reloader>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            log::error!("Failed to listen for SIGHUP: {:?}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        log::info!("Received SIGHUP, reloading the configuration");
+        if let Err(e) = reloader.reload().await {
+            log::error!(
+                "Failed to reload the configuration, keeping the current one: {:?}",
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::{test_config, test_vars};
+
+    fn reloader() -> Reloader {
+        let live = Live::new(LiveSettings::from(&test_config("test")));
+        Reloader::new(test_vars(&[]), live, Default::default())
+    }
+
+    #[tokio::test]
+    async fn test_live_settings_are_applied_and_others_reported() {
+        let reloader = reloader();
+        let report = reloader
+            .apply(test_vars(&[
+                ("ALLOW_CROSS_SLOT_HINT_UPDATES", "true"),
+                ("MOTD", "Welcome back"),
+                ("AP_SERVER", "elsewhere:38281"),
+            ]))
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            ReloadReport {
+                applied: vec!["ALLOW_CROSS_SLOT_HINT_UPDATES".into(), "MOTD".into()],
+                restart_required: vec!["AP_SERVER".into()],
+            }
+        );
+        assert!(reloader.live.snapshot().allow_cross_slot_hint_updates);
+        assert_eq!(reloader.motd.read().await.as_deref(), Some("Welcome back"));
+
+        // Still not in effect
+        let report = reloader
+            .apply(test_vars(&[
+                ("ALLOW_CROSS_SLOT_HINT_UPDATES", "true"),
+                ("MOTD", "Welcome back"),
+                ("AP_SERVER", "elsewhere:38281"),
+            ]))
+            .await
+            .unwrap();
+        assert!(report.applied.is_empty());
+        assert_eq!(report.restart_required, ["AP_SERVER"]);
+    }
+
+    #[tokio::test]
+    async fn test_transforms_are_reloaded_when_their_file_changes() {
+        let path = std::env::temp_dir().join(format!("transforms-{}.json", uuid::Uuid::new_v4()));
+        let rule = |field: &str| {
+            format!(
+                r#"[{{"name": "r", "direction": "from_upstream", "cmd": "PrintJSON", "operations": [{{"op": "remove", "path": "{}"}}]}}]"#,
+                field
+            )
+        };
+        std::fs::write(&path, rule("color")).unwrap();
+        let vars = || test_vars(&[("TRANSFORMS_PATH", path.to_str().unwrap())]);
+        let reloader = reloader();
+        let report = reloader.apply(vars()).await.unwrap();
+        assert_eq!(report.applied, ["TRANSFORMS_PATH"]);
+        assert_eq!(reloader.live.snapshot().transforms.len(), 1);
+
+        // Same path, nothing to reload
+        assert!(reloader.apply(vars()).await.unwrap().applied.is_empty());
+
+        std::fs::write(&path, rule("type")).unwrap();
+        let report = reloader.apply(vars()).await.unwrap();
+        assert_eq!(report.applied, ["TRANSFORMS_PATH"]);
+
+        // Broken rules keep the current ones
+        std::fs::write(&path, r#"[{"name": "r", "operations": [{"op": "eval"}]}]"#).unwrap();
+        assert!(reloader.apply(vars()).await.is_err());
+        let transforms = serde_json::to_value(&reloader.live.snapshot().transforms).unwrap();
+        assert_eq!(transforms[0]["operations"][0]["path"], "type");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_settings_change_nothing() {
+        let reloader = reloader();
+        let error = reloader
+            .apply(test_vars(&[
+                ("ALLOW_CROSS_SLOT_HINT_UPDATES", "true"),
+                ("PRELOGIN_MAX_BYTES", "lots"),
+            ]))
+            .await;
+        assert!(error.is_err());
+        assert!(!reloader.live.snapshot().allow_cross_slot_hint_updates);
+    }
+}
@@ -0,0 +1,300 @@
+use anyhow::Result;
+use aprs_proto::primitives::SlotId;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::db::DieselPool;
+use crate::db::models;
+use crate::diagnostics;
+use crate::preferences::{SlotPreferences, canonical_name};
+
+pub const RESUME_SETTING_KEY: &str = "resumable_sessions";
+/// How often sessions still going are persisted as seen, a restart loses at most this much of
+/// their window
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// Wait before persisting again after a failure
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Prefixes the token so it can't be mistaken for a client's own uuid
+const TOKEN_PREFIX: &str = "apx-resume-";
+
+/// Only hashes are kept, of tokens and of client uuids, so the stored sessions can't be used to
+/// log in
+pub fn hash(secret: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// A session as persisted, what a client resuming it gets back
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ResumableSession {
+    pub slot: SlotId,
+    pub name: String,
+    /// Of the uuid the client logged in with before it used the token
+    pub uuid_hash: String,
+    pub preferences: Option<SlotPreferences>,
+    /// Issue of the token, or the last time the session was seen going
+    pub last_seen: DateTime<Utc>,
+}
+
+/// A session as written to the room settings, under the hash of its token
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredSession {
+    token_hash: String,
+    #[serde(flatten)]
+    session: ResumableSession,
+}
+
+struct Entry {
+    session: ResumableSession,
+    /// Connections currently logged in with the token
+    live: usize,
+}
+
+/// Tokens handed to logged in clients in their `Connected`, as `apx_resume`. A client that
+/// comes back with one as its `uuid`, within `window` of the session being last seen, is let
+/// back in to its slot without its password, even across a restart of the proxy. Each token
+/// only resumes once, the resumed session gets a new one.
+pub struct ResumableSessions {
+    window: chrono::Duration,
+    sessions: Mutex<HashMap<String, Entry>>,
+    /// Woken on every change, for `persist`
+    changed: Notify,
+}
+
+/// Held by a session that got a token, it's seen as going until this is dropped
+pub struct ResumeHold {
+    sessions: Arc<ResumableSessions>,
+    token_hash: String,
+}
+
+impl Drop for ResumeHold {
+    fn drop(&mut self) {
+        self.sessions.release(&self.token_hash, Utc::now());
+    }
+}
+
+impl ResumableSessions {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window: chrono::Duration::from_std(window).unwrap_or(chrono::Duration::MAX),
+            sessions: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Sessions persisted by a previous run, as stored under `RESUME_SETTING_KEY`
+    pub fn restore(&self, setting: serde_json::Value) -> Result<usize> {
+        let restored: Vec<StoredSession> = serde_json::from_value(setting)?;
+        let count = restored.len();
+        let mut sessions = self.sessions.lock().unwrap();
+        for stored in restored {
+            sessions.insert(
+                stored.token_hash,
+                Entry {
+                    session: stored.session,
+                    live: 0,
+                },
+            );
+        }
+        Ok(count)
+    }
+
+    /// A new token for the session logged in to `slot` as `name` with `uuid`
+    pub fn issue(
+        self: &Arc<Self>,
+        slot: SlotId,
+        name: &str,
+        uuid_hash: String,
+        preferences: Option<SlotPreferences>,
+        now: DateTime<Utc>,
+    ) -> (String, ResumeHold) {
+        let token = format!(
+            "{}{}",
+            TOKEN_PREFIX,
+            URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+        );
+        let token_hash = hash(&token);
+        self.sessions.lock().unwrap().insert(
+            token_hash.clone(),
+            Entry {
+                session: ResumableSession {
+                    slot,
+                    name: name.to_string(),
+                    uuid_hash,
+                    preferences,
+                    last_seen: now,
+                },
+                live: 1,
+            },
+        );
+        self.changed.notify_one();
+        let hold = ResumeHold {
+            sessions: self.clone(),
+            token_hash,
+        };
+        (token, hold)
+    }
+
+    /// Takes the session of `token` if it's for `slot` and `name` and still in its window.
+    /// Anything that isn't a token is left alone, it's most likely a client's own uuid.
+    pub fn resume(
+        &self,
+        token: &str,
+        slot: SlotId,
+        name: &str,
+        now: DateTime<Utc>,
+    ) -> Option<ResumableSession> {
+        if !token.starts_with(TOKEN_PREFIX) {
+            return None;
+        }
+        let token_hash = hash(token);
+        let mut sessions = self.sessions.lock().unwrap();
+        let entry = sessions.get(&token_hash)?;
+        if self.is_expired(entry, now) {
+            log::info!("Refusing to resume an expired session of slot {}", slot.0);
+            sessions.remove(&token_hash);
+            self.changed.notify_one();
+            return None;
+        }
+        if entry.session.slot != slot || canonical_name(&entry.session.name) != canonical_name(name)
+        {
+            log::warn!(
+                "Refusing to resume a session of slot {} as {} on slot {}",
+                entry.session.slot.0,
+                name,
+                slot.0
+            );
+            return None;
+        }
+        let entry = sessions.remove(&token_hash)?;
+        self.changed.notify_one();
+        Some(entry.session)
+    }
+
+    /// Sessions that can still be resumed. Those going are seen at `now`.
+    pub fn resumable(&self, now: DateTime<Utc>) -> Vec<StoredSession> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, entry| !self.is_expired(entry, now));
+        let mut resumable: Vec<StoredSession> = sessions
+            .iter_mut()
+            .map(|(token_hash, entry)| {
+                if entry.live > 0 {
+                    entry.session.last_seen = now;
+                }
+                StoredSession {
+                    token_hash: token_hash.clone(),
+                    session: entry.session.clone(),
+                }
+            })
+            .collect();
+        resumable.sort_unstable_by_key(|stored| stored.session.slot);
+        resumable
+    }
+
+    fn release(&self, token_hash: &str, now: DateTime<Utc>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        // Resumed from another connection while this one went on
+        let Some(entry) = sessions.get_mut(token_hash) else {
+            return;
+        };
+        entry.live = entry.live.saturating_sub(1);
+        entry.session.last_seen = entry.session.last_seen.max(now);
+        self.changed.notify_one();
+    }
+
+    fn is_expired(&self, entry: &Entry, now: DateTime<Utc>) -> bool {
+        entry.live == 0 && now.signed_duration_since(entry.session.last_seen) > self.window
+    }
+}
+
+/// Writes the sessions to the room settings whenever they change, and every `REFRESH_INTERVAL`
+/// so the ones going are seen as such after a restart
+pub async fn persist(pool: DieselPool, room_id: String, sessions: Arc<ResumableSessions>) {
+    let mut refresh = tokio::time::interval(REFRESH_INTERVAL);
+    refresh.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = sessions.changed.notified() => {}
+            _ = refresh.tick() => {}
+        }
+        diagnostics::heartbeat("resumable_sessions");
+        let setting = serde_json::to_value(sessions.resumable(Utc::now()))
+            .expect("sessions always serialize");
+        if let Err(e) = models::set_room_setting(&pool, &room_id, RESUME_SETTING_KEY, setting).await
+        {
+            log::error!("Failed to persist resumable sessions, retrying: {:?}", e);
+            tokio::time::sleep(RETRY_DELAY).await;
+            sessions.changed.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn issue(sessions: &Arc<ResumableSessions>, now: DateTime<Utc>) -> (String, ResumeHold) {
+        sessions.issue(SlotId(1), "Alice", hash("alice-uuid"), None, now)
+    }
+
+    #[test]
+    fn test_tokens_resume_their_session_once() {
+        let sessions = Arc::new(ResumableSessions::new(MINUTE));
+        let now = Utc::now();
+        let (token, _hold) = issue(&sessions, now);
+        assert!(token.starts_with(TOKEN_PREFIX));
+
+        // Another slot or player can't use it, nor can a client uuid stand in for it
+        assert!(sessions.resume(&token, SlotId(2), "Alice", now).is_none());
+        assert!(sessions.resume(&token, SlotId(1), "Bob", now).is_none());
+        assert!(
+            sessions
+                .resume("alice-uuid", SlotId(1), "Alice", now)
+                .is_none()
+        );
+
+        let resumed = sessions.resume(&token, SlotId(1), "alice ", now).unwrap();
+        assert_eq!(resumed.uuid_hash, hash("alice-uuid"));
+        assert!(sessions.resume(&token, SlotId(1), "Alice", now).is_none());
+    }
+
+    #[test]
+    fn test_tokens_expire_once_their_session_ended() {
+        let sessions = Arc::new(ResumableSessions::new(MINUTE));
+        let now = Utc::now();
+        let minutes = |minutes| now + chrono::Duration::minutes(minutes);
+        let (token, hold) = issue(&sessions, now);
+        // Going sessions are seen whenever they're persisted, however long they last
+        assert_eq!(sessions.resumable(minutes(10)).len(), 1);
+        drop(hold);
+
+        assert_eq!(sessions.resumable(minutes(11)).len(), 1);
+        assert_eq!(sessions.resumable(minutes(12)), []);
+        assert!(
+            sessions
+                .resume(&token, SlotId(1), "Alice", minutes(12))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_restored_sessions_resume_without_the_token_being_stored() {
+        let sessions = Arc::new(ResumableSessions::new(MINUTE));
+        let now = Utc::now();
+        let (token, _hold) = issue(&sessions, now);
+        let setting = serde_json::to_value(sessions.resumable(now)).unwrap();
+        assert!(!setting.to_string().contains(&token));
+
+        let restored = ResumableSessions::new(MINUTE);
+        assert_eq!(restored.restore(setting).unwrap(), 1);
+        assert!(restored.resume(&token, SlotId(1), "Alice", now).is_some());
+    }
+}
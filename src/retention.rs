@@ -0,0 +1,156 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::db::DieselPool;
+use crate::db::models::{self, PRUNABLE_TABLES};
+use crate::diagnostics;
+
+/// How often tables are pruned of their rows past retention
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug)]
+pub struct InvalidRetention(String);
+
+impl std::fmt::Display for InvalidRetention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid retention: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidRetention {}
+
+/// Days of history kept by table, tables left out are kept forever
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetentionPolicy(BTreeMap<String, u32>);
+
+/// A JSON object like `{"connection_attempts": 30, "traffic_snapshots": 90}`
+impl std::str::FromStr for RetentionPolicy {
+    type Err = InvalidRetention;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let days: BTreeMap<String, u32> =
+            serde_json::from_str(s).map_err(|e| InvalidRetention(e.to_string()))?;
+        for (table, days) in &days {
+            if !PRUNABLE_TABLES.contains(&table.as_str()) {
+                return Err(InvalidRetention(format!(
+                    "unknown table {}, expected one of {}",
+                    table,
+                    PRUNABLE_TABLES.join(", ")
+                )));
+            }
+            // Daily stats are aggregated from the rows of the last day or so
+            if *days == 0 {
+                return Err(InvalidRetention(format!(
+                    "{} must keep at least a day",
+                    table
+                )));
+            }
+        }
+        Ok(Self(days))
+    }
+}
+
+impl RetentionPolicy {
+    pub fn days(&self, table: &str) -> Option<u32> {
+        self.0.get(table).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Rows of `table` created before this are past retention
+    fn cutoff(&self, table: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.days(table)
+            .map(|days| now - chrono::Duration::days(days.into()))
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TableRetention {
+    pub table: &'static str,
+    /// `None` when the table is kept forever
+    pub days: Option<u32>,
+    /// From the planner's statistics, so only as fresh as the last analyze
+    pub estimated_rows: i64,
+}
+
+pub async fn tables(pool: &DieselPool, policy: &RetentionPolicy) -> Result<Vec<TableRetention>> {
+    let estimates = models::estimated_row_counts(pool).await?;
+    Ok(PRUNABLE_TABLES
+        .iter()
+        .map(|table| TableRetention {
+            table,
+            days: policy.days(table),
+            estimated_rows: estimates.get(*table).copied().unwrap_or(0),
+        })
+        .collect())
+}
+
+/// Deletes the rows of `table` past retention, returns how many. `None` if the table is kept
+/// forever.
+pub async fn prune(
+    pool: &DieselPool,
+    policy: &RetentionPolicy,
+    table: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<usize>> {
+    let Some(before) = policy.cutoff(table, now) else {
+        return Ok(None);
+    };
+    let deleted = models::prune_table(pool, table, before).await?;
+    if deleted > 0 {
+        log::info!(
+            "Pruned {} rows of {} created before {}",
+            deleted,
+            table,
+            before
+        );
+    }
+    Ok(Some(deleted))
+}
+
+pub async fn run(pool: DieselPool, policy: RetentionPolicy) {
+    let mut ticker = tokio::time::interval(PRUNE_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        diagnostics::heartbeat("retention");
+        for table in policy.0.keys() {
+            if let Err(e) = prune(&pool, &policy, table, Utc::now()).await {
+                log::error!("Failed to prune {}: {:?}", table, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_retention_policy() {
+        let policy: RetentionPolicy =
+            r#"{"deathlinks": 30, "traffic_snapshots": 90}"#.parse().unwrap();
+        assert_eq!(policy.days("deathlinks"), Some(30));
+        assert_eq!(policy.days("countdowns"), None);
+
+        let now: DateTime<Utc> = "2026-04-10T12:00:00Z".parse().unwrap();
+        assert_eq!(
+            policy.cutoff("deathlinks", now),
+            "2026-03-11T12:00:00Z".parse().ok()
+        );
+
+        for invalid in [
+            r#"{"slot_notes": 30}"#,
+            r#"{"deathlinks": 0}"#,
+            r#"{"deathlinks": -1}"#,
+            "[]",
+        ] {
+            assert!(invalid.parse::<RetentionPolicy>().is_err(), "{}", invalid);
+        }
+    }
+}
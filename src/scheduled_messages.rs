@@ -0,0 +1,388 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+use crate::db::DieselPool;
+use crate::db::models;
+use crate::diagnostics;
+use crate::motd;
+use crate::proto::PrintJSON;
+use crate::registry::ClientRegistry;
+use crate::stats::Schedule;
+
+pub const SCHEDULED_MESSAGES_SETTING_KEY: &str = "scheduled_messages";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Repeat {
+    /// `<minute> <hour> * * *`, in UTC
+    Cron(Schedule),
+    /// Counted from the start of the window, or from when the message was scheduled
+    EverySeconds(u64),
+}
+
+/// A message sent to everyone logged in, again and again
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Announcement {
+    pub repeat: Repeat,
+    pub text: String,
+    #[serde(default)]
+    pub color: Option<String>,
+    /// Nothing is sent before this
+    #[serde(default)]
+    pub starts_at: Option<DateTime<Utc>>,
+    /// Nor after this
+    #[serde(default)]
+    pub ends_at: Option<DateTime<Utc>>,
+}
+
+impl Announcement {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.text.trim().is_empty() {
+            return Err("the text is empty".to_string());
+        }
+        if self.text.chars().count() > motd::MAX_MOTD_LENGTH {
+            return Err(format!(
+                "the text is longer than {} characters",
+                motd::MAX_MOTD_LENGTH
+            ));
+        }
+        if let Some(color) = self.color.as_deref().filter(|color| !motd::is_color(color)) {
+            return Err(format!("unknown color {}", color));
+        }
+        if self.repeat == Repeat::EverySeconds(0) {
+            return Err("it repeats every 0 seconds".to_string());
+        }
+        if let (Some(starts_at), Some(ends_at)) = (self.starts_at, self.ends_at)
+            && ends_at <= starts_at
+        {
+            return Err("it ends before it starts".to_string());
+        }
+        Ok(())
+    }
+
+    /// When it's next due after `now`, `None` once its window is over
+    fn next_after(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let starts_at = self.starts_at.filter(|starts_at| *starts_at > now);
+        let next = match (self.repeat, starts_at) {
+            (Repeat::EverySeconds(_), Some(starts_at)) => starts_at,
+            (Repeat::EverySeconds(seconds), None) => {
+                now + chrono::Duration::seconds(seconds.try_into().unwrap_or(i64::MAX))
+            }
+            // Matching the start itself too
+            (Repeat::Cron(schedule), Some(starts_at)) => {
+                schedule.next_after(starts_at - chrono::Duration::seconds(1))
+            }
+            (Repeat::Cron(schedule), None) => schedule.next_after(now),
+        };
+        self.ends_at
+            .is_none_or(|ends_at| next <= ends_at)
+            .then_some(next)
+    }
+
+    fn to_print_json(&self) -> Value {
+        let message = match &self.color {
+            Some(color) => PrintJSON::with_color(&self.text, color),
+            None => PrintJSON::new(&self.text),
+        };
+        serde_json::to_value(message).unwrap()
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidAnnouncements(String);
+
+impl std::fmt::Display for InvalidAnnouncements {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid scheduled messages: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidAnnouncements {}
+
+/// SCHEDULED_MESSAGES, a JSON array of announcements
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Announcements(pub Vec<Announcement>);
+
+impl std::str::FromStr for Announcements {
+    type Err = InvalidAnnouncements;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let announcements: Vec<Announcement> =
+            serde_json::from_str(s).map_err(|e| InvalidAnnouncements(e.to_string()))?;
+        for (index, announcement) in announcements.iter().enumerate() {
+            announcement
+                .validate()
+                .map_err(|e| InvalidAnnouncements(format!("message {}: {}", index, e)))?;
+        }
+        Ok(Self(announcements))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ScheduledMessage {
+    pub id: u32,
+    #[serde(flatten)]
+    pub announcement: Announcement,
+}
+
+struct Entry {
+    message: ScheduledMessage,
+    next: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: Vec<Entry>,
+    last_id: u32,
+}
+
+impl Entries {
+    fn insert(&mut self, announcement: Announcement, now: DateTime<Utc>) -> ScheduledMessage {
+        self.last_id += 1;
+        let message = ScheduledMessage {
+            id: self.last_id,
+            announcement,
+        };
+        self.entries.push(Entry {
+            next: message.announcement.next_after(now),
+            message: message.clone(),
+        });
+        message
+    }
+}
+
+/// Messages of the primary room sent on a schedule, managed through
+/// `/api/rooms/<room_id>/scheduled_messages`
+pub struct ScheduledMessages {
+    entries: Mutex<Entries>,
+    /// Woken on every change, for `run`
+    changed: Notify,
+}
+
+impl ScheduledMessages {
+    pub fn new(announcements: Vec<Announcement>, now: DateTime<Utc>) -> Self {
+        let mut entries = Entries::default();
+        for announcement in announcements {
+            entries.insert(announcement, now);
+        }
+        Self {
+            entries: Mutex::new(entries),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Messages persisted through the API, as stored under `SCHEDULED_MESSAGES_SETTING_KEY`.
+    /// They replace the ones from SCHEDULED_MESSAGES.
+    pub fn restore(&self, setting: Value, now: DateTime<Utc>) -> Result<usize> {
+        let restored: Vec<ScheduledMessage> = serde_json::from_value(setting)?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.last_id = restored.iter().map(|message| message.id).max().unwrap_or(0);
+        entries.entries = restored
+            .into_iter()
+            .map(|message| Entry {
+                next: message.announcement.next_after(now),
+                message,
+            })
+            .collect();
+        self.changed.notify_one();
+        Ok(entries.entries.len())
+    }
+
+    pub fn list(&self) -> Vec<ScheduledMessage> {
+        self.entries
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .map(|entry| entry.message.clone())
+            .collect()
+    }
+
+    pub fn add(&self, announcement: Announcement, now: DateTime<Utc>) -> ScheduledMessage {
+        let message = self.entries.lock().unwrap().insert(announcement, now);
+        self.changed.notify_one();
+        message
+    }
+
+    /// Replaces the message, `None` if there's none with that id
+    pub fn update(
+        &self,
+        id: u32,
+        announcement: Announcement,
+        now: DateTime<Utc>,
+    ) -> Option<ScheduledMessage> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .entries
+            .iter_mut()
+            .find(|entry| entry.message.id == id)?;
+        entry.next = announcement.next_after(now);
+        entry.message.announcement = announcement;
+        self.changed.notify_one();
+        Some(entry.message.clone())
+    }
+
+    /// Returns whether there was a message with that id
+    pub fn remove(&self, id: u32) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.entries.len();
+        entries.entries.retain(|entry| entry.message.id != id);
+        let removed = entries.entries.len() < before;
+        if removed {
+            self.changed.notify_one();
+        }
+        removed
+    }
+
+    fn next_due(&self) -> Option<DateTime<Utc>> {
+        self.entries
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .filter_map(|entry| entry.next)
+            .min()
+    }
+
+    /// Messages due at `now`, each is scheduled again for its next time
+    fn take_due(&self, now: DateTime<Utc>) -> Vec<ScheduledMessage> {
+        let mut entries = self.entries.lock().unwrap();
+        let mut due = Vec::new();
+        for entry in entries.entries.iter_mut() {
+            if entry.next.is_some_and(|next| next <= now) {
+                due.push(entry.message.clone());
+                entry.next = entry.message.announcement.next_after(now);
+            }
+        }
+        due
+    }
+}
+
+/// Writes the messages to the room settings, after each change through the API
+pub async fn persist(pool: &DieselPool, room_id: &str, messages: &ScheduledMessages) -> Result<()> {
+    let setting = serde_json::to_value(messages.list())?;
+    models::set_room_setting(pool, room_id, SCHEDULED_MESSAGES_SETTING_KEY, setting).await
+}
+
+/// Sends the messages to everyone logged in as they come due. Nothing is sent while nobody is,
+/// the message just waits for its next time.
+pub async fn run(messages: Arc<ScheduledMessages>, client_registry: Arc<ClientRegistry>) {
+    loop {
+        let Some(next) = messages.next_due() else {
+            messages.changed.notified().await;
+            continue;
+        };
+        let wait = (next - Utc::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = messages.changed.notified() => continue,
+        }
+        diagnostics::heartbeat("scheduled_messages");
+        // Waking up a little early mustn't make it sleep again for what's left
+        let now = Utc::now().max(next);
+        for message in messages.take_due(now) {
+            let sent = client_registry
+                .broadcast(&[message.announcement.to_print_json()])
+                .await;
+            if sent == 0 {
+                log::debug!(
+                    "Skipping scheduled message {}, nobody is connected",
+                    message.id
+                );
+            } else {
+                log::info!("Sent scheduled message {} to {} clients", message.id, sent);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn every(seconds: u64) -> Announcement {
+        Announcement {
+            repeat: Repeat::EverySeconds(seconds),
+            text: "Remember to hydrate".to_string(),
+            color: Some("cyan".to_string()),
+            starts_at: None,
+            ends_at: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_announcements() {
+        let announcements: Announcements = r#"[
+            {"repeat": {"every_seconds": 3600}, "text": "Remember to hydrate"},
+            {"repeat": {"cron": "0 19 * * *"}, "text": "Rules", "color": "red",
+             "starts_at": "2026-04-10T19:00:00Z"}
+        ]"#
+        .parse()
+        .unwrap();
+        assert_eq!(announcements.0.len(), 2);
+        assert_eq!(
+            announcements.0[1].repeat,
+            Repeat::Cron("0 19 * * *".parse().unwrap())
+        );
+
+        for invalid in [
+            r#"[{"repeat": {"cron": "0 19 * * 1"}, "text": "Rules"}]"#,
+            r#"[{"repeat": {"every_seconds": 0}, "text": "Rules"}]"#,
+            r#"[{"repeat": {"every_seconds": 60}, "text": " "}]"#,
+            r#"[{"repeat": {"every_seconds": 60}, "text": "Rules", "color": "pink"}]"#,
+            r#"[{"repeat": {"every_seconds": 60}, "text": "Rules",
+                 "starts_at": "2026-04-10T19:00:00Z", "ends_at": "2026-04-10T18:00:00Z"}]"#,
+        ] {
+            assert!(invalid.parse::<Announcements>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_messages_come_due_within_their_window() {
+        let start = at("2026-04-10T18:00:00Z");
+        let messages = ScheduledMessages::new(vec![every(600)], start);
+        assert_eq!(messages.next_due(), Some(at("2026-04-10T18:10:00Z")));
+        assert!(messages.take_due(at("2026-04-10T18:09:59Z")).is_empty());
+        assert_eq!(messages.take_due(at("2026-04-10T18:10:00Z")).len(), 1);
+        assert_eq!(messages.next_due(), Some(at("2026-04-10T18:20:00Z")));
+
+        // One shot at the start of the event, the cron would have it every day otherwise
+        let rules = messages.add(
+            Announcement {
+                repeat: Repeat::Cron("0 19 * * *".parse().unwrap()),
+                starts_at: Some(at("2026-04-10T19:00:00Z")),
+                ends_at: Some(at("2026-04-10T20:00:00Z")),
+                ..every(1)
+            },
+            start,
+        );
+        messages.remove(1);
+        assert_eq!(messages.next_due(), Some(at("2026-04-10T19:00:00Z")));
+        assert_eq!(
+            messages.take_due(at("2026-04-10T19:00:00Z"))[0].id,
+            rules.id
+        );
+        assert_eq!(messages.next_due(), None);
+    }
+
+    #[test]
+    fn test_restored_messages_keep_their_ids() {
+        let messages = ScheduledMessages::new(vec![every(60), every(60)], Utc::now());
+        messages.remove(1);
+        let setting = serde_json::to_value(messages.list()).unwrap();
+
+        let restored = ScheduledMessages::new(vec![every(60)], Utc::now());
+        assert_eq!(restored.restore(setting, Utc::now()).unwrap(), 1);
+        assert_eq!(restored.list()[0].id, 2);
+        assert_eq!(restored.add(every(60), Utc::now()).id, 3);
+        assert!(restored.update(1, every(30), Utc::now()).is_none());
+    }
+}
@@ -0,0 +1,235 @@
+use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::time::{Duration, Instant};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+/// What a failed startup self-test does
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureMode {
+    /// Refuse to start
+    Abort,
+    /// Start anyway, the failure is reported on `/api/selftest`
+    Degrade,
+}
+
+#[derive(Debug)]
+pub struct InvalidFailureMode(String);
+
+impl std::fmt::Display for InvalidFailureMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected abort or degrade, got {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidFailureMode {}
+
+impl std::str::FromStr for FailureMode {
+    type Err = InvalidFailureMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "abort" => Ok(FailureMode::Abort),
+            "degrade" => Ok(FailureMode::Degrade),
+            _ => Err(InvalidFailureMode(s.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SelfTestOptions {
+    /// Applies to each step on its own
+    pub step_timeout: Duration,
+    /// Game whose datapackage is requested, the step is skipped without one
+    pub datapackage_game: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    Connect,
+    RoomInfo,
+    DataPackage,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct StepReport {
+    pub step: Step,
+    pub duration_ms: u128,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SelfTestReport {
+    pub ok: bool,
+    /// Steps that completed, in order
+    pub steps: Vec<StepReport>,
+    pub failed_step: Option<Step>,
+    pub error: Option<String>,
+}
+
+/// Goes through the start of a login against `upstream_url` the way a client would, up to
+/// RoomInfo and optionally a datapackage request, then disconnects.
+pub async fn run(upstream_url: &str, options: &SelfTestOptions) -> SelfTestReport {
+    let mut steps = Vec::new();
+    let result = run_steps(upstream_url, options, &mut steps).await;
+    match result {
+        Ok(()) => SelfTestReport {
+            ok: true,
+            steps,
+            failed_step: None,
+            error: None,
+        },
+        Err((step, error)) => SelfTestReport {
+            ok: false,
+            steps,
+            failed_step: Some(step),
+            error: Some(error),
+        },
+    }
+}
+
+async fn run_steps(
+    upstream_url: &str,
+    options: &SelfTestOptions,
+    steps: &mut Vec<StepReport>,
+) -> Result<(), (Step, String)> {
+    let timed_out = |step| (step, format!("timed out after {:?}", options.step_timeout));
+
+    let started = Instant::now();
+    let (mut ws, _) = tokio::time::timeout(options.step_timeout, connect_async(upstream_url))
+        .await
+        .map_err(|_| timed_out(Step::Connect))?
+        .map_err(|e| (Step::Connect, e.to_string()))?;
+    steps.push(StepReport {
+        step: Step::Connect,
+        duration_ms: started.elapsed().as_millis(),
+    });
+
+    let started = Instant::now();
+    tokio::time::timeout(
+        options.step_timeout,
+        wait_for(&mut ws, |cmd| is_cmd(cmd, "RoomInfo")),
+    )
+    .await
+    .map_err(|_| timed_out(Step::RoomInfo))?
+    .map_err(|e| (Step::RoomInfo, e))?;
+    steps.push(StepReport {
+        step: Step::RoomInfo,
+        duration_ms: started.elapsed().as_millis(),
+    });
+
+    if let Some(game) = &options.datapackage_game {
+        let started = Instant::now();
+        let request = json!([{"cmd": "GetDataPackage", "games": [game]}]);
+        let received = async {
+            ws.send(Message::Text(request.to_string().into()))
+                .await
+                .map_err(|e| e.to_string())?;
+            let datapackage = wait_for(&mut ws, |cmd| is_cmd(cmd, "DataPackage")).await?;
+            if datapackage["data"]["games"].get(game).is_none() {
+                return Err(format!("game {} missing from the DataPackage", game));
+            }
+            Ok(())
+        };
+        tokio::time::timeout(options.step_timeout, received)
+            .await
+            .map_err(|_| timed_out(Step::DataPackage))?
+            .map_err(|e| (Step::DataPackage, e))?;
+        steps.push(StepReport {
+            step: Step::DataPackage,
+            duration_ms: started.elapsed().as_millis(),
+        });
+    }
+
+    // The upstream going away during the close doesn't make the test fail
+    let _ = ws.close(None).await;
+    Ok(())
+}
+
+fn is_cmd(cmd: &Value, name: &str) -> bool {
+    cmd.get("cmd").and_then(Value::as_str) == Some(name)
+}
+
+async fn wait_for<S>(ws: &mut S, predicate: impl Fn(&Value) -> bool) -> Result<Value, String>
+where
+    S: futures_util::Stream<Item = tungstenite::Result<Message>> + Unpin,
+{
+    while let Some(msg) = ws.next().await {
+        let Message::Text(text) = msg.map_err(|e| e.to_string())? else {
+            continue;
+        };
+        let commands: Vec<Value> = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+        if let Some(cmd) = commands.into_iter().find(&predicate) {
+            return Ok(cmd);
+        }
+    }
+    Err("connection closed".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::tests::{mock_room_info, packet, spawn_mock_upstream};
+    use tokio::net::TcpListener;
+
+    fn options(game: Option<&str>) -> SelfTestOptions {
+        SelfTestOptions {
+            step_timeout: Duration::from_millis(500),
+            datapackage_game: game.map(str::to_string),
+        }
+    }
+
+    /// Upstream sending RoomInfo only if `room_info`, and answering datapackage requests
+    async fn spawn_upstream(room_info: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+            if room_info {
+                ws.send(packet(vec![mock_room_info()])).await.unwrap();
+            }
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.to_text().unwrap_or("").contains("GetDataPackage") {
+                    let datapackage =
+                        json!({"cmd": "DataPackage", "data": {"games": {"Test": {}}}});
+                    ws.send(packet(vec![datapackage])).await.unwrap();
+                }
+            }
+        });
+        format!("ws://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_selftest_succeeds() {
+        let report = run(&spawn_mock_upstream().await, &options(None)).await;
+        assert!(report.ok, "{:?}", report);
+        assert_eq!(report.steps.len(), 2);
+
+        let report = run(&spawn_upstream(true).await, &options(Some("Test"))).await;
+        assert!(report.ok, "{:?}", report);
+        assert_eq!(report.steps.last().unwrap().step, Step::DataPackage);
+    }
+
+    #[tokio::test]
+    async fn test_selftest_failed_steps() {
+        // Nothing listening
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = format!("ws://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let report = run(&closed, &options(None)).await;
+        assert_eq!(report.failed_step, Some(Step::Connect));
+
+        let report = run(&spawn_upstream(false).await, &options(None)).await;
+        assert_eq!(report.failed_step, Some(Step::RoomInfo));
+        assert_eq!(report.steps.len(), 1);
+
+        // The mock upstream never answers datapackage requests
+        let report = run(&spawn_mock_upstream().await, &options(Some("Test"))).await;
+        assert_eq!(report.failed_step, Some(Step::DataPackage));
+
+        let report = run(&spawn_upstream(true).await, &options(Some("Other"))).await;
+        assert_eq!(report.failed_step, Some(Step::DataPackage));
+        assert!(!report.ok);
+    }
+}
@@ -0,0 +1,324 @@
+use futures_util::{Sink, SinkExt};
+use std::collections::HashSet;
+use std::future::poll_fn;
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tungstenite::Message;
+
+use crate::error::{ProxyError, ProxyResult, Side};
+use crate::metrics;
+use crate::proxy::{CmdOnly, UpstreamWrite};
+
+/// Tries of a write past the first, a socket congested for longer than that is given up on
+const MAX_RETRIES: u32 = 3;
+/// Doubled on every retry
+const FIRST_BACKOFF: Duration = Duration::from_millis(1);
+/// Commands a client can do without when its socket is congested
+const DEFAULT_DROPPABLE_COMMANDS: &[&str] = &["PrintJSON"];
+
+/// Commands dropped rather than the connection when writing them to the client keeps failing,
+/// from a comma separated list
+#[derive(Clone, Debug, PartialEq)]
+pub struct DroppableCommands(HashSet<String>);
+
+impl Default for DroppableCommands {
+    fn default() -> Self {
+        Self(
+            DEFAULT_DROPPABLE_COMMANDS
+                .iter()
+                .map(|cmd| cmd.to_string())
+                .collect(),
+        )
+    }
+}
+
+impl std::str::FromStr for DroppableCommands {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(
+            s.split(',')
+                .map(str::trim)
+                .filter(|cmd| !cmd.is_empty())
+                .map(str::to_string)
+                .collect(),
+        ))
+    }
+}
+
+impl DroppableCommands {
+    /// Whether `msg` is only made of droppable commands. Only looked into once a write failed.
+    pub fn allows(&self, msg: &Message) -> bool {
+        let Message::Text(text) = msg else {
+            return false;
+        };
+        serde_json::from_str::<Vec<CmdOnly>>(text).is_ok_and(|commands| {
+            !commands.is_empty()
+                && commands
+                    .iter()
+                    .all(|command| self.0.contains(command.cmd.as_ref()))
+        })
+    }
+}
+
+/// Whether a write failed on a socket that's merely congested, and may well work a millisecond
+/// later. Anything else, a reset connection or a broken frame, ends the connection.
+pub fn is_transient(error: &tungstenite::Error) -> bool {
+    matches!(
+        error,
+        tungstenite::Error::Io(e)
+            if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted | ErrorKind::TimedOut)
+    )
+}
+
+/// Retries of a single write, shared by all of its steps
+struct Retries {
+    side: Side,
+    left: u32,
+    backoff: Duration,
+}
+
+impl Retries {
+    fn new(side: Side) -> Self {
+        Self {
+            side,
+            left: MAX_RETRIES,
+            backoff: FIRST_BACKOFF,
+        }
+    }
+
+    /// Waits before the write is tried again, or gives `error` back when retrying can't help
+    async fn wait(&mut self, error: tungstenite::Error) -> Result<(), tungstenite::Error> {
+        if self.left == 0 || !is_transient(&error) {
+            return Err(error);
+        }
+        self.left -= 1;
+        log::debug!(
+            "Retrying write to {} in {:?}: {}",
+            self.side.label(),
+            self.backoff,
+            error
+        );
+        metrics::record_send_retry(self.side.label());
+        tokio::time::sleep(self.backoff).await;
+        self.backoff *= 2;
+        Ok(())
+    }
+}
+
+/// Writes `msg` and flushes it, retrying past transient errors. Until the socket accepts the
+/// frame a copy is kept, a failed send is tried again from the readiness check. A frame the
+/// socket accepted stays buffered whatever happens to the write, so only the flush is retried
+/// from there, sending it again could have it arrive twice.
+pub async fn write<S>(sink: &mut S, msg: Message, side: Side) -> Result<(), tungstenite::Error>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    let mut retries = Retries::new(side);
+    loop {
+        // The payload is reference counted, the copy doesn't copy the frame
+        let accepted = match poll_fn(|cx| sink.poll_ready_unpin(cx)).await {
+            Ok(()) => sink.start_send_unpin(msg.clone()),
+            Err(e) => Err(e),
+        };
+        match accepted {
+            Ok(()) => break,
+            Err(e) => retries.wait(e).await?,
+        }
+    }
+    while let Err(e) = poll_fn(|cx| sink.poll_flush_unpin(cx)).await {
+        retries.wait(e).await?;
+    }
+    Ok(())
+}
+
+/// Writes to upstream, which can never be dropped
+pub async fn to_upstream(upstream_write: &Mutex<UpstreamWrite>, msg: Message) -> ProxyResult<()> {
+    let mut upstream_write = upstream_write.lock().await;
+    write(&mut *upstream_write, msg, Side::Upstream)
+        .await
+        .map_err(ProxyError::from_upstream)
+}
+
+/// Writes to the client. When the socket stays congested, a message of `droppable` commands is
+/// given up on rather than the connection. It may still go out with the next write, it's only
+/// no longer waited for.
+pub async fn to_client<S>(
+    client_write: &mut S,
+    msg: Message,
+    droppable: &DroppableCommands,
+    room_id: &str,
+) -> ProxyResult<()>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    let kept = msg.clone();
+    match write(client_write, msg, Side::Client).await {
+        Ok(()) => Ok(()),
+        Err(e) if is_transient(&e) && droppable.allows(&kept) => {
+            log::warn!(
+                "Dropping a {} bytes message to a congested client: {}",
+                kept.len(),
+                e
+            );
+            metrics::record_dropped_responses(room_id, "congested", 1);
+            Ok(())
+        }
+        Err(e) => Err(ProxyError::from_client(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Fails the first `failures` flushes with `kind`, keeping what it's sent. The first
+    /// `send_failures` sends fail with `kind` too, losing their message.
+    struct FlakySink {
+        kind: ErrorKind,
+        failures: usize,
+        send_failures: usize,
+        queued: Vec<Message>,
+        sent: Vec<Message>,
+    }
+
+    impl FlakySink {
+        fn new(kind: ErrorKind, failures: usize) -> Self {
+            Self {
+                kind,
+                failures,
+                send_failures: 0,
+                queued: Vec::new(),
+                sent: Vec::new(),
+            }
+        }
+
+        fn error(&self) -> tungstenite::Error {
+            tungstenite::Error::Io(self.kind.into())
+        }
+    }
+
+    impl Sink<Message> for FlakySink {
+        type Error = tungstenite::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, msg: Message) -> Result<(), Self::Error> {
+            if self.send_failures > 0 {
+                self.send_failures -= 1;
+                return Err(self.error());
+            }
+            self.queued.push(msg);
+            Ok(())
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Poll::Ready(Err(self.error()));
+            }
+            let queued = std::mem::take(&mut self.queued);
+            self.sent.extend(queued);
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.poll_flush(cx)
+        }
+    }
+
+    fn print_json() -> Message {
+        Message::Text(r#"[{"cmd": "PrintJSON", "data": []}]"#.into())
+    }
+
+    fn received_items() -> Message {
+        Message::Text(r#"[{"cmd": "ReceivedItems", "index": 0, "items": []}]"#.into())
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_are_retried_without_resending() {
+        let mut sink = FlakySink::new(ErrorKind::WouldBlock, MAX_RETRIES as usize);
+        write(&mut sink, received_items(), Side::Upstream)
+            .await
+            .unwrap();
+        assert_eq!(sink.sent, [received_items()]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_sends_are_sent_again() {
+        let mut sink = FlakySink::new(ErrorKind::WouldBlock, 1);
+        sink.send_failures = 2;
+        write(&mut sink, received_items(), Side::Upstream)
+            .await
+            .unwrap();
+        assert_eq!(sink.sent, [received_items()]);
+
+        // A socket that keeps refusing the frame is given up on
+        let mut sink = FlakySink::new(ErrorKind::WouldBlock, 0);
+        sink.send_failures = MAX_RETRIES as usize + 1;
+        assert!(
+            write(&mut sink, received_items(), Side::Upstream)
+                .await
+                .is_err()
+        );
+        assert!(sink.sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fatal_errors_are_not_retried() {
+        let mut sink = FlakySink::new(ErrorKind::ConnectionReset, 1);
+        let error = write(&mut sink, received_items(), Side::Upstream)
+            .await
+            .unwrap_err();
+        assert!(!is_transient(&error));
+        // The next flush would have worked, it was never tried
+        assert_eq!(sink.failures, 0);
+        assert!(sink.sent.is_empty());
+        assert!(matches!(
+            ProxyError::from_upstream(error),
+            ProxyError::UpstreamIo(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_only_droppable_messages_survive_a_congested_client() {
+        let droppable = DroppableCommands::default();
+        let mut sink = FlakySink::new(ErrorKind::TimedOut, usize::MAX);
+        to_client(&mut sink, print_json(), &droppable, "room")
+            .await
+            .unwrap();
+        assert!(matches!(
+            to_client(&mut sink, received_items(), &droppable, "room").await,
+            Err(ProxyError::ClientIo(_))
+        ));
+
+        let nothing: DroppableCommands = "".parse().unwrap();
+        assert!(!nothing.allows(&print_json()));
+        assert!(matches!(
+            to_client(&mut sink, print_json(), &nothing, "room").await,
+            Err(ProxyError::ClientIo(_))
+        ));
+    }
+
+    #[test]
+    fn test_droppable_messages_are_only_made_of_droppable_commands() {
+        let droppable: DroppableCommands = "PrintJSON, Bounced".parse().unwrap();
+        assert!(droppable.allows(&print_json()));
+        assert!(droppable.allows(&Message::Text(
+            r#"[{"cmd": "Bounced"}, {"cmd": "PrintJSON"}]"#.into()
+        )));
+        assert!(!droppable.allows(&Message::Text(
+            r#"[{"cmd": "PrintJSON"}, {"cmd": "ReceivedItems"}]"#.into()
+        )));
+        assert!(!droppable.allows(&Message::Text("[]".into())));
+        assert!(!droppable.allows(&Message::Ping(Vec::new().into())));
+    }
+}
@@ -0,0 +1,212 @@
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+use crate::proto::PrintJSON;
+
+/// How long before a session ends its player is warned
+const DEFAULT_WARNINGS: [Duration; 2] = [Duration::from_secs(5 * 60), Duration::from_secs(60)];
+
+/// When logged in connections are closed: after a while, at a time of day, or whichever comes
+/// first
+#[derive(Clone, Debug)]
+pub struct SessionLimits {
+    pub max_duration: Option<Duration>,
+    /// Past it, connections are closed and new ones are refused
+    pub close_at: Option<DateTime<Utc>>,
+    /// Time left at which the player is warned, longest first
+    pub warnings: Vec<Duration>,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self {
+            max_duration: None,
+            close_at: None,
+            warnings: DEFAULT_WARNINGS.to_vec(),
+        }
+    }
+}
+
+impl SessionLimits {
+    /// Whether the proxy is past its closing time and no longer lets anyone in
+    pub fn closed(&self, now: DateTime<Utc>) -> bool {
+        self.close_at.is_some_and(|close_at| now >= close_at)
+    }
+
+    /// The timer of a session that logged in at `logged_in_at`, `now` being the same moment on
+    /// the wall clock. None when nothing limits it.
+    pub fn timer(&self, logged_in_at: Instant, now: DateTime<Utc>) -> Option<SessionTimer> {
+        let by_duration = self
+            .max_duration
+            .map(|max| (logged_in_at + max, SessionEnd::MaxDuration));
+        let by_closing = self.close_at.map(|close_at| {
+            let left = (close_at - now).to_std().unwrap_or_default();
+            (logged_in_at + left, SessionEnd::ClosingTime)
+        });
+        let (deadline, end) = match (by_duration, by_closing) {
+            (Some(duration), Some(closing)) => {
+                std::cmp::min_by_key(duration, closing, |(at, _)| *at)
+            }
+            (Some(limit), None) | (None, Some(limit)) => limit,
+            (None, None) => return None,
+        };
+
+        // Warnings that would come before the session even started are skipped
+        let mut warnings: Vec<Instant> = self
+            .warnings
+            .iter()
+            .filter_map(|left| deadline.checked_sub(*left))
+            .filter(|at| *at >= logged_in_at)
+            .collect();
+        warnings.sort_unstable();
+        Some(SessionTimer {
+            deadline,
+            end,
+            warnings,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionEnd {
+    MaxDuration,
+    ClosingTime,
+}
+
+impl SessionEnd {
+    /// Sent as the reason of the close frame
+    pub fn reason(self) -> &'static str {
+        match self {
+            SessionEnd::MaxDuration => "Maximum session duration reached",
+            SessionEnd::ClosingTime => "The room is closed",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SessionStep {
+    /// Tell the player how long they have left
+    Warn(Duration),
+    End(SessionEnd),
+}
+
+/// Warnings and end of one connection's session. Kept by the connection for as long as it lives,
+/// so re-establishing the upstream connection doesn't start it over.
+#[derive(Debug)]
+pub struct SessionTimer {
+    deadline: Instant,
+    end: SessionEnd,
+    /// Still to be sent, soonest first
+    warnings: Vec<Instant>,
+}
+
+impl SessionTimer {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// When `step` has something to do next
+    pub fn next(&self) -> Instant {
+        self.warnings.first().copied().unwrap_or(self.deadline)
+    }
+
+    /// What's due at `now`. A warning is only sent once, once they're all out the session ends.
+    pub fn step(&mut self, now: Instant) -> SessionStep {
+        if self.warnings.first().is_some_and(|at| *at <= now) {
+            self.warnings.remove(0);
+            return SessionStep::Warn(self.deadline.saturating_duration_since(now));
+        }
+        SessionStep::End(self.end)
+    }
+
+    /// PrintJSON telling the player the session ends in `left`
+    pub fn warning(&self, left: Duration) -> Value {
+        let text = match self.end {
+            SessionEnd::MaxDuration => format!("Your session ends in {}.", describe(left)),
+            SessionEnd::ClosingTime => format!("The room closes in {}.", describe(left)),
+        };
+        serde_json::to_value(PrintJSON::with_color(&text, "yellow")).unwrap()
+    }
+}
+
+/// Rounded to what's worth telling a player
+fn describe(left: Duration) -> String {
+    let secs = left.as_secs_f64().round() as u64;
+    match secs {
+        0..=1 => "a second".to_string(),
+        2..=59 => format!("{} seconds", secs),
+        60..=89 => "a minute".to_string(),
+        _ => format!("{} minutes", (secs + 30) / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_warns_then_ends() {
+        let limits = SessionLimits {
+            max_duration: Some(10 * MINUTE),
+            ..SessionLimits::default()
+        };
+        let start = Instant::now();
+        let mut timer = limits.timer(start, Utc::now()).unwrap();
+
+        assert_eq!(timer.next(), start + 5 * MINUTE);
+        assert_eq!(
+            timer.step(start + 5 * MINUTE),
+            SessionStep::Warn(5 * MINUTE)
+        );
+        assert_eq!(timer.next(), start + 9 * MINUTE);
+        assert_eq!(timer.step(start + 9 * MINUTE), SessionStep::Warn(MINUTE));
+        assert_eq!(timer.next(), start + 10 * MINUTE);
+        assert_eq!(
+            timer.step(start + 10 * MINUTE),
+            SessionStep::End(SessionEnd::MaxDuration)
+        );
+    }
+
+    #[test]
+    fn test_closing_time_wins_when_sooner() {
+        let now = Utc::now();
+        let limits = SessionLimits {
+            max_duration: Some(60 * MINUTE),
+            close_at: Some(now + chrono::Duration::minutes(3)),
+            ..SessionLimits::default()
+        };
+        let start = Instant::now();
+        let mut timer = limits.timer(start, now).unwrap();
+
+        // Too late for the 5 minute warning
+        assert_eq!(timer.deadline(), start + 3 * MINUTE);
+        assert_eq!(timer.next(), start + 2 * MINUTE);
+        assert_eq!(timer.step(start + 2 * MINUTE), SessionStep::Warn(MINUTE));
+        assert_eq!(
+            timer.step(start + 3 * MINUTE),
+            SessionStep::End(SessionEnd::ClosingTime)
+        );
+        assert!(!limits.closed(now));
+        assert!(limits.closed(now + chrono::Duration::minutes(3)));
+    }
+
+    #[test]
+    fn test_unlimited_sessions_have_no_timer() {
+        assert!(
+            SessionLimits::default()
+                .timer(Instant::now(), Utc::now())
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(describe(5 * MINUTE), "5 minutes");
+        assert_eq!(describe(MINUTE), "a minute");
+        assert_eq!(describe(Duration::from_millis(29_600)), "30 seconds");
+        assert_eq!(describe(Duration::from_millis(200)), "a second");
+    }
+}
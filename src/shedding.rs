@@ -0,0 +1,205 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::diagnostics;
+use crate::metrics;
+
+/// Upstream messages are counted over this long to tell whether the proxy is under pressure
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Set through `PUT /api/degraded_mode`, `auto` leaves it to the message rate
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    Auto,
+    On,
+    Off,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct DegradedStatus {
+    pub degraded: bool,
+    pub mode: Mode,
+    /// Upstream messages of the last second, across all connections
+    pub messages_per_second: u64,
+    /// Rate degraded mode starts at, never on its own without one
+    pub threshold: Option<u64>,
+    /// When the current degraded period started
+    pub since: Option<DateTime<Utc>>,
+}
+
+struct State {
+    mode: Mode,
+    rate: u64,
+    /// What the message rate alone says
+    pressured: bool,
+    /// Since when the rate has been below half the threshold while pressured
+    calm_since: Option<Instant>,
+    since: Option<DateTime<Utc>>,
+}
+
+/// Switches logged in connections to degraded mode under pressure: their upstream messages are
+/// forwarded as they are, only the commands APX rewrites or drops are still parsed. It starts
+/// once upstream messages reach `threshold` a second, and ends after the rate stayed below half
+/// of it for `recovery`.
+pub struct LoadShedding {
+    threshold: Option<u64>,
+    recovery: Duration,
+    messages: AtomicU64,
+    degraded: AtomicBool,
+    state: Mutex<State>,
+}
+
+impl LoadShedding {
+    pub fn new(threshold: Option<u64>, recovery: Duration) -> Self {
+        Self {
+            threshold,
+            recovery,
+            messages: AtomicU64::new(0),
+            degraded: AtomicBool::new(false),
+            state: Mutex::new(State {
+                mode: Mode::Auto,
+                rate: 0,
+                pressured: false,
+                calm_since: None,
+                since: None,
+            }),
+        }
+    }
+
+    /// Counts a message from upstream
+    pub fn count(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    pub fn set_mode(&self, mode: Mode) {
+        let mut state = self.state.lock().unwrap();
+        state.mode = mode;
+        self.apply(&mut state);
+    }
+
+    pub fn status(&self) -> DegradedStatus {
+        let state = self.state.lock().unwrap();
+        DegradedStatus {
+            degraded: self.is_degraded(),
+            mode: state.mode,
+            messages_per_second: state.rate,
+            threshold: self.threshold,
+            since: state.since,
+        }
+    }
+
+    /// Takes the messages counted since the last sample as the rate of the last second
+    fn sample(&self, now: Instant) {
+        let rate = self.messages.swap(0, Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        state.rate = rate;
+        if let Some(threshold) = self.threshold {
+            if rate >= threshold {
+                state.pressured = true;
+                state.calm_since = None;
+            } else if state.pressured && rate < threshold / 2 {
+                let calm_since = *state.calm_since.get_or_insert(now);
+                if now.duration_since(calm_since) >= self.recovery {
+                    state.pressured = false;
+                    state.calm_since = None;
+                }
+            } else {
+                state.calm_since = None;
+            }
+        }
+        self.apply(&mut state);
+    }
+
+    fn apply(&self, state: &mut State) {
+        let degraded = match state.mode {
+            Mode::Auto => state.pressured,
+            Mode::On => true,
+            Mode::Off => false,
+        };
+        if self.degraded.swap(degraded, Ordering::Relaxed) == degraded {
+            return;
+        }
+        if degraded {
+            log::warn!(
+                "Entering degraded mode ({:?}, {} upstream messages/s), bulk upstream messages are forwarded unparsed",
+                state.mode,
+                state.rate
+            );
+            state.since = Some(Utc::now());
+        } else {
+            log::info!("Leaving degraded mode ({:?})", state.mode);
+            state.since = None;
+        }
+        metrics::set_degraded_mode(degraded);
+    }
+}
+
+pub async fn run(shedding: Arc<LoadShedding>) {
+    let mut ticker = tokio::time::interval(SAMPLE_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick is immediate, its sample would cover no time
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        diagnostics::heartbeat("load_shedding");
+        shedding.sample(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn sample(shedding: &LoadShedding, messages: u64, at: Instant) -> bool {
+        shedding.messages.store(messages, Ordering::Relaxed);
+        shedding.sample(at);
+        shedding.is_degraded()
+    }
+
+    #[test]
+    fn test_pressure_starts_and_ends_with_hysteresis() {
+        let shedding = LoadShedding::new(Some(1000), 3 * SECOND);
+        let start = Instant::now();
+        assert!(!sample(&shedding, 999, start));
+        assert!(sample(&shedding, 1000, start + SECOND));
+        assert!(shedding.status().since.is_some());
+
+        // Below the threshold but not by enough
+        assert!(sample(&shedding, 600, start + 2 * SECOND));
+        assert!(sample(&shedding, 400, start + 3 * SECOND));
+        // A spike starts the calm period over
+        assert!(sample(&shedding, 1200, start + 4 * SECOND));
+        assert!(sample(&shedding, 100, start + 5 * SECOND));
+        assert!(sample(&shedding, 100, start + 7 * SECOND));
+        assert!(!sample(&shedding, 100, start + 8 * SECOND));
+        assert_eq!(shedding.status().since, None);
+    }
+
+    #[test]
+    fn test_mode_overrides_the_rate() {
+        let shedding = LoadShedding::new(None, SECOND);
+        let start = Instant::now();
+        assert!(!sample(&shedding, u64::MAX, start));
+
+        shedding.set_mode(Mode::On);
+        assert!(sample(&shedding, 0, start + SECOND));
+        assert_eq!(shedding.status().mode, Mode::On);
+
+        let shedding = LoadShedding::new(Some(10), SECOND);
+        shedding.set_mode(Mode::Off);
+        assert!(!sample(&shedding, 100, start));
+        // Back to the rate, which says it's under pressure
+        shedding.set_mode(Mode::Auto);
+        assert!(shedding.is_degraded());
+    }
+}
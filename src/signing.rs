@@ -0,0 +1,71 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A secret HMAC-SHA256 key. What's signed is given as parts fed to the MAC one after the other,
+/// signatures are unpadded base64url.
+#[derive(Clone)]
+pub struct HmacKey(Arc<[u8]>);
+
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    /// Not base64url, so not a signature at all
+    Malformed,
+    Mismatch,
+}
+
+impl HmacKey {
+    pub fn new(secret: &str) -> Self {
+        Self(secret.as_bytes().into())
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("HMAC accepts keys of any length");
+        for part in parts {
+            mac.update(part);
+        }
+        mac
+    }
+
+    pub fn sign(&self, parts: &[&[u8]]) -> String {
+        URL_SAFE_NO_PAD.encode(self.mac(parts).finalize().into_bytes())
+    }
+
+    /// Compared in constant time
+    pub fn verify(&self, parts: &[&[u8]], signature: &str) -> Result<(), SignatureError> {
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| SignatureError::Malformed)?;
+        self.mac(parts)
+            .verify_slice(&signature)
+            .map_err(|_| SignatureError::Mismatch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signatures() {
+        let key = HmacKey::new("secret");
+        let signature = key.sign(&["hello ".as_bytes(), "world".as_bytes()]);
+        assert_eq!(key.verify(&["hello world".as_bytes()], &signature), Ok(()));
+        assert_eq!(
+            key.verify(&["hello there".as_bytes()], &signature),
+            Err(SignatureError::Mismatch)
+        );
+        assert_eq!(
+            HmacKey::new("other").verify(&["hello world".as_bytes()], &signature),
+            Err(SignatureError::Mismatch)
+        );
+        assert_eq!(
+            key.verify(&["hello world".as_bytes()], "not base64!"),
+            Err(SignatureError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+
+const EXTENSION: &str = "json";
+
+/// Batches that don't fit in memory, one file per batch in an append-only sequence. A file only
+/// appears under its final name once fully written, anything else found in the directory is
+/// the leftover of a crash.
+pub struct SpillDir {
+    dir: PathBuf,
+    next_seq: u64,
+}
+
+impl SpillDir {
+    /// Opens `dir`, creating it if needed. Numbering resumes after the files already there.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create spill directory {}", dir.display()))?;
+        // Corrupted files keep their number too
+        let next_seq = Self::numbered(&dir)?
+            .iter()
+            .map(|(seq, _)| seq + 1)
+            .max()
+            .unwrap_or(0);
+        Ok(Self { dir, next_seq })
+    }
+
+    /// Batches, oldest first
+    fn list(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+        let mut files: Vec<_> = Self::numbered(dir)?
+            .into_iter()
+            .filter(|(_, path)| path.extension().is_some_and(|ext| ext == EXTENSION))
+            .collect();
+        files.sort_unstable_by_key(|(seq, _)| *seq);
+        Ok(files)
+    }
+
+    fn numbered(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(seq) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            else {
+                continue;
+            };
+            files.push((seq, path));
+        }
+        Ok(files)
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(Self::list(&self.dir)?.is_empty())
+    }
+
+    pub fn write<T: Serialize>(&mut self, batch: &[T]) -> Result<()> {
+        let seq = self.next_seq;
+        let path = self.dir.join(format!("{:020}.{}", seq, EXTENSION));
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(batch)?)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, &path)
+            .with_context(|| format!("Failed to rename {}", tmp.display()))?;
+        self.next_seq = seq + 1;
+        Ok(())
+    }
+
+    /// Oldest batch, skipping over files that can't be read back. Those are renamed so they
+    /// stay around for inspection without being replayed again.
+    pub fn oldest<T: DeserializeOwned>(&self) -> Result<Option<(PathBuf, Vec<T>)>> {
+        for (_, path) in Self::list(&self.dir)? {
+            let batch = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| Ok(serde_json::from_slice::<Vec<T>>(&data)?));
+            match batch {
+                Ok(batch) => return Ok(Some((path, batch))),
+                Err(e) => {
+                    log::warn!("Skipping corrupted spill file {}: {:?}", path.display(), e);
+                    std::fs::rename(&path, path.with_extension("corrupt"))?;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn remove(&self, path: &Path) -> Result<()> {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove spill file {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Fresh directory under the system temp dir
+    pub(crate) fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("apx-spill-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_batches_come_back_in_order() {
+        let dir = temp_dir();
+        let mut spill = SpillDir::open(&dir).unwrap();
+        spill.write(&[1, 2]).unwrap();
+        spill.write(&[3]).unwrap();
+
+        // Reopening resumes the numbering
+        let mut spill = SpillDir::open(&dir).unwrap();
+        spill.write(&[4]).unwrap();
+
+        let mut batches = Vec::new();
+        while let Some((path, batch)) = spill.oldest::<i32>().unwrap() {
+            batches.push(batch);
+            spill.remove(&path).unwrap();
+        }
+        assert_eq!(batches, vec![vec![1, 2], vec![3], vec![4]]);
+        assert!(spill.is_empty().unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_files_are_skipped() {
+        let dir = temp_dir();
+        let mut spill = SpillDir::open(&dir).unwrap();
+        spill.write(&[1]).unwrap();
+        spill.write(&[2]).unwrap();
+        std::fs::write(dir.join(format!("{:020}.json", 0)), "[1, 2").unwrap();
+        // Crashed while writing
+        std::fs::write(dir.join(format!("{:020}.tmp", 2)), "[3").unwrap();
+
+        let (path, batch) = spill.oldest::<i32>().unwrap().unwrap();
+        assert_eq!(batch, vec![2]);
+        spill.remove(&path).unwrap();
+        assert!(spill.oldest::<i32>().unwrap().is_none());
+        assert!(dir.join(format!("{:020}.corrupt", 0)).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,379 @@
+use aprs_proto::primitives::SlotId;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::config::DeathlinkProbability;
+use crate::diagnostics;
+use crate::preferences::{PreferenceMap, SlotPreferences};
+
+/// Bumped whenever the snapshot layout changes incompatibly
+pub const SNAPSHOT_FORMAT: u32 = 1;
+
+/// Soft state a standby needs to take over without players noticing. Sessions themselves can't
+/// migrate, and the DataPackage cache isn't included since every instance builds the same one
+/// from upstream at startup.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StateSnapshot {
+    pub format: u32,
+    /// Milliseconds since the epoch on the instance the snapshot was taken on
+    pub version: u64,
+    pub room_id: String,
+    pub deathlink_exclusions: Vec<SlotId>,
+    pub deathlink_probability: f64,
+    pub deferred_datapackage_games: Vec<String>,
+    pub preferences: Vec<SnapshotPreferences>,
+    pub motd: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SnapshotPreferences {
+    pub slot: SlotId,
+    #[serde(flatten)]
+    pub preferences: SlotPreferences,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SnapshotError {
+    UnsupportedFormat(u32),
+    WrongRoom(String),
+    /// The snapshot is older than one that was already applied
+    Stale {
+        version: u64,
+        applied: u64,
+    },
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::UnsupportedFormat(format) => {
+                write!(f, "unsupported snapshot format {}", format)
+            }
+            SnapshotError::WrongRoom(room_id) => write!(f, "snapshot is for room {}", room_id),
+            SnapshotError::Stale { version, applied } => write!(
+                f,
+                "snapshot version {} is older than the applied version {}",
+                version, applied
+            ),
+        }
+    }
+}
+
+/// Handles to the soft state shared with the proxy and the API
+#[derive(Clone)]
+pub struct SoftState {
+    pub deathlink_exclusions: Arc<RwLock<HashSet<SlotId>>>,
+    pub deathlink_probability: Arc<DeathlinkProbability>,
+    pub deferred_datapackage_games: Arc<RwLock<HashSet<String>>>,
+    pub preferences: Arc<RwLock<PreferenceMap>>,
+    pub motd: Arc<RwLock<Option<String>>>,
+}
+
+/// Replication role of this instance. A follower applies snapshots polled from its primary and
+/// refuses player connections until it is promoted.
+pub struct Standby {
+    room_id: String,
+    state: SoftState,
+    following: AtomicBool,
+    /// Version of the last applied snapshot, the lock also serializes applies
+    applied_version: Mutex<Option<u64>>,
+}
+
+impl Standby {
+    pub fn new(room_id: String, state: SoftState, following: bool) -> Self {
+        Self {
+            room_id,
+            state,
+            following: AtomicBool::new(following),
+            applied_version: Mutex::new(None),
+        }
+    }
+
+    pub fn is_following(&self) -> bool {
+        self.following.load(Ordering::Relaxed)
+    }
+
+    /// Stops following the primary. Returns whether this instance was following.
+    pub fn promote(&self) -> bool {
+        self.following.swap(false, Ordering::Relaxed)
+    }
+
+    pub async fn snapshot(&self) -> StateSnapshot {
+        let version = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let mut deathlink_exclusions: Vec<SlotId> = self
+            .state
+            .deathlink_exclusions
+            .read()
+            .await
+            .iter()
+            .copied()
+            .collect();
+        deathlink_exclusions.sort_unstable();
+
+        let mut deferred_datapackage_games: Vec<String> = self
+            .state
+            .deferred_datapackage_games
+            .read()
+            .await
+            .iter()
+            .cloned()
+            .collect();
+        deferred_datapackage_games.sort_unstable();
+
+        let mut preferences: Vec<SnapshotPreferences> = self
+            .state
+            .preferences
+            .read()
+            .await
+            .iter()
+            .map(|(slot, preferences)| SnapshotPreferences {
+                slot: *slot,
+                preferences: preferences.clone(),
+            })
+            .collect();
+        preferences.sort_unstable_by_key(|p| p.slot);
+
+        StateSnapshot {
+            format: SNAPSHOT_FORMAT,
+            version,
+            room_id: self.room_id.clone(),
+            deathlink_exclusions,
+            deathlink_probability: self.state.deathlink_probability.get(),
+            deferred_datapackage_games,
+            preferences,
+            motd: self.state.motd.read().await.clone(),
+        }
+    }
+
+    /// Replaces the soft state with the snapshot's. Snapshots older than the last applied one are
+    /// rejected, re-applying the same version is a no-op. Returns whether anything was applied.
+    ///
+    /// Snapshots are only applied in memory, the primary already persisted them to the database.
+    pub async fn apply(&self, snapshot: StateSnapshot) -> Result<bool, SnapshotError> {
+        if snapshot.format != SNAPSHOT_FORMAT {
+            return Err(SnapshotError::UnsupportedFormat(snapshot.format));
+        }
+        if snapshot.room_id != self.room_id {
+            return Err(SnapshotError::WrongRoom(snapshot.room_id));
+        }
+
+        let mut applied_version = self.applied_version.lock().await;
+        match *applied_version {
+            Some(applied) if snapshot.version < applied => {
+                return Err(SnapshotError::Stale {
+                    version: snapshot.version,
+                    applied,
+                });
+            }
+            Some(applied) if snapshot.version == applied => return Ok(false),
+            _ => {}
+        }
+
+        *self.state.deathlink_exclusions.write().await =
+            snapshot.deathlink_exclusions.into_iter().collect();
+        self.state
+            .deathlink_probability
+            .set(snapshot.deathlink_probability);
+        *self.state.deferred_datapackage_games.write().await =
+            snapshot.deferred_datapackage_games.into_iter().collect();
+        *self.state.preferences.write().await = snapshot
+            .preferences
+            .into_iter()
+            .map(|p| (p.slot, p.preferences))
+            .collect();
+        *self.state.motd.write().await = snapshot.motd;
+
+        *applied_version = Some(snapshot.version);
+        Ok(true)
+    }
+}
+
+/// Polls the primary at `primary_url` and applies its snapshots until this instance is promoted
+pub async fn follow(standby: Arc<Standby>, primary_url: Url, api_key: String, interval: Duration) {
+    let url = match primary_url.join("/api/state_snapshot") {
+        Ok(url) => url,
+        Err(e) => {
+            log::error!("Invalid FOLLOW_URL {}: {:?}", primary_url, e);
+            return;
+        }
+    };
+    log::info!(
+        "Following primary at {}, refusing player connections until promoted",
+        url
+    );
+
+    let client = reqwest::Client::new();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        diagnostics::heartbeat("standby_follow");
+        if !standby.is_following() {
+            log::info!("Promoted, no longer following {}", url);
+            return;
+        }
+
+        let snapshot = async {
+            let response = client
+                .get(url.clone())
+                .header("X-Api-Key", &api_key)
+                .send()
+                .await?
+                .error_for_status()?;
+            response.json::<StateSnapshot>().await
+        }
+        .await;
+
+        match snapshot {
+            Ok(snapshot) => {
+                let version = snapshot.version;
+                match standby.apply(snapshot).await {
+                    Ok(true) => log::debug!("Applied state snapshot {}", version),
+                    Ok(false) => {}
+                    Err(e) => log::warn!("Rejected state snapshot from primary: {}", e),
+                }
+            }
+            Err(e) => log::warn!("Failed to fetch state snapshot from primary: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn soft_state() -> SoftState {
+        SoftState {
+            deathlink_exclusions: Default::default(),
+            deathlink_probability: Default::default(),
+            deferred_datapackage_games: Default::default(),
+            preferences: Arc::new(RwLock::new(HashMap::new())),
+            motd: Default::default(),
+        }
+    }
+
+    async fn primary() -> Standby {
+        let state = soft_state();
+        state
+            .deathlink_exclusions
+            .write()
+            .await
+            .extend([SlotId(3), SlotId(1)]);
+        state.deathlink_probability.set(0.25);
+        state
+            .deferred_datapackage_games
+            .write()
+            .await
+            .insert("A Link to the Past".to_string());
+        state.preferences.write().await.insert(
+            SlotId(2),
+            SlotPreferences {
+                muted: true,
+                ..Default::default()
+            },
+        );
+        *state.motd.write().await = Some("Welcome".to_string());
+        Standby::new("room".to_string(), state, false)
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_round_trip() {
+        let snapshot = primary().await.snapshot().await;
+        assert_eq!(snapshot.deathlink_exclusions, vec![SlotId(1), SlotId(3)]);
+
+        // Goes over the wire as JSON
+        let snapshot: StateSnapshot =
+            serde_json::from_str(&serde_json::to_string(&snapshot).unwrap()).unwrap();
+
+        let state = soft_state();
+        let follower = Standby::new("room".to_string(), state.clone(), true);
+        assert_eq!(follower.apply(snapshot.clone()).await, Ok(true));
+
+        assert_eq!(
+            *state.deathlink_exclusions.read().await,
+            HashSet::from([SlotId(1), SlotId(3)])
+        );
+        assert_eq!(state.deathlink_probability.get(), 0.25);
+        assert!(
+            state
+                .deferred_datapackage_games
+                .read()
+                .await
+                .contains("A Link to the Past")
+        );
+        assert!(state.preferences.read().await[&SlotId(2)].muted);
+        assert_eq!(state.motd.read().await.as_deref(), Some("Welcome"));
+
+        let mut resnapshot = follower.snapshot().await;
+        resnapshot.version = snapshot.version;
+        assert_eq!(resnapshot, snapshot);
+    }
+
+    #[tokio::test]
+    async fn test_older_snapshots_are_rejected() {
+        let mut snapshot = primary().await.snapshot().await;
+        let state = soft_state();
+        let follower = Standby::new("room".to_string(), state.clone(), true);
+
+        snapshot.version = 200;
+        assert_eq!(follower.apply(snapshot.clone()).await, Ok(true));
+
+        // Same version again, e.g. the primary didn't change anything in between polls
+        assert_eq!(follower.apply(snapshot.clone()).await, Ok(false));
+
+        let mut older = snapshot.clone();
+        older.version = 100;
+        older.deathlink_exclusions.clear();
+        assert_eq!(
+            follower.apply(older).await,
+            Err(SnapshotError::Stale {
+                version: 100,
+                applied: 200
+            })
+        );
+        assert_eq!(state.deathlink_exclusions.read().await.len(), 2);
+
+        let mut newer = snapshot;
+        newer.version = 300;
+        newer.deathlink_exclusions.clear();
+        assert_eq!(follower.apply(newer).await, Ok(true));
+        assert!(state.deathlink_exclusions.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_snapshots_are_rejected() {
+        let snapshot = primary().await.snapshot().await;
+        let follower = Standby::new("other".to_string(), soft_state(), true);
+        assert_eq!(
+            follower.apply(snapshot.clone()).await,
+            Err(SnapshotError::WrongRoom("room".to_string()))
+        );
+
+        let follower = Standby::new("room".to_string(), soft_state(), true);
+        let mut future_format = snapshot;
+        future_format.format = SNAPSHOT_FORMAT + 1;
+        assert_eq!(
+            follower.apply(future_format).await,
+            Err(SnapshotError::UnsupportedFormat(SNAPSHOT_FORMAT + 1))
+        );
+    }
+
+    #[test]
+    fn test_promote() {
+        let follower = Standby::new("room".to_string(), soft_state(), true);
+        assert!(follower.is_following());
+        assert!(follower.promote());
+        assert!(!follower.is_following());
+        assert!(!follower.promote());
+    }
+}
@@ -0,0 +1,440 @@
+use anyhow::Result;
+use aprs_proto::primitives::SlotId;
+use chrono::{DateTime, Days, NaiveDate, NaiveTime, Timelike, Utc};
+use diesel::sql_types::{Bool, Text};
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use crate::db::DieselPool;
+use crate::db::models::{self, DailyStats, NewTrafficSnapshot, TrafficSnapshot};
+use crate::diagnostics;
+
+/// Traffic since the last snapshot, per room. Only deathlinks are stored as events, everything
+/// else the daily stats need is counted here and persisted periodically.
+static PENDING: LazyLock<Mutex<HashMap<String, Traffic>>> = LazyLock::new(Default::default);
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Traffic {
+    pub messages_proxied: i64,
+    pub chat_messages: i64,
+    pub sessions: i64,
+    pub slots: BTreeSet<i32>,
+}
+
+impl Traffic {
+    fn merge(&mut self, other: Traffic) {
+        self.messages_proxied += other.messages_proxied;
+        self.chat_messages += other.chat_messages;
+        self.sessions += other.sessions;
+        self.slots.extend(other.slots);
+    }
+}
+
+pub fn record_message(room_id: &str, slot: SlotId, message_type: &str, direction: &str) {
+    let mut pending = PENDING.lock().unwrap();
+    let traffic = pending.entry(room_id.to_string()).or_default();
+    traffic.messages_proxied += 1;
+    if message_type == "Say" && direction == "client_to_upstream" {
+        traffic.chat_messages += 1;
+    }
+    traffic.slots.insert(slot.0 as i32);
+}
+
+pub fn record_session(room_id: &str, slot: SlotId) {
+    let mut pending = PENDING.lock().unwrap();
+    let traffic = pending.entry(room_id.to_string()).or_default();
+    traffic.sessions += 1;
+    traffic.slots.insert(slot.0 as i32);
+}
+
+fn take_pending() -> HashMap<String, Traffic> {
+    std::mem::take(&mut *PENDING.lock().unwrap())
+}
+
+/// Puts back traffic that couldn't be persisted, it goes with the next snapshot
+fn restore_pending(room_id: String, traffic: Traffic) {
+    PENDING
+        .lock()
+        .unwrap()
+        .entry(room_id)
+        .or_default()
+        .merge(traffic);
+}
+
+pub async fn persist_snapshots(pool: DieselPool, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        diagnostics::heartbeat("stats_snapshots");
+        for (room_id, traffic) in take_pending() {
+            let new_snapshot = NewTrafficSnapshot {
+                room_id: room_id.clone(),
+                messages_proxied: traffic.messages_proxied,
+                chat_messages: traffic.chat_messages,
+                sessions: traffic.sessions,
+                slots: traffic.slots.iter().copied().collect(),
+            };
+            if let Err(e) = models::insert_traffic_snapshot(&pool, new_snapshot).await {
+                log::error!(
+                    "Failed to persist traffic snapshot for {}: {:?}",
+                    room_id,
+                    e
+                );
+                restore_pending(room_id, traffic);
+            }
+        }
+    }
+}
+
+/// When the aggregation or a scheduled message runs, as the minute and hour fields of a cron
+/// expression in UTC. The day, month and weekday fields must be `*`: the job always aggregates
+/// whole days so running it more than daily only makes the current day fresher.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    /// `None` for every minute
+    minute: Option<u32>,
+    /// `None` for every hour
+    hour: Option<u32>,
+}
+
+impl Default for Schedule {
+    /// Daily at 04:00 UTC
+    fn default() -> Self {
+        Self {
+            minute: Some(0),
+            hour: Some(4),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidSchedule(String);
+
+impl std::fmt::Display for InvalidSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected `<minute> <hour> * * *`, got {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidSchedule {}
+
+impl std::str::FromStr for Schedule {
+    type Err = InvalidSchedule;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidSchedule(s.to_string());
+        let field = |value: &str, max: u32| match value {
+            "*" => Ok(None),
+            value => match value.parse() {
+                Ok(value) if value <= max => Ok(Some(value)),
+                _ => Err(invalid()),
+            },
+        };
+
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minute, hour, "*", "*", "*"] = fields.as_slice() else {
+            return Err(invalid());
+        };
+        Ok(Self {
+            minute: field(minute, 59)?,
+            hour: field(hour, 23)?,
+        })
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let field = |value: Option<u32>| value.map_or("*".to_string(), |value| value.to_string());
+        write!(f, "{} {} * * *", field(self.minute), field(self.hour))
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = InvalidSchedule;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> Self {
+        schedule.to_string()
+    }
+}
+
+impl Schedule {
+    /// First time matching the schedule strictly after `now`
+    pub fn next_after(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let mut next =
+            now.with_second(0).unwrap().with_nanosecond(0).unwrap() + chrono::Duration::minutes(1);
+        // At most a day of minutes to go through
+        while !(self.minute.is_none_or(|minute| next.minute() == minute)
+            && self.hour.is_none_or(|hour| next.hour() == hour))
+        {
+            next += chrono::Duration::minutes(1);
+        }
+        next
+    }
+}
+
+/// Days to aggregate, oldest first. The last aggregated day is done again since it may have been
+/// aggregated before it was over, and so is today.
+pub fn days_to_aggregate(
+    last_aggregated: Option<NaiveDate>,
+    first_activity: Option<NaiveDate>,
+    today: NaiveDate,
+) -> Vec<NaiveDate> {
+    let Some(first) = last_aggregated.or(first_activity) else {
+        return Vec::new();
+    };
+    first.iter_days().take_while(|day| *day <= today).collect()
+}
+
+pub fn daily_stats(
+    room_id: &str,
+    day: NaiveDate,
+    deathlinks: i64,
+    snapshots: &[TrafficSnapshot],
+) -> DailyStats {
+    let slots: BTreeSet<i32> = snapshots
+        .iter()
+        .flat_map(|snapshot| snapshot.slots.iter().copied())
+        .collect();
+    DailyStats {
+        room_id: room_id.to_string(),
+        day,
+        deathlinks,
+        chat_messages: snapshots.iter().map(|s| s.chat_messages).sum(),
+        sessions: snapshots.iter().map(|s| s.sessions).sum(),
+        unique_slots: slots.len() as i64,
+        messages_proxied: snapshots.iter().map(|s| s.messages_proxied).sum(),
+    }
+}
+
+#[derive(diesel::QueryableByName)]
+struct AdvisoryLock {
+    #[diesel(sql_type = Bool)]
+    acquired: bool,
+}
+
+/// Aggregates the room's days up to `today`. Returns `None` when another instance sharing the
+/// database holds the room's lock, it's doing the same work.
+pub async fn aggregate(
+    pool: &DieselPool,
+    room_id: &str,
+    today: NaiveDate,
+) -> Result<Option<usize>> {
+    // Session locks belong to the connection, it's kept until the lock is released
+    let mut lock_conn = pool.get().await?;
+    let lock: AdvisoryLock = diesel::sql_query(
+        "SELECT pg_try_advisory_lock(hashtext('apx_daily_stats'), hashtext($1)) AS acquired",
+    )
+    .bind::<Text, _>(room_id)
+    .get_result(&mut lock_conn)
+    .await?;
+    if !lock.acquired {
+        return Ok(None);
+    }
+
+    let result = aggregate_days(pool, room_id, today).await;
+
+    diesel::sql_query("SELECT pg_advisory_unlock(hashtext('apx_daily_stats'), hashtext($1))")
+        .bind::<Text, _>(room_id)
+        .execute(&mut lock_conn)
+        .await?;
+
+    result.map(Some)
+}
+
+async fn aggregate_days(pool: &DieselPool, room_id: &str, today: NaiveDate) -> Result<usize> {
+    let last_aggregated = models::get_last_daily_stats_day(pool, room_id).await?;
+    let first_activity = match last_aggregated {
+        Some(_) => None,
+        None => models::get_room_first_activity(pool, room_id)
+            .await?
+            .map(|at| at.date_naive()),
+    };
+
+    let days = days_to_aggregate(last_aggregated, first_activity, today);
+    for day in &days {
+        let from = day.and_time(NaiveTime::MIN).and_utc();
+        let to = (*day + Days::new(1)).and_time(NaiveTime::MIN).and_utc();
+        let deathlinks = models::count_room_deathlinks(pool, room_id, from, to).await?;
+        let snapshots = models::get_room_traffic_snapshots(pool, room_id, from, to).await?;
+        models::upsert_daily_stats(pool, &daily_stats(room_id, *day, deathlinks, &snapshots))
+            .await?;
+    }
+    Ok(days.len())
+}
+
+pub async fn run_aggregation(pool: DieselPool, room_ids: Vec<String>, schedule: Schedule) {
+    loop {
+        let now = Utc::now();
+        let next = schedule.next_after(now);
+        tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+        diagnostics::heartbeat("stats_aggregation");
+
+        let today = Utc::now().date_naive();
+        for room_id in &room_ids {
+            match aggregate(&pool, room_id, today).await {
+                Ok(Some(days)) => log::info!("Aggregated {} days of stats for {}", days, room_id),
+                Ok(None) => log::info!(
+                    "Stats for {} are being aggregated by another instance, skipping",
+                    room_id
+                ),
+                Err(e) => log::error!("Failed to aggregate stats for {}: {:?}", room_id, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn snapshot(messages: i64, chat: i64, sessions: i64, slots: &[i32]) -> TrafficSnapshot {
+        TrafficSnapshot {
+            id: 0,
+            room_id: "room".to_string(),
+            messages_proxied: messages,
+            chat_messages: chat,
+            sessions,
+            slots: slots.to_vec(),
+            created_at: at("2026-04-04T00:00:00Z"),
+        }
+    }
+
+    #[test]
+    fn test_schedule() {
+        let schedule = Schedule::default();
+        assert_eq!("0 4 * * *".parse::<Schedule>().unwrap(), schedule);
+        assert_eq!(
+            schedule.next_after(at("2026-04-04T03:59:30Z")),
+            at("2026-04-04T04:00:00Z")
+        );
+        assert_eq!(
+            schedule.next_after(at("2026-04-04T04:00:00Z")),
+            at("2026-04-05T04:00:00Z")
+        );
+
+        let hourly: Schedule = "30 * * * *".parse().unwrap();
+        assert_eq!(hourly.to_string(), "30 * * * *");
+        assert_eq!(
+            hourly.next_after(at("2026-04-04T23:45:00Z")),
+            at("2026-04-05T00:30:00Z")
+        );
+
+        assert!("0 4 * * 1".parse::<Schedule>().is_err());
+        assert!("60 4 * * *".parse::<Schedule>().is_err());
+        assert!("daily".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_days_to_aggregate() {
+        let today = date("2026-04-04");
+        assert!(days_to_aggregate(None, None, today).is_empty());
+        assert_eq!(
+            days_to_aggregate(None, Some(date("2026-04-02")), today),
+            vec![date("2026-04-02"), date("2026-04-03"), today]
+        );
+        // A rerun on the same day aggregates today again, nothing else
+        assert_eq!(days_to_aggregate(Some(today), None, today), vec![today]);
+    }
+
+    #[test]
+    fn test_daily_stats() {
+        let snapshots = [
+            snapshot(10, 2, 1, &[1, 2]),
+            snapshot(5, 0, 1, &[2, 3]),
+            snapshot(0, 0, 0, &[]),
+        ];
+        let stats = daily_stats("room", date("2026-04-04"), 3, &snapshots);
+        assert_eq!(
+            stats,
+            DailyStats {
+                room_id: "room".to_string(),
+                day: date("2026-04-04"),
+                deathlinks: 3,
+                chat_messages: 2,
+                sessions: 2,
+                unique_slots: 3,
+                messages_proxied: 15,
+            }
+        );
+    }
+
+    #[test]
+    fn test_aggregating_again_is_idempotent() {
+        // Upserts keyed like daily_stats
+        let mut table: HashMap<(String, NaiveDate), DailyStats> = HashMap::new();
+        let mut run = |today: NaiveDate, snapshots: &[TrafficSnapshot]| {
+            let last = table.keys().map(|(_, day)| *day).max();
+            for day in days_to_aggregate(last, Some(date("2026-04-03")), today) {
+                let stats = daily_stats("room", day, 1, snapshots);
+                table.insert((stats.room_id.clone(), day), stats);
+            }
+            let mut rows: Vec<DailyStats> = table.values().cloned().collect();
+            rows.sort_by_key(|row| row.day);
+            rows
+        };
+
+        let snapshots = [snapshot(10, 2, 1, &[1])];
+        let first = run(date("2026-04-04"), &snapshots);
+        assert_eq!(first.len(), 2);
+        assert_eq!(run(date("2026-04-04"), &snapshots), first);
+    }
+
+    #[test]
+    fn test_pending_traffic() {
+        record_message(
+            "test_pending_traffic",
+            SlotId(1),
+            "Say",
+            "client_to_upstream",
+        );
+        record_message(
+            "test_pending_traffic",
+            SlotId(1),
+            "PrintJSON",
+            "upstream_to_client",
+        );
+        record_session("test_pending_traffic", SlotId(2));
+
+        let traffic = take_pending().remove("test_pending_traffic").unwrap();
+        assert_eq!(
+            traffic,
+            Traffic {
+                messages_proxied: 2,
+                chat_messages: 1,
+                sessions: 1,
+                slots: BTreeSet::from([1, 2]),
+            }
+        );
+
+        // Traffic that failed to persist isn't lost
+        record_message(
+            "test_pending_traffic",
+            SlotId(3),
+            "Say",
+            "client_to_upstream",
+        );
+        restore_pending("test_pending_traffic".to_string(), traffic);
+        let traffic = take_pending().remove("test_pending_traffic").unwrap();
+        assert_eq!(traffic.messages_proxied, 3);
+        assert_eq!(traffic.slots, BTreeSet::from([1, 2, 3]));
+    }
+}
@@ -0,0 +1,272 @@
+use futures_util::future::BoxFuture;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio::task::{Id, JoinError, JoinSet};
+
+use crate::diagnostics;
+use crate::metrics;
+
+/// How often connection tasks are checked for ones hanging around without a login
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Set by a connection once it logged in
+#[derive(Clone, Default)]
+pub struct LoginMark(Arc<AtomicBool>);
+
+impl LoginMark {
+    pub fn set(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn is_set(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The cap on connections is reached, or the proxy is shutting down
+#[derive(Debug, PartialEq)]
+pub struct Refused;
+
+struct Job {
+    addr: SocketAddr,
+    login: LoginMark,
+    task: BoxFuture<'static, ()>,
+}
+
+struct Tracked {
+    addr: SocketAddr,
+    started: Instant,
+    login: LoginMark,
+    /// Already logged as stale
+    reported: bool,
+}
+
+/// Hands accepted connections to `ConnectionTasks`, refusing those past the cap
+#[derive(Clone)]
+pub struct Supervisor {
+    max: Option<usize>,
+    /// Submitted and not reaped yet
+    live: Arc<AtomicUsize>,
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+/// Owns the task of every connection, so they're all accounted for: finished ones are reaped,
+/// ones still without a login after a while are reported, and shutting down aborts the rest.
+pub struct ConnectionTasks {
+    live: Arc<AtomicUsize>,
+    jobs: mpsc::UnboundedReceiver<Job>,
+    set: JoinSet<()>,
+    tracked: HashMap<Id, Tracked>,
+}
+
+impl Supervisor {
+    pub fn new(max: Option<usize>) -> (Self, ConnectionTasks) {
+        let live = Arc::new(AtomicUsize::new(0));
+        let (jobs_tx, jobs_rx) = mpsc::unbounded_channel();
+        let supervisor = Self {
+            max,
+            live: live.clone(),
+            jobs: jobs_tx,
+        };
+        let tasks = ConnectionTasks {
+            live,
+            jobs: jobs_rx,
+            set: JoinSet::new(),
+            tracked: HashMap::new(),
+        };
+        (supervisor, tasks)
+    }
+
+    /// Runs the connection from `addr` made by `make`, which is given the mark to set once it
+    /// logged in
+    pub fn submit<F, Fut>(&self, addr: SocketAddr, make: F) -> Result<(), Refused>
+    where
+        F: FnOnce(LoginMark) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.live
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |live| {
+                self.max.is_none_or(|max| live < max).then_some(live + 1)
+            })
+            .map_err(|_| Refused)?;
+        let login = LoginMark::default();
+        let job = Job {
+            addr,
+            login: login.clone(),
+            task: Box::pin(make(login)),
+        };
+        if self.jobs.send(job).is_err() {
+            self.live.fetch_sub(1, Ordering::Relaxed);
+            return Err(Refused);
+        }
+        Ok(())
+    }
+}
+
+impl ConnectionTasks {
+    /// Runs connections until `shutdown`, connections without a login are reported once they're
+    /// `stale_after` old
+    pub async fn run(mut self, stale_after: Duration, mut shutdown: watch::Receiver<bool>) {
+        let mut sweep = tokio::time::interval(SWEEP_INTERVAL);
+        sweep.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                Some(job) = self.jobs.recv() => self.spawn(job),
+                Some(joined) = self.set.join_next_with_id() => self.reap(joined),
+                _ = sweep.tick() => {
+                    diagnostics::heartbeat("connections");
+                    self.sweep(stale_after, Instant::now());
+                }
+                _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+            }
+        }
+        self.drain().await;
+    }
+
+    fn spawn(&mut self, job: Job) {
+        let handle = self.set.spawn(job.task);
+        self.tracked.insert(
+            handle.id(),
+            Tracked {
+                addr: job.addr,
+                started: Instant::now(),
+                login: job.login,
+                reported: false,
+            },
+        );
+        metrics::set_connection_tasks(self.set.len());
+    }
+
+    fn reap(&mut self, joined: Result<(Id, ()), JoinError>) {
+        let id = match joined {
+            Ok((id, ())) => id,
+            Err(e) => {
+                if e.is_panic() {
+                    log::error!("Connection task panicked: {:?}", e);
+                }
+                e.id()
+            }
+        };
+        self.tracked.remove(&id);
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        metrics::set_connection_tasks(self.set.len());
+    }
+
+    /// Logs connections that went `stale_after` without logging in, returns how many were new
+    fn sweep(&mut self, stale_after: Duration, now: Instant) -> usize {
+        let mut reported = 0;
+        for tracked in self.tracked.values_mut() {
+            let age = now.saturating_duration_since(tracked.started);
+            if tracked.reported || tracked.login.is_set() || age < stale_after {
+                continue;
+            }
+            log::warn!(
+                "Connection from {} has been open for {}s without logging in",
+                tracked.addr,
+                age.as_secs()
+            );
+            tracked.reported = true;
+            reported += 1;
+        }
+        reported
+    }
+
+    /// Refuses new connections and aborts the running ones
+    async fn drain(&mut self) {
+        self.jobs.close();
+        while let Ok(job) = self.jobs.try_recv() {
+            self.spawn(job);
+        }
+        log::info!("Closing {} connections", self.set.len());
+        self.set.abort_all();
+        while let Some(joined) = self.set.join_next_with_id().await {
+            self.reap(joined);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:38281".parse().unwrap()
+    }
+
+    /// A connection that runs until its sender is dropped
+    fn pending(supervisor: &Supervisor) -> Result<oneshot::Sender<()>, Refused> {
+        let (done_tx, done_rx) = oneshot::channel();
+        supervisor.submit(addr(), |_| async move {
+            let _ = done_rx.await;
+        })?;
+        Ok(done_tx)
+    }
+
+    #[tokio::test]
+    async fn test_connections_past_the_cap_are_refused() {
+        let (supervisor, tasks) = Supervisor::new(Some(2));
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let run = tokio::spawn(tasks.run(Duration::from_secs(60), shutdown_rx));
+
+        let first = pending(&supervisor).unwrap();
+        let _second = pending(&supervisor).unwrap();
+        assert_eq!(pending(&supervisor).err(), Some(Refused));
+
+        // Its seat is only freed once the task was reaped
+        drop(first);
+        while supervisor.live.load(Ordering::Relaxed) == 2 {
+            tokio::task::yield_now().await;
+        }
+        let _third = pending(&supervisor).unwrap();
+
+        shutdown_tx.send(true).unwrap();
+        run.await.unwrap();
+        assert_eq!(supervisor.live.load(Ordering::Relaxed), 0);
+        assert_eq!(pending(&supervisor).err(), Some(Refused));
+    }
+
+    #[tokio::test]
+    async fn test_finished_tasks_are_reaped() {
+        let (supervisor, mut tasks) = Supervisor::new(None);
+        let done = pending(&supervisor).unwrap();
+        let _running = pending(&supervisor).unwrap();
+        for _ in 0..2 {
+            let job = tasks.jobs.recv().await.unwrap();
+            tasks.spawn(job);
+        }
+
+        drop(done);
+        let joined = tasks.set.join_next_with_id().await.unwrap();
+        tasks.reap(joined);
+        assert_eq!((tasks.set.len(), tasks.tracked.len()), (1, 1));
+        assert_eq!(supervisor.live.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_connections_are_reported_once() {
+        let (supervisor, mut tasks) = Supervisor::new(None);
+        let _waiting = pending(&supervisor).unwrap();
+        supervisor
+            .submit(addr(), |login| async move {
+                login.set();
+                std::future::pending::<()>().await
+            })
+            .unwrap();
+        for _ in 0..2 {
+            let job = tasks.jobs.recv().await.unwrap();
+            tasks.spawn(job);
+        }
+        // Let the second one log in
+        tokio::task::yield_now().await;
+
+        let minute = Duration::from_secs(60);
+        assert_eq!(tasks.sweep(minute, Instant::now()), 0);
+        assert_eq!(tasks.sweep(minute, Instant::now() + minute), 1);
+        assert_eq!(tasks.sweep(minute, Instant::now() + 2 * minute), 0);
+    }
+}
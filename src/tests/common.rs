@@ -0,0 +1,501 @@
+use futures_util::{SinkExt, Stream, StreamExt, stream};
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{RwLock, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tungstenite::Message;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::extensions::compression::deflate::DeflateConfig;
+use tungstenite::http::header::ORIGIN;
+use tungstenite::protocol::{CloseFrame, WebSocketConfig};
+
+use crate::DataPackageCache;
+use crate::audit::AuditKey;
+use crate::bounce_log::BounceRecorder;
+use crate::chain::Identity;
+use crate::claims::SlotClaims;
+use crate::config::Config;
+use crate::error::{DisconnectCause, ProxyResult};
+use crate::events::EventBus;
+use crate::login_queue::LoginQueue;
+use crate::mirror::TrackerMirrors;
+use crate::net::{self, TlsDetection};
+use crate::password_audit::PasswordFailures;
+use crate::proxy::tests::{mock_room_info, packet};
+use crate::proxy::{ProxyContext, handle_client};
+use crate::ready::ReadyStates;
+use crate::registry::ClientRegistry;
+use crate::reload::{Live, LiveSettings};
+use crate::resume::ResumableSessions;
+use crate::shedding::LoadShedding;
+use crate::standby::{SoftState, Standby};
+use crate::supervisor::{LoginMark, Supervisor};
+use crate::token::TokenKey;
+use crate::upstream::{UpstreamLimiter, UpstreamServers};
+
+/// How long an expectation waits before failing the test
+const EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) fn connect(name: &str, password: &str) -> Value {
+    json!({
+        "cmd": "Connect",
+        "password": password,
+        "name": name,
+        "game": "Test",
+        "uuid": "test",
+        "version": {"major": 0, "minor": 6, "build": 0, "class": "Version"},
+        "tags": [],
+        "items_handling": 7,
+    })
+}
+
+pub(crate) fn say(text: &str) -> Value {
+    json!({"cmd": "Say", "text": text})
+}
+
+fn cmd_of(command: &Value) -> &str {
+    command["cmd"].as_str().unwrap_or_default()
+}
+
+/// Next command read off `ws`, the rest of its message is kept in `pending`. None once `within`
+/// went by, or the socket closed, without one.
+async fn next_command<S>(
+    ws: &mut S,
+    pending: &mut VecDeque<Value>,
+    within: Duration,
+) -> Option<Value>
+where
+    S: Stream<Item = tungstenite::Result<Message>> + Unpin,
+{
+    let deadline = tokio::time::Instant::now() + within;
+    while pending.is_empty() {
+        let msg = tokio::time::timeout_at(deadline, ws.next())
+            .await
+            .ok()??
+            .ok()?;
+        if let Message::Text(text) = msg {
+            let commands: Vec<Value> =
+                serde_json::from_str(&text).expect("messages are arrays of commands");
+            pending.extend(commands);
+        }
+    }
+    pending.pop_front()
+}
+
+/// Context main would build from `config`, without a database or lobby behind it
+pub(crate) fn context(config: &Config, upstream_url: &str) -> ProxyContext {
+    ProxyContext {
+        upstream: Arc::new(UpstreamServers::new(
+            upstream_url.parse().unwrap(),
+            config.ap_server_fallbacks.clone(),
+            config.upstream_resolve_interval,
+        )),
+        events: EventBus::new(),
+        passwords: Default::default(),
+        upstream_room_password: config.upstream_room_password.clone(),
+        deathlink_exclusions: Default::default(),
+        deathlink_probability: Default::default(),
+        deferred_datapackage_games: Default::default(),
+        preferences: Default::default(),
+        slot_groups: Default::default(),
+        slot_names: Default::default(),
+        motd: Arc::new(RwLock::new(config.motd.clone())),
+        bounce_recorder: Arc::new(BounceRecorder::new(
+            config.record_bounces.clone(),
+            config.bounce_daily_cap,
+        )),
+        datapackage_cache: Arc::new(DataPackageCache::from_response(json!({})).unwrap()),
+        room_id: config.room_id.clone(),
+        client_registry: Arc::new(ClientRegistry::new(config.flap_limits)),
+        login_queue: Arc::new(LoginQueue::new(
+            &config.room_id,
+            config.max_logged_in_clients,
+            config.login_queue_max_wait,
+        )),
+        load_shedding: Arc::new(LoadShedding::new(
+            config.degrade_messages_per_second,
+            config.degrade_recovery,
+        )),
+        rooms: Default::default(),
+        upstream_limiter: Arc::new(UpstreamLimiter::new(
+            config.max_upstream_connections,
+            config.upstream_queue_wait,
+        )),
+        token_key: config.token_secret.as_deref().map(TokenKey::new),
+        audit_key: config.audit_hmac_key.as_deref().map(AuditKey::new),
+        password_failures: Arc::new(PasswordFailures::new(config.password_failure_history)),
+        tracker_mirrors: config.mirror_trackers.then(TrackerMirrors::default),
+        slot_claims: config
+            .slot_claiming
+            .then(|| Arc::new(SlotClaims::new(config.claim_ttl))),
+        resumption: config
+            .resume_window
+            .map(|window| Arc::new(ResumableSessions::new(window))),
+        allowed_origins: config.allowed_origins.clone(),
+        live: Live::new(LiveSettings::from(config)),
+        chain_mode: config.chain_mode,
+        identity: Identity::default(),
+        bridge: None,
+        ready: Arc::new(ReadyStates::default()),
+    }
+}
+
+/// A proxy on an ephemeral port, accepting connections the way main does. Its context is kept
+/// around for tests that look at the registry or the event bus.
+pub(crate) struct TestApx {
+    pub(crate) addr: SocketAddr,
+    pub(crate) context: ProxyContext,
+    accept: JoinHandle<()>,
+    connections: JoinHandle<()>,
+    /// Connections run until it's dropped
+    _shutdown: watch::Sender<bool>,
+}
+
+impl TestApx {
+    pub(crate) async fn start(context: ProxyContext) -> Self {
+        let listener = net::bind_listener("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let standby = Standby::new(
+            context.room_id.clone(),
+            SoftState {
+                deathlink_exclusions: context.deathlink_exclusions.clone(),
+                deathlink_probability: context.deathlink_probability.clone(),
+                deferred_datapackage_games: context.deferred_datapackage_games.clone(),
+                preferences: context.preferences.clone(),
+                motd: context.motd.clone(),
+            },
+            false,
+        );
+        let (supervisor, tasks) = Supervisor::new(None);
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let connections = tokio::spawn(tasks.run(Duration::from_secs(300), shutdown_rx));
+        let accept = tokio::spawn(crate::accept_loop(
+            listener,
+            false,
+            context.clone(),
+            None,
+            TlsDetection::Auto,
+            Arc::new(standby),
+            supervisor,
+        ));
+        Self {
+            addr,
+            context,
+            accept,
+            connections,
+            _shutdown: shutdown,
+        }
+    }
+
+    pub(crate) async fn client(&self) -> TestClient {
+        TestClient::connect(self.addr).await
+    }
+}
+
+impl Drop for TestApx {
+    fn drop(&mut self) {
+        self.accept.abort();
+        self.connections.abort();
+    }
+}
+
+/// Serves a single connection, for tests that check how the handler ended
+pub(crate) async fn serve_one(
+    context: ProxyContext,
+) -> (TestClient, JoinHandle<ProxyResult<DisconnectCause>>) {
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = proxy.local_addr().unwrap();
+    let handler = tokio::spawn(async move {
+        let (socket, _) = proxy.accept().await.unwrap();
+        handle_client(socket, &context, false, LoginMark::default()).await
+    });
+    (TestClient::connect(addr).await, handler)
+}
+
+/// A player's connection to the proxy
+pub(crate) struct TestClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    pending: VecDeque<Value>,
+}
+
+impl TestClient {
+    pub(crate) async fn connect(addr: SocketAddr) -> Self {
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        Self {
+            ws,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Connects offering permessage-deflate, which APX always accepts
+    pub(crate) async fn connect_deflate(addr: SocketAddr) -> Self {
+        let mut config = WebSocketConfig::default();
+        config.extensions.permessage_deflate = Some(DeflateConfig::default());
+        let (ws, _) = tokio_tungstenite::connect_async_with_config(
+            format!("ws://{}", addr),
+            Some(config),
+            false,
+        )
+        .await
+        .unwrap();
+        Self {
+            ws,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Connects the way a browser on a page of `origin` does
+    pub(crate) async fn connect_from_origin(
+        addr: SocketAddr,
+        origin: &str,
+    ) -> tungstenite::Result<Self> {
+        let mut request = format!("ws://{}", addr).into_client_request()?;
+        request
+            .headers_mut()
+            .insert(ORIGIN, origin.parse().unwrap());
+        let (ws, _) = tokio_tungstenite::connect_async(request).await?;
+        Ok(Self {
+            ws,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Sends a command, or an array of them, as one message
+    pub(crate) async fn send_cmds(&mut self, commands: Value) {
+        let commands = match commands {
+            Value::Array(commands) => commands,
+            command => vec![command],
+        };
+        self.ws.send(packet(commands)).await.unwrap();
+    }
+
+    pub(crate) async fn send_text(&mut self, text: &str) {
+        self.ws.send(Message::Text(text.into())).await.unwrap();
+    }
+
+    /// Fails unless the next command received is a `cmd`
+    pub(crate) async fn expect_cmd(&mut self, cmd: &str) -> Value {
+        let command = next_command(&mut self.ws, &mut self.pending, EXPECT_TIMEOUT)
+            .await
+            .unwrap_or_else(|| panic!("client never received {}", cmd));
+        assert_eq!(cmd_of(&command), cmd, "client received {}", command);
+        command
+    }
+
+    pub(crate) async fn expect_no_cmd_for(&mut self, ms: u64) {
+        let within = Duration::from_millis(ms);
+        if let Some(command) = next_command(&mut self.ws, &mut self.pending, within).await {
+            panic!("client received {}", command);
+        }
+    }
+
+    /// Goes through RoomInfo, Connect and Connected
+    pub(crate) async fn login(&mut self, connect: Value) -> Value {
+        self.expect_cmd("RoomInfo").await;
+        self.send_cmds(connect).await;
+        self.expect_cmd("Connected").await
+    }
+
+    /// Skips whatever comes before the close frame
+    pub(crate) async fn expect_close(&mut self) -> Option<CloseFrame> {
+        loop {
+            match tokio::time::timeout(EXPECT_TIMEOUT, self.ws.next()).await {
+                Ok(Some(Ok(Message::Close(frame)))) => return frame,
+                Ok(Some(Ok(_))) => continue,
+                other => panic!("Expected a close frame, got {:?}", other),
+            }
+        }
+    }
+
+    /// Everything received until the connection ends or goes quiet
+    pub(crate) async fn messages_until_closed(&mut self) -> Vec<tungstenite::Result<Message>> {
+        let mut received = Vec::new();
+        while let Ok(Some(msg)) = tokio::time::timeout(EXPECT_TIMEOUT, self.ws.next()).await {
+            received.push(msg);
+        }
+        received
+    }
+
+    /// The connection under the WebSocket, for writing frames the client library wouldn't
+    pub(crate) fn socket(&mut self) -> &mut TcpStream {
+        let MaybeTlsStream::Plain(socket) = self.ws.get_mut() else {
+            unreachable!("the test client doesn't use TLS");
+        };
+        socket
+    }
+}
+
+enum Step {
+    Send(Vec<Value>),
+    SendRaw(Vec<u8>),
+    Expect(String),
+    ExpectField(String, String, Value),
+    Close,
+}
+
+/// What a mock upstream does on one connection, in order. Once it's done the connection stays
+/// open, everything received after is handed to the test.
+#[derive(Default)]
+pub(crate) struct Script(Vec<Step>);
+
+impl Script {
+    /// Greets with RoomInfo and answers the first Connect with `connected`
+    pub(crate) fn login(connected: Vec<Value>) -> Self {
+        Self::default()
+            .send(vec![mock_room_info()])
+            .expect("Connect")
+            .send(connected)
+    }
+
+    pub(crate) fn send(mut self, commands: Vec<Value>) -> Self {
+        self.0.push(Step::Send(commands));
+        self
+    }
+
+    /// Writes bytes as they are, for frames a well behaved server wouldn't send
+    pub(crate) fn send_raw(mut self, bytes: Vec<u8>) -> Self {
+        self.0.push(Step::SendRaw(bytes));
+        self
+    }
+
+    /// Fails the script unless the next command received is a `cmd`
+    pub(crate) fn expect(mut self, cmd: &str) -> Self {
+        self.0.push(Step::Expect(cmd.to_string()));
+        self
+    }
+
+    /// Like `expect`, also failing unless the command's `field` is `value`
+    pub(crate) fn expect_field(mut self, cmd: &str, field: &str, value: Value) -> Self {
+        self.0
+            .push(Step::ExpectField(cmd.to_string(), field.to_string(), value));
+        self
+    }
+
+    /// Closes the connection, whatever would have come after is never played
+    pub(crate) fn close(mut self) -> Self {
+        self.0.push(Step::Close);
+        self
+    }
+}
+
+/// An AP server playing one script per connection it accepts
+pub(crate) struct MockUpstream {
+    pub(crate) url: String,
+    received: mpsc::UnboundedReceiver<Message>,
+    pending: VecDeque<Value>,
+    task: JoinHandle<()>,
+}
+
+impl MockUpstream {
+    pub(crate) async fn spawn(scripts: Vec<Script>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (received_tx, received) = mpsc::unbounded_channel();
+        let task = tokio::spawn(async move {
+            let mut connections = Vec::new();
+            for script in scripts {
+                let (socket, _) = listener.accept().await.unwrap();
+                let ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+                connections.push(tokio::spawn(play(ws, script, received_tx.clone())));
+            }
+            drop(received_tx);
+            for connection in connections {
+                if let Err(e) = connection.await
+                    && e.is_panic()
+                {
+                    std::panic::resume_unwind(e.into_panic());
+                }
+            }
+        });
+        Self {
+            url,
+            received,
+            pending: VecDeque::new(),
+            task,
+        }
+    }
+
+    /// Fails the test with the panic of a script that didn't go as expected, if there's one
+    async fn check_scripts(&mut self) {
+        if self.task.is_finished()
+            && let Err(e) = (&mut self.task).await
+            && e.is_panic()
+        {
+            std::panic::resume_unwind(e.into_panic());
+        }
+    }
+
+    /// Fails unless the next command received after the scripts is a `cmd`
+    pub(crate) async fn expect_cmd(&mut self, cmd: &str) -> Value {
+        let mut received = stream::poll_fn(|cx| self.received.poll_recv(cx).map(|m| m.map(Ok)));
+        let Some(command) = next_command(&mut received, &mut self.pending, EXPECT_TIMEOUT).await
+        else {
+            self.check_scripts().await;
+            panic!("upstream never received {}", cmd);
+        };
+        assert_eq!(cmd_of(&command), cmd, "upstream received {}", command);
+        command
+    }
+
+    pub(crate) async fn expect_no_cmd_for(&mut self, ms: u64) {
+        let within = Duration::from_millis(ms);
+        let mut received = stream::poll_fn(|cx| self.received.poll_recv(cx).map(|m| m.map(Ok)));
+        if let Some(command) = next_command(&mut received, &mut self.pending, within).await {
+            panic!("upstream received {}", command);
+        }
+    }
+
+    /// Everything received after the scripts, until the connections end or go quiet
+    pub(crate) async fn messages_until_closed(&mut self) -> Vec<Message> {
+        let mut received = Vec::new();
+        while let Ok(Some(msg)) = tokio::time::timeout(EXPECT_TIMEOUT, self.received.recv()).await {
+            received.push(msg);
+        }
+        received
+    }
+}
+
+async fn play(
+    mut ws: WebSocketStream<TcpStream>,
+    script: Script,
+    received: mpsc::UnboundedSender<Message>,
+) {
+    let mut pending = VecDeque::new();
+    for step in script.0 {
+        match step {
+            Step::Send(commands) => ws.send(packet(commands)).await.unwrap(),
+            Step::SendRaw(bytes) => ws.get_mut().write_all(&bytes).await.unwrap(),
+            Step::Expect(cmd) => {
+                let command = next_command(&mut ws, &mut pending, EXPECT_TIMEOUT)
+                    .await
+                    .unwrap_or_else(|| panic!("upstream never received {}", cmd));
+                assert_eq!(cmd_of(&command), cmd, "upstream received {}", command);
+            }
+            Step::ExpectField(cmd, field, value) => {
+                let command = next_command(&mut ws, &mut pending, EXPECT_TIMEOUT)
+                    .await
+                    .unwrap_or_else(|| panic!("upstream never received {}", cmd));
+                assert_eq!(cmd_of(&command), cmd, "upstream received {}", command);
+                assert_eq!(command[&field], value, "upstream received {}", command);
+            }
+            Step::Close => {
+                let _ = ws.close(None).await;
+                return;
+            }
+        }
+    }
+    if !pending.is_empty() {
+        let _ = received.send(packet(pending.into()));
+    }
+    while let Some(Ok(msg)) = ws.next().await {
+        let _ = received.send(msg);
+    }
+}
@@ -0,0 +1,152 @@
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::path::Path;
+
+use crate::error::Side;
+
+/// What seed names are replaced with, AP's are 20 digits
+const SEED_NAME: &str = "00000000000000000000";
+/// Keys whose strings are player names
+const NAME_KEYS: &[&str] = &["name", "alias", "source"];
+/// Keys whose strings are free text that may mention players
+const TEXT_KEYS: &[&str] = &["text", "cause", "message"];
+
+/// A message captured off a connection to an AP server, as committed under
+/// `tests/fixtures/<side>/`, `side` being the one that sent it
+pub(crate) struct Fixture {
+    pub(crate) name: String,
+    pub(crate) text: String,
+}
+
+/// Every fixture sent by `side`, by name
+pub(crate) fn load(side: Side) -> Vec<Fixture> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(side.label());
+    let mut fixtures: Vec<Fixture> = std::fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("Failed to list {}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .map(|path| Fixture {
+            name: path.file_name().unwrap().to_string_lossy().into_owned(),
+            text: std::fs::read_to_string(&path).unwrap(),
+        })
+        .collect();
+    fixtures.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    assert!(!fixtures.is_empty(), "No fixtures in {}", dir.display());
+    fixtures
+}
+
+/// What a capture is committed as: a pretty printed batch, player names swapped for `Player1`,
+/// `Player2`... in order of appearance, and the seed name zeroed. A fixture comes out of it
+/// unchanged.
+pub(crate) fn normalize(capture: &str) -> String {
+    let mut commands: Value = serde_json::from_str(capture).expect("captures are JSON");
+    if !commands.is_array() {
+        commands = Value::Array(vec![commands]);
+    }
+
+    let mut names = Vec::new();
+    collect_names(&commands, &mut names);
+    let mut pseudonyms: Vec<(String, String)> = names
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| (name, format!("Player{}", i + 1)))
+        .collect();
+    // So no name is replaced inside a longer one
+    pseudonyms.sort_by_key(|(name, _)| Reverse(name.len()));
+    anonymize(&mut commands, None, &pseudonyms);
+
+    let mut text = serde_json::to_string_pretty(&commands).unwrap();
+    text.push('\n');
+    text
+}
+
+fn collect_names(value: &Value, names: &mut Vec<String>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                if NAME_KEYS.contains(&key.as_str())
+                    && let Value::String(name) = value
+                    && !names.contains(name)
+                {
+                    names.push(name.clone());
+                }
+                collect_names(value, names);
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                collect_names(value, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn anonymize(value: &mut Value, key: Option<&str>, pseudonyms: &[(String, String)]) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                if key == "seed_name" && value.is_string() {
+                    *value = SEED_NAME.into();
+                } else {
+                    anonymize(value, Some(key), pseudonyms);
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                anonymize(value, key, pseudonyms);
+            }
+        }
+        Value::String(text) => {
+            if let Some((_, pseudonym)) = pseudonyms
+                .iter()
+                .find(|(name, _)| name.as_str() == text.as_str())
+            {
+                *text = pseudonym.clone();
+            } else if key.is_some_and(|key| TEXT_KEYS.contains(&key)) {
+                for (name, pseudonym) in pseudonyms {
+                    *text = text.replace(name.as_str(), pseudonym);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Turns a capture into a fixture, in place:
+/// `CAPTURE=tests/fixtures/upstream/new.json cargo test capture_fixture -- --ignored`
+#[test]
+#[ignore]
+fn capture_fixture() {
+    let path = std::env::var("CAPTURE").expect("CAPTURE is the file to normalize");
+    let capture = std::fs::read_to_string(&path).unwrap();
+    std::fs::write(&path, normalize(&capture)).unwrap();
+}
+
+#[test]
+fn test_fixtures_are_normalized() {
+    for fixture in load(Side::Upstream).into_iter().chain(load(Side::Client)) {
+        assert_eq!(
+            normalize(&fixture.text),
+            fixture.text,
+            "{} isn't normalized, run capture_fixture on it",
+            fixture.name
+        );
+    }
+}
+
+#[test]
+fn test_captures_are_anonymized() {
+    let capture = r#"{"cmd": "Bounced", "tags": ["DeathLink"], "data": {"source": "Link", "cause": "Link fell in a pit", "seed_name": "13371337133713371337"}}"#;
+    let fixture = normalize(capture);
+    let commands: Value = serde_json::from_str(&fixture).unwrap();
+    assert_eq!(commands[0]["data"]["source"], "Player1");
+    assert_eq!(commands[0]["data"]["cause"], "Player1 fell in a pit");
+    assert_eq!(commands[0]["data"]["seed_name"], SEED_NAME);
+    // Only free text is searched for names
+    assert_eq!(commands[0]["tags"][0], "DeathLink");
+    assert_eq!(normalize(&fixture), fixture);
+}
@@ -0,0 +1,6 @@
+// Scenarios driving the proxy over real sockets, between a test client and a scripted upstream.
+// APX is a binary, they live in the crate so the harness can build a ProxyContext.
+
+mod common;
+pub(crate) mod fixtures;
+mod scenarios;
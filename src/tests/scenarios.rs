@@ -0,0 +1,1790 @@
+use aprs_proto::primitives::SlotId;
+use chrono::Utc;
+use serde_json::{Value, json};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+use tungstenite::protocol::frame::coding::CloseCode;
+
+use super::common::{MockUpstream, Script, TestApx, TestClient, connect, context, say, serve_one};
+use crate::DataPackageCache;
+use crate::admin_ops;
+use crate::bandwidth;
+use crate::bridge::{self, Bridge, BridgeKey, Refused};
+use crate::budget::PreLoginLimits;
+use crate::chain::ChainMode;
+use crate::config::Config;
+use crate::config::tests::{test_config, test_vars};
+use crate::error::{DisconnectCause, ProxyError};
+use crate::events::RoomEvent;
+use crate::latency;
+use crate::loadtest;
+use crate::login_queue;
+use crate::messages::Notice;
+use crate::metrics;
+use crate::preferences::SlotPreferences;
+use crate::proxy::ProxyContext;
+use crate::proxy::tests::{mock_connected, mock_received_items, mock_room_info, packet};
+use crate::registry::{ClientControl, ReconnectError};
+use crate::release_pacing::ReleasePacing;
+use crate::reload::Reloader;
+use crate::scheduled_messages::{self, Announcements, ScheduledMessages};
+use crate::session::SessionLimits;
+use crate::shedding::Mode;
+use crate::upstream::UpstreamServers;
+
+/// A final frame of `payload`. Frames from clients are masked, frames from servers aren't.
+fn raw_frame(opcode: u8, payload: &[u8], masked: bool) -> Vec<u8> {
+    assert!(payload.len() < 126);
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![0x80 | opcode, payload.len() as u8];
+    if !masked {
+        frame.extend(payload);
+        return frame;
+    }
+    frame[1] |= 0x80;
+    frame.extend(mask);
+    frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+    frame
+}
+
+/// Frames a peer that doesn't play by the rules sends: a Close, then a LocationChecks
+fn close_then_checks(masked: bool) -> Vec<u8> {
+    let checks = r#"[{"cmd":"LocationChecks","locations":[1]}]"#;
+    [
+        raw_frame(0x8, &[], masked),
+        raw_frame(0x1, checks.as_bytes(), masked),
+    ]
+    .concat()
+}
+
+#[tokio::test]
+async fn test_wrong_password_is_refused_and_can_be_retried() {
+    let upstream = MockUpstream::spawn(vec![
+        Script::login(vec![mock_connected()])
+            .expect("Connect")
+            .send(vec![mock_connected()]),
+    ])
+    .await;
+    let context = context(&test_config("test"), &upstream.url);
+    context
+        .passwords
+        .write()
+        .await
+        .insert(SlotId(1), "hunter2".to_string());
+    let apx = TestApx::start(context).await;
+
+    let mut client = apx.client().await;
+    // APX checks passwords itself, the room always says it has one
+    let room_info = client.expect_cmd("RoomInfo").await;
+    assert_eq!(room_info["password"], true);
+
+    client.send_cmds(connect("Alice", "wrong")).await;
+    let refused = client.expect_cmd("ConnectionRefused").await;
+    assert_eq!(refused["errors"], json!(["InvalidPassword"]));
+    client.expect_no_cmd_for(100).await;
+
+    client.send_cmds(connect("Alice", "hunter2")).await;
+    let connected = client.expect_cmd("Connected").await;
+    assert_eq!(connected["slot"], 1);
+}
+
+/// Context of a room whose AP server has its own password, with a password on slot 1
+async fn room_password_context(upstream: &MockUpstream, room_password: &str) -> ProxyContext {
+    let config = Config {
+        upstream_room_password: Some(room_password.to_string()),
+        ..test_config("test")
+    };
+    let context = context(&config, &upstream.url);
+    context
+        .passwords
+        .write()
+        .await
+        .insert(SlotId(1), "hunter2".to_string());
+    context
+}
+
+#[tokio::test]
+async fn test_slot_passwords_layer_on_the_room_password() {
+    let upstream = MockUpstream::spawn(vec![
+        Script::default()
+            .send(vec![mock_room_info()])
+            .expect_field("Connect", "password", json!("s3cret"))
+            .send(vec![mock_connected()])
+            .expect_field("Connect", "password", json!("s3cret"))
+            .send(vec![mock_connected()]),
+    ])
+    .await;
+    let apx = TestApx::start(room_password_context(&upstream, "s3cret").await).await;
+
+    let mut client = apx.client().await;
+    client.expect_cmd("RoomInfo").await;
+    // Upstream takes the room password, the slot's is still checked by APX
+    client.send_cmds(connect("Alice", "wrong")).await;
+    let refused = client.expect_cmd("ConnectionRefused").await;
+    assert_eq!(refused["errors"], json!(["InvalidPassword"]));
+
+    client.send_cmds(connect("Alice", "hunter2")).await;
+    let connected = client.expect_cmd("Connected").await;
+    assert_eq!(connected["slot"], 1);
+}
+
+#[tokio::test]
+async fn test_wrong_room_password_is_not_blamed_on_the_player() {
+    let refused = json!({"cmd": "ConnectionRefused", "errors": ["InvalidPassword"]});
+    let upstream = MockUpstream::spawn(vec![
+        Script::default()
+            .send(vec![mock_room_info()])
+            .expect_field("Connect", "password", json!("wrong"))
+            .send(vec![refused])
+            .expect("Connect")
+            .send(vec![mock_connected()]),
+    ])
+    .await;
+    let apx = TestApx::start(room_password_context(&upstream, "wrong").await).await;
+
+    let mut client = apx.client().await;
+    client.expect_cmd("RoomInfo").await;
+    client.send_cmds(connect("Alice", "hunter2")).await;
+    let refused = client.expect_cmd("ConnectionRefused").await;
+    assert_eq!(refused["errors"], json!(["InvalidPassword"]));
+    let notice = client.expect_cmd("PrintJSON").await;
+    assert_eq!(notice, Notice::UpstreamPasswordRejected.to_print_json());
+    // Not a failed slot password
+    assert!(apx.context.password_failures.recent().is_empty());
+
+    // Upstream keeps the connection, so the client can try again on it
+    client.send_cmds(connect("Alice", "hunter2")).await;
+    let connected = client.expect_cmd("Connected").await;
+    assert_eq!(connected["slot"], 1);
+}
+
+/// Connected to `slot` of a room where Alice plays slot 1 and Bob slot 2
+fn connected_to(slot: i64) -> Value {
+    let mut connected = mock_connected();
+    connected["slot"] = json!(slot);
+    connected["players"] = json!([
+        {"team": 0, "slot": 1, "alias": "Alice", "name": "Alice"},
+        {"team": 0, "slot": 2, "alias": "Bob", "name": "Bob"},
+    ]);
+    connected
+}
+
+#[tokio::test]
+async fn test_logging_in_again_switches_slots() {
+    let mut upstream = MockUpstream::spawn(vec![
+        Script::login(vec![connected_to(1)])
+            // The slot password never goes upstream, on a second login either
+            .expect_field("Connect", "password", json!(""))
+            .send(vec![
+                print_json("Alice: still here"),
+                connected_to(2),
+                mock_received_items(0, &[7]),
+                print_json("Bob: found it"),
+            ]),
+        // Refused, the connection starts over upstream rather than staying on Bob's slot
+        Script::default()
+            .send(vec![mock_room_info()])
+            .expect_field("Connect", "password", json!(""))
+            .send(vec![connected_to(2)]),
+    ])
+    .await;
+    let context = context(&test_config("slot-switch"), &upstream.url);
+    context.passwords.write().await.extend([
+        (SlotId(1), "hunter2".to_string()),
+        (SlotId(2), "opensesame".to_string()),
+    ]);
+    let apx = TestApx::start(context).await;
+    let registered_slots = || async {
+        let clients = apx.context.client_registry.clients().await;
+        clients.iter().map(|client| client.slot).collect::<Vec<_>>()
+    };
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "hunter2")).await;
+    assert_eq!(registered_slots().await, [SlotId(1)]);
+
+    // The new slot's password is checked, what upstream still sent for the old one is dropped
+    client.send_cmds(connect("Bob", "hunter2")).await;
+    let refused = client.expect_cmd("ConnectionRefused").await;
+    assert_eq!(refused["errors"], json!(["InvalidPassword"]));
+    assert_eq!(registered_slots().await, []);
+    // Nor does anything upstream sent for Bob's slot
+    client.expect_no_cmd_for(100).await;
+
+    client.send_cmds(connect("Bob", "opensesame")).await;
+    let connected = client.expect_cmd("Connected").await;
+    assert_eq!(connected["slot"], 2);
+    assert_eq!(registered_slots().await, [SlotId(2)]);
+
+    // What the connection sends now counts for the slot it switched to
+    let alice = bandwidth::usage("slot-switch", SlotId(1));
+    let bob = bandwidth::usage("slot-switch", SlotId(2));
+    client.send_cmds(say("hello")).await;
+    upstream.expect_cmd("Say").await;
+    assert_eq!(bandwidth::usage("slot-switch", SlotId(1)), alice);
+    assert!(bandwidth::usage("slot-switch", SlotId(2)).client_to_upstream > bob.client_to_upstream);
+}
+
+fn connect_from(uuid: &str, tags: Value) -> Value {
+    let mut connect = connect("Alice", "");
+    connect["uuid"] = json!(uuid);
+    connect["tags"] = tags;
+    connect
+}
+
+#[tokio::test]
+async fn test_claimed_slot_only_lets_its_client_in() {
+    let login = || Script::login(vec![mock_connected()]);
+    let upstream = MockUpstream::spawn(vec![
+        login(),
+        login(),
+        login().expect("Connect").send(vec![mock_connected()]),
+        login(),
+    ])
+    .await;
+    let config = Config {
+        slot_claiming: true,
+        ..test_config("test")
+    };
+    let apx = TestApx::start(context(&config, &upstream.url)).await;
+
+    let mut first = apx.client().await;
+    first.login(connect_from("alice", json!([]))).await;
+    // Trackers don't need the claim
+    let mut tracker = apx.client().await;
+    tracker
+        .login(connect_from("poptracker", json!(["Tracker"])))
+        .await;
+
+    let mut other = apx.client().await;
+    other.expect_cmd("RoomInfo").await;
+    other.send_cmds(connect_from("mallory", json!([]))).await;
+    let notice = other.expect_cmd("PrintJSON").await;
+    assert_eq!(notice, Notice::SlotClaimed.to_print_json());
+    let refused = other.expect_cmd("ConnectionRefused").await;
+    assert_eq!(refused["errors"], json!(["SlotClaimed"]));
+
+    // The claiming client gets back in
+    drop(first);
+    let mut again = apx.client().await;
+    again.login(connect_from("alice", json!([]))).await;
+
+    // Once cleared by an admin, the next login claims the slot
+    assert!(apx.context.slot_claims.as_ref().unwrap().clear(SlotId(1)));
+    other.send_cmds(connect_from("mallory", json!([]))).await;
+    other.expect_cmd("Connected").await;
+    let claimed = apx
+        .context
+        .slot_claims
+        .as_ref()
+        .unwrap()
+        .claimed(chrono::Utc::now());
+    assert_eq!(claimed[0].uuid, "mallory");
+}
+
+/// A proxy letting sessions resume for `window`, with a password on slot 1. `previous` is what
+/// the proxy it replaces persisted: its resumable sessions, then its claims.
+async fn restarted_apx(upstream_url: &str, window: Duration, previous: (Value, Value)) -> TestApx {
+    let config = Config {
+        resume_window: Some(window),
+        slot_claiming: true,
+        ..test_config("test")
+    };
+    let context = context(&config, upstream_url);
+    context
+        .passwords
+        .write()
+        .await
+        .insert(SlotId(1), "hunter2".to_string());
+    let (sessions, claims) = previous;
+    context
+        .resumption
+        .as_ref()
+        .unwrap()
+        .restore(sessions)
+        .unwrap();
+    context
+        .slot_claims
+        .as_ref()
+        .unwrap()
+        .restore(claims)
+        .unwrap();
+    TestApx::start(context).await
+}
+
+fn resume_with(token: &Value) -> Value {
+    let mut connect = connect("Alice", "");
+    connect["uuid"] = token.clone();
+    connect
+}
+
+#[tokio::test]
+async fn test_sessions_resume_across_a_restart() {
+    let login = || Script::login(vec![mock_connected()]);
+    let upstream = MockUpstream::spawn(vec![
+        login(),
+        login().expect("Connect").send(vec![mock_connected()]),
+        login(),
+    ])
+    .await;
+    let minute = Duration::from_secs(60);
+    let first = restarted_apx(&upstream.url, minute, (json!([]), json!([]))).await;
+    let muted = SlotPreferences {
+        muted: true,
+        ..SlotPreferences::default()
+    };
+    first
+        .context
+        .preferences
+        .write()
+        .await
+        .insert(SlotId(1), muted.clone());
+
+    let mut client = first.client().await;
+    let mut login_connect = connect_from("alice", json!([]));
+    login_connect["password"] = json!("hunter2");
+    let connected = client.login(login_connect).await;
+    let token = connected["apx_resume"].clone();
+    assert!(token.is_string());
+
+    // What the first proxy would have persisted before going down with the client logged in
+    let now = Utc::now();
+    let persisted = (
+        serde_json::to_value(first.context.resumption.as_ref().unwrap().resumable(now)).unwrap(),
+        serde_json::to_value(first.context.slot_claims.as_ref().unwrap().claimed(now)).unwrap(),
+    );
+    drop(client);
+    drop(first);
+
+    let second = restarted_apx(&upstream.url, minute, persisted.clone()).await;
+    let mut client = second.client().await;
+    client.expect_cmd("RoomInfo").await;
+    client
+        .send_cmds(resume_with(&json!("apx-resume-bogus")))
+        .await;
+    let refused = client.expect_cmd("ConnectionRefused").await;
+    assert_eq!(refused["errors"], json!(["InvalidPassword"]));
+
+    // No password needed, the claim of its uuid still holds and its preferences are back
+    client.send_cmds(resume_with(&token)).await;
+    let connected = client.expect_cmd("Connected").await;
+    assert!(connected["apx_resume"].is_string());
+    assert_ne!(connected["apx_resume"], token);
+    assert_eq!(
+        second.context.preferences.read().await.get(&SlotId(1)),
+        Some(&muted)
+    );
+
+    // Past its window the token is only a wrong password
+    let third = restarted_apx(&upstream.url, Duration::ZERO, persisted).await;
+    let mut client = third.client().await;
+    client.expect_cmd("RoomInfo").await;
+    client.send_cmds(resume_with(&token)).await;
+    let refused = client.expect_cmd("ConnectionRefused").await;
+    assert_eq!(refused["errors"], json!(["InvalidPassword"]));
+}
+
+#[tokio::test]
+async fn test_countdowns_are_intercepted() {
+    let mut upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+    let apx = TestApx::start(context(&test_config("test"), &upstream.url)).await;
+    let mut events = apx.context.events.subscribe();
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "")).await;
+    client.send_cmds(say("!countdown 10")).await;
+    let notice = client.expect_cmd("PrintJSON").await;
+    assert_eq!(notice["data"][0]["text"], Notice::CountdownBlocked.text());
+
+    let countdown = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let RoomEvent::CountdownInit { slot } = events.recv().await.unwrap() {
+                break slot;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(countdown, SlotId(1));
+
+    // Only the chat that isn't a countdown makes it upstream
+    client.send_cmds(say("hello")).await;
+    let forwarded = upstream.expect_cmd("Say").await;
+    assert_eq!(forwarded["text"], "hello");
+    upstream.expect_no_cmd_for(100).await;
+}
+
+async fn next_deathlink(
+    events: &mut tokio::sync::broadcast::Receiver<RoomEvent>,
+) -> (SlotId, String, Option<String>) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let RoomEvent::DeathLink {
+                slot,
+                source,
+                cause,
+            } = events.recv().await.unwrap()
+            {
+                break (slot, source, cause);
+            }
+        }
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_deathlinks_are_published() {
+    let upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+    let apx = TestApx::start(context(&test_config("test"), &upstream.url)).await;
+    let mut events = apx.context.events.subscribe();
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "")).await;
+
+    client
+        .send_cmds(json!({
+            "cmd": "Bounce",
+            "tags": ["DeathLink"],
+            "data": {"source": "Alice", "cause": "Alice fell", "time": 0},
+        }))
+        .await;
+    assert_eq!(
+        next_deathlink(&mut events).await,
+        (
+            SlotId(1),
+            "Alice".to_string(),
+            Some("Alice fell".to_string())
+        )
+    );
+
+    // Malformed ones are still stored, with what could be made of them
+    client
+        .send_cmds(
+            json!([{"cmd": "Bounce", "tags": ["DeathLink"], "data": {"cause": 3, "time": 0}}]),
+        )
+        .await;
+    assert_eq!(
+        next_deathlink(&mut events).await,
+        (SlotId(1), "Unknown".to_string(), None)
+    );
+}
+
+/// An instance of `room_id` whose bridge posts to a mock of the peer's `/api/bridge`
+async fn bridged_room(
+    room_id: &str,
+    upstream: &MockUpstream,
+    key: &BridgeKey,
+) -> (TestApx, wiremock::MockServer) {
+    let peer = wiremock::MockServer::start().await;
+    wiremock::Mock::given(wiremock::matchers::method("POST"))
+        .respond_with(wiremock::ResponseTemplate::new(200))
+        .mount(&peer)
+        .await;
+    let (bridge, outgoing) = Bridge::new(room_id.to_string());
+    let url = format!("{}/api/bridge", peer.uri()).parse().unwrap();
+    tokio::spawn(bridge::run(url, key.clone(), outgoing));
+    let mut context = context(&test_config(room_id), &upstream.url);
+    context.bridge = Some(bridge);
+    (TestApx::start(context).await, peer)
+}
+
+#[tokio::test]
+async fn test_chat_crosses_the_bridge_once() {
+    let key = BridgeKey::new("secret");
+    let mut north_upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+    let mut south_upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+    let (north, north_peer) = bridged_room("north", &north_upstream, &key).await;
+    let (south, south_peer) = bridged_room("south", &south_upstream, &key).await;
+
+    let mut alice = north.client().await;
+    alice.login(connect("Alice", "")).await;
+    let mut bob = south.client().await;
+    bob.login(connect("Bob", "")).await;
+
+    alice.send_cmds(say("!hint Sword")).await;
+    alice.send_cmds(say("hello south")).await;
+    north_upstream.expect_cmd("Say").await;
+    assert_eq!(
+        north_upstream.expect_cmd("Say").await["text"],
+        "hello south"
+    );
+    let posted = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let requests = north_peer.received_requests().await.unwrap();
+            if let Some(request) = requests.into_iter().next() {
+                break request;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let signature = posted.headers[bridge::SIGNATURE_HEADER].to_str().unwrap();
+
+    // What the south's /api/bridge does with it
+    let delivered = bridge::deliver(
+        &key,
+        "south",
+        &south.context.client_registry,
+        &posted.body,
+        signature,
+    )
+    .await;
+    assert_eq!(delivered, Ok(1));
+    let shown = bob.expect_cmd("PrintJSON").await;
+    assert_eq!(shown["data"][0]["text"], "[north] ");
+    assert_eq!(shown["data"][1]["text"], "Alice: hello south");
+
+    // Nothing goes back: not to the south's AP server, not over its bridge, not to Alice
+    bob.expect_no_cmd_for(200).await;
+    alice.expect_no_cmd_for(0).await;
+    south_upstream.expect_no_cmd_for(0).await;
+    assert_eq!(south_peer.received_requests().await.unwrap().len(), 0);
+    assert_eq!(north_peer.received_requests().await.unwrap().len(), 1);
+    let echo = bridge::deliver(
+        &key,
+        "north",
+        &north.context.client_registry,
+        &posted.body,
+        signature,
+    )
+    .await;
+    assert_eq!(echo, Err(Refused::OwnRoom));
+}
+
+#[tokio::test]
+async fn test_release_after_a_goal_reaches_clients_in_chunks() {
+    let goal = json!({"cmd": "PrintJSON", "type": "Goal", "data": [{"text": "Bob has completed their goal."}]});
+    let released: Vec<i64> = (0..1000).collect();
+    let upstream = MockUpstream::spawn(vec![
+        Script::login(vec![mock_connected()])
+            .send(vec![goal])
+            .send(vec![mock_received_items(0, &released)]),
+    ])
+    .await;
+    let mut config = test_config("test");
+    config.release_pacing = Some(ReleasePacing {
+        window: Duration::from_secs(60),
+        items_per_second: NonZeroUsize::new(500).unwrap(),
+    });
+    let apx = TestApx::start(context(&config, &upstream.url)).await;
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "")).await;
+    assert_eq!(client.expect_cmd("PrintJSON").await["type"], "Goal");
+    let first = client.expect_cmd("ReceivedItems").await;
+    let first_at = Instant::now();
+    let second = client.expect_cmd("ReceivedItems").await;
+    assert!(first_at.elapsed() >= Duration::from_millis(900));
+
+    assert_eq!(first["index"], 0);
+    assert_eq!(second["index"], 500);
+    let items: Vec<i64> = [first, second]
+        .iter()
+        .flat_map(|chunk| chunk["items"].as_array().unwrap().clone())
+        .map(|item| item["item"].as_i64().unwrap())
+        .collect();
+    assert_eq!(items, released);
+}
+
+#[tokio::test]
+async fn test_degraded_mode_still_blocks_what_it_inspects() {
+    let cheat = json!({"cmd": "PrintJSON", "type": "ItemCheat", "data": [{"text": "Cheated"}]});
+    let mut upstream = MockUpstream::spawn(vec![
+        Script::login(vec![mock_connected()])
+            .send(vec![mock_received_items(0, &[1])])
+            .send(vec![mock_received_items(1, &[2]), cheat])
+            .send(vec![mock_received_items(2, &[3])]),
+    ])
+    .await;
+    let apx = TestApx::start(context(&test_config("test"), &upstream.url)).await;
+    apx.context.load_shedding.set_mode(Mode::On);
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "")).await;
+    // Forwarded unparsed, but a message with a command APX drops is still looked into
+    for index in 0..3 {
+        let received = client.expect_cmd("ReceivedItems").await;
+        assert_eq!(received["index"], index);
+    }
+
+    client.send_cmds(say("!countdown 10")).await;
+    let notice = client.expect_cmd("PrintJSON").await;
+    assert_eq!(notice["data"][0]["text"], Notice::CountdownBlocked.text());
+    upstream.expect_no_cmd_for(100).await;
+}
+
+#[tokio::test]
+async fn test_only_storage_reads_go_upstream_before_login() {
+    let mut upstream = MockUpstream::spawn(vec![
+        Script::default()
+            .send(vec![mock_room_info()])
+            .expect("Get")
+            .expect("Connect")
+            .send(vec![mock_connected()]),
+    ])
+    .await;
+    let apx = TestApx::start(context(&test_config("test"), &upstream.url)).await;
+
+    let mut client = apx.client().await;
+    client.expect_cmd("RoomInfo").await;
+    client
+        .send_cmds(json!([
+            say("hi"),
+            {"cmd": "LocationChecks", "locations": [1]},
+            {"cmd": "Get", "keys": ["_read_race_mode"]},
+        ]))
+        .await;
+    client.expect_no_cmd_for(100).await;
+    client.send_cmds(connect("Alice", "")).await;
+    client.expect_cmd("Connected").await;
+
+    client
+        .send_cmds(json!({"cmd": "LocationChecks", "locations": [1]}))
+        .await;
+    upstream.expect_cmd("LocationChecks").await;
+}
+
+#[tokio::test]
+async fn test_invalid_json_from_client_closes_with_protocol_error() {
+    let upstream = MockUpstream::spawn(vec![Script::default().send(vec![mock_room_info()])]).await;
+    let (mut client, handler) = serve_one(context(&test_config("test"), &upstream.url)).await;
+    client.expect_cmd("RoomInfo").await;
+    client.send_text("not json").await;
+
+    let close = client.expect_close().await;
+    assert_eq!(close.map(|frame| frame.code), Some(CloseCode::Protocol));
+
+    let error = handler.await.unwrap().unwrap_err();
+    assert!(matches!(error, ProxyError::ClientProtocol(_)));
+    assert_eq!(error.log_level(), log::Level::Debug);
+    assert_eq!(error.disconnect_cause().label(), "client_error_protocol");
+}
+
+#[tokio::test]
+async fn test_adversarial_json_from_clients_closes_with_protocol_error() {
+    let nested = format!("{}{}", "[".repeat(1000), "]".repeat(1000));
+    let huge_string = json!([{"cmd": "Say", "text": "a".repeat(10 * 1024 * 1024)}]).to_string();
+    let huge_number = r#"[{"cmd": "LocationChecks", "locations": [1e400]}]"#.to_string();
+    for (text, reason) in [
+        (nested, "nested deeper than 32 levels"),
+        (huge_string, "has a string longer than 65536 bytes"),
+        (huge_number, "has a number that isn't finite"),
+    ] {
+        let upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+        let (mut client, handler) = serve_one(context(&test_config("test"), &upstream.url)).await;
+        client.login(connect("Alice", "")).await;
+        client.send_text(&text).await;
+
+        let close = client.expect_close().await;
+        assert_eq!(close.map(|frame| frame.code), Some(CloseCode::Protocol));
+        let error = handler.await.unwrap().unwrap_err();
+        assert!(
+            matches!(&error, ProxyError::ClientProtocol(message) if message.contains(reason)),
+            "{:?}",
+            error
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_text_that_isnt_utf8_is_a_protocol_error() {
+    let upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+    let (mut client, handler) = serve_one(context(&test_config("test"), &upstream.url)).await;
+    client.login(connect("Alice", "")).await;
+    tokio::io::AsyncWriteExt::write_all(client.socket(), &raw_frame(0x1, b"\xff\xfe", true))
+        .await
+        .unwrap();
+
+    let close = client.expect_close().await;
+    assert_eq!(close.map(|frame| frame.code), Some(CloseCode::Protocol));
+    let error = handler.await.unwrap().unwrap_err();
+    assert_eq!(error.label(), "client_protocol");
+    assert_eq!(error.disconnect_cause().label(), "client_error_protocol");
+}
+
+#[tokio::test]
+async fn test_messages_past_our_limit_are_not_blamed_on_the_client() {
+    let upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+    let config = Config {
+        client_max_message_size: Some(1024),
+        ..test_config("test")
+    };
+    let (mut client, handler) = serve_one(context(&config, &upstream.url)).await;
+    client.login(connect("Alice", "")).await;
+    client.send_text(&"x".repeat(2048)).await;
+
+    let close = client.expect_close().await;
+    assert_eq!(close.map(|frame| frame.code), Some(CloseCode::Size));
+    let error = handler.await.unwrap().unwrap_err();
+    assert_eq!(error.label(), "client_limit");
+    assert_eq!(error.disconnect_cause().label(), "proxy_policy");
+}
+
+#[tokio::test]
+async fn test_vanished_client_is_an_io_error() {
+    let upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+    let (mut client, handler) = serve_one(context(&test_config("test"), &upstream.url)).await;
+    client.login(connect("Alice", "")).await;
+    drop(client);
+
+    let error = handler.await.unwrap().unwrap_err();
+    assert_eq!(error.disconnect_cause().label(), "client_error_io");
+}
+
+#[tokio::test]
+async fn test_unparsable_login_from_upstream_is_an_upstream_error() {
+    let upstream = MockUpstream::spawn(vec![Script::default().send_raw(raw_frame(
+        0x1,
+        b"not json",
+        false,
+    ))])
+    .await;
+    let (_client, handler) = serve_one(context(&test_config("test"), &upstream.url)).await;
+
+    let error = handler.await.unwrap().unwrap_err();
+    assert_eq!(error.disconnect_cause().label(), "upstream_error");
+}
+
+#[tokio::test]
+async fn test_silent_upstream_times_out_waiting_for_room_info() {
+    let upstream = MockUpstream::spawn(vec![Script::default()]).await;
+    let config = Config {
+        room_info_timeout: Duration::from_millis(200),
+        ..test_config("test")
+    };
+    let context = context(&config, &upstream.url);
+    let servers = context.upstream.clone();
+    let (mut client, handler) = serve_one(context).await;
+
+    let notice = client.expect_cmd("PrintJSON").await;
+    assert_eq!(notice, Notice::UpstreamNotResponding.to_print_json());
+    assert_eq!(client.expect_close().await.unwrap().code, CloseCode::Again);
+
+    let error = handler.await.unwrap().unwrap_err();
+    assert!(matches!(error, ProxyError::UpstreamTimeout(_)), "{}", error);
+    assert_eq!(error.disconnect_cause(), DisconnectCause::UpstreamTimeout);
+    // A single silent connection isn't enough to call upstream unresponsive
+    assert!(!servers.is_unresponsive());
+}
+
+#[tokio::test]
+async fn test_prelogin_budget_is_proxy_policy() {
+    let upstream = MockUpstream::spawn(vec![Script::default().send(vec![mock_room_info()])]).await;
+    let config = Config {
+        prelogin_limits: PreLoginLimits {
+            max_messages: 1,
+            ..PreLoginLimits::default()
+        },
+        ..test_config("test")
+    };
+    let (mut client, handler) = serve_one(context(&config, &upstream.url)).await;
+    client.expect_cmd("RoomInfo").await;
+    client.send_cmds(say("hi")).await;
+    client.send_cmds(say("hi")).await;
+
+    let close = client.expect_close().await;
+    assert_eq!(close.map(|frame| frame.code), Some(CloseCode::Policy));
+    let error = handler.await.unwrap().unwrap_err();
+    assert_eq!(error.disconnect_cause().label(), "proxy_policy");
+}
+
+#[tokio::test]
+async fn test_forced_upstream_reconnect_keeps_items_flowing() {
+    let login = || Script::login(vec![mock_connected(), mock_received_items(0, &[1])]);
+    let upstream = MockUpstream::spawn(vec![
+        login(),
+        login().send(vec![mock_received_items(1, &[2])]),
+    ])
+    .await;
+    let apx = TestApx::start(context(&test_config("test"), &upstream.url)).await;
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "")).await;
+    client.expect_cmd("ReceivedItems").await;
+
+    let controls = apx
+        .context
+        .client_registry
+        .controls_for_slot(SlotId(1))
+        .await;
+    assert_eq!(controls.len(), 1);
+    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+    controls[0]
+        .1
+        .send(ClientControl::ReconnectUpstream(reply_tx))
+        .await
+        .unwrap();
+    assert!(reply_rx.await.unwrap().is_ok());
+
+    // The new Connected is swallowed, items resync and keep flowing on the same socket
+    let resync = client.expect_cmd("ReceivedItems").await;
+    assert_eq!(resync["index"], 0);
+    let items = client.expect_cmd("ReceivedItems").await;
+    assert_eq!(items["index"], 1);
+    assert_eq!(items["items"][0]["item"], 2);
+}
+
+#[tokio::test]
+async fn test_payload_bytes_are_counted_uncompressed() {
+    metrics::init_metrics(&metrics::Registry::new("payload_bytes"), false);
+    let mut upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+    let apx = TestApx::start(context(&test_config("payload_bytes"), &upstream.url)).await;
+
+    let mut client = TestClient::connect_deflate(apx.addr).await;
+    client.login(connect("Alice", "")).await;
+    // Deflates to a small fraction of its length
+    let chat = say(&"a".repeat(2000));
+    client.send_cmds(chat.clone()).await;
+    upstream.expect_cmd("Say").await;
+
+    let expected = (packet(vec![connect("Alice", "")]).len() + packet(vec![chat]).len()) as u64;
+    let counted =
+        || metrics::tests::payload_bytes("payload_bytes", "client_to_upstream", "deflate");
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while counted() < expected {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(counted(), expected);
+    assert_eq!(
+        metrics::tests::payload_bytes("payload_bytes", "client_to_upstream", "none"),
+        0
+    );
+}
+
+async fn expect_motd(client: &mut TestClient) {
+    for line in ["Welcome", "Have fun"] {
+        let print = client.expect_cmd("PrintJSON").await;
+        assert_eq!(print["data"][0]["text"], line);
+    }
+}
+
+#[tokio::test]
+async fn test_motd_is_sent_once_per_login() {
+    let upstream = MockUpstream::spawn(vec![
+        Script::login(vec![mock_connected()]),
+        Script::login(vec![mock_connected(), mock_received_items(0, &[1])])
+            .expect("Connect")
+            .send(vec![mock_connected()]),
+    ])
+    .await;
+    let config = Config {
+        motd: Some("Welcome\nHave fun".to_string()),
+        ..test_config("test")
+    };
+    let apx = TestApx::start(context(&config, &upstream.url)).await;
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "")).await;
+    expect_motd(&mut client).await;
+
+    // The new upstream connection logs in again, the client doesn't
+    let controls = apx
+        .context
+        .client_registry
+        .controls_for_slot(SlotId(1))
+        .await;
+    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+    controls[0]
+        .1
+        .send(ClientControl::ReconnectUpstream(reply_tx))
+        .await
+        .unwrap();
+    assert!(reply_rx.await.unwrap().is_ok());
+    client.expect_cmd("ReceivedItems").await;
+    client.expect_no_cmd_for(100).await;
+
+    client.send_cmds(connect("Alice", "")).await;
+    client.expect_cmd("Connected").await;
+    expect_motd(&mut client).await;
+    client.expect_no_cmd_for(100).await;
+}
+
+#[tokio::test]
+async fn test_upstream_reconnects_wait_for_a_free_connection() {
+    let mut upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+    let config = Config {
+        max_upstream_connections: Some(1),
+        ..test_config("test")
+    };
+    let apx = TestApx::start(context(&config, &upstream.url)).await;
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "")).await;
+
+    // The only connection is the client's own, which stays open until the new one is up
+    let controls = apx
+        .context
+        .client_registry
+        .controls_for_slot(SlotId(1))
+        .await;
+    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+    controls[0]
+        .1
+        .send(ClientControl::ReconnectUpstream(reply_tx))
+        .await
+        .unwrap();
+    assert!(matches!(
+        reply_rx.await.unwrap(),
+        Err(ReconnectError::Failed(e)) if e.contains("limit")
+    ));
+    assert_eq!(apx.context.upstream_limiter.live_connections(), 1);
+
+    client.send_cmds(json!({"cmd": "Sync"})).await;
+    upstream.expect_cmd("Sync").await;
+}
+
+#[tokio::test]
+async fn test_kicked_clients_are_closed() {
+    let upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+    let context = context(&test_config("test"), &upstream.url);
+    let client_registry = context.client_registry.clone();
+    let (mut client, handler) = serve_one(context).await;
+    client.login(connect("Alice", "")).await;
+
+    let client_id = client_registry.clients().await[0].client_id;
+    assert!(admin_ops::kick(&client_registry, client_id).await);
+    let close = client.expect_close().await;
+    assert_eq!(close.map(|frame| frame.code), Some(CloseCode::Policy));
+    assert_eq!(
+        handler.await.unwrap().unwrap(),
+        DisconnectCause::ProxyPolicy
+    );
+}
+
+async fn mark_ready(client: &mut TestClient) {
+    client.send_cmds(say("!apx ready")).await;
+    let reply = client.expect_cmd("PrintJSON").await;
+    assert_eq!(
+        reply["data"][0]["text"],
+        "You're ready. The race starts once everyone is."
+    );
+}
+
+#[tokio::test]
+async fn test_start_waits_for_every_ready_player_to_be_there() {
+    let mut upstream = MockUpstream::spawn(vec![
+        Script::login(vec![connected_to(1)]),
+        Script::login(vec![connected_to(2)]),
+        Script::login(vec![connected_to(2)]),
+    ])
+    .await;
+    let config = Config {
+        start_countdown: true,
+        ..test_config("test")
+    };
+    let context = context(&config, &upstream.url);
+    context.slot_names.write().await.extend([
+        (SlotId(1), "Alice".to_string()),
+        (SlotId(2), "Bob".to_string()),
+    ]);
+    let apx = TestApx::start(context).await;
+    let start = || {
+        admin_ops::start(
+            &apx.context.client_registry,
+            &apx.context.slot_names,
+            &apx.context.ready,
+            &apx.context.events,
+            &config,
+        )
+    };
+
+    let mut alice = apx.client().await;
+    alice.login(connect("Alice", "")).await;
+    mark_ready(&mut alice).await;
+    assert_eq!(
+        start().await.unwrap_err(),
+        admin_ops::StartRefused::WaitingOn(vec![SlotId(2)])
+    );
+
+    // Bob readies, then leaves before the start
+    let mut bob = apx.client().await;
+    bob.login(connect("Bob", "")).await;
+    mark_ready(&mut bob).await;
+    drop(bob);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while apx.context.client_registry.clients().await.len() > 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        start().await.unwrap_err(),
+        admin_ops::StartRefused::WaitingOn(vec![SlotId(2)])
+    );
+
+    // Still ready once back
+    let mut bob = apx.client().await;
+    bob.login(connect("Bob", "")).await;
+    let started = start().await.unwrap();
+    assert_eq!(started.announced_to, 2);
+    for client in [&mut alice, &mut bob] {
+        let announcement = client.expect_cmd("PrintJSON").await;
+        assert_eq!(announcement["data"][0]["text"], config.start_announcement);
+    }
+    let client_id = apx.context.client_registry.clients().await[0].client_id;
+    assert_eq!(started.countdown_by, Some(client_id));
+    let countdown = upstream.expect_cmd("Say").await;
+    assert_eq!(countdown["text"], "!countdown 10");
+    assert!(apx.context.ready.slots().is_empty());
+}
+
+#[tokio::test]
+async fn test_client_frames_after_close_never_reach_upstream() {
+    let mut upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+    let (mut client, handler) = serve_one(context(&test_config("test"), &upstream.url)).await;
+    client.login(connect("Alice", "")).await;
+
+    tokio::io::AsyncWriteExt::write_all(client.socket(), &close_then_checks(true))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        handler.await.unwrap().unwrap(),
+        DisconnectCause::ClientClose
+    );
+    let received = upstream.messages_until_closed().await;
+    assert!(
+        matches!(received.as_slice(), [Message::Close(_)]),
+        "{:?}",
+        received
+    );
+}
+
+#[tokio::test]
+async fn test_upstream_frames_after_close_never_reach_the_client() {
+    let upstream = MockUpstream::spawn(vec![
+        Script::default()
+            .send(vec![mock_room_info()])
+            .send_raw(close_then_checks(false)),
+    ])
+    .await;
+    let (mut client, handler) = serve_one(context(&test_config("test"), &upstream.url)).await;
+    client.expect_cmd("RoomInfo").await;
+    assert_eq!(
+        handler.await.unwrap().unwrap(),
+        DisconnectCause::UpstreamClose
+    );
+
+    let received = client.messages_until_closed().await;
+    assert!(
+        matches!(received.as_slice(), [Ok(Message::Close(_))]),
+        "{:?}",
+        received
+    );
+}
+
+/// Slot 1 receives the item at location 100 of slot 2, slot 2 the one at location 200 of slot 1
+fn hints_of_slot_1() -> Value {
+    let hint = |receiving: i64, finding: i64, location: i64| {
+        json!({
+            "receiving_player": receiving,
+            "finding_player": finding,
+            "location": location,
+            "item": 1,
+            "found": false,
+            "entrance": "",
+            "item_flags": 0,
+            "status": 0,
+            "class": "Hint",
+        })
+    };
+    json!({
+        "cmd": "Retrieved",
+        "keys": {"_read_hints_0_1": [hint(1, 2, 100), hint(2, 1, 200)]},
+    })
+}
+
+fn update_hint(player: i64, location: i64) -> Value {
+    json!({"cmd": "UpdateHint", "player": player, "location": location, "status": 20})
+}
+
+#[tokio::test]
+async fn test_hint_updates_are_limited_to_the_receiving_player() {
+    let mut upstream = MockUpstream::spawn(vec![Script::login(vec![
+        mock_connected(),
+        hints_of_slot_1(),
+    ])])
+    .await;
+    let apx = TestApx::start(context(&test_config("test"), &upstream.url)).await;
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "")).await;
+    client.expect_cmd("Retrieved").await;
+
+    client.send_cmds(update_hint(1, 200)).await;
+    let notice = client.expect_cmd("PrintJSON").await;
+    assert_eq!(notice["data"][0]["text"], Notice::HintUpdateBlocked.text());
+
+    client.send_cmds(update_hint(2, 100)).await;
+    let forwarded = upstream.expect_cmd("UpdateHint").await;
+    assert_eq!(forwarded["location"], 100);
+    upstream.expect_no_cmd_for(100).await;
+}
+
+#[tokio::test]
+async fn test_cross_slot_hint_updates_can_be_allowed() {
+    let mut upstream = MockUpstream::spawn(vec![Script::login(vec![
+        mock_connected(),
+        hints_of_slot_1(),
+    ])])
+    .await;
+    let config = Config {
+        allow_cross_slot_hint_updates: true,
+        ..test_config("test")
+    };
+    let apx = TestApx::start(context(&config, &upstream.url)).await;
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "")).await;
+    client.expect_cmd("Retrieved").await;
+    client.send_cmds(update_hint(1, 200)).await;
+    upstream.expect_cmd("UpdateHint").await;
+    client.expect_no_cmd_for(100).await;
+}
+
+#[tokio::test]
+async fn test_reloaded_settings_apply_to_new_connections() {
+    let mut upstream = MockUpstream::spawn(vec![
+        Script::login(vec![mock_connected(), hints_of_slot_1()]),
+        Script::login(vec![mock_connected(), hints_of_slot_1()]),
+    ])
+    .await;
+    let apx = TestApx::start(context(&test_config("test"), &upstream.url)).await;
+
+    let mut before = apx.client().await;
+    before.login(connect("Alice", "")).await;
+    before.expect_cmd("Retrieved").await;
+
+    let reloader = Reloader::new(
+        test_vars(&[]),
+        apx.context.live.clone(),
+        apx.context.motd.clone(),
+    );
+    let report = reloader
+        .apply(test_vars(&[("ALLOW_CROSS_SLOT_HINT_UPDATES", "true")]))
+        .await
+        .unwrap();
+    assert_eq!(report.applied, ["ALLOW_CROSS_SLOT_HINT_UPDATES"]);
+
+    let mut after = apx.client().await;
+    after.login(connect("Alice", "")).await;
+    after.expect_cmd("Retrieved").await;
+    after.send_cmds(update_hint(1, 200)).await;
+    upstream.expect_cmd("UpdateHint").await;
+
+    // Kept the settings it started with
+    before.send_cmds(update_hint(1, 200)).await;
+    let notice = before.expect_cmd("PrintJSON").await;
+    assert_eq!(notice["data"][0]["text"], Notice::HintUpdateBlocked.text());
+    upstream.expect_no_cmd_for(100).await;
+}
+
+fn tracker(name: &str) -> Value {
+    let mut connect = connect(name, "");
+    connect["tags"] = json!(["Tracker"]);
+    connect
+}
+
+fn mirrored_config() -> Config {
+    Config {
+        mirror_trackers: true,
+        ..test_config("test")
+    }
+}
+
+fn print_json(text: &str) -> Value {
+    json!({"cmd": "PrintJSON", "data": [{"text": text}]})
+}
+
+#[tokio::test]
+async fn test_trackers_of_a_slot_share_one_upstream_connection() {
+    let upstream = MockUpstream::spawn(vec![
+        Script::login(vec![mock_connected()]),
+        // The shared connection: syncs once connected, then again for each tracker attaching
+        Script::login(vec![mock_connected()])
+            .expect("Sync")
+            .expect("Sync")
+            .send(vec![mock_received_items(0, &[1])])
+            .expect("Sync")
+            .send(vec![mock_received_items(0, &[1])])
+            .expect("Get")
+            .expect("Get")
+            .send(vec![
+                json!({"cmd": "Retrieved", "keys": {"b": 2}}),
+                json!({"cmd": "Retrieved", "keys": {"a": 1}}),
+                print_json("hello"),
+            ]),
+        Script::login(vec![mock_connected()]),
+    ])
+    .await;
+    let apx = TestApx::start(context(&mirrored_config(), &upstream.url)).await;
+
+    let mut first = apx.client().await;
+    first.login(tracker("Alice")).await;
+    first.expect_cmd("ReceivedItems").await;
+
+    let mut second = apx.client().await;
+    second.login(tracker("Alice")).await;
+    second.expect_cmd("ReceivedItems").await;
+    first.expect_cmd("ReceivedItems").await;
+
+    first
+        .send_cmds(json!([{"cmd": "Get", "keys": ["a"]}]))
+        .await;
+    second
+        .send_cmds(json!([{"cmd": "Get", "keys": ["b"]}]))
+        .await;
+
+    // Each tracker only gets the reply to its own Get, everything else goes to both
+    let retrieved = first.expect_cmd("Retrieved").await;
+    assert_eq!(retrieved["keys"], json!({"a": 1}));
+    first.expect_cmd("PrintJSON").await;
+    let retrieved = second.expect_cmd("Retrieved").await;
+    assert_eq!(retrieved["keys"], json!({"b": 2}));
+    second.expect_cmd("PrintJSON").await;
+}
+
+#[tokio::test]
+async fn test_shared_tracker_connection_reconnects() {
+    let upstream = MockUpstream::spawn(vec![
+        Script::login(vec![mock_connected()]),
+        Script::login(vec![mock_connected()])
+            .expect("Sync")
+            .expect("Sync")
+            .send(vec![mock_received_items(0, &[1])])
+            .close(),
+        Script::login(vec![mock_connected()])
+            .expect("Sync")
+            .send(vec![print_json("back")]),
+    ])
+    .await;
+    let apx = TestApx::start(context(&mirrored_config(), &upstream.url)).await;
+
+    let mut client = apx.client().await;
+    client.login(tracker("Alice")).await;
+    client.expect_cmd("ReceivedItems").await;
+
+    // The tracker's own socket stays open through the upstream going away
+    let print = client.expect_cmd("PrintJSON").await;
+    assert_eq!(print["data"][0]["text"], "back");
+}
+
+#[tokio::test]
+async fn test_trackers_on_a_shared_connection_only_read() {
+    let mut upstream = MockUpstream::spawn(vec![
+        Script::login(vec![mock_connected()]),
+        Script::login(vec![mock_connected()])
+            .expect("Sync")
+            .expect("Sync")
+            .send(vec![mock_received_items(0, &[1])]),
+    ])
+    .await;
+    let apx = TestApx::start(context(&mirrored_config(), &upstream.url)).await;
+
+    let mut client = apx.client().await;
+    client.login(tracker("Alice")).await;
+    client.expect_cmd("ReceivedItems").await;
+
+    client
+        .send_cmds(json!([
+            {"cmd": "Set", "key": "k", "operations": [{"operation": "replace", "value": 1}]},
+            {"cmd": "Say", "text": "hello"},
+            {"cmd": "Get", "keys": ["a"]},
+        ]))
+        .await;
+    let notice = client.expect_cmd("PrintJSON").await;
+    assert_eq!(notice["data"][0]["text"], Notice::TrackerReadOnly.text());
+    upstream.expect_cmd("Get").await;
+    upstream.expect_no_cmd_for(100).await;
+}
+
+#[tokio::test]
+async fn test_sessions_are_warned_then_closed_through_a_reconnect() {
+    let upstream = MockUpstream::spawn(vec![
+        Script::login(vec![mock_connected()]),
+        Script::login(vec![mock_connected()]),
+    ])
+    .await;
+    let config = Config {
+        session_limits: SessionLimits {
+            max_duration: Some(Duration::from_millis(1500)),
+            close_at: None,
+            warnings: vec![Duration::from_millis(1000), Duration::from_millis(500)],
+        },
+        ..test_config("test")
+    };
+    let apx = TestApx::start(context(&config, &upstream.url)).await;
+
+    let mut client = apx.client().await;
+    let logged_in_at = Instant::now();
+    client.login(connect("Alice", "")).await;
+    let clients = apx.context.client_registry.clients().await;
+    assert!(
+        clients[0]
+            .session_remaining_secs
+            .is_some_and(|secs| secs <= 1)
+    );
+
+    let warning = client.expect_cmd("PrintJSON").await;
+    assert!(
+        warning["data"][0]["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("Your session ends in")
+    );
+
+    // A new upstream connection doesn't give the session more time
+    let controls = apx
+        .context
+        .client_registry
+        .controls_for_slot(SlotId(1))
+        .await;
+    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+    controls[0]
+        .1
+        .send(ClientControl::ReconnectUpstream(reply_tx))
+        .await
+        .unwrap();
+    assert!(reply_rx.await.unwrap().is_ok());
+
+    client.expect_cmd("PrintJSON").await;
+    let close = client.expect_close().await.unwrap();
+    assert_eq!(close.code, CloseCode::Normal);
+    assert_eq!(close.reason, "Maximum session duration reached");
+    assert!(logged_in_at.elapsed() < Duration::from_millis(2500));
+}
+
+#[tokio::test]
+async fn test_connections_are_refused_past_closing_time() {
+    let config = Config {
+        session_limits: SessionLimits {
+            close_at: Some(chrono::Utc::now() - chrono::Duration::minutes(1)),
+            ..SessionLimits::default()
+        },
+        ..test_config("test")
+    };
+    let (mut client, handler) = serve_one(context(&config, "ws://127.0.0.1:1")).await;
+
+    client.expect_cmd("PrintJSON").await;
+    let close = client.expect_close().await.unwrap();
+    assert_eq!(close.code, CloseCode::Normal);
+    assert_eq!(close.reason, "The room is closed");
+    assert_eq!(
+        handler.await.unwrap().unwrap(),
+        DisconnectCause::ProxyPolicy
+    );
+}
+
+#[tokio::test]
+async fn test_missing_locations_are_answered_by_the_proxy() {
+    let mut connected = mock_connected();
+    connected["missing_locations"] = json!([1, 2, 3]);
+    connected["checked_locations"] = json!([4]);
+    let mut upstream = MockUpstream::spawn(vec![
+        Script::login(vec![connected])
+            .expect("LocationChecks")
+            .send(vec![json!({"cmd": "RoomUpdate", "checked_locations": [1]})]),
+    ])
+    .await;
+    let mut context = context(&test_config("test"), &upstream.url);
+    context.datapackage_cache = Arc::new(
+        DataPackageCache::from_response(json!({
+            "cmd": "DataPackage",
+            "data": {"games": {"Test": {"location_name_to_id": {"Chest": 2, "Pot": 4}}}},
+        }))
+        .unwrap(),
+    );
+    let apx = TestApx::start(context).await;
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "")).await;
+    client
+        .send_cmds(json!({"cmd": "LocationChecks", "locations": [1]}))
+        .await;
+    client.expect_cmd("RoomUpdate").await;
+
+    client.send_cmds(say("!apx missing 5")).await;
+    let mut text = Vec::new();
+    for _ in 0..3 {
+        let line = client.expect_cmd("PrintJSON").await;
+        text.push(line["data"][0]["text"].as_str().unwrap().to_string());
+    }
+    assert_eq!(
+        text,
+        [
+            "You have 2 locations left to check.",
+            "  Chest (2)",
+            "  Location 3",
+        ]
+    );
+    // Nothing went upstream for it
+    upstream.expect_no_cmd_for(100).await;
+}
+
+/// An APX in front of another, the inner one holding the passwords
+async fn chained(outer: Config, upstream: &MockUpstream) -> (TestApx, TestApx) {
+    let inner = context(&test_config("test"), &upstream.url);
+    inner
+        .passwords
+        .write()
+        .await
+        .insert(SlotId(1), "hunter2".to_string());
+    let inner = TestApx::start(inner).await;
+    let outer = TestApx::start(context(&outer, &format!("ws://{}", inner.addr))).await;
+    (outer, inner)
+}
+
+#[tokio::test]
+async fn test_chained_proxies_leave_the_login_to_the_inner_one() {
+    let upstream = MockUpstream::spawn(vec![
+        Script::login(vec![mock_connected()]),
+        Script::login(vec![mock_connected()]),
+    ])
+    .await;
+    let config = Config {
+        chain_mode: ChainMode::Passthrough,
+        ..test_config("test")
+    };
+    let (outer, _inner) = chained(config, &upstream).await;
+
+    let mut client = outer.client().await;
+    let room_info = client.expect_cmd("RoomInfo").await;
+    assert_eq!(room_info["password"], true);
+    client.send_cmds(connect("Alice", "wrong")).await;
+    let refused = client.expect_cmd("ConnectionRefused").await;
+    assert_eq!(refused["errors"], json!(["InvalidPassword"]));
+
+    // Only the inner proxy knows the password, so it went through the outer one untouched
+    let mut client = outer.client().await;
+    let connected = client.login(connect("Alice", "hunter2")).await;
+    assert_eq!(connected["slot"], 1);
+}
+
+#[tokio::test]
+async fn test_chained_proxies_are_refused_by_default() {
+    let upstream = MockUpstream::spawn(vec![Script::default()]).await;
+    let inner = TestApx::start(context(&test_config("test"), &upstream.url)).await;
+    let (_client, handler) = serve_one(context(
+        &test_config("test"),
+        &format!("ws://{}", inner.addr),
+    ))
+    .await;
+
+    let error = handler.await.unwrap().unwrap_err();
+    assert!(matches!(error, ProxyError::UpstreamProtocol(e) if e == "Upstream is another APX"));
+}
+
+#[tokio::test]
+async fn test_proxy_looping_back_to_itself_is_refused() {
+    let upstream = MockUpstream::spawn(vec![]).await;
+    let apx = TestApx::start(context(&test_config("test"), &upstream.url)).await;
+    // Same proxy, pointed at its own listener
+    let mut looped = apx.context.clone();
+    looped.upstream = Arc::new(UpstreamServers::new(
+        apx.addr.to_string().parse().unwrap(),
+        Vec::new(),
+        Duration::ZERO,
+    ));
+    let (_client, handler) = serve_one(looped).await;
+
+    let error = handler.await.unwrap().unwrap_err();
+    assert!(matches!(error, ProxyError::UpstreamProtocol(e) if e.contains("loops back")));
+}
+
+#[tokio::test]
+async fn test_check_to_item_latency_is_tracked_per_connection() {
+    let upstream = MockUpstream::spawn(vec![
+        Script::login(vec![mock_connected()])
+            .expect("LocationChecks")
+            .send(vec![mock_received_items(0, &[5])]),
+    ])
+    .await;
+    let apx = TestApx::start(context(&test_config("test"), &upstream.url)).await;
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "")).await;
+    client
+        .send_cmds(json!({"cmd": "LocationChecks", "locations": [0]}))
+        .await;
+    client.expect_cmd("ReceivedItems").await;
+
+    let clients = apx.context.client_registry.clients().await;
+    assert_eq!(clients[0].check_to_item.as_ref().unwrap().samples, 1);
+}
+
+#[tokio::test]
+async fn test_invalid_packets_keep_the_connection_until_the_limit() {
+    let mut upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+    let config = Config {
+        invalid_packet_limit: 2,
+        ..test_config("test")
+    };
+    let apx = TestApx::start(context(&config, &upstream.url)).await;
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "")).await;
+    let unreadable = json!({"cmd": "LocationChecks", "locations": "all of them"});
+    for _ in 0..2 {
+        client.send_cmds(unreadable.clone()).await;
+        let invalid = client.expect_cmd("InvalidPacket").await;
+        assert_eq!(invalid["type"], "arguments");
+        assert_eq!(invalid["original_cmd"], "LocationChecks");
+    }
+    client.send_cmds(json!({"cmd": "Sync"})).await;
+    upstream.expect_cmd("Sync").await;
+
+    client.send_cmds(unreadable).await;
+    let close = client.expect_close().await.unwrap();
+    assert_eq!(close.code, CloseCode::Protocol);
+    upstream.expect_no_cmd_for(100).await;
+}
+
+#[tokio::test]
+async fn test_repeated_invalid_packets_are_answered_once_per_cooldown() {
+    let mut upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+    let config = Config {
+        denial_cooldown: Duration::from_secs(60),
+        ..test_config("test")
+    };
+    let apx = TestApx::start(context(&config, &upstream.url)).await;
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "")).await;
+    let unreadable = json!({"cmd": "LocationChecks", "locations": "all of them"});
+    client.send_cmds(unreadable.clone()).await;
+    client.expect_cmd("InvalidPacket").await;
+    for _ in 0..3 {
+        client.send_cmds(unreadable.clone()).await;
+    }
+    client.expect_no_cmd_for(100).await;
+
+    // The suppressed ones still count towards INVALID_PACKET_LIMIT, this is under it
+    client.send_cmds(json!({"cmd": "Sync"})).await;
+    upstream.expect_cmd("Sync").await;
+}
+
+#[tokio::test]
+async fn test_connections_over_the_memory_cap_are_closed_alone() {
+    let mut upstream = MockUpstream::spawn(vec![
+        Script::login(vec![mock_connected()]),
+        Script::login(vec![mock_connected()]),
+    ])
+    .await;
+    let cap = 20 * latency::OUTSTANDING_CHECK_BYTES;
+    let config = Config {
+        per_connection_memory_cap: Some(cap),
+        ..test_config("test")
+    };
+    let apx = TestApx::start(context(&config, &upstream.url)).await;
+
+    let mut bob = apx.client().await;
+    bob.login(connect("Bob", "")).await;
+    bob.send_cmds(json!({"cmd": "LocationChecks", "locations": [1, 2]}))
+        .await;
+    upstream.expect_cmd("LocationChecks").await;
+
+    // None of these checks ever get their item
+    let mut alice = apx.client().await;
+    alice.login(connect("Alice", "")).await;
+    let locations: Vec<i64> = (0..100).collect();
+    alice
+        .send_cmds(json!({"cmd": "LocationChecks", "locations": locations}))
+        .await;
+    let close = alice.expect_close().await.unwrap();
+    assert_eq!(close.code, CloseCode::Policy);
+    assert_eq!(close.reason, "Memory budget exceeded");
+
+    let clients = apx.context.client_registry.clients().await;
+    assert_eq!(clients.len(), 1);
+    assert_eq!(
+        clients[0].memory.outstanding_checks_bytes,
+        2 * latency::OUTSTANDING_CHECK_BYTES
+    );
+    assert_eq!(clients[0].memory.cap_bytes, Some(cap));
+    bob.send_cmds(say("!apx status")).await;
+    bob.expect_cmd("PrintJSON").await;
+}
+
+fn capped_config(max: usize) -> Config {
+    Config {
+        max_logged_in_clients: Some(max),
+        ..test_config("test")
+    }
+}
+
+/// Sends the Connect of a login that's going to be queued
+async fn queue_login(apx: &TestApx, name: &str, position: usize) -> TestClient {
+    let mut client = apx.client().await;
+    client.expect_cmd("RoomInfo").await;
+    client.send_cmds(connect(name, "")).await;
+    let notice = client.expect_cmd("PrintJSON").await;
+    assert_eq!(notice, login_queue::position_notice(position));
+    client
+}
+
+#[tokio::test]
+async fn test_logins_past_capacity_wait_in_order() {
+    let upstream = MockUpstream::spawn(
+        (0..4)
+            .map(|_| Script::login(vec![mock_connected()]))
+            .collect(),
+    )
+    .await;
+    let apx = TestApx::start(context(&capped_config(1), &upstream.url)).await;
+
+    let mut alice = apx.client().await;
+    alice.login(connect("Alice", "")).await;
+    let mut bob = queue_login(&apx, "Bob", 1).await;
+    let mut carol = queue_login(&apx, "Carol", 2).await;
+
+    // Trackers don't take a seat
+    let mut tracker_client = apx.client().await;
+    tracker_client.login(tracker("Alice")).await;
+
+    let waiting: Vec<String> = apx
+        .context
+        .login_queue
+        .waiting()
+        .into_iter()
+        .map(|queued| queued.name)
+        .collect();
+    assert_eq!(waiting, ["Bob", "Carol"]);
+
+    drop(alice);
+    bob.expect_cmd("Connected").await;
+    let notice = carol.expect_cmd("PrintJSON").await;
+    assert_eq!(notice, login_queue::position_notice(1));
+    carol.expect_no_cmd_for(100).await;
+}
+
+#[tokio::test]
+async fn test_abandoned_and_expired_logins_leave_the_queue() {
+    let upstream = MockUpstream::spawn(
+        (0..3)
+            .map(|_| Script::login(vec![mock_connected()]))
+            .collect(),
+    )
+    .await;
+    let config = Config {
+        login_queue_max_wait: Duration::from_millis(300),
+        ..capped_config(1)
+    };
+    let apx = TestApx::start(context(&config, &upstream.url)).await;
+
+    let mut alice = apx.client().await;
+    alice.login(connect("Alice", "")).await;
+    drop(queue_login(&apx, "Bob", 1).await);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !apx.context.login_queue.waiting().is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .expect("Bob never left the queue");
+
+    let mut carol = queue_login(&apx, "Carol", 1).await;
+    let refused = carol.expect_cmd("PrintJSON").await;
+    assert_eq!(refused, Notice::RoomFullRefused.to_print_json());
+    carol.expect_close().await;
+    assert!(apx.context.login_queue.waiting().is_empty());
+}
+
+#[tokio::test]
+async fn test_browsers_are_only_let_in_from_allowed_origins() {
+    let upstream = MockUpstream::spawn(vec![
+        Script::login(vec![mock_connected()]),
+        Script::login(vec![mock_connected()]),
+    ])
+    .await;
+    let config = Config {
+        allowed_origins: "https://*.archipelago.gg".parse().ok(),
+        ..test_config("test")
+    };
+    let apx = TestApx::start(context(&config, &upstream.url)).await;
+
+    let denied = TestClient::connect_from_origin(apx.addr, "https://archipelago.gg.example").await;
+    match denied {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 403),
+        other => panic!("expected a 403, got {:?}", other.map(|_| ())),
+    }
+
+    let mut browser = TestClient::connect_from_origin(apx.addr, "https://tracker.archipelago.gg")
+        .await
+        .unwrap();
+    browser.login(connect("Alice", "")).await;
+    // Clients outside of browsers don't send an Origin
+    let mut desktop = apx.client().await;
+    desktop.login(connect("Alice", "")).await;
+
+    let mut origins: Vec<Option<String>> = apx
+        .context
+        .client_registry
+        .clients()
+        .await
+        .into_iter()
+        .map(|client| client.origin)
+        .collect();
+    origins.sort();
+    assert_eq!(
+        origins,
+        [None, Some("https://tracker.archipelago.gg".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn test_scheduled_messages_reach_logged_in_clients() {
+    let upstream = MockUpstream::spawn(vec![Script::login(vec![mock_connected()])]).await;
+    let config = test_config("test");
+    let apx = TestApx::start(context(&config, &upstream.url)).await;
+    let announcements: Announcements =
+        r#"[{"repeat": {"every_seconds": 1}, "text": "Remember to hydrate", "color": "cyan"}]"#
+            .parse()
+            .unwrap();
+    let messages = Arc::new(ScheduledMessages::new(announcements.0, Utc::now()));
+    let task = tokio::spawn(scheduled_messages::run(
+        messages,
+        apx.context.client_registry.clone(),
+    ));
+
+    let mut client = apx.client().await;
+    client.login(connect("Alice", "")).await;
+    let print = client.expect_cmd("PrintJSON").await;
+    assert_eq!(print["data"][0]["text"], "Remember to hydrate");
+    assert_eq!(print["data"][0]["color"], "cyan");
+    task.abort();
+}
+
+#[tokio::test]
+async fn test_load_test_drives_many_clients() {
+    let clients = 10;
+    let upstream = MockUpstream::spawn(
+        (0..clients)
+            .map(|_| Script::login(vec![mock_connected()]))
+            .collect(),
+    )
+    .await;
+    let apx = TestApx::start(context(&test_config("test"), &upstream.url)).await;
+
+    let options = loadtest::Options {
+        target: format!("ws://{}", apx.addr),
+        clients,
+        names: "Alice".to_string(),
+        rate: 20.0,
+        duration: Duration::from_millis(500),
+        ..Default::default()
+    };
+    let report = loadtest::load_test(&options).await;
+    assert_eq!(report.logged_in, clients);
+    assert_eq!(report.failed, 0);
+    assert_eq!(report.handshake.samples, clients);
+    assert!(report.sent.bounces > 0);
+    assert!(report.round_trip.samples > 0);
+}
@@ -1,15 +1,85 @@
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use rustls_acme::caches::DirCache;
+use rustls_acme::{AcmeConfig, is_tls_alpn_challenge};
 use rustls_pki_types::pem::PemObject;
 use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use serde::Serialize;
 use std::fs::File;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::LazyConfigAcceptor;
 use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::server::Acceptor;
+use tokio_rustls::server::TlsStream;
+
+use crate::diagnostics;
+use crate::metrics;
+
+const WARNING_DAYS: i64 = 14;
+const CRITICAL_DAYS: i64 = 3;
+const REMINDER_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// When the certificate loaded from disk stops being valid. Certificates issued through ACME
+/// are renewed on their own and aren't tracked.
+static CERT_EXPIRY: Mutex<Option<DateTime<Utc>>> = Mutex::new(None);
+
+/// In order of severity
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpiryStatus {
+    Ok,
+    Warning,
+    Critical,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct CertExpiry {
+    pub expires_at: DateTime<Utc>,
+    /// Whole days left, negative once expired
+    pub days_remaining: i64,
+    pub status: ExpiryStatus,
+}
+
+impl CertExpiry {
+    pub fn at(expires_at: DateTime<Utc>, now: DateTime<Utc>) -> Self {
+        let days_remaining = (expires_at - now).num_days();
+        let status = if expires_at <= now || days_remaining < CRITICAL_DAYS {
+            ExpiryStatus::Critical
+        } else if days_remaining < WARNING_DAYS {
+            ExpiryStatus::Warning
+        } else {
+            ExpiryStatus::Ok
+        };
+        Self {
+            expires_at,
+            days_remaining,
+            status,
+        }
+    }
+}
+
+pub fn cert_expiry() -> Option<CertExpiry> {
+    let expires_at = (*CERT_EXPIRY.lock().unwrap())?;
+    Some(CertExpiry::at(expires_at, Utc::now()))
+}
+
+/// The leaf certificate's notAfter
+fn leaf_expiry(cert: &CertificateDer) -> Result<DateTime<Utc>> {
+    let (_, parsed) =
+        x509_parser::parse_x509_certificate(cert).context("Failed to parse the certificate")?;
+    let not_after = parsed.validity().not_after.timestamp();
+    DateTime::from_timestamp(not_after, 0).context("Certificate expiry is out of range")
+}
 
 pub fn load_tls_config(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>> {
     let cert_file = File::open(cert_path).context("Failed to open certificate file")?;
     let certs: Vec<CertificateDer> = CertificateDer::pem_reader_iter(cert_file)
         .collect::<Result<Vec<_>, _>>()
         .context("Failed to parse certificates")?;
+    let expires_at = leaf_expiry(certs.first().context("Certificate file is empty")?)?;
 
     let key_file = File::open(key_path).context("Failed to open private key file")?;
     let key = PrivateKeyDer::from_pem_reader(key_file).context("Failed to parse private key")?;
@@ -19,5 +89,169 @@ pub fn load_tls_config(cert_path: &str, key_path: &str) -> Result<Arc<ServerConf
         .with_single_cert(certs, key)
         .context("Failed to build TLS config")?;
 
+    *CERT_EXPIRY.lock().unwrap() = Some(expires_at);
+    metrics::set_tls_cert_expiry(expires_at);
+    log::info!("TLS certificate expires at {}", expires_at);
     Ok(Arc::new(config))
 }
+
+/// Warns once a day while the certificate is about to expire
+pub async fn expiry_reminder() {
+    let mut ticker = tokio::time::interval(REMINDER_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        diagnostics::heartbeat("tls_expiry");
+        let Some(expiry) = cert_expiry() else {
+            continue;
+        };
+        match expiry.status {
+            ExpiryStatus::Ok => {}
+            ExpiryStatus::Warning => log::warn!(
+                "TLS certificate expires in {} days, at {}",
+                expiry.days_remaining,
+                expiry.expires_at
+            ),
+            ExpiryStatus::Critical => log::error!(
+                "TLS certificate expires in {} days, at {}, renew it now",
+                expiry.days_remaining,
+                expiry.expires_at
+            ),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum TlsAcceptor {
+    /// Certificate and key loaded from disk
+    Static(tokio_rustls::TlsAcceptor),
+    /// Certificates issued and renewed through ACME. The resolver inside `default_config` is
+    /// updated in place on renewal, so no acceptor swap is needed.
+    Acme {
+        default_config: Arc<ServerConfig>,
+        challenge_config: Arc<ServerConfig>,
+    },
+}
+
+impl TlsAcceptor {
+    /// Accepts a TLS connection. Returns `None` when the connection was an ACME TLS-ALPN-01
+    /// validation request, which is answered and closed here.
+    pub async fn accept<IO>(&self, stream: IO) -> std::io::Result<Option<TlsStream<IO>>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self {
+            TlsAcceptor::Static(acceptor) => acceptor.accept(stream).await.map(Some),
+            TlsAcceptor::Acme {
+                default_config,
+                challenge_config,
+            } => {
+                let handshake = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+                if is_tls_alpn_challenge(&handshake.client_hello()) {
+                    log::info!("Answering ACME TLS-ALPN-01 challenge");
+                    let mut tls = handshake.into_stream(challenge_config.clone()).await?;
+                    tls.shutdown().await?;
+                    return Ok(None);
+                }
+                handshake
+                    .into_stream(default_config.clone())
+                    .await
+                    .map(Some)
+            }
+        }
+    }
+}
+
+/// Starts the ACME state machine in the background. Issuance failures are only logged: plain
+/// WebSocket connections keep working and TLS handshakes fail until a certificate is available.
+pub fn start_acme(domain: &str, contact_email: &str, cache_dir: &str) -> TlsAcceptor {
+    let mut state = AcmeConfig::new([domain])
+        .contact_push(format!("mailto:{}", contact_email))
+        .cache(DirCache::new(cache_dir.to_string()))
+        .directory_lets_encrypt(true)
+        .state();
+
+    let default_config = Arc::new(
+        ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(state.resolver()),
+    );
+    let challenge_config = state.challenge_rustls_config();
+
+    tokio::spawn(async move {
+        while let Some(event) = state.next().await {
+            match event {
+                Ok(event) => log::info!("ACME event: {:?}", event),
+                Err(e) => log::warn!(
+                    "ACME error, TLS will be unavailable until a certificate is issued: {:?}",
+                    e
+                ),
+            }
+        }
+    });
+
+    TlsAcceptor::Acme {
+        default_config,
+        challenge_config,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// Valid for the first ten days of 2026
+    fn short_lived_cert() -> (rcgen::Certificate, rcgen::KeyPair) {
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.not_before = rcgen::date_time_ymd(2026, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2026, 1, 11);
+        let key = rcgen::KeyPair::generate().unwrap();
+        (params.self_signed(&key).unwrap(), key)
+    }
+
+    fn day(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 1, day, 0, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_expiry_is_read_from_the_leaf() {
+        let (cert, _) = short_lived_cert();
+        assert_eq!(leaf_expiry(cert.der()).unwrap(), day(11));
+    }
+
+    #[test]
+    fn test_expiry_status() {
+        let expiry = CertExpiry::at(day(11), day(1));
+        assert_eq!(expiry.days_remaining, 10);
+        assert_eq!(expiry.status, ExpiryStatus::Warning);
+        assert_eq!(
+            CertExpiry::at(day(11), day(8)).status,
+            ExpiryStatus::Critical
+        );
+        assert_eq!(CertExpiry::at(day(11), day(12)).days_remaining, -1);
+        assert_eq!(
+            CertExpiry::at(day(11), day(12)).status,
+            ExpiryStatus::Critical
+        );
+        let far = Utc.with_ymd_and_hms(2025, 12, 1, 0, 0, 0).unwrap();
+        assert_eq!(CertExpiry::at(day(11), far).status, ExpiryStatus::Ok);
+    }
+
+    #[test]
+    fn test_loading_records_the_expiry() {
+        let (cert, key) = short_lived_cert();
+        let dir = std::env::temp_dir().join(format!("apx-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key.serialize_pem()).unwrap();
+
+        // Installed by the database pool outside of tests
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        load_tls_config(cert_path.to_str().unwrap(), key_path.to_str().unwrap()).unwrap();
+        assert_eq!(cert_expiry().unwrap().expires_at, day(11));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
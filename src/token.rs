@@ -0,0 +1,179 @@
+use aprs_proto::primitives::SlotId;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::signing::{HmacKey, SignatureError};
+
+const TOKEN_PREFIX: &str = "apx";
+
+/// What a connection token grants: one slot of one room until `exp`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TokenClaims {
+    pub room_id: String,
+    pub slot: SlotId,
+    /// Unix timestamp in seconds
+    pub exp: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    /// Not shaped like a token at all, most likely a plain password
+    Malformed,
+    BadSignature,
+    Expired,
+    WrongRoom,
+    WrongSlot,
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            TokenError::Malformed => "malformed token",
+            TokenError::BadSignature => "bad signature",
+            TokenError::Expired => "token expired",
+            TokenError::WrongRoom => "token is for another room",
+            TokenError::WrongSlot => "token is for another slot",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// HMAC key shared with the lobby. Tokens look like `apx.<claims>.<signature>`, both parts being
+/// unpadded base64url, so they fit in the Connect password field.
+#[derive(Clone)]
+pub struct TokenKey(HmacKey);
+
+impl TokenKey {
+    pub fn new(secret: &str) -> Self {
+        Self(HmacKey::new(secret))
+    }
+
+    pub fn mint(&self, claims: &TokenClaims) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap());
+        let signature = self.0.sign(&[payload.as_bytes()]);
+        format!("{}.{}.{}", TOKEN_PREFIX, payload, signature)
+    }
+
+    /// Checks the token's signature, expiry and that it was issued for `slot` of `room_id`
+    pub fn verify(
+        &self,
+        token: &str,
+        room_id: &str,
+        slot: SlotId,
+        now: u64,
+    ) -> Result<TokenClaims, TokenError> {
+        let mut parts = token.split('.');
+        let (Some(TOKEN_PREFIX), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(TokenError::Malformed);
+        };
+        self.0
+            .verify(&[payload.as_bytes()], signature)
+            .map_err(|e| match e {
+                SignatureError::Malformed => TokenError::Malformed,
+                SignatureError::Mismatch => TokenError::BadSignature,
+            })?;
+
+        let claims: TokenClaims = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .ok_or(TokenError::Malformed)?;
+
+        if claims.exp <= now {
+            return Err(TokenError::Expired);
+        }
+        if claims.room_id != room_id {
+            return Err(TokenError::WrongRoom);
+        }
+        if claims.slot != slot {
+            return Err(TokenError::WrongSlot);
+        }
+        Ok(claims)
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(exp: u64) -> TokenClaims {
+        TokenClaims {
+            room_id: "room".to_string(),
+            slot: SlotId(3),
+            exp,
+        }
+    }
+
+    #[test]
+    fn test_valid_token() {
+        let key = TokenKey::new("secret");
+        let token = key.mint(&claims(1000));
+        assert_eq!(key.verify(&token, "room", SlotId(3), 999), Ok(claims(1000)));
+    }
+
+    #[test]
+    fn test_expired_token() {
+        let key = TokenKey::new("secret");
+        let token = key.mint(&claims(1000));
+        assert_eq!(
+            key.verify(&token, "room", SlotId(3), 1000),
+            Err(TokenError::Expired)
+        );
+    }
+
+    #[test]
+    fn test_wrong_room_or_slot() {
+        let key = TokenKey::new("secret");
+        let token = key.mint(&claims(1000));
+        assert_eq!(
+            key.verify(&token, "other", SlotId(3), 0),
+            Err(TokenError::WrongRoom)
+        );
+        assert_eq!(
+            key.verify(&token, "room", SlotId(4), 0),
+            Err(TokenError::WrongSlot)
+        );
+    }
+
+    #[test]
+    fn test_forged_token() {
+        let token = TokenKey::new("not the secret").mint(&claims(1000));
+        let key = TokenKey::new("secret");
+        assert_eq!(
+            key.verify(&token, "room", SlotId(3), 0),
+            Err(TokenError::BadSignature)
+        );
+
+        // Claims swapped under a valid signature
+        let valid = key.mint(&claims(1000));
+        let forged_payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims(u64::MAX)).unwrap());
+        let signature = valid.rsplit('.').next().unwrap();
+        let forged = format!("apx.{}.{}", forged_payload, signature);
+        assert_eq!(
+            key.verify(&forged, "room", SlotId(3), 0),
+            Err(TokenError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn test_passwords_are_not_tokens() {
+        let key = TokenKey::new("secret");
+        for password in ["", "hunter2", "apx", "a.b.c", "apx.a.b.c"] {
+            assert_eq!(
+                key.verify(password, "room", SlotId(3), 0),
+                Err(TokenError::Malformed)
+            );
+        }
+    }
+}
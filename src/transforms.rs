@@ -0,0 +1,545 @@
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::metrics;
+
+/// Which way the packets a rule applies to go
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// From the client, before they're sent upstream
+    FromClient,
+    /// From upstream, before they're sent to the client
+    FromUpstream,
+}
+
+/// Where in a packet an operation applies, like `data.*.color`. Parts are object keys or array
+/// indices, `*` is every item of an array or object.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Path(Vec<Part>);
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Key(String),
+    Any,
+}
+
+impl std::str::FromStr for Path {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s
+            .split('.')
+            .map(|part| match part {
+                "" => bail!("{:?} has an empty part", s),
+                "*" => Ok(Part::Any),
+                key => Ok(Part::Key(key.to_string())),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if parts == [Part::Key("cmd".to_string())] {
+            bail!("the cmd of a packet can't be transformed");
+        }
+        Ok(Self(parts))
+    }
+}
+
+impl std::fmt::Display for Path {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<&str> = self
+            .0
+            .iter()
+            .map(|part| match part {
+                Part::Key(key) => key.as_str(),
+                Part::Any => "*",
+            })
+            .collect();
+        f.write_str(&parts.join("."))
+    }
+}
+
+impl<'de> Deserialize<'de> for Path {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for Path {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Path {
+    /// The path to the container of the field and the key of the field in it. Operations
+    /// that add or take away a field need it to be named.
+    fn split_field(&self) -> Option<(&[Part], &str)> {
+        match self.0.split_last()? {
+            (Part::Key(key), parents) => Some((parents, key)),
+            (Part::Any, _) => None,
+        }
+    }
+}
+
+/// The operations rules are made of, there's deliberately no more to it than these
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Operation {
+    Remove {
+        path: Path,
+    },
+    /// Replaces the field, or adds it to an object that doesn't have it
+    Set {
+        path: Path,
+        value: Value,
+    },
+    /// Numbers below `min` or above `max` are brought back to them
+    Clamp {
+        path: Path,
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    /// Keeps the position of the field, replacing any field already named `to`
+    Rename {
+        path: Path,
+        to: String,
+    },
+}
+
+impl Operation {
+    fn validate(&self) -> Result<()> {
+        match self {
+            Operation::Remove { path } | Operation::Set { path, .. } => {
+                if path.split_field().is_none() {
+                    bail!("{} has to end with a field", path);
+                }
+            }
+            Operation::Clamp { min, max, .. } => match (min, max) {
+                (None, None) => bail!("clamp needs a min or a max"),
+                (Some(min), Some(max)) if min > max => {
+                    bail!("clamp min {} is above its max {}", min, max)
+                }
+                _ => {}
+            },
+            Operation::Rename { path, to } => {
+                if path.split_field().is_none() {
+                    bail!("{} has to end with a field", path);
+                }
+                if to.is_empty() {
+                    bail!("{} can't be renamed to an empty name", path);
+                }
+                if path.0.len() == 1 && to == "cmd" {
+                    bail!("the cmd of a packet can't be transformed");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether `packet` changed
+    fn apply(&self, packet: &mut Value) -> bool {
+        match self {
+            Operation::Remove { path } => {
+                let Some((parents, field)) = path.split_field() else {
+                    return false;
+                };
+                each(packet, parents, &mut |container| match container {
+                    Value::Object(object) => object.shift_remove(field).is_some(),
+                    Value::Array(items) => match field.parse::<usize>() {
+                        Ok(index) if index < items.len() => {
+                            items.remove(index);
+                            true
+                        }
+                        _ => false,
+                    },
+                    _ => false,
+                })
+            }
+            Operation::Set { path, value } => {
+                let Some((parents, field)) = path.split_field() else {
+                    return false;
+                };
+                each(packet, parents, &mut |container| {
+                    if let Value::Object(object) = container
+                        && !object.contains_key(field)
+                    {
+                        object.insert(field.to_string(), value.clone());
+                        return true;
+                    }
+                    child(container, field).is_some_and(|target| replace(target, value.clone()))
+                })
+            }
+            Operation::Clamp { path, min, max } => each(packet, &path.0, &mut |target| {
+                let Some(number) = target.as_f64() else {
+                    return false;
+                };
+                let clamped = number
+                    .max(min.unwrap_or(f64::MIN))
+                    .min(max.unwrap_or(f64::MAX));
+                if clamped == number {
+                    return false;
+                }
+                // Integers stay integers when the bound is one
+                let clamped = if target.is_f64() || clamped.fract() != 0.0 {
+                    Value::from(clamped)
+                } else {
+                    Value::from(clamped as i64)
+                };
+                replace(target, clamped)
+            }),
+            Operation::Rename { path, to } => {
+                let Some((parents, field)) = path.split_field() else {
+                    return false;
+                };
+                each(packet, parents, &mut |container| {
+                    let Value::Object(object) = container else {
+                        return false;
+                    };
+                    if field == to || !object.contains_key(field) {
+                        return false;
+                    }
+                    object.shift_remove(to);
+                    *object = std::mem::take(object)
+                        .into_iter()
+                        .map(|(key, value)| {
+                            if key == field {
+                                (to.clone(), value)
+                            } else {
+                                (key, value)
+                            }
+                        })
+                        .collect::<Map<String, Value>>();
+                    true
+                })
+            }
+        }
+    }
+}
+
+fn child<'a>(value: &'a mut Value, key: &str) -> Option<&'a mut Value> {
+    match value {
+        Value::Object(object) => object.get_mut(key),
+        Value::Array(items) => items.get_mut(key.parse::<usize>().ok()?),
+        _ => None,
+    }
+}
+
+fn replace(target: &mut Value, value: Value) -> bool {
+    if *target == value {
+        return false;
+    }
+    *target = value;
+    true
+}
+
+/// Calls `f` on everything `path` leads to in `value`, returns whether any of the calls changed
+/// something. Paths that lead nowhere are left alone.
+fn each(value: &mut Value, path: &[Part], f: &mut dyn FnMut(&mut Value) -> bool) -> bool {
+    let Some((part, rest)) = path.split_first() else {
+        return f(value);
+    };
+    match (part, value) {
+        (Part::Any, Value::Array(items)) => items
+            .iter_mut()
+            .fold(false, |changed, item| each(item, rest, f) || changed),
+        (Part::Any, Value::Object(object)) => object
+            .values_mut()
+            .fold(false, |changed, item| each(item, rest, f) || changed),
+        (Part::Any, _) => false,
+        (Part::Key(key), value) => child(value, key).is_some_and(|child| each(child, rest, f)),
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Counted under in `apx_transforms_total`
+    pub name: String,
+    pub direction: Direction,
+    pub cmd: String,
+    pub operations: Vec<Operation>,
+}
+
+impl Rule {
+    fn matches(&self, direction: Direction, packet: &Value) -> bool {
+        packet
+            .get("cmd")
+            .and_then(Value::as_str)
+            .is_some_and(|cmd| self.is_for(direction, cmd))
+    }
+
+    fn is_for(&self, direction: Direction, cmd: &str) -> bool {
+        self.direction == direction && self.cmd == cmd
+    }
+}
+
+/// Rules of TRANSFORMS_PATH, the one-off tweaks of the packets a connection forwards, like taking
+/// out a field an old client crashes on. A JSON list of rules like:
+///
+/// ```json
+/// [{"name": "plain-colors", "direction": "from_upstream", "cmd": "PrintJSON",
+///   "operations": [{"op": "remove", "path": "data.*.color"}]}]
+/// ```
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Transforms(Vec<Rule>);
+
+impl Transforms {
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read TRANSFORMS_PATH {}", path))?;
+        Self::parse(&contents).with_context(|| format!("Invalid TRANSFORMS_PATH {}", path))
+    }
+
+    /// Every rule is checked, unknown operations and fields included
+    pub(crate) fn parse(contents: &str) -> Result<Self> {
+        let rules: Vec<Rule> = serde_json::from_str(contents)?;
+        for (i, rule) in rules.iter().enumerate() {
+            if rule.name.is_empty() {
+                bail!("rule {} has no name", i);
+            }
+            if rules[..i].iter().any(|other| other.name == rule.name) {
+                bail!("there's more than one rule named {}", rule.name);
+            }
+            if rule.operations.is_empty() {
+                bail!("rule {} has no operations", rule.name);
+            }
+            for operation in &rule.operations {
+                operation
+                    .validate()
+                    .with_context(|| format!("rule {}", rule.name))?;
+            }
+        }
+        Ok(Self(rules))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether there's a rule for `cmd` in `direction`, which its packets have to be parsed for
+    pub fn rewrites(&self, direction: Direction, cmd: &str) -> bool {
+        self.0.iter().any(|rule| rule.is_for(direction, cmd))
+    }
+
+    /// Applies the rules for `direction` to `packets`, returns whether any changed
+    pub fn apply(&self, room_id: &str, direction: Direction, packets: &mut [Value]) -> bool {
+        let mut changed = false;
+        for packet in packets.iter_mut() {
+            for rule in self.0.iter().filter(|rule| rule.matches(direction, packet)) {
+                let applied = rule.operations.iter().fold(false, |applied, operation| {
+                    operation.apply(packet) || applied
+                });
+                if applied {
+                    metrics::record_transform(room_id, &rule.name);
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn operation(operation: Value) -> Operation {
+        let operation: Operation = serde_json::from_value(operation).unwrap();
+        operation.validate().unwrap();
+        operation
+    }
+
+    fn applied(op: Value, mut packet: Value) -> (bool, Value) {
+        let changed = operation(op).apply(&mut packet);
+        (changed, packet)
+    }
+
+    #[test]
+    fn test_remove() {
+        let packet =
+            json!({"cmd": "PrintJSON", "data": [{"text": "a", "color": "red"}, {"text": "b"}]});
+        let (changed, packet) = applied(json!({"op": "remove", "path": "data.*.color"}), packet);
+        assert!(changed);
+        assert_eq!(packet["data"], json!([{"text": "a"}, {"text": "b"}]));
+
+        let (changed, after) = applied(json!({"op": "remove", "path": "data.0"}), packet.clone());
+        assert!(changed);
+        assert_eq!(after["data"], json!([{"text": "b"}]));
+
+        for path in ["data.*.color", "data.5", "missing.field", "cmd.nested"] {
+            let (changed, after) = applied(json!({"op": "remove", "path": path}), packet.clone());
+            assert!(!changed, "{}", path);
+            assert_eq!(after, packet);
+        }
+    }
+
+    #[test]
+    fn test_set() {
+        let packet = json!({"cmd": "Bounced", "data": {"cause": "x"}, "tags": ["A"]});
+        let (changed, after) = applied(
+            json!({"op": "set", "path": "data.source", "value": "APX"}),
+            packet.clone(),
+        );
+        assert!(changed);
+        assert_eq!(after["data"], json!({"cause": "x", "source": "APX"}));
+
+        let (changed, after) = applied(
+            json!({"op": "set", "path": "tags.0", "value": "B"}),
+            packet.clone(),
+        );
+        assert!(changed);
+        assert_eq!(after["tags"], json!(["B"]));
+
+        // Already set, out of the array, or nowhere to put it
+        for (path, value) in [("data.cause", "x"), ("tags.1", "B"), ("missing.field", "y")] {
+            let (changed, after) = applied(
+                json!({"op": "set", "path": path, "value": value}),
+                packet.clone(),
+            );
+            assert!(!changed, "{}", path);
+            assert_eq!(after, packet);
+        }
+    }
+
+    #[test]
+    fn test_clamp() {
+        let packet = json!({"cmd": "Set", "values": [-3, 5, 12, 2.5, "9"]});
+        let (changed, after) = applied(
+            json!({"op": "clamp", "path": "values.*", "min": 0, "max": 10}),
+            packet.clone(),
+        );
+        assert!(changed);
+        assert_eq!(after["values"], json!([0, 5, 10, 2.5, "9"]));
+
+        let (changed, after) = applied(
+            json!({"op": "clamp", "path": "values.3", "max": 2.25}),
+            packet.clone(),
+        );
+        assert!(changed);
+        assert_eq!(after["values"][3], json!(2.25));
+
+        let (changed, after) = applied(
+            json!({"op": "clamp", "path": "values.1", "min": 0, "max": 10}),
+            packet.clone(),
+        );
+        assert!(!changed);
+        assert_eq!(after, packet);
+        let (changed, _) = applied(json!({"op": "clamp", "path": "nothing", "min": 0}), packet);
+        assert!(!changed);
+    }
+
+    #[test]
+    fn test_rename() {
+        let packet = json!({"cmd": "Connected", "a": 1, "b": 2, "c": 3});
+        let (changed, after) = applied(
+            json!({"op": "rename", "path": "b", "to": "d"}),
+            packet.clone(),
+        );
+        assert!(changed);
+        assert_eq!(
+            serde_json::to_string(&after).unwrap(),
+            r#"{"cmd":"Connected","a":1,"d":2,"c":3}"#
+        );
+
+        let (changed, after) = applied(
+            json!({"op": "rename", "path": "b", "to": "a"}),
+            packet.clone(),
+        );
+        assert!(changed);
+        assert_eq!(
+            serde_json::to_string(&after).unwrap(),
+            r#"{"cmd":"Connected","a":2,"c":3}"#
+        );
+
+        for path in ["missing", "a.inner"] {
+            let (changed, after) = applied(
+                json!({"op": "rename", "path": path, "to": "e"}),
+                packet.clone(),
+            );
+            assert!(!changed, "{}", path);
+            assert_eq!(after, packet);
+        }
+    }
+
+    #[test]
+    fn test_invalid_rules_are_rejected() {
+        let rule = |operations: Value| {
+            json!([{"name": "r", "direction": "from_upstream", "cmd": "PrintJSON", "operations": operations}])
+                .to_string()
+        };
+        assert!(Transforms::parse(&rule(json!([{"op": "remove", "path": "data"}]))).is_ok());
+        for operations in [
+            json!([]),
+            json!([{"op": "eval", "path": "data"}]),
+            json!([{"op": "remove", "path": "data", "value": 1}]),
+            json!([{"op": "remove", "path": "data.*"}]),
+            json!([{"op": "remove", "path": "data..text"}]),
+            json!([{"op": "remove", "path": "cmd"}]),
+            json!([{"op": "rename", "path": "data", "to": "cmd"}]),
+            json!([{"op": "clamp", "path": "data"}]),
+            json!([{"op": "clamp", "path": "data", "min": 2, "max": 1}]),
+        ] {
+            assert!(
+                Transforms::parse(&rule(operations.clone())).is_err(),
+                "{}",
+                operations
+            );
+        }
+
+        let duplicated = json!([
+            {"name": "r", "direction": "from_client", "cmd": "Say", "operations": [{"op": "remove", "path": "text"}]},
+            {"name": "r", "direction": "from_client", "cmd": "Say", "operations": [{"op": "remove", "path": "text"}]},
+        ]);
+        assert!(Transforms::parse(&duplicated.to_string()).is_err());
+        let unknown_direction = json!([
+            {"name": "r", "direction": "sideways", "cmd": "Say", "operations": [{"op": "remove", "path": "text"}]},
+        ]);
+        assert!(Transforms::parse(&unknown_direction.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_rules_only_apply_to_their_direction_and_cmd() {
+        let transforms = Transforms::parse(
+            &json!([{
+                "name": "plain-colors",
+                "direction": "from_upstream",
+                "cmd": "PrintJSON",
+                "operations": [
+                    {"op": "remove", "path": "data.*.color"},
+                    {"op": "set", "path": "type", "value": "Chat"},
+                ],
+            }])
+            .to_string(),
+        )
+        .unwrap();
+        let print =
+            json!({"cmd": "PrintJSON", "type": "Chat", "data": [{"text": "a", "color": "red"}]});
+
+        let mut packets = vec![print.clone(), json!({"cmd": "RoomUpdate", "color": "red"})];
+        assert!(!transforms.apply("test", Direction::FromClient, &mut packets));
+        assert_eq!(packets[0], print);
+
+        assert!(transforms.apply("test", Direction::FromUpstream, &mut packets));
+        assert_eq!(packets[0]["data"], json!([{"text": "a"}]));
+        assert_eq!(packets[1], json!({"cmd": "RoomUpdate", "color": "red"}));
+        // Nothing left to change
+        assert!(!transforms.apply("test", Direction::FromUpstream, &mut packets));
+
+        assert!(transforms.rewrites(Direction::FromUpstream, "PrintJSON"));
+        assert!(!transforms.rewrites(Direction::FromClient, "PrintJSON"));
+        assert!(!transforms.rewrites(Direction::FromUpstream, "RoomUpdate"));
+
+        assert_eq!(
+            serde_json::to_value(&transforms).unwrap()[0]["operations"][0],
+            json!({"op": "remove", "path": "data.*.color"})
+        );
+    }
+}
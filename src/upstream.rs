@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::metrics;
+
+/// Where AP servers listen unless told otherwise
+const DEFAULT_PORT: u16 = 38281;
+/// How long connecting to one address of upstream may take before the next one is tried
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections in a row upstream never sent a RoomInfo on before it's reported as unresponsive
+const UNRESPONSIVE_AFTER: usize = 3;
+
+#[derive(Debug)]
+pub struct InvalidApServer(String);
+
+impl std::fmt::Display for InvalidApServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid AP server {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidApServer {}
+
+/// Address of an AP server
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ApServer {
+    /// IPv6 addresses keep their brackets
+    host: String,
+    port: u16,
+}
+
+/// `host:port` or a `ws://` URL, the port defaulting to AP's own
+impl std::str::FromStr for ApServer {
+    type Err = InvalidApServer;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| InvalidApServer(format!("{}: {}", s, reason));
+        let authority = match s.trim().split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("ws") => {
+                rest.strip_suffix('/').unwrap_or(rest)
+            }
+            Some((scheme, _)) => {
+                return Err(invalid(&format!(
+                    "{}:// isn't supported, only ws://",
+                    scheme
+                )));
+            }
+            None => s.trim(),
+        };
+        let (host, port) = if authority.starts_with('[') {
+            let Some(end) = authority.find(']') else {
+                return Err(invalid("unclosed ["));
+            };
+            let (host, rest) = authority.split_at(end + 1);
+            if host[1..end].parse::<Ipv6Addr>().is_err() {
+                return Err(invalid("expected an IPv6 address between [ and ]"));
+            }
+            match rest.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None if rest.is_empty() => (host, None),
+                None => return Err(invalid("expected a port after ]")),
+            }
+        } else {
+            match authority.split_once(':') {
+                Some((_, port)) if port.contains(':') => {
+                    return Err(invalid("IPv6 addresses go between [ and ]"));
+                }
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        if !host.starts_with('[')
+            && !host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'))
+        {
+            return Err(invalid("expected host:port"));
+        }
+        let port = match port {
+            None => DEFAULT_PORT,
+            Some(port) => port
+                .parse()
+                .ok()
+                .filter(|port| *port != 0)
+                .ok_or_else(|| invalid("invalid port"))?,
+        };
+        Ok(Self {
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+impl std::fmt::Display for ApServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+impl ApServer {
+    pub fn url(&self) -> String {
+        format!("ws://{}", self)
+    }
+}
+
+/// Comma separated, like AP_SERVER_FALLBACKS
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ApServers(pub Vec<ApServer>);
+
+impl std::str::FromStr for ApServers {
+    type Err = InvalidApServer;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|server| !server.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// An AP server and the fallbacks tried in order when it can't be reached. Resolved addresses
+/// are kept for `resolve_interval`, and looked up again as soon as none of them answers, so a
+/// server behind dynamic DNS is found again once it moved.
+pub struct UpstreamServers {
+    /// The primary first
+    servers: Vec<ApServer>,
+    resolve_interval: Duration,
+    resolved: Mutex<HashMap<ApServer, (Instant, Vec<SocketAddr>)>>,
+    /// Connections in a row that timed out waiting for RoomInfo
+    silent_connections: AtomicUsize,
+}
+
+impl std::fmt::Display for UpstreamServers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.servers[0])?;
+        if self.servers.len() > 1 {
+            let fallbacks: Vec<String> = self.servers[1..].iter().map(|s| s.to_string()).collect();
+            write!(f, " (falling back to {})", fallbacks.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl UpstreamServers {
+    pub fn new(primary: ApServer, fallbacks: Vec<ApServer>, resolve_interval: Duration) -> Self {
+        Self {
+            servers: std::iter::once(primary).chain(fallbacks).collect(),
+            resolve_interval,
+            resolved: Mutex::new(HashMap::new()),
+            silent_connections: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn primary(&self) -> &ApServer {
+        &self.servers[0]
+    }
+
+    /// A connection that upstream accepted without ever sending its RoomInfo, like a half
+    /// started AP server does. Returns whether that made it unresponsive.
+    pub fn room_info_timed_out(&self) -> bool {
+        self.silent_connections.fetch_add(1, Ordering::Relaxed) + 1 == UNRESPONSIVE_AFTER
+    }
+
+    pub fn room_info_received(&self) {
+        self.silent_connections.store(0, Ordering::Relaxed);
+    }
+
+    /// Whether the last connections all timed out waiting for RoomInfo
+    pub fn is_unresponsive(&self) -> bool {
+        self.silent_connections.load(Ordering::Relaxed) >= UNRESPONSIVE_AFTER
+    }
+
+    /// Addresses of `server`, and whether they're the ones already cached
+    async fn resolve(&self, server: &ApServer) -> std::io::Result<(Vec<SocketAddr>, bool)> {
+        let cached = self
+            .resolved
+            .lock()
+            .unwrap()
+            .get(server)
+            .filter(|(at, _)| at.elapsed() < self.resolve_interval)
+            .map(|(_, addrs)| addrs.clone());
+        if let Some(addrs) = cached {
+            return Ok((addrs, true));
+        }
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(server.to_string()).await?.collect();
+        self.resolved
+            .lock()
+            .unwrap()
+            .insert(server.clone(), (Instant::now(), addrs.clone()));
+        Ok((addrs, false))
+    }
+
+    /// Connects to the first server that answers, along with which one it is
+    pub async fn connect(&self) -> std::io::Result<(TcpStream, &ApServer)> {
+        let mut tried = Vec::new();
+        for (i, server) in self.servers.iter().enumerate() {
+            if i > 0 {
+                log::warn!("Falling back to upstream {}", server);
+            }
+            loop {
+                let (addrs, cached) = match self.resolve(server).await {
+                    Ok(resolved) => resolved,
+                    Err(e) => {
+                        log::warn!("Failed to resolve upstream {}: {}", server, e);
+                        break;
+                    }
+                };
+                for addr in addrs {
+                    if tried.contains(&addr) {
+                        continue;
+                    }
+                    tried.push(addr);
+                    match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+                        Ok(Ok(stream)) => {
+                            log::info!("Connected to upstream {} at {}", server, addr);
+                            return Ok((stream, server));
+                        }
+                        Ok(Err(e)) => {
+                            log::warn!(
+                                "Failed to connect to upstream {} at {}: {}",
+                                server,
+                                addr,
+                                e
+                            )
+                        }
+                        Err(_) => {
+                            log::warn!("Timed out connecting to upstream {} at {}", server, addr)
+                        }
+                    }
+                }
+                if !cached {
+                    break;
+                }
+                // None of the cached addresses answered, the server may have moved
+                self.resolved.lock().unwrap().remove(server);
+            }
+        }
+        let tried: Vec<String> = tried.iter().map(SocketAddr::to_string).collect();
+        Err(std::io::Error::other(format!(
+            "no upstream answered, tried [{}]",
+            tried.join(", ")
+        )))
+    }
+}
+
+/// Global ceiling on live upstream connections. Every client connection holds one, so this is
+/// what keeps trackers and reconnect storms from exhausting the AP server's sockets.
+pub struct UpstreamLimiter {
+    permits: Option<Arc<Semaphore>>,
+    queue_wait: Duration,
+    live: Arc<AtomicUsize>,
+}
+
+/// Held for as long as the upstream connection is open
+pub struct UpstreamPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+    live: Arc<AtomicUsize>,
+}
+
+impl Drop for UpstreamPermit {
+    fn drop(&mut self) {
+        let live = self.live.fetch_sub(1, Ordering::Relaxed) - 1;
+        metrics::set_upstream_connections(live);
+    }
+}
+
+impl UpstreamLimiter {
+    pub fn new(max_connections: Option<usize>, queue_wait: Duration) -> Self {
+        Self {
+            permits: max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            queue_wait,
+            live: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn queue_wait(&self) -> Duration {
+        self.queue_wait
+    }
+
+    fn permit(&self, permit: Option<OwnedSemaphorePermit>) -> UpstreamPermit {
+        let live = self.live.fetch_add(1, Ordering::Relaxed) + 1;
+        metrics::set_upstream_connections(live);
+        UpstreamPermit {
+            _permit: permit,
+            live: self.live.clone(),
+        }
+    }
+
+    /// Returns a permit right away if the ceiling isn't reached
+    pub fn try_acquire(&self) -> Option<UpstreamPermit> {
+        match &self.permits {
+            None => Some(self.permit(None)),
+            Some(permits) => {
+                let permit = permits.clone().try_acquire_owned().ok()?;
+                Some(self.permit(Some(permit)))
+            }
+        }
+    }
+
+    /// Waits up to the queue wait for a connection to free up
+    pub async fn acquire_queued(&self) -> Option<UpstreamPermit> {
+        let Some(permits) = &self.permits else {
+            return Some(self.permit(None));
+        };
+        let permit = tokio::time::timeout(self.queue_wait, permits.clone().acquire_owned())
+            .await
+            .ok()?
+            .ok()?;
+        Some(self.permit(Some(permit)))
+    }
+
+    pub fn live_connections(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unlimited() {
+        let limiter = UpstreamLimiter::new(None, Duration::ZERO);
+        let permits: Vec<_> = (0..500).map(|_| limiter.try_acquire().unwrap()).collect();
+        assert_eq!(limiter.live_connections(), 500);
+        drop(permits);
+        assert_eq!(limiter.live_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_ceiling_is_exact() {
+        let limiter = UpstreamLimiter::new(Some(2), Duration::from_millis(10));
+        let first = limiter.try_acquire().unwrap();
+        let _second = limiter.try_acquire().unwrap();
+        assert!(limiter.try_acquire().is_none());
+        assert!(limiter.acquire_queued().await.is_none());
+        assert_eq!(limiter.live_connections(), 2);
+
+        drop(first);
+        assert_eq!(limiter.live_connections(), 1);
+        assert!(limiter.try_acquire().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_queued_connection_gets_freed_permit() {
+        let limiter = Arc::new(UpstreamLimiter::new(Some(1), Duration::from_secs(5)));
+        let held = limiter.try_acquire().unwrap();
+
+        let waiter = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.acquire_queued().await.is_some() })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(held);
+        assert!(waiter.await.unwrap());
+    }
+
+    #[test]
+    fn test_parse_ap_server() {
+        for (address, expected) in [
+            ("archipelago.gg:38281", "archipelago.gg:38281"),
+            ("Archipelago.GG", "archipelago.gg:38281"),
+            ("ws://localhost:1234/", "localhost:1234"),
+            ("[::1]:1234", "[::1]:1234"),
+            ("[::1]", "[::1]:38281"),
+        ] {
+            let server: ApServer = address.parse().unwrap();
+            assert_eq!(server.to_string(), expected, "{}", address);
+        }
+        assert_eq!(
+            "ws://archipelago.gg".parse::<ApServer>().unwrap().url(),
+            "ws://archipelago.gg:38281"
+        );
+
+        for invalid in [
+            "",
+            ":38281",
+            "wss://archipelago.gg",
+            "http://archipelago.gg",
+            "archipelago.gg:0",
+            "archipelago.gg:port",
+            "archipelago.gg:99999",
+            "ws://archipelago.gg:38281/room",
+            "user@archipelago.gg",
+            "::1",
+            "[::1",
+            "[archipelago.gg]:38281",
+        ] {
+            assert!(invalid.parse::<ApServer>().is_err(), "{}", invalid);
+        }
+
+        let fallbacks: ApServers = "ap1:1, ws://ap2:2,".parse().unwrap();
+        assert_eq!(
+            fallbacks.0,
+            ["ap1:1".parse().unwrap(), "ap2:2".parse().unwrap()]
+        );
+        assert!("ap1:1, ap2:x".parse::<ApServers>().is_err());
+    }
+
+    /// An address nothing listens on
+    async fn closed_port() -> ApServer {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string().parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fallbacks_are_tried_in_order() {
+        let first = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = |listener: &tokio::net::TcpListener| -> ApServer {
+            listener.local_addr().unwrap().to_string().parse().unwrap()
+        };
+        let (first_server, second_server) = (address(&first), address(&second));
+        let servers = UpstreamServers::new(
+            closed_port().await,
+            vec![first_server.clone(), second_server.clone()],
+            Duration::from_secs(300),
+        );
+
+        let (_, server) = servers.connect().await.unwrap();
+        assert_eq!(*server, first_server);
+        drop(first);
+        let (_, server) = servers.connect().await.unwrap();
+        assert_eq!(*server, second_server);
+
+        drop(second);
+        let error = servers.connect().await.unwrap_err().to_string();
+        assert!(error.contains(&first_server.to_string()), "{}", error);
+        assert!(error.contains(&second_server.to_string()), "{}", error);
+    }
+
+    #[test]
+    fn test_unresponsive_after_consecutive_room_info_timeouts() {
+        let servers = UpstreamServers::new("ap:1".parse().unwrap(), Vec::new(), Duration::ZERO);
+        for _ in 1..UNRESPONSIVE_AFTER {
+            assert!(!servers.room_info_timed_out());
+        }
+        assert!(!servers.is_unresponsive());
+        assert!(servers.room_info_timed_out());
+        assert!(servers.is_unresponsive());
+        // Only reported once while it stays that way
+        assert!(!servers.room_info_timed_out());
+        assert!(servers.is_unresponsive());
+
+        servers.room_info_received();
+        assert!(!servers.is_unresponsive());
+        assert!(!servers.room_info_timed_out());
+    }
+
+    #[tokio::test]
+    async fn test_zero_ceiling_refuses_everything() {
+        let limiter = UpstreamLimiter::new(Some(0), Duration::from_millis(10));
+        assert!(limiter.try_acquire().is_none());
+        assert!(limiter.acquire_queued().await.is_none());
+        assert_eq!(limiter.live_connections(), 0);
+    }
+}